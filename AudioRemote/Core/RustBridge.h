@@ -2,13 +2,1648 @@
 #define RustBridge_h
 
 #include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/// Version of the interface declared in this header (major << 16 | minor). Call
+/// ar_ffi_check_abi(AR_FFI_ABI_VERSION) at startup before anything else.
+#define AR_FFI_ABI_VERSION ((uint32_t)((2 << 16) | 59))
+
+/// Initialize the library once at launch. Until then, every function except the ABI check,
+/// ar_init/ar_shutdown, the last-error accessors, rust_string_free, ar_bytes_free, ar_log_set_callback and
+/// ar_log_set_module_level fails with AR_ERROR_NOT_INITIALIZED.
+/// `config_json` may be NULL for defaults; otherwise a JSON object with optional keys:
+///   "storeDirectory": path for persisted state (default ~/Library/Application Support/AudioRemote)
+///   "logFile": {"directory": path, "level": ArLogLevel, "maxFileMb": n}  (see ar_log_enable_file)
+///   "workers": number of background job threads (default one per core, up to 4)
+/// Thread-safe and idempotent: later calls return 1 without applying their config.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_init(const char* config_json);
+
+/// Cancel all jobs, stop the runtime and close the log file; a no-op if not initialized.
+/// Afterwards functions fail with AR_ERROR_NOT_INITIALIZED until the next ar_init. Don't call
+/// it from a serial callback queue: the remaining completions are delivered there.
+void ar_shutdown(void);
+
+/// Returns: the FFI ABI version the linked library implements
+uint32_t ar_ffi_abi_version(void);
+
+/// Returns: 1 if the library implements `expected` (same major, at least the same minor),
+/// -999 otherwise, with a description of the mismatch (see last_error_message)
+int ar_ffi_check_abi(uint32_t expected);
+
+/// Run a JSON request {"id", "method", "params"}; call the "methods" method for the list.
+/// Returns: JSON {"id", "ok": true, "result"} or {"id", "ok": false, "error": {"code", "message"}}
+/// where `code` is an AudioRemoteError (free with rust_string_free); NULL only if `request`
+/// is NULL or not UTF-8 (see last_error_message)
+char* ar_dispatch(const char* request);
+
+/// Progress callback for long-running calls; `total` is 0 when unknown.
+/// Return false to cancel the operation.
+typedef bool (*ProgressCallback)(uint64_t done, uint64_t total, void* ctx);
+
+/// Opaque handle to a long-lived Rust object. Handles are validated on every call (a freed
+/// or wrong-kind handle is an AR_ERROR_INVALID_ARGUMENT error) and never reused; 0 is never valid.
+typedef uint64_t ArHandle;
+
+/// Background job callbacks. `total` is 0 when unknown. On completion `error_code` is AR_OK
+/// and `result` the job's output, or an AudioRemoteError code and `result` the message;
+/// `result` is only valid during the call. Every submitted job completes exactly once.
+typedef void (*ArJobProgressCallback)(ArHandle job, uint64_t done, uint64_t total, void* ctx);
+typedef void (*ArJobCompletionCallback)(ArHandle job, int error_code, const char* result, void* ctx);
+
+/// Request cancellation; the job completes with AR_ERROR_CANCELLED soon after
+/// Returns: 1 if the job was running, 0 if it already finished
+int ar_job_cancel(ArHandle job);
+
+/// Deliver job callbacks on a dispatch queue (e.g. dispatch_get_main_queue()); NULL calls
+/// them on worker threads. The queue is retained until it's replaced.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_runtime_set_callback_queue(void* queue);
+
+/// Error codes reported by ar_last_error_code. Values are stable across releases.
+typedef enum {
+    AR_OK = 0,
+    AR_ERROR_INVALID_ARGUMENT = 1,     // NULL/non-UTF-8 pointer, unknown enum value
+    AR_ERROR_INVALID_VERSION = 2,      // version string doesn't parse
+    AR_ERROR_INVALID_DATA = 3,         // malformed feed, release JSON, archive or delta
+    AR_ERROR_INVALID_BUNDLE = 4,       // app bundle fails validation
+    AR_ERROR_VERIFICATION_FAILED = 5,  // checksum or signature mismatch
+    AR_ERROR_IO = 6,
+    AR_ERROR_NETWORK = 7,              // connection failure or HTTP error status
+    AR_ERROR_CANCELLED = 8,            // a progress callback returned false
+    AR_ERROR_NOT_FOUND = 9,            // e.g. no release on the requested channel
+    AR_ERROR_REFUSED = 10,             // refused by policy, e.g. a downgrade
+    AR_ERROR_UNSUPPORTED = 11,
+    AR_ERROR_PANIC = 12,               // internal bug; the call was aborted and its error value returned
+    AR_ERROR_NOT_INITIALIZED = 13,     // called before ar_init or after ar_shutdown
+    AR_ERROR_OTHER = 99,
+} AudioRemoteError;
+
+/// Code of the last failing call on this thread
+/// Returns: an AudioRemoteError value, or AR_OK if the last call succeeded
+int ar_last_error_code(void);
+
+/// Describe why the last failing call on this thread failed
+/// Returns: newly allocated string (free with rust_string_free), or NULL if the last call succeeded
+char* ar_last_error_message(void);
+
+/// Older name of ar_last_error_message
+char* last_error_message(void);
+
+/// Free a string returned by this library (NULL is ignored)
+/// Every `char*` returned by a function in this header is owned by the caller and must be
+/// released exactly once with rust_string_free, never free(). Input strings are only
+/// borrowed for the duration of the call.
+void rust_string_free(char* ptr);
+
+/// Free a byte buffer returned by this library, passing the length it returned (NULL is ignored)
+void ar_bytes_free(uint8_t* ptr, size_t len);
 
 /// Compare two semantic version strings
-/// Returns: -1 if v1 < v2, 0 if equal, 1 if v1 > v2, -999 on error
+/// Returns: -1 if v1 < v2, 0 if equal, 1 if v1 > v2, -999 on error (see last_error_message)
 int version_compare(const char* v1, const char* v2);
 
+/// Version parsing modes
+typedef enum {
+    VERSION_PARSE_STRICT = 0,   // valid semver, optional 'v' prefix
+    VERSION_PARSE_LENIENT = 1,  // also "2.6", "2.6.0.1", "v2.6.0 (142)"
+} VersionParseMode;
+
+/// Compare two version strings using the given VersionParseMode
+/// Returns: -1 if v1 < v2, 0 if equal, 1 if v1 > v2, -999 on error (see last_error_message)
+int version_compare_with_mode(const char* v1, const char* v2, int mode);
+
+/// Normalize a version string into canonical semver using the given VersionParseMode
+/// Returns: newly allocated string (free with rust_string_free), or NULL on error
+char* version_normalize(const char* version, int mode);
+
+/// Compare marketing versions, breaking ties with CFBundleVersion build numbers (e.g. "142" vs "150")
+/// NULL or empty build numbers are allowed and never break a tie
+/// Returns: -1 if current < latest, 0 if equal, 1 if current > latest, -999 on error (see last_error_message)
+int version_compare_with_build(const char* current, const char* current_build,
+                               const char* latest, const char* latest_build);
+
 /// Check if update is available (latest > current)
 /// Returns: true if latest > current, false otherwise
 bool version_has_update(const char* current, const char* latest);
 
+/// Update channels accepted by every `channel` argument; UPDATE_CHANNEL_SAVED
+/// means the channel persisted with channel_set
+typedef enum {
+    UPDATE_CHANNEL_SAVED = -1,
+    UPDATE_CHANNEL_STABLE = 0,
+    UPDATE_CHANNEL_BETA = 1,
+    UPDATE_CHANNEL_NIGHTLY = 2,
+} UpdateChannel;
+
+/// Check if update is available on a channel (latest > current and latest is
+/// published on the channel: stable = releases only, beta = + beta/rc, nightly = all)
+/// Returns: true if an update should be offered, false otherwise or on error
+bool version_has_update_on_channel(const char* current, const char* latest, int channel);
+
+/// Most significant component that differs between two versions
+typedef enum {
+    VERSION_DIFF_NONE = 0,
+    VERSION_DIFF_MAJOR = 1,
+    VERSION_DIFF_MINOR = 2,
+    VERSION_DIFF_PATCH = 3,
+    VERSION_DIFF_PRERELEASE = 4,
+} VersionDiffKind;
+
+/// Classify which component changed between current and latest (build metadata ignored)
+/// Returns: a VersionDiffKind value, or -999 on error (see last_error_message)
+int version_diff_kind(const char* current, const char* latest);
+
+/// Order version strings by semver precedence (not lexically), e.g. for the release picker.
+/// Writes indices into `versions` in ascending order to `out_indices` (room for `count`).
+/// Duplicates keep only their first index; strings that don't parse in `mode` are left out.
+/// Returns: number of indices written, or -999 on error (see last_error_message)
+int version_sort(const char* const* versions, size_t count, int mode, size_t* out_indices);
+
+/// Check whether a version satisfies a requirement string (e.g. ">=2.5.0, <3.0.0")
+/// Returns: 1 if it matches, 0 if not, -999 on error (see last_error_message)
+int version_satisfies(const char* version, const char* range);
+
+/// UTF-16 variants of the hot version helpers, taking a buffer and its length in code
+/// units (no terminator), e.g. from Swift's String.utf16. Same results as the char* versions.
+int version_compare_utf16(const uint16_t* v1, size_t v1_len, const uint16_t* v2, size_t v2_len);
+bool version_has_update_on_channel_utf16(const uint16_t* current, size_t current_len,
+                                         const uint16_t* latest, size_t latest_len, int channel);
+int version_satisfies_utf16(const uint16_t* version, size_t version_len,
+                            const uint16_t* range, size_t range_len);
+
+/// Pick the newest release for an UpdateChannel from Sparkle appcast XML.
+/// Items without a URL or parseable version are skipped; prereleases and
+/// <sparkle:channel> items are only offered on the matching channel.
+/// Returns: JSON object {title, version, build, url, length, signature,
+/// minimumSystemVersion, releaseNotesUrl, channel} (free with rust_string_free),
+/// or NULL on error (see last_error_message)
+char* appcast_best_release(const char* xml, int channel);
+
+/// Parse appcast XML once and keep it for repeated queries
+/// Returns: a handle (free with ar_appcast_free), or 0 on error (see last_error_message)
+ArHandle ar_appcast_new(const char* xml);
+
+/// Same as appcast_best_release, for a feed parsed with ar_appcast_new
+/// Returns: JSON object (free with rust_string_free), or NULL on error (see last_error_message)
+char* ar_appcast_best_release(ArHandle feed, int channel);
+
+/// Release a feed; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_appcast_free(ArHandle feed);
+
+/// Pick the latest applicable release from GitHub Releases API JSON (array or single object).
+/// Drafts are skipped, prereleases need the beta channel or above, and the asset is
+/// chosen for `arch` ("arm64", "x86_64", or NULL for this machine), preferring DMG over ZIP.
+/// Returns: JSON object {tag, version, name, releaseNotes, publishedAt, htmlUrl, prerelease,
+/// assetName, assetUrl, assetSize, isDmg} (free with rust_string_free),
+/// or NULL on error (see last_error_message)
+char* github_latest_release(const char* json, int channel, const char* arch);
+
+/// Outcome of appcast_update_decision
+typedef enum {
+    UPDATE_DECISION_AVAILABLE = 0,          // a newer release runs on this Mac
+    UPDATE_DECISION_UP_TO_DATE = 1,         // nothing newer on this channel
+    UPDATE_DECISION_REQUIRES_NEWER_OS = 2,  // newer releases need a newer macOS
+    UPDATE_DECISION_OS_TOO_NEW = 3,         // newer releases don't support this macOS anymore
+} UpdateDecision;
+
+/// Decide whether to offer an update from Sparkle appcast XML, honoring each item's
+/// minimumSystemVersion/maximumSystemVersion against `os_version` (e.g. "14.2.1") and
+/// ignoring the version skipped with update_skip_version.
+/// `release_json` (nullable) receives the offered release, or for the OS outcomes the newest
+/// blocked release; free it with rust_string_free. It is set to NULL otherwise.
+/// Returns: an UpdateDecision value, or -999 on error (see last_error_message)
+int appcast_update_decision(const char* xml, const char* current_version, const char* os_version,
+                            int channel, char** release_json);
+
+/// Verify a downloaded update against its Ed25519 signature (Sparkle edSignature format)
+/// `signature_b64`: base64 64-byte signature, `public_key`: base64 32-byte key (SUPublicEDKey)
+/// Returns: 1 if valid, 0 if the signature doesn't match, -999 on error (see last_error_message)
+int verify_update_signature(const char* file_path, const char* signature_b64, const char* public_key);
+
+/// Compute the SHA-256 of a file without loading it into memory
+/// `progress` (nullable) is called after every 1 MB chunk with `ctx`; return false to cancel
+/// Returns: lowercase hex digest (free with rust_string_free), or NULL on error (see last_error_message)
+char* checksum_file_sha256(const char* path, ProgressCallback progress, void* ctx);
+
+/// Check a file against an expected SHA-256 hex digest (case-insensitive)
+/// Returns: 1 if it matches, 0 if not, -999 on error or cancel (see last_error_message)
+int checksum_verify_sha256(const char* path, const char* expected_hex, ProgressCallback progress, void* ctx);
+
+/// Download progress with transfer rate; `total` is 0 and `eta_secs` is -1 when unknown.
+/// Return false to cancel the download.
+typedef bool (*DownloadProgressCallback)(uint64_t done, uint64_t total, double bytes_per_sec,
+                                         double eta_secs, void* ctx);
+
+/// Download a URL to `dest`, resuming a previous partial download ("<dest>.part") via
+/// Range requests when the server's ETag or Last-Modified still matches ("<dest>.part.validator"),
+/// and retrying transient failures up to `max_retries` times with backoff.
+/// Blocks until finished; call from a background queue. Cancelling keeps the partial file.
+/// Returns: 1 on success, 0 if cancelled, -999 on error (see last_error_message)
+int download_file(const char* url, const char* dest, uint32_t max_retries,
+                  DownloadProgressCallback progress, void* ctx);
+
+/// download_file as a background job; `completion` receives the destination path
+/// Returns: a job handle, or 0 on error (see last_error_message)
+ArHandle ar_download_file_async(const char* url, const char* dest, uint32_t max_retries,
+                                ArJobProgressCallback progress, ArJobCompletionCallback completion,
+                                void* ctx);
+
+/// Apply a delta update (bsdiff patches in a zstd container) to the installed app bundle,
+/// writing the new bundle to `output_path`, which must not exist yet. Each file is checked
+/// against its expected SHA-256 before and after patching; nothing is left behind on failure.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int delta_apply(const char* installed_path, const char* delta_path, const char* output_path);
+
+/// Unpack a downloaded .zip/.tar.gz/.dmg update into `staging_dir` (created if needed, must be empty)
+/// and validate the bundle: Info.plist readable, CFBundleExecutable present and executable,
+/// and CFBundleIdentifier equal to `expected_bundle_id` unless it is NULL.
+/// Returns: path of the staged .app (free with rust_string_free), or NULL on error (see last_error_message)
+char* update_stage_archive(const char* archive_path, const char* staging_dir, const char* expected_bundle_id);
+
+/// Atomically replace the installed app bundle with a staged update. The staged bundle must
+/// have the same CFBundleIdentifier; the swap is a single rename on APFS, permissions and
+/// xattrs are preserved, and the swap is undone if the new bundle fails validation.
+/// The previous version is kept as "<dir>/.<name>.previous" when `keep_backup` is true.
+/// Versions below the last launched one are refused unless update_set_allow_downgrade was called.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int update_apply_staged(const char* staged_app, const char* installed_app, bool keep_backup);
+
+/// Reopen `app_path` once process `pid` has exited; call this, then terminate the app
+/// Returns: 1 if the relaunch helper started, -999 on error (see last_error_message)
+int update_relaunch_after_exit(const char* app_path, uint32_t pid);
+
+/// Decide whether a staged rollout of `rollout_percent` (0-100, e.g. 10.0) includes this device.
+/// `device_id` must be stable across launches; the same device stays in as the percentage grows.
+/// Returns: 1 if the update should be offered, 0 if not, -999 on error (see last_error_message)
+int rollout_applies(const char* device_id, const char* version, double rollout_percent);
+
+/// Use `directory` for all state persisted by the Rust layer
+/// (default: ~/Library/Application Support/AudioRemote)
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_store_set_directory(const char* directory);
+
+/// Remember "Skip this version" across restarts. appcast_update_decision stops offering
+/// that version (and anything older) until a newer release appears.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int update_skip_version(const char* version);
+
+/// Returns: 1 if `version` was skipped by the user, 0 if not, -999 on error (see last_error_message)
+int update_is_skipped(const char* version);
+
+/// Forget the skipped version
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int update_clear_skipped(void);
+
+/// Ask whether an update check may run now, and if so record it. Use this from every
+/// component that checks the feed: checks are spaced at least `min_interval_secs` apart
+/// plus a random jitter of up to `max_jitter_secs`, and the last check survives restarts.
+/// Returns: 1 if the caller should check now, 0 if it's too soon, -999 on error (see last_error_message)
+int update_begin_check(uint64_t min_interval_secs, uint64_t max_jitter_secs);
+
+/// Record a check that bypassed the scheduler (e.g. the user clicked "Check for Updates")
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int update_record_check(uint64_t max_jitter_secs);
+
+/// Seconds until update_begin_check would allow a check (0 = now), for scheduling timers
+uint64_t update_seconds_until_check(uint64_t min_interval_secs);
+
+/// Convert Markdown release notes (GitHub-flavored: tables, strikethrough, task lists) to an
+/// HTML fragment for the update dialog. Output is sanitized: no scripts, styles or event
+/// handlers, and links are limited to http/https/mailto.
+/// Returns: HTML (free with rust_string_free), or NULL on error (see last_error_message)
+char* release_notes_to_html(const char* markdown);
+
+/// Record that `version` launched successfully; call once per launch. This also clears
+/// a pending update_set_allow_downgrade override.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int update_record_launch(const char* version);
+
+/// Check whether installing `candidate` would go below the last launched version
+/// Returns: 1 if it is a downgrade (and no override is set), 0 if not, -999 on error (see last_error_message)
+int update_is_downgrade(const char* candidate);
+
+/// Allow one explicit rollback to an older version; the override lasts until the next recorded launch
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int update_set_allow_downgrade(bool allow);
+
+/// Fetch a release feed (appcast XML or GitHub releases JSON) with ETag/If-Modified-Since
+/// caching, so unchanged feeds cost a 304. The cache lives under the store directory.
+/// `not_modified` (nullable) is set to true when the body came from the cache.
+/// Returns: response body (free with rust_string_free), or NULL on error (see last_error_message)
+char* update_fetch_feed(const char* url, bool* not_modified);
+
+/// update_fetch_feed as a background job; `completion` receives the body
+/// Returns: a job handle, or 0 on error (see last_error_message)
+ArHandle ar_fetch_feed_async(const char* url, ArJobCompletionCallback completion, void* ctx);
+
+/// Send update checks and downloads through an HTTP proxy (CONNECT for https).
+/// `host` NULL clears the proxy; `username`/`password` may be NULL for an open proxy.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int http_set_proxy(const char* host, uint16_t port, const char* username, const char* password);
+
+/// Trust the certificates in a PEM bundle in addition to the built-in roots, e.g. for
+/// TLS-inspecting corporate gateways. `path` NULL removes the custom bundle.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int http_set_ca_bundle(const char* path);
+
+/// Install an update from a local .zip/.tar.gz/.dmg for air-gapped or MDM-managed machines.
+/// `signature_b64` and `public_key_b64` are required; `sha256_hex` may be NULL to skip the
+/// checksum. The file is copied privately before it is checked, and the copy is what gets
+/// installed. The bundle must have the installed bundle's identifier and must not be older than
+/// it unless update_set_allow_downgrade was called.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int update_install_local(const char* archive_path, const char* sha256_hex, const char* signature_b64,
+                         const char* public_key_b64, const char* installed_app, bool keep_backup);
+
+/// Persist the user's update channel (an UpdateChannel other than UPDATE_CHANNEL_SAVED).
+/// Users moving from beta back to stable keep their build until a stable release at least
+/// as new ships; a skipped prerelease is forgotten so it can't hide that release.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int channel_set(int channel);
+
+/// Returns: the saved UpdateChannel (stable until channel_set is called)
+int channel_get(void);
+
+typedef enum {
+    AR_LOG_OFF = 0,
+    AR_LOG_ERROR = 1,
+    AR_LOG_WARN = 2,
+    AR_LOG_INFO = 3,
+    AR_LOG_DEBUG = 4,
+    AR_LOG_TRACE = 5,
+} ArLogLevel;
+
+/// Receives Rust-side log lines. `module` is the path inside the library (e.g. "feed");
+/// both strings are only valid during the call. May be called from any thread.
+typedef void (*ArLogCallback)(int level, const char* module, const char* message, void* ctx);
+
+/// Forward logs at `level` (an ArLogLevel) and below to `callback`; NULL stops forwarding.
+/// Per-module overrides are kept.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_log_set_callback(int level, ArLogCallback callback, void* ctx);
+
+/// Override the level for one module and its submodules (e.g. "http"); level -1 removes it.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_log_set_module_level(const char* module, int level);
+
+/// Also write logs at `level` and below to a rotating file; AR_LOG_OFF closes it.
+/// `directory` NULL means ~/Library/Logs/AudioRemote. The file rotates at `max_file_mb`
+/// (0 = 5 MB) and rotated files older than 14 days are deleted.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_log_enable_file(const char* directory, int level, uint32_t max_file_mb);
+
+/// Write buffered log lines to disk, e.g. before exporting diagnostics
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_log_flush(void);
+
+/// Remote-control server: Rust listens, routes and encodes JSON; Swift implements each endpoint.
+///   GET /api/v1/volume           GetVolume     -> {"volume": 0.0-1.0, "muted": bool}
+///   PUT /api/v1/volume           SetVolume     {"volume": 0.0-1.0} -> volume state
+///   PUT /api/v1/mute             SetMute       {"muted": bool} -> volume state
+///   GET /api/v1/devices          ListDevices   -> [{"id": uid, "name", "isCurrent": bool, "batteryPercent"?}]
+///   PUT /api/v1/devices/current  SelectDevice  {"id": uid} -> the selected device
+///   GET /api/v1/now-playing      NowPlaying    -> {"title", "artist", "album", "app", "isPlaying",
+///                                                  "elapsed", "duration"} (all but isPlaying nullable) or null
+/// GET / serves a control page for phone browsers and GET /openapi.json an OpenAPI 3 description
+/// of these routes; neither needs a token. Errors are {"error": {"status", "message"}}.
+typedef enum {
+    AR_ENDPOINT_GET_VOLUME = 0,
+    AR_ENDPOINT_SET_VOLUME = 1,
+    AR_ENDPOINT_SET_MUTE = 2,
+    AR_ENDPOINT_LIST_DEVICES = 3,
+    AR_ENDPOINT_SELECT_DEVICE = 4,
+    AR_ENDPOINT_NOW_PLAYING = 5,
+} ArServerEndpoint;
+
+/// Called for each request with its handle and validated parameters (JSON, {} for GETs; only
+/// valid during the call). Answer within 5 s with ar_server_respond or ar_server_reject, from
+/// any thread; unanswered requests get 504.
+typedef void (*ArServerHandler)(ArHandle request, int endpoint, const char* params_json, void* ctx);
+
+/// Start the server. `config_json` may be NULL; keys: "port" (default 8765, 0 = any free port),
+/// "bindAddress" (default "::", IPv4 and IPv6), "requireAuth" (default true: refuse API requests without
+/// a paired remote's access token; false only for development), "tls" (default false: serve HTTPS and WSS with this Mac's
+/// self-signed certificate, see ar_tls_fingerprint), "rateLimit" {"requestsPerSecond" (default 10,
+/// 0 = off), "burst" (default 30) per paired remote or address, "maxAuthFailures" (default 10,
+/// 0 = never), "failureWindowSecs" (default 60), "lockoutSecs" (default 300) per address}.
+/// Clients over the limit or locked out get 429 with Retry-After.
+/// Returns: the port listened on, -999 on error (see last_error_message)
+int ar_server_start(const char* config_json);
+
+/// Stop the server; requests waiting on a handler get 503. Also done by ar_shutdown.
+void ar_server_stop(void);
+
+/// Returns: the port the server listens on, 0 if it isn't running
+int ar_server_port(void);
+
+/// Register the handler for an ArServerEndpoint (NULL removes it; its routes answer 501).
+/// Handlers run on the ar_runtime_set_callback_queue queue, or the connection's thread.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_server_set_handler(int endpoint, ArServerHandler handler, void* ctx);
+
+/// Answer a request with the endpoint's result JSON (see above). A result that doesn't match
+/// is answered with 500 and reported as an error here.
+/// Returns: 1 on success, -999 on error, e.g. the request already timed out (see last_error_message)
+int ar_server_respond(ArHandle request, const char* result_json);
+
+/// Answer a request with an HTTP error status (400-599) and a message (NULL for the default)
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_server_reject(ArHandle request, int status, const char* message);
+
+/// State pushed to remotes over WebSocket at /api/v1/events[?topics=volume,devices,nowPlaying].
+/// Clients get {"topic": name, "data": state} per change, starting with the current state, and
+/// can send {"subscribe": [names]} to change their topics. With ?format=msgpack, messages are
+/// binary MessagePack envelopes instead (see ar_protocol_encode). Clients without WebSocket can
+/// GET /api/v1/events/stream[?topics=...] for the same state as server-sent events,
+/// "event: <topic>" and "data: <state>" per change.
+typedef enum {
+    AR_TOPIC_VOLUME = 0,        // "volume": VolumeState
+    AR_TOPIC_DEVICES = 1,       // "devices": [OutputDevice]
+    AR_TOPIC_NOW_PLAYING = 2,   // "nowPlaying": NowPlaying or null
+} ArStateTopic;
+
+/// Publish the new state of an ArStateTopic, in the same JSON shape as the REST result.
+/// Call on every change from any thread; it never blocks on slow remotes, which only get
+/// the newest value.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_state_publish(int topic, const char* state_json);
+
+/// Advertise the server over Bonjour as _audioremote._tcp, replacing any running advertisement.
+/// `config_json`: {"name": shown to remotes, "version": app version, "port": n (default: the
+/// running server's), "capabilities": [strings] (default volume, mute, devices, nowPlaying,
+/// events)}. The TXT record carries txtvers, version, name, caps (comma-separated) and path.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_mdns_start(const char* config_json);
+
+/// Withdraw the advertisement (remotes see it disappear at once). Also done by ar_shutdown.
+void ar_mdns_stop(void);
+
+/// Returns: the advertised name, e.g. "Studio Mac (2)" if the configured one was taken;
+/// NULL if not advertising (free with rust_string_free)
+char* ar_mdns_name(void);
+
+/// What happened to a peer Mac found by ar_browse_start
+typedef enum {
+    AR_PEER_ADDED = 0,
+    AR_PEER_UPDATED = 1,     // port, addresses or TXT changed
+    AR_PEER_REMOVED = 2,     // said goodbye or its records expired
+} ArPeerEvent;
+
+/// `peer_json`: {"name", "host", "port", "addresses": [strings, IPv4 first, link-local IPv6 with
+/// its zone like "fe80::1%en0"], "version", "capabilities": [strings], "txt": {}, "isSelf"}.
+/// Only valid during the call.
+typedef void (*ArPeerCallback)(int event, const char* peer_json, void* ctx);
+
+/// Browse for other Macs advertising _audioremote._tcp, replacing a running browser.
+/// `callback` runs on the ar_runtime_set_callback_queue queue, or the browser's thread.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_browse_start(ArPeerCallback callback, void* ctx);
+
+/// Stop browsing; `ctx` may be released afterwards. Also done by ar_shutdown.
+void ar_browse_stop(void);
+
+/// Returns: JSON array of the peers resolved so far, NULL if not browsing (free with rust_string_free)
+char* ar_browse_peers(void);
+
+/// UDP discovery, the fallback for networks that filter mDNS: remotes broadcast a query to
+/// AR_DISCOVERY_PORT (and the 239.255.77.77 group); the Mac answers with its service info,
+/// signed with its TLS certificate's key over the query's nonce.
+#define AR_DISCOVERY_PORT 47809
+
+/// Start answering discovery queries, and taking signed LAN commands, replacing a running responder.
+/// `config_json` is the same as ar_mdns_start's. Returns: 1 on success, -999 on error (see last_error_message)
+int ar_discovery_start(const char* config_json);
+
+/// Stop answering discovery queries. Also done by ar_shutdown.
+void ar_discovery_stop(void);
+
+/// Query for Macs, waiting `timeout_ms` (blocks; call off the main thread). Only answers whose
+/// signature checks out are listed; trust one if its "certFingerprint" is the pinned one.
+/// Returns: JSON array of {"info": {"name", "macId", "host", "port", "version", "capabilities",
+/// "tls"}, "address", "certFingerprint", "isSelf"} (free with rust_string_free), NULL on error
+char* ar_discovery_probe(uint32_t timeout_ms);
+
+/// Wake a sleeping Mac with a Wake-on-LAN magic packet. `mac_address` is its hardware address
+/// ("a4:83:e7:12:34:56", dashes, Cisco dots or bare hex); `broadcast_addr` (nullable) is an IPv4
+/// broadcast address with an optional port, default "255.255.255.255:9". Whether the Mac woke
+/// can't be told from here; wait for it to answer before sending commands.
+/// Returns: 1 if the packet was sent, -999 on error (see last_error_message)
+int ar_send_wol(const char* mac_address, const char* broadcast_addr);
+
+/// Signed LAN commands: a paired remote can send a command to AR_DISCOVERY_PORT without a
+/// connection, e.g. right after waking the Mac. Each is signed with an HMAC keyed from the pairing
+/// key and carries a timestamp and nonce; unsigned, stale (over 30 s off) and replayed commands
+/// are dropped without an answer. Answers are signed the same way.
+
+/// Called with the remote's ID (only valid during the call) when it asks to wake the display
+typedef void (*ArLanWakeHandler)(const char* remote_id, void* ctx);
+
+/// Register the handler for signed "wake" commands from paired remotes, e.g. to wake the display;
+/// NULL removes it, and wake commands are answered as unsupported. It runs on the callback queue
+/// set with ar_runtime_set_callback_queue.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_lan_set_wake_handler(ArLanWakeHandler handler, void* ctx);
+
+/// Send a command to a paired Mac's discovery port, signed with the pairing key, e.g. right after
+/// waking it with ar_send_wol. `request_json`: {"host", "port" (default: the discovery port),
+/// "remoteId", "key" (the pairing key, base64), "method" ("volume.set", "mute.set", "wake", or
+/// another local control method), "params", "timeoutMs" (default 2000)}.
+/// Returns: the answer's result as JSON (free with rust_string_free), NULL on error or when
+/// the Mac refused the command (see last_error_message)
+char* ar_lan_command_send(const char* request_json);
+
+/// A supervised connection to another Mac's event channel: pings when it's quiet, drops the
+/// connection when nothing arrives for a while, and reconnects with exponential backoff.
+typedef enum {
+    AR_CONNECTION_CONNECTING = 0,    // the first attempt
+    AR_CONNECTION_CONNECTED = 1,
+    AR_CONNECTION_RECONNECTING = 2,  // lost; detail has "attempt", "retryInMs" and "error"
+    AR_CONNECTION_FAILED = 3,        // refused for good (e.g. 401, wrong certificate); detail has "error", "status"
+    AR_CONNECTION_CLOSED = 4,        // by ar_connection_close
+} ArConnectionState;
+
+/// The detail JSON and the bytes are only valid during the call
+typedef void (*ArConnectionStateCallback)(uint64_t connection, int state, const char* detail_json, void* ctx);
+typedef void (*ArConnectionMessageCallback)(uint64_t connection, const uint8_t* bytes, size_t len, int binary, void* ctx);
+
+/// Connect and stay connected. `config_json`: {"host" (a name or an IP, link-local IPv6 with its
+/// zone, e.g. "fe80::1%en0"), "port", "certFingerprint" (optional: TLS,
+/// trusting only that certificate), "accessToken", "format" ("json"/"msgpack"), "topics": [names],
+/// "pingIntervalMs" (15000), "deadAfterMs" (45000), "probeIntervalMs" (2000, 0: off; see
+/// ar_connection_stats), "initialBackoffMs" (500), "maxBackoffMs" (30000)}.
+/// Callbacks run on the ar_runtime_set_callback_queue queue, or the connection's thread;
+/// `on_message` may be NULL. `ctx` must stay valid until ar_connection_close.
+/// Returns: the connection handle, 0 on error (see last_error_message)
+uint64_t ar_connection_open(const char* config_json, ArConnectionStateCallback on_state,
+                            ArConnectionMessageCallback on_message, void* ctx);
+
+/// Returns: the ArConnectionState, -999 on error (see last_error_message)
+int ar_connection_state(uint64_t connection);
+
+/// Round-trip probes of the current session, for a signal-quality indicator and latency
+/// compensation. They start over on each reconnect.
+/// Returns: JSON {"state": ArConnectionState, "rttMs" (the last probe's), "smoothedRttMs",
+/// "jitterMs" (round-trip variation), "minRttMs", "lossPercent" (probes unanswered within 5 s, of
+/// the last 50), "probesSent", "probesLost", "quality" ("good", "fair" or "poor")}, values null
+/// until the first probe settles (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_connection_stats(uint64_t connection);
+
+/// Send a text or (binary nonzero) binary message; nothing is queued while disconnected.
+/// Returns: 1 if sent, -999 if not connected or on error (see last_error_message)
+int ar_connection_send(uint64_t connection, const uint8_t* bytes, size_t len, int binary);
+
+/// Use a new access token (NULL for none) from the next reconnection on, e.g. after a refresh.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_connection_set_token(uint64_t connection, const char* token);
+
+/// Close and free the handle; the last callback is AR_CONNECTION_CLOSED. Also done by ar_shutdown.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_connection_close(uint64_t connection);
+
+/// Connect to an MQTT broker and mirror state to it, replacing a running client. State goes to
+/// <prefix>/volume, /devices and /nowPlaying (retained), "online"/"offline" to <prefix>/status,
+/// and commands are taken on <prefix>/volume/set, /mute/set and /device/set, answered by the
+/// request handler like REST requests. `config_json`: {"host", "port" (1883, 8883 with TLS),
+/// "clientId", "username", "password", "tls" (false), "certFingerprint" (pin the broker's
+/// certificate), "caFile" (PEM bundle of extra CAs), "topicPrefix" ("audioremote/<host>"),
+/// "keepAliveSecs" (30), "retain" (true), "initialBackoffMs" (1000), "maxBackoffMs" (60000),
+/// "homeAssistant" (false), "discoveryPrefix" ("homeassistant")}. With "homeAssistant" the Mac
+/// also publishes Home Assistant discovery configs (volume number, mute switch, output select,
+/// media player) and shows up there as a device. Runs until ar_mqtt_stop, reconnecting with backoff.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_mqtt_start(const char* config_json);
+
+/// Publish "offline", disconnect and stop reconnecting; a no-op if not running. Also done by ar_shutdown.
+void ar_mqtt_stop(void);
+
+/// Returns: JSON {"state": ArConnectionState, "attempt", "error", "topicPrefix"}, state
+/// AR_CONNECTION_CLOSED when not started (free with rust_string_free)
+char* ar_mqtt_status(void);
+
+/// HomeKit: the Mac as a speaker accessory the Home app and Siri can set the volume of and mute.
+/// Controllers pair with the 8-digit setup code (show it, or a QR code of it, while unpaired);
+/// after that every connection is encrypted. Mute and volume writes arrive as SetMute and SetVolume
+/// server requests, so install handlers with ar_server_set_handler first.
+
+/// Serve the Mac as a HomeKit speaker, replacing a running accessory. `config_json`: {"name",
+/// "setupCode" ("XXX-XX-XXX"; default: made up once and kept, see ar_hap_status), "port" (0: any
+/// free port), "advertise" (true: advertise _hap._tcp over Bonjour)}.
+/// Returns: the port listened on, -999 on error (see last_error_message)
+int32_t ar_hap_start(const char* config_json);
+
+/// Stop serving and advertising the accessory; pairings are kept. A no-op if not running. Also done by ar_shutdown.
+void ar_hap_stop(void);
+
+/// Returns: JSON {"running", "port", "accessoryId", "setupCode" (to show the user while
+/// unpaired), "paired", "controllers": [{"id", "admin"}]} (free with rust_string_free)
+char* ar_hap_status(void);
+
+/// Forget every paired controller and end their sessions, so the accessory can be added to a home again.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int32_t ar_hap_reset_pairings(void);
+
+/// Answer SSDP searches and serve a UPnP device description and a DIAL application, so control
+/// points and TVs find the Mac. `config_json`: {"name", "port" (default: the running server's)}.
+/// Replaces a running responder.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int32_t ar_ssdp_start(const char* config_json);
+
+/// Announce ssdp:byebye and stop answering; a no-op if not running. Also done by ar_shutdown.
+void ar_ssdp_stop(void);
+
+/// Noise channel: a raw TCP transport for paired remotes, Noise_XXpsk3_25519_ChaChaPoly_SHA256
+/// with the pre-shared key derived from the remote's pairing key. Messages are a 2-byte
+/// big-endian length and a Noise message; after the handshake each carries one protocol envelope.
+
+/// Listen for Noise-encrypted connections from paired remotes, an alternative to the server's TLS
+/// for remotes that can't afford it. `config_json` (nullable): {"port" (0: any free port),
+/// "bindAddress" ("::", IPv4 and IPv6)}. Advertise the port to remotes yourself, e.g. in the mDNS capabilities.
+/// Returns: the port listened on, -999 on error (see last_error_message)
+int32_t ar_noise_start(const char* config_json);
+
+/// Stop listening and close every Noise channel; a no-op if not running. Also done by ar_shutdown.
+void ar_noise_stop(void);
+
+/// Relay: control from outside the LAN. The Mac keeps a WebSocket over TLS open to
+/// <url>/mac/<relayId>, remotes connect to <url>/remote/<relayId>, and the relay passes each
+/// remote's Noise channel through end to end, so it only sees ciphertext. Frames to and from the
+/// Mac are a 4-byte big-endian channel number and a Noise message; an empty message closes the channel.
+
+/// Keep a connection to a relay server open so paired remotes can reach this Mac from anywhere,
+/// replacing a running client. `config_json`: {"url" ("wss://host[:port][/path]"), "token"
+/// (bearer token for the relay), "certFingerprint" (pin the relay's certificate), "caFile" (PEM
+/// bundle of extra CAs), "initialBackoffMs" (1000), "maxBackoffMs" (60000)}. Runs until
+/// ar_relay_stop, reconnecting.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int32_t ar_relay_start(const char* config_json);
+
+/// Disconnect from the relay and stop reconnecting; a no-op if not running. Also done by ar_shutdown.
+void ar_relay_stop(void);
+
+/// Returns: JSON {"state": ArConnectionState, "attempt", "error", "relayId" (what remotes connect
+/// to; null before pairing is set up), "channels" (remotes connected through the relay)}, state
+/// AR_CONNECTION_CLOSED when not started (free with rust_string_free)
+char* ar_relay_status(void);
+
+/// WebRTC: a direct data channel to a remote outside the LAN where NATs allow one. The remote's
+/// offer and the Mac's answer travel by any signaling channel; the data channel carries a Noise
+/// channel as on the relay, one Noise message per data-channel message.
+
+/// Answer a remote's WebRTC offer, for a direct channel when it can't reach the Mac's server,
+/// e.g. on a cellular network. Carry the offer and answer over any channel you have; the data
+/// channel runs the Noise handshake (see ar_noise_start) before anything else, so only a paired
+/// remote gets through. `offer_json`: {"type": "offer", "sdp"}, as RTCPeerConnection makes it,
+/// with one data channel and no trickled candidates. `config_json` (nullable): {"stunServers"
+/// (["host[:port]"]: servers to learn the Mac's public address from, port 3478 by default)}.
+/// Returns: the answer JSON {"type": "answer", "sdp"} (free with rust_string_free), NULL on error
+/// (see last_error_message)
+char* ar_webrtc_answer(const char* offer_json, const char* config_json);
+
+/// Close every WebRTC peer connection; the remotes may offer again. Also done by ar_shutdown.
+void ar_webrtc_stop(void);
+
+/// Port mapping: remote access without a relay, for routers that forward ports on request. PCP,
+/// NAT-PMP and UPnP-IGD are tried in turn; the mapping is renewed while it runs and deleted on stop.
+
+/// Ask the router to forward a port to the server, for remote access without a relay, replacing
+/// a running mapping. `config_json`: {"port" (default: the running server's), "externalPort"
+/// (default: port; the router may grant another), "methods" (["pcp", "natPmp", "upnp"], tried in
+/// order), "gateway" (IPv4, default: the default route's), "lifetimeSecs" (7200, at least 120)}.
+/// Runs until ar_portmap_stop, renewing at half the lifetime and retrying failures with backoff.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int32_t ar_portmap_start(const char* config_json);
+
+/// Delete the mapping from the router and stop renewing it; a no-op if not running. Also done by
+/// ar_shutdown.
+void ar_portmap_stop(void);
+
+/// Returns: JSON {"state": ArConnectionState (AR_CONNECTION_CONNECTED while mapped), "attempt",
+/// "error", "method" ("pcp", "natPmp" or "upnp"), "externalAddress" (what remotes outside connect
+/// to; null if the router won't say), "externalPort", "internalPort", "lifetimeSecs" (0: permanent)},
+/// the mapping fields null while unmapped (free with rust_string_free)
+char* ar_portmap_status(void);
+
+/// `host` (a name, an IP, or link-local IPv6 with its zone as in peer addresses) for a URL:
+/// "fe80::1%en0" becomes "[fe80::1%25en0]", "2001:db8::1" "[2001:db8::1]"; names are unchanged.
+/// Returns: the host (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_url_host(const char* host);
+
+/// gRPC: the REST API's operations as the AudioRemote service in audioremote.proto (shipped in
+/// RustFFI/proto), for integrations that generate typed clients. Calls take the access token as
+/// "authorization: Bearer" metadata and reach the ar_server_set_handler handlers; HTTP error
+/// statuses arrive as the matching gRPC codes.
+
+/// Serve the REST API's operations over gRPC as well, for clients generated from the
+/// audioremote.proto shipped with the library. `config_json` (nullable): {"port" (0: any free
+/// port), "bindAddress" ("::", IPv4 and IPv6), "requireAuth" (refuse calls without an access token)}.
+/// Calls reach the ar_server_set_handler handlers, whether or not the HTTP server is running.
+/// Returns: the port listened on, -999 on error (see last_error_message)
+int32_t ar_grpc_start(const char* config_json);
+
+/// Stop the gRPC service; a no-op if not running. Also done by ar_shutdown.
+void ar_grpc_stop(void);
+
+/// Local control: JSON-RPC 2.0 on a Unix domain socket, one message per line, for scripts, a CLI
+/// or a helper app on this Mac. The REST API's operations are methods there, next to the
+/// ar_dispatch ones; errors carry the HTTP status or AudioRemoteError code in "data".
+
+/// Open a Unix domain socket for local control by scripts, a CLI or a helper app, speaking
+/// JSON-RPC 2.0 with one message per line. Methods: volume.get, volume.set {"volume"}, mute.set
+/// {"muted"}, devices.list, devices.select {"id"}, nowPlaying.get (answered by the
+/// ar_server_set_handler handlers) and the ar_dispatch methods. There's no authentication; the
+/// socket is owner-only. `config_json` (nullable): {"path" (default: control.sock in the store directory)}.
+/// Returns: the socket's path (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_ipc_start(const char* config_json);
+
+/// Close the local control socket and its connections; a no-op if not open. Also done by ar_shutdown.
+void ar_ipc_stop(void);
+
+/// Commands that apply once however often they're resent: send state changes to a Mac with an
+/// X-Command-Id: <client>:<seq> header and resend until answered. The Mac replays the answer to
+/// a repeat (with X-Command-Replayed: true) and answers 409 while an earlier one is unanswered.
+/// A command queue does the numbering, ordering and retry timing for a remote.
+
+/// Returns: a new queue with a fresh client ID, 0 on error (see last_error_message)
+uint64_t ar_command_queue_new(void);
+
+/// Queue a command, e.g. ("PUT", "/api/v1/volume", "{\"volume\": 0.5}"); `body_json` may be NULL.
+/// Returns: its sequence number, -999 on error, e.g. the queue is full (see last_error_message)
+int64_t ar_command_queue_push(uint64_t queue, const char* method, const char* path, const char* body_json);
+
+/// Take the command to send now, one at a time, in order: {"seq", "method", "path", "body",
+/// "commandId" (the X-Command-Id value), "attempt"}.
+/// Returns: the JSON (free with rust_string_free), NULL when nothing is due or on error
+char* ar_command_queue_next(uint64_t queue);
+
+/// Report the HTTP status the command got, 0 if there was none (network error, timeout).
+/// 409 and 5xx are retried; any other status is an answer.
+/// Returns: 0 when the command is done, else milliseconds until it's due again, -999 on error
+int64_t ar_command_queue_result(uint64_t queue, uint64_t seq, int http_status);
+
+/// Returns: commands waiting or in flight, -999 on error (see last_error_message)
+int ar_command_queue_len(uint64_t queue);
+
+/// Drop a queue and its commands. Returns: 1 on success, -999 on error (see last_error_message)
+int ar_command_queue_free(uint64_t queue);
+
+/// Pairing: the Mac shows a PIN, the remote runs a SPAKE2 exchange over POST /api/v1/pair/start
+/// and /api/v1/pair/finish, and both sides derive a long-term key. Neither the PIN nor the key is
+/// sent over the network.
+typedef enum {
+    AR_PAIRING_PAIRED = 0,   // a remote paired; the window is closed
+    AR_PAIRING_FAILED = 1,   // a remote got the PIN wrong
+    AR_PAIRING_LOCKED = 2,   // too many wrong PINs; the window is closed
+} ArPairingEvent;
+
+/// `json`: {"remoteId", "remoteName"}, only valid during the call
+typedef void (*ArPairingCallback)(int event, const char* json, void* ctx);
+
+/// Open a pairing window for 5 minutes, replacing any open one. `callback` (nullable) runs on
+/// the ar_runtime_set_callback_queue queue, or the server's thread.
+/// Returns: the 6-digit PIN to show (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_pairing_begin(ArPairingCallback callback, void* ctx);
+
+/// Close the pairing window; the PIN stops working at once. Also done by ar_shutdown.
+void ar_pairing_cancel(void);
+
+/// The open window's pairing payload for the remote to scan instead of typing the PIN:
+/// audioremote://pair?v=1&mac=<macId>&host=<Bonjour name>&addr=<IP>&port=<n>&fp=<certificate
+/// SHA-256, over HTTPS only>&code=<PIN>
+/// Returns: the URI (free with rust_string_free), NULL if the server isn't running or no
+/// pairing window is open (see last_error_message)
+char* ar_pairing_payload(void);
+
+/// The same payload as a black-on-white QR code PNG, `scale` pixels per module (0 = 8, max 64)
+/// Returns: the PNG (free with ar_bytes_free, passing `out_len`), NULL on error (see last_error_message)
+uint8_t* ar_pairing_qr_png(uint32_t scale, size_t* out_len);
+
+/// Paired remotes sign in with `Authorization: Bearer <accessToken>` (?access_token= only when opening a
+/// WebSocket). Pairing returns the first tokens; POST /api/v1/auth/refresh
+/// {"refreshToken"} rotates them, and POST /api/v1/auth/token {"remoteId", "timestamp", "proof"}
+/// signs in again with the pairing key.
+
+/// Returns: JSON array of paired remotes, [{"remoteId", "remoteName", "pairedAt", "signedInUntil",
+/// "scope"}] in Unix time, signedInUntil null if it has to sign in again (free with rust_string_free)
+char* ar_auth_remotes(void);
+
+/// Unpair a remote and revoke its tokens; it has to pair again.
+/// Returns: 1 if it was paired, 0 if not, -999 on error (see last_error_message)
+int ar_auth_revoke(const char* remote_id);
+
+/// What a paired remote may do; each scope allows everything the one before it does.
+/// Refused requests get 403, and WebSocket clients only see the topics their scope covers.
+/// In JSON: "volumeOnly", "playback", "fullControl", "admin".
+typedef enum {
+    AR_SCOPE_VOLUME_ONLY = 0,    // volume and mute
+    AR_SCOPE_PLAYBACK = 1,       // also now playing
+    AR_SCOPE_FULL_CONTROL = 2,   // also output devices; the default, and clients without a token
+    AR_SCOPE_ADMIN = 3,          // everything
+} ArScope;
+
+/// Returns: JSON array of live sessions (remotes seen in the last 5 minutes or with an open
+/// WebSocket), most recent first: [{"remoteId" (null without a token), "name", "address",
+/// "scope", "connectedSince", "lastSeen", "requests", "streams"}] in Unix time
+/// (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_sessions(void);
+
+/// Set a paired remote's ArScope; it applies from its next request.
+/// Returns: 1 if it's paired, 0 if not, -999 on error (see last_error_message)
+int ar_session_set_scope(const char* remote_id, int scope);
+
+/// Returns: SHA-256 of this Mac's self-signed TLS certificate as lowercase hex, created on first
+/// use and kept in the store directory. Remotes pin it; pairing over HTTPS hands it over as
+/// "certFingerprint", covered by the Mac's key confirmation. NULL on error (free with rust_string_free)
+char* ar_tls_fingerprint(void);
+
+/// The remote protocol's MessagePack envelopes, {"v": version, "id": optional, "type", "body"}.
+/// Types: getVolume, setVolume {volume}, setMute {muted}, listDevices, selectDevice {id},
+/// getNowPlaying, subscribe {topics}, volume VolumeState, devices [OutputDevice],
+/// nowPlaying NowPlaying/null, error {status, message}.
+/// A remote opens with hello {minVersion, maxVersion, features}; the Mac answers welcome
+/// {version, features, mac: its hello} with the highest common version and the shared
+/// features. What a session can't handle is answered unsupported {type, message}.
+#define AR_PROTOCOL_VERSION 1
+#define AR_PROTOCOL_MIN_VERSION 1
+
+/// Feature bits in hello and welcome
+#define AR_FEATURE_EVENTS    (1u << 0)
+#define AR_FEATURE_SUBSCRIBE (1u << 1)
+#define AR_FEATURE_COMMANDS  (1u << 2)
+/// Envelopes over 1 KiB may be sent as zstd frames; decoding detects them either way
+#define AR_FEATURE_COMPRESSION (1u << 3)
+/// Audio is streamed as FLAC (ar_flac_*) rather than Opus; a remote sets it in its hello to ask
+/// for bit-perfect monitoring
+#define AR_FEATURE_LOSSLESS (1u << 4)
+
+/// Encode an envelope given as JSON; bodies are validated and unknown types refused.
+/// Returns: the bytes, length in `out_len` (free with ar_bytes_free), NULL on error (see last_error_message)
+uint8_t* ar_protocol_encode(const char* envelope_json, size_t* out_len);
+
+/// Decode a MessagePack envelope, plain or zstd-compressed, into JSON. Unknown types (from newer remotes) are passed
+/// through with their body, to be answered as unsupported.
+/// Returns: the JSON (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_protocol_decode(const uint8_t* bytes, size_t len);
+
+/// Negotiate with a remote's hello JSON {"minVersion", "maxVersion", "features"}.
+/// Returns: the session JSON {"version", "features"} (free with rust_string_free), NULL if
+/// there's no common version or on error (see last_error_message)
+char* ar_protocol_negotiate(const char* hello_json);
+
+/// Volume tapers: a perceptual curve between a slider's position and the gain it sets, so
+/// each step of a remote's slider or volume keys sounds like the same change. Positions and
+/// gains are 0.0–1.0 (clamped); dB are 0 at full scale and -INFINITY for silence.
+/// `taper_json` (NULL: the defaults): {"curve": "exponential" (default: rangeDb over the
+/// slider, fading linearly to silence in its bottom tenth) | "power" (gain = position^exponent)
+/// | "linear", "rangeDb" (default 60), "exponent" (default 3), "steps" (default 16)}.
+typedef enum {
+    AR_VOLUME_POSITION = 0,
+    AR_VOLUME_GAIN = 1,
+    AR_VOLUME_DB = 2,
+} ArVolumeScale;
+
+/// Convert a volume from one ArVolumeScale to another
+/// Returns: 1 with the result in `out`, -999 on error (see last_error_message)
+int ar_volume_convert(double value, int from, int to, const char* taper_json, double* out);
+
+/// The gain after `steps` presses of a volume key (negative: down) from `gain`, counted from
+/// the nearest of the taper's steps so presses always land on the same gains
+/// Returns: 1 with the new gain in `out`, -999 on error (see last_error_message)
+int ar_volume_step(double gain, int steps, const char* taper_json, double* out);
+
+/// Level meters for live meters on a remote, fed from the audio tap: each channel has a peak
+/// and an RMS level (as gains, over 1.0 when clipping; ar_volume_convert gives dB) that rise
+/// with the attack time and fall with the release time.
+
+/// Create a meter. `config_json` (nullable): {"channels" (default 2), "sampleRate" (default
+/// 48000), "attackMs" (default 0: catch every peak), "releaseMs" (default 300), "rmsWindowMs"
+/// (the RMS averaging time, default 300)}.
+/// Returns: a handle (free with ar_meter_free), 0 on error (see last_error_message)
+ArHandle ar_meter_new(const char* config_json);
+
+/// Meter `frames` frames of interleaved float samples (frames × channels floats)
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_meter_process(ArHandle meter, const float* samples, size_t frames);
+
+/// Read up to `capacity` channels' levels into `out_peaks` and `out_rms`
+/// Returns: the meter's channel count, -999 on error (see last_error_message)
+int ar_meter_levels(ArHandle meter, float* out_peaks, float* out_rms, size_t capacity);
+
+/// Drop the levels to silence, e.g. when the tap restarts. Returns: 1 on success, -999 on error
+int ar_meter_reset(ArHandle meter);
+
+/// Release a meter. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_meter_free(ArHandle meter);
+
+/// Spectrum analyzers for visualizers on a remote, fed from the audio tap: the channels are
+/// mixed to mono and every fftSize / 2 frames the latest fftSize frames are Hann-windowed,
+/// transformed and gathered into log-spaced bands. Band levels are 0.0–1.0 over floorDb–0 dB
+/// (a full-scale sine is 0 dB); they rise at once and fall back by `smoothing` each update.
+
+/// Create an analyzer. `config_json` (nullable): {"channels" (default 2), "sampleRate" (default
+/// 48000), "fftSize" (a power of two, default 2048), "bands" (default 32), "minHz" (default 20),
+/// "maxHz" (default 20000, at most half the sample rate), "smoothing" (the share of the last
+/// level kept, 0–1, default 0.7), "floorDb" (default -90)}.
+/// Returns: a handle (free with ar_spectrum_free), 0 on error (see last_error_message)
+ArHandle ar_spectrum_new(const char* config_json);
+
+/// Add `frames` frames of interleaved float samples (frames × channels floats)
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_spectrum_process(ArHandle analyzer, const float* samples, size_t frames);
+
+/// Read up to `capacity` band levels, lowest band first, into `out_bands`
+/// Returns: the analyzer's band count, -999 on error (see last_error_message)
+int ar_spectrum_bands(ArHandle analyzer, float* out_bands, size_t capacity);
+
+/// Drop the audio and levels, e.g. when the tap restarts. Returns: 1 on success, -999 on error
+int ar_spectrum_reset(ArHandle analyzer);
+
+/// Release an analyzer. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_spectrum_free(ArHandle analyzer);
+
+/// Loudness meters, measuring LUFS as in ITU-R BS.1770-4 / EBU R128 for the loudness display
+/// and auto-leveling: momentary (the last 400 ms), short-term (the last 3 s) and integrated
+/// (everything since the meter was created or reset, gated at -70 LUFS and 10 LU below).
+
+/// Create a meter. `config_json` (nullable): {"channels" (default 2), "sampleRate" (default
+/// 48000), "channelWeights" (one per channel; default 1.0 each, for six channels the 5.1
+/// weights in L R C LFE Ls Rs order: surrounds 1.41, LFE 0)}.
+/// Returns: a handle (free with ar_loudness_free), 0 on error (see last_error_message)
+ArHandle ar_loudness_new(const char* config_json);
+
+/// Measure `frames` frames of interleaved float samples (frames × channels floats)
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_loudness_process(ArHandle meter, const float* samples, size_t frames);
+
+/// Read the momentary, short-term and integrated loudness in LUFS into the non-NULL out
+/// pointers; each is -INFINITY until there's enough audio for it, or while it's silent.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_loudness_read(ArHandle meter, double* out_momentary, double* out_short_term, double* out_integrated);
+
+/// Read the highest true peak (BS.1770-4, 4× oversampled, so inter-sample peaks count) since the
+/// meter was created or reset, over all channels, in dBTP; -INFINITY while it's been silent
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_loudness_true_peak(ArHandle meter, double* out_db);
+
+/// Start the measurement over, e.g. for a new track. Returns: 1 on success, -999 on error
+int ar_loudness_reset(ArHandle meter);
+
+/// Release a meter. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_loudness_free(ArHandle meter);
+
+/// A parametric EQ for the selected output device, run over the audio tap's buffers in place:
+/// a chain of biquad bands with Audio EQ Cookbook coefficients. A band is {"type": "peaking" |
+/// "lowShelf" | "highShelf" | "lowPass" | "highPass", "frequency" (Hz), "gainDb" (-30–30, not
+/// used by the passes), "q" (default 0.7071), "enabled" (default true)}.
+
+/// Compute one band's coefficients at `sample_rate`
+/// Returns: JSON {"b": [b0, b1, b2], "a": [a1, a2]}, a0 normalized to 1 (free with
+/// rust_string_free), NULL on error (see last_error_message)
+char* ar_eq_coefficients(const char* band_json, double sample_rate);
+
+/// Create an equalizer. `config_json`: {"channels" (default 2), "sampleRate" (default 48000),
+/// "bands" (up to 32, applied in order), "preampDb" (default 0; negative leaves room for boosts)}.
+/// Returns: a handle (free with ar_eq_free), 0 on error (see last_error_message)
+ArHandle ar_eq_new(const char* config_json);
+
+/// Replace the config, as for ar_eq_new. The filters keep their state if the channels, sample
+/// rate and number of enabled bands are the same, so moving a band doesn't click.
+/// Returns: 1 on success, -999 on error, keeping the old config (see last_error_message)
+int ar_eq_update(ArHandle equalizer, const char* config_json);
+
+/// Filter `frames` frames of interleaved float samples (frames × channels floats) in place
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_eq_process(ArHandle equalizer, float* samples, size_t frames);
+
+/// Write the equalizer's gain in dB at each of `count` frequencies to `out_db`, to draw its curve
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_eq_response(ArHandle equalizer, const double* frequencies, double* out_db, size_t count);
+
+/// Clear the filters' state, e.g. when the tap restarts. Returns: 1 on success, -999 on error
+int ar_eq_reset(ArHandle equalizer);
+
+/// Release an equalizer. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_eq_free(ArHandle equalizer);
+
+/// Sample rate converters, for streaming to remotes when the device's rate isn't the stream's:
+/// a polyphase windowed-sinc filter stepping in exact fractions, so it never drifts. Output is
+/// aligned with input but lags it by the filter's half-length (8, 16 or 32 input frames for low,
+/// medium, high); flush at the end of a stream to get the rest out.
+
+/// Create a converter. `config_json`: {"channels" (default 2), "inputRate", "outputRate",
+/// "quality": "low" | "medium" | "high" (default)}.
+/// Returns: a handle (free with ar_resampler_free), 0 on error (see last_error_message)
+ArHandle ar_resampler_new(const char* config_json);
+
+/// Push `frames` frames of interleaved float samples (frames × channels floats) at the input rate.
+/// Refused (AR_ERROR_REFUSED) while 10 s of output are waiting to be pulled.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_resampler_push(ArHandle resampler, const float* samples, size_t frames);
+
+/// Pull up to `capacity` frames of converted interleaved samples at the output rate into `out`
+/// Returns: the frames written, 0 if none are ready, -999 on error (see last_error_message)
+int64_t ar_resampler_pull(ArHandle resampler, float* out, size_t capacity);
+
+/// Returns: converted frames waiting to be pulled, -999 on error (see last_error_message)
+int64_t ar_resampler_available(ArHandle resampler);
+
+/// Convert the end of the input when a stream stops. Returns: 1 on success, -999 on error
+int ar_resampler_flush(ArHandle resampler);
+
+/// Drop all input and output, e.g. between streams. Returns: 1 on success, -999 on error
+int ar_resampler_reset(ArHandle resampler);
+
+/// Release a converter. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_resampler_free(ArHandle resampler);
+
+/// Channel mixers: a matrix of gains from each input channel to each output channel, for
+/// folding 5.1 to stereo, stereo to a single speaker, and the "mono audio" setting. Without a
+/// matrix the usual mix for the channel counts is used: 5.1 (L R C LFE Ls Rs) to stereo with
+/// centre and surrounds at -3 dB and no LFE, anything to mono as the average, mono to the front
+/// pair, and fewer channels to more one to one.
+
+/// Create a mixer. `config_json`: {"inputChannels", "outputChannels", "matrix" (one row of input
+/// gains per output channel), "mono" (the mono mix on every output channel), "normalize" (default
+/// true: outputs whose gains add up to more than 1 are scaled down so they can't clip)}.
+/// Returns: a handle (free with ar_mixer_free), 0 on error, e.g. no usual mix (see last_error_message)
+ArHandle ar_mixer_new(const char* config_json);
+
+/// Mix `frames` interleaved frames from `input` (frames × input channels floats) into `output`
+/// (frames × output channels floats); the buffers must not overlap
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_mixer_process(ArHandle mixer, const float* input, size_t frames, float* output);
+
+/// Returns: the gains in use as JSON [[gain per input] per output] (free with rust_string_free),
+/// NULL on error (see last_error_message)
+char* ar_mixer_matrix(ArHandle mixer);
+
+/// Release a mixer. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_mixer_free(ArHandle mixer);
+
+/// Lookahead limiters, so a remote can boost above 0 dBFS without distortion: the audio is
+/// boosted by gainDb, then delayed by the lookahead while the gain is ramped down ahead of each
+/// peak so nothing goes over the threshold; it recovers over releaseMs. Boost changes are
+/// smoothed so volume steps don't click.
+
+/// Create a limiter. `config_json` (nullable): {"channels" (default 2), "sampleRate" (default
+/// 48000), "gainDb" (the boost, -60–24, default 0), "thresholdDb" (default -1), "releaseMs"
+/// (default 100), "lookaheadMs" (default 5), "truePeak" (limit the 4× oversampled true peak,
+/// catching inter-sample peaks, for 6 more frames of latency; default false)}.
+/// Returns: a handle (free with ar_limiter_free), 0 on error (see last_error_message)
+ArHandle ar_limiter_new(const char* config_json);
+
+/// Change the settings, as for ar_limiter_new, e.g. when a remote changes the boost. Audio in
+/// flight is kept unless the channels, sample rate, lookahead or truePeak change.
+/// Returns: 1 on success, -999 on error, keeping the old settings (see last_error_message)
+int ar_limiter_update(ArHandle limiter, const char* config_json);
+
+/// Boost and limit `frames` frames of interleaved float samples (frames × channels floats) in place
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_limiter_process(ArHandle limiter, float* samples, size_t frames);
+
+/// Gain reduction metering: the gain applied now and the lowest since the last read, in dB
+/// (0 when not limiting, negative when limiting), into the non-NULL out pointers
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_limiter_gain_reduction(ArHandle limiter, double* out_current_db, double* out_lowest_db);
+
+/// Returns: how many frames the output lags the input, -999 on error (see last_error_message)
+int64_t ar_limiter_latency(ArHandle limiter);
+
+/// Drop the audio in flight and the gain reduction. Returns: 1 on success, -999 on error
+int ar_limiter_reset(ArHandle limiter);
+
+/// Release a limiter. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_limiter_free(ArHandle limiter);
+
+/// Silence detection, for auto-pausing metering, dropping the stream's bitrate or showing the Mac
+/// as idle: the detector turns silent once the RMS has been under thresholdDb for holdSeconds of
+/// audio, and back to sound on the first window over it.
+typedef enum {
+    AR_SILENCE_SOUND = 0,
+    AR_SILENCE_SILENT = 1,
+} ArSilenceState;
+
+/// (detector, ArSilenceState, seconds, ctx): on turning silent, how long it has been quiet; on
+/// sound returning, how long the silence lasted. Called on the callback queue if one is set.
+typedef void (*ArSilenceCallback)(ArHandle detector, int state, double seconds, void* ctx);
+
+/// Create a silence detector. `config_json` (nullable): {"channels" (default 2), "sampleRate"
+/// (default 48000), "thresholdDb" (RMS, default -60), "holdSeconds" (default 10), "windowMs"
+/// (default 50)}. `callback` (nullable) hears each change of state.
+/// Returns: a handle (free with ar_silence_free), 0 on error (see last_error_message)
+ArHandle ar_silence_new(const char* config_json, ArSilenceCallback callback, void* ctx);
+
+/// Measure `frames` frames of interleaved float samples (frames × channels floats)
+/// Returns: the ArSilenceState afterwards, -999 on error (see last_error_message)
+int ar_silence_process(ArHandle detector, const float* samples, size_t frames);
+
+/// Returns: the ArSilenceState, -999 on error (see last_error_message)
+int ar_silence_state(ArHandle detector);
+
+/// Start over as sound, without a callback. Returns: 1 on success, -999 on error
+int ar_silence_reset(ArHandle detector);
+
+/// Release a detector; no more callbacks follow. Returns: 1 on success, -999 if the handle is
+/// invalid (see last_error_message)
+int ar_silence_free(ArHandle detector);
+
+/// Single-producer, single-consumer ring buffers of interleaved float frames, for handing audio
+/// from the render callback to the streaming thread. Writing and reading never allocate, lock or
+/// wait (after the first call on each thread); a full ring drops what doesn't fit as overruns.
+/// One thread writes and one reads at a time; an overlapping second writer or reader is refused.
+
+/// Create a ring holding `capacity` frames of `channels` interleaved floats
+/// Returns: a handle (free with ar_ring_free), 0 on error (see last_error_message)
+ArHandle ar_ring_new(size_t capacity, uint32_t channels);
+
+/// Append `frames` frames (frames × channels floats), from the producing thread
+/// Returns: the frames written, -999 on error (see last_error_message)
+int64_t ar_ring_write(ArHandle ring, const float* samples, size_t frames);
+
+/// Take up to `frames` frames into `out` (room for frames × channels floats), from the consuming thread
+/// Returns: the frames read (0 when empty), -999 on error (see last_error_message)
+int64_t ar_ring_read(ArHandle ring, float* out, size_t frames);
+
+/// Returns: the frames waiting to be read, -999 on error (see last_error_message)
+int64_t ar_ring_available(ArHandle ring);
+
+/// Returns: the frames dropped so far because the ring was full, -999 on error (see last_error_message)
+int64_t ar_ring_overruns(ArHandle ring);
+
+/// Release a ring. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_ring_free(ArHandle ring);
+
+/// Opus encoding and decoding for "listen on my phone": the Mac encodes its output in short
+/// frames (10 ms by default) for the remote to decode, keeping the codec well inside a 100 ms
+/// latency budget. In-band FEC needs "application": "voip" and a libopus receiver to use it; this
+/// decoder conceals lost packets instead.
+
+/// Create an encoder. `config_json` (nullable): {"channels" (1 or 2, default 2), "sampleRate"
+/// (8000, 12000, 16000, 24000 or 48000, default 48000), "application" ("audio" (default), "voip"
+/// or "lowDelay"), "bitrate" (bits/s, default 96000), "frameMs" (2.5, 5, 10 (default), 20, 40 or
+/// 60), "fec" (default false), "expectedLossPercent" (default 0)}.
+/// Returns: a handle (free with ar_opus_encoder_free), 0 on error (see last_error_message)
+ArHandle ar_opus_encoder_new(const char* config_json);
+
+/// Change the settings, as for ar_opus_encoder_new, e.g. to drop the bitrate while the Mac is
+/// silent. The bitrate, FEC and expected loss change within the stream; any other change starts a
+/// new one, which the remote's decoder should be reset for.
+/// Returns: 1 on success, -999 on error, keeping the old settings (see last_error_message)
+int ar_opus_encoder_update(ArHandle encoder, const char* config_json);
+
+/// Returns: the frames each ar_opus_encode takes, -999 on error (see last_error_message)
+int64_t ar_opus_frame_size(ArHandle encoder);
+
+/// Encode one frame: `frames` (which must be ar_opus_frame_size) frames of interleaved floats,
+/// into `packet`, which has room for `capacity` bytes (4000 is always enough)
+/// Returns: the packet's length in bytes, -999 on error (see last_error_message)
+int64_t ar_opus_encode(ArHandle encoder, const float* samples, size_t frames, uint8_t* packet, size_t capacity);
+
+/// Release an encoder. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_opus_encoder_free(ArHandle encoder);
+
+/// Create a decoder. `config_json` (nullable) is as for ar_opus_encoder_new; only the channels,
+/// sample rate and frameMs (what a lost packet is concealed with) are used.
+/// Returns: a handle (free with ar_opus_decoder_free), 0 on error (see last_error_message)
+ArHandle ar_opus_decoder_new(const char* config_json);
+
+/// Decode a packet of `len` bytes into `out`, which has room for `frames` frames of interleaved
+/// floats (120 ms is always enough). A NULL `packet` marks one as lost and conceals a frame's worth.
+/// Returns: the frames decoded, -999 on error (see last_error_message)
+int64_t ar_opus_decode(ArHandle decoder, const uint8_t* packet, size_t len, float* out, size_t frames);
+
+/// Release a decoder. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_opus_decoder_free(ArHandle decoder);
+
+/// FLAC encoding for sessions that negotiated AR_FEATURE_LOSSLESS: send the header, then one
+/// frame per block. Float samples are quantised to bitsPerSample and clipped to ±1.0.
+
+/// Create a FLAC encoder. `config_json` (nullable): {"channels" (1–8, default 2), "sampleRate"
+/// (default 48000), "bitsPerSample" (16 or 24, default 24), "blockSize" (frames per FLAC frame,
+/// 64–4608, default 1024), "dither" ("tpdf" (default) | "shaped" | "none")}
+/// Returns: a handle (free with ar_flac_encoder_free), 0 on error (see last_error_message)
+ArHandle ar_flac_encoder_new(const char* config_json);
+
+/// The stream header (fLaC and STREAMINFO), to send before the first frame
+/// Returns: the bytes, length in `out_len` (free with ar_bytes_free), NULL on error (see last_error_message)
+uint8_t* ar_flac_header(ArHandle encoder, size_t* out_len);
+
+/// Encode one block: `frames` (which must be the blockSize) frames of interleaved floats
+/// Returns: the FLAC frame, length in `out_len` (free with ar_bytes_free), NULL on error (see last_error_message)
+uint8_t* ar_flac_encode(ArHandle encoder, const float* samples, size_t frames, size_t* out_len);
+
+/// Release an encoder. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_flac_encoder_free(ArHandle encoder);
+
+/// RTP for streamed audio: the Mac wraps each encoded packet with a sequence number and a
+/// timestamp in samples; the remote puts what arrives through an adaptive jitter buffer, pulled
+/// once per packet duration, which holds back enough to ride out the measured jitter (between
+/// minDelayMs and maxDelayMs), reports a missing packet as lost for concealment, and skips
+/// packets when it falls too far behind.
+typedef enum {
+    AR_JITTER_BUFFERING = 0,
+    AR_JITTER_PACKET = 1,
+    AR_JITTER_LOST = 2,
+} ArJitterPlayout;
+
+/// Create a packetizer for one outgoing stream. `config_json` (nullable): {"payloadType" (0–127,
+/// default 111), "ssrc" (default random)}
+/// Returns: a handle (free with ar_rtp_packetizer_free), 0 on error (see last_error_message)
+ArHandle ar_rtp_packetizer_new(const char* config_json);
+
+/// Wrap an encoded packet of `len` bytes, carrying `frames` samples per channel, in an RTP header
+/// Returns: the RTP packet, length in `out_len` (free with ar_bytes_free), NULL on error (see last_error_message)
+uint8_t* ar_rtp_packetize(ArHandle packetizer, const uint8_t* payload, size_t len, uint32_t frames, size_t* out_len);
+
+/// Release a packetizer. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_rtp_packetizer_free(ArHandle packetizer);
+
+/// Create a jitter buffer for one incoming stream. `config_json` (nullable): {"clockRate" (the
+/// sample rate, default 48000), "minDelayMs" (default 20), "maxDelayMs" (default 200)}
+/// Returns: a handle (free with ar_jitter_free), 0 on error (see last_error_message)
+ArHandle ar_jitter_new(const char* config_json);
+
+/// Take in an RTP packet as it arrives; a new SSRC starts the buffer over
+/// Returns: 1 if kept, 0 if dropped as late or a duplicate, -999 if it isn't RTP or on error (see last_error_message)
+int ar_jitter_push(ArHandle jitter, const uint8_t* packet, size_t len);
+
+/// Call once per packet duration. For AR_JITTER_PACKET the payload is copied into `out` (room
+/// for `capacity` bytes) and its length stored in `out_len`; for AR_JITTER_LOST conceal a packet;
+/// for AR_JITTER_BUFFERING play silence.
+/// Returns: the ArJitterPlayout, -999 on error, e.g. a payload over `capacity`, which is kept (see last_error_message)
+int ar_jitter_pop(ArHandle jitter, uint8_t* out, size_t capacity, size_t* out_len);
+
+/// Returns: JSON {"received", "lost", "late", "duplicates", "skipped", "underruns", "jitterMs",
+/// "targetMs", "bufferedMs"} (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_jitter_stats(ArHandle jitter);
+
+/// Drop what's buffered and start over. Returns: 1 on success, -999 on error
+int ar_jitter_reset(ArHandle jitter);
+
+/// Release a jitter buffer. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_jitter_free(ArHandle jitter);
+
+/// AirPlay 1 (RAOP) sending: plays the Mac's audio on an AirPlay speaker over RTSP, with RTP
+/// audio in uncompressed ALAC (44.1 kHz 16-bit stereo, 352 frames a packet) and the timing and
+/// control channels alongside. Unencrypted only: speakers that want a password or AirPlay 2
+/// pairing refuse the session. `host` and `port` come from the speaker's _raop._tcp record.
+typedef enum {
+    AR_RAOP_CONNECTING = 0,
+    AR_RAOP_STREAMING = 1,           // detail may have "latencyMs"
+    AR_RAOP_FAILED = 2,              // refused or gone; detail has "error"
+    AR_RAOP_CLOSED = 3,              // by ar_raop_close
+} ArRaopState;
+
+/// The detail JSON is only valid during the call
+typedef void (*ArRaopStateCallback)(uint64_t speaker, int state, const char* detail_json, void* ctx);
+
+/// Start playing to a speaker. `config_json`: {"host" (a name or an IP, link-local IPv6 with its
+/// zone), "port" (5000), "volume" (0–1, 0.5), "dither" ("tpdf" (default) | "shaped" | "none")}.
+/// `on_state` gets every ArRaopState change, on the ar_runtime_set_callback_queue queue or the
+/// session's thread. `ctx` must stay valid until ar_raop_close.
+/// Returns: the speaker handle, 0 on error (see last_error_message)
+uint64_t ar_raop_open(const char* config_json, ArRaopStateCallback on_state, void* ctx);
+
+/// Returns: the ArRaopState, -999 on error (see last_error_message)
+int ar_raop_state(uint64_t speaker);
+
+/// Play `frames` frames of interleaved stereo floats at 44.1 kHz, fed in real time
+/// Returns: 1 if queued, -999 if not streaming or on error (see last_error_message)
+int ar_raop_send(uint64_t speaker, const float* samples, size_t frames);
+
+/// Set the speaker's volume, 0–1 (0 mutes)
+/// Returns: 1 if queued, -999 if not streaming or on error (see last_error_message)
+int ar_raop_set_volume(uint64_t speaker, double volume);
+
+/// Drop what the speaker has buffered, for a pause or a seek
+/// Returns: 1 if queued, -999 if not streaming or on error (see last_error_message)
+int ar_raop_flush(uint64_t speaker);
+
+/// End the session and free the handle; no callbacks after it returns except ones already
+/// queued. Also done by ar_shutdown.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_raop_close(uint64_t speaker);
+
+/// Clock sync for multi-room playback: the Mac answers timing requests, and each endpoint
+/// playing its stream polls it to map the Mac's clock onto its own (NTP-style, keeping the
+/// fastest recent exchange and fitting the drift), so audio stamped "play at Mac time T" starts
+/// everywhere together. All times are nanoseconds on ar_clock_now_ns's clock.
+int64_t ar_clock_now_ns(void);
+
+/// Answer clock sync requests on UDP `port` (0 picks a free one). Replaces a running server.
+/// Returns: the port, -999 on error (see last_error_message)
+int ar_clock_server_start(uint16_t port);
+
+/// Stop answering; a no-op if not running. Also done by ar_shutdown.
+void ar_clock_server_stop(void);
+
+/// Start syncing to a Mac's clock. `config_json`: {"host" (a name or an IP, link-local IPv6 with
+/// its zone), "port", "intervalMs" (1000)}
+/// Returns: a handle (free with ar_clock_client_close), 0 on error (see last_error_message)
+ArHandle ar_clock_client_open(const char* config_json);
+
+/// Returns: JSON {"synced", "offsetNs" (Mac time minus ours), "delayNs" (the offset is good to
+/// half this), "driftPpm", "exchanges"} (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_clock_client_status(ArHandle client);
+
+/// Convert a time on the Mac's clock to ours, or ours to the Mac's
+/// Returns: 1 with the time in `out`, -999 before the first exchange or on error (see last_error_message)
+int ar_clock_client_to_local(ArHandle client, int64_t server_ns, int64_t* out);
+int ar_clock_client_to_server(ArHandle client, int64_t local_ns, int64_t* out);
+
+/// Stop syncing and free the handle. Also done by ar_shutdown.
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_clock_client_close(ArHandle client);
+
+/// The common playout delay for a group of endpoints. `request_json`: {"endpoints": [{"id",
+/// "networkMs" (one way), "bufferMs", "outputMs"}], "marginMs" (10)}
+/// Returns: JSON {"delayMs" (stamp audio to play this far after the Mac's now), "endpoints":
+/// [{"id", "extraDelayMs"}]} (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_clock_playout_delay(const char* request_json);
+
+/// Identify a local audio file and read its format from the headers, without decoding it
+/// Returns: JSON {"container" ("wav", "aiff", "flac", "mp3", "aac", "mp4", "ogg"), "codec" (e.g.
+/// "pcm_s16le", "flac", "alac", "mp3", "aac", "vorbis"), "lossless", "sampleRate", "channels",
+/// "bitDepth" (null for lossy codecs), "frames", "durationSeconds", "hasTags", "hasArtwork",
+/// "sizeBytes"}, fields the headers don't give null (free with rust_string_free), NULL on error
+/// or if it isn't an audio file (see last_error_message)
+char* ar_probe_audio_file(const char* path);
+
+/// Work out a local audio file's ReplayGain 2.0 track gain and peak (WAV, AIFF, FLAC, ALAC, MP3,
+/// AAC/M4A, Ogg Vorbis). `options_json` (nullable): {"referenceLufs" (-18)}. `progress`
+/// (nullable) gets (frames done, total frames or 0 if unknown); return false to cancel.
+/// Returns: JSON {"gainDb", "safeGainDb" (lowered to keep the true peak under 0 dBTP), "peak"
+/// (linear), "truePeakDb", "loudnessLufs", "durationSeconds", "sampleRate", "channels"}, the gains
+/// and levels null for a silent track (free with rust_string_free), NULL on error or cancel
+/// (see last_error_message)
+char* ar_replaygain_analyze(const char* path, const char* options_json, ProgressCallback progress, void* ctx);
+
+/// Headphone safety: the listener's sound dose after WHO/ITU-T H.870, kept as daily totals in the
+/// store and summed over the last seven days. The allowance is `referenceDb` for `referenceHours`
+/// a week (80 dB for 40 hours); every 3 dB louder halves the safe time.
+/// (threshold, percent, ctx): the seven-day dose reached the `threshold` warning percent of the
+/// allowance. Called on the callback queue if one is set.
+typedef void (*ArHearingCallback)(double threshold, double percent, void* ctx);
+
+/// Set up dose tracking. `config_json` (nullable): {"fullScaleDbSpl" (dB SPL from a full-scale
+/// signal at full volume, default 100), "referenceDb" (80; 75 for H.870's sensitive mode),
+/// "referenceHours" (40), "warningPercents" ([50, 80, 100]), "utcOffsetMinutes" (0)}. `callback`
+/// (nullable) hears each warning percent reached. Totals so far are kept.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_hearing_configure(const char* config_json, ArHearingCallback callback, void* ctx);
+
+/// Count `seconds` of listening at `level_db` dBFS (e.g. the loudness meter's short-term reading)
+/// with the output volume at `volume_db` (0 at full volume; -INFINITY when muted)
+/// Returns: the seven-day dose as a percent of the allowance, -999 on error (see last_error_message)
+double ar_hearing_add(double level_db, double volume_db, double seconds);
+
+/// Returns: JSON {"percent" (the seven-day dose as a percent of the allowance), "todayPercent",
+/// "hoursLeft" (at the reference level), "days": [{"date" ("2026-10-15"), "dosePa2h", "percent"}]}
+/// (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_hearing_status(void);
+
+/// Forget every total, e.g. when the user asks to
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_hearing_reset(void);
+
+/// Volume fades for sleep-timer fade-outs, crossfades on device switches and gentle unmutes. Each
+/// runs on its own thread and hands out a gain (0–1) every step for the app to set.
+/// (fade, gain, finished, ctx): set `gain` now; `finished` on the last step, at the target. Called
+/// on the callback queue if one is set.
+typedef void (*ArFadeCallback)(ArHandle fade, double gain, bool finished, void* ctx);
+
+/// Start fading the volume. `config_json`: {"from", "to" (gains, 0–1), "durationMs", "curve":
+/// "exponential" (default; the same perceived change each step) | "sCurve" (eased in and out) |
+/// "linear" (in gain), "stepMs" (20), "rangeDb" (the taper's span, as for ar_volume_convert, 60)}.
+/// `callback` gets the starting gain at once, then each step.
+/// Returns: a handle (free with ar_fade_stop, finished or not), 0 on error (see last_error_message)
+ArHandle ar_fade_start(const char* config_json, ArFadeCallback callback, void* ctx);
+
+/// The gain the fade last handed out, written to `out_gain`
+/// Returns: 1 while fading, 0 once finished, -999 on error (see last_error_message)
+int ar_fade_gain(ArHandle fade, double* out_gain);
+
+/// Stop a fade where it is, e.g. to fade back from there, and free the handle; no more steps
+/// follow. `out_gain` (nullable) gets the gain it got to. Also done by ar_shutdown.
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_fade_stop(ArHandle fade, double* out_gain);
+
+/// Left/right balance and per-channel trims, for asymmetric hearing or mismatched speakers, run
+/// over the tap's audio in place after the EQ. The balance turns down the side it moves away from,
+/// linearly in gain; with six channels (L R C LFE Ls Rs) the surrounds follow. Changes glide over
+/// a few milliseconds.
+/// `config_json` (nullable): {"channels" (default 2), "sampleRate" (default 48000), "balance" (-1
+/// left only to 1 right only, default 0), "trimsDb" ([per channel, -24–12])}
+/// Returns: a handle (free with ar_balance_free), 0 on error (see last_error_message)
+ArHandle ar_balance_new(const char* config_json);
+
+/// Change the balance and trims, gliding to them. `settings_json`: {"balance", "trimsDb"}, e.g.
+/// from ar_balance_load
+/// Returns: 1 on success, -999 on error (see last_error_message), leaving the old settings in place
+int ar_balance_update(ArHandle balance, const char* settings_json);
+
+/// Apply the balance and trims to `frames` frames of interleaved float samples in place
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_balance_process(ArHandle balance, float* samples, size_t frames);
+
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_balance_free(ArHandle balance);
+
+/// Save the balance and trims for an output device (e.g. its Core Audio UID); saving the defaults
+/// forgets the device
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_balance_save(const char* device_id, const char* settings_json);
+
+/// Returns: JSON {"balance", "trimsDb"} saved for an output device, the defaults if none are (free
+/// with rust_string_free), NULL on error (see last_error_message)
+char* ar_balance_load(const char* device_id);
+
+/// Spectrograms for the diagnostics window, showing what the audio tap receives: analysed as
+/// ar_spectrum_new does, with one log-spaced band per row and no smoothing, each fftSize / 2
+/// frames adds a column and the last `width` columns are kept. Rendered on demand as RGBA, newest
+/// column on the right, highest frequency at the top, floorDb–0 dB from black to pale yellow.
+
+/// Create a spectrogram. `config_json` (nullable): {"channels" (default 2), "sampleRate" (default
+/// 48000), "fftSize" (a power of two, default 1024), "width" (columns, 1–4096, default 512),
+/// "height" (rows, 1–256, default 128), "minHz" (default 20), "maxHz" (default 20000),
+/// "floorDb" (default -100)}
+/// Returns: a handle (free with ar_spectrogram_free), 0 on error (see last_error_message)
+ArHandle ar_spectrogram_new(const char* config_json);
+
+/// Add `frames` frames of interleaved float samples (frames × channels floats)
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_spectrogram_process(ArHandle spectrogram, const float* samples, size_t frames);
+
+/// Render into `out_rgba` if `capacity` is at least width × height × 4 bytes; pass NULL and 0 to
+/// get the size. Rows run top to bottom, 4 bytes (R, G, B, A = 255) per pixel.
+/// Returns: the image's size in bytes, -999 on error (see last_error_message)
+int ar_spectrogram_render(ArHandle spectrogram, uint8_t* out_rgba, size_t capacity);
+
+/// Clear the image and drop the audio, e.g. when the tap restarts. Returns: 1 on success, -999 on error
+int ar_spectrogram_reset(ArHandle spectrogram);
+
+/// Release a spectrogram. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_spectrogram_free(ArHandle spectrogram);
+
+/// Noise gates for monitoring the Mac's microphone on a remote: the channels are keyed together on
+/// their loudest sample. The gate opens at thresholdDb (fading in over attackMs), stays open while
+/// the level is over the threshold less hysteresisDb and for holdMs after, then fades down to
+/// rangeDb over releaseMs. Fades are in dB, so the release time doesn't depend on the range.
+
+/// Create a gate. `config_json` (nullable): {"channels" (default 1), "sampleRate" (default 48000),
+/// "thresholdDb" (-100–0, default -45), "hysteresisDb" (0–20, default 6), "attackMs" (default 1),
+/// "holdMs" (default 150), "releaseMs" (default 100), "rangeDb" (the gain while closed, -120–0,
+/// default -80)}
+/// Returns: a handle (free with ar_gate_free), 0 on error (see last_error_message)
+ArHandle ar_gate_new(const char* config_json);
+
+/// Change a gate's settings, as for ar_gate_new; whether it's open is kept
+/// Returns: 1 on success, -999 on error (see last_error_message), leaving the old settings in place
+int ar_gate_update(ArHandle gate, const char* config_json);
+
+/// Gate `frames` frames of interleaved float samples in place (frames × channels floats)
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_gate_process(ArHandle gate, float* samples, size_t frames);
+
+/// The gain applied now in dB through `out_gain_db` (may be NULL), for a "talking" light
+/// Returns: 1 when open, 0 when closed, -999 on error (see last_error_message)
+int ar_gate_state(ArHandle gate, double* out_gain_db);
+
+/// Close the gate and forget the audio, e.g. when the microphone restarts. Returns: 1 on success, -999 on error
+int ar_gate_reset(ArHandle gate);
+
+/// Release a gate. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_gate_free(ArHandle gate);
+
+/// Crossfades that splice two PCM streams when switching sources or output devices, so the switch
+/// neither pops nor drops audio. One crossfade covers one switch: feed it the outgoing and incoming
+/// streams together until it reports 0 frames left, then the incoming one alone. "equalPower" keeps
+/// unrelated audio level; "linear" suits the same audio on both sides. With waitForAudio the fade
+/// waits (up to maxWaitMs) for the incoming stream's leading digital silence to end.
+
+/// Create a crossfade. `config_json` (nullable): {"channels" (default 2), "sampleRate" (default
+/// 48000), "durationMs" (0–10000, default 50), "curve" ("equalPower" (default) | "linear"),
+/// "waitForAudio" (default true), "maxWaitMs" (0–10000, default 500)}
+/// Returns: a handle (free with ar_crossfade_free), 0 on error (see last_error_message)
+ArHandle ar_crossfade_new(const char* config_json);
+
+/// Splice `frames` frames of interleaved `outgoing` and `incoming` audio (frames × channels floats
+/// each) into `output`, which mustn't overlap them. A NULL `outgoing` is silence, e.g. a device
+/// that's gone.
+/// Returns: the frames of fade still to come (0 once crossed over), -999 on error (see last_error_message)
+int64_t ar_crossfade_process(ArHandle crossfade, const float* outgoing, const float* incoming, size_t frames, float* output);
+
+/// Release a crossfade. Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+int ar_crossfade_free(ArHandle crossfade);
+
+/// The Mac's audio devices, kept in Rust as the one record every remote answers from. Forward
+/// CoreAudio's device notifications to ar_devices_event; devices are identified by their UID, so
+/// they keep their ID across relaunches and reconnects, and one that goes away stays on record as
+/// disconnected. The "devices" and "volume" event topics are republished from here when they change.
+/// (change_json, ctx): {"generation", "change" ("added" | "changed" | "removed" | "defaultOutput"),
+/// "device" (as in ar_devices_snapshot)}; the JSON is only valid during the call.
+typedef void (*ArDevicesCallback)(const char* change_json, void* ctx);
+
+/// Pass on a CoreAudio notification. `event_json` is one of:
+/// {"event": "added", "objectId", "uid", "name", "manufacturer", "transport" ("builtIn" | "usb" |
+/// "bluetooth" | "airPlay" | "hdmi" | "displayPort" | "thunderbolt" | "aggregate" | "virtual"),
+/// "outputChannels", "inputChannels", "sampleRate", "volume" (0–1, null without a control), "muted"};
+/// {"event": "changed", "objectId", and any of "name", "outputChannels", "inputChannels",
+/// "sampleRate", "volume", "muted"}; {"event": "removed", "objectId"}; {"event": "defaultOutput", "objectId"}
+/// Returns: the number of records changed (0 if none), -999 on error (see last_error_message)
+int ar_devices_event(const char* event_json);
+
+/// Every device on record, connected or not
+/// Returns: JSON {"generation", "defaultOutput" (an id or null), "devices": [{"id" (the UID), "name",
+/// "manufacturer", "transport", "category" (an ArDeviceCategory's name: "headphones", "speakers",
+/// "displayAudio", "virtual", "airPlay", "bluetooth" or "unknown"), "outputChannels",
+/// "inputChannels", "sampleRate", "volume", "muted", "objectId" (null when disconnected),
+/// "connected", "isDefault", "nickname", "icon" (each null if not set), "bluetooth" (as in
+/// ar_bluetooth_parse; null if not set)}],
+/// by nickname or name} (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_devices_snapshot(void);
+
+/// Give a device a nickname and icon hint, kept in the store and shown by every remote instead of
+/// its name, whether or not it's connected. `alias_json` (nullable): {"nickname", "icon" (e.g.
+/// "headphones"), each up to 64 bytes}; a missing or blank field, or NULL, takes it away.
+/// Returns: the number of records changed (0 or 1), -999 on error (see last_error_message)
+int ar_devices_set_alias(const char* uid, const char* alias_json);
+
+/// Hear every change to a record, on the callback queue if one is set. NULL stops them.
+/// Returns: 1
+int ar_devices_set_callback(ArDevicesCallback callback, void* ctx);
+
+/// Give a connected device the Bluetooth properties Swift read for it, as for ar_bluetooth_parse,
+/// e.g. as it connects and when its battery changes; NULL forgets them. They're left out of the
+/// record once it disconnects, and remotes get the battery level as "batteryPercent" in the
+/// device list (the lower earbud's for a pair).
+/// Returns: the number of records changed (0 or 1), -999 on error (see last_error_message)
+int ar_devices_set_bluetooth(const char* uid, const char* properties_json);
+
+/// What to put a reconnecting device back to, e.g. as ar_devices_event adds it: the volume and mute
+/// last changed on it (remembered from "changed" events, not from what a device reports as it's
+/// added) and its saved balance
+/// Returns: JSON {"volume" (0–1), "muted" (each null if never set), "balance", "trimsDb" (as in
+/// ar_balance_load)} (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_device_restore_state(const char* uid);
+
+/// Switching the default output by the user's priorities (AirPods over the USB DAC over the
+/// built-in speakers), decided on every change to the device registry (see ar_devices_event). A
+/// device is switched to once it has stayed connected for settleMs, and a switch waits holdMs after
+/// the last unless the default device went away. A device chosen by hand stays until it goes away
+/// or another device connects.
+/// (switch_json, ctx): make a device the default output: {"id", "objectId", "name", "rule" (the
+/// index of the rule it matched), "reason" ("preferred" | "fallback": the default went away)}; the
+/// JSON is only valid during the call.
+typedef void (*ArAutoSwitchCallback)(const char* switch_json, void* ctx);
+
+/// Set the priorities, which are kept in the store. `config_json` (nullable): {"enabled" (default
+/// true), "rules": [{"id" (a UID), "nameContains" (in the name or nickname), "transport" (as in
+/// ar_devices_event), "category" (as in ar_devices_snapshot)}, most preferred first; a rule matches
+/// devices with every field it gives],
+/// "settleMs" (0–60000, default 2000), "holdMs" (0–600000, default 10000)}; NULL keeps the stored
+/// ones. `callback` (nullable) is asked to make a device the default output, on the callback queue if one is set.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+int ar_autoswitch_configure(const char* config_json, ArAutoSwitchCallback callback, void* ctx);
+
+/// Returns: the config as in ar_autoswitch_configure (free with rust_string_free), NULL on error
+/// (see last_error_message)
+char* ar_autoswitch_config(void);
+
+/// What kind of thing an audio device is, guessed from its name, manufacturer and transport: the
+/// transport settles AirPlay, HDMI and DisplayPort, and virtual and aggregate devices; the rest go
+/// by words like "AirPods", "WH-1000XM5", "Speakers", "Studio Display" or "BlackHole". Bluetooth
+/// devices that match nothing are AR_DEVICE_BLUETOOTH. Records in ar_devices_snapshot carry it
+/// by name, and auto-switch rules can match on it.
+typedef enum {
+    AR_DEVICE_UNKNOWN = 0,          // "unknown"
+    AR_DEVICE_HEADPHONES = 1,       // "headphones"
+    AR_DEVICE_SPEAKERS = 2,         // "speakers"
+    AR_DEVICE_DISPLAY_AUDIO = 3,    // "displayAudio"
+    AR_DEVICE_VIRTUAL = 4,          // "virtual"
+    AR_DEVICE_AIRPLAY = 5,          // "airPlay"
+    AR_DEVICE_BLUETOOTH = 6,        // "bluetooth"
+} ArDeviceCategory;
+
+/// Guess what kind of device this is. `transport` (nullable) as in ar_devices_event;
+/// `manufacturer` (nullable) is kAudioObjectPropertyManufacturer.
+/// Returns: an ArDeviceCategory, -999 on error (see last_error_message)
+int ar_device_classify(const char* name, const char* transport, const char* manufacturer);
+
+/// Codec, battery and signal strength of Bluetooth audio devices, from the properties Swift reads
+/// out of IOBluetooth and the IORegistry or the system_profiler report. Keys are matched loosely,
+/// ignoring case and punctuation ("BatteryPercentLeft", "Left Battery Level" and
+/// "device_batteryLevelLeft" are the same), and values that can't be read or are out of range are
+/// left out.
+
+/// Make sense of a Bluetooth device's properties. `properties_json`: an object of what Swift read
+/// from IOBluetooth, the IORegistry or system_profiler, by their own names, e.g.
+/// {"BatteryPercentLeft": 80, "Case Battery Level": "45%", "RSSI": -56, "Codec": "AAC"}
+/// Returns: JSON {"codec" ("sbc" | "aac" | "aptX" | "aptXHd" | "aptXAdaptive" | "ldac" | "lc3" |
+/// "msbc" | "cvsd" | "unknown"), "battery": {"percent", "left", "right", "case"} (0–100), "rssi"
+/// (dBm)}, each null if not found (free with rust_string_free), NULL on error (see last_error_message)
+char* ar_bluetooth_parse(const char* properties_json);
+
 #endif /* RustBridge_h */
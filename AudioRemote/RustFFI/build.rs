@@ -0,0 +1,9 @@
+//! Generates the gRPC service (see `grpc`) from the .proto shipped in proto/,
+//! with a vendored protoc so building doesn't need one installed.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    println!("cargo:rerun-if-changed=proto/audioremote.proto");
+    tonic_build::configure().compile_protos(&["proto/audioremote.proto"], &["proto"])?;
+    Ok(())
+}
@@ -0,0 +1,72 @@
+//! Version of the C interface itself, so an app built against one RustBridge.h
+//! can refuse to run with a library built from another (e.g. after a partial
+//! upgrade) instead of crashing on a changed signature.
+//!
+//! The version is `major << 16 | minor`. Bump the minor for additions and the
+//! major for anything that changes or removes an existing declaration, and keep
+//! AR_FFI_ABI_VERSION in RustBridge.h in step.
+
+use crate::{guard, record, AudioRemoteError};
+
+pub const ABI_MAJOR: u32 = 2;
+pub const ABI_MINOR: u32 = 59;
+pub const ABI_VERSION: u32 = (ABI_MAJOR << 16) | ABI_MINOR;
+
+fn describe(version: u32) -> String {
+    format!("{}.{}", version >> 16, version & 0xffff)
+}
+
+/// A header at `expected` works with this library if the majors agree and the header
+/// doesn't declare anything newer than the library has
+pub fn check(expected: u32) -> Result<(), AudioRemoteError> {
+    let (major, minor) = (expected >> 16, expected & 0xffff);
+    if major == ABI_MAJOR && minor <= ABI_MINOR {
+        return Ok(());
+    }
+    Err(AudioRemoteError::Unsupported(format!(
+        "RustBridge.h declares FFI ABI {} but the linked library implements {}; rebuild both from the same revision",
+        describe(expected),
+        describe(ABI_VERSION)
+    )))
+}
+
+/// Returns: the FFI ABI version of this library (major << 16 | minor)
+#[no_mangle]
+pub extern "C" fn ar_ffi_abi_version() -> u32 {
+    guard("ar_ffi_abi_version", 0, || ABI_VERSION)
+}
+
+/// Startup handshake: pass AR_FFI_ABI_VERSION from the header the app was built with
+/// Returns: 1 if compatible, -999 if not (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_ffi_check_abi(expected: u32) -> i32 {
+    guard("ar_ffi_check_abi", -999, || {
+        match record(check(expected)) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abi_check() {
+        assert_eq!(ar_ffi_check_abi(ar_ffi_abi_version()), 1);
+        assert!(check(ABI_MAJOR << 16).is_ok());
+        assert!(check(ABI_VERSION + 1).is_err());
+        let err = check((ABI_MAJOR + 1) << 16).unwrap_err();
+        assert!(matches!(&err, AudioRemoteError::Unsupported(m) if m.contains("declares FFI ABI 3.0")), "{err}");
+    }
+
+    /// The header and the crate must agree, or the handshake is meaningless
+    #[test]
+    fn test_header_matches() {
+        let header = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../Core/RustBridge.h");
+        let header = std::fs::read_to_string(header).unwrap();
+        let expected = format!("#define AR_FFI_ABI_VERSION ((uint32_t)(({ABI_MAJOR} << 16) | {ABI_MINOR}))");
+        assert!(header.contains(&expected), "RustBridge.h should contain {expected}");
+    }
+}
@@ -0,0 +1,454 @@
+use std::cmp::Ordering;
+use std::ffi::c_char;
+
+use semver::Version;
+use serde::Serialize;
+
+use crate::channel::channel_arg;
+use crate::error::{into_c_string, string_result};
+use crate::handle::{Handle, Registry};
+use crate::version::{self, ParseMode};
+use crate::{guard, policy, record, store, str_arg, AudioRemoteError, UpdateChannel};
+
+const SPARKLE_NS: &str = "http://www.andymatuschak.org/xml-namespaces/sparkle";
+
+/// One downloadable release from a Sparkle appcast
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AppcastItem {
+    pub title: Option<String>,
+    /// Normalized semver of the marketing version
+    pub version: String,
+    /// CFBundleVersion (`sparkle:version`) when it differs from the marketing version
+    pub build: Option<String>,
+    pub url: String,
+    pub length: Option<u64>,
+    pub signature: Option<String>,
+    pub minimum_system_version: Option<String>,
+    pub maximum_system_version: Option<String>,
+    pub release_notes_url: Option<String>,
+    pub channel: Option<String>,
+    #[serde(skip)]
+    pub parsed_version: Version,
+    #[serde(skip)]
+    pub update_channel: UpdateChannel,
+}
+
+impl AppcastItem {
+    /// Order by marketing version, then by build number
+    pub fn cmp_release(&self, other: &Self) -> Ordering {
+        self.parsed_version
+            .cmp_precedence(&other.parsed_version)
+            .then_with(|| match (&self.build, &other.build) {
+                (Some(a), Some(b)) => version::compare_build_numbers(a, b).unwrap_or(Ordering::Equal),
+                _ => Ordering::Equal,
+            })
+    }
+}
+
+/// Parse every usable `<item>` of an appcast; items missing a URL or version are skipped
+pub fn parse(xml: &str) -> Result<Vec<AppcastItem>, AudioRemoteError> {
+    let doc = roxmltree::Document::parse(xml).map_err(|e| AudioRemoteError::InvalidData(format!("malformed appcast: {e}")))?;
+    let items: Vec<_> = doc
+        .descendants()
+        .filter(|n| n.has_tag_name("item"))
+        .filter_map(parse_item)
+        .collect();
+
+    if items.is_empty() {
+        return Err(AudioRemoteError::InvalidData("appcast contains no usable releases".into()));
+    }
+    Ok(items)
+}
+
+fn parse_item(item: roxmltree::Node) -> Option<AppcastItem> {
+    let child_text = |name: &str, ns: Option<&str>| {
+        item.children()
+            .find(|c| c.is_element() && c.tag_name().name() == name && c.tag_name().namespace() == ns)
+            .and_then(|c| c.text())
+            .map(|t| t.trim().to_owned())
+            .filter(|t| !t.is_empty())
+    };
+    let enclosure = item.children().find(|c| c.has_tag_name("enclosure"))?;
+    let sparkle_value = |name: &str| {
+        enclosure
+            .attribute((SPARKLE_NS, name))
+            .map(str::to_owned)
+            .or_else(|| child_text(name, Some(SPARKLE_NS)))
+    };
+
+    let url = enclosure.attribute("url")?.trim().to_owned();
+    if url.is_empty() {
+        return None;
+    }
+
+    let build = sparkle_value("version");
+    let marketing = sparkle_value("shortVersionString").or_else(|| build.clone())?;
+    let parsed_version = version::parse(&marketing, ParseMode::Lenient).ok()?;
+
+    let channel = child_text("channel", Some(SPARKLE_NS));
+    let feed_channel = match channel.as_deref() {
+        None => UpdateChannel::Stable,
+        Some(c) if c.eq_ignore_ascii_case("beta") => UpdateChannel::Beta,
+        Some(_) => UpdateChannel::Nightly,
+    };
+
+    Some(AppcastItem {
+        title: child_text("title", None),
+        version: parsed_version.to_string(),
+        build: build.filter(|b| *b != marketing),
+        url,
+        length: enclosure.attribute("length").and_then(|l| l.trim().parse().ok()),
+        signature: enclosure.attribute((SPARKLE_NS, "edSignature")).map(str::to_owned),
+        minimum_system_version: child_text("minimumSystemVersion", Some(SPARKLE_NS)),
+        maximum_system_version: child_text("maximumSystemVersion", Some(SPARKLE_NS)),
+        release_notes_url: child_text("releaseNotesLink", Some(SPARKLE_NS)),
+        channel,
+        update_channel: feed_channel.max(UpdateChannel::of(&parsed_version)),
+        parsed_version,
+    })
+}
+
+/// Newest release published on the given channel
+pub fn best_release(items: &[AppcastItem], channel: UpdateChannel) -> Option<&AppcastItem> {
+    items
+        .iter()
+        .filter(|item| item.update_channel <= channel)
+        .max_by(|a, b| a.cmp_release(b))
+}
+
+/// Whether the running macOS can run a release
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsCompatibility {
+    Compatible,
+    TooOld,
+    TooNew,
+}
+
+/// Outcome of an update check, as reported to the UI
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateDecision {
+    /// A newer release runs on this Mac
+    Available = 0,
+    /// Nothing newer on this channel
+    UpToDate = 1,
+    /// Newer releases exist but all need a newer macOS
+    RequiresNewerOs = 2,
+    /// Newer releases exist but all have dropped support for this macOS
+    OsTooNew = 3,
+}
+
+impl AppcastItem {
+    /// Compare the feed's system requirements with the running macOS version.
+    /// Requirements that can't be parsed are treated as unmet.
+    pub fn os_compatibility(&self, os: &Version) -> OsCompatibility {
+        let parse = |v: &str| version::parse(v, ParseMode::Lenient).ok();
+        if let Some(min) = &self.minimum_system_version {
+            match parse(min) {
+                Some(min) if os.cmp_precedence(&min) != Ordering::Less => {}
+                _ => return OsCompatibility::TooOld,
+            }
+        }
+        if let Some(max) = &self.maximum_system_version {
+            match parse(max) {
+                Some(max) if os.cmp_precedence(&max) != Ordering::Greater => {}
+                _ => return OsCompatibility::TooNew,
+            }
+        }
+        OsCompatibility::Compatible
+    }
+}
+
+/// Decide what to offer a user on `channel` running `current` on macOS `os`.
+/// Releases at or below `skipped` (the user's "Skip this version") are ignored.
+/// Returns the release to offer, or for the OS-blocked outcomes the newest
+/// release the user is missing out on so the UI can name it.
+pub fn decide<'a>(
+    items: &'a [AppcastItem],
+    current: &Version,
+    os: &Version,
+    channel: UpdateChannel,
+    skipped: Option<&Version>,
+) -> (UpdateDecision, Option<&'a AppcastItem>) {
+    let newer: Vec<&AppcastItem> = items
+        .iter()
+        .filter(|item| item.update_channel <= channel)
+        .filter(|item| item.parsed_version.cmp_precedence(current) == Ordering::Greater)
+        .filter(|item| skipped.is_none_or(|s| item.parsed_version.cmp_precedence(s) == Ordering::Greater))
+        .collect();
+    let newest = |it: &mut dyn Iterator<Item = &'a AppcastItem>| it.max_by(|a, b| a.cmp_release(b));
+
+    let compatible = newest(&mut newer.iter().copied().filter(|i| i.os_compatibility(os) == OsCompatibility::Compatible));
+    if let Some(item) = compatible {
+        return (UpdateDecision::Available, Some(item));
+    }
+    match newest(&mut newer.iter().copied()) {
+        None => (UpdateDecision::UpToDate, None),
+        Some(item) => match item.os_compatibility(os) {
+            OsCompatibility::TooNew => (UpdateDecision::OsTooNew, Some(item)),
+            _ => (UpdateDecision::RequiresNewerOs, Some(item)),
+        },
+    }
+}
+
+/// Pick the newest release for a channel (0 = stable, 1 = beta, 2 = nightly, -1 = saved) from appcast XML
+/// Returns: JSON object (free with rust_string_free), or NULL on error (see last_error_message)
+///
+/// # Safety
+/// `xml_ptr` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn appcast_best_release(xml_ptr: *const c_char, channel: i32) -> *mut c_char {
+    guard("appcast_best_release", std::ptr::null_mut(), || {
+        let result = str_arg(xml_ptr, "xml").and_then(|xml| {
+            let channel = channel_arg(channel)?;
+            best_release_json(&parse(xml)?, channel)
+        });
+        string_result(result)
+    })
+}
+
+fn best_release_json(items: &[AppcastItem], channel: UpdateChannel) -> Result<String, AudioRemoteError> {
+    let best = best_release(items, channel).ok_or(AudioRemoteError::NotFound("no release available on this channel".into()))?;
+    serde_json::to_string(best).map_err(|e| AudioRemoteError::Other(e.to_string()))
+}
+
+/// Parsed feeds held by Swift between calls, so periodic checks don't re-parse the XML
+static FEEDS: Registry<Vec<AppcastItem>> = Registry::new("appcast");
+
+/// Parse appcast XML once and keep it for ar_appcast_best_release
+/// Returns: a handle (free with ar_appcast_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `xml_ptr` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_appcast_new(xml_ptr: *const c_char) -> Handle {
+    guard("ar_appcast_new", 0, || {
+        match record(str_arg(xml_ptr, "xml").and_then(parse)) {
+            Some(items) => FEEDS.insert(items),
+            None => 0,
+        }
+    })
+}
+
+/// Same as appcast_best_release, for a feed parsed with ar_appcast_new
+/// Returns: JSON object (free with rust_string_free), or NULL on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_appcast_best_release(feed: Handle, channel: i32) -> *mut c_char {
+    guard("ar_appcast_best_release", std::ptr::null_mut(), || {
+        let result = FEEDS.get(feed).and_then(|items| best_release_json(items.as_slice(), channel_arg(channel)?));
+        string_result(result)
+    })
+}
+
+/// Release a feed from ar_appcast_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_appcast_free(feed: Handle) -> i32 {
+    guard("ar_appcast_free", -999, || {
+        match record(FEEDS.remove(feed)) {
+            Some(_) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Decide whether to offer an update, honoring the feed's minimum/maximum macOS versions
+/// and the version skipped with update_skip_version
+/// `os_version` is the running macOS version (e.g. "14.2.1"); `release_json` (nullable)
+/// receives the offered release, or the blocked one for the OS outcomes, or NULL.
+/// Returns: 0 = update available, 1 = up to date, 2 = requires newer macOS,
+/// 3 = macOS too new, -999 on error (see last_error_message)
+///
+/// # Safety
+/// All string pointers must point to valid NUL-terminated strings; `release_json`
+/// must be null or point to writable storage for one pointer.
+#[no_mangle]
+pub unsafe extern "C" fn appcast_update_decision(
+    xml_ptr: *const c_char,
+    current_ptr: *const c_char,
+    os_version_ptr: *const c_char,
+    channel: i32,
+    release_json: *mut *mut c_char,
+) -> i32 {
+    guard("appcast_update_decision", -999, || {
+        if !release_json.is_null() {
+            *release_json = std::ptr::null_mut();
+        }
+        let result = str_arg(xml_ptr, "xml").and_then(|xml| {
+            let current = crate::version_arg(current_ptr, "current")?;
+            let os = str_arg(os_version_ptr, "os_version")?;
+            let os = version::parse(os, ParseMode::Lenient).map_err(|e| AudioRemoteError::InvalidVersion(format!("invalid os_version \"{os}\": {e}")))?;
+            let channel = channel_arg(channel)?;
+            let items = parse(xml)?;
+            let skipped = policy::skipped_version(&store::global());
+            let (decision, item) = decide(&items, &current, &os, channel, skipped.as_ref());
+            let json = item.map(serde_json::to_string).transpose().map_err(|e| e.to_string())?;
+            Ok((decision, json))
+        });
+        match record(result) {
+            Some((decision, json)) => {
+                if let (false, Some(json)) = (release_json.is_null(), json) {
+                    *release_json = into_c_string(json);
+                }
+                decision as i32
+            }
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+
+    const APPCAST: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0" xmlns:sparkle="http://www.andymatuschak.org/xml-namespaces/sparkle">
+  <channel>
+    <title>Audio Remote</title>
+    <item>
+      <title>Version 2.6.0</title>
+      <sparkle:minimumSystemVersion>12.0</sparkle:minimumSystemVersion>
+      <enclosure url="https://example.com/AudioRemote-2.6.0.zip" length="1024"
+                 sparkle:version="142" sparkle:shortVersionString="2.6.0"
+                 sparkle:edSignature="c2lnbmF0dXJl" type="application/octet-stream"/>
+    </item>
+    <item>
+      <title>Version 2.6.0 (150)</title>
+      <enclosure url="https://example.com/AudioRemote-2.6.0-150.zip"
+                 sparkle:version="150" sparkle:shortVersionString="2.6.0"/>
+    </item>
+    <item>
+      <title>Version 2.7.0 Beta</title>
+      <sparkle:channel>beta</sparkle:channel>
+      <sparkle:version>160</sparkle:version>
+      <sparkle:shortVersionString>2.7.0</sparkle:shortVersionString>
+      <enclosure url="https://example.com/AudioRemote-2.7.0.zip" length="2048"/>
+    </item>
+    <item>
+      <title>Broken: no enclosure</title>
+      <sparkle:shortVersionString>9.9.9</sparkle:shortVersionString>
+    </item>
+    <item>
+      <title>Broken: bad version</title>
+      <enclosure url="https://example.com/x.zip" sparkle:shortVersionString="latest"/>
+    </item>
+  </channel>
+</rss>"#;
+
+    #[test]
+    fn test_parse_skips_malformed_items() {
+        let items = parse(APPCAST).unwrap();
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].version, "2.6.0");
+        assert_eq!(items[0].build.as_deref(), Some("142"));
+        assert_eq!(items[0].length, Some(1024));
+        assert_eq!(items[0].signature.as_deref(), Some("c2lnbmF0dXJl"));
+        assert_eq!(items[0].minimum_system_version.as_deref(), Some("12.0"));
+        assert_eq!(items[2].update_channel, UpdateChannel::Beta);
+    }
+
+    #[test]
+    fn test_best_release_per_channel() {
+        let items = parse(APPCAST).unwrap();
+        let stable = best_release(&items, UpdateChannel::Stable).unwrap();
+        assert_eq!(stable.url, "https://example.com/AudioRemote-2.6.0-150.zip");
+        let beta = best_release(&items, UpdateChannel::Beta).unwrap();
+        assert_eq!(beta.version, "2.7.0");
+    }
+
+    fn v(s: &str) -> Version {
+        version::parse(s, ParseMode::Lenient).unwrap()
+    }
+
+    #[test]
+    fn test_decide_honors_minimum_os() {
+        let xml = r#"<rss xmlns:sparkle="http://www.andymatuschak.org/xml-namespaces/sparkle"><channel>
+            <item><sparkle:minimumSystemVersion>12.0</sparkle:minimumSystemVersion>
+              <enclosure url="https://example.com/2.6.zip" sparkle:shortVersionString="2.6.0"/></item>
+            <item><sparkle:minimumSystemVersion>14.0</sparkle:minimumSystemVersion>
+              <enclosure url="https://example.com/2.7.zip" sparkle:shortVersionString="2.7.0"/></item>
+            <item><sparkle:maximumSystemVersion>11.7</sparkle:maximumSystemVersion>
+              <enclosure url="https://example.com/2.5.9.zip" sparkle:shortVersionString="2.5.9"/></item>
+        </channel></rss>"#;
+        let items = parse(xml).unwrap();
+        let stable = UpdateChannel::Stable;
+
+        let (decision, item) = decide(&items, &v("2.5.0"), &v("13.6"), stable, None);
+        assert_eq!(decision, UpdateDecision::Available);
+        assert_eq!(item.unwrap().version, "2.6.0");
+
+        let (decision, item) = decide(&items, &v("2.6.0"), &v("13.6"), stable, None);
+        assert_eq!(decision, UpdateDecision::RequiresNewerOs);
+        assert_eq!(item.unwrap().minimum_system_version.as_deref(), Some("14.0"));
+
+        assert_eq!(decide(&items, &v("2.6.0"), &v("14.1"), stable, None).0, UpdateDecision::Available);
+        assert_eq!(decide(&items, &v("2.7.0"), &v("14.1"), stable, None).0, UpdateDecision::UpToDate);
+        assert_eq!(decide(&items[2..], &v("2.5.0"), &v("12.0"), stable, None).0, UpdateDecision::OsTooNew);
+
+        // Skipping 2.6.0 hides it, leaving only the release that needs macOS 14
+        let skipped = v("2.6.0");
+        let (decision, item) = decide(&items, &v("2.5.0"), &v("13.6"), stable, Some(&skipped));
+        assert_eq!(decision, UpdateDecision::RequiresNewerOs);
+        assert_eq!(item.unwrap().version, "2.7.0");
+    }
+
+    #[test]
+    fn test_appcast_update_decision_ffi() {
+        let (_guard, _dir) = crate::store::tests::with_temp_store("decision");
+        let xml = std::ffi::CString::new(APPCAST).unwrap();
+        let current = std::ffi::CString::new("2.5.0").unwrap();
+        let os = std::ffi::CString::new("11.6").unwrap();
+        let mut json = std::ptr::null_mut();
+        let decision = unsafe { appcast_update_decision(xml.as_ptr(), current.as_ptr(), os.as_ptr(), 0, &mut json) };
+        // Build 142 needs macOS 12, but build 150 has no requirement
+        assert_eq!(decision, UpdateDecision::Available as i32);
+        let value: serde_json::Value = serde_json::from_str(&take_c_string(json).unwrap()).unwrap();
+        assert_eq!(value["url"], "https://example.com/AudioRemote-2.6.0-150.zip");
+
+        let current = std::ffi::CString::new("2.6.0").unwrap();
+        let decision = unsafe { appcast_update_decision(xml.as_ptr(), current.as_ptr(), os.as_ptr(), 0, &mut json) };
+        assert_eq!(decision, UpdateDecision::UpToDate as i32);
+        assert!(json.is_null());
+    }
+
+    #[test]
+    fn test_malformed_feeds() {
+        assert!(parse("<rss><channel>").is_err());
+        assert!(parse("<rss><channel></channel></rss>").is_err());
+        assert!(parse("not xml at all").is_err());
+    }
+
+    #[test]
+    fn test_appcast_best_release_json() {
+        let xml = std::ffi::CString::new(APPCAST).unwrap();
+        let ptr = unsafe { appcast_best_release(xml.as_ptr(), UpdateChannel::Beta as i32) };
+        let json = take_c_string(ptr).unwrap();
+
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["version"], "2.7.0");
+        assert_eq!(value["build"], "160");
+        assert_eq!(value["channel"], "beta");
+        assert_eq!(value["length"], 2048);
+
+        let empty = std::ffi::CString::new("<rss/>").unwrap();
+        assert!(unsafe { appcast_best_release(empty.as_ptr(), 0) }.is_null());
+    }
+
+    #[test]
+    fn test_feed_handle() {
+        let xml = std::ffi::CString::new(APPCAST).unwrap();
+        let feed = unsafe { ar_appcast_new(xml.as_ptr()) };
+        assert_ne!(feed, 0);
+        let json = take_c_string(ar_appcast_best_release(feed, UpdateChannel::Beta as i32)).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["version"], "2.7.0");
+
+        assert_eq!(ar_appcast_free(feed), 1);
+        assert!(ar_appcast_best_release(feed, UpdateChannel::Beta as i32).is_null());
+        assert_eq!(ar_appcast_free(feed), -999);
+        let bad = std::ffi::CString::new("not xml").unwrap();
+        assert_eq!(unsafe { ar_appcast_new(bad.as_ptr()) }, 0);
+    }
+}
@@ -0,0 +1,258 @@
+//! Local audio files: identifying them and opening them for decoding. The
+//! container is told from the file's first bytes (past any ID3v2 tag), and
+//! the rest comes from the container's headers without decoding any audio, so
+//! a probe is quick enough to run over a whole library.
+//!
+//! Decoding goes through symphonia; `open` is shared with the ReplayGain
+//! analysis.
+
+use std::ffi::c_char;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use serde::Serialize;
+use symphonia::core::codecs::{CodecParameters, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision};
+use symphonia::core::probe::{Hint, ProbeResult};
+
+use crate::error::string_result;
+use crate::{guard, str_arg, AudioRemoteError};
+
+/// Codecs that decode to exactly what was encoded
+const LOSSLESS: [&str; 3] = ["pcm", "flac", "alac"];
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AudioFileInfo {
+    /// "wav", "aiff", "flac", "mp3", "aac" (ADTS), "mp4" (AAC or ALAC in an .m4a) or "ogg"
+    pub container: &'static str,
+    /// symphonia's short name, e.g. "pcm_s16le", "flac", "alac", "mp3", "aac", "vorbis"
+    pub codec: String,
+    pub lossless: bool,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u32>,
+    /// None for lossy codecs, which have no fixed depth
+    pub bit_depth: Option<u32>,
+    pub frames: Option<u64>,
+    pub duration_seconds: Option<f64>,
+    /// Whether there are tags (ID3, Vorbis comments, iTunes atoms, RIFF INFO) and cover art
+    pub has_tags: bool,
+    pub has_artwork: bool,
+    pub size_bytes: u64,
+}
+
+/// Map a symphonia error on `path` to ours
+pub(crate) fn decode_error(path: &Path, e: Error) -> AudioRemoteError {
+    match e {
+        Error::IoError(e) => AudioRemoteError::Io(format!("can't read {}: {e}", path.display())),
+        Error::Unsupported(what) => AudioRemoteError::Unsupported(format!("{} isn't a supported audio file: {what}", path.display())),
+        e => AudioRemoteError::InvalidData(format!("can't decode {}: {e}", path.display())),
+    }
+}
+
+/// The container `head` (the file's first 12 bytes past any ID3v2 tag) starts
+fn container(head: &[u8]) -> Option<&'static str> {
+    match head {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some("wav"),
+        [b'F', b'O', b'R', b'M', _, _, _, _, b'A', b'I', b'F', b'F' | b'C', ..] => Some("aiff"),
+        [b'f', b'L', b'a', b'C', ..] => Some("flac"),
+        [b'O', b'g', b'g', b'S', ..] => Some("ogg"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("mp4"),
+        // An ADTS header has layer 0, an MPEG audio frame any other
+        [0xFF, second, ..] if second & 0xF6 == 0xF0 => Some("aac"),
+        [0xFF, second, ..] if second & 0xE0 == 0xE0 && second & 0x06 != 0 => Some("mp3"),
+        _ => None,
+    }
+}
+
+/// Read the first bytes of `file` past any ID3v2 tags
+fn head(file: &mut File) -> std::io::Result<Vec<u8>> {
+    let mut start = 0;
+    loop {
+        let mut head = Vec::with_capacity(12);
+        file.seek(SeekFrom::Start(start))?;
+        file.by_ref().take(12).read_to_end(&mut head)?;
+        match head.as_slice() {
+            [b'I', b'D', b'3', _, _, flags, size @ ..] if head.len() >= 10 => {
+                let size = size[..4].iter().fold(0u64, |size, &byte| size << 7 | u64::from(byte & 0x7F));
+                start += 10 + size + if flags & 0x10 != 0 { 10 } else { 0 };
+            }
+            _ => return Ok(head),
+        }
+    }
+}
+
+fn open_file(path: &Path) -> Result<(File, &'static str), AudioRemoteError> {
+    let io = |e: std::io::Error| AudioRemoteError::Io(format!("can't open {}: {e}", path.display()));
+    let mut file = File::open(path).map_err(io)?;
+    let container = container(&head(&mut file).map_err(io)?)
+        .ok_or(AudioRemoteError::Unsupported(format!("{} isn't a WAV, AIFF, FLAC, MP3, AAC, MP4 or Ogg file", path.display())))?;
+    file.rewind().map_err(io)?;
+    Ok((file, container))
+}
+
+fn probe_file(path: &Path, file: File) -> Result<ProbeResult, AudioRemoteError> {
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    symphonia::default::get_probe()
+        .format(&hint, stream, &FormatOptions::default(), &MetadataOptions::default())
+        .map_err(|e| decode_error(path, e))
+}
+
+/// Open `path` for decoding
+pub(crate) fn open(path: &Path) -> Result<ProbeResult, AudioRemoteError> {
+    let (file, _) = open_file(path)?;
+    probe_file(path, file)
+}
+
+/// The first track with audio in it
+pub(crate) fn audio_track<'a>(path: &Path, format: &'a dyn FormatReader) -> Result<&'a Track, AudioRemoteError> {
+    format
+        .tracks()
+        .iter()
+        .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(AudioRemoteError::Unsupported(format!("{} has no audio track", path.display())))
+}
+
+/// Identify `path` and read its headers
+pub fn probe(path: &Path) -> Result<AudioFileInfo, AudioRemoteError> {
+    let (file, container) = open_file(path)?;
+    let size_bytes = file.metadata().map_err(|e| AudioRemoteError::Io(format!("can't open {}: {e}", path.display())))?.len();
+    let mut probed = probe_file(path, file)?;
+    let CodecParameters { codec, sample_rate, channels, bits_per_sample, n_frames, .. } = audio_track(path, probed.format.as_ref())?.codec_params.clone();
+    let codec = symphonia::default::get_codecs().get_codec(codec).map_or("unknown", |descriptor| descriptor.short_name).to_string();
+    let lossless = LOSSLESS.iter().any(|name| codec.split('_').next() == Some(name));
+
+    // Tags may come before the container (ID3v2) or inside it
+    let (mut has_tags, mut has_artwork) = (false, false);
+    let mut note = |revision: Option<&MetadataRevision>| {
+        if let Some(revision) = revision {
+            has_tags |= !revision.tags().is_empty();
+            has_artwork |= !revision.visuals().is_empty();
+        }
+    };
+    if let Some(metadata) = probed.metadata.get() {
+        note(metadata.current());
+    }
+    note(probed.format.metadata().current());
+
+    Ok(AudioFileInfo {
+        container,
+        lossless,
+        sample_rate,
+        channels: channels.map(|channels| channels.count() as u32),
+        bit_depth: bits_per_sample.filter(|_| lossless),
+        frames: n_frames,
+        duration_seconds: n_frames.zip(sample_rate).map(|(frames, rate)| frames as f64 / f64::from(rate)),
+        codec,
+        has_tags,
+        has_artwork,
+        size_bytes,
+    })
+}
+
+/// Identify a local audio file and read its format from the headers, without decoding it
+/// Returns: JSON {"container" ("wav", "aiff", "flac", "mp3", "aac", "mp4", "ogg"), "codec" (e.g.
+/// "pcm_s16le", "flac", "alac", "mp3", "aac", "vorbis"), "lossless", "sampleRate", "channels",
+/// "bitDepth" (null for lossy codecs), "frames", "durationSeconds", "hasTags", "hasArtwork",
+/// "sizeBytes"}, fields the headers don't give null (free with rust_string_free), NULL on error
+/// or if it isn't an audio file (see last_error_message)
+///
+/// # Safety
+/// `path_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_probe_audio_file(path_ptr: *const c_char) -> *mut c_char {
+    guard("ar_probe_audio_file", std::ptr::null_mut(), || {
+        let result = str_arg(path_ptr, "path")
+            .and_then(|path| probe(Path::new(path)))
+            .and_then(|info| serde_json::to_string(&info).map_err(|e| AudioRemoteError::Other(e.to_string())));
+        string_result(result)
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+    use std::ffi::CString;
+
+    /// A 16-bit WAV of a 1 kHz tone at `dbfs`, the same in every channel, with `chunk` (e.g. a
+    /// LIST/INFO tag) before the audio
+    pub(crate) fn wav_with(name: &str, channels: u16, seconds: f64, dbfs: f64, chunk: &[u8]) -> std::path::PathBuf {
+        let (rate, amplitude) = (44_100u32, 10f64.powf(dbfs / 20.0));
+        let samples: Vec<i16> = (0..(f64::from(rate) * seconds) as usize)
+            .map(|i| (amplitude * 32767.0 * (2.0 * std::f64::consts::PI * 1000.0 * i as f64 / f64::from(rate)).sin()).round() as i16)
+            .flat_map(|sample| std::iter::repeat_n(sample, usize::from(channels)))
+            .collect();
+        let data_len = samples.len() as u32 * 2;
+        let mut bytes = b"RIFF".to_vec();
+        bytes.extend_from_slice(&(36 + chunk.len() as u32 + data_len).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        let block = u32::from(channels) * 2;
+        for field in [16u32, 1 | (u32::from(channels) << 16), rate, rate * block, block | (16 << 16)] {
+            bytes.extend_from_slice(&field.to_le_bytes());
+        }
+        bytes.extend_from_slice(chunk);
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&data_len.to_le_bytes());
+        bytes.extend(samples.iter().flat_map(|sample| sample.to_le_bytes()));
+        let path = std::env::temp_dir().join(format!("audioremote-{name}-{}.wav", std::process::id()));
+        std::fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    pub(crate) fn wav(name: &str, channels: u16, seconds: f64, dbfs: f64) -> std::path::PathBuf {
+        wav_with(name, channels, seconds, dbfs, &[])
+    }
+
+    #[test]
+    fn test_container() {
+        assert_eq!(container(b"RIFF\0\0\0\0WAVEfmt "), Some("wav"));
+        assert_eq!(container(b"FORM\0\0\0\0AIFC"), Some("aiff"));
+        assert_eq!(container(b"\0\0\0\x20ftypM4A "), Some("mp4"));
+        assert_eq!(container(&[0xFF, 0xF1, 0x50, 0x80]), Some("aac"));
+        assert_eq!(container(&[0xFF, 0xFB, 0x90, 0x64]), Some("mp3"));
+        assert_eq!(container(b"RIFF\0\0\0\0AVI "), None);
+
+        // An ID3v2 tag is skipped to find what's under it
+        let path = std::env::temp_dir().join(format!("audioremote-probe-id3-{}.flac", std::process::id()));
+        let mut bytes = b"ID3\x04\x00\x00\x00\x00\x01\x00".to_vec();
+        bytes.extend_from_slice(&[0; 128]);
+        bytes.extend_from_slice(b"fLaC\0\0\0\x22");
+        std::fs::write(&path, &bytes).unwrap();
+        assert_eq!(head(&mut File::open(&path).unwrap()).unwrap()[..4], *b"fLaC");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_probe_wav() {
+        let path = wav("probe", 2, 1.5, -6.0);
+        let info = probe(&path).unwrap();
+        assert_eq!((info.container, info.codec.as_str(), info.lossless), ("wav", "pcm_s16le", true));
+        assert_eq!((info.sample_rate, info.channels, info.bit_depth, info.frames), (Some(44_100), Some(2), Some(16), Some(66_150)));
+        assert_eq!((info.duration_seconds, info.has_tags, info.size_bytes), (Some(1.5), false, 44 + 66_150 * 4));
+
+        let tagged = wav_with("probe-tagged", 1, 0.5, -6.0, b"LIST\x12\0\0\0INFOINAM\x06\0\0\0Title\0");
+        assert!(probe(&tagged).unwrap().has_tags);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&tagged).unwrap();
+    }
+
+    #[test]
+    fn test_ffi() {
+        let path = wav("probe-ffi", 1, 0.25, -6.0);
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let json = take_c_string(unsafe { ar_probe_audio_file(c_path.as_ptr()) }).unwrap();
+        assert!(json.contains("\"container\":\"wav\"") && json.contains("\"channels\":1"), "{json}");
+        std::fs::write(&path, b"<html></html>").unwrap();
+        assert_eq!(take_c_string(unsafe { ar_probe_audio_file(c_path.as_ptr()) }), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
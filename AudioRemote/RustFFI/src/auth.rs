@@ -0,0 +1,422 @@
+//! Access tokens for paired remotes. Pairing ends with a token pair: a short-lived
+//! access token sent as `Authorization: Bearer` on every request, and a refresh
+//! token that trades itself for a new pair at `POST /api/v1/auth/refresh`. Once
+//! the refresh token has lapsed too, a remote proves it holds its pairing key at
+//! `POST /api/v1/auth/token` instead of pairing again.
+//!
+//! Refresh tokens rotate: each works once, and a used one goes on the revocation
+//! list until it would have expired. Seeing it again means it was copied, so
+//! every token of that remote is revoked. Only hashes of tokens are stored.
+
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::string_result;
+use crate::openapi;
+use crate::pairing::{self, Rejection};
+use crate::server::{Request, Response};
+use crate::sessions::{self, Scope};
+use crate::ssdp;
+use crate::websocket;
+use crate::{guard, record, store, str_arg, AudioRemoteError};
+
+pub const TOKEN_PATH: &str = "/api/v1/auth/token";
+pub const REFRESH_PATH: &str = "/api/v1/auth/refresh";
+
+const ACCESS_TTL: u64 = 3600;
+const REFRESH_TTL: u64 = 30 * 24 * 3600;
+/// How far a key proof's timestamp may be from the Mac's clock
+const PROOF_SKEW: u64 = 300;
+
+const LEDGER_KEY: &str = "auth.tokens";
+
+/// The ledger as last read from or written to the store, with the store directory it belongs
+/// to; it's written back only when it changes. Also serializes changes to it.
+static LEDGER_LOCK: Mutex<Option<(PathBuf, Ledger)>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Kind {
+    Access,
+    Refresh,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Issued {
+    remote_id: String,
+    kind: Kind,
+    /// Unix time
+    expires: u64,
+}
+
+/// Every live token and every used refresh token, keyed by SHA-256 of the token
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Ledger {
+    tokens: BTreeMap<String, Issued>,
+    revoked: BTreeMap<String, Issued>,
+    /// Newest key-proof timestamp accepted per remote, so a proof can't be replayed
+    proofs: BTreeMap<String, u64>,
+}
+
+impl Ledger {
+    fn load() -> Self {
+        store::global().get(LEDGER_KEY).unwrap_or_default()
+    }
+
+    fn save(&mut self, now: u64) -> Result<(), AudioRemoteError> {
+        self.tokens.retain(|_, issued| issued.expires > now);
+        self.revoked.retain(|_, issued| issued.expires > now);
+        let result = store::global().set(LEDGER_KEY, &*self);
+        if result.is_err() {
+            // Stay in step with what's on disk
+            *self = Self::load();
+        }
+        result
+    }
+
+    fn revoke_remote(&mut self, remote_id: &str) {
+        let tokens = std::mem::take(&mut self.tokens);
+        let (revoked, kept): (BTreeMap<_, _>, BTreeMap<_, _>) = tokens.into_iter().partition(|(_, issued)| issued.remote_id == remote_id);
+        self.tokens = kept;
+        self.revoked.extend(revoked);
+    }
+
+    fn issue(&mut self, remote_id: &str, now: u64) -> Result<Tokens, AudioRemoteError> {
+        let access_token = new_token()?;
+        let refresh_token = new_token()?;
+        let issued = |kind, ttl| Issued { remote_id: remote_id.to_owned(), kind, expires: now + ttl };
+        self.tokens.insert(hash(&access_token), issued(Kind::Access, ACCESS_TTL));
+        self.tokens.insert(hash(&refresh_token), issued(Kind::Refresh, REFRESH_TTL));
+        Ok(Tokens { access_token, refresh_token, expires_in: ACCESS_TTL })
+    }
+}
+
+fn with_ledger<T>(f: impl FnOnce(&mut Ledger) -> T) -> T {
+    let mut cached = LEDGER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = store::directory();
+    if !matches!(&*cached, Some((cached_dir, _)) if *cached_dir == dir) {
+        *cached = Some((dir, Ledger::load()));
+    }
+    f(&mut cached.as_mut().expect("the ledger was just loaded").1)
+}
+
+/// What a remote gets after pairing, refreshing or proving its key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tokens {
+    pub access_token: String,
+    pub refresh_token: String,
+    /// Seconds the access token is valid for
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct TokenRequest {
+    remote_id: String,
+    /// Unix time the proof was made
+    timestamp: u64,
+    /// base64 HMAC-SHA256 of `proof_message(remote_id, timestamp)`, keyed with the pairing key
+    proof: String,
+}
+
+/// A paired remote, as the settings UI lists it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteInfo {
+    pub remote_id: String,
+    pub remote_name: String,
+    pub paired_at: u64,
+    /// When its refresh token lapses, if it holds one
+    pub signed_in_until: Option<u64>,
+    pub scope: Scope,
+}
+
+fn new_token() -> Result<String, AudioRemoteError> {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).map_err(|e| AudioRemoteError::Other(format!("no randomness available: {e}")))?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+fn hash(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn proof_message(remote_id: &str, timestamp: u64) -> String {
+    format!("audioremote token {remote_id} {timestamp}")
+}
+
+/// Issue a token pair for a remote that just paired
+pub fn issue(remote_id: &str) -> Result<Tokens, AudioRemoteError> {
+    let now = pairing::now();
+    with_ledger(|ledger| {
+        let tokens = ledger.issue(remote_id, now)?;
+        ledger.save(now)?;
+        Ok(tokens)
+    })
+}
+
+/// Trade a refresh token for a new pair; the old one stops working
+fn refresh(refresh_token: &str) -> Result<Tokens, Rejection> {
+    let now = pairing::now();
+    let key = hash(refresh_token);
+    with_ledger(|ledger| {
+        if let Some(reused) = ledger.revoked.get(&key).filter(|issued| issued.kind == Kind::Refresh).cloned() {
+            log::warn!("refresh token of {} was used twice; revoking all its tokens", reused.remote_id);
+            ledger.revoke_remote(&reused.remote_id);
+            ledger.save(now).map_err(|e| (500, e.to_string()))?;
+            return Err((401, "this refresh token was already used; sign in again".into()));
+        }
+        let issued = match ledger.tokens.get(&key) {
+            Some(issued) if issued.kind == Kind::Refresh && issued.expires > now => issued.clone(),
+            _ => return Err((401, "unknown or expired refresh token".into())),
+        };
+        if !pairing::is_paired(&issued.remote_id) {
+            return Err((401, "this remote isn't paired any more".into()));
+        }
+        ledger.tokens.remove(&key);
+        let remote_id = issued.remote_id.clone();
+        ledger.revoked.insert(key, issued);
+        let tokens = ledger.issue(&remote_id, now).map_err(|e| (500, e.to_string()))?;
+        ledger.save(now).map_err(|e| (500, e.to_string()))?;
+        Ok(tokens)
+    })
+}
+
+/// Issue tokens to a remote that proves it holds its pairing key
+fn token_with_key(request: &TokenRequest) -> Result<Tokens, Rejection> {
+    let now = pairing::now();
+    let unauthorized = || (401, "invalid key proof".to_owned());
+    let pairing = pairing::pairings().remove(&request.remote_id).ok_or_else(unauthorized)?;
+    if request.timestamp.abs_diff(now) > PROOF_SKEW {
+        return Err((401, "the proof's timestamp is too far from this Mac's clock".into()));
+    }
+    let key = STANDARD.decode(&pairing.key).map_err(|e| (500, e.to_string()))?;
+    let proof = STANDARD.decode(&request.proof).map_err(|_| unauthorized())?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC takes any key length");
+    mac.update(proof_message(&request.remote_id, request.timestamp).as_bytes());
+    mac.verify_slice(&proof).map_err(|_| unauthorized())?;
+    with_ledger(|ledger| {
+        if ledger.proofs.get(&request.remote_id).is_some_and(|last| request.timestamp <= *last) {
+            return Err((401, "this proof was already used".into()));
+        }
+        ledger.proofs.insert(request.remote_id.clone(), request.timestamp);
+        let tokens = ledger.issue(&request.remote_id, now).map_err(|e| (500, e.to_string()))?;
+        ledger.save(now).map_err(|e| (500, e.to_string()))?;
+        Ok(tokens)
+    })
+}
+
+/// The paired remote an access token belongs to, if it's valid
+pub fn authenticate(access_token: &str) -> Option<String> {
+    let now = pairing::now();
+    let issued = with_ledger(|ledger| ledger.tokens.get(&hash(access_token)).cloned())?;
+    (issued.kind == Kind::Access && issued.expires > now && pairing::is_paired(&issued.remote_id)).then_some(issued.remote_id)
+}
+
+/// The server's check on every request: records the remote a valid token belongs to in
+/// `request.remote`, and with `required` refuses requests without one. Pairing, signing in,
+/// preflight requests and the control page stay open.
+pub(crate) fn authorize(request: &mut Request, required: bool) -> Result<(), Response> {
+    let open = request.method == "OPTIONS"
+        || (request.method == "GET" && [openapi::PATH, "/"].contains(&request.path.as_str()))
+        || [pairing::START_PATH, pairing::FINISH_PATH, TOKEN_PATH, REFRESH_PATH].contains(&request.path.as_str())
+        || ssdp::is_public(&request.path);
+    let token = match request.header("authorization") {
+        Some(value) => value.strip_prefix("Bearer ").map(|token| token.trim().to_owned()),
+        // Browsers can't set headers on WebSocket connections; anywhere else a token in the URL
+        // would end up in logs, history and Referer headers
+        None if websocket::is_upgrade(request) => request.query_param("access_token"),
+        None => None,
+    };
+    request.remote = token.as_deref().and_then(authenticate);
+    if !required || open || request.remote.is_some() {
+        return Ok(());
+    }
+    let message = match token {
+        Some(_) => "the access token is invalid or expired",
+        None => "pair with this Mac first",
+    };
+    Err(Response::error(401, message).with_header("WWW-Authenticate", "Bearer realm=\"AudioRemote\""))
+}
+
+/// Serve the sign-in routes; None for any other path
+pub(crate) fn route(request: &Request) -> Option<Response> {
+    if request.path != TOKEN_PATH && request.path != REFRESH_PATH {
+        return None;
+    }
+    if request.method != "POST" {
+        return Some(Response::error(405, format!("{} isn't allowed here", request.method)).with_header("Allow", "POST"));
+    }
+    let result = match request.path == TOKEN_PATH {
+        true => pairing::parse(&request.body).and_then(|body| token_with_key(&body)),
+        false => pairing::parse(&request.body).and_then(|body: RefreshRequest| refresh(&body.refresh_token)),
+    };
+    Some(match result {
+        Ok(tokens) => Response::json(200, &tokens),
+        Err((status, message)) => Response::error(status, message),
+    })
+}
+
+pub fn remotes() -> Vec<RemoteInfo> {
+    let now = pairing::now();
+    with_ledger(|ledger| {
+        pairing::pairings()
+            .into_values()
+            .map(|pairing| RemoteInfo {
+                signed_in_until: ledger
+                    .tokens
+                    .values()
+                    .filter(|issued| issued.remote_id == pairing.remote_id && issued.kind == Kind::Refresh && issued.expires > now)
+                    .map(|issued| issued.expires)
+                    .max(),
+                scope: sessions::scope_of(&pairing.remote_id),
+                remote_id: pairing.remote_id,
+                remote_name: pairing.remote_name,
+                paired_at: pairing.paired_at,
+            })
+            .collect()
+    })
+}
+
+/// Forget a remote: its pairing and every token it holds. Returns whether it was paired.
+pub fn revoke(remote_id: &str) -> Result<bool, AudioRemoteError> {
+    let removed = with_ledger(|ledger| {
+        ledger.revoke_remote(remote_id);
+        ledger.proofs.remove(remote_id);
+        ledger.save(pairing::now())?;
+        pairing::remove(remote_id)
+    })?;
+    sessions::forget(remote_id)?;
+    if removed {
+        log::info!("revoked remote {remote_id}");
+    }
+    Ok(removed)
+}
+
+/// Returns: JSON array of paired remotes, [{"remoteId", "remoteName", "pairedAt", "signedInUntil",
+/// "scope"}] with Unix times, signedInUntil null if the remote must sign in again (free with
+/// rust_string_free), NULL on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_auth_remotes() -> *mut c_char {
+    guard("ar_auth_remotes", std::ptr::null_mut(), || {
+        string_result(serde_json::to_string(&remotes()).map_err(|e| AudioRemoteError::Other(e.to_string())))
+    })
+}
+
+/// Unpair a remote and revoke its tokens; its next request is refused and it has to pair again.
+/// Returns: 1 if it was paired, 0 if not, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `remote_id` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_auth_revoke(remote_id: *const c_char) -> i32 {
+    guard("ar_auth_revoke", -999, || match record(str_arg(remote_id, "remote ID").and_then(revoke)) {
+        Some(revoked) => i32::from(revoked),
+        None => -999,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pairing::Pairing;
+    use crate::server::tests::parse;
+    use crate::store::tests::with_temp_store;
+
+    fn pair(remote_id: &str) -> [u8; 32] {
+        let key = [7; 32];
+        let pairing =
+            Pairing { remote_id: remote_id.into(), remote_name: "iPad".into(), key: STANDARD.encode(key), paired_at: pairing::now() };
+        pairing::save(pairing).unwrap();
+        key
+    }
+
+    fn get(path: &str, token: Option<&str>) -> Request {
+        let authorization = token.map(|token| format!("Authorization: Bearer {token}\r\n")).unwrap_or_default();
+        parse(&format!("GET {path} HTTP/1.1\r\nHost: mac\r\n{authorization}\r\n")).unwrap()
+    }
+
+    #[test]
+    fn test_refresh_rotates_and_detects_reuse() {
+        let (_lock, _dir) = with_temp_store("auth-refresh");
+        pair("ipad");
+        let first = issue("ipad").unwrap();
+        assert_eq!(authenticate(&first.access_token).as_deref(), Some("ipad"));
+        assert_eq!(authenticate(&first.refresh_token), None, "a refresh token isn't an access token");
+
+        let second = refresh(&first.refresh_token).unwrap();
+        assert_ne!(second.refresh_token, first.refresh_token);
+        assert_eq!(authenticate(&second.access_token).as_deref(), Some("ipad"));
+        assert!(remotes()[0].signed_in_until.is_some());
+
+        // The first refresh token resurfacing means it leaked: everything is revoked
+        assert_eq!(refresh(&first.refresh_token).unwrap_err().0, 401);
+        assert_eq!(authenticate(&second.access_token), None);
+        assert_eq!(refresh(&second.refresh_token).unwrap_err().0, 401);
+        assert_eq!(remotes()[0].signed_in_until, None);
+    }
+
+    #[test]
+    fn test_requests_are_checked_from_memory() {
+        let (_lock, dir) = with_temp_store("auth-memory");
+        pair("watch");
+        let tokens = issue("watch").unwrap();
+        // Gone from disk, still known: checking a token doesn't read the store
+        std::fs::remove_file(dir.join("state.json")).unwrap();
+        assert_eq!(authenticate(&tokens.access_token).as_deref(), Some("watch"));
+        assert!(!dir.join("state.json").exists(), "checking a token doesn't write the store either");
+
+        // Changes are still written through
+        let refreshed = refresh(&tokens.refresh_token).unwrap();
+        assert_eq!(store::global().get::<Ledger>(LEDGER_KEY).unwrap().tokens.len(), 3);
+        assert_eq!(authenticate(&refreshed.access_token).as_deref(), Some("watch"));
+    }
+
+    #[test]
+    fn test_middleware_and_key_sign_in() {
+        let (_lock, _dir) = with_temp_store("auth-middleware");
+        let key = pair("phone");
+        let now = pairing::now();
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+        mac.update(proof_message("phone", now).as_bytes());
+        let request = TokenRequest { remote_id: "phone".into(), timestamp: now, proof: STANDARD.encode(mac.finalize().into_bytes()) };
+        let tokens = token_with_key(&request).unwrap();
+        assert_eq!(token_with_key(&request).unwrap_err(), (401, "this proof was already used".into()));
+
+        let mut request = get("/api/v1/volume", Some(&tokens.access_token));
+        assert!(authorize(&mut request, true).is_ok());
+        assert_eq!(request.remote.as_deref(), Some("phone"));
+        // In the URL only to open a WebSocket
+        let mut request = get(&format!("/api/v1/events?access_token={}", tokens.access_token), None);
+        assert_eq!(authorize(&mut request, true).unwrap_err().status, 401);
+        let upgrade = format!("GET {}?access_token={} HTTP/1.1\r\nHost: mac\r\nUpgrade: websocket\r\n\r\n", websocket::PATH, tokens.access_token);
+        let mut request = parse(&upgrade).unwrap();
+        assert!(authorize(&mut request, true).is_ok());
+        assert_eq!(request.remote.as_deref(), Some("phone"));
+        assert_eq!(authorize(&mut get("/api/v1/volume", None), true).unwrap_err().status, 401);
+        assert!(authorize(&mut get("/api/v1/volume", None), false).is_ok());
+        assert!(authorize(&mut get("/", None), true).is_ok());
+
+        assert!(revoke("phone").unwrap());
+        assert!(!revoke("phone").unwrap());
+        assert_eq!(authorize(&mut get("/api/v1/volume", Some(&tokens.access_token)), true).unwrap_err().status, 401);
+        assert!(remotes().is_empty());
+    }
+}
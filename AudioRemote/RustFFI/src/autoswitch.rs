@@ -0,0 +1,480 @@
+//! Switching the default output by the user's priorities, e.g. AirPods over
+//! the USB DAC over the built-in speakers. Whenever the device registry
+//! changes this picks the connected output highest on the list, and asks
+//! Swift, through the callback, to make it the default.
+//!
+//! Two delays keep it from flapping: a device must have stayed connected for
+//! `settleMs` before it's switched to (Bluetooth headphones often connect,
+//! drop and reconnect), and after a switch the next waits `holdMs`, unless
+//! the default device went away. Choosing a device by hand pins it until it
+//! goes away or another device connects.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void, CString};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+
+use crate::device_class::DeviceCategory;
+use crate::devices::{self, DeviceRecord, Snapshot, Transport};
+use crate::error::string_result;
+use crate::runtime::{self, SendPtr};
+use crate::{guard, record, store, str_arg, AudioRemoteError};
+
+const CONFIG_KEY: &str = "autoswitch.config";
+
+/// (switch_json, ctx): {"id", "objectId", "name", "rule", "reason"}; the JSON is only valid during the call
+pub type AutoSwitchCallback = extern "C" fn(switch_json: *const c_char, ctx: *mut c_void);
+
+/// Matches devices with every field given
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Rule {
+    /// A device's UID
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Case-insensitive, in the name or the nickname
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name_contains: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transport: Option<Transport>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<DeviceCategory>,
+}
+
+impl Rule {
+    fn matches(&self, device: &DeviceRecord) -> bool {
+        self.id.as_ref().is_none_or(|id| *id == device.id)
+            && self.name_contains.as_ref().is_none_or(|name| {
+                let name = name.to_lowercase();
+                device.name.to_lowercase().contains(&name) || device.nickname.as_ref().is_some_and(|nickname| nickname.to_lowercase().contains(&name))
+            })
+            && self.transport.is_none_or(|transport| transport == device.transport)
+            && self.category.is_none_or(|category| category == device.category)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AutoSwitchConfig {
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Most preferred first; devices no rule matches are never switched to
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    #[serde(default = "default_settle_ms")]
+    pub settle_ms: u64,
+    #[serde(default = "default_hold_ms")]
+    pub hold_ms: u64,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_settle_ms() -> u64 {
+    2000
+}
+
+fn default_hold_ms() -> u64 {
+    10_000
+}
+
+impl Default for AutoSwitchConfig {
+    fn default() -> Self {
+        Self { enabled: default_enabled(), rules: vec![], settle_ms: default_settle_ms(), hold_ms: default_hold_ms() }
+    }
+}
+
+impl AutoSwitchConfig {
+    pub fn validate(&self) -> Result<(), AudioRemoteError> {
+        if self.rules.len() > 64 {
+            return Err(AudioRemoteError::InvalidArgument("at most 64 rules".into()));
+        }
+        if let Some(i) = self.rules.iter().position(|rule| *rule == Rule::default()) {
+            return Err(AudioRemoteError::InvalidArgument(format!("rule {i} matches every device")));
+        }
+        if self.settle_ms > 60_000 || self.hold_ms > 600_000 {
+            return Err(AudioRemoteError::InvalidArgument("settleMs must be 0–60000 and holdMs 0–600000".into()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Reason {
+    /// A device higher on the list is connected
+    Preferred,
+    /// The default device went away
+    Fallback,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Switch {
+    pub id: String,
+    pub object_id: Option<u32>,
+    pub name: String,
+    /// The index of the rule it matched
+    pub rule: usize,
+    pub reason: Reason,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Evaluation {
+    pub switch: Option<Switch>,
+    /// When to look again, for a device still settling or a hold to run out
+    pub recheck: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct Engine {
+    config: AutoSwitchConfig,
+    /// Connected outputs and when they connected
+    seen: BTreeMap<String, Instant>,
+    default_output: Option<String>,
+    /// The device last switched to, until it becomes the default
+    requested: Option<String>,
+    switched_at: Option<Instant>,
+    /// Chosen by hand
+    pinned: Option<String>,
+}
+
+impl Engine {
+    pub fn new(config: AutoSwitchConfig) -> Result<Self, AudioRemoteError> {
+        config.validate()?;
+        Ok(Self { config, seen: BTreeMap::new(), default_output: None, requested: None, switched_at: None, pinned: None })
+    }
+
+    pub fn config(&self) -> &AutoSwitchConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: AutoSwitchConfig) -> Result<(), AudioRemoteError> {
+        config.validate()?;
+        self.config = config;
+        Ok(())
+    }
+
+    fn rank(&self, device: &DeviceRecord) -> Option<usize> {
+        self.config.rules.iter().position(|rule| rule.matches(device))
+    }
+
+    /// Follow the registry's latest `snapshot`, and say where to switch if anywhere
+    pub fn evaluate(&mut self, snapshot: &Snapshot, now: Instant) -> Evaluation {
+        let outputs: Vec<&DeviceRecord> = snapshot.devices.iter().filter(|device| device.connected && device.output_channels > 0).collect();
+        let arrived = outputs.iter().any(|device| !self.seen.contains_key(&device.id));
+        self.seen.retain(|id, _| outputs.iter().any(|device| device.id == *id));
+        for device in &outputs {
+            self.seen.entry(device.id.clone()).or_insert(now);
+        }
+        let connected = |id: &String| outputs.iter().any(|device| device.id == *id);
+
+        // A new default we didn't ask for, while the old one is still there, was chosen by hand
+        let current = snapshot.default_output.clone().filter(connected);
+        if current != self.default_output {
+            match &current {
+                Some(id) if self.requested.as_ref() == Some(id) => self.requested = None,
+                Some(id) if self.default_output.as_ref().is_some_and(connected) => self.pinned = Some(id.clone()),
+                _ => {}
+            }
+            self.default_output = current.clone();
+        }
+        if arrived || self.pinned.as_ref().is_some_and(|pinned| current.as_ref() != Some(pinned)) {
+            self.pinned = None;
+        }
+        if !self.config.enabled {
+            return Evaluation::default();
+        }
+
+        let settle = Duration::from_millis(self.config.settle_ms);
+        let mut ranked: Vec<(usize, &DeviceRecord)> = outputs.iter().filter_map(|device| Some((self.rank(device)?, *device))).collect();
+        ranked.sort_by_key(|(rank, _)| *rank);
+        let settled = |device: &DeviceRecord| now.saturating_duration_since(self.seen[&device.id]) >= settle;
+        let best = ranked.iter().find(|(_, device)| settled(device));
+        // Anything better still settling is worth another look
+        let settling = ranked
+            .iter()
+            .take_while(|(rank, _)| best.is_none_or(|(best, _)| rank < best))
+            .map(|(_, device)| settle.saturating_sub(now.saturating_duration_since(self.seen[&device.id])))
+            .min();
+
+        let mut evaluation = Evaluation { switch: None, recheck: settling };
+        let Some(&(rank, device)) = best else {
+            return evaluation;
+        };
+        if current.as_ref() == Some(&device.id) {
+            return evaluation;
+        }
+        let reason = match &current {
+            None => Reason::Fallback,
+            Some(_) if self.pinned.is_some() => return evaluation,
+            Some(id) => {
+                let current_rank = outputs.iter().find(|device| device.id == *id).and_then(|device| self.rank(device));
+                if current_rank.is_some_and(|current_rank| current_rank <= rank) {
+                    return evaluation;
+                }
+                let held = self.switched_at.map_or(Duration::ZERO, |at| Duration::from_millis(self.config.hold_ms).saturating_sub(now - at));
+                if !held.is_zero() {
+                    evaluation.recheck = Some(evaluation.recheck.map_or(held, |recheck| recheck.min(held)));
+                    return evaluation;
+                }
+                Reason::Preferred
+            }
+        };
+        (self.requested, self.switched_at) = (Some(device.id.clone()), Some(now));
+        evaluation.switch = Some(Switch { id: device.id.clone(), object_id: device.object_id, name: device.name.clone(), rule: rank, reason });
+        evaluation
+    }
+}
+
+/// A look at the registry waiting on the timer runtime
+struct Recheck {
+    at: Instant,
+    task: AbortHandle,
+}
+
+struct AutoSwitch {
+    engine: Engine,
+    callback: Option<AutoSwitchCallback>,
+    ctx: SendPtr,
+    recheck: Option<Recheck>,
+}
+
+static AUTOSWITCH: Mutex<Option<AutoSwitch>> = Mutex::new(None);
+
+impl AutoSwitch {
+    /// Look again at `at`, unless a recheck is already due sooner; a later one is cancelled
+    fn schedule(&mut self, at: Instant) -> Result<(), AudioRemoteError> {
+        if self.recheck.as_ref().is_some_and(|recheck| !recheck.task.is_finished() && recheck.at <= at) {
+            return Ok(());
+        }
+        if let Some(recheck) = self.recheck.take() {
+            recheck.task.abort();
+        }
+        let task = runtime::timers()?.spawn(async move {
+            tokio::time::sleep_until(at.into()).await;
+            evaluate();
+        });
+        self.recheck = Some(Recheck { at, task: task.abort_handle() });
+        Ok(())
+    }
+}
+
+/// Run `body` on the engine, set up with the stored config if it hasn't been
+fn with_autoswitch<T>(body: impl FnOnce(&mut AutoSwitch) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let mut autoswitch = AUTOSWITCH.lock().unwrap_or_else(|e| e.into_inner());
+    let autoswitch = match &mut *autoswitch {
+        Some(autoswitch) => autoswitch,
+        None => {
+            let engine = Engine::new(store::global().get(CONFIG_KEY).unwrap_or_default()).or_else(|_| Engine::new(AutoSwitchConfig::default()))?;
+            autoswitch.insert(AutoSwitch { engine, callback: None, ctx: SendPtr(std::ptr::null_mut()), recheck: None })
+        }
+    };
+    body(autoswitch)
+}
+
+/// Look at the registry again; called whenever it changes
+pub fn evaluate() {
+    let snapshot = devices::snapshot();
+    let now = Instant::now();
+    let result = with_autoswitch(|autoswitch| {
+        let evaluation = autoswitch.engine.evaluate(&snapshot, now);
+        if let Some(after) = evaluation.recheck {
+            if let Err(e) = autoswitch.schedule(now + after) {
+                log::warn!("can't schedule the next device check: {e}");
+            }
+        }
+        Ok((evaluation.switch, autoswitch.callback, autoswitch.ctx))
+    });
+    let Ok((switch, callback, ctx)) = result else {
+        return;
+    };
+    if let Some(switch) = switch {
+        log::info!("switching the output to {} ({:?})", switch.name, switch.reason);
+        if let Some(callback) = callback {
+            let json = CString::new(serde_json::to_string(&switch).unwrap_or_default()).unwrap_or_default();
+            runtime::deliver(move || callback(json.as_ptr(), ctx.get()));
+        }
+    }
+}
+
+/// Set the priorities, which are kept in the store. `config_json` (nullable): {"enabled" (default
+/// true), "rules": [{"id" (a UID), "nameContains" (in the name or nickname), "transport" (as in
+/// ar_devices_event), "category" (as in ar_devices_snapshot)}, most preferred first; a rule matches
+/// devices with every field it gives],
+/// "settleMs" (0–60000, default 2000), "holdMs" (0–600000, default 10000)}; NULL keeps the stored
+/// ones. `callback` (nullable) is asked to make a device the default output.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string; `callback` must stay
+/// callable, and `ctx` valid from any thread, until it is replaced.
+#[no_mangle]
+pub unsafe extern "C" fn ar_autoswitch_configure(config_json: *const c_char, callback: Option<AutoSwitchCallback>, ctx: *mut c_void) -> i32 {
+    guard("ar_autoswitch_configure", -999, || {
+        let config = match config_json.is_null() {
+            true => Ok(None),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str::<AutoSwitchConfig>(json).map(Some).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid auto-switch config: {e}")))
+            }),
+        };
+        let result = config.and_then(|config| {
+            with_autoswitch(|autoswitch| {
+                if let Some(config) = config {
+                    config.validate()?;
+                    store::global().set(CONFIG_KEY, &config)?;
+                    autoswitch.engine.set_config(config)?;
+                }
+                (autoswitch.callback, autoswitch.ctx) = (callback, SendPtr(ctx));
+                Ok(())
+            })
+        });
+        let result = record(result).map_or(-999, |()| 1);
+        if result == 1 {
+            evaluate();
+        }
+        result
+    })
+}
+
+/// Returns: the config as in ar_autoswitch_configure (free with rust_string_free), NULL on error
+/// (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_autoswitch_config() -> *mut c_char {
+    guard("ar_autoswitch_config", std::ptr::null_mut(), || {
+        string_result(with_autoswitch(|autoswitch| serde_json::to_string(autoswitch.engine.config()).map_err(|e| AudioRemoteError::Other(e.to_string()))))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::{DeviceEvent, DeviceRegistry, ObjectRef, RawDevice};
+    use crate::error::tests::take_c_string;
+    use crate::store::tests::with_temp_store;
+    use std::thread;
+
+    fn connect(registry: &mut DeviceRegistry, object_id: u32, uid: &str, transport: Transport) {
+        let raw = RawDevice {
+            object_id,
+            uid: uid.into(),
+            name: uid.into(),
+            manufacturer: None,
+            transport,
+            output_channels: 2,
+            input_channels: 0,
+            sample_rate: None,
+            volume: None,
+            muted: None,
+        };
+        registry.apply(DeviceEvent::Added(raw)).unwrap();
+    }
+
+    fn engine() -> Engine {
+        let rules = vec![
+            Rule { category: Some(DeviceCategory::Headphones), transport: Some(Transport::Bluetooth), ..Rule::default() },
+            Rule { transport: Some(Transport::Usb), ..Rule::default() },
+            Rule { id: Some("BuiltInSpeakerDevice".into()), ..Rule::default() },
+        ];
+        Engine::new(AutoSwitchConfig { rules, ..AutoSwitchConfig::default() }).unwrap()
+    }
+
+    fn switched_to(evaluation: &Evaluation) -> Option<(&str, Reason)> {
+        evaluation.switch.as_ref().map(|switch| (switch.id.as_str(), switch.reason))
+    }
+
+    #[test]
+    fn test_switches_to_the_preferred_device_once_settled() {
+        let (mut registry, mut engine, start) = (DeviceRegistry::new(), engine(), Instant::now());
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        connect(&mut registry, 1, "BuiltInSpeakerDevice", Transport::BuiltIn);
+        registry.apply(DeviceEvent::DefaultOutput(ObjectRef { object_id: 1 })).unwrap();
+        engine.evaluate(&registry.snapshot(), at(0));
+
+        connect(&mut registry, 2, "AirPods Pro", Transport::Bluetooth);
+        let evaluation = engine.evaluate(&registry.snapshot(), at(20_000));
+        assert_eq!((evaluation.switch, evaluation.recheck), (None, Some(Duration::from_millis(2000))));
+        let evaluation = engine.evaluate(&registry.snapshot(), at(22_000));
+        assert_eq!(switched_to(&evaluation), Some(("AirPods Pro", Reason::Preferred)));
+        assert_eq!(evaluation.switch.unwrap().rule, 0);
+        registry.apply(DeviceEvent::DefaultOutput(ObjectRef { object_id: 2 })).unwrap();
+        assert_eq!(engine.evaluate(&registry.snapshot(), at(22_100)), Evaluation::default());
+
+        // Lower on the list: no switch
+        connect(&mut registry, 3, "usb-dac", Transport::Usb);
+        assert_eq!(engine.evaluate(&registry.snapshot(), at(30_000)).switch, None);
+        assert_eq!(engine.evaluate(&registry.snapshot(), at(40_000)).switch, None);
+
+        // The AirPods go: straight back to the best there is, despite the hold
+        registry.apply(DeviceEvent::Removed(ObjectRef { object_id: 2 })).unwrap();
+        assert_eq!(switched_to(&engine.evaluate(&registry.snapshot(), at(41_000))), Some(("usb-dac", Reason::Fallback)));
+    }
+
+    #[test]
+    fn test_hold_and_manual_choice() {
+        let (mut registry, mut engine, start) = (DeviceRegistry::new(), engine(), Instant::now());
+        let at = |ms: u64| start + Duration::from_millis(ms);
+        connect(&mut registry, 1, "BuiltInSpeakerDevice", Transport::BuiltIn);
+        registry.apply(DeviceEvent::DefaultOutput(ObjectRef { object_id: 1 })).unwrap();
+        engine.evaluate(&registry.snapshot(), at(0));
+        connect(&mut registry, 3, "usb-dac", Transport::Usb);
+        engine.evaluate(&registry.snapshot(), at(0));
+        assert_eq!(switched_to(&engine.evaluate(&registry.snapshot(), at(2000))), Some(("usb-dac", Reason::Preferred)));
+        registry.apply(DeviceEvent::DefaultOutput(ObjectRef { object_id: 3 })).unwrap();
+        engine.evaluate(&registry.snapshot(), at(2100));
+
+        // Headphones right after the last switch wait out the hold
+        connect(&mut registry, 2, "AirPods", Transport::Bluetooth);
+        engine.evaluate(&registry.snapshot(), at(3000));
+        let evaluation = engine.evaluate(&registry.snapshot(), at(5000));
+        assert_eq!((evaluation.switch, evaluation.recheck), (None, Some(Duration::from_millis(7000))));
+        assert_eq!(switched_to(&engine.evaluate(&registry.snapshot(), at(12_000))), Some(("AirPods", Reason::Preferred)));
+        registry.apply(DeviceEvent::DefaultOutput(ObjectRef { object_id: 2 })).unwrap();
+        engine.evaluate(&registry.snapshot(), at(12_100));
+
+        // Back to the speakers by hand: they stay until something connects
+        registry.apply(DeviceEvent::DefaultOutput(ObjectRef { object_id: 1 })).unwrap();
+        assert_eq!(engine.evaluate(&registry.snapshot(), at(60_000)).switch, None);
+        assert_eq!(engine.evaluate(&registry.snapshot(), at(90_000)).switch, None);
+        connect(&mut registry, 4, "hdmi-tv", Transport::Hdmi);
+        assert_eq!(switched_to(&engine.evaluate(&registry.snapshot(), at(100_000))), Some(("AirPods", Reason::Preferred)));
+    }
+
+    #[test]
+    fn test_ffi() {
+        let _store = with_temp_store("autoswitch");
+        let config = c"{\"rules\": [{\"transport\": \"usb\"}, {\"nameContains\": \"Speakers\"}], \"holdMs\": 5000}";
+        assert_eq!(unsafe { ar_autoswitch_configure(config.as_ptr(), None, std::ptr::null_mut()) }, 1);
+        let saved: AutoSwitchConfig = store::global().get(CONFIG_KEY).unwrap();
+        assert_eq!((saved.rules.len(), saved.hold_ms, saved.settle_ms), (2, 5000, 2000));
+        let json: serde_json::Value = serde_json::from_str(&take_c_string(ar_autoswitch_config()).unwrap()).unwrap();
+        assert_eq!(json["rules"][1], serde_json::json!({"nameContains": "Speakers"}));
+        assert_eq!(unsafe { ar_autoswitch_configure(c"{\"rules\": [{}]}".as_ptr(), None, std::ptr::null_mut()) }, -999);
+        assert_eq!(unsafe { ar_autoswitch_configure(std::ptr::null(), None, std::ptr::null_mut()) }, 1);
+        assert_eq!(with_autoswitch(|autoswitch| Ok(autoswitch.engine.config().hold_ms)).unwrap(), 5000);
+    }
+
+    #[test]
+    fn test_a_sooner_recheck_replaces_the_pending_one() {
+        let mut autoswitch = AutoSwitch { engine: engine(), callback: None, ctx: SendPtr(std::ptr::null_mut()), recheck: None };
+        let later = Instant::now() + Duration::from_secs(3600);
+        autoswitch.schedule(later).unwrap();
+        let first = autoswitch.recheck.as_ref().unwrap().task.clone();
+        autoswitch.schedule(later + Duration::from_secs(1)).unwrap();
+        assert!(autoswitch.recheck.as_ref().unwrap().task.id() == first.id(), "the sooner one stays");
+
+        autoswitch.schedule(later - Duration::from_secs(1)).unwrap();
+        let second = autoswitch.recheck.as_ref().unwrap().task.clone();
+        assert!(second.id() != first.id());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !first.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(first.is_finished(), "the later one is cancelled");
+        second.abort();
+    }
+}
@@ -0,0 +1,354 @@
+//! Left/right balance and per-channel trims, for listeners who hear one ear
+//! better than the other and for speakers that don't match. Run over
+//! interleaved float PCM from the Swift tap in place, after the EQ.
+//!
+//! The balance turns down the side it moves away from, linearly in gain as
+//! Core Audio's stereo pan does: at 0.5 to the right the left plays at half
+//! gain and the right as it was. Left is channel 0 and right channel 1, and
+//! with six channels (L R C LFE Ls Rs, as for the mixer) the surrounds follow
+//! them. Trims are in dB, one per channel in order. Changes glide over a few
+//! milliseconds so moving the slider doesn't click.
+//!
+//! Settings are kept per output device in the store, so each pair of
+//! headphones or speakers gets its own.
+
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::string_result;
+use crate::handle::{Handle, Registry};
+use crate::store;
+use crate::volume_curve::db_to_gain;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+const DEVICES_KEY: &str = "balance.devices";
+/// How long a change of gain takes to settle, as for the limiter's boost
+const SMOOTHING_MS: f64 = 10.0;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct BalanceSettings {
+    /// -1 (left only) to 1 (right only)
+    #[serde(default)]
+    pub balance: f64,
+    /// Per channel in order; channels past the end aren't trimmed
+    #[serde(default)]
+    pub trims_db: Vec<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct BalanceConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default)]
+    pub balance: f64,
+    #[serde(default)]
+    pub trims_db: Vec<f64>,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> f64 {
+    48_000.0
+}
+
+impl Default for BalanceConfig {
+    fn default() -> Self {
+        Self { channels: default_channels(), sample_rate: default_sample_rate(), balance: 0.0, trims_db: vec![] }
+    }
+}
+
+impl BalanceSettings {
+    pub fn validate(&self) -> Result<(), AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(-1.0..=1.0).contains(&self.balance) {
+            return invalid(format!("balance {} is outside -1–1", self.balance));
+        }
+        if self.trims_db.len() > MAX_CHANNELS as usize {
+            return invalid(format!("{} trims is more than {MAX_CHANNELS} channels", self.trims_db.len()));
+        }
+        if let Some(trim) = self.trims_db.iter().find(|trim| !(-24.0..=12.0).contains(*trim)) {
+            return invalid(format!("trim {trim} dB is outside -24–12"));
+        }
+        Ok(())
+    }
+
+    /// Each of `channels` channels' gain
+    pub fn gains(&self, channels: usize) -> Vec<f64> {
+        let (left, right) = ((1.0 - self.balance).min(1.0), (1.0 + self.balance).min(1.0));
+        (0..channels)
+            .map(|channel| {
+                let side = match (channels, channel) {
+                    (1, _) => 1.0,
+                    (_, 0) | (6, 4) => left,
+                    (_, 1) | (6, 5) => right,
+                    _ => 1.0,
+                };
+                side * self.trims_db.get(channel).map_or(1.0, |&trim| db_to_gain(trim))
+            })
+            .collect()
+    }
+}
+
+pub struct Balance {
+    /// The gains being glided towards, and where each channel's gain is now
+    targets: Vec<f64>,
+    gains: Vec<f64>,
+    smoothing: f64,
+}
+
+impl Balance {
+    pub fn new(config: &BalanceConfig) -> Result<Self, AudioRemoteError> {
+        if !(1..=MAX_CHANNELS).contains(&config.channels) {
+            return Err(AudioRemoteError::InvalidArgument(format!("channels {} is outside 1–{MAX_CHANNELS}", config.channels)));
+        }
+        if !(8000.0..=768_000.0).contains(&config.sample_rate) {
+            return Err(AudioRemoteError::InvalidArgument(format!("sampleRate {} is outside 8000–768000", config.sample_rate)));
+        }
+        let mut balance = Self { targets: vec![], gains: vec![], smoothing: (-1000.0 / (SMOOTHING_MS * config.sample_rate)).exp() };
+        balance.targets = vec![1.0; config.channels as usize];
+        balance.update(&BalanceSettings { balance: config.balance, trims_db: config.trims_db.clone() })?;
+        // Nothing to glide from yet
+        balance.gains = balance.targets.clone();
+        Ok(balance)
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.targets.len()
+    }
+
+    /// Change the balance and trims; the gains glide to the new ones
+    pub fn update(&mut self, settings: &BalanceSettings) -> Result<(), AudioRemoteError> {
+        settings.validate()?;
+        if settings.trims_db.len() > self.channel_count() {
+            return Err(AudioRemoteError::InvalidArgument(format!("{} trims for {} channels", settings.trims_db.len(), self.channel_count())));
+        }
+        self.targets = settings.gains(self.channel_count());
+        Ok(())
+    }
+
+    /// Apply the gains to interleaved samples in place; a partial frame at the end is left alone
+    /// and samples that aren't finite become silence
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let channels = self.channel_count();
+        for frame in samples.chunks_exact_mut(channels) {
+            for ((sample, gain), &target) in frame.iter_mut().zip(&mut self.gains).zip(&self.targets) {
+                *gain = target + (*gain - target) * self.smoothing;
+                *sample = if sample.is_finite() { (f64::from(*sample) * *gain) as f32 } else { 0.0 };
+            }
+        }
+    }
+}
+
+static BALANCES: Registry<Mutex<Balance>> = Registry::new("balance");
+
+/// Held across each read-modify-write of the saved devices
+static DEVICES_LOCK: Mutex<()> = Mutex::new(());
+
+fn with_balance<T>(handle: Handle, body: impl FnOnce(&mut Balance) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let balance = BALANCES.get(handle)?;
+    let mut balance = balance.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut balance)
+}
+
+fn device_arg(device: &str) -> Result<&str, AudioRemoteError> {
+    match device.is_empty() || device.len() > 256 {
+        true => Err(AudioRemoteError::InvalidArgument("the device ID must be 1–256 bytes".into())),
+        false => Ok(device),
+    }
+}
+
+/// The settings saved for `device`, or the defaults
+pub fn load(device: &str) -> BalanceSettings {
+    let devices: BTreeMap<String, BalanceSettings> = store::global().get(DEVICES_KEY).unwrap_or_default();
+    devices.get(device).cloned().unwrap_or_default()
+}
+
+/// Save `settings` for `device`; saving the defaults forgets it
+pub fn save(device: &str, settings: &BalanceSettings) -> Result<(), AudioRemoteError> {
+    settings.validate()?;
+    let _guard = DEVICES_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let store = store::global();
+    let mut devices: BTreeMap<String, BalanceSettings> = store.get(DEVICES_KEY).unwrap_or_default();
+    let trimmed = settings.trims_db.iter().any(|&trim| trim != 0.0);
+    match settings.balance != 0.0 || trimmed {
+        true => devices.insert(device.to_owned(), settings.clone()),
+        false => devices.remove(device),
+    };
+    store.set(DEVICES_KEY, &devices)
+}
+
+unsafe fn json_arg<T: serde::de::DeserializeOwned>(ptr: *const c_char, name: &str) -> Result<T, AudioRemoteError> {
+    serde_json::from_str(str_arg(ptr, name)?).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid {name}: {e}")))
+}
+
+/// Create a balance stage. `config_json` (nullable): {"channels" (default 2), "sampleRate" (default
+/// 48000), "balance" (-1 left only to 1 right only, default 0), "trimsDb" ([per channel, -24–12])}
+/// Returns: a handle (free with ar_balance_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_balance_new(config_json: *const c_char) -> Handle {
+    guard("ar_balance_new", 0, || {
+        let config = match config_json.is_null() {
+            true => Ok(BalanceConfig::default()),
+            false => json_arg(config_json, "config"),
+        };
+        match record(config.and_then(|config| Balance::new(&config))) {
+            Some(balance) => BALANCES.insert(Mutex::new(balance)),
+            None => 0,
+        }
+    })
+}
+
+/// Change the balance and trims, gliding to them. `settings_json`: {"balance", "trimsDb"}, as for
+/// ar_balance_new, e.g. from ar_balance_load
+/// Returns: 1 on success, -999 on error (see last_error_message), leaving the old settings in place
+///
+/// # Safety
+/// `settings_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_balance_update(balance: Handle, settings_json: *const c_char) -> i32 {
+    guard("ar_balance_update", -999, || {
+        let result = json_arg(settings_json, "settings").and_then(|settings| with_balance(balance, |balance| balance.update(&settings)));
+        record(result).map_or(-999, |()| 1)
+    })
+}
+
+/// Apply the balance and trims to `frames` frames of interleaved float samples in place
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels writable floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_balance_process(balance: Handle, samples: *mut f32, frames: usize) -> i32 {
+    guard("ar_balance_process", -999, || {
+        let result = with_balance(balance, |balance| {
+            let len = frames
+                .checked_mul(balance.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len == 0 {
+                return Ok(1);
+            }
+            if samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            balance.process(std::slice::from_raw_parts_mut(samples, len));
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Release a balance stage from ar_balance_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_balance_free(balance: Handle) -> i32 {
+    guard("ar_balance_free", -999, || record(BALANCES.remove(balance)).map_or(-999, |_| 1))
+}
+
+/// Save the balance and trims for an output device (e.g. its Core Audio UID). `settings_json` as
+/// for ar_balance_update; saving the defaults forgets the device.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `device_id` and `settings_json` must point to valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn ar_balance_save(device_id: *const c_char, settings_json: *const c_char) -> i32 {
+    guard("ar_balance_save", -999, || {
+        let result = str_arg(device_id, "device ID").and_then(device_arg).and_then(|device| {
+            let settings: BalanceSettings = json_arg(settings_json, "settings")?;
+            save(device, &settings)
+        });
+        record(result).map_or(-999, |()| 1)
+    })
+}
+
+/// Returns: JSON {"balance", "trimsDb"} saved for an output device, the defaults if none are (free
+/// with rust_string_free), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `device_id` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_balance_load(device_id: *const c_char) -> *mut c_char {
+    guard("ar_balance_load", std::ptr::null_mut(), || {
+        let result = str_arg(device_id, "device ID")
+            .and_then(device_arg)
+            .and_then(|device| serde_json::to_string(&load(device)).map_err(|e| AudioRemoteError::Other(e.to_string())));
+        string_result(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+    use crate::store::tests::with_temp_store;
+
+    #[test]
+    fn test_gains() {
+        let settings = BalanceSettings { balance: 0.5, trims_db: vec![0.0, -6.0] };
+        let gains = settings.gains(2);
+        assert_eq!(gains[0], 0.5);
+        assert!((gains[1] - db_to_gain(-6.0)).abs() < 1e-12);
+        // The surrounds follow their side; the centre and LFE, and mono, are left alone
+        let left = BalanceSettings { balance: -0.25, trims_db: vec![] };
+        assert_eq!(left.gains(6), [1.0, 0.75, 1.0, 1.0, 1.0, 0.75]);
+        assert_eq!(left.gains(1), [1.0]);
+        assert!(BalanceSettings { balance: 1.5, trims_db: vec![] }.validate().is_err());
+        assert!(BalanceSettings { balance: 0.0, trims_db: vec![20.0] }.validate().is_err());
+    }
+
+    #[test]
+    fn test_process_glides() {
+        let mut balance = Balance::new(&BalanceConfig { balance: -1.0, ..BalanceConfig::default() }).unwrap();
+        let mut samples = vec![0.5f32; 2 * 4800];
+        balance.process(&mut samples);
+        assert_eq!((samples[0], samples[1]), (0.5, 0.0));
+
+        // Back to the centre, the right comes in over a few milliseconds rather than at once
+        balance.update(&BalanceSettings::default()).unwrap();
+        let mut samples = vec![0.5f32; 2 * 4800];
+        balance.process(&mut samples);
+        assert!(samples[1] > 0.0 && samples[1] < 0.01, "{}", samples[1]);
+        assert!(samples[2 * 480 + 1] > 0.3 && (samples[2 * 4799 + 1] - 0.5).abs() < 1e-4);
+        assert!(samples.iter().step_by(2).all(|&left| left == 0.5));
+        assert!(balance.update(&BalanceSettings { balance: 0.0, trims_db: vec![0.0; 3] }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let (_store, dir) = with_temp_store("balance");
+        let headphones = c"BuiltInHeadphoneOutputDevice";
+        let settings = c"{\"balance\": 0.2, \"trimsDb\": [-1.5, 0]}";
+        assert_eq!(unsafe { ar_balance_save(headphones.as_ptr(), settings.as_ptr()) }, 1);
+        let saved = take_c_string(unsafe { ar_balance_load(headphones.as_ptr()) }).unwrap();
+        assert_eq!(saved, "{\"balance\":0.2,\"trimsDb\":[-1.5,0.0]}");
+        let other = take_c_string(unsafe { ar_balance_load(c"AirPods".as_ptr()) }).unwrap();
+        assert_eq!(other, "{\"balance\":0.0,\"trimsDb\":[]}");
+
+        let balance = unsafe { ar_balance_new(std::ptr::null()) };
+        let saved = std::ffi::CString::new(saved).unwrap();
+        assert_eq!(unsafe { ar_balance_update(balance, saved.as_ptr()) }, 1);
+        let mut samples = [0.5f32; 2];
+        assert_eq!(unsafe { ar_balance_process(balance, samples.as_mut_ptr(), 1) }, 1);
+        assert_eq!(unsafe { ar_balance_process(balance, std::ptr::null_mut(), 1) }, -999);
+        assert_eq!(ar_balance_free(balance), 1);
+
+        // Saving the defaults forgets the device
+        assert_eq!(unsafe { ar_balance_save(headphones.as_ptr(), c"{}".as_ptr()) }, 1);
+        assert_eq!(load("BuiltInHeadphoneOutputDevice"), BalanceSettings::default());
+        assert_eq!(unsafe { ar_balance_save(c"".as_ptr(), settings.as_ptr()) }, -999);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+//! Codec, battery and signal strength of Bluetooth audio devices, from the
+//! properties Swift reads out of IOBluetooth and the IORegistry (or the
+//! system_profiler report), so remotes can show how much charge headphones
+//! have left.
+//!
+//! Those sources agree on little: the same battery level turns up as
+//! `BatteryPercentLeft` = 80, "Left Battery Level" = "80%" or
+//! `device_batteryLevelLeft` = "80 %", and codecs as "AAC", "A2DP: aptX HD"
+//! or the A2DP codec number. Keys are matched loosely, ignoring case and
+//! punctuation, and values that can't be read or are out of range are left
+//! out rather than guessed at.
+
+use std::ffi::c_char;
+
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::error::string_result;
+use crate::{guard, str_arg, AudioRemoteError};
+
+/// IOBluetooth's RSSI when there's no reading
+const RSSI_UNAVAILABLE: i64 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Codec {
+    Sbc,
+    Aac,
+    #[serde(rename = "aptX")]
+    AptX,
+    #[serde(rename = "aptXHd")]
+    AptXHd,
+    #[serde(rename = "aptXAdaptive")]
+    AptXAdaptive,
+    Ldac,
+    Lc3,
+    /// Hands-free, while the microphone is in use
+    Msbc,
+    Cvsd,
+    /// Reported, but not one of these
+    Unknown,
+}
+
+/// Percents, 0–100
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Battery {
+    /// The device's only battery, or the combined level
+    pub percent: Option<u8>,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+    pub case: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BluetoothInfo {
+    pub codec: Option<Codec>,
+    pub battery: Battery,
+    /// dBm
+    pub rssi: Option<i32>,
+}
+
+impl BluetoothInfo {
+    /// The level to show for the device as a whole: its own, or the lower earbud's
+    pub fn battery_percent(&self) -> Option<u8> {
+        let Battery { percent, left, right, .. } = self.battery;
+        percent.or(match (left, right) {
+            (Some(left), Some(right)) => Some(left.min(right)),
+            (left, right) => left.or(right),
+        })
+    }
+}
+
+/// Lowercase letters and digits only: "Left Battery Level" is "leftbatterylevel"
+fn squash(text: &str) -> String {
+    text.chars().filter(char::is_ascii_alphanumeric).map(|c| c.to_ascii_lowercase()).collect()
+}
+
+/// The first number in a value: 80, "80%", "-56 dBm", "Level: 80 %"
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(number) => number.as_f64(),
+        Value::String(text) => {
+            let start = text.char_indices().find(|&(i, c)| c.is_ascii_digit() || (c == '-' && text[i + 1..].starts_with(|c: char| c.is_ascii_digit())))?.0;
+            let end = text[start + 1..].find(|c: char| !c.is_ascii_digit() && c != '.').map_or(text.len(), |end| start + 1 + end);
+            text[start..end].parse().ok()
+        }
+        _ => None,
+    }
+}
+
+fn percent(value: &Value) -> Option<u8> {
+    number(value).filter(|percent| (0.0..=100.0).contains(percent)).map(|percent| percent.round() as u8)
+}
+
+fn codec(value: &Value) -> Option<Codec> {
+    let name = match value {
+        // A2DP's codec numbers
+        Value::Number(number) => {
+            return Some(match number.as_u64() {
+                Some(0) => Codec::Sbc,
+                Some(2) => Codec::Aac,
+                _ => Codec::Unknown,
+            })
+        }
+        Value::String(name) => squash(name),
+        _ => return None,
+    };
+    let codecs = [
+        ("aptxadaptive", Codec::AptXAdaptive),
+        ("aptxhd", Codec::AptXHd),
+        ("aptx", Codec::AptX),
+        ("ldac", Codec::Ldac),
+        ("lc3", Codec::Lc3),
+        ("msbc", Codec::Msbc),
+        ("cvsd", Codec::Cvsd),
+        ("aac", Codec::Aac),
+        ("sbc", Codec::Sbc),
+    ];
+    match name.is_empty() {
+        true => None,
+        false => Some(codecs.iter().find(|(word, _)| name.contains(word)).map_or(Codec::Unknown, |(_, codec)| *codec)),
+    }
+}
+
+/// Read a device's properties as Swift found them, by whatever names they had
+pub fn parse(properties: &Map<String, Value>) -> BluetoothInfo {
+    let mut info = BluetoothInfo::default();
+    for (key, value) in properties {
+        let key = squash(key);
+        if key.contains("rssi") {
+            let rssi = number(value).map(|rssi| rssi as i64).filter(|rssi| *rssi != RSSI_UNAVAILABLE && (-127..=20).contains(rssi));
+            info.rssi = rssi.map(|rssi| rssi as i32).or(info.rssi);
+        } else if key.contains("codec") {
+            info.codec = codec(value).or(info.codec);
+        } else if key.contains("battery") {
+            let slot = if key.contains("left") {
+                &mut info.battery.left
+            } else if key.contains("right") {
+                &mut info.battery.right
+            } else if key.contains("case") {
+                &mut info.battery.case
+            } else {
+                &mut info.battery.percent
+            };
+            *slot = percent(value).or(*slot);
+        }
+    }
+    info
+}
+
+pub(crate) unsafe fn properties_arg(properties_json: *const c_char) -> Result<Map<String, Value>, AudioRemoteError> {
+    serde_json::from_str(str_arg(properties_json, "properties")?).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid Bluetooth properties: {e}")))
+}
+
+/// Make sense of a Bluetooth device's properties. `properties_json`: an object of what Swift read
+/// from IOBluetooth, the IORegistry or system_profiler, by their own names, e.g.
+/// {"BatteryPercentLeft": 80, "Case Battery Level": "45%", "RSSI": -56, "Codec": "AAC"}
+/// Returns: JSON {"codec" ("sbc" | "aac" | "aptX" | "aptXHd" | "aptXAdaptive" | "ldac" | "lc3" |
+/// "msbc" | "cvsd" | "unknown"), "battery": {"percent", "left", "right", "case"} (0–100), "rssi"
+/// (dBm)}, each null if not found (free with rust_string_free), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `properties_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_bluetooth_parse(properties_json: *const c_char) -> *mut c_char {
+    guard("ar_bluetooth_parse", std::ptr::null_mut(), || {
+        let result = properties_arg(properties_json)
+            .and_then(|properties| serde_json::to_string(&parse(&properties)).map_err(|e| AudioRemoteError::Other(e.to_string())));
+        string_result(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+    use serde_json::json;
+
+    fn parsed(properties: Value) -> BluetoothInfo {
+        parse(properties.as_object().unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        // IORegistry
+        let info = parsed(json!({"BatteryPercentLeft": 80, "BatteryPercentRight": 75, "BatteryPercentCase": 40, "RSSI": -56, "Codec": 2}));
+        assert_eq!(info.battery, Battery { percent: None, left: Some(80), right: Some(75), case: Some(40) });
+        assert_eq!((info.codec, info.rssi, info.battery_percent()), (Some(Codec::Aac), Some(-56), Some(75)));
+
+        // system_profiler
+        let info = parsed(json!({"Left Battery Level": "80%", "device_batteryLevelCase": "45 %", "Codec": "A2DP: aptX HD", "RSSI": "-61 dBm"}));
+        assert_eq!((info.battery.left, info.battery.case, info.battery_percent()), (Some(80), Some(45), Some(80)));
+        assert_eq!((info.codec, info.rssi), (Some(Codec::AptXHd), Some(-61)));
+
+        let info = parsed(json!({"BatteryPercentSingle": 100, "Codec": "mSBC", "RSSI": 127}));
+        assert_eq!((info.battery_percent(), info.codec, info.rssi), (Some(100), Some(Codec::Msbc), None));
+    }
+
+    #[test]
+    fn test_unreadable_values_are_left_out() {
+        let info = parsed(json!({"Battery Level": "Charging", "BatteryPercentLeft": 140, "Codec": "Opus", "RSSI": "weak", "Vendor ID": "0x004C"}));
+        assert_eq!(info, BluetoothInfo { codec: Some(Codec::Unknown), ..BluetoothInfo::default() });
+        assert_eq!(parsed(json!({"Codec": "", "Battery": null})), BluetoothInfo::default());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let json = take_c_string(unsafe { ar_bluetooth_parse(c"{\"Battery Level\": \"62%\", \"Codec\": \"aptX\"}".as_ptr()) }).unwrap();
+        assert_eq!(json, r#"{"codec":"aptX","battery":{"percent":62,"left":null,"right":null,"case":null},"rssi":null}"#);
+        assert!(unsafe { ar_bluetooth_parse(c"[]".as_ptr()) }.is_null());
+    }
+}
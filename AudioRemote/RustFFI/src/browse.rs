@@ -0,0 +1,412 @@
+//! Finding other Macs running Audio Remote: browses for `_audioremote._tcp`
+//! over multicast DNS, resolves each instance to a port and addresses, and
+//! reports peers to Swift as they appear, change and disappear.
+//!
+//! The cache follows RFC 6762: records live for their TTL, goodbyes (TTL 0)
+//! expire a record after one second, and queries repeat at doubling intervals
+//! with the answers already known so responders stay quiet. A link-local IPv6
+//! address is only usable on the interface it was heard on, so it's reported
+//! with that interface's zone ("fe80::1%en0").
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{c_char, c_void, CString};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::dns::{self, Message, Question, RData, Record};
+use crate::error::string_result;
+use crate::interfaces::{self, is_link_local_v6};
+use crate::mdns::{self, Sockets, SERVICE_TYPE};
+use crate::runtime::{self, SendPtr};
+use crate::{guard, record, AudioRemoteError};
+
+const FIRST_QUERY_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_QUERY_INTERVAL: Duration = Duration::from_secs(60);
+/// A goodbye keeps the record for this long, in case it was sent by mistake (§10.1)
+const GOODBYE_GRACE: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerEvent {
+    Added = 0,
+    Updated = 1,
+    Removed = 2,
+}
+
+/// A resolved Mac, as Swift sees it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Peer {
+    /// The instance name, e.g. "Studio Mac"
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    /// Usable addresses, link-local IPv6 with its zone, IPv4 first
+    pub addresses: Vec<String>,
+    pub version: Option<String>,
+    pub capabilities: Vec<String>,
+    pub txt: BTreeMap<String, String>,
+    /// This Mac's own advertisement
+    pub is_self: bool,
+}
+
+/// An address together with the interface it's reachable on (0 unless link-local)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct ScopedAddress {
+    address: IpAddr,
+    scope: u32,
+}
+
+#[derive(Debug, Default)]
+struct Instance {
+    /// As first heard; the cache is keyed by the lowercased name
+    name: String,
+    ptr_expires: Option<Instant>,
+    srv: Option<(String, u16, Instant)>,
+    txt: Option<(BTreeMap<String, String>, Instant)>,
+    reported: Option<Peer>,
+}
+
+/// What the browser knows, independent of sockets so it can be driven in tests
+#[derive(Debug, Default)]
+pub(crate) struct Cache {
+    instances: BTreeMap<String, Instance>,
+    hosts: BTreeMap<String, BTreeMap<ScopedAddress, Instant>>,
+}
+
+fn expiry(now: Instant, ttl: u32) -> Instant {
+    match ttl {
+        0 => now + GOODBYE_GRACE,
+        ttl => now + Duration::from_secs(u64::from(ttl)),
+    }
+}
+
+/// "Studio Mac._audioremote._tcp.local" -> "Studio Mac"
+fn instance_label(name: &str) -> Option<&str> {
+    let name = name.trim_end_matches('.');
+    let dot = name.len().checked_sub(SERVICE_TYPE.len() + 1)?;
+    let (label, service) = (name.get(..dot)?, name.get(dot + 1..)?);
+    (!label.is_empty() && name.as_bytes()[dot] == b'.' && dns::same_name(service, SERVICE_TYPE)).then_some(label)
+}
+
+impl Cache {
+    fn instance(&mut self, name: &str) -> &mut Instance {
+        let name = name.trim_end_matches('.');
+        self.instances.entry(name.to_ascii_lowercase()).or_insert_with(|| Instance { name: name.to_owned(), ..Instance::default() })
+    }
+
+    /// Take in a packet received from `from`
+    pub(crate) fn receive(&mut self, message: &Message, from: SocketAddr, now: Instant) {
+        if !message.is_response() {
+            return;
+        }
+        // Cache-flush records replace what we had for their name and type (§10.2)
+        let mut flushed: BTreeSet<(String, bool)> = BTreeSet::new();
+        for record in message.records() {
+            match &record.data {
+                RData::Ptr(target) if dns::same_name(&record.name, SERVICE_TYPE) && instance_label(target).is_some() => {
+                    self.instance(target).ptr_expires = Some(expiry(now, record.ttl));
+                }
+                RData::Srv { port, target, .. } if instance_label(&record.name).is_some() => {
+                    let instance = self.instance(&record.name);
+                    instance.srv = Some((target.to_ascii_lowercase(), *port, expiry(now, record.ttl)));
+                }
+                RData::Txt(entries) if instance_label(&record.name).is_some() => {
+                    let instance = self.instance(&record.name);
+                    instance.txt = Some((dns::parse_txt(entries), expiry(now, record.ttl)));
+                }
+                RData::A(_) | RData::Aaaa(_) => self.address(record, from, now, &mut flushed),
+                _ => {}
+            }
+        }
+    }
+
+    fn address(&mut self, record: &Record, from: SocketAddr, now: Instant, flushed: &mut BTreeSet<(String, bool)>) {
+        let address = match record.data {
+            RData::A(address) => IpAddr::V4(address),
+            RData::Aaaa(address) => IpAddr::V6(address),
+            _ => return,
+        };
+        let scope = match (address, from) {
+            (IpAddr::V6(v6), SocketAddr::V6(from)) if is_link_local_v6(&v6) => from.scope_id(),
+            // Heard over IPv4: which interface isn't known, leave it to the system
+            _ => 0,
+        };
+        let host = record.name.to_ascii_lowercase();
+        let addresses = self.hosts.entry(host.clone()).or_default();
+        if record.cache_flush && flushed.insert((host, address.is_ipv6())) {
+            for (known, expires) in addresses.iter_mut() {
+                if known.address.is_ipv6() == address.is_ipv6() {
+                    *expires = (*expires).min(now + GOODBYE_GRACE);
+                }
+            }
+        }
+        addresses.insert(ScopedAddress { address, scope }, expiry(now, record.ttl));
+    }
+
+    /// Forget expired records and report what changed since the last call
+    pub(crate) fn changes(&mut self, now: Instant, own_name: Option<&str>) -> Vec<(PeerEvent, Peer)> {
+        for addresses in self.hosts.values_mut() {
+            addresses.retain(|_, expires| *expires > now);
+        }
+        self.hosts.retain(|_, addresses| !addresses.is_empty());
+        let mut changes = Vec::new();
+        let hosts = &self.hosts;
+        self.instances.retain(|_, instance| {
+            if instance.srv.as_ref().is_some_and(|(_, _, expires)| *expires <= now) {
+                instance.srv = None;
+            }
+            if instance.txt.as_ref().is_some_and(|(_, expires)| *expires <= now) {
+                instance.txt = None;
+            }
+            let alive = instance.ptr_expires.is_some_and(|expires| expires > now);
+            let peer = if alive { resolve(instance, hosts, own_name) } else { None };
+            match (instance.reported.take(), &peer) {
+                (None, Some(peer)) => changes.push((PeerEvent::Added, peer.clone())),
+                (Some(reported), Some(peer)) if reported != *peer => changes.push((PeerEvent::Updated, peer.clone())),
+                (Some(reported), None) => changes.push((PeerEvent::Removed, reported)),
+                _ => {}
+            }
+            instance.reported = peer;
+            alive
+        });
+        changes
+    }
+
+    pub(crate) fn peers(&self) -> Vec<Peer> {
+        self.instances.values().filter_map(|instance| instance.reported.clone()).collect()
+    }
+
+    /// The next query: browse for the service, listing answers we know that are still
+    /// fresh (§7.1), and ask for whatever resolving is still missing
+    pub(crate) fn query(&self, now: Instant) -> Message {
+        let mut questions = vec![Question { name: SERVICE_TYPE.into(), qtype: dns::TYPE_PTR, unicast: false }];
+        let mut answers = Vec::new();
+        for instance in self.instances.values() {
+            let name = &instance.name;
+            if let Some(expires) = instance.ptr_expires {
+                let remaining = expires.saturating_duration_since(now).as_secs() as u32;
+                if remaining > 0 {
+                    answers.push(Record { name: SERVICE_TYPE.into(), cache_flush: false, ttl: remaining, data: RData::Ptr(name.clone()) });
+                }
+            }
+            match &instance.srv {
+                None => questions.push(Question { name: name.clone(), qtype: dns::TYPE_ANY, unicast: false }),
+                Some((host, _, _)) if !self.hosts.contains_key(host) => {
+                    questions.push(Question { name: host.clone(), qtype: dns::TYPE_ANY, unicast: false });
+                }
+                Some(_) => {}
+            }
+        }
+        Message { questions, answers, ..Message::default() }
+    }
+}
+
+fn resolve(instance: &Instance, hosts: &BTreeMap<String, BTreeMap<ScopedAddress, Instant>>, own_name: Option<&str>) -> Option<Peer> {
+    let (host, port, _) = instance.srv.as_ref()?;
+    let mut addresses: Vec<&ScopedAddress> = hosts.get(host)?.keys().collect();
+    // IPv4 first, then global IPv6, then link-local
+    addresses.sort_by_key(|a| (a.address.is_ipv6(), a.scope != 0, a.address));
+    let addresses: Vec<String> = addresses.into_iter().map(|a| interfaces::format_address(a.address, a.scope)).collect();
+    let txt = instance.txt.as_ref().map(|(txt, _)| txt.clone()).unwrap_or_default();
+    let label = instance_label(&instance.name)?.to_owned();
+    Some(Peer {
+        is_self: own_name.is_some_and(|own| own.eq_ignore_ascii_case(&label)),
+        name: label,
+        host: host.clone(),
+        port: *port,
+        addresses,
+        version: txt.get("version").cloned(),
+        capabilities: txt.get("caps").map(|caps| caps.split(',').filter(|c| !c.is_empty()).map(String::from).collect()).unwrap_or_default(),
+        txt,
+    })
+}
+
+/// (event, peer JSON, ctx); `event` is an ArPeerEvent and the JSON is only valid during the call
+pub type PeerCallback = extern "C" fn(event: i32, peer_json: *const c_char, ctx: *mut c_void);
+
+struct Running {
+    stop: Arc<AtomicBool>,
+    cache: Arc<Mutex<Cache>>,
+    thread: JoinHandle<()>,
+}
+
+static BROWSER: Mutex<Option<Running>> = Mutex::new(None);
+
+fn browse(sockets: Sockets, cache: Arc<Mutex<Cache>>, stop: Arc<AtomicBool>, callback: PeerCallback, ctx: SendPtr) {
+    let mut interval = FIRST_QUERY_INTERVAL;
+    let mut next_query = Instant::now();
+    let mut buffer = vec![0; 9000];
+    while !stop.load(Ordering::Acquire) {
+        let now = Instant::now();
+        if now >= next_query {
+            let query = cache.lock().unwrap_or_else(|e| e.into_inner()).query(now);
+            sockets.multicast(&query);
+            next_query = now + interval;
+            interval = (interval * 2).min(MAX_QUERY_INTERVAL);
+        }
+        if let Some((length, from, _)) = sockets.receive(&mut buffer, POLL_INTERVAL) {
+            if let Ok(message) = dns::decode(&buffer[..length]) {
+                cache.lock().unwrap_or_else(|e| e.into_inner()).receive(&message, from, Instant::now());
+            }
+        }
+        let own_name = mdns::advertised_name();
+        let changes = cache.lock().unwrap_or_else(|e| e.into_inner()).changes(Instant::now(), own_name.as_deref());
+        for (event, peer) in changes {
+            log::debug!("peer {event:?}: {} at {:?}", peer.name, peer.addresses);
+            let json = CString::new(serde_json::to_string(&peer).unwrap_or_default()).unwrap_or_default();
+            runtime::deliver(move || callback(event as i32, json.as_ptr(), ctx.get()));
+        }
+    }
+}
+
+/// Start browsing, replacing a running browser; `callback` gets every change from then on
+pub fn start(callback: PeerCallback, ctx: *mut c_void) -> Result<(), AudioRemoteError> {
+    stop();
+    let sockets = Sockets::open(interfaces::local_addresses()?)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let cache = Arc::new(Mutex::new(Cache::default()));
+    let thread = {
+        let (stop, cache, ctx) = (stop.clone(), cache.clone(), SendPtr(ctx));
+        thread::Builder::new()
+            .name("audioremote-browse".into())
+            .spawn(move || browse(sockets, cache, stop, callback, ctx))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the browser thread: {e}")))?
+    };
+    *BROWSER.lock().unwrap_or_else(|e| e.into_inner()) = Some(Running { stop, cache, thread });
+    Ok(())
+}
+
+pub fn stop() {
+    let Some(running) = BROWSER.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    running.stop.store(true, Ordering::Release);
+    let _ = running.thread.join();
+}
+
+/// The peers currently resolved
+pub fn peers() -> Result<Vec<Peer>, AudioRemoteError> {
+    let browser = BROWSER.lock().unwrap_or_else(|e| e.into_inner());
+    let running = browser.as_ref().ok_or(AudioRemoteError::Refused("not browsing; call ar_browse_start first".into()))?;
+    let cache = running.cache.lock().unwrap_or_else(|e| e.into_inner());
+    Ok(cache.peers())
+}
+
+/// Browse for other Macs advertising _audioremote._tcp. `callback` gets (ArPeerEvent, peer JSON,
+/// ctx) for each change, on the ar_runtime_set_callback_queue queue or the browser's thread.
+/// Replaces a running browser.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `ctx` is passed to `callback` from another thread and must stay valid until ar_browse_stop.
+#[no_mangle]
+pub unsafe extern "C" fn ar_browse_start(callback: Option<PeerCallback>, ctx: *mut c_void) -> i32 {
+    guard("ar_browse_start", -999, || {
+        let result = callback.ok_or(AudioRemoteError::InvalidArgument("callback is null".into())).and_then(|callback| start(callback, ctx));
+        match record(result) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Stop browsing; no callbacks are made after it returns, except ones already queued.
+/// Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_browse_stop() {
+    guard("ar_browse_stop", (), stop)
+}
+
+/// Returns: JSON array of the peers found so far (free with rust_string_free), NULL if not
+/// browsing (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_browse_peers() -> *mut c_char {
+    guard("ar_browse_peers", std::ptr::null_mut(), || {
+        string_result(peers().and_then(|peers| serde_json::to_string(&peers).map_err(|e| AudioRemoteError::Other(e.to_string()))))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV6};
+
+    fn record(name: &str, ttl: u32, data: RData) -> Record {
+        Record { name: name.into(), cache_flush: !matches!(data, RData::Ptr(_)), ttl, data }
+    }
+
+    fn response(records: Vec<Record>) -> Message {
+        Message { flags: dns::FLAG_RESPONSE, answers: records, ..Message::default() }
+    }
+
+    const INSTANCE: &str = "Kitchen Mac._audioremote._tcp.local";
+
+    #[test]
+    fn test_resolve_update_and_remove() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        let from_v6 = SocketAddr::V6(SocketAddrV6::new("fe80::2".parse().unwrap(), 5353, 0, 4));
+        cache.receive(&response(vec![record(SERVICE_TYPE, 4500, RData::Ptr(INSTANCE.into()))]), from_v6, now);
+        // Not resolved yet: nothing to report, and the next query asks for the rest
+        assert!(cache.changes(now, None).is_empty());
+        assert!(cache.query(now).questions.iter().any(|q| dns::same_name(&q.name, INSTANCE)));
+
+        cache.receive(
+            &response(vec![
+                record(INSTANCE, 120, RData::Srv { priority: 0, weight: 0, port: 8765, target: "kitchen-audioremote.local".into() }),
+                record(INSTANCE, 4500, RData::Txt(vec![b"version=2.6.0".to_vec(), b"caps=volume,events".to_vec()])),
+                record("kitchen-audioremote.local", 120, RData::Aaaa("fe80::2".parse().unwrap())),
+                record("kitchen-audioremote.local", 120, RData::A(Ipv4Addr::new(192, 168, 1, 30))),
+            ]),
+            from_v6,
+            now,
+        );
+        let changes = cache.changes(now, Some("kitchen mac"));
+        assert_eq!(changes.len(), 1);
+        let (event, peer) = &changes[0];
+        assert_eq!(*event, PeerEvent::Added);
+        assert_eq!(peer.name, "Kitchen Mac");
+        assert_eq!(peer.port, 8765);
+        assert_eq!(peer.addresses[0], "192.168.1.30");
+        assert!(peer.addresses[1].starts_with("fe80::2%"), "{:?}", peer.addresses);
+        assert_eq!(peer.capabilities, vec!["volume", "events"]);
+        assert!(peer.is_self);
+        // Known answers are listed so the responder doesn't repeat itself
+        assert_eq!(cache.query(now).answers.len(), 1);
+
+        let from_v4 = SocketAddr::from((Ipv4Addr::new(192, 168, 1, 30), 5353));
+        cache.receive(&response(vec![record(INSTANCE, 4500, RData::Txt(vec![b"version=2.7.0".to_vec()]))]), from_v4, now);
+        let changes = cache.changes(now, None);
+        assert_eq!(changes[0].0, PeerEvent::Updated);
+        assert_eq!(changes[0].1.version.as_deref(), Some("2.7.0"));
+
+        // A goodbye removes the peer after the grace period
+        cache.receive(&response(vec![record(SERVICE_TYPE, 0, RData::Ptr(INSTANCE.into()))]), from_v4, now);
+        assert!(cache.changes(now, None).is_empty());
+        let later = now + Duration::from_secs(2);
+        let changes = cache.changes(later, None);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].0, PeerEvent::Removed);
+        assert!(cache.peers().is_empty());
+    }
+
+    #[test]
+    fn test_queries_and_foreign_records_are_ignored() {
+        let mut cache = Cache::default();
+        let now = Instant::now();
+        let from = SocketAddr::from((Ipv4Addr::new(192, 168, 1, 40), 5353));
+        let query = Message { answers: vec![record(SERVICE_TYPE, 4500, RData::Ptr(INSTANCE.into()))], ..Message::default() };
+        cache.receive(&query, from, now);
+        cache.receive(&response(vec![record("_airplay._tcp.local", 4500, RData::Ptr("TV._airplay._tcp.local".into()))]), from, now);
+        assert!(cache.instances.is_empty());
+        assert_eq!(instance_label(INSTANCE), Some("Kitchen Mac"));
+        assert_eq!(instance_label("_audioremote._tcp.local"), None);
+    }
+}
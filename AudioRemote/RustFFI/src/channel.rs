@@ -0,0 +1,104 @@
+//! The update channel the user picked, persisted so every release selector
+//! (appcast, GitHub, version helpers) filters the same way
+
+use crate::store::{self, Store};
+use crate::{guard, policy, record, AudioRemoteError, UpdateChannel};
+
+const CHANNEL_KEY: &str = "update.channel";
+
+/// Pass as `channel` to any FFI call to use the saved channel
+pub const SAVED_CHANNEL: i32 = -1;
+
+/// The saved channel; stable until the user opts into something else
+pub fn saved(store: &Store) -> UpdateChannel {
+    store.get(CHANNEL_KEY).and_then(UpdateChannel::from_raw).unwrap_or(UpdateChannel::Stable)
+}
+
+/// Save `channel`. Moving to a more conservative channel forgets a skipped
+/// prerelease, which isn't offered there and would otherwise hide the stable
+/// release of the same version.
+pub fn set(store: &Store, channel: UpdateChannel) -> Result<(), AudioRemoteError> {
+    if channel < saved(store) && policy::skipped_version(store).is_some_and(|v| UpdateChannel::of(&v) > channel) {
+        policy::clear_skipped(store)?;
+    }
+    store.set(CHANNEL_KEY, &(channel as i32))
+}
+
+fn unknown_channel(raw: i32) -> AudioRemoteError {
+    AudioRemoteError::InvalidArgument(format!("unknown update channel {raw}"))
+}
+
+/// Resolve a `channel` argument, where SAVED_CHANNEL means the persisted choice
+pub(crate) fn channel_arg(raw: i32) -> Result<UpdateChannel, AudioRemoteError> {
+    match raw {
+        SAVED_CHANNEL => Ok(saved(&store::global())),
+        _ => UpdateChannel::from_raw(raw).ok_or(unknown_channel(raw)),
+    }
+}
+
+/// Persist the update channel (0 = stable, 1 = beta, 2 = nightly)
+/// Users switching from beta back to stable stay on their build until a stable
+/// release at least as new comes out; nothing is downgraded.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn channel_set(channel: i32) -> i32 {
+    guard("channel_set", -999, || {
+        let result = UpdateChannel::from_raw(channel)
+            .ok_or(unknown_channel(channel))
+            .and_then(|channel| set(&store::global(), channel));
+        match record(result) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Returns: the saved update channel (0 = stable, 1 = beta, 2 = nightly)
+#[no_mangle]
+pub extern "C" fn channel_get() -> i32 {
+    guard("channel_get", -999, || {
+        saved(&store::global()) as i32
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::appcast::{self, UpdateDecision};
+    use crate::store::tests::with_temp_store;
+    use crate::version::{self, ParseMode};
+
+    #[test]
+    fn test_channel_persists_and_resolves() {
+        let (_guard, _dir) = with_temp_store("channel");
+        assert_eq!(channel_get(), UpdateChannel::Stable as i32);
+        assert_eq!(channel_set(UpdateChannel::Beta as i32), 1);
+        assert_eq!(channel_arg(SAVED_CHANNEL), Ok(UpdateChannel::Beta));
+        assert_eq!(channel_arg(2), Ok(UpdateChannel::Nightly));
+        assert!(channel_arg(5).is_err());
+        assert_eq!(channel_set(9), -999);
+        assert_eq!(channel_get(), UpdateChannel::Beta as i32);
+    }
+
+    #[test]
+    fn test_switching_back_to_stable() {
+        let (_guard, dir) = with_temp_store("channel-switch");
+        let store = Store::open(&dir);
+        let v = |s: &str| version::parse(s, ParseMode::Lenient).unwrap();
+        let xml = r#"<rss xmlns:sparkle="http://www.andymatuschak.org/xml-namespaces/sparkle"><channel>
+            <item><enclosure url="https://example.com/2.6.5.zip" sparkle:shortVersionString="2.6.5"/></item>
+            <item><enclosure url="https://example.com/2.7.0-beta.3.zip" sparkle:shortVersionString="2.7.0-beta.3"/></item>
+        </channel></rss>"#;
+        let items = appcast::parse(xml).unwrap();
+
+        set(&store, UpdateChannel::Beta).unwrap();
+        policy::skip_version(&store, &v("2.7.0-beta.3")).unwrap();
+        set(&store, UpdateChannel::Stable).unwrap();
+        assert_eq!(policy::skipped_version(&store), None);
+
+        // On 2.7.0-beta.2 with stable at 2.6.5 there's nothing to offer until 2.7.0 ships
+        let current = v("2.7.0-beta.2");
+        let (decision, _) = appcast::decide(&items, &current, &v("14.0"), saved(&store), None);
+        assert_eq!(decision, UpdateDecision::UpToDate);
+    }
+}
@@ -0,0 +1,148 @@
+use std::ffi::{c_char, c_void};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::string_result;
+use crate::{guard, record, str_arg, AudioRemoteError, ProgressCallback};
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Lowercase hex encoding
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Hash a file in fixed-size chunks, reporting progress after each chunk.
+/// `progress` returns false to cancel.
+pub fn sha256_file(path: &Path, mut progress: impl FnMut(u64, u64) -> bool) -> Result<String, AudioRemoteError> {
+    let mut file = File::open(path).map_err(|e| AudioRemoteError::Io(format!("can't open {}: {e}", path.display())))?;
+    let total = file.metadata().map(|m| m.len()).unwrap_or(0);
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; CHUNK_SIZE];
+    let mut done = 0u64;
+
+    loop {
+        let n = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(AudioRemoteError::Io(format!("can't read {}: {e}", path.display()))),
+        };
+        hasher.update(&buffer[..n]);
+        done += n as u64;
+        if !progress(done, total) {
+            return Err(AudioRemoteError::Cancelled);
+        }
+    }
+
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Wrap an optional C progress callback as a closure
+pub(crate) fn progress_fn(callback: Option<ProgressCallback>, ctx: *mut c_void) -> impl FnMut(u64, u64) -> bool {
+    move |done, total| callback.is_none_or(|cb| cb(done, total, ctx))
+}
+
+/// Compute the SHA-256 of a file without loading it into memory
+/// `progress` (nullable) is called after every 1 MB chunk; return false from it to cancel
+/// Returns: lowercase hex digest (free with rust_string_free), or NULL on error/cancel (see last_error_message)
+///
+/// # Safety
+/// `path_ptr` must point to a valid NUL-terminated string; `ctx` is passed
+/// through to `progress` untouched.
+#[no_mangle]
+pub unsafe extern "C" fn checksum_file_sha256(
+    path_ptr: *const c_char,
+    progress: Option<ProgressCallback>,
+    ctx: *mut c_void,
+) -> *mut c_char {
+    guard("checksum_file_sha256", std::ptr::null_mut(), || {
+        let result = str_arg(path_ptr, "path").and_then(|path| sha256_file(Path::new(path), progress_fn(progress, ctx)));
+        string_result(result)
+    })
+}
+
+/// Check a file against an expected SHA-256 hex digest (case-insensitive)
+/// Returns: 1 if it matches, 0 if not, -999 on error/cancel (see last_error_message)
+///
+/// # Safety
+/// Both string pointers must point to valid NUL-terminated strings; `ctx` is
+/// passed through to `progress` untouched.
+#[no_mangle]
+pub unsafe extern "C" fn checksum_verify_sha256(
+    path_ptr: *const c_char,
+    expected_ptr: *const c_char,
+    progress: Option<ProgressCallback>,
+    ctx: *mut c_void,
+) -> i32 {
+    guard("checksum_verify_sha256", -999, || {
+        let result = str_arg(path_ptr, "path").and_then(|path| {
+            let expected = str_arg(expected_ptr, "expected")?.trim().to_ascii_lowercase();
+            Ok(sha256_file(Path::new(path), progress_fn(progress, ctx))? == expected)
+        });
+        match record(result) {
+            Some(matches) => matches as i32,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+    use std::ffi::CString;
+
+    fn temp_file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("audioremote-{name}-{}", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_sha256_file_streams_chunks() {
+        let data = vec![0xabu8; CHUNK_SIZE * 2 + 10];
+        let path = temp_file("sha-chunks", &data);
+        let mut calls = Vec::new();
+        let digest = sha256_file(&path, |done, total| {
+            calls.push((done, total));
+            true
+        })
+        .unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(digest, to_hex(&Sha256::digest(&data)));
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls.last(), Some(&(data.len() as u64, data.len() as u64)));
+    }
+
+    extern "C" fn cancel_immediately(_done: u64, _total: u64, ctx: *mut c_void) -> bool {
+        unsafe { *(ctx as *mut u32) += 1 };
+        false
+    }
+
+    #[test]
+    fn test_checksum_ffi() {
+        let path = temp_file("sha-ffi", b"abc");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        let ptr = unsafe { checksum_file_sha256(c_path.as_ptr(), None, std::ptr::null_mut()) };
+        let digest = take_c_string(ptr).unwrap();
+        assert_eq!(digest, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+
+        let expected = CString::new(digest.to_uppercase()).unwrap();
+        assert_eq!(unsafe { checksum_verify_sha256(c_path.as_ptr(), expected.as_ptr(), None, std::ptr::null_mut()) }, 1);
+        let wrong = CString::new("00").unwrap();
+        assert_eq!(unsafe { checksum_verify_sha256(c_path.as_ptr(), wrong.as_ptr(), None, std::ptr::null_mut()) }, 0);
+
+        let mut calls = 0u32;
+        let ctx = &mut calls as *mut u32 as *mut c_void;
+        assert!(unsafe { checksum_file_sha256(c_path.as_ptr(), Some(cancel_immediately), ctx) }.is_null());
+        assert_eq!(calls, 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,627 @@
+//! Clock synchronization for multi-room playback: the Mac answers timing
+//! requests on UDP, and each endpoint playing its stream (a phone, another
+//! Mac) polls it to learn how the Mac's clock maps onto its own, the way NTP
+//! does. With that, the Mac can stamp audio "play at Mac time T" and every
+//! endpoint starts it at the same moment, within a few milliseconds.
+//!
+//! Both sides count nanoseconds on a monotonic clock that starts with the
+//! process (`now_ns`), so the offset is large but steady. Each exchange gives
+//! four timestamps and from them an offset and a round-trip delay; Wi-Fi
+//! queues make most round trips slow and lopsided, so only the fastest of the
+//! last few exchanges is believed (NTP's clock filter). Two crystals drift
+//! apart by tens of parts per million, which is a millisecond every minute at
+//! worst, so the drift is fitted over the last minutes of filtered offsets and
+//! used between polls.
+//!
+//! `playout_delay` picks the common delay: the slowest endpoint's latency
+//! (network, buffering and output) plus a margin, with each endpoint told how
+//! much to hold back on top of its own latency to land together.
+
+use std::collections::VecDeque;
+use std::ffi::c_char;
+use std::net::{Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::error::string_result;
+use crate::handle::{Handle, Registry};
+use crate::interfaces;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAGIC: &[u8; 4] = b"arc1";
+const REQUEST: u8 = 1;
+const REPLY: u8 = 2;
+const REQUEST_LEN: usize = 16;
+const REPLY_LEN: usize = 32;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Exchanges the clock filter picks the fastest of
+const FILTER_SAMPLES: usize = 8;
+/// Filtered offsets the drift is fitted over
+const DRIFT_POINTS: usize = 64;
+/// The drift isn't trusted over a shorter span than this
+const MIN_DRIFT_SPAN_NS: i64 = 10_000_000_000;
+/// Real crystals are within this; more means a bad fit
+const MAX_DRIFT_PPM: f64 = 500.0;
+
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Nanoseconds on this process's monotonic clock: the time base of every timestamp here
+pub fn now_ns() -> i64 {
+    EPOCH.get_or_init(Instant::now).elapsed().as_nanos() as i64
+}
+
+fn request(sent: i64) -> [u8; REQUEST_LEN] {
+    let mut packet = [0; REQUEST_LEN];
+    packet[..4].copy_from_slice(MAGIC);
+    packet[4] = REQUEST;
+    packet[8..16].copy_from_slice(&sent.to_be_bytes());
+    packet
+}
+
+/// The answer to a request received at `received`, None if `packet` isn't one
+fn reply(packet: &[u8], received: i64, sent: i64) -> Option<[u8; REPLY_LEN]> {
+    if packet.len() < REQUEST_LEN || &packet[..4] != MAGIC || packet[4] != REQUEST {
+        return None;
+    }
+    let mut answer = [0; REPLY_LEN];
+    answer[..4].copy_from_slice(MAGIC);
+    answer[4] = REPLY;
+    answer[8..16].copy_from_slice(&packet[8..16]);
+    answer[16..24].copy_from_slice(&received.to_be_bytes());
+    answer[24..32].copy_from_slice(&sent.to_be_bytes());
+    Some(answer)
+}
+
+/// (origin, server received, server sent) from a reply
+fn parse_reply(packet: &[u8]) -> Option<(i64, i64, i64)> {
+    if packet.len() < REPLY_LEN || &packet[..4] != MAGIC || packet[4] != REPLY {
+        return None;
+    }
+    let word = |at: usize| i64::from_be_bytes(packet[at..at + 8].try_into().unwrap_or_default());
+    Some((word(8), word(16), word(24)))
+}
+
+/// One exchange: the client sent at `t1` and got the answer at `t4` on its clock; the server
+/// got it at `t2` and answered at `t3` on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exchange {
+    pub t1: i64,
+    pub t2: i64,
+    pub t3: i64,
+    pub t4: i64,
+}
+
+impl Exchange {
+    /// Server time minus client time, if the trip was symmetric
+    pub fn offset(&self) -> i64 {
+        ((self.t2 - self.t1) + (self.t3 - self.t4)) / 2
+    }
+
+    /// The round trip less the server's turnaround
+    pub fn delay(&self) -> i64 {
+        (self.t4 - self.t1) - (self.t3 - self.t2)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClockStatus {
+    pub synced: bool,
+    /// Server time minus local time now
+    pub offset_ns: i64,
+    /// The round trip of the exchange the offset came from; the offset is good to half this
+    pub delay_ns: i64,
+    pub drift_ppm: f64,
+    pub exchanges: u64,
+}
+
+/// Turns exchanges into a mapping between the server's clock and ours
+#[derive(Debug, Default)]
+pub struct ClockSync {
+    recent: VecDeque<Exchange>,
+    /// (local time, offset) of each filtered pick
+    picks: VecDeque<(i64, i64)>,
+    drift: f64,
+    exchanges: u64,
+}
+
+impl ClockSync {
+    pub fn add(&mut self, exchange: Exchange) {
+        if exchange.delay() < 0 {
+            return;
+        }
+        self.exchanges += 1;
+        if self.recent.len() == FILTER_SAMPLES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(exchange);
+        let Some(best) = self.recent.iter().min_by_key(|exchange| exchange.delay()) else {
+            return;
+        };
+        let pick = ((best.t1 + best.t4) / 2, best.offset());
+        // The same fast exchange stays the pick for a while; once is enough for the fit
+        if self.picks.back() != Some(&pick) {
+            if self.picks.len() == DRIFT_POINTS {
+                self.picks.pop_front();
+            }
+            self.picks.push_back(pick);
+            self.drift = fit_drift(&self.picks);
+        }
+    }
+
+    /// Server time minus local time at local time `local`, None before the first exchange
+    pub fn offset_at(&self, local: i64) -> Option<i64> {
+        let &(at, offset) = self.picks.back()?;
+        Some(offset + ((local - at) as f64 * self.drift) as i64)
+    }
+
+    pub fn to_server(&self, local: i64) -> Option<i64> {
+        self.offset_at(local).map(|offset| local + offset)
+    }
+
+    pub fn to_local(&self, server: i64) -> Option<i64> {
+        // The offset barely moves over its own size, so one step back is close enough
+        let guess = server - self.offset_at(server - self.offset_at(server)?)?;
+        self.offset_at(guess).map(|offset| server - offset)
+    }
+
+    pub fn status(&self, now: i64) -> ClockStatus {
+        let delay_ns = self.recent.iter().map(Exchange::delay).min().unwrap_or(0);
+        ClockStatus {
+            synced: !self.picks.is_empty(),
+            offset_ns: self.offset_at(now).unwrap_or(0),
+            delay_ns,
+            drift_ppm: self.drift * 1e6,
+            exchanges: self.exchanges,
+        }
+    }
+}
+
+/// Least-squares slope of offset over local time, 0 until the picks span long enough
+fn fit_drift(picks: &VecDeque<(i64, i64)>) -> f64 {
+    let (Some(&(first, base)), Some(&(last, _))) = (picks.front(), picks.back()) else {
+        return 0.0;
+    };
+    if last - first < MIN_DRIFT_SPAN_NS {
+        return 0.0;
+    }
+    let n = picks.len() as f64;
+    let points = picks.iter().map(|&(at, offset)| ((at - first) as f64, (offset - base) as f64));
+    let (sum_x, sum_y, sum_xx, sum_xy) =
+        points.fold((0.0, 0.0, 0.0, 0.0), |(sx, sy, sxx, sxy), (x, y)| (sx + x, sy + y, sxx + x * x, sxy + x * y));
+    let denominator = n * sum_xx - sum_x * sum_x;
+    if denominator == 0.0 {
+        return 0.0;
+    }
+    let slope = (n * sum_xy - sum_x * sum_y) / denominator;
+    slope.clamp(-MAX_DRIFT_PPM / 1e6, MAX_DRIFT_PPM / 1e6)
+}
+
+/// One endpoint's share of the path from the Mac to a speaker
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Endpoint {
+    pub id: String,
+    /// One way, e.g. half ClockStatus's delayNs
+    #[serde(default)]
+    pub network_ms: f64,
+    /// What its jitter buffer holds back
+    #[serde(default)]
+    pub buffer_ms: f64,
+    /// The audio device's own latency
+    #[serde(default)]
+    pub output_ms: f64,
+}
+
+impl Endpoint {
+    fn latency_ms(&self) -> f64 {
+        self.network_ms + self.buffer_ms + self.output_ms
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PlayoutRequest {
+    pub endpoints: Vec<Endpoint>,
+    /// Headroom over the slowest endpoint, for its latency creeping up
+    #[serde(default = "default_margin_ms")]
+    pub margin_ms: f64,
+}
+
+fn default_margin_ms() -> f64 {
+    10.0
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointDelay {
+    pub id: String,
+    /// How much longer than its own latency it waits
+    pub extra_delay_ms: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Playout {
+    /// From the Mac stamping audio to every endpoint playing it
+    pub delay_ms: f64,
+    pub endpoints: Vec<EndpointDelay>,
+}
+
+/// The delay that lets every endpoint play together
+pub fn playout_delay(request: &PlayoutRequest) -> Result<Playout, AudioRemoteError> {
+    let valid = |value: f64| value.is_finite() && value >= 0.0;
+    if let Some(endpoint) = request.endpoints.iter().find(|e| !(valid(e.network_ms) && valid(e.buffer_ms) && valid(e.output_ms))) {
+        return Err(AudioRemoteError::InvalidArgument(format!("endpoint {} has a negative or invalid latency", endpoint.id)));
+    }
+    if !valid(request.margin_ms) {
+        return Err(AudioRemoteError::InvalidArgument(format!("marginMs {} is negative or invalid", request.margin_ms)));
+    }
+    let slowest = request.endpoints.iter().map(Endpoint::latency_ms).fold(0.0, f64::max);
+    let delay_ms = slowest + request.margin_ms;
+    let endpoints =
+        request.endpoints.iter().map(|endpoint| EndpointDelay { id: endpoint.id.clone(), extra_delay_ms: delay_ms - endpoint.latency_ms() }).collect();
+    Ok(Playout { delay_ms, endpoints })
+}
+
+struct Running {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+    port: u16,
+}
+
+static SERVER: Mutex<Option<Running>> = Mutex::new(None);
+
+fn network(e: std::io::Error) -> AudioRemoteError {
+    AudioRemoteError::Network(format!("clock sync failed: {e}"))
+}
+
+/// IPv4 and IPv6, so remotes on link-local addresses are answered too
+fn server_socket(port: u16) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_only_v6(false)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    Ok(UdpSocket::from(socket))
+}
+
+fn serve(socket: UdpSocket, stop: Arc<AtomicBool>) {
+    let mut buffer = [0; 64];
+    while !stop.load(Ordering::Acquire) {
+        let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        let received = now_ns();
+        if let Some(answer) = reply(&buffer[..length], received, now_ns()) {
+            let _ = socket.send_to(&answer, from);
+        }
+    }
+}
+
+/// Answer timing requests on `port` (0 picks one); replaces a running server
+pub fn start(port: u16) -> Result<u16, AudioRemoteError> {
+    stop();
+    let socket = server_socket(port).map_err(|e| AudioRemoteError::Network(format!("can't listen for clock sync on port {port}: {e}")))?;
+    let port = socket.local_addr().map_err(network)?.port();
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = stop.clone();
+        thread::Builder::new()
+            .name("audioremote-clock".into())
+            .spawn(move || serve(socket, stop))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the clock thread: {e}")))?
+    };
+    *SERVER.lock().unwrap_or_else(|e| e.into_inner()) = Some(Running { stop, thread, port });
+    log::info!("answering clock sync on port {port}");
+    Ok(port)
+}
+
+pub fn stop() {
+    let Some(running) = SERVER.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    running.stop.store(true, Ordering::Release);
+    let _ = running.thread.join();
+}
+
+/// The port the server answers on, None if it isn't running
+pub fn port() -> Option<u16> {
+    SERVER.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|running| running.port)
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ClientConfig {
+    /// The Mac: a name or an IP, link-local IPv6 with its zone
+    pub host: String,
+    pub port: u16,
+    /// Between exchanges; the first few go out faster to sync quickly
+    #[serde(default = "default_interval_ms")]
+    pub interval_ms: u64,
+}
+
+fn default_interval_ms() -> u64 {
+    1000
+}
+
+pub struct Client {
+    sync: Mutex<ClockSync>,
+    stop: AtomicBool,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+static CLIENTS: Registry<Client> = Registry::new("clock sync client");
+
+fn poll(client: Arc<Client>, socket: UdpSocket, interval: Duration) {
+    let mut buffer = [0; 64];
+    let mut next = Instant::now();
+    while !client.stop.load(Ordering::Acquire) {
+        if Instant::now() >= next {
+            let _ = socket.send(&request(now_ns()));
+            // A burst to start with fills the filter
+            let exchanges = client.sync.lock().unwrap_or_else(|e| e.into_inner()).exchanges;
+            next = Instant::now() + if exchanges < FILTER_SAMPLES as u64 { interval / 8 } else { interval };
+        }
+        let Ok(length) = socket.recv(&mut buffer) else {
+            continue;
+        };
+        let t4 = now_ns();
+        if let Some((t1, t2, t3)) = parse_reply(&buffer[..length]) {
+            client.sync.lock().unwrap_or_else(|e| e.into_inner()).add(Exchange { t1, t2, t3, t4 });
+        }
+    }
+}
+
+/// Start syncing with a Mac's clock server
+pub fn open(config: &ClientConfig) -> Result<Handle, AudioRemoteError> {
+    if config.interval_ms == 0 {
+        return Err(AudioRemoteError::InvalidArgument("intervalMs must be over 0".into()));
+    }
+    let host = &config.host;
+    let address = interfaces::resolve(host, config.port)
+        .map_err(|e| AudioRemoteError::Network(format!("can't resolve {host}: {e}")))?
+        .into_iter()
+        .next()
+        .ok_or(AudioRemoteError::Network(format!("{host} has no addresses")))?;
+    let socket = UdpSocket::bind(if address.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" }).map_err(network)?;
+    socket.connect(address).map_err(network)?;
+    socket.set_read_timeout(Some(POLL_INTERVAL)).map_err(network)?;
+    let client = Arc::new(Client { sync: Mutex::new(ClockSync::default()), stop: AtomicBool::new(false), thread: Mutex::new(None) });
+    let handle = CLIENTS.insert_shared(client.clone());
+    let interval = Duration::from_millis(config.interval_ms);
+    let spawned = {
+        let client = client.clone();
+        thread::Builder::new().name("audioremote-clock-client".into()).spawn(move || poll(client, socket, interval))
+    };
+    match spawned {
+        Ok(thread) => {
+            *client.thread.lock().unwrap_or_else(|e| e.into_inner()) = Some(thread);
+            Ok(handle)
+        }
+        Err(e) => {
+            let _ = CLIENTS.remove(handle);
+            Err(AudioRemoteError::Other(format!("can't start the clock client thread: {e}")))
+        }
+    }
+}
+
+fn with_sync<T>(handle: Handle, body: impl FnOnce(&ClockSync) -> T) -> Result<T, AudioRemoteError> {
+    let client = CLIENTS.get(handle)?;
+    let sync = client.sync.lock().unwrap_or_else(|e| e.into_inner());
+    Ok(body(&sync))
+}
+
+fn shut(client: &Client) {
+    client.stop.store(true, Ordering::Release);
+    if let Some(thread) = client.thread.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        let _ = thread.join();
+    }
+}
+
+pub fn close(handle: Handle) -> Result<(), AudioRemoteError> {
+    let client = CLIENTS.remove(handle)?;
+    shut(&client);
+    Ok(())
+}
+
+/// Stop every client, at shutdown
+pub fn close_all() {
+    for client in CLIENTS.all() {
+        shut(&client);
+    }
+}
+
+fn not_synced() -> AudioRemoteError {
+    AudioRemoteError::Refused("the clock isn't synced yet".into())
+}
+
+/// Now on this process's clock in nanoseconds, the time base of the other ar_clock_ functions
+#[no_mangle]
+pub extern "C" fn ar_clock_now_ns() -> i64 {
+    guard("ar_clock_now_ns", 0, now_ns)
+}
+
+/// Answer clock sync requests from playback endpoints on UDP `port` (0 picks a free one; put it
+/// in the stream's setup so endpoints know where to ask). Replaces a running server.
+/// Returns: the port, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_clock_server_start(port: u16) -> i32 {
+    guard("ar_clock_server_start", -999, || record(start(port)).map_or(-999, i32::from))
+}
+
+/// Stop answering; a no-op if not running. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_clock_server_stop() {
+    guard("ar_clock_server_stop", (), stop)
+}
+
+/// Start syncing to a Mac's clock from a playback endpoint. `config_json`: {"host" (a name or an
+/// IP, link-local IPv6 with its zone), "port", "intervalMs" (1000)}
+/// Returns: a handle (free with ar_clock_client_close), 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_clock_client_open(config_json: *const c_char) -> Handle {
+    guard("ar_clock_client_open", 0, || {
+        let result = str_arg(config_json, "config").and_then(|json| {
+            let config: ClientConfig =
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid clock sync config: {e}")))?;
+            open(&config)
+        });
+        record(result).unwrap_or(0)
+    })
+}
+
+/// Returns: JSON {"synced", "offsetNs" (Mac time minus ours), "delayNs" (the round trip the offset
+/// is from; it's good to half this), "driftPpm", "exchanges"} (free with rust_string_free), NULL on
+/// error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_clock_client_status(client: Handle) -> *mut c_char {
+    guard("ar_clock_client_status", std::ptr::null_mut(), || {
+        string_result(with_sync(client, |sync| sync.status(now_ns())).and_then(|status| {
+            serde_json::to_string(&status).map_err(|e| AudioRemoteError::Other(e.to_string()))
+        }))
+    })
+}
+
+/// Convert a time on the Mac's clock, e.g. when a packet is due to play, to ours (ar_clock_now_ns)
+/// Returns: 1 with the time in `out`, -999 before the first exchange or on error (see last_error_message)
+///
+/// # Safety
+/// `out` must be valid for writing.
+#[no_mangle]
+pub unsafe extern "C" fn ar_clock_client_to_local(client: Handle, server_ns: i64, out: *mut i64) -> i32 {
+    guard("ar_clock_client_to_local", -999, || {
+        if out.is_null() {
+            record::<()>(Err(AudioRemoteError::InvalidArgument("out is null".into())));
+            return -999;
+        }
+        let local = with_sync(client, |sync| sync.to_local(server_ns)).and_then(|local| local.ok_or_else(not_synced));
+        record(local).map_or(-999, |local| {
+            *out = local;
+            1
+        })
+    })
+}
+
+/// Convert one of our times to the Mac's clock
+/// Returns: 1 with the time in `out`, -999 before the first exchange or on error (see last_error_message)
+///
+/// # Safety
+/// `out` must be valid for writing.
+#[no_mangle]
+pub unsafe extern "C" fn ar_clock_client_to_server(client: Handle, local_ns: i64, out: *mut i64) -> i32 {
+    guard("ar_clock_client_to_server", -999, || {
+        if out.is_null() {
+            record::<()>(Err(AudioRemoteError::InvalidArgument("out is null".into())));
+            return -999;
+        }
+        let server = with_sync(client, |sync| sync.to_server(local_ns)).and_then(|server| server.ok_or_else(not_synced));
+        record(server).map_or(-999, |server| {
+            *out = server;
+            1
+        })
+    })
+}
+
+/// Stop syncing and free the handle. Also done by ar_shutdown.
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_clock_client_close(client: Handle) -> i32 {
+    guard("ar_clock_client_close", -999, || record(close(client)).map_or(-999, |()| 1))
+}
+
+/// The common playout delay for a group of endpoints. `request_json`: {"endpoints": [{"id",
+/// "networkMs" (one way), "bufferMs", "outputMs"}], "marginMs" (10)}
+/// Returns: JSON {"delayMs" (stamp audio to play this far after the Mac's now), "endpoints":
+/// [{"id", "extraDelayMs"}]} (free with rust_string_free), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `request_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_clock_playout_delay(request_json: *const c_char) -> *mut c_char {
+    guard("ar_clock_playout_delay", std::ptr::null_mut(), || {
+        let result = str_arg(request_json, "request").and_then(|json| {
+            let request: PlayoutRequest =
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid playout request: {e}")))?;
+            let playout = playout_delay(&request)?;
+            serde_json::to_string(&playout).map_err(|e| AudioRemoteError::Other(e.to_string()))
+        });
+        string_result(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+
+    /// A client whose clock runs `offset` behind the server's and `ppm` slow, over a path with
+    /// `up` and `down` one-way delays
+    fn exchange(t1: i64, offset: i64, ppm: f64, up: i64, down: i64) -> Exchange {
+        let server = |local: i64| local + offset + (local as f64 * ppm / 1e6) as i64;
+        Exchange { t1, t2: server(t1 + up), t3: server(t1 + up + 50_000), t4: t1 + up + 50_000 + down }
+    }
+
+    #[test]
+    fn test_filter_and_drift() {
+        let mut sync = ClockSync::default();
+        assert_eq!(sync.to_local(0), None);
+        // Queues on the way back make most trips lopsided; every eighth is quick both ways
+        for i in 0..200i64 {
+            let t1 = i * 1_000_000_000;
+            let (up, down) = if i % 8 == 0 { (1_000_000, 1_000_000) } else { (2_000_000, 3_000_000 + (i % 5) * 4_000_000) };
+            sync.add(exchange(t1, 5_000_000_000, 20.0, up, down));
+        }
+        let status = sync.status(200_000_000_000);
+        assert!(status.synced && status.exchanges == 200);
+        assert!((status.drift_ppm - 20.0).abs() < 1.0, "{status:?}");
+        let expected = 5_000_000_000 + 4_000_000;
+        assert!((status.offset_ns - expected).abs() < 100_000, "{status:?}");
+        let local = sync.to_local(sync.to_server(123_456_789_000).unwrap()).unwrap();
+        assert!((local - 123_456_789_000).abs() < 1_000);
+    }
+
+    #[test]
+    fn test_playout_delay() {
+        let request: PlayoutRequest = serde_json::from_str(
+            r#"{"endpoints": [{"id": "phone", "networkMs": 4, "bufferMs": 60, "outputMs": 20}, {"id": "studio", "networkMs": 1, "bufferMs": 20, "outputMs": 5}]}"#,
+        )
+        .unwrap();
+        let playout = playout_delay(&request).unwrap();
+        assert_eq!(playout.delay_ms, 94.0);
+        assert_eq!(playout.endpoints[0], EndpointDelay { id: "phone".into(), extra_delay_ms: 10.0 });
+        assert_eq!(playout.endpoints[1].extra_delay_ms, 68.0);
+
+        let json = r#"{"endpoints": [{"id": "bad", "networkMs": -1}]}"#;
+        assert_eq!(take_c_string(unsafe { ar_clock_playout_delay(format!("{json}\0").as_ptr().cast()) }), None);
+    }
+
+    #[test]
+    fn test_client_syncs_with_server() {
+        let _runtime = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let port = start(0).unwrap();
+        assert_eq!(super::port(), Some(port));
+        let handle = open(&ClientConfig { host: "127.0.0.1".into(), port, interval_ms: 40 }).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while with_sync(handle, |sync| sync.status(now_ns()).exchanges).unwrap() < FILTER_SAMPLES as u64 {
+            assert!(Instant::now() < deadline, "no answers from the clock server");
+            thread::sleep(Duration::from_millis(10));
+        }
+        // Same process, same clock
+        let status = with_sync(handle, |sync| sync.status(now_ns())).unwrap();
+        assert!(status.offset_ns.abs() < 1_000_000, "{status:?}");
+        let mut local = 0;
+        assert_eq!(unsafe { ar_clock_client_to_local(handle, 1_000_000_000, &mut local) }, 1);
+        assert!((local - 1_000_000_000).abs() < 1_000_000);
+        close(handle).unwrap();
+        stop();
+        assert_eq!(super::port(), None);
+    }
+}
@@ -0,0 +1,393 @@
+//! Exactly-once commands over a flaky connection. A remote numbers each
+//! command it sends, `X-Command-Id: <client>:<seq>`, and resends it until it
+//! gets an answer; the server applies each number once and answers repeats
+//! from a short log of recent answers, so a retried "volume up" lands once.
+//!
+//! Ordering is per client: a client sends its next command only after the
+//! previous one is answered (`CommandQueue` does this for Rust and Swift
+//! remotes), and the server refuses a number while another is still being
+//! applied. Commands without the header behave as before.
+//!
+//! Answers of 5xx aren't logged: the command may not have run (the server was
+//! stopping, Swift didn't answer), so a retry runs it again.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::c_char;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::connection::{jitter, Backoff};
+use crate::error::string_result;
+use crate::handle::{Handle, Registry};
+use crate::server::{Request, Response};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+pub const COMMAND_HEADER: &str = "X-Command-Id";
+/// Set on answers repeated from the log
+pub const REPLAYED_HEADER: &str = "X-Command-Replayed";
+/// Answers kept per client, for retries of recent commands
+const ANSWER_LOG: usize = 32;
+const MAX_CLIENTS: usize = 256;
+/// A client quiet for this long is forgotten; its next command starts it afresh
+const CLIENT_TTL: Duration = Duration::from_secs(600);
+const MAX_QUEUED: usize = 64;
+const RETRY_INITIAL: Duration = Duration::from_millis(250);
+const RETRY_MAX: Duration = Duration::from_secs(10);
+
+/// A command being applied, to be finished with its answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Command {
+    client: String,
+    seq: u64,
+}
+
+#[derive(Debug, Default)]
+struct ClientLog {
+    /// The last sequence number applied
+    applied: u64,
+    in_flight: Option<u64>,
+    answers: VecDeque<(u64, Response)>,
+    last_seen: Option<Instant>,
+}
+
+/// Server side: which commands each client has had applied
+#[derive(Debug, Default)]
+pub struct Ledger {
+    clients: BTreeMap<String, ClientLog>,
+}
+
+fn conflict(message: String) -> Response {
+    Response::error(409, message).with_header("Retry-After", "1")
+}
+
+impl Ledger {
+    /// Decide about command `seq` from `client`: apply it, or the response to send instead
+    pub fn begin(&mut self, client: &str, seq: u64, now: Instant) -> Result<Command, Response> {
+        self.clients.retain(|_, log| log.in_flight.is_some() || log.last_seen.is_some_and(|seen| now.duration_since(seen) < CLIENT_TTL));
+        if !self.clients.contains_key(client) && self.clients.len() >= MAX_CLIENTS {
+            let oldest = self.clients.iter().filter(|(_, log)| log.in_flight.is_none()).min_by_key(|(_, log)| log.last_seen);
+            if let Some(oldest) = oldest.map(|(name, _)| name.clone()) {
+                self.clients.remove(&oldest);
+            }
+        }
+        let known = self.clients.contains_key(client);
+        let log = self.clients.entry(client.to_owned()).or_default();
+        log.last_seen = Some(now);
+        if log.in_flight == Some(seq) {
+            return Err(conflict(format!("command {seq} is still being applied")));
+        }
+        if let Some(current) = log.in_flight {
+            return Err(conflict(format!("command {current} isn't answered yet; send {seq} after it")));
+        }
+        if known && seq <= log.applied {
+            return match log.answers.iter().find(|(applied, _)| *applied == seq) {
+                Some((_, answer)) => Err(answer.clone().with_header(REPLAYED_HEADER, "true")),
+                None => Err(Response::error(410, format!("command {seq} was applied long ago"))),
+            };
+        }
+        // A new client may start anywhere: its first numbers may have gone to a Mac that restarted since
+        if known && log.applied != 0 && seq != log.applied + 1 {
+            return Err(conflict(format!("expected command {}, got {seq}", log.applied + 1)));
+        }
+        log.in_flight = Some(seq);
+        Ok(Command { client: client.to_owned(), seq })
+    }
+
+    /// Record the answer to a command from `begin`
+    pub fn finish(&mut self, command: &Command, answer: &Response) {
+        let Some(log) = self.clients.get_mut(&command.client) else {
+            return;
+        };
+        log.in_flight = None;
+        if answer.status >= 500 {
+            return;
+        }
+        log.applied = command.seq;
+        log.answers.push_back((command.seq, answer.clone()));
+        while log.answers.len() > ANSWER_LOG {
+            log.answers.pop_front();
+        }
+    }
+}
+
+static LEDGER: Mutex<Ledger> = Mutex::new(Ledger { clients: BTreeMap::new() });
+
+fn ledger() -> std::sync::MutexGuard<'static, Ledger> {
+    LEDGER.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Check a state-changing request's command ID; the error is the response to send instead of running it
+pub(crate) fn begin(request: &Request) -> Result<Option<Command>, Response> {
+    let Some(id) = request.header(COMMAND_HEADER) else {
+        return Ok(None);
+    };
+    let parsed = id.rsplit_once(':').and_then(|(client, seq)| Some((client, seq.parse::<u64>().ok()?)));
+    let Some((client, seq)) = parsed.filter(|(client, seq)| !client.is_empty() && client.len() <= 64 && *seq > 0) else {
+        return Err(Response::error(400, format!("{COMMAND_HEADER} must be <client>:<sequence number from 1>")));
+    };
+    // Scoped to who's asking, so one remote can't answer for another's commands
+    let owner = request.remote.clone().unwrap_or_else(|| request.peer.ip().to_string());
+    ledger().begin(&format!("{owner}/{client}"), seq, Instant::now()).map(Some)
+}
+
+pub(crate) fn finish(command: Option<Command>, answer: &Response) {
+    if let Some(command) = command {
+        ledger().finish(&command, answer);
+    }
+}
+
+/// A command waiting in a `CommandQueue`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Outgoing {
+    pub seq: u64,
+    pub method: String,
+    pub path: String,
+    pub body: Value,
+    /// Send this as X-Command-Id
+    pub command_id: String,
+    /// 1 for the first send
+    pub attempt: u32,
+}
+
+#[derive(Debug)]
+struct Queued {
+    command: Outgoing,
+    in_flight: bool,
+    not_before: Instant,
+}
+
+/// Client side: commands in order, one in flight at a time, each resent until it's answered
+#[derive(Debug)]
+pub struct CommandQueue {
+    client: String,
+    next_seq: u64,
+    queue: VecDeque<Queued>,
+    backoff: Backoff,
+}
+
+impl CommandQueue {
+    pub fn new(client: String) -> Self {
+        Self { client, next_seq: 1, queue: VecDeque::new(), backoff: Backoff::new(RETRY_INITIAL, RETRY_MAX) }
+    }
+
+    /// Queue a command; returns its sequence number
+    pub fn push(&mut self, method: &str, path: &str, body: Value, now: Instant) -> Result<u64, AudioRemoteError> {
+        if self.queue.len() >= MAX_QUEUED {
+            return Err(AudioRemoteError::Refused(format!("{MAX_QUEUED} commands are already waiting")));
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        let command = Outgoing {
+            seq,
+            method: method.to_owned(),
+            path: path.to_owned(),
+            body,
+            command_id: format!("{}:{seq}", self.client),
+            attempt: 0,
+        };
+        self.queue.push_back(Queued { command, in_flight: false, not_before: now });
+        Ok(seq)
+    }
+
+    /// The command to send now, if any: the oldest, unless it's in flight or waiting to retry
+    pub fn next(&mut self, now: Instant) -> Option<Outgoing> {
+        let head = self.queue.front_mut()?;
+        if head.in_flight || now < head.not_before {
+            return None;
+        }
+        head.in_flight = true;
+        head.command.attempt += 1;
+        Some(head.command.clone())
+    }
+
+    /// Apply the HTTP status a send got (0 if there was no answer); returns when to retry, or None
+    /// once the command is done. 409 (busy) and 5xx are retried; anything else is an answer.
+    pub fn result(&mut self, seq: u64, status: u16, jitter: f64, now: Instant) -> Result<Option<Duration>, AudioRemoteError> {
+        let head = match self.queue.front_mut() {
+            Some(head) if head.command.seq == seq && head.in_flight => head,
+            _ => return Err(AudioRemoteError::InvalidArgument(format!("command {seq} isn't the one in flight"))),
+        };
+        head.in_flight = false;
+        if status == 0 || status == 409 || status >= 500 {
+            let delay = self.backoff.next(jitter);
+            head.not_before = now + delay;
+            return Ok(Some(delay));
+        }
+        self.queue.pop_front();
+        self.backoff.reset();
+        Ok(None)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+static QUEUES: Registry<Mutex<CommandQueue>> = Registry::new("command queue");
+
+fn queue(handle: Handle) -> Result<std::sync::Arc<Mutex<CommandQueue>>, AudioRemoteError> {
+    QUEUES.get(handle)
+}
+
+/// Create a command queue for sending commands to a Mac, with a fresh client ID
+/// Returns: the queue handle, 0 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_command_queue_new() -> Handle {
+    guard("ar_command_queue_new", 0, || {
+        let client = crate::pairing::random_bytes::<8>().map(|bytes| bytes.iter().map(|b| format!("{b:02x}")).collect::<String>());
+        record(client.map(|client| QUEUES.insert(Mutex::new(CommandQueue::new(client))))).unwrap_or(0)
+    })
+}
+
+/// Queue a command, e.g. ("PUT", "/api/v1/volume", "{\"volume\": 0.5}"); `body_json` may be NULL
+/// Returns: its sequence number, -999 on error, e.g. the queue is full (see last_error_message)
+///
+/// # Safety
+/// `method` and `path` must point to valid NUL-terminated strings, `body_json` too unless NULL.
+#[no_mangle]
+pub unsafe extern "C" fn ar_command_queue_push(
+    queue_handle: Handle,
+    method: *const c_char,
+    path: *const c_char,
+    body_json: *const c_char,
+) -> i64 {
+    guard("ar_command_queue_push", -999, || {
+        let result = (|| {
+            let (method, path) = (str_arg(method, "method")?, str_arg(path, "path")?);
+            let body = match body_json.is_null() {
+                true => Value::Null,
+                false => serde_json::from_str(str_arg(body_json, "body")?)
+                    .map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid body JSON: {e}")))?,
+            };
+            let queue = queue(queue_handle)?;
+            let seq = queue.lock().unwrap_or_else(|e| e.into_inner()).push(method, path, body, Instant::now())?;
+            Ok(seq as i64)
+        })();
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Take the command to send now: {"seq", "method", "path", "body", "commandId" (send it as the
+/// X-Command-Id header), "attempt"}. Report how it went with ar_command_queue_result.
+/// Returns: the JSON (free with rust_string_free), NULL when nothing is due or on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_command_queue_next(queue_handle: Handle) -> *mut c_char {
+    guard("ar_command_queue_next", std::ptr::null_mut(), || {
+        let next = queue(queue_handle).and_then(|queue| {
+            let next = queue.lock().unwrap_or_else(|e| e.into_inner()).next(Instant::now());
+            next.ok_or(AudioRemoteError::NotFound("nothing to send now".into()))
+        });
+        string_result(next.map(|command| serde_json::to_string(&command).unwrap_or_default()))
+    })
+}
+
+/// Report the HTTP status a command got, 0 if the request failed without one
+/// Returns: 0 if the command is done, else the milliseconds until it's due again (call
+/// ar_command_queue_next then), -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_command_queue_result(queue_handle: Handle, seq: u64, http_status: i32) -> i64 {
+    guard("ar_command_queue_result", -999, || {
+        let result = queue(queue_handle).and_then(|queue| {
+            let status =
+                u16::try_from(http_status).map_err(|_| AudioRemoteError::InvalidArgument(format!("invalid status {http_status}")))?;
+            let retry = queue.lock().unwrap_or_else(|e| e.into_inner()).result(seq, status, jitter(), Instant::now())?;
+            Ok(retry.map_or(0, |delay| delay.as_millis().max(1) as i64))
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Returns: how many commands are waiting or in flight, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_command_queue_len(queue_handle: Handle) -> i32 {
+    guard("ar_command_queue_len", -999, || {
+        record(queue(queue_handle).map(|queue| queue.lock().unwrap_or_else(|e| e.into_inner()).len() as i32)).unwrap_or(-999)
+    })
+}
+
+/// Drop a queue and any commands still in it
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_command_queue_free(queue_handle: Handle) -> i32 {
+    guard("ar_command_queue_free", -999, || record(QUEUES.remove(queue_handle)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::parse;
+    use serde_json::json;
+
+    #[test]
+    fn test_ledger_applies_once() {
+        let mut ledger = Ledger::default();
+        let now = Instant::now();
+        let first = ledger.begin("a", 7, now).unwrap();
+        assert_eq!(ledger.begin("a", 7, now).unwrap_err().status, 409);
+        assert_eq!(ledger.begin("a", 8, now).unwrap_err().status, 409);
+        ledger.finish(&first, &Response::json(200, &json!({"volume": 0.6})));
+
+        let replay = ledger.begin("a", 7, now).unwrap_err();
+        assert_eq!((replay.status, replay.body.as_slice()), (200, br#"{"volume":0.6}"#.as_slice()));
+        assert!(replay.headers.contains(&(REPLAYED_HEADER, "true".into())));
+        assert_eq!(ledger.begin("a", 9, now).unwrap_err().status, 409);
+
+        // Not applied for sure: the retry runs it
+        let second = ledger.begin("a", 8, now).unwrap();
+        ledger.finish(&second, &Response::error(503, "stopping"));
+        let second = ledger.begin("a", 8, now).unwrap();
+        ledger.finish(&second, &Response::new(204));
+        for seq in 9..9 + ANSWER_LOG as u64 {
+            let command = ledger.begin("a", seq, now).unwrap();
+            ledger.finish(&command, &Response::new(204));
+        }
+        assert_eq!(ledger.begin("a", 8, now).unwrap_err().status, 410);
+        assert!(ledger.begin("b", 8, now).is_ok());
+        // Forgotten after a quiet spell, then welcome to start over
+        assert!(ledger.begin("a", 1, now + CLIENT_TTL).is_ok());
+
+        let raw = "PUT /api/v1/volume HTTP/1.1\r\nX-Command-Id: phone:1\r\nContent-Length: 13\r\n\r\n{\"volume\": 3}";
+        let first = crate::routes::handle(&parse(raw).unwrap());
+        let again = crate::routes::handle(&parse(raw).unwrap());
+        assert_eq!((first.status, again.status, again.body), (400, 400, first.body));
+        assert!(again.headers.contains(&(REPLAYED_HEADER, "true".into())));
+        let bad = crate::routes::handle(&parse("PUT /api/v1/mute HTTP/1.1\r\nX-Command-Id: phone\r\n\r\n").unwrap());
+        assert_eq!(bad.status, 400);
+    }
+
+    #[test]
+    fn test_queue_retries_in_order() {
+        let mut queue = CommandQueue::new("phone".into());
+        let now = Instant::now();
+        let first = queue.push("PUT", "/api/v1/volume", json!({"volume": 0.6}), now).unwrap();
+        let second = queue.push("PUT", "/api/v1/mute", json!({"muted": true}), now).unwrap();
+        assert_eq!((first, second), (1, 2));
+
+        let sent = queue.next(now).unwrap();
+        assert_eq!((sent.seq, sent.command_id.as_str(), sent.attempt), (1, "phone:1", 1));
+        // One in flight at a time, so the Mac sees them in order
+        assert_eq!(queue.next(now), None);
+        assert!(queue.result(2, 200, 0.5, now).is_err());
+
+        let retry = queue.result(1, 0, 1.0, now).unwrap().unwrap();
+        assert_eq!(retry, RETRY_INITIAL);
+        assert_eq!(queue.next(now), None);
+        let resent = queue.next(now + retry).unwrap();
+        assert_eq!((resent.seq, resent.attempt), (1, 2));
+        assert_eq!(queue.result(1, 200, 0.5, now).unwrap(), None);
+
+        let sent = queue.next(now).unwrap();
+        assert_eq!(sent.seq, 2);
+        // A refusal is an answer too: retrying won't change it
+        assert_eq!(queue.result(2, 400, 0.5, now).unwrap(), None);
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,976 @@
+//! Supervised connections to another Mac's event channel (see `websocket`),
+//! for controlling a peer found by `browse` or `discovery`. A connection
+//! pings the Mac when it's been quiet, gives up on it when nothing at all has
+//! arrived for a while, and reconnects with exponential backoff until it's
+//! closed, reporting each state change so the UI can show "Reconnecting…".
+//!
+//! Errors that another attempt can't fix (a rejected token, a certificate
+//! that isn't the pinned one) end in `Failed` instead of retrying forever.
+//!
+//! Each connection runs a supervisor thread that owns the writing end and the
+//! timers, and a reader thread per session that forwards frames to it.
+//!
+//! While connected, the supervisor also sends numbered probe pings and times
+//! their pongs, for `ar_connection_stats`: round-trip latency smoothed as TCP
+//! does it (RFC 6298), its variation, and the share of probes that went
+//! unanswered. Over TCP nothing is lost outright, so "lost" means stalled past
+//! `PROBE_TIMEOUT`, which is what a remote on bad Wi-Fi feels as loss.
+
+use std::collections::VecDeque;
+use std::ffi::{c_char, c_void, CString};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::handle::{Handle, Registry};
+use crate::interfaces;
+use crate::runtime::{self, SendPtr};
+use crate::server::Stream;
+use crate::tls::{self, TlsStream};
+use crate::websocket::{self, Frame, Opcode};
+use crate::error::string_result;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+/// A probe not answered within this counts as lost
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Loss is over the last this many settled probes
+const PROBE_WINDOW: usize = 50;
+/// Starts probe pings' payload, so heartbeat pongs aren't taken for answers
+const PROBE_MAGIC: &[u8] = b"arq1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum ConnectionState {
+    /// The first attempt is under way
+    Connecting = 0,
+    Connected = 1,
+    /// Lost, waiting to try again or trying
+    Reconnecting = 2,
+    /// Gave up: the Mac refused us in a way retrying won't change
+    Failed = 3,
+    /// Closed by ar_connection_close
+    Closed = 4,
+}
+
+impl ConnectionState {
+    pub fn from_raw(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Connecting),
+            1 => Some(Self::Connected),
+            2 => Some(Self::Reconnecting),
+            3 => Some(Self::Failed),
+            4 => Some(Self::Closed),
+            _ => None,
+        }
+    }
+}
+
+/// Exponential backoff with jitter, so Macs that restart don't see all their remotes at once
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, attempt: 0 }
+    }
+
+    /// The wait before the next attempt; `jitter` in 0..1 picks a point in its upper half
+    pub fn next(&mut self, jitter: f64) -> Duration {
+        let ceiling = self.initial.saturating_mul(1 << self.attempt.min(20)).min(self.max);
+        self.attempt += 1;
+        ceiling / 2 + ceiling.mul_f64(jitter.clamp(0.0, 1.0) / 2.0)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// What the heartbeat wants done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beat {
+    Wait(Duration),
+    Ping,
+    /// Nothing has arrived for too long
+    Dead,
+}
+
+/// Liveness from the peer's side: any frame counts, pings only go out when it's quiet
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    interval: Duration,
+    dead_after: Duration,
+    last_received: Instant,
+    last_ping: Instant,
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, dead_after: Duration, now: Instant) -> Self {
+        Self { interval, dead_after, last_received: now, last_ping: now }
+    }
+
+    pub fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    pub fn check(&mut self, now: Instant) -> Beat {
+        let quiet = now.saturating_duration_since(self.last_received);
+        if quiet >= self.dead_after {
+            return Beat::Dead;
+        }
+        let since_ping = now.saturating_duration_since(self.last_received.max(self.last_ping));
+        if since_ping >= self.interval {
+            self.last_ping = now;
+            return Beat::Ping;
+        }
+        Beat::Wait((self.interval - since_ping).min(self.dead_after - quiet))
+    }
+}
+
+/// Round trips of probe pings over one session
+#[derive(Debug, Default)]
+pub struct Quality {
+    next: u64,
+    /// Sent and not answered yet, oldest first
+    outstanding: VecDeque<(u64, Instant)>,
+    /// Whether each of the last `PROBE_WINDOW` settled probes was answered
+    outcomes: VecDeque<bool>,
+    last: Option<Duration>,
+    smoothed: Option<Duration>,
+    variation: Duration,
+    min: Option<Duration>,
+    sent: u64,
+    lost: u64,
+}
+
+impl Quality {
+    /// The payload for the next probe's ping
+    pub fn probe(&mut self, now: Instant) -> Vec<u8> {
+        self.expire(now);
+        self.next += 1;
+        self.sent += 1;
+        self.outstanding.push_back((self.next, now));
+        [PROBE_MAGIC, &self.next.to_be_bytes()].concat()
+    }
+
+    /// Take a pong; false if it doesn't answer an outstanding probe
+    pub fn answered(&mut self, payload: &[u8], now: Instant) -> bool {
+        let Some(sequence) = payload.strip_prefix(PROBE_MAGIC).and_then(|rest| <[u8; 8]>::try_from(rest).ok()).map(u64::from_be_bytes) else {
+            return false;
+        };
+        let Some(index) = self.outstanding.iter().position(|(outstanding, _)| *outstanding == sequence) else {
+            return false;
+        };
+        // RFC 6455 lets the peer answer only the latest of several pings: the older ones aren't lost
+        let (_, sent) = self.outstanding.drain(..=index).next_back().expect("index is in range");
+        let rtt = now.saturating_duration_since(sent);
+        match self.smoothed {
+            None => {
+                self.smoothed = Some(rtt);
+                self.variation = rtt / 2;
+            }
+            Some(smoothed) => {
+                self.variation = (self.variation * 3 + smoothed.abs_diff(rtt)) / 4;
+                self.smoothed = Some((smoothed * 7 + rtt) / 8);
+            }
+        }
+        self.last = Some(rtt);
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.settle(true);
+        true
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(&(_, sent)) = self.outstanding.front() {
+            if now.saturating_duration_since(sent) < PROBE_TIMEOUT {
+                break;
+            }
+            self.outstanding.pop_front();
+            self.lost += 1;
+            self.settle(false);
+        }
+    }
+
+    fn settle(&mut self, answered: bool) {
+        self.outcomes.push_back(answered);
+        if self.outcomes.len() > PROBE_WINDOW {
+            self.outcomes.pop_front();
+        }
+    }
+
+    /// `{"rttMs", "smoothedRttMs", "jitterMs", "minRttMs", "lossPercent", "probesSent", "probesLost", "quality"}`
+    pub fn stats(&mut self, now: Instant) -> Value {
+        self.expire(now);
+        let ms = |duration: Duration| (duration.as_secs_f64() * 10_000.0).round() / 10.0;
+        let loss = match self.outcomes.len() {
+            0 => None,
+            settled => Some(self.outcomes.iter().filter(|answered| !**answered).count() as f64 * 100.0 / settled as f64),
+        };
+        let quality = match (self.smoothed, loss) {
+            (None, None) => None,
+            (smoothed, loss) => {
+                let (rtt, loss) = (smoothed.map_or(f64::INFINITY, ms), loss.unwrap_or(0.0));
+                Some(match () {
+                    _ if loss >= 10.0 || rtt >= 300.0 => "poor",
+                    _ if loss >= 2.0 || rtt >= 100.0 => "fair",
+                    _ => "good",
+                })
+            }
+        };
+        json!({
+            "rttMs": self.last.map(ms),
+            "smoothedRttMs": self.smoothed.map(ms),
+            "jitterMs": self.smoothed.map(|_| ms(self.variation)),
+            "minRttMs": self.min.map(ms),
+            "lossPercent": loss,
+            "probesSent": self.sent,
+            "probesLost": self.lost,
+            "quality": quality,
+        })
+    }
+}
+
+fn default_ping_interval() -> u64 {
+    15_000
+}
+
+fn default_probe_interval() -> u64 {
+    2_000
+}
+
+fn default_dead_after() -> u64 {
+    45_000
+}
+
+fn default_initial_backoff() -> u64 {
+    500
+}
+
+fn default_max_backoff() -> u64 {
+    30_000
+}
+
+/// `ar_connection_open` configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ConnectionConfig {
+    pub host: String,
+    pub port: u16,
+    /// Connect with TLS, trusting only the certificate with this SHA-256 fingerprint
+    pub cert_fingerprint: Option<String>,
+    pub access_token: Option<String>,
+    /// "json" (default) or "msgpack"
+    pub format: Option<String>,
+    /// Default: every topic
+    pub topics: Option<Vec<String>>,
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval_ms: u64,
+    #[serde(default = "default_dead_after")]
+    pub dead_after_ms: u64,
+    /// How often to probe the round trip while connected; 0 turns probing off
+    #[serde(default = "default_probe_interval")]
+    pub probe_interval_ms: u64,
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff_ms: u64,
+}
+
+impl ConnectionConfig {
+    fn validate(&self) -> Result<(), AudioRemoteError> {
+        if self.ping_interval_ms == 0 || self.dead_after_ms <= self.ping_interval_ms {
+            return Err(AudioRemoteError::InvalidArgument("deadAfterMs must be longer than pingIntervalMs".into()));
+        }
+        if self.initial_backoff_ms == 0 || self.max_backoff_ms < self.initial_backoff_ms {
+            return Err(AudioRemoteError::InvalidArgument("maxBackoffMs must be at least initialBackoffMs".into()));
+        }
+        Ok(())
+    }
+
+    fn path(&self) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        if let Some(format) = &self.format {
+            query.append_pair("format", format);
+        }
+        if let Some(topics) = &self.topics {
+            query.append_pair("topics", &topics.join(","));
+        }
+        match query.finish() {
+            query if query.is_empty() => websocket::PATH.to_owned(),
+            query => format!("{}?{query}", websocket::PATH),
+        }
+    }
+}
+
+/// Why an attempt failed
+#[derive(Debug)]
+pub(crate) enum Failure {
+    Retry(String),
+    Permanent { status: Option<u16>, message: String },
+}
+
+/// A TLS failure retrying won't fix
+pub(crate) fn is_certificate_error(error: &io::Error) -> bool {
+    error.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()).is_some_and(|e| matches!(e, rustls::Error::InvalidCertificate(_)))
+}
+
+fn attempt_failed(error: io::Error) -> Failure {
+    match is_certificate_error(&error) {
+        true => Failure::Permanent { status: None, message: "the Mac's certificate isn't the pinned one".into() },
+        false => Failure::Retry(error.to_string()),
+    }
+}
+
+/// Open the socket and do the WebSocket opening handshake
+fn connect(config: &ConnectionConfig, tls: Option<&Arc<rustls::ClientConfig>>, token: Option<&str>) -> Result<BufReader<Stream>, Failure> {
+    let target = Target { host: &config.host, port: config.port, path: &config.path(), peer: "the Mac" };
+    open_websocket(&target, tls, token, attempt_failed)
+}
+
+/// Where `open_websocket` connects, and what to call it in errors
+pub(crate) struct Target<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub path: &'a str,
+    pub peer: &'a str,
+}
+
+/// Open a client WebSocket to `target`; `failed` sorts I/O errors into retry or give up
+pub(crate) fn open_websocket(
+    target: &Target,
+    tls: Option<&Arc<rustls::ClientConfig>>,
+    token: Option<&str>,
+    failed: fn(io::Error) -> Failure,
+) -> Result<BufReader<Stream>, Failure> {
+    let Target { host, port, path, peer } = *target;
+    let addresses = interfaces::resolve(host, port).map_err(|e| Failure::Retry(format!("can't resolve {host}: {e}")))?;
+    let mut last_error = format!("{host} has no addresses");
+    let socket = addresses
+        .into_iter()
+        .find_map(|address| match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                last_error = format!("can't connect to {address}: {e}");
+                None
+            }
+        })
+        .ok_or(Failure::Retry(last_error))?;
+    let _ = socket.set_nodelay(true);
+    socket.set_read_timeout(Some(CONNECT_TIMEOUT)).map_err(failed)?;
+    let mut stream = match tls {
+        Some(tls) => Stream::Tls(TlsStream::client(socket, tls.clone(), host).map_err(failed)?),
+        None => Stream::Plain(socket),
+    };
+
+    let key =
+        base64::engine::general_purpose::STANDARD.encode(crate::pairing::random_bytes::<16>().map_err(|e| Failure::Retry(e.to_string()))?);
+    let mut head = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n",
+        path,
+        interfaces::host_header(host, port)
+    );
+    if let Some(token) = token {
+        head.push_str(&format!("Authorization: Bearer {token}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).map_err(failed)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut lines = Vec::new();
+    let mut read = 0;
+    loop {
+        let mut line = String::new();
+        let length = reader.read_line(&mut line).map_err(failed)?;
+        read += length;
+        if length == 0 || read > MAX_HEAD_BYTES {
+            return Err(Failure::Retry(format!("{peer} sent no valid handshake answer")));
+        }
+        let line = line.trim_end().to_owned();
+        if line.is_empty() {
+            break;
+        }
+        lines.push(line);
+    }
+    let status: u16 = lines.first().and_then(|line| line.split(' ').nth(1)).and_then(|status| status.parse().ok()).unwrap_or(0);
+    match status {
+        101 => {}
+        // Busy or timed out: worth another try
+        0 | 408 | 429 | 500.. => return Err(Failure::Retry(format!("{peer} answered {status}"))),
+        status => return Err(Failure::Permanent { status: Some(status), message: format!("{peer} refused the connection ({status})") }),
+    }
+    let accept = lines.iter().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("sec-websocket-accept").then(|| value.trim().to_owned())
+    });
+    if accept.as_deref() != Some(websocket::accept_key(&key).as_str()) {
+        return Err(Failure::Permanent { status: Some(status), message: format!("{peer}'s handshake answer doesn't match") });
+    }
+    reader.get_ref().set_read_timeout(None).map_err(failed)?;
+    Ok(reader)
+}
+
+/// What the supervisor thread hears about
+enum Input {
+    /// From the reader of session N
+    Frame(u64, Frame),
+    Lost(u64, String),
+    Send(Opcode, Vec<u8>),
+    Close,
+}
+
+/// (connection, ArConnectionState, detail JSON, ctx); the JSON is only valid during the call
+pub type StateCallback = extern "C" fn(connection: Handle, state: i32, detail_json: *const c_char, ctx: *mut c_void);
+/// (connection, bytes, length, 1 if binary, ctx); the bytes are only valid during the call
+pub type MessageCallback = extern "C" fn(connection: Handle, bytes: *const u8, len: usize, binary: i32, ctx: *mut c_void);
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Detail {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attempt: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_in_ms: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+}
+
+pub struct Connection {
+    state: AtomicI32,
+    quality: Mutex<Quality>,
+    input: Mutex<Sender<Input>>,
+    token: Mutex<Option<String>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+static CONNECTIONS: Registry<Connection> = Registry::new("connection");
+
+struct Callbacks {
+    handle: Handle,
+    on_state: StateCallback,
+    on_message: Option<MessageCallback>,
+    ctx: SendPtr,
+}
+
+impl Callbacks {
+    fn state(&self, connection: &Connection, state: ConnectionState, detail: Detail) {
+        connection.state.store(state as i32, Ordering::Release);
+        log::debug!("connection {}: {state:?} {detail:?}", self.handle);
+        let json = CString::new(serde_json::to_string(&detail).unwrap_or_default()).unwrap_or_default();
+        let (handle, callback, ctx) = (self.handle, self.on_state, self.ctx);
+        runtime::deliver(move || callback(handle, state as i32, json.as_ptr(), ctx.get()));
+    }
+
+    fn message(&self, binary: bool, payload: Vec<u8>) {
+        if let Some(callback) = self.on_message {
+            let (handle, ctx) = (self.handle, self.ctx);
+            runtime::deliver(move || callback(handle, payload.as_ptr(), payload.len(), binary as i32, ctx.get()));
+        }
+    }
+}
+
+enum Ended {
+    Closed,
+    Lost(String),
+}
+
+fn read_frames(mut reader: BufReader<Stream>, session: u64, input: Sender<Input>) {
+    loop {
+        match websocket::read_server_frame(&mut reader) {
+            Ok(frame) => {
+                if input.send(Input::Frame(session, frame)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                let reason = match e.kind() {
+                    io::ErrorKind::UnexpectedEof => "the Mac closed the connection".to_owned(),
+                    _ => e.to_string(),
+                };
+                let _ = input.send(Input::Lost(session, reason));
+                return;
+            }
+        }
+    }
+}
+
+/// Run one connected session until it's lost or closed
+fn run_session(
+    config: &ConnectionConfig,
+    writer: &mut Stream,
+    session: u64,
+    inputs: &Receiver<Input>,
+    callbacks: &Callbacks,
+    quality: &Mutex<Quality>,
+) -> Ended {
+    let lost = |e: io::Error| Ended::Lost(e.to_string());
+    let quality = || quality.lock().unwrap_or_else(|e| e.into_inner());
+    let interval = Duration::from_millis(config.ping_interval_ms);
+    let dead_after = Duration::from_millis(config.dead_after_ms);
+    let probe_interval = (config.probe_interval_ms > 0).then(|| Duration::from_millis(config.probe_interval_ms));
+    let mut heartbeat = Heartbeat::new(interval, dead_after, Instant::now());
+    let mut next_probe = Instant::now();
+    let mut message: Option<(bool, Vec<u8>)> = None;
+    *quality() = Quality::default();
+    loop {
+        let now = Instant::now();
+        if let Some(probe_interval) = probe_interval.filter(|_| now >= next_probe) {
+            next_probe = now + probe_interval;
+            let payload = quality().probe(now);
+            if let Err(e) = websocket::write_client_frame(writer, Opcode::Ping, &payload) {
+                return lost(e);
+            }
+        }
+        let wait = match heartbeat.check(now) {
+            Beat::Wait(wait) => wait,
+            Beat::Ping => match websocket::write_client_frame(writer, Opcode::Ping, b"") {
+                Ok(()) => continue,
+                Err(e) => return lost(e),
+            },
+            Beat::Dead => return Ended::Lost(format!("nothing from the Mac for {} s", dead_after.as_secs_f64())),
+        };
+        let wait = match probe_interval {
+            Some(_) => wait.min(next_probe.saturating_duration_since(now)),
+            None => wait,
+        };
+        let frame = match inputs.recv_timeout(wait) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) | Ok(Input::Close) => {
+                let _ = websocket::write_client_frame(writer, Opcode::Close, &1000u16.to_be_bytes());
+                return Ended::Closed;
+            }
+            Ok(Input::Send(opcode, payload)) => match websocket::write_client_frame(writer, opcode, &payload) {
+                Ok(()) => continue,
+                Err(e) => return lost(e),
+            },
+            Ok(Input::Frame(from, _) | Input::Lost(from, _)) if from != session => continue,
+            Ok(Input::Lost(_, reason)) => return Ended::Lost(reason),
+            Ok(Input::Frame(_, frame)) => frame,
+        };
+        heartbeat.received(Instant::now());
+        let (binary, payload) = match frame.opcode {
+            Opcode::Ping => match websocket::write_client_frame(writer, Opcode::Pong, &frame.payload) {
+                Ok(()) => continue,
+                Err(e) => return lost(e),
+            },
+            Opcode::Pong => {
+                quality().answered(&frame.payload, Instant::now());
+                continue;
+            }
+            Opcode::Close => {
+                let _ = websocket::write_client_frame(writer, Opcode::Close, &frame.payload);
+                return Ended::Lost("the Mac closed the connection".into());
+            }
+            Opcode::Text | Opcode::Binary => (frame.opcode == Opcode::Binary, frame.payload),
+            Opcode::Continuation => match message.take() {
+                Some((binary, mut so_far)) => {
+                    so_far.extend_from_slice(&frame.payload);
+                    (binary, so_far)
+                }
+                None => return Ended::Lost("the Mac sent a stray continuation frame".into()),
+            },
+        };
+        match frame.fin {
+            true => callbacks.message(binary, payload),
+            false => message = Some((binary, payload)),
+        }
+    }
+}
+
+/// Wait out a backoff delay; false if the connection was closed meanwhile
+fn wait(inputs: &Receiver<Input>, delay: Duration) -> bool {
+    let deadline = Instant::now() + delay;
+    loop {
+        match inputs.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Err(RecvTimeoutError::Timeout) => return true,
+            Err(RecvTimeoutError::Disconnected) | Ok(Input::Close) => return false,
+            // Frames from a finished session, sends that raced the disconnect
+            Ok(_) => {}
+        }
+    }
+}
+
+/// A random jitter for `Backoff::next`
+pub(crate) fn jitter() -> f64 {
+    crate::pairing::random_bytes::<4>().map(|bytes| f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX)).unwrap_or(0.5)
+}
+
+fn supervise(connection: Arc<Connection>, config: ConnectionConfig, inputs: Receiver<Input>, callbacks: Callbacks) {
+    let tls = match config.cert_fingerprint.as_deref().map(tls::pinned_client_config).transpose() {
+        Ok(tls) => tls,
+        Err(e) => return callbacks.state(&connection, ConnectionState::Failed, Detail { error: Some(e.to_string()), ..Detail::default() }),
+    };
+    let mut backoff = Backoff::new(Duration::from_millis(config.initial_backoff_ms), Duration::from_millis(config.max_backoff_ms));
+    let mut attempt = 0;
+    let mut session = 0;
+    callbacks.state(&connection, ConnectionState::Connecting, Detail::default());
+    loop {
+        let token = connection.token.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let error = match connect(&config, tls.as_ref(), token.as_deref()) {
+            Ok(reader) => {
+                let mut writer = match reader.get_ref().try_clone() {
+                    Ok(writer) => writer,
+                    Err(e) => {
+                        return callbacks.state(
+                            &connection,
+                            ConnectionState::Failed,
+                            Detail { error: Some(e.to_string()), ..Detail::default() },
+                        )
+                    }
+                };
+                session += 1;
+                let sender = connection.input.lock().unwrap_or_else(|e| e.into_inner()).clone();
+                let reader =
+                    thread::Builder::new().name("audioremote-connection-read".into()).spawn(move || read_frames(reader, session, sender));
+                let ended = match reader {
+                    Ok(_) => {
+                        backoff.reset();
+                        attempt = 0;
+                        callbacks.state(&connection, ConnectionState::Connected, Detail::default());
+                        run_session(&config, &mut writer, session, &inputs, &callbacks, &connection.quality)
+                    }
+                    Err(e) => Ended::Lost(format!("can't start the reader thread: {e}")),
+                };
+                // Unblocks the reader, which then ends on its own
+                let _ = writer.shutdown(Shutdown::Both);
+                match ended {
+                    Ended::Closed => return callbacks.state(&connection, ConnectionState::Closed, Detail::default()),
+                    Ended::Lost(reason) => reason,
+                }
+            }
+            Err(Failure::Permanent { status, message }) => {
+                return callbacks.state(&connection, ConnectionState::Failed, Detail { error: Some(message), status, ..Detail::default() });
+            }
+            Err(Failure::Retry(reason)) => reason,
+        };
+        attempt += 1;
+        let delay = backoff.next(jitter());
+        let detail = Detail { attempt: Some(attempt), retry_in_ms: Some(delay.as_millis() as u64), error: Some(error), status: None };
+        callbacks.state(&connection, ConnectionState::Reconnecting, detail);
+        if !wait(&inputs, delay) {
+            return callbacks.state(&connection, ConnectionState::Closed, Detail::default());
+        }
+    }
+}
+
+pub(crate) fn open(
+    config: ConnectionConfig,
+    on_state: StateCallback,
+    on_message: Option<MessageCallback>,
+    ctx: SendPtr,
+) -> Result<Handle, AudioRemoteError> {
+    config.validate()?;
+    let (sender, inputs) = mpsc::channel();
+    let connection = Arc::new(Connection {
+        state: AtomicI32::new(ConnectionState::Connecting as i32),
+        quality: Mutex::new(Quality::default()),
+        input: Mutex::new(sender),
+        token: Mutex::new(config.access_token.clone()),
+        thread: Mutex::new(None),
+    });
+    let handle = CONNECTIONS.insert_shared(connection.clone());
+    let callbacks = Callbacks { handle, on_state, on_message, ctx };
+    let spawned = {
+        let connection = connection.clone();
+        thread::Builder::new().name("audioremote-connection".into()).spawn(move || supervise(connection, config, inputs, callbacks))
+    };
+    match spawned {
+        Ok(thread) => {
+            *connection.thread.lock().unwrap_or_else(|e| e.into_inner()) = Some(thread);
+            Ok(handle)
+        }
+        Err(e) => {
+            let _ = CONNECTIONS.remove(handle);
+            Err(AudioRemoteError::Other(format!("can't start the connection thread: {e}")))
+        }
+    }
+}
+
+pub fn state(handle: Handle) -> Result<ConnectionState, AudioRemoteError> {
+    let connection = CONNECTIONS.get(handle)?;
+    Ok(ConnectionState::from_raw(connection.state.load(Ordering::Acquire)).unwrap_or(ConnectionState::Failed))
+}
+
+/// Send a message; refused unless connected, since nothing is queued across reconnects
+pub fn send(handle: Handle, payload: Vec<u8>, binary: bool) -> Result<(), AudioRemoteError> {
+    let connection = CONNECTIONS.get(handle)?;
+    if connection.state.load(Ordering::Acquire) != ConnectionState::Connected as i32 {
+        return Err(AudioRemoteError::Refused("not connected".into()));
+    }
+    let opcode = if binary { Opcode::Binary } else { Opcode::Text };
+    let input = connection.input.lock().unwrap_or_else(|e| e.into_inner());
+    input.send(Input::Send(opcode, payload)).map_err(|_| AudioRemoteError::Refused("the connection has ended".into()))
+}
+
+/// The current session's probe results; they start over on each reconnect
+pub fn stats(handle: Handle) -> Result<Value, AudioRemoteError> {
+    let connection = CONNECTIONS.get(handle)?;
+    let mut stats = connection.quality.lock().unwrap_or_else(|e| e.into_inner()).stats(Instant::now());
+    stats["state"] = json!(connection.state.load(Ordering::Acquire));
+    Ok(stats)
+}
+
+/// Use a new access token from the next attempt on, e.g. after refreshing an expired one
+pub fn set_token(handle: Handle, token: Option<String>) -> Result<(), AudioRemoteError> {
+    *CONNECTIONS.get(handle)?.token.lock().unwrap_or_else(|e| e.into_inner()) = token;
+    Ok(())
+}
+
+fn shut(connection: &Connection) {
+    let _ = connection.input.lock().unwrap_or_else(|e| e.into_inner()).send(Input::Close);
+    let thread = connection.thread.lock().unwrap_or_else(|e| e.into_inner()).take();
+    // A state callback may close its own connection, on the supervisor thread
+    if let Some(thread) = thread.filter(|thread| thread.thread().id() != thread::current().id()) {
+        let _ = thread.join();
+    }
+}
+
+pub fn close(handle: Handle) -> Result<(), AudioRemoteError> {
+    let connection = CONNECTIONS.remove(handle)?;
+    shut(&connection);
+    Ok(())
+}
+
+/// Close every connection, at shutdown
+pub fn close_all() {
+    for connection in CONNECTIONS.all() {
+        shut(&connection);
+    }
+}
+
+/// Connect to a Mac's event channel and keep the connection up. `config_json`: {"host" (a name or
+/// an IP, link-local IPv6 with its zone, e.g. "fe80::1%en0"), "port",
+/// "certFingerprint" (optional: connect with TLS, trusting only this certificate), "accessToken",
+/// "format" ("json"/"msgpack"), "topics" [names], "pingIntervalMs" (15000), "deadAfterMs" (45000),
+/// "probeIntervalMs" (2000, 0: off; see ar_connection_stats), "initialBackoffMs" (500),
+/// "maxBackoffMs" (30000)}. `on_state` gets every ArConnectionState change
+/// with a detail JSON {"attempt", "retryInMs", "error", "status"}; `on_message` (nullable) every
+/// message. Both run on the ar_runtime_set_callback_queue queue, or the connection's thread.
+/// Returns: the connection handle, 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string; `ctx` is passed to the callbacks
+/// from another thread and must stay valid until ar_connection_close.
+#[no_mangle]
+pub unsafe extern "C" fn ar_connection_open(
+    config_json: *const c_char,
+    on_state: Option<StateCallback>,
+    on_message: Option<MessageCallback>,
+    ctx: *mut c_void,
+) -> Handle {
+    guard("ar_connection_open", 0, || {
+        let result = str_arg(config_json, "config").and_then(|json| {
+            let config: ConnectionConfig =
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid connection config: {e}")))?;
+            let on_state = on_state.ok_or(AudioRemoteError::InvalidArgument("on_state is null".into()))?;
+            open(config, on_state, on_message, SendPtr(ctx))
+        });
+        record(result).unwrap_or(0)
+    })
+}
+
+/// Returns: the connection's ArConnectionState, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_connection_state(connection: Handle) -> i32 {
+    guard("ar_connection_state", -999, || record(state(connection)).map_or(-999, |state| state as i32))
+}
+
+/// Round-trip probes of the current session, for a signal-quality indicator and latency
+/// compensation. They start over on each reconnect.
+/// Returns: JSON {"state": ArConnectionState, "rttMs" (the last probe's), "smoothedRttMs",
+/// "jitterMs" (round-trip variation), "minRttMs", "lossPercent" (probes unanswered within 5 s, of
+/// the last 50), "probesSent", "probesLost", "quality" ("good", "fair" or "poor")}, values null
+/// until the first probe settles (free with rust_string_free), NULL on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_connection_stats(connection: Handle) -> *mut c_char {
+    guard("ar_connection_stats", std::ptr::null_mut(), || string_result(stats(connection).map(|stats| stats.to_string())))
+}
+
+/// Send a message (`binary` nonzero for a binary one) over a connected connection
+/// Returns: 1 if sent, -999 if not connected or on error (see last_error_message)
+///
+/// # Safety
+/// `bytes` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ar_connection_send(connection: Handle, bytes: *const u8, len: usize, binary: i32) -> i32 {
+    guard("ar_connection_send", -999, || {
+        if bytes.is_null() {
+            record::<()>(Err(AudioRemoteError::InvalidArgument("bytes is null".into())));
+            return -999;
+        }
+        let payload = std::slice::from_raw_parts(bytes, len).to_vec();
+        record(send(connection, payload, binary != 0)).map_or(-999, |()| 1)
+    })
+}
+
+/// Replace the access token used from the next (re)connection on; NULL for none
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `token` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_connection_set_token(connection: Handle, token: *const c_char) -> i32 {
+    guard("ar_connection_set_token", -999, || {
+        let token = match token.is_null() {
+            true => Ok(None),
+            false => str_arg(token, "token").map(|token| Some(token.to_owned())),
+        };
+        record(token.and_then(|token| set_token(connection, token))).map_or(-999, |()| 1)
+    })
+}
+
+/// Close a connection and free its handle; the state callback gets AR_CONNECTION_CLOSED, and
+/// nothing after it returns except callbacks already queued. Also done by ar_shutdown.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_connection_close(connection: Handle) -> i32 {
+    guard("ar_connection_close", -999, || record(close(connection)).map_or(-999, |()| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+    use crate::events::{Topic, HUB};
+    use crate::server::{self, ServerConfig};
+
+    #[test]
+    fn test_backoff_and_heartbeat() {
+        let mut backoff = Backoff::new(Duration::from_millis(500), Duration::from_secs(4));
+        let delays: Vec<u64> = (0..6).map(|_| backoff.next(1.0).as_millis() as u64).collect();
+        assert_eq!(delays, [500, 1000, 2000, 4000, 4000, 4000]);
+        assert_eq!(backoff.next(0.0), Duration::from_secs(2));
+        backoff.reset();
+        assert_eq!(backoff.next(0.5), Duration::from_millis(375));
+
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(10), Duration::from_secs(30), start);
+        assert_eq!(heartbeat.check(at(4_000)), Beat::Wait(Duration::from_secs(6)));
+        heartbeat.received(at(4_000));
+        assert_eq!(heartbeat.check(at(14_000)), Beat::Ping);
+        assert_eq!(heartbeat.check(at(20_000)), Beat::Wait(Duration::from_secs(4)));
+        assert_eq!(heartbeat.check(at(24_000)), Beat::Ping);
+        assert_eq!(heartbeat.check(at(34_000)), Beat::Dead);
+    }
+
+    #[test]
+    fn test_quality() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut quality = Quality::default();
+        assert_eq!(quality.stats(start)["quality"], Value::Null);
+
+        let first = quality.probe(at(0));
+        assert!(!quality.answered(b"", at(10)), "a heartbeat pong");
+        assert!(quality.answered(&first, at(40)));
+        assert!(!quality.answered(&first, at(50)), "answered twice");
+        let stats = quality.stats(at(40));
+        assert_eq!((stats["rttMs"].as_f64(), stats["jitterMs"].as_f64(), stats["lossPercent"].as_f64()), (Some(40.0), Some(20.0), Some(0.0)));
+        assert_eq!(stats["quality"], "good");
+
+        let second = quality.probe(at(1_000));
+        quality.answered(&second, at(1_600));
+        let stats = quality.stats(at(1_600));
+        assert_eq!((stats["smoothedRttMs"].as_f64(), stats["minRttMs"].as_f64()), (Some(110.0), Some(40.0)));
+        assert_eq!(stats["quality"], "fair");
+
+        // One stalls past the timeout; of two unanswered pings, only the older one is lost if the newer is answered
+        quality.probe(at(2_000));
+        let stats = quality.stats(at(7_000));
+        assert_eq!((stats["probesLost"].as_u64(), stats["lossPercent"].as_f64()), (Some(1), Some(100.0 / 3.0)));
+        assert_eq!(stats["quality"], "poor");
+        quality.probe(at(8_000));
+        let latest = quality.probe(at(9_000));
+        assert!(quality.answered(&latest, at(9_010)));
+        assert_eq!(quality.stats(at(20_000))["probesLost"].as_u64(), Some(1));
+    }
+
+    #[derive(Default)]
+    struct Seen {
+        states: Vec<(i32, String)>,
+        messages: Vec<String>,
+    }
+
+    extern "C" fn on_state(_: Handle, state: i32, detail: *const c_char, ctx: *mut c_void) {
+        let seen = unsafe { &*(ctx as *const Mutex<Seen>) };
+        let detail = unsafe { std::ffi::CStr::from_ptr(detail) }.to_string_lossy().into_owned();
+        seen.lock().unwrap().states.push((state, detail));
+    }
+
+    extern "C" fn on_message(_: Handle, bytes: *const u8, len: usize, _: i32, ctx: *mut c_void) {
+        let seen = unsafe { &*(ctx as *const Mutex<Seen>) };
+        let text = String::from_utf8_lossy(unsafe { std::slice::from_raw_parts(bytes, len) }).into_owned();
+        seen.lock().unwrap().messages.push(text);
+    }
+
+    fn wait_for(seen: &Mutex<Seen>, what: impl Fn(&Seen) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !what(&seen.lock().unwrap()) {
+            assert!(Instant::now() < deadline, "timed out; states so far {:?}", seen.lock().unwrap().states);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn last_state(seen: &Seen) -> i32 {
+        seen.states.last().map_or(-1, |(state, _)| *state)
+    }
+
+    #[test]
+    fn test_reconnects_until_refused() {
+        let _runtime = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let config = ServerConfig { port: 0, bind_address: "127.0.0.1".parse().unwrap(), require_auth: false, ..Default::default() };
+        let port = server::start(&config).unwrap().port();
+        let config = ServerConfig { port, ..config };
+
+        let seen: &'static Mutex<Seen> = Box::leak(Box::new(Mutex::new(Seen::default())));
+        let ctx = SendPtr(seen as *const Mutex<Seen> as *mut c_void);
+        let json = format!(r#"{{"host": "127.0.0.1", "port": {port}, "topics": ["volume"], "probeIntervalMs": 20, "initialBackoffMs": 20, "maxBackoffMs": 50}}"#);
+        let handle = open(serde_json::from_str(&json).unwrap(), on_state, Some(on_message), ctx).unwrap();
+        wait_for(seen, |seen| last_state(seen) == ConnectionState::Connected as i32);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while stats(handle).unwrap()["rttMs"].is_null() {
+            assert!(Instant::now() < deadline, "no probe answered");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let probed = take_c_string(ar_connection_stats(handle)).unwrap();
+        assert!(probed.contains("\"quality\":\"good\"") && probed.contains("\"state\":1"), "{probed}");
+        HUB.publish(Topic::Volume, serde_json::json!({"volume": 0.3, "muted": false}));
+        wait_for(seen, |seen| seen.messages.iter().any(|message| message.contains("0.3")));
+        send(handle, br#"{"subscribe": ["volume", "devices"]}"#.to_vec(), false).unwrap();
+
+        // The Mac goes away and comes back
+        server::stop();
+        wait_for(seen, |seen| last_state(seen) == ConnectionState::Reconnecting as i32);
+        let reconnecting = ConnectionState::Reconnecting as i32;
+        assert!(seen.lock().unwrap().states.iter().any(|(state, detail)| *state == reconnecting && detail.contains("\"attempt\":1")));
+        assert!(send(handle, b"{}".to_vec(), false).is_err());
+        server::start(&config).unwrap();
+        wait_for(seen, |seen| last_state(seen) == ConnectionState::Connected as i32);
+
+        // ...now wanting a token we don't have: no point retrying
+        server::stop();
+        server::start(&ServerConfig { require_auth: true, ..config }).unwrap();
+        wait_for(seen, |seen| last_state(seen) == ConnectionState::Failed as i32);
+        assert!(seen.lock().unwrap().states.last().unwrap().1.contains("\"status\":401"));
+        assert_eq!(state(handle).unwrap(), ConnectionState::Failed);
+        close(handle).unwrap();
+        server::stop();
+        assert!(state(handle).is_err());
+    }
+}
@@ -0,0 +1,297 @@
+//! Sample-accurate splices between two PCM streams, for switching sources or
+//! output devices without a pop or a gap. Where `fade` steps a device's
+//! volume, this mixes the audio itself: one crossfade handles one switch, fed
+//! the outgoing and incoming streams side by side until it has crossed over,
+//! and the incoming stream alone after that.
+//!
+//! `equalPower` (the default) keeps the loudness steady across unrelated
+//! audio; `linear` suits the same audio on both sides, e.g. one source moving
+//! to another device, where equal power would bulge by 3 dB in the middle.
+//!
+//! A new device or decoder often starts with a run of digital silence. With
+//! `waitForAudio` the fade doesn't start until the incoming stream has any,
+//! playing the outgoing one meanwhile, so the switch can't fade into the gap;
+//! it gives up waiting after `maxWaitMs`. An outgoing stream that's gone
+//! (NULL) is silence, so a dead device still fades in cleanly.
+
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::handle::{Handle, Registry};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum CrossfadeCurve {
+    #[default]
+    EqualPower,
+    Linear,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct CrossfadeConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default = "default_duration_ms")]
+    pub duration_ms: f64,
+    #[serde(default)]
+    pub curve: CrossfadeCurve,
+    #[serde(default = "default_wait_for_audio")]
+    pub wait_for_audio: bool,
+    #[serde(default = "default_max_wait_ms")]
+    pub max_wait_ms: f64,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> f64 {
+    48_000.0
+}
+
+/// Long enough not to click, short enough not to hear both
+fn default_duration_ms() -> f64 {
+    50.0
+}
+
+fn default_wait_for_audio() -> bool {
+    true
+}
+
+fn default_max_wait_ms() -> f64 {
+    500.0
+}
+
+impl Default for CrossfadeConfig {
+    fn default() -> Self {
+        Self {
+            channels: default_channels(),
+            sample_rate: default_sample_rate(),
+            duration_ms: default_duration_ms(),
+            curve: CrossfadeCurve::default(),
+            wait_for_audio: default_wait_for_audio(),
+            max_wait_ms: default_max_wait_ms(),
+        }
+    }
+}
+
+pub struct Crossfade {
+    channels: usize,
+    curve: CrossfadeCurve,
+    /// Frames in the fade, and how far through it is
+    length: u64,
+    position: u64,
+    /// Frames left to wait for the incoming audio, None once it's come (or without waitForAudio)
+    waiting: Option<u64>,
+}
+
+impl Crossfade {
+    pub fn new(config: &CrossfadeConfig) -> Result<Self, AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=MAX_CHANNELS).contains(&config.channels) {
+            return invalid(format!("channels {} is outside 1–{MAX_CHANNELS}", config.channels));
+        }
+        if !(8000.0..=768_000.0).contains(&config.sample_rate) {
+            return invalid(format!("sampleRate {} is outside 8000–768000", config.sample_rate));
+        }
+        for (name, ms) in [("durationMs", config.duration_ms), ("maxWaitMs", config.max_wait_ms)] {
+            if !(0.0..=10_000.0).contains(&ms) {
+                return invalid(format!("{name} {ms} is outside 0–10000"));
+            }
+        }
+        let frames = |ms: f64| (ms * config.sample_rate / 1000.0).round() as u64;
+        Ok(Self {
+            channels: config.channels as usize,
+            curve: config.curve,
+            length: frames(config.duration_ms),
+            position: 0,
+            waiting: config.wait_for_audio.then(|| frames(config.max_wait_ms)),
+        })
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    /// Frames of the fade still to come, not counting any wait; 0 once it's crossed over
+    pub fn remaining(&self) -> u64 {
+        self.length - self.position
+    }
+
+    /// The outgoing and incoming gains `t` (0–1) through the fade
+    fn gains(&self, t: f64) -> (f64, f64) {
+        match self.curve {
+            CrossfadeCurve::EqualPower => {
+                let angle = t * std::f64::consts::FRAC_PI_2;
+                (angle.cos(), angle.sin())
+            }
+            CrossfadeCurve::Linear => (1.0 - t, t),
+        }
+    }
+
+    /// Splice interleaved `outgoing` (None for silence) and `incoming` into `output`, for as many
+    /// whole frames as `incoming` and `output` hold; samples that aren't finite count as silence
+    pub fn process(&mut self, outgoing: Option<&[f32]>, incoming: &[f32], output: &mut [f32]) {
+        let finite = |sample: f32| if sample.is_finite() { f64::from(sample) } else { 0.0 };
+        let frames = incoming.chunks_exact(self.channels).zip(output.chunks_exact_mut(self.channels));
+        for (i, (incoming, output)) in frames.enumerate() {
+            let outgoing = outgoing.and_then(|outgoing| outgoing.get(i * self.channels..(i + 1) * self.channels));
+            if let Some(left) = self.waiting {
+                self.waiting = match incoming.iter().all(|&sample| finite(sample) == 0.0) {
+                    true => left.checked_sub(1),
+                    false => None,
+                };
+            }
+            let (out_gain, in_gain) = match self.waiting {
+                Some(_) => (1.0, 0.0),
+                None if self.position >= self.length => (0.0, 1.0),
+                None => {
+                    self.position += 1;
+                    self.gains(self.position as f64 / (self.length + 1) as f64)
+                }
+            };
+            for (channel, (out, &sample)) in output.iter_mut().zip(incoming).enumerate() {
+                let from = outgoing.map_or(0.0, |outgoing| finite(outgoing[channel]));
+                *out = (from * out_gain + finite(sample) * in_gain) as f32;
+            }
+        }
+    }
+}
+
+static CROSSFADES: Registry<Mutex<Crossfade>> = Registry::new("crossfade");
+
+/// Start a splice for one switch. `config_json` (nullable): {"channels" (default 2), "sampleRate"
+/// (default 48000), "durationMs" (default 50), "curve" ("equalPower" (default) | "linear"),
+/// "waitForAudio" (default true), "maxWaitMs" (default 500)}
+/// Returns: a handle (free with ar_crossfade_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_crossfade_new(config_json: *const c_char) -> Handle {
+    guard("ar_crossfade_new", 0, || {
+        let config = match config_json.is_null() {
+            true => Ok(CrossfadeConfig::default()),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid crossfade config: {e}")))
+            }),
+        };
+        match record(config.and_then(|config| Crossfade::new(&config))) {
+            Some(crossfade) => CROSSFADES.insert(Mutex::new(crossfade)),
+            None => 0,
+        }
+    })
+}
+
+/// Splice `frames` frames of the interleaved `outgoing` and `incoming` streams into `output`
+/// Returns: the frames of fade still to come (0 once crossed over, when `outgoing` can stop), -999
+/// on error (see last_error_message)
+///
+/// # Safety
+/// `outgoing` must be null or point to `frames` × channels floats, `incoming` and `output` must each
+/// point to that many (or may be null when `frames` is 0), and `output` mustn't overlap either input.
+#[no_mangle]
+pub unsafe extern "C" fn ar_crossfade_process(crossfade: Handle, outgoing: *const f32, incoming: *const f32, frames: usize, output: *mut f32) -> i64 {
+    guard("ar_crossfade_process", -999, || {
+        let result = CROSSFADES.get(crossfade).and_then(|crossfade| {
+            let mut crossfade = crossfade.lock().unwrap_or_else(|e| e.into_inner());
+            let len = frames
+                .checked_mul(crossfade.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len > 0 {
+                if incoming.is_null() || output.is_null() {
+                    return Err(AudioRemoteError::InvalidArgument("incoming and output must not be null".into()));
+                }
+                let outgoing = (!outgoing.is_null()).then(|| std::slice::from_raw_parts(outgoing, len));
+                crossfade.process(outgoing, std::slice::from_raw_parts(incoming, len), std::slice::from_raw_parts_mut(output, len));
+            }
+            Ok(crossfade.remaining() as i64)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Release a crossfade from ar_crossfade_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_crossfade_free(crossfade: Handle) -> i32 {
+    guard("ar_crossfade_free", -999, || record(CROSSFADES.remove(crossfade)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mono(config: CrossfadeConfig) -> Crossfade {
+        Crossfade::new(&CrossfadeConfig { channels: 1, sample_rate: 8000.0, wait_for_audio: false, ..config }).unwrap()
+    }
+
+    #[test]
+    fn test_curves() {
+        // 10 ms at 8 kHz is 80 frames; the same audio both sides stays level through a linear fade
+        let mut linear = mono(CrossfadeConfig { duration_ms: 10.0, curve: CrossfadeCurve::Linear, ..CrossfadeConfig::default() });
+        let mut output = vec![0.0; 100];
+        linear.process(Some(&[0.5; 100]), &[0.5; 100], &mut output);
+        assert!(output.iter().all(|&sample| (sample - 0.5).abs() < 1e-6), "{output:?}");
+        assert_eq!(linear.remaining(), 0);
+
+        // Equal power: unrelated audio keeps its power, from all outgoing to all incoming
+        let mut equal = mono(CrossfadeConfig { duration_ms: 10.0, ..CrossfadeConfig::default() });
+        let (mut from_only, mut to_only) = (vec![0.0; 100], vec![0.0; 100]);
+        equal.process(Some(&[1.0; 100]), &[0.0; 100], &mut from_only);
+        let mut equal = mono(CrossfadeConfig { duration_ms: 10.0, ..CrossfadeConfig::default() });
+        equal.process(Some(&[0.0; 100]), &[1.0; 100], &mut to_only);
+        assert!(from_only[0] > 0.99 && to_only[0] < 0.03 && from_only[80] == 0.0 && to_only[80] == 1.0);
+        for (from, to) in from_only.iter().zip(&to_only) {
+            assert!((from * from + to * to - 1.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_waits_for_audio_and_splits_across_calls() {
+        let config = CrossfadeConfig { channels: 2, sample_rate: 8000.0, duration_ms: 1.0, max_wait_ms: 2.0, ..CrossfadeConfig::default() };
+        let mut crossfade = Crossfade::new(&config).unwrap();
+        // The new device's first 4 frames are silent: the old one plays on, untouched
+        let incoming: Vec<f32> = [[0.0; 2]; 4].into_iter().chain([[1.0, -1.0]; 16]).flatten().collect();
+        let outgoing = [0.25; 40];
+        let mut output = [0.0; 40];
+        crossfade.process(Some(&outgoing[..10]), &incoming[..10], &mut output[..10]);
+        assert_eq!(output[..8], [0.25; 8]);
+        assert_eq!(crossfade.remaining(), 7);
+        crossfade.process(Some(&outgoing[10..]), &incoming[10..], &mut output[10..]);
+        assert_eq!(crossfade.remaining(), 0);
+        assert_eq!(output[24..], [1.0, -1.0].repeat(8));
+
+        // Silence for longer than maxWaitMs (16 frames) starts the fade anyway; a gone outgoing
+        // stream is silence
+        let mut crossfade = Crossfade::new(&config).unwrap();
+        let mut output = vec![1.0; 60];
+        crossfade.process(None, &[0.0; 60], &mut output);
+        assert_eq!(crossfade.remaining(), 0);
+        assert!(output.iter().all(|&sample| sample == 0.0));
+        assert!(Crossfade::new(&CrossfadeConfig { duration_ms: -1.0, ..CrossfadeConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let crossfade = unsafe { ar_crossfade_new(c"{\"channels\": 1, \"durationMs\": 1, \"waitForAudio\": false}".as_ptr()) };
+        assert_ne!(crossfade, 0);
+        let (outgoing, incoming, mut output) = ([1.0f32; 32], [0.5f32; 32], [0.0f32; 32]);
+        assert_eq!(unsafe { ar_crossfade_process(crossfade, outgoing.as_ptr(), incoming.as_ptr(), 32, output.as_mut_ptr()) }, 16);
+        assert_eq!(unsafe { ar_crossfade_process(crossfade, std::ptr::null(), incoming.as_ptr(), 32, output.as_mut_ptr()) }, 0);
+        assert!(output[0] < 0.5 && output[16..] == [0.5; 16]);
+        assert_eq!(unsafe { ar_crossfade_process(crossfade, std::ptr::null(), std::ptr::null(), 0, std::ptr::null_mut()) }, 0);
+        assert_eq!(unsafe { ar_crossfade_process(crossfade, outgoing.as_ptr(), std::ptr::null(), 1, output.as_mut_ptr()) }, -999);
+        assert_eq!(ar_crossfade_free(crossfade), 1);
+        assert_eq!(ar_crossfade_free(crossfade), -999);
+        assert_eq!(unsafe { ar_crossfade_new(c"{\"curve\": \"sCurve\"}".as_ptr()) }, 0);
+    }
+}
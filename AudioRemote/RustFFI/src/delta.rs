@@ -0,0 +1,413 @@
+//! Binary delta updates for app bundles.
+//!
+//! A delta file is a zstd stream containing the magic `ARDELTA1`, a little-endian
+//! u32 header length, a JSON [`DeltaHeader`], and then the payload blobs of the
+//! `add`/`patch` entries in manifest order. Files not mentioned in the manifest
+//! are copied unchanged from the installed bundle. Every patched or added file
+//! carries the SHA-256 of its expected result, and patched files also carry the
+//! SHA-256 they must have before patching.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::c_char;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::checksum::to_hex;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAGIC: &[u8; 8] = b"ARDELTA1";
+const COMPRESSION_LEVEL: i32 = 19;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeltaHeader {
+    pub from_version: Option<String>,
+    pub to_version: Option<String>,
+    pub entries: Vec<DeltaEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum DeltaEntry {
+    /// bsdiff patch against the installed file
+    #[serde(rename_all = "camelCase")]
+    Patch { path: String, source_sha256: String, target_sha256: String, mode: u32, size: u64 },
+    /// New file stored in full
+    #[serde(rename_all = "camelCase")]
+    Add { path: String, target_sha256: String, mode: u32, size: u64 },
+    Symlink { path: String, target: String },
+    Dir { path: String, mode: u32 },
+    Mode { path: String, mode: u32 },
+    Delete { path: String },
+}
+
+impl DeltaEntry {
+    fn path(&self) -> &str {
+        match self {
+            Self::Patch { path, .. }
+            | Self::Add { path, .. }
+            | Self::Symlink { path, .. }
+            | Self::Dir { path, .. }
+            | Self::Mode { path, .. }
+            | Self::Delete { path } => path,
+        }
+    }
+
+    fn blob_size(&self) -> u64 {
+        match self {
+            Self::Patch { size, .. } | Self::Add { size, .. } => *size,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Entry {
+    File { mode: u32 },
+    Symlink(PathBuf),
+    Dir { mode: u32 },
+}
+
+/// List everything below `root` by relative path, without following symlinks
+pub(crate) fn walk(root: &Path) -> Result<BTreeMap<PathBuf, Entry>, AudioRemoteError> {
+    fn visit(root: &Path, dir: &Path, out: &mut BTreeMap<PathBuf, Entry>) -> Result<(), AudioRemoteError> {
+        let read = fs::read_dir(dir).map_err(|e| AudioRemoteError::Io(format!("can't list {}: {e}", dir.display())))?;
+        for item in read {
+            let item = item?;
+            let path = item.path();
+            let meta = fs::symlink_metadata(&path).map_err(|e| AudioRemoteError::Io(format!("can't stat {}: {e}", path.display())))?;
+            let rel = path.strip_prefix(root).map_err(|e| e.to_string())?.to_path_buf();
+            let mode = meta.permissions().mode() & 0o7777;
+            if meta.file_type().is_symlink() {
+                let target = fs::read_link(&path)?;
+                out.insert(rel, Entry::Symlink(target));
+            } else if meta.is_dir() {
+                out.insert(rel, Entry::Dir { mode });
+                visit(root, &path, out)?;
+            } else {
+                out.insert(rel, Entry::File { mode });
+            }
+        }
+        Ok(())
+    }
+
+    let mut out = BTreeMap::new();
+    visit(root, root, &mut out)?;
+    Ok(out)
+}
+
+/// Copy a tree preserving symlinks and permissions, skipping relative paths rejected by `keep`
+pub(crate) fn copy_tree(src: &Path, dest: &Path, keep: impl Fn(&Path) -> bool) -> Result<(), AudioRemoteError> {
+    fs::create_dir_all(dest).map_err(|e| AudioRemoteError::Io(format!("can't create {}: {e}", dest.display())))?;
+    for (rel, entry) in walk(src)? {
+        if !keep(&rel) {
+            continue;
+        }
+        let target = dest.join(&rel);
+        match entry {
+            Entry::Dir { mode } => {
+                fs::create_dir_all(&target)?;
+                set_mode(&target, mode)?;
+            }
+            Entry::File { .. } => {
+                fs::copy(src.join(&rel), &target).map_err(|e| AudioRemoteError::Io(format!("can't copy {}: {e}", rel.display())))?;
+            }
+            Entry::Symlink(link) => {
+                std::os::unix::fs::symlink(&link, &target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn set_mode(path: &Path, mode: u32) -> Result<(), AudioRemoteError> {
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|e| AudioRemoteError::Io(format!("can't chmod {}: {e}", path.display())))
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+/// Reject absolute paths and ".." so a hostile delta can't write outside the bundle
+fn safe_relative(path: &str) -> Result<PathBuf, AudioRemoteError> {
+    let p = Path::new(path);
+    if path.is_empty() || !p.components().all(|c| matches!(c, Component::Normal(_))) {
+        return Err(AudioRemoteError::InvalidData(format!("unsafe path in delta: \"{path}\"")));
+    }
+    Ok(p.to_path_buf())
+}
+
+/// Build a delta that turns the `old` bundle into the `new` bundle
+pub fn create(old: &Path, new: &Path, from_version: Option<&str>, to_version: Option<&str>) -> Result<Vec<u8>, AudioRemoteError> {
+    let old_entries = walk(old)?;
+    let new_entries = walk(new)?;
+    let mut entries = Vec::new();
+    let mut blobs = Vec::new();
+    let name = |rel: &Path| rel.to_string_lossy().into_owned();
+
+    for (rel, entry) in &new_entries {
+        let before = old_entries.get(rel);
+        match entry {
+            Entry::Dir { mode } => match before {
+                Some(Entry::Dir { mode: old_mode }) if old_mode == mode => {}
+                Some(Entry::Dir { .. }) => entries.push(DeltaEntry::Mode { path: name(rel), mode: *mode }),
+                _ => entries.push(DeltaEntry::Dir { path: name(rel), mode: *mode }),
+            },
+            Entry::Symlink(target) => {
+                if before != Some(entry) {
+                    entries.push(DeltaEntry::Symlink { path: name(rel), target: target.to_string_lossy().into_owned() });
+                }
+            }
+            Entry::File { mode } => {
+                let data = fs::read(new.join(rel))?;
+                let target_sha256 = sha256_hex(&data);
+                match before {
+                    Some(Entry::File { mode: old_mode }) => {
+                        let old_data = fs::read(old.join(rel))?;
+                        if old_data == data {
+                            if old_mode != mode {
+                                entries.push(DeltaEntry::Mode { path: name(rel), mode: *mode });
+                            }
+                            continue;
+                        }
+                        let mut patch = Vec::new();
+                        bsdiff::diff(&old_data, &data, &mut patch).map_err(|e| e.to_string())?;
+                        entries.push(DeltaEntry::Patch {
+                            path: name(rel),
+                            source_sha256: sha256_hex(&old_data),
+                            target_sha256,
+                            mode: *mode,
+                            size: patch.len() as u64,
+                        });
+                        blobs.push(patch);
+                    }
+                    _ => {
+                        entries.push(DeltaEntry::Add { path: name(rel), target_sha256, mode: *mode, size: data.len() as u64 });
+                        blobs.push(data);
+                    }
+                }
+            }
+        }
+    }
+
+    // Anything that vanished, or changed kind, must be removed before the new entry is written
+    for (rel, entry) in &old_entries {
+        let replaced = match (entry, new_entries.get(rel)) {
+            (_, None) => true,
+            (Entry::Dir { .. }, Some(Entry::Dir { .. })) | (Entry::File { .. }, Some(Entry::File { .. })) => false,
+            (Entry::Symlink(_), Some(Entry::Symlink(_))) => false,
+            _ => true,
+        };
+        let parent_deleted = rel.ancestors().skip(1).any(|a| !a.as_os_str().is_empty() && !new_entries.contains_key(a));
+        if replaced && !parent_deleted {
+            entries.insert(0, DeltaEntry::Delete { path: name(rel) });
+        }
+    }
+
+    let header = DeltaHeader {
+        from_version: from_version.map(str::to_owned),
+        to_version: to_version.map(str::to_owned),
+        entries,
+    };
+    let header_json = serde_json::to_vec(&header).map_err(|e| e.to_string())?;
+
+    let mut raw = Vec::new();
+    raw.extend_from_slice(MAGIC);
+    raw.extend_from_slice(&(header_json.len() as u32).to_le_bytes());
+    raw.extend_from_slice(&header_json);
+    for blob in blobs {
+        raw.extend_from_slice(&blob);
+    }
+    zstd::stream::encode_all(&raw[..], COMPRESSION_LEVEL).map_err(AudioRemoteError::from)
+}
+
+/// Split a delta file into its header and payload section
+pub fn read(delta: &[u8]) -> Result<(DeltaHeader, Vec<u8>), AudioRemoteError> {
+    let raw = zstd::stream::decode_all(delta).map_err(|e| AudioRemoteError::InvalidData(format!("delta is not a valid zstd stream: {e}")))?;
+    if raw.len() < 12 || &raw[..8] != MAGIC {
+        return Err(AudioRemoteError::InvalidData("not an Audio Remote delta file".into()));
+    }
+    let header_len = u32::from_le_bytes(raw[8..12].try_into().unwrap()) as usize;
+    let header_end = 12usize.checked_add(header_len).filter(|&end| end <= raw.len()).ok_or(AudioRemoteError::InvalidData("truncated delta header".into()))?;
+    let header: DeltaHeader =
+        serde_json::from_slice(&raw[12..header_end]).map_err(|e| AudioRemoteError::InvalidData(format!("invalid delta header: {e}")))?;
+    let payload = raw[header_end..].to_vec();
+    let expected: u64 = header.entries.iter().map(DeltaEntry::blob_size).sum();
+    if payload.len() as u64 != expected {
+        return Err(AudioRemoteError::InvalidData(format!("delta payload is {} bytes, manifest expects {expected}", payload.len())));
+    }
+    Ok((header, payload))
+}
+
+/// Apply a delta to the installed bundle, writing the new bundle to `output`.
+/// `output` must not exist yet; it is removed again if anything fails.
+pub fn apply(installed: &Path, delta: &[u8], output: &Path) -> Result<DeltaHeader, AudioRemoteError> {
+    if output.exists() {
+        return Err(AudioRemoteError::InvalidArgument(format!("{} already exists", output.display())));
+    }
+    let result = apply_inner(installed, delta, output);
+    if result.is_err() {
+        let _ = fs::remove_dir_all(output);
+    }
+    result
+}
+
+fn apply_inner(installed: &Path, delta: &[u8], output: &Path) -> Result<DeltaHeader, AudioRemoteError> {
+    let (header, payload) = read(delta)?;
+
+    let mut removed = BTreeSet::new();
+    for entry in &header.entries {
+        let rel = safe_relative(entry.path())?;
+        if matches!(entry, DeltaEntry::Delete { .. }) {
+            removed.insert(rel);
+        }
+    }
+    copy_tree(installed, output, |rel| !rel.ancestors().any(|a| removed.contains(a)))?;
+
+    let mut blobs = payload.as_slice();
+    for entry in &header.entries {
+        let rel = safe_relative(entry.path())?;
+        let target = output.join(&rel);
+        let (blob, rest) = blobs.split_at(entry.blob_size() as usize);
+        blobs = rest;
+
+        match entry {
+            DeltaEntry::Patch { source_sha256, target_sha256, mode, .. } => {
+                let old = fs::read(installed.join(&rel)).map_err(|e| AudioRemoteError::Io(format!("can't read {}: {e}", rel.display())))?;
+                if sha256_hex(&old) != *source_sha256 {
+                    return Err(AudioRemoteError::VerificationFailed(format!("{} doesn't match the version this delta was built for", rel.display())));
+                }
+                let mut new = Vec::new();
+                bsdiff::patch(&old, &mut &blob[..], &mut new).map_err(|e| AudioRemoteError::InvalidData(format!("can't patch {}: {e}", rel.display())))?;
+                write_verified(&target, &new, target_sha256, *mode)?;
+            }
+            DeltaEntry::Add { target_sha256, mode, .. } => write_verified(&target, blob, target_sha256, *mode)?,
+            DeltaEntry::Symlink { target: link, .. } => {
+                let _ = fs::remove_file(&target);
+                std::os::unix::fs::symlink(link, &target)?;
+            }
+            DeltaEntry::Dir { mode, .. } => {
+                fs::create_dir_all(&target)?;
+                set_mode(&target, *mode)?;
+            }
+            DeltaEntry::Mode { mode, .. } => set_mode(&target, *mode)?,
+            DeltaEntry::Delete { .. } => {}
+        }
+    }
+    Ok(header)
+}
+
+fn write_verified(target: &Path, data: &[u8], expected_sha256: &str, mode: u32) -> Result<(), AudioRemoteError> {
+    if sha256_hex(data) != expected_sha256 {
+        return Err(AudioRemoteError::VerificationFailed(format!("checksum mismatch for {}", target.display())));
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = fs::File::create(target).map_err(|e| AudioRemoteError::Io(format!("can't write {}: {e}", target.display())))?;
+    file.write_all(data)?;
+    set_mode(target, mode)
+}
+
+/// Apply a delta update to the installed app bundle, producing the new bundle at `output_path`
+/// Every patched file is checked against its expected SHA-256 before and after patching.
+/// `output_path` must not exist; nothing is left behind on failure.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// All pointers must point to valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn delta_apply(
+    installed_path_ptr: *const c_char,
+    delta_path_ptr: *const c_char,
+    output_path_ptr: *const c_char,
+) -> i32 {
+    guard("delta_apply", -999, || {
+        let result = str_arg(installed_path_ptr, "installed_path").and_then(|installed| {
+            let delta_path = str_arg(delta_path_ptr, "delta_path")?;
+            let output = str_arg(output_path_ptr, "output_path")?;
+            let mut delta = Vec::new();
+            fs::File::open(delta_path)
+                .and_then(|mut f| f.read_to_end(&mut delta))
+                .map_err(|e| AudioRemoteError::Io(format!("can't read {delta_path}: {e}")))?;
+            apply(Path::new(installed), &delta, Path::new(output))
+        });
+        match record(result) {
+            Some(_) => 1,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("audioremote-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    pub(crate) fn make_bundle(root: &Path, binary: &[u8], extra: Option<&str>) {
+        fs::create_dir_all(root.join("Contents/MacOS")).unwrap();
+        fs::create_dir_all(root.join("Contents/Resources")).unwrap();
+        fs::write(root.join("Contents/Info.plist"), "<plist/>").unwrap();
+        fs::write(root.join("Contents/MacOS/AudioRemote"), binary).unwrap();
+        set_mode(&root.join("Contents/MacOS/AudioRemote"), 0o755).unwrap();
+        if let Some(extra) = extra {
+            fs::write(root.join("Contents/Resources").join(extra), extra).unwrap();
+        }
+    }
+
+    fn binary(version: u8) -> Vec<u8> {
+        (0..50_000u32).map(|i| if i % 997 == 0 { version } else { (i % 13) as u8 }).collect()
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let root = temp_dir("delta-roundtrip");
+        let (old, new, out) = (root.join("old.app"), root.join("new.app"), root.join("out.app"));
+        make_bundle(&old, &binary(1), Some("old.txt"));
+        make_bundle(&new, &binary(2), Some("new.txt"));
+        std::os::unix::fs::symlink("Contents/Info.plist", new.join("Info-link")).unwrap();
+
+        let delta = create(&old, &new, Some("2.6.0"), Some("2.7.0")).unwrap();
+        assert!(delta.len() < binary(2).len() / 4, "delta should be much smaller than the binary");
+
+        let header = apply(&old, &delta, &out).unwrap();
+        assert_eq!(header.to_version.as_deref(), Some("2.7.0"));
+        assert_eq!(walk(&out).unwrap(), walk(&new).unwrap());
+        assert_eq!(fs::read(out.join("Contents/MacOS/AudioRemote")).unwrap(), binary(2));
+        assert!(!out.join("Contents/Resources/old.txt").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_delta_rejects_wrong_source() {
+        let root = temp_dir("delta-wrong-source");
+        let (old, new, other, out) = (root.join("old.app"), root.join("new.app"), root.join("other.app"), root.join("out.app"));
+        make_bundle(&old, &binary(1), None);
+        make_bundle(&new, &binary(2), None);
+        make_bundle(&other, &binary(3), None);
+
+        let delta = create(&old, &new, None, None).unwrap();
+        let err = apply(&other, &delta, &out).unwrap_err();
+        assert!(matches!(&err, AudioRemoteError::VerificationFailed(m) if m.contains("doesn't match")), "{err}");
+        assert!(!out.exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_delta_rejects_path_traversal() {
+        assert!(safe_relative("../../etc/passwd").is_err());
+        assert!(safe_relative("/etc/passwd").is_err());
+        assert!(safe_relative("Contents/MacOS/AudioRemote").is_ok());
+        assert!(read(b"garbage").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! What kind of thing an audio device is, guessed from what CoreAudio says
+//! about it, so the UI can pick an icon and the auto-switch rules can say
+//! "headphones" instead of listing every pair.
+//!
+//! The transport settles most cases: AirPlay is AirPlay, HDMI and DisplayPort
+//! are a display's audio, and virtual and aggregate devices are virtual. The
+//! rest go by words in the name and manufacturer: loopback drivers that
+//! report themselves as built in, headphones and speakers by model names, and
+//! "Studio Display" over USB. Bluetooth devices that match nothing stay
+//! "bluetooth" rather than being guessed at.
+
+use std::ffi::c_char;
+
+use serde::{Deserialize, Serialize};
+
+use crate::devices::Transport;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DeviceCategory {
+    #[default]
+    Unknown = 0,
+    Headphones = 1,
+    Speakers = 2,
+    DisplayAudio = 3,
+    Virtual = 4,
+    AirPlay = 5,
+    Bluetooth = 6,
+}
+
+/// Drivers and apps whose devices are virtual, by name or manufacturer
+const VIRTUAL: &[&str] = &[
+    "blackhole",
+    "existential audio",
+    "loopback",
+    "rogue amoeba",
+    "soundflower",
+    "background music",
+    "eqmac",
+    "zoomaudiodevice",
+    "zoom audio",
+    "teams audio",
+    "krisp",
+    "virtual",
+    "aggregate",
+    "multi output",
+];
+
+const DISPLAY: &[&str] = &["display", "ultrafine", "monitor", "tv", "television"];
+
+/// Word prefixes; "airpods" also matches "AirPods Pro"
+const HEADPHONES: &[&str] = &[
+    "headphone",
+    "headset",
+    "earphone",
+    "earbud",
+    "airpods",
+    "buds",
+    "beats solo",
+    "beats studio",
+    "beats fit",
+    "beats flex",
+    "powerbeats",
+    "wh 1000",
+    "wf 1000",
+    "quietcomfort",
+    "momentum",
+    "jabra",
+];
+
+/// Whole words only: "Earth" isn't a headphone
+const HEADPHONE_WORDS: &[&str] = &["ear", "qc", "external headphones"];
+
+const SPEAKERS: &[&str] = &[
+    "speaker",
+    "homepod",
+    "soundbar",
+    "sonos",
+    "soundlink",
+    "boom",
+    "megaboom",
+    "jbl flip",
+    "jbl charge",
+    "jbl go",
+    "jbl clip",
+    "monitors",
+];
+
+/// Lowercased words, space-separated with a space at each end
+fn words(text: &str) -> String {
+    let mut words = String::from(" ");
+    for word in text.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+        words.push_str(&word.to_lowercase());
+        words.push(' ');
+    }
+    words
+}
+
+/// Whether any of `prefixes` starts a word (or run of words) in `words`
+fn any_prefix(words: &str, prefixes: &[&str]) -> bool {
+    prefixes.iter().any(|prefix| words.contains(&format!(" {prefix}")))
+}
+
+/// Whether any of `phrases` is a whole word (or run of words) in `words`
+fn any_word(words: &str, phrases: &[&str]) -> bool {
+    phrases.iter().any(|phrase| words.contains(&format!(" {phrase} ")))
+}
+
+/// Guess the category of a device from its name, transport and manufacturer
+pub fn classify(name: &str, transport: Transport, manufacturer: Option<&str>) -> DeviceCategory {
+    let name = words(name);
+    let both = format!("{name}{}", words(manufacturer.unwrap_or_default()));
+    match transport {
+        Transport::Virtual | Transport::Aggregate => return DeviceCategory::Virtual,
+        Transport::AirPlay => return DeviceCategory::AirPlay,
+        Transport::Hdmi | Transport::DisplayPort => return DeviceCategory::DisplayAudio,
+        _ => {}
+    }
+    if any_prefix(&both, VIRTUAL) {
+        return DeviceCategory::Virtual;
+    }
+    // Before the speakers: "Studio Display Speakers" are a display's
+    if any_word(&name, DISPLAY) {
+        return DeviceCategory::DisplayAudio;
+    }
+    if any_prefix(&name, HEADPHONES) || any_word(&name, HEADPHONE_WORDS) {
+        return DeviceCategory::Headphones;
+    }
+    if any_prefix(&name, SPEAKERS) {
+        return DeviceCategory::Speakers;
+    }
+    match transport {
+        Transport::Bluetooth => DeviceCategory::Bluetooth,
+        _ => DeviceCategory::Unknown,
+    }
+}
+
+/// Guess what kind of device this is. `transport` (nullable) as in ar_devices_event;
+/// `manufacturer` (nullable) is kAudioObjectPropertyManufacturer.
+/// Returns: an ArDeviceCategory, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `name` must point to a valid NUL-terminated string, and `transport` and `manufacturer` be null
+/// or point to one.
+#[no_mangle]
+pub unsafe extern "C" fn ar_device_classify(name: *const c_char, transport: *const c_char, manufacturer: *const c_char) -> i32 {
+    guard("ar_device_classify", -999, || {
+        let transport = match transport.is_null() {
+            true => Ok(Transport::Unknown),
+            false => str_arg(transport, "transport").and_then(|transport| {
+                serde_json::from_value(serde_json::Value::from(transport)).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid transport: {e}")))
+            }),
+        };
+        let manufacturer = match manufacturer.is_null() {
+            true => Ok(None),
+            false => str_arg(manufacturer, "manufacturer").map(Some),
+        };
+        let result = str_arg(name, "name").and_then(|name| Ok(classify(name, transport?, manufacturer?)));
+        record(result).map_or(-999, |category| category as i32)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        use DeviceCategory::*;
+        let cases = [
+            ("AirPods Pro", Transport::Bluetooth, Some("Apple Inc."), Headphones),
+            ("WH-1000XM5", Transport::Bluetooth, None, Headphones),
+            ("Bose QC Earbuds II", Transport::Bluetooth, None, Headphones),
+            ("Galaxy Buds2 Pro", Transport::Bluetooth, None, Headphones),
+            ("External Headphones", Transport::BuiltIn, Some("Apple Inc."), Headphones),
+            ("MacBook Pro Speakers", Transport::BuiltIn, Some("Apple Inc."), Speakers),
+            ("Living Room HomePod", Transport::AirPlay, None, AirPlay),
+            ("JBL Flip 6", Transport::Bluetooth, None, Speakers),
+            ("Studio Display Speakers", Transport::Usb, Some("Apple Inc."), DisplayAudio),
+            ("LG HDR 4K", Transport::DisplayPort, None, DisplayAudio),
+            ("Samsung TV", Transport::Unknown, None, DisplayAudio),
+            ("BlackHole 2ch", Transport::BuiltIn, Some("Existential Audio Inc."), Virtual),
+            ("Streaming", Transport::Unknown, Some("Rogue Amoeba Software, Inc."), Virtual),
+            ("Multi-Output Device", Transport::Aggregate, None, Virtual),
+            ("Pixel 8", Transport::Bluetooth, None, Bluetooth),
+            ("Earth Sounds Mixer", Transport::Usb, None, Unknown),
+            ("Scarlett 2i2 USB", Transport::Usb, Some("Focusrite"), Unknown),
+            ("MacBook Pro Microphone", Transport::BuiltIn, Some("Apple Inc."), Unknown),
+        ];
+        for (name, transport, manufacturer, category) in cases {
+            assert_eq!(classify(name, transport, manufacturer), category, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_ffi() {
+        let airpods = unsafe { ar_device_classify(c"AirPods Max".as_ptr(), c"bluetooth".as_ptr(), std::ptr::null()) };
+        assert_eq!(airpods, DeviceCategory::Headphones as i32);
+        let unknown = unsafe { ar_device_classify(c"USB Audio CODEC".as_ptr(), std::ptr::null(), std::ptr::null()) };
+        assert_eq!(unknown, DeviceCategory::Unknown as i32);
+        assert_eq!(unsafe { ar_device_classify(std::ptr::null(), std::ptr::null(), std::ptr::null()) }, -999);
+    }
+}
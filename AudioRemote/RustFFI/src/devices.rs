@@ -0,0 +1,861 @@
+//! The Mac's audio devices as remotes see them. Swift forwards CoreAudio's
+//! raw notifications (a device appeared, one of its properties changed, it
+//! went away, the default output moved) and this keeps the canonical record
+//! of each, so every transport answers from the same state.
+//!
+//! Devices are keyed by their CoreAudio UID, which survives relaunches and
+//! reconnects, unlike the AudioObjectID a notification names them by; the
+//! object IDs are only mapped to UIDs while a device is connected. A device
+//! that goes away stays on record as disconnected, and comes back under the
+//! same ID with whatever settings it reports then.
+//!
+//! Every change bumps the generation and goes to the change callback; the
+//! `devices` and `volume` topics (see `events`) are republished when what a
+//! remote sees of them changes, so Swift no longer publishes those itself.
+//!
+//! Volume and mute are remembered per device as they're changed, so headphones
+//! that reconnect can be put back where they were instead of at whatever the
+//! Mac gives a new device; with the balance saved per device they're what
+//! `ar_device_restore_state` returns. What a device reports as it's added isn't
+//! remembered, as that is the volume being replaced. A dragged slider changes
+//! them many times a second, so they go to the store once they've stayed put
+//! for a second, when a device goes away, and at shutdown.
+//!
+//! Nicknames and icons the user gives devices are kept in the store too, and
+//! go into the records and the names remotes show, so "BlackHole 2ch" is
+//! "Streaming Mix" everywhere.
+//!
+//! Bluetooth devices also carry their codec, battery and signal strength, as
+//! Swift reads them (see `bluetooth`); remotes get the battery level with the
+//! device list.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void, CString};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+
+use crate::balance::{self, BalanceSettings};
+use crate::bluetooth::{self, BluetoothInfo};
+use crate::device_class::{self, DeviceCategory};
+use crate::error::string_result;
+use crate::events::{Topic, HUB};
+use crate::remote::{OutputDevice, VolumeState};
+use crate::runtime::{self, SendPtr};
+use crate::store::{self, Store};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const STATES_KEY: &str = "devices.states";
+const ALIASES_KEY: &str = "devices.aliases";
+/// The longest nickname or icon name, in bytes
+const MAX_ALIAS_LEN: usize = 64;
+/// How long volume and mute must stay put before they're written
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// (change_json, ctx): {"generation", "change", "device"}; the JSON is only valid during the call
+pub type DevicesCallback = extern "C" fn(change_json: *const c_char, ctx: *mut c_void);
+
+/// kAudioDevicePropertyTransportType, by name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Transport {
+    BuiltIn,
+    Usb,
+    Bluetooth,
+    AirPlay,
+    Hdmi,
+    DisplayPort,
+    Thunderbolt,
+    Aggregate,
+    Virtual,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+/// A device as CoreAudio reports it when it appears
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RawDevice {
+    pub object_id: u32,
+    pub uid: String,
+    pub name: String,
+    #[serde(default)]
+    pub manufacturer: Option<String>,
+    #[serde(default)]
+    pub transport: Transport,
+    #[serde(default)]
+    pub output_channels: u32,
+    #[serde(default)]
+    pub input_channels: u32,
+    #[serde(default)]
+    pub sample_rate: Option<f64>,
+    /// None for a device without a volume control
+    #[serde(default)]
+    pub volume: Option<f32>,
+    #[serde(default)]
+    pub muted: Option<bool>,
+}
+
+/// The properties that changed; the rest are left as they were
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DeviceChange {
+    pub object_id: u32,
+    pub name: Option<String>,
+    pub output_channels: Option<u32>,
+    pub input_channels: Option<u32>,
+    pub sample_rate: Option<f64>,
+    pub volume: Option<f32>,
+    pub muted: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ObjectRef {
+    pub object_id: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "event", rename_all = "camelCase")]
+pub enum DeviceEvent {
+    Added(RawDevice),
+    Changed(DeviceChange),
+    Removed(ObjectRef),
+    DefaultOutput(ObjectRef),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceRecord {
+    /// The CoreAudio UID
+    pub id: String,
+    pub name: String,
+    pub manufacturer: Option<String>,
+    pub transport: Transport,
+    /// Guessed from the name, manufacturer and transport
+    pub category: DeviceCategory,
+    pub output_channels: u32,
+    pub input_channels: u32,
+    pub sample_rate: Option<f64>,
+    pub volume: Option<f32>,
+    pub muted: Option<bool>,
+    /// The AudioObjectID while connected
+    pub object_id: Option<u32>,
+    pub connected: bool,
+    pub is_default: bool,
+    /// The user's name for it, if they gave one
+    pub nickname: Option<String>,
+    /// An icon hint from the user, e.g. "headphones"
+    pub icon: Option<String>,
+    /// While connected, once Swift has read it
+    pub bluetooth: Option<BluetoothInfo>,
+}
+
+impl DeviceRecord {
+    /// The nickname, or the name if it hasn't one
+    pub fn display_name(&self) -> &str {
+        self.nickname.as_deref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeKind {
+    Added,
+    Changed,
+    Removed,
+    DefaultOutput,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Change {
+    pub generation: u64,
+    pub change: ChangeKind,
+    pub device: DeviceRecord,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Snapshot {
+    pub generation: u64,
+    pub default_output: Option<String>,
+    /// By display name
+    pub devices: Vec<DeviceRecord>,
+}
+
+/// A device's last volume and mute, as remembered
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedState {
+    pub volume: Option<f32>,
+    pub muted: Option<bool>,
+}
+
+/// What to put a reconnecting device back to
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestoredState {
+    #[serde(flatten)]
+    pub saved: SavedState,
+    #[serde(flatten)]
+    pub balance: BalanceSettings,
+}
+
+/// The user's nickname and icon for a device
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Alias {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nickname: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon: Option<String>,
+}
+
+impl Alias {
+    /// Trimmed, with blank fields dropped
+    fn normalized(self) -> Result<Self, AudioRemoteError> {
+        let field = |value: Option<String>, name: &str| match value.as_deref().map(str::trim) {
+            Some(value) if value.len() > MAX_ALIAS_LEN => Err(AudioRemoteError::InvalidArgument(format!("{name} is over {MAX_ALIAS_LEN} bytes"))),
+            Some(value) if !value.is_empty() => Ok(Some(value.to_owned())),
+            _ => Ok(None),
+        };
+        Ok(Self { nickname: field(self.nickname, "nickname")?, icon: field(self.icon, "icon")? })
+    }
+}
+
+fn check_volume(volume: Option<f32>) -> Result<(), AudioRemoteError> {
+    match volume {
+        Some(volume) if !(0.0..=1.0).contains(&volume) => Err(AudioRemoteError::InvalidArgument(format!("volume {volume} is outside 0–1"))),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct DeviceRegistry {
+    devices: BTreeMap<String, DeviceRecord>,
+    /// Connected devices' object IDs
+    objects: BTreeMap<u32, String>,
+    generation: u64,
+    /// What the topics were last published as
+    published_outputs: Vec<OutputDevice>,
+    published_volume: Option<VolumeState>,
+    /// By UID, including devices not on record
+    aliases: BTreeMap<String, Alias>,
+}
+
+impl DeviceRegistry {
+    pub const fn new() -> Self {
+        Self { devices: BTreeMap::new(), objects: BTreeMap::new(), generation: 0, published_outputs: Vec::new(), published_volume: None, aliases: BTreeMap::new() }
+    }
+
+    fn uid(&self, object_id: u32) -> Result<String, AudioRemoteError> {
+        self.objects.get(&object_id).cloned().ok_or(AudioRemoteError::NotFound(format!("no connected device {object_id}")))
+    }
+
+    fn changed(&mut self, change: ChangeKind, uid: &str) -> Change {
+        self.generation += 1;
+        Change { generation: self.generation, change, device: self.devices[uid].clone() }
+    }
+
+    /// Take a notification; returns the records it changed, none if it changed nothing
+    pub fn apply(&mut self, event: DeviceEvent) -> Result<Vec<Change>, AudioRemoteError> {
+        match event {
+            DeviceEvent::Added(raw) => {
+                if raw.uid.is_empty() {
+                    return Err(AudioRemoteError::InvalidArgument("uid must not be empty".into()));
+                }
+                check_volume(raw.volume)?;
+                // Object IDs can be reused: whatever had this one is gone
+                let mut changes = match self.objects.get(&raw.object_id) {
+                    Some(uid) if *uid != raw.uid => self.apply(DeviceEvent::Removed(ObjectRef { object_id: raw.object_id }))?,
+                    _ => vec![],
+                };
+                let is_default = self.devices.get(&raw.uid).is_some_and(|device| device.is_default);
+                let alias = self.aliases.get(&raw.uid).cloned().unwrap_or_default();
+                let record = DeviceRecord {
+                    id: raw.uid.clone(),
+                    category: device_class::classify(&raw.name, raw.transport, raw.manufacturer.as_deref()),
+                    name: raw.name,
+                    manufacturer: raw.manufacturer,
+                    transport: raw.transport,
+                    output_channels: raw.output_channels,
+                    input_channels: raw.input_channels,
+                    sample_rate: raw.sample_rate,
+                    volume: raw.volume,
+                    muted: raw.muted,
+                    object_id: Some(raw.object_id),
+                    connected: true,
+                    is_default,
+                    nickname: alias.nickname,
+                    icon: alias.icon,
+                    bluetooth: self.devices.get(&raw.uid).and_then(|device| device.bluetooth.clone()),
+                };
+                if self.devices.get(&raw.uid) == Some(&record) {
+                    return Ok(changes);
+                }
+                if let Some(old) = self.devices.get(&raw.uid).and_then(|device| device.object_id) {
+                    self.objects.remove(&old);
+                }
+                self.objects.insert(raw.object_id, raw.uid.clone());
+                self.devices.insert(raw.uid.clone(), record);
+                changes.push(self.changed(ChangeKind::Added, &raw.uid));
+                Ok(changes)
+            }
+            DeviceEvent::Changed(change) => {
+                check_volume(change.volume)?;
+                let uid = self.uid(change.object_id)?;
+                let device = self.devices.get_mut(&uid).expect("mapped devices are on record");
+                let before = device.clone();
+                if let Some(name) = change.name {
+                    device.category = device_class::classify(&name, device.transport, device.manufacturer.as_deref());
+                    device.name = name;
+                }
+                device.output_channels = change.output_channels.unwrap_or(device.output_channels);
+                device.input_channels = change.input_channels.unwrap_or(device.input_channels);
+                device.sample_rate = change.sample_rate.or(device.sample_rate);
+                device.volume = change.volume.or(device.volume);
+                device.muted = change.muted.or(device.muted);
+                match *device == before {
+                    true => Ok(vec![]),
+                    false => Ok(vec![self.changed(ChangeKind::Changed, &uid)]),
+                }
+            }
+            DeviceEvent::Removed(removed) => {
+                let uid = self.uid(removed.object_id)?;
+                self.objects.remove(&removed.object_id);
+                let device = self.devices.get_mut(&uid).expect("mapped devices are on record");
+                (device.connected, device.object_id, device.is_default, device.bluetooth) = (false, None, false, None);
+                Ok(vec![self.changed(ChangeKind::Removed, &uid)])
+            }
+            DeviceEvent::DefaultOutput(default) => {
+                let uid = self.uid(default.object_id)?;
+                if self.devices[&uid].is_default {
+                    return Ok(vec![]);
+                }
+                let mut changes = vec![];
+                let previous: Vec<String> = self.devices.values().filter(|device| device.is_default).map(|device| device.id.clone()).collect();
+                for previous in previous {
+                    self.devices.get_mut(&previous).expect("listed").is_default = false;
+                    changes.push(self.changed(ChangeKind::DefaultOutput, &previous));
+                }
+                self.devices.get_mut(&uid).expect("mapped devices are on record").is_default = true;
+                changes.push(self.changed(ChangeKind::DefaultOutput, &uid));
+                Ok(changes)
+            }
+        }
+    }
+
+    /// Give `uid` a nickname and icon, or take them away with the default; returns the change to its record if it's on record
+    pub fn set_alias(&mut self, uid: &str, alias: Alias) -> Result<Option<Change>, AudioRemoteError> {
+        if uid.is_empty() {
+            return Err(AudioRemoteError::InvalidArgument("uid must not be empty".into()));
+        }
+        let alias = alias.normalized()?;
+        match alias == Alias::default() {
+            true => self.aliases.remove(uid),
+            false => self.aliases.insert(uid.to_owned(), alias.clone()),
+        };
+        let Some(device) = self.devices.get_mut(uid) else {
+            return Ok(None);
+        };
+        if (&device.nickname, &device.icon) == (&alias.nickname, &alias.icon) {
+            return Ok(None);
+        }
+        (device.nickname, device.icon) = (alias.nickname, alias.icon);
+        Ok(Some(self.changed(ChangeKind::Changed, uid)))
+    }
+
+    /// Set what's known of a connected Bluetooth device, None to forget it; returns the change if there is one
+    pub fn set_bluetooth(&mut self, uid: &str, info: Option<BluetoothInfo>) -> Result<Option<Change>, AudioRemoteError> {
+        let device = self.devices.get_mut(uid).filter(|device| device.connected).ok_or(AudioRemoteError::NotFound(format!("no connected device {uid}")))?;
+        if device.bluetooth == info {
+            return Ok(None);
+        }
+        device.bluetooth = info;
+        Ok(Some(self.changed(ChangeKind::Changed, uid)))
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut devices: Vec<DeviceRecord> = self.devices.values().cloned().collect();
+        devices.sort_by(|a, b| (a.display_name(), &a.id).cmp(&(b.display_name(), &b.id)));
+        let default_output = devices.iter().find(|device| device.is_default).map(|device| device.id.clone());
+        Snapshot { generation: self.generation, default_output, devices }
+    }
+
+    /// The `devices` and `volume` topics, each if it differs from what was last published
+    pub fn topics(&mut self) -> (Option<Vec<OutputDevice>>, Option<VolumeState>) {
+        let devices = self.snapshot().devices;
+        let outputs: Vec<OutputDevice> = devices
+            .iter()
+            .filter(|device| device.connected && device.output_channels > 0)
+            .map(|device| OutputDevice {
+                id: device.id.clone(),
+                name: device.display_name().to_owned(),
+                is_current: device.is_default,
+                battery_percent: device.bluetooth.as_ref().and_then(BluetoothInfo::battery_percent),
+            })
+            .collect();
+        let volume = devices
+            .iter()
+            .find(|device| device.is_default)
+            .and_then(|device| Some(VolumeState { volume: device.volume?, muted: device.muted.unwrap_or(false) }));
+        let outputs = (outputs != self.published_outputs).then(|| {
+            self.published_outputs = outputs.clone();
+            outputs
+        });
+        let volume = match volume {
+            Some(volume) if Some(&volume) != self.published_volume.as_ref() => {
+                self.published_volume = Some(volume.clone());
+                Some(volume)
+            }
+            _ => None,
+        };
+        (outputs, volume)
+    }
+}
+
+static REGISTRY: Mutex<Option<DeviceRegistry>> = Mutex::new(None);
+static CALLBACK: Mutex<Option<(DevicesCallback, SendPtr)>> = Mutex::new(None);
+/// The remembered states, with the store directory they belong to, whether some aren't written
+/// yet, and the timer that will write them
+struct States {
+    directory: PathBuf,
+    states: BTreeMap<String, SavedState>,
+    dirty: bool,
+    save: Option<AbortHandle>,
+}
+
+static STATES: Mutex<Option<States>> = Mutex::new(None);
+
+impl States {
+    fn write(&mut self) -> Result<(), AudioRemoteError> {
+        if let Some(save) = self.save.take() {
+            save.abort();
+        }
+        if self.dirty {
+            Store::open(&self.directory).set(STATES_KEY, &self.states)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Run `body` on the states, read from the store if they haven't been or it has moved
+fn with_states<T>(body: impl FnOnce(&mut States) -> T) -> T {
+    let mut cached = STATES.lock().unwrap_or_else(|e| e.into_inner());
+    let directory = store::directory();
+    if !matches!(&*cached, Some(states) if states.directory == directory) {
+        if let Some(Err(e)) = cached.as_mut().map(States::write) {
+            log::warn!("can't save device states: {e}");
+        }
+        let states = Store::open(&directory).get(STATES_KEY).unwrap_or_default();
+        *cached = Some(States { directory, states, dirty: false, save: None });
+    }
+    body(cached.as_mut().expect("the states were just loaded"))
+}
+
+/// Note a device's volume and mute if they aren't what's remembered already; they're written
+/// SAVE_DELAY after the last change
+fn remember(device: &DeviceRecord) -> Result<(), AudioRemoteError> {
+    let state = SavedState { volume: device.volume, muted: device.muted };
+    if state == SavedState::default() {
+        return Ok(());
+    }
+    with_states(|states| {
+        if states.states.get(&device.id) == Some(&state) {
+            return Ok(());
+        }
+        states.states.insert(device.id.clone(), state);
+        states.dirty = true;
+        if let Some(save) = states.save.take() {
+            save.abort();
+        }
+        let task = runtime::timers()?.spawn(async {
+            tokio::time::sleep(SAVE_DELAY).await;
+            save();
+        });
+        states.save = Some(task.abort_handle());
+        Ok(())
+    })
+}
+
+/// Write the volume and mute changes not saved yet; done when a device goes away and at shutdown
+pub fn save() {
+    let mut states = STATES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(Err(e)) = states.as_mut().map(States::write) {
+        log::warn!("can't save device states: {e}");
+    }
+}
+
+/// The volume and mute last set on `uid`, and its balance
+pub fn restore_state(uid: &str) -> RestoredState {
+    let saved = with_states(|states| states.states.get(uid).cloned().unwrap_or_default());
+    RestoredState { saved, balance: balance::load(uid) }
+}
+
+/// Run `body` on the registry, set up with the stored aliases if it hasn't been
+fn with_registry<T>(body: impl FnOnce(&mut DeviceRegistry) -> T) -> T {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    let registry = registry.get_or_insert_with(|| DeviceRegistry { aliases: store::global().get(ALIASES_KEY).unwrap_or_default(), ..DeviceRegistry::new() });
+    body(registry)
+}
+
+/// Apply a notification and pass on what it changed
+pub fn apply(event: DeviceEvent) -> Result<usize, AudioRemoteError> {
+    let (changes, topics) = with_registry(|registry| {
+        let changes = registry.apply(event)?;
+        Ok::<_, AudioRemoteError>((changes, registry.topics()))
+    })?;
+    announce(changes, topics)
+}
+
+/// Give a device a nickname and icon, or take them away with the default, and save them
+pub fn set_alias(uid: &str, alias: Alias) -> Result<usize, AudioRemoteError> {
+    let (changes, topics) = with_registry(|registry| {
+        let change = registry.set_alias(uid, alias)?;
+        store::global().set(ALIASES_KEY, &registry.aliases)?;
+        Ok::<_, AudioRemoteError>((change.into_iter().collect(), registry.topics()))
+    })?;
+    announce(changes, topics)
+}
+
+/// Set a connected device's Bluetooth properties, as `bluetooth::parse` reads them, or forget them with None
+pub fn set_bluetooth(uid: &str, info: Option<BluetoothInfo>) -> Result<usize, AudioRemoteError> {
+    let (changes, topics) = with_registry(|registry| {
+        let change = registry.set_bluetooth(uid, info)?;
+        Ok::<_, AudioRemoteError>((change.into_iter().collect(), registry.topics()))
+    })?;
+    announce(changes, topics)
+}
+
+/// Publish the topics that changed and pass each change on
+fn announce(changes: Vec<Change>, (outputs, volume): (Option<Vec<OutputDevice>>, Option<VolumeState>)) -> Result<usize, AudioRemoteError> {
+    if let Some(outputs) = outputs {
+        HUB.publish(Topic::Devices, serde_json::to_value(outputs).unwrap_or_default());
+    }
+    if let Some(volume) = volume {
+        HUB.publish(Topic::Volume, serde_json::to_value(volume).unwrap_or_default());
+    }
+    for change in changes.iter().filter(|change| change.change == ChangeKind::Changed) {
+        if let Err(e) = remember(&change.device) {
+            log::warn!("can't save the state of {}: {e}", change.device.id);
+        }
+    }
+    if changes.iter().any(|change| change.change == ChangeKind::Removed) {
+        save();
+    }
+    if let Some((callback, ctx)) = *CALLBACK.lock().unwrap_or_else(|e| e.into_inner()) {
+        for change in &changes {
+            let json = CString::new(serde_json::to_string(change).unwrap_or_default()).unwrap_or_default();
+            runtime::deliver(move || callback(json.as_ptr(), ctx.get()));
+        }
+    }
+    if !changes.is_empty() {
+        crate::autoswitch::evaluate();
+    }
+    Ok(changes.len())
+}
+
+pub fn snapshot() -> Snapshot {
+    with_registry(|registry| registry.snapshot())
+}
+
+/// Pass on a CoreAudio notification. `event_json` is one of:
+/// {"event": "added", "objectId", "uid", "name", "manufacturer", "transport" ("builtIn" | "usb" |
+/// "bluetooth" | "airPlay" | "hdmi" | "displayPort" | "thunderbolt" | "aggregate" | "virtual"),
+/// "outputChannels", "inputChannels", "sampleRate", "volume" (0–1, null without a control), "muted"};
+/// {"event": "changed", "objectId", and any of "name", "outputChannels", "inputChannels",
+/// "sampleRate", "volume", "muted"}; {"event": "removed", "objectId"}; {"event": "defaultOutput", "objectId"}
+/// Returns: the number of records changed (0 if none), -999 on error (see last_error_message)
+///
+/// # Safety
+/// `event_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_devices_event(event_json: *const c_char) -> i32 {
+    guard("ar_devices_event", -999, || {
+        let event = str_arg(event_json, "event").and_then(|json| {
+            serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid device event: {e}")))
+        });
+        record(event.and_then(apply)).map_or(-999, |changes| changes as i32)
+    })
+}
+
+/// Every device on record, connected or not
+/// Returns: JSON {"generation", "defaultOutput" (an id or null), "devices": [{"id" (the UID), "name",
+/// "manufacturer", "transport", "category" (an ArDeviceCategory's name: "headphones", "speakers",
+/// "displayAudio", "virtual", "airPlay", "bluetooth" or "unknown"), "outputChannels",
+/// "inputChannels", "sampleRate", "volume", "muted", "objectId" (null when disconnected),
+/// "connected", "isDefault", "nickname", "icon" (each null if not set), "bluetooth" (as in
+/// ar_bluetooth_parse; null if not set)}],
+/// by nickname or name} (free with rust_string_free), NULL on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_devices_snapshot() -> *mut c_char {
+    guard("ar_devices_snapshot", std::ptr::null_mut(), || {
+        string_result(serde_json::to_string(&snapshot()).map_err(|e| AudioRemoteError::Other(e.to_string())))
+    })
+}
+
+/// Give a device a nickname and icon hint, kept in the store and shown by every remote instead of
+/// its name, whether or not it's connected. `alias_json` (nullable): {"nickname", "icon" (e.g.
+/// "headphones"), each up to 64 bytes}; a missing or blank field, or NULL, takes it away.
+/// Returns: the number of records changed (0 or 1), -999 on error (see last_error_message)
+///
+/// # Safety
+/// `uid` must point to a valid NUL-terminated string, and `alias_json` be null or point to one.
+#[no_mangle]
+pub unsafe extern "C" fn ar_devices_set_alias(uid: *const c_char, alias_json: *const c_char) -> i32 {
+    guard("ar_devices_set_alias", -999, || {
+        let alias = match alias_json.is_null() {
+            true => Ok(Alias::default()),
+            false => str_arg(alias_json, "alias").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid alias: {e}")))
+            }),
+        };
+        let result = str_arg(uid, "uid").and_then(|uid| set_alias(uid, alias?));
+        record(result).map_or(-999, |changes| changes as i32)
+    })
+}
+
+/// Hear every change to a record: JSON {"generation", "change" ("added" | "changed" | "removed" |
+/// "defaultOutput"), "device" (as in ar_devices_snapshot)}, on the callback queue if one is set.
+/// NULL stops them.
+/// Returns: 1
+///
+/// # Safety
+/// `callback` must stay callable, and `ctx` valid from any thread, until it is replaced.
+#[no_mangle]
+pub unsafe extern "C" fn ar_devices_set_callback(callback: Option<DevicesCallback>, ctx: *mut c_void) -> i32 {
+    guard("ar_devices_set_callback", -999, || {
+        *CALLBACK.lock().unwrap_or_else(|e| e.into_inner()) = callback.map(|callback| (callback, SendPtr(ctx)));
+        1
+    })
+}
+
+/// Give a connected device the Bluetooth properties Swift read for it, as for ar_bluetooth_parse,
+/// e.g. as it connects and when its battery changes; NULL forgets them. They're left out of the
+/// record once it disconnects.
+/// Returns: the number of records changed (0 or 1), -999 on error (see last_error_message)
+///
+/// # Safety
+/// `uid` must point to a valid NUL-terminated string, and `properties_json` be null or point to one.
+#[no_mangle]
+pub unsafe extern "C" fn ar_devices_set_bluetooth(uid: *const c_char, properties_json: *const c_char) -> i32 {
+    guard("ar_devices_set_bluetooth", -999, || {
+        let info = match properties_json.is_null() {
+            true => Ok(None),
+            false => bluetooth::properties_arg(properties_json).map(|properties| Some(bluetooth::parse(&properties))),
+        };
+        let result = str_arg(uid, "uid").and_then(|uid| set_bluetooth(uid, info?));
+        record(result).map_or(-999, |changes| changes as i32)
+    })
+}
+
+/// What to put a reconnecting device back to, e.g. as ar_devices_event adds it
+/// Returns: JSON {"volume" (0–1), "muted" (each null if never set), "balance", "trimsDb" (as in
+/// ar_balance_load)} (free with rust_string_free), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `uid` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_device_restore_state(uid: *const c_char) -> *mut c_char {
+    guard("ar_device_restore_state", std::ptr::null_mut(), || {
+        let result = str_arg(uid, "uid")
+            .and_then(|uid| serde_json::to_string(&restore_state(uid)).map_err(|e| AudioRemoteError::Other(e.to_string())));
+        string_result(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+    use crate::store::tests::with_temp_store;
+
+    fn added(object_id: u32, uid: &str, name: &str) -> DeviceEvent {
+        DeviceEvent::Added(RawDevice {
+            object_id,
+            uid: uid.into(),
+            name: name.into(),
+            manufacturer: None,
+            transport: Transport::Usb,
+            output_channels: 2,
+            input_channels: 0,
+            sample_rate: Some(48_000.0),
+            volume: Some(0.5),
+            muted: Some(false),
+        })
+    }
+
+    #[test]
+    fn test_records_follow_notifications() {
+        let mut registry = DeviceRegistry::new();
+        assert_eq!(registry.apply(added(41, "usb-dac", "DAC")).unwrap().len(), 1);
+        assert_eq!(registry.apply(added(42, "BuiltInSpeakerDevice", "MacBook Speakers")).unwrap().len(), 1);
+        assert!(registry.apply(added(41, "usb-dac", "DAC")).unwrap().is_empty());
+
+        let changes = registry.apply(DeviceEvent::DefaultOutput(ObjectRef { object_id: 41 })).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert!(changes[0].device.is_default && changes[0].generation == 3);
+        let change = DeviceChange { object_id: 41, volume: Some(0.75), ..DeviceChange::default() };
+        assert_eq!(registry.apply(DeviceEvent::Changed(change.clone())).unwrap()[0].device.volume, Some(0.75));
+        assert!(registry.apply(DeviceEvent::Changed(change)).unwrap().is_empty());
+
+        // Moving the default changes both devices
+        let changes = registry.apply(DeviceEvent::DefaultOutput(ObjectRef { object_id: 42 })).unwrap();
+        assert_eq!(changes.iter().map(|change| (change.device.id.as_str(), change.device.is_default)).collect::<Vec<_>>(), [
+            ("usb-dac", false),
+            ("BuiltInSpeakerDevice", true)
+        ]);
+
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.default_output.as_deref(), Some("BuiltInSpeakerDevice"));
+        assert_eq!(snapshot.devices.iter().map(|device| device.name.as_str()).collect::<Vec<_>>(), ["DAC", "MacBook Speakers"]);
+        assert!(registry.apply(DeviceEvent::Changed(DeviceChange { object_id: 99, ..DeviceChange::default() })).is_err());
+        assert!(registry.apply(DeviceEvent::Changed(DeviceChange { object_id: 41, volume: Some(2.0), ..DeviceChange::default() })).is_err());
+    }
+
+    #[test]
+    fn test_ids_survive_reconnects() {
+        let mut registry = DeviceRegistry::new();
+        registry.apply(added(41, "usb-dac", "DAC")).unwrap();
+        registry.apply(DeviceEvent::DefaultOutput(ObjectRef { object_id: 41 })).unwrap();
+        let removed = registry.apply(DeviceEvent::Removed(ObjectRef { object_id: 41 })).unwrap();
+        assert!(!removed[0].device.connected && removed[0].device.object_id.is_none() && !removed[0].device.is_default);
+        assert_eq!(registry.snapshot().default_output, None);
+
+        // Back under a new object ID, as the same device
+        registry.apply(added(57, "usb-dac", "DAC")).unwrap();
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.devices.len(), 1);
+        assert_eq!((snapshot.devices[0].object_id, snapshot.devices[0].connected), (Some(57), true));
+        assert!(registry.apply(DeviceEvent::Removed(ObjectRef { object_id: 41 })).is_err());
+
+        // A reused object ID means the device that had it is gone
+        let changes = registry.apply(added(57, "hdmi-tv", "TV")).unwrap();
+        assert_eq!(changes.iter().map(|change| change.change).collect::<Vec<_>>(), [ChangeKind::Removed, ChangeKind::Added]);
+        assert!(!registry.snapshot().devices.iter().find(|device| device.id == "usb-dac").unwrap().connected);
+    }
+
+    #[test]
+    fn test_topics_only_when_remotes_would_see_a_change() {
+        let mut registry = DeviceRegistry::new();
+        registry.apply(added(41, "usb-dac", "DAC")).unwrap();
+        let (outputs, volume) = registry.topics();
+        assert_eq!(outputs.unwrap(), [OutputDevice { id: "usb-dac".into(), name: "DAC".into(), is_current: false, battery_percent: None }]);
+        assert_eq!(volume, None);
+        registry.apply(DeviceEvent::DefaultOutput(ObjectRef { object_id: 41 })).unwrap();
+        let (outputs, volume) = registry.topics();
+        assert!(outputs.unwrap()[0].is_current);
+        assert_eq!(volume, Some(VolumeState { volume: 0.5, muted: false }));
+
+        // A new sample rate isn't something remotes show
+        registry.apply(DeviceEvent::Changed(DeviceChange { object_id: 41, sample_rate: Some(96_000.0), ..DeviceChange::default() })).unwrap();
+        assert_eq!(registry.topics(), (None, None));
+        registry.apply(DeviceEvent::Changed(DeviceChange { object_id: 41, muted: Some(true), ..DeviceChange::default() })).unwrap();
+        assert_eq!(registry.topics(), (None, Some(VolumeState { volume: 0.5, muted: true })));
+    }
+
+    #[test]
+    fn test_ffi() {
+        // An input-only device, so nothing is published to the other tests' remotes
+        let event = cr#"{"event": "added", "objectId": 7001, "uid": "ffi-test-mic", "name": "Mic", "transport": "somethingNew", "inputChannels": 1}"#;
+        assert_eq!(unsafe { ar_devices_event(event.as_ptr()) }, 1);
+        let snapshot: serde_json::Value = serde_json::from_str(&take_c_string(ar_devices_snapshot()).unwrap()).unwrap();
+        let device = snapshot["devices"].as_array().unwrap().iter().find(|device| device["id"] == "ffi-test-mic").unwrap();
+        assert_eq!((device["transport"].as_str(), device["objectId"].as_u64(), device["volume"].is_null()), (Some("unknown"), Some(7001), true));
+        assert_eq!(unsafe { ar_devices_event(cr#"{"event": "changed", "objectId": 7001, "name": "Mic"}"#.as_ptr()) }, 0);
+        assert_eq!(unsafe { ar_devices_event(cr#"{"event": "removed", "objectId": 7001}"#.as_ptr()) }, 1);
+        assert_eq!(unsafe { ar_devices_event(cr#"{"event": "removed", "objectId": 7001}"#.as_ptr()) }, -999);
+        assert_eq!(unsafe { ar_devices_event(cr#"{"event": "renamed"}"#.as_ptr()) }, -999);
+        assert_eq!(unsafe { ar_devices_set_callback(None, std::ptr::null_mut()) }, 1);
+    }
+
+    #[test]
+    fn test_remembers_volume_per_device() {
+        let _store = with_temp_store("devices");
+        let mut registry = DeviceRegistry::new();
+        registry.apply(added(41, "usb-dac", "DAC")).unwrap();
+        assert_eq!(restore_state("usb-dac").saved, SavedState::default());
+        let change = DeviceChange { object_id: 41, volume: Some(0.3), muted: Some(true), ..DeviceChange::default() };
+        remember(&registry.apply(DeviceEvent::Changed(change)).unwrap()[0].device).unwrap();
+        balance::save("usb-dac", &BalanceSettings { balance: -0.25, trims_db: vec![] }).unwrap();
+        // Written once the volume has settled
+        let saved = || store::global().get::<BTreeMap<String, SavedState>>(STATES_KEY).unwrap_or_default();
+        assert!(saved().is_empty());
+        let settled = std::time::Instant::now() + SAVE_DELAY * 5;
+        while saved().is_empty() && std::time::Instant::now() < settled {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(saved()["usb-dac"], SavedState { volume: Some(0.3), muted: Some(true) });
+
+        let json: serde_json::Value = serde_json::from_str(&take_c_string(unsafe { ar_device_restore_state(c"usb-dac".as_ptr()) }).unwrap()).unwrap();
+        assert_eq!(json, serde_json::json!({"volume": 0.3, "muted": true, "balance": -0.25, "trimsDb": []}));
+        let json = take_c_string(unsafe { ar_device_restore_state(c"hdmi-tv".as_ptr()) }).unwrap();
+        assert_eq!(json, r#"{"volume":null,"muted":null,"balance":0.0,"trimsDb":[]}"#);
+    }
+
+    #[test]
+    fn test_aliases() {
+        let mut registry = DeviceRegistry::new();
+        let nickname = |nickname: &str| Alias { nickname: Some(nickname.into()), icon: Some(" mixer ".into()) };
+        assert_eq!(registry.set_alias("BlackHole2ch_UID", nickname("Streaming Mix")).unwrap(), None);
+        registry.apply(added(41, "BlackHole2ch_UID", "BlackHole 2ch")).unwrap();
+        registry.apply(added(42, "usb-dac", "DAC")).unwrap();
+        let snapshot = registry.snapshot();
+        assert_eq!(snapshot.devices.iter().map(|device| device.display_name()).collect::<Vec<_>>(), ["DAC", "Streaming Mix"]);
+        assert_eq!((snapshot.devices[1].name.as_str(), snapshot.devices[1].icon.as_deref()), ("BlackHole 2ch", Some("mixer")));
+        assert_eq!(snapshot.devices[1].category, DeviceCategory::Virtual);
+        assert_eq!(registry.topics().0.unwrap()[1].name, "Streaming Mix");
+
+        let change = registry.set_alias("usb-dac", nickname("Desk")).unwrap().unwrap();
+        assert_eq!((change.change, change.device.nickname.as_deref()), (ChangeKind::Changed, Some("Desk")));
+        assert_eq!(registry.set_alias("usb-dac", nickname("Desk")).unwrap(), None);
+        // Blank takes it away
+        let change = registry.set_alias("usb-dac", Alias { nickname: Some("  ".into()), icon: None }).unwrap().unwrap();
+        assert_eq!(change.device.display_name(), "DAC");
+        assert!(registry.set_alias("usb-dac", nickname(&"x".repeat(65))).is_err());
+        assert!(registry.set_alias("", nickname("Nowhere")).is_err());
+    }
+
+    #[test]
+    fn test_aliases_ffi() {
+        let _store = with_temp_store("device-aliases");
+        let alias = c"{\"nickname\": \"Podcast Mic\", \"icon\": \"mic\"}";
+        assert_eq!(unsafe { ar_devices_set_alias(c"ffi-test-alias".as_ptr(), alias.as_ptr()) }, 0);
+        let saved: BTreeMap<String, Alias> = store::global().get(ALIASES_KEY).unwrap();
+        assert_eq!(saved["ffi-test-alias"].nickname.as_deref(), Some("Podcast Mic"));
+
+        // An input-only device, so nothing is published to the other tests' remotes
+        let event = cr#"{"event": "added", "objectId": 7002, "uid": "ffi-test-alias", "name": "USB Audio", "inputChannels": 1}"#;
+        assert_eq!(unsafe { ar_devices_event(event.as_ptr()) }, 1);
+        let device = snapshot().devices.into_iter().find(|device| device.id == "ffi-test-alias").unwrap();
+        assert_eq!((device.display_name(), device.icon.as_deref()), ("Podcast Mic", Some("mic")));
+        assert_eq!(unsafe { ar_devices_set_alias(c"ffi-test-alias".as_ptr(), std::ptr::null()) }, 1);
+        assert!(store::global().get::<BTreeMap<String, Alias>>(ALIASES_KEY).unwrap().is_empty());
+        assert_eq!(unsafe { ar_devices_set_alias(c"ffi-test-alias".as_ptr(), c"{\"color\": \"red\"}".as_ptr()) }, -999);
+        assert_eq!(unsafe { ar_devices_event(cr#"{"event": "removed", "objectId": 7002}"#.as_ptr()) }, 1);
+    }
+
+    #[test]
+    fn test_bluetooth_battery_reaches_remotes() {
+        let mut registry = DeviceRegistry::new();
+        registry.apply(added(41, "airpods", "AirPods Pro")).unwrap();
+        registry.topics();
+        let properties = serde_json::json!({"BatteryPercentLeft": 80, "BatteryPercentRight": 65, "Codec": "AAC"});
+        let info = bluetooth::parse(properties.as_object().unwrap());
+        let change = registry.set_bluetooth("airpods", Some(info.clone())).unwrap().unwrap();
+        assert_eq!(change.device.bluetooth.unwrap().codec, Some(bluetooth::Codec::Aac));
+        assert_eq!(registry.set_bluetooth("airpods", Some(info)).unwrap(), None);
+        assert_eq!(registry.topics().0.unwrap()[0].battery_percent, Some(65));
+
+        // Gone with the connection
+        let removed = registry.apply(DeviceEvent::Removed(ObjectRef { object_id: 41 })).unwrap();
+        assert_eq!(removed[0].device.bluetooth, None);
+        assert!(registry.set_bluetooth("airpods", None).is_err());
+        assert!(registry.set_bluetooth("nowhere", None).is_err());
+    }
+}
@@ -0,0 +1,404 @@
+//! Discovery for networks that filter mDNS (hotel and guest Wi-Fi often do).
+//! A remote broadcasts a magic query to UDP port 47809 (and sends it to the
+//! 239.255.77.77 group, for networks that pass multicast but not broadcast);
+//! every Mac that hears it answers the sender with its service info.
+//!
+//! Answers are signed with the key of the Mac's TLS certificate over the
+//! query's nonce and the info's JSON text exactly as sent, and carry the
+//! certificate: a remote that pinned the fingerprint while pairing knows the
+//! answer is from that Mac and fresh, not a replay. Queries are padded to `QUERY_SIZE` so a spoofed one can't be
+//! turned into a much bigger answer aimed at someone else. IPv4 only:
+//! IPv6 has no broadcast, and networks that drop mDNS drop its groups too.
+//!
+//! The same port takes signed commands from paired remotes (see `lan_command`).
+
+use std::ffi::c_char;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::error::string_result;
+use crate::interfaces::{self, InterfaceAddress};
+use crate::mdns::{self, AdvertiseConfig};
+use crate::{guard, lan_command, pairing, record, server, str_arg, tls, AudioRemoteError};
+
+pub const DISCOVERY_PORT: u16 = 47809;
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 77, 77);
+const QUERY_MAGIC: &[u8] = b"AUDIOREMOTE-DISCOVER/1\n";
+const REPLY_MAGIC: &[u8] = b"AUDIOREMOTE-HERE/1\n";
+/// Queries are at least this long, about as long as an answer
+pub const QUERY_SIZE: usize = 1200;
+const NONCE_BYTES: usize = 16;
+/// Signed answers start with this, so the signature can't be passed off as anything else
+const SIGNING_CONTEXT: &[u8] = b"audioremote discovery v1";
+/// How often the answer loop checks for stop and for new interfaces
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const ADDRESS_REFRESH: Duration = Duration::from_secs(10);
+
+/// What a Mac says about itself
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceInfo {
+    pub name: String,
+    /// The pairing identity (see `pairing::mac_id`)
+    pub mac_id: String,
+    pub host: String,
+    pub port: u16,
+    pub version: String,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Whether the server speaks HTTPS
+    #[serde(default)]
+    pub tls: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Reply {
+    /// `ServiceInfo` as JSON text, the exact bytes that are signed: parsed and written again, JSON
+    /// needn't come out the same
+    info: String,
+    certificate: String,
+    signature: String,
+}
+
+/// A Mac that answered a probe, its signature checked
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Found {
+    pub info: ServiceInfo,
+    /// Where the answer came from
+    pub address: String,
+    /// SHA-256 of the certificate that signed it, for comparing with the pinned one
+    pub cert_fingerprint: String,
+    /// This Mac's own answer
+    pub is_self: bool,
+}
+
+pub fn query(nonce: &[u8; NONCE_BYTES]) -> Vec<u8> {
+    let mut packet = [QUERY_MAGIC, nonce].concat();
+    packet.resize(QUERY_SIZE, 0);
+    packet
+}
+
+/// The nonce of a well-formed query
+fn parse_query(packet: &[u8]) -> Option<[u8; NONCE_BYTES]> {
+    if packet.len() < QUERY_SIZE {
+        return None;
+    }
+    packet.strip_prefix(QUERY_MAGIC)?.get(..NONCE_BYTES)?.try_into().ok()
+}
+
+fn signed_bytes(nonce: &[u8], info: &str) -> Vec<u8> {
+    [SIGNING_CONTEXT, nonce, info.as_bytes()].concat()
+}
+
+fn signed_reply(info: String, identity: &tls::Identity, nonce: &[u8]) -> Result<Vec<u8>, AudioRemoteError> {
+    let signature = identity.sign(&signed_bytes(nonce, &info))?;
+    let reply = Reply { info, certificate: STANDARD.encode(&identity.certificate), signature: STANDARD.encode(signature) };
+    Ok([REPLY_MAGIC, &serde_json::to_vec(&reply).unwrap_or_default()].concat())
+}
+
+/// The signed answer to the query with `nonce`
+pub fn reply(info: &ServiceInfo, identity: &tls::Identity, nonce: &[u8]) -> Result<Vec<u8>, AudioRemoteError> {
+    let info = serde_json::to_string(info).map_err(|e| AudioRemoteError::Other(e.to_string()))?;
+    signed_reply(info, identity, nonce)
+}
+
+/// Check an answer to the query with `nonce`: the info and the signer's fingerprint
+pub fn verify_reply(packet: &[u8], nonce: &[u8]) -> Result<(ServiceInfo, String), AudioRemoteError> {
+    let invalid = |what: &str| AudioRemoteError::InvalidData(format!("invalid discovery answer: {what}"));
+    let json = packet.strip_prefix(REPLY_MAGIC).ok_or_else(|| invalid("no magic"))?;
+    let reply: Reply = serde_json::from_slice(json).map_err(|e| invalid(&e.to_string()))?;
+    let certificate = STANDARD.decode(&reply.certificate).map_err(|_| invalid("certificate isn't base64"))?;
+    let signature = STANDARD.decode(&reply.signature).map_err(|_| invalid("signature isn't base64"))?;
+    if !tls::verify(&certificate, &signed_bytes(nonce, &reply.info), &signature) {
+        return Err(AudioRemoteError::VerificationFailed("the discovery answer's signature doesn't match".into()));
+    }
+    let info = serde_json::from_str(&reply.info).map_err(|e| invalid(&e.to_string()))?;
+    Ok((info, tls::fingerprint(&certificate)))
+}
+
+fn service_info(config: &AdvertiseConfig, port: u16) -> Result<ServiceInfo, AudioRemoteError> {
+    Ok(ServiceInfo {
+        name: mdns::advertised_name().unwrap_or_else(|| config.name.clone()),
+        mac_id: pairing::mac_id()?,
+        host: mdns::host_name(),
+        port,
+        version: config.version.clone(),
+        capabilities: config.capabilities.clone(),
+        tls: server::certificate_fingerprint().is_some(),
+    })
+}
+
+fn responder_socket(port: u16) -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)).into())?;
+    socket.set_read_timeout(Some(POLL_INTERVAL))?;
+    Ok(UdpSocket::from(socket))
+}
+
+fn join_group(socket: &UdpSocket, interfaces: &[InterfaceAddress]) {
+    for interface in interfaces {
+        if let IpAddr::V4(address) = interface.address {
+            // Fails harmlessly on interfaces already joined
+            let _ = socket.join_multicast_v4(&DISCOVERY_GROUP, &address);
+        }
+    }
+}
+
+fn respond(socket: UdpSocket, config: AdvertiseConfig, port: u16, stop: Arc<AtomicBool>) {
+    let identity = match tls::identity() {
+        Ok(identity) => identity,
+        Err(e) => return log::error!("discovery can't answer without a certificate: {e}"),
+    };
+    let mut refreshed = Instant::now();
+    let mut buffer = vec![0; QUERY_SIZE * 2];
+    while !stop.load(Ordering::Acquire) {
+        if refreshed.elapsed() >= ADDRESS_REFRESH {
+            refreshed = Instant::now();
+            if let Ok(interfaces) = interfaces::local_addresses() {
+                join_group(&socket, &interfaces);
+            }
+        }
+        let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        if lan_command::is_command(&buffer[..length]) {
+            lan_command::receive(&socket, &buffer[..length], from);
+            continue;
+        }
+        let Some(nonce) = parse_query(&buffer[..length]) else {
+            continue;
+        };
+        match service_info(&config, port).and_then(|info| reply(&info, &identity, &nonce)) {
+            Ok(answer) => {
+                let _ = socket.send_to(&answer, from);
+            }
+            Err(e) => log::warn!("can't answer a discovery query from {from}: {e}"),
+        }
+    }
+}
+
+struct Running {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+static RESPONDER: Mutex<Option<Running>> = Mutex::new(None);
+
+fn spawn(socket: UdpSocket, config: AdvertiseConfig, port: u16) -> Result<(), AudioRemoteError> {
+    stop();
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let stop = stop.clone();
+        thread::Builder::new()
+            .name("audioremote-discovery".into())
+            .spawn(move || respond(socket, config, port, stop))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the discovery thread: {e}")))?
+    };
+    *RESPONDER.lock().unwrap_or_else(|e| e.into_inner()) = Some(Running { stop, thread });
+    Ok(())
+}
+
+/// Start answering discovery queries; replaces a running responder
+pub fn start(config: &AdvertiseConfig) -> Result<(), AudioRemoteError> {
+    let port = match config.port.or_else(|| server::local_address().map(|address| address.port())) {
+        Some(port) => port,
+        None => return Err(AudioRemoteError::InvalidArgument("no port given and the server isn't running".into())),
+    };
+    let socket = responder_socket(DISCOVERY_PORT)
+        .map_err(|e| AudioRemoteError::Network(format!("can't listen for discovery on port {DISCOVERY_PORT}: {e}")))?;
+    join_group(&socket, &interfaces::local_addresses()?);
+    spawn(socket, config.clone(), port)?;
+    log::info!("answering discovery queries on port {DISCOVERY_PORT}");
+    Ok(())
+}
+
+pub fn stop() {
+    let Some(running) = RESPONDER.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    running.stop.store(true, Ordering::Release);
+    let _ = running.thread.join();
+}
+
+/// Send a query to `targets` and collect the answers that verify, until `timeout`
+fn probe_targets(targets: &[SocketAddr], interfaces: &[InterfaceAddress], timeout: Duration) -> Result<Vec<Found>, AudioRemoteError> {
+    let network = |e: std::io::Error| AudioRemoteError::Network(format!("discovery probe failed: {e}"));
+    let nonce = pairing::random_bytes::<NONCE_BYTES>()?;
+    let packet = query(&nonce);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(network)?;
+    socket.set_broadcast(true).map_err(network)?;
+    for target in targets {
+        let sent = match target {
+            SocketAddr::V4(v4) if v4.ip().is_multicast() => {
+                // Once per interface, or it only leaves by the default route
+                for interface in interfaces {
+                    if let IpAddr::V4(address) = interface.address {
+                        let _ = socket2::SockRef::from(&socket).set_multicast_if_v4(&address);
+                        let _ = socket.send_to(&packet, target);
+                    }
+                }
+                Ok(0)
+            }
+            _ => socket.send_to(&packet, target),
+        };
+        if let Err(e) = sent {
+            log::debug!("discovery probe to {target} failed: {e}");
+        }
+    }
+
+    let own_id = pairing::mac_id().ok();
+    let deadline = Instant::now() + timeout;
+    let mut found: Vec<Found> = Vec::new();
+    let mut buffer = vec![0; 9000];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(left)).map_err(network)?;
+        let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+            break;
+        };
+        match verify_reply(&buffer[..length], &nonce) {
+            Ok((info, cert_fingerprint)) => {
+                let address = from.ip().to_string();
+                if !found.iter().any(|f| f.info.mac_id == info.mac_id && f.address == address) {
+                    let is_self = own_id.as_deref() == Some(info.mac_id.as_str());
+                    found.push(Found { info, address, cert_fingerprint, is_self });
+                }
+            }
+            Err(e) => log::debug!("ignoring a discovery answer from {from}: {e}"),
+        }
+    }
+    Ok(found)
+}
+
+/// Broadcast a query on every interface and collect the Macs that answer within `timeout`
+pub fn probe(timeout: Duration) -> Result<Vec<Found>, AudioRemoteError> {
+    let targets = [
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, DISCOVERY_PORT)),
+        SocketAddr::V4(SocketAddrV4::new(DISCOVERY_GROUP, DISCOVERY_PORT)),
+    ];
+    probe_targets(&targets, &interfaces::local_addresses()?, timeout)
+}
+
+/// Answer UDP discovery queries, the fallback for networks that filter mDNS, and take signed LAN
+/// commands (see ar_lan_command_send); replaces a running responder. `config_json` is the same as
+/// ar_mdns_start's.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_discovery_start(config_ptr: *const c_char) -> i32 {
+    guard("ar_discovery_start", -999, || {
+        let config = str_arg(config_ptr, "config").and_then(|json| {
+            serde_json::from_str::<AdvertiseConfig>(json)
+                .map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid discovery config: {e}")))
+        });
+        match record(config.and_then(|config| start(&config))) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Stop answering discovery queries; a no-op if not running. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_discovery_stop() {
+    guard("ar_discovery_stop", (), stop)
+}
+
+/// Look for Macs with a discovery query, waiting `timeout_ms` for answers. Only answers whose
+/// signature checks out are returned; compare "certFingerprint" with the pinned one to trust them.
+/// Returns: a JSON array of {"info": {"name", "macId", "host", "port", "version", "capabilities",
+/// "tls"}, "address", "certFingerprint", "isSelf"} (free with rust_string_free), NULL on error
+/// (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_discovery_probe(timeout_ms: u32) -> *mut c_char {
+    guard("ar_discovery_probe", std::ptr::null_mut(), || {
+        let found = probe(Duration::from_millis(timeout_ms.into()));
+        string_result(found.map(|found| serde_json::to_string(&found).unwrap_or_default()))
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::delta::tests::temp_dir;
+
+    /// Answer on `socket` instead of the discovery port
+    pub(crate) fn spawn_responder(socket: UdpSocket, config: AdvertiseConfig) {
+        let port = config.port.unwrap_or(server::DEFAULT_PORT);
+        spawn(socket, config, port).unwrap();
+    }
+
+    fn info() -> ServiceInfo {
+        ServiceInfo {
+            name: "Studio Mac".into(),
+            mac_id: "0f".repeat(16),
+            host: "studio-audioremote.local".into(),
+            port: 8765,
+            version: "2.1.0".into(),
+            capabilities: vec!["volume".into()],
+            tls: true,
+        }
+    }
+
+    #[test]
+    fn test_signed_replies() {
+        let dir = temp_dir("discovery-replies");
+        let identity = tls::Identity::load_or_create(&dir).unwrap();
+        let nonce = [7u8; NONCE_BYTES];
+        assert_eq!(parse_query(&query(&nonce)), Some(nonce));
+        assert_eq!(parse_query(&query(&nonce)[..QUERY_SIZE - 1]), None);
+
+        let answer = reply(&info(), &identity, &nonce).unwrap();
+        assert!(answer.len() <= QUERY_SIZE, "{} bytes", answer.len());
+        assert_eq!(verify_reply(&answer, &nonce).unwrap(), (info(), identity.fingerprint()));
+        // A replayed answer to someone else's query
+        assert!(matches!(verify_reply(&answer, &[8u8; NONCE_BYTES]), Err(AudioRemoteError::VerificationFailed(_))));
+        // Tampered info
+        let text = String::from_utf8(answer).unwrap().replace("8765", "8766");
+        assert!(verify_reply(text.as_bytes(), &nonce).is_err());
+        // Info written another way, as another implementation might, verifies as sent
+        let written = r#"{ "tls": true, "port": 8765, "name": "Studio Mac", "macId": "0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f0f",
+            "host": "studio-audioremote.local", "version": "2.1.0", "capabilities": ["volume"], "extra": 1 }"#;
+        let answer = signed_reply(written.into(), &identity, &nonce).unwrap();
+        assert_eq!(verify_reply(&answer, &nonce).unwrap().0, info());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_probe_finds_responder() {
+        // ar_shutdown in other tests stops the responder
+        let _runtime = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_lock, _dir) = crate::store::tests::with_temp_store("discovery-probe");
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_read_timeout(Some(POLL_INTERVAL)).unwrap();
+        let target = socket.local_addr().unwrap();
+        let config = AdvertiseConfig { name: "Studio Mac".into(), version: "2.1.0".into(), port: Some(9000), capabilities: vec![] };
+        spawn(socket, config, 9000).unwrap();
+        let found = probe_targets(&[target], &[], Duration::from_secs(1));
+        stop();
+
+        let found = found.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].info.port, found[0].address.as_str(), found[0].is_self), (9000, "127.0.0.1", true));
+        assert_eq!(found[0].cert_fingerprint, tls::identity().unwrap().fingerprint());
+
+        let header = std::fs::read_to_string(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../Core/RustBridge.h")).unwrap();
+        assert!(header.contains(&format!("#define AR_DISCOVERY_PORT {DISCOVERY_PORT}\n")));
+    }
+}
@@ -0,0 +1,234 @@
+//! A single JSON entry point. Requests name a method and carry its parameters:
+//!
+//! ```json
+//! {"id": 7, "method": "version.compare", "params": {"v1": "2.6.0", "v2": "2.10.0"}}
+//! ```
+//!
+//! and get back `{"id": 7, "ok": true, "result": -1}` or
+//! `{"id": 7, "ok": false, "error": {"code": 2, "message": "..."}}`, where `code`
+//! is an AudioRemoteError code. Features register handlers here instead of adding
+//! a C function each, and a request/response transcript can be replayed in tests.
+
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::sync::{OnceLock, RwLock};
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::error::string_result;
+use crate::version::ParseMode;
+use crate::{abi, appcast, channel, guard, ordering_code, parse_version_arg, release_notes, satisfies, store, str_arg};
+use crate::{AudioRemoteError, UpdateChannel};
+
+pub type Handler = fn(Value) -> Result<Value, AudioRemoteError>;
+
+fn handlers() -> &'static RwLock<BTreeMap<&'static str, Handler>> {
+    static HANDLERS: OnceLock<RwLock<BTreeMap<&'static str, Handler>>> = OnceLock::new();
+    HANDLERS.get_or_init(|| RwLock::new(builtin_handlers()))
+}
+
+/// Add or replace the handler for `method`
+pub fn register(method: &'static str, handler: Handler) {
+    handlers().write().unwrap_or_else(|e| e.into_inner()).insert(method, handler);
+}
+
+/// Decode a handler's parameters, treating a missing `params` as `{}`
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, AudioRemoteError> {
+    let params = if params.is_null() { json!({}) } else { params };
+    serde_json::from_value(params).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid params: {e}")))
+}
+
+fn to_value(value: impl serde::Serialize) -> Result<Value, AudioRemoteError> {
+    serde_json::to_value(value).map_err(|e| AudioRemoteError::Other(e.to_string()))
+}
+
+fn mode(lenient: bool) -> ParseMode {
+    if lenient { ParseMode::Lenient } else { ParseMode::Strict }
+}
+
+#[derive(Deserialize)]
+struct VersionPair {
+    v1: String,
+    v2: String,
+    #[serde(default)]
+    lenient: bool,
+}
+
+#[derive(Deserialize)]
+struct SingleVersion {
+    version: String,
+    #[serde(default)]
+    lenient: bool,
+}
+
+#[derive(Deserialize)]
+struct Satisfies {
+    version: String,
+    range: String,
+}
+
+#[derive(Deserialize)]
+struct BestRelease {
+    xml: String,
+    #[serde(default = "saved_channel")]
+    channel: i32,
+}
+
+fn saved_channel() -> i32 {
+    channel::SAVED_CHANNEL
+}
+
+#[derive(Deserialize)]
+struct SetChannel {
+    channel: i32,
+}
+
+#[derive(Deserialize)]
+struct Markdown {
+    markdown: String,
+}
+
+fn builtin_handlers() -> BTreeMap<&'static str, Handler> {
+    let mut handlers: BTreeMap<&'static str, Handler> = BTreeMap::new();
+    handlers.insert("abi.version", |_| Ok(json!(abi::ABI_VERSION)));
+    handlers.insert("methods", |_| Ok(json!(methods())));
+    handlers.insert("version.compare", |p| {
+        let p: VersionPair = params(p)?;
+        let v1 = parse_version_arg(&p.v1, "v1", mode(p.lenient))?;
+        let v2 = parse_version_arg(&p.v2, "v2", mode(p.lenient))?;
+        Ok(json!(ordering_code(v1.cmp(&v2))))
+    });
+    handlers.insert("version.normalize", |p| {
+        let p: SingleVersion = params(p)?;
+        Ok(json!(parse_version_arg(&p.version, "version", mode(p.lenient))?.to_string()))
+    });
+    handlers.insert("version.satisfies", |p| {
+        let p: Satisfies = params(p)?;
+        Ok(json!(satisfies(&parse_version_arg(&p.version, "version", ParseMode::Strict)?, &p.range)?))
+    });
+    handlers.insert("appcast.bestRelease", |p| {
+        let p: BestRelease = params(p)?;
+        let items = appcast::parse(&p.xml)?;
+        let best = appcast::best_release(&items, channel::channel_arg(p.channel)?);
+        to_value(best.ok_or(AudioRemoteError::NotFound("no release available on this channel".into()))?)
+    });
+    handlers.insert("channel.get", |_| Ok(json!(channel::saved(&store::global()) as i32)));
+    handlers.insert("channel.set", |p| {
+        let p: SetChannel = params(p)?;
+        let channel = UpdateChannel::from_raw(p.channel)
+            .ok_or(AudioRemoteError::InvalidArgument(format!("unknown update channel {}", p.channel)))?;
+        channel::set(&store::global(), channel)?;
+        Ok(Value::Null)
+    });
+    handlers.insert("releaseNotes.toHtml", |p| {
+        let p: Markdown = params(p)?;
+        Ok(json!(release_notes::to_html(&p.markdown)))
+    });
+    handlers
+}
+
+/// The handler registered for `method`, for transports other than ar_dispatch (see `ipc`)
+pub(crate) fn handler(method: &str) -> Option<Handler> {
+    handlers().read().unwrap_or_else(|e| e.into_inner()).get(method).copied()
+}
+
+/// Names of every registered method, sorted
+pub fn methods() -> Vec<&'static str> {
+    handlers().read().unwrap_or_else(|e| e.into_inner()).keys().copied().collect()
+}
+
+#[derive(Deserialize)]
+struct Request {
+    #[serde(default)]
+    id: Value,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+/// Run one request and build its response; never fails, errors are part of the response
+pub fn dispatch(request: &str) -> String {
+    let (id, result) = match serde_json::from_str::<Request>(request) {
+        Ok(request) => {
+            let result = match handler(&request.method) {
+                Some(handler) => handler(request.params),
+                None => Err(AudioRemoteError::NotFound(format!("unknown method \"{}\"", request.method))),
+            };
+            (request.id, result)
+        }
+        Err(e) => (Value::Null, Err(AudioRemoteError::InvalidArgument(format!("invalid request: {e}")))),
+    };
+    let response = match result {
+        Ok(result) => json!({"id": id, "ok": true, "result": result}),
+        Err(error) => json!({"id": id, "ok": false, "error": {"code": error.code(), "message": error.to_string()}}),
+    };
+    response.to_string()
+}
+
+/// Run a JSON request {"id", "method", "params"} against the registered handlers
+/// Returns: JSON response {"id", "ok", "result"} or {"id", "ok": false, "error": {"code", "message"}}
+/// (free with rust_string_free); NULL only if `request` itself can't be read (see last_error_message)
+///
+/// # Safety
+/// `request_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_dispatch(request_ptr: *const c_char) -> *mut c_char {
+    guard("ar_dispatch", std::ptr::null_mut(), || {
+        string_result(str_arg(request_ptr, "request").map(dispatch))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Request/response pairs as Swift would send and receive them
+    const TRANSCRIPT: &[(&str, &str)] = &[
+        (
+            r#"{"id":1,"method":"version.compare","params":{"v1":"2.6.0","v2":"2.10.0"}}"#,
+            r#"{"id":1,"ok":true,"result":-1}"#,
+        ),
+        (
+            r#"{"id":"n","method":"version.normalize","params":{"version":"v2.6 (142)","lenient":true}}"#,
+            r#"{"id":"n","ok":true,"result":"2.6.0+142"}"#,
+        ),
+        (
+            r#"{"id":2,"method":"version.satisfies","params":{"version":"2.6.0","range":">=2.5, <3"}}"#,
+            r#"{"id":2,"ok":true,"result":true}"#,
+        ),
+        (
+            r#"{"id":3,"method":"version.compare","params":{"v1":"2.6","v2":"2.6.0"}}"#,
+            r#"{"id":3,"ok":false,"error":{"code":2,"message":"invalid v1 \"2.6\": unexpected end of input while parsing minor version number"}}"#,
+        ),
+        (
+            r#"{"id":4,"method":"version.compare","params":{"v1":"2.6.0"}}"#,
+            r#"{"id":4,"ok":false,"error":{"code":1,"message":"invalid params: missing field `v2`"}}"#,
+        ),
+        (r#"{"id":5,"method":"nope"}"#, r#"{"id":5,"ok":false,"error":{"code":9,"message":"unknown method \"nope\""}}"#),
+    ];
+
+    #[test]
+    fn test_replay_transcript() {
+        for (request, expected) in TRANSCRIPT {
+            let response: Value = serde_json::from_str(&dispatch(request)).unwrap();
+            let expected: Value = serde_json::from_str(expected).unwrap();
+            assert_eq!(response, expected, "{request}");
+        }
+        let garbage: Value = serde_json::from_str(&dispatch("not json")).unwrap();
+        assert_eq!(garbage["ok"], false);
+        assert_eq!(garbage["error"]["code"], 1);
+    }
+
+    #[test]
+    fn test_register_and_list() {
+        register("test.echo", Ok);
+        let response = dispatch(r#"{"method":"test.echo","params":[1,2]}"#);
+        assert_eq!(response, r#"{"id":null,"ok":true,"result":[1,2]}"#);
+        assert!(methods().contains(&"test.echo"));
+        assert!(methods().contains(&"appcast.bestRelease"));
+        let abi: Value = serde_json::from_str(&dispatch(r#"{"method":"abi.version"}"#)).unwrap();
+        assert_eq!(abi["result"], abi::ABI_VERSION);
+    }
+}
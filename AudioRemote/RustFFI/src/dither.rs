@@ -0,0 +1,147 @@
+//! Dither for turning the tap's float samples into 16- or 24-bit integers for
+//! FLAC and AirPlay. Rounding alone leaves an error that follows the signal,
+//! heard as grit on quiet passages and fade tails; TPDF dither (two uniform
+//! random values summed, ±1 LSB) turns it into a steady hiss instead, and
+//! noise shaping moves that hiss up where the ear is least sensitive, with
+//! Wannamaker's 3-tap psychoacoustic filter (designed for 44.1 kHz, close
+//! enough at 48).
+//!
+//! Audio that is already on the integers' grid, as from a 16- or 24-bit source
+//! played untouched, isn't being requantised and goes through exactly, so
+//! bit-perfect stays bit-perfect and digital silence stays silent. Samples
+//! outside ±1.0 are clipped.
+
+use serde::Deserialize;
+
+/// Wannamaker's 3-tap "F-weighted" error filter
+const SHAPING: [f64; 3] = [1.623, -0.982, 0.109];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DitherMode {
+    /// Round to the nearest
+    None,
+    #[default]
+    Tpdf,
+    /// TPDF with the error shaped up the spectrum
+    Shaped,
+}
+
+pub struct Quantizer {
+    mode: DitherMode,
+    scale: f64,
+    min: f64,
+    max: f64,
+    /// xorshift64* state, for the dither
+    random: u64,
+    /// Each channel's last errors, newest first, for shaping
+    errors: Vec<[f64; 3]>,
+    /// The channel the next sample belongs to
+    channel: usize,
+}
+
+impl Quantizer {
+    pub fn new(mode: DitherMode, bits: u32, channels: usize) -> Self {
+        let scale = (1i64 << (bits - 1)) as f64;
+        Self { mode, scale, min: -scale, max: scale - 1.0, random: 0x9E37_79B9_7F4A_7C15, errors: vec![[0.0; 3]; channels.max(1)], channel: 0 }
+    }
+
+    /// Uniform in [0, 1)
+    fn uniform(&mut self) -> f64 {
+        self.random ^= self.random >> 12;
+        self.random ^= self.random << 25;
+        self.random ^= self.random >> 27;
+        (self.random.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Quantise interleaved samples onto the end of `out`; samples that aren't finite become 0
+    pub fn quantize(&mut self, samples: &[f32], out: &mut Vec<i32>) {
+        let on_grid = samples.iter().all(|&sample| !sample.is_finite() || (f64::from(sample) * self.scale).fract() == 0.0);
+        for &sample in samples {
+            let channel = self.channel;
+            self.channel = (channel + 1) % self.errors.len();
+            if !sample.is_finite() {
+                out.push(0);
+                continue;
+            }
+            let value = f64::from(sample) * self.scale;
+            if on_grid || self.mode == DitherMode::None {
+                out.push(value.round().clamp(self.min, self.max) as i32);
+                continue;
+            }
+            let dither = self.uniform() + self.uniform() - 1.0;
+            let errors = &mut self.errors[channel];
+            let target = match self.mode {
+                DitherMode::Shaped => value - SHAPING.iter().zip(errors.iter()).map(|(tap, error)| tap * error).sum::<f64>(),
+                _ => value,
+            };
+            let quantized = (target + dither).round().clamp(self.min, self.max);
+            // Bounded, so a clipped sample's error can't set the filter ringing
+            *errors = [(quantized - target).clamp(-2.0, 2.0), errors[0], errors[1]];
+            out.push(quantized as i32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 1 kHz tone at `lsb` steps of 16 bits, as floats
+    fn tone(lsb: f64, frames: usize) -> Vec<f32> {
+        (0..frames).map(|i| (lsb / 32768.0 * (2.0 * std::f64::consts::PI * 1000.0 * i as f64 / 48_000.0).sin()) as f32).collect()
+    }
+
+    /// The error's power after averaging each 16 samples, a crude low-pass
+    fn low_band_power(samples: &[f32], quantized: &[i32]) -> f64 {
+        let errors: Vec<f64> = samples.iter().zip(quantized).map(|(&sample, &q)| f64::from(q) - f64::from(sample) * 32768.0).collect();
+        let means: Vec<f64> = errors.chunks_exact(16).map(|chunk| chunk.iter().sum::<f64>() / 16.0).collect();
+        means.iter().map(|mean| mean * mean).sum::<f64>() / means.len() as f64
+    }
+
+    #[test]
+    fn test_quiet_signals_survive() {
+        // A tone under half a step rounds away to nothing, but dithered it's still there on average
+        let samples = tone(0.4, 48_000);
+        let reference: Vec<f64> = tone(1.0, 48_000).iter().map(|&sample| f64::from(sample) * 32768.0).collect();
+        for (mode, expected) in [(DitherMode::None, 0.0), (DitherMode::Tpdf, 0.2), (DitherMode::Shaped, 0.2)] {
+            let mut out = vec![];
+            Quantizer::new(mode, 16, 1).quantize(&samples, &mut out);
+            let correlation = out.iter().zip(&reference).map(|(&q, &r)| f64::from(q) * r).sum::<f64>() / out.len() as f64;
+            assert!((correlation - expected).abs() < 0.02, "{mode:?}: {correlation}");
+        }
+
+        // TPDF's error is ±1.5 steps at most, a quarter of a step squared on average
+        let mut out = vec![];
+        let samples = tone(1000.5, 48_000);
+        Quantizer::new(DitherMode::Tpdf, 16, 1).quantize(&samples, &mut out);
+        let errors: Vec<f64> = samples.iter().zip(&out).map(|(&sample, &q)| f64::from(q) - f64::from(sample) * 32768.0).collect();
+        assert!(errors.iter().all(|error| error.abs() <= 1.5));
+        let power = errors.iter().map(|error| error * error).sum::<f64>() / errors.len() as f64;
+        assert!((power - 0.25).abs() < 0.02, "{power}");
+    }
+
+    #[test]
+    fn test_shaping_moves_noise_up() {
+        let samples = tone(300.3, 48_000);
+        let (mut flat, mut shaped) = (vec![], vec![]);
+        Quantizer::new(DitherMode::Tpdf, 16, 1).quantize(&samples, &mut flat);
+        Quantizer::new(DitherMode::Shaped, 16, 1).quantize(&samples, &mut shaped);
+        let (flat, shaped) = (low_band_power(&samples, &flat), low_band_power(&samples, &shaped));
+        assert!(shaped < flat / 4.0, "{shaped} vs {flat}");
+    }
+
+    #[test]
+    fn test_on_grid_is_exact() {
+        // 16-bit audio, a clipped sample, NaN and silence go through as they are
+        let samples = [0.5, -0.25, 3.0 / 32768.0, 1.5, f32::NAN, 0.0];
+        for mode in [DitherMode::Tpdf, DitherMode::Shaped] {
+            let mut out = vec![];
+            Quantizer::new(mode, 16, 2).quantize(&samples, &mut out);
+            assert_eq!(out, [16384, -8192, 3, 32767, 0, 0]);
+            let mut out = vec![];
+            Quantizer::new(mode, 24, 2).quantize(&samples, &mut out);
+            assert_eq!(out[..3], [4_194_304, -2_097_152, 768]);
+        }
+    }
+}
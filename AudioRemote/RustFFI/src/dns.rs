@@ -0,0 +1,372 @@
+//! DNS messages as used by multicast DNS (RFC 1035 wire format, RFC 6762
+//! extensions): just the record types DNS-SD needs. Names are decoded with
+//! compression pointers followed (and loops refused); encoded names are
+//! compressed against earlier names in the same message.
+
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::AudioRemoteError;
+
+pub const TYPE_A: u16 = 1;
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_AAAA: u16 = 28;
+pub const TYPE_SRV: u16 = 33;
+pub const TYPE_NSEC: u16 = 47;
+pub const TYPE_ANY: u16 = 255;
+
+pub const CLASS_IN: u16 = 1;
+/// Top bit of the class: "unicast response" in questions, "cache flush" in records
+pub const CLASS_FLAG: u16 = 0x8000;
+
+/// Header flag of responses
+pub const FLAG_RESPONSE: u16 = 0x8000;
+/// Authoritative answer, always set by mDNS responders
+pub const FLAG_AUTHORITATIVE: u16 = 0x0400;
+
+const MAX_POINTER_HOPS: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub qtype: u16,
+    /// Asks for a unicast reply (the QU bit)
+    pub unicast: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(String),
+    Srv { priority: u16, weight: u16, port: u16, target: String },
+    /// Each string is one `key=value` entry
+    Txt(Vec<Vec<u8>>),
+    Other(u16, Vec<u8>),
+}
+
+impl RData {
+    pub fn rtype(&self) -> u16 {
+        match self {
+            Self::A(_) => TYPE_A,
+            Self::Aaaa(_) => TYPE_AAAA,
+            Self::Ptr(_) => TYPE_PTR,
+            Self::Srv { .. } => TYPE_SRV,
+            Self::Txt(_) => TYPE_TXT,
+            Self::Other(rtype, _) => *rtype,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    /// Unique to its owner: receivers replace cached records instead of adding to them
+    pub cache_flush: bool,
+    /// Seconds; 0 withdraws the record ("goodbye")
+    pub ttl: u32,
+    pub data: RData,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<Question>,
+    pub answers: Vec<Record>,
+    pub authorities: Vec<Record>,
+    pub additionals: Vec<Record>,
+}
+
+impl Message {
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_RESPONSE != 0
+    }
+
+    /// Every record in the answer, authority and additional sections
+    pub fn records(&self) -> impl Iterator<Item = &Record> {
+        self.answers.iter().chain(&self.authorities).chain(&self.additionals)
+    }
+}
+
+/// DNS names compare case-insensitively
+pub fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.').eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// Encode TXT entries, skipping the ones too long for a single string
+pub fn txt_entries(entries: &BTreeMap<String, String>) -> Vec<Vec<u8>> {
+    entries.iter().map(|(key, value)| format!("{key}={value}").into_bytes()).filter(|entry| entry.len() <= 255).collect()
+}
+
+/// Decode TXT entries; keys without '=' map to an empty value
+pub fn parse_txt(entries: &[Vec<u8>]) -> BTreeMap<String, String> {
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let entry = String::from_utf8_lossy(entry);
+            let (key, value) = entry.split_once('=').unwrap_or((&entry, ""));
+            (!key.is_empty()).then(|| (key.to_ascii_lowercase(), value.to_owned()))
+        })
+        .collect()
+}
+
+fn invalid(message: &str) -> AudioRemoteError {
+    AudioRemoteError::InvalidData(format!("malformed DNS message: {message}"))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], AudioRemoteError> {
+        let end = self.position.checked_add(count).filter(|end| *end <= self.bytes.len()).ok_or_else(|| invalid("truncated"))?;
+        let slice = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, AudioRemoteError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, AudioRemoteError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, AudioRemoteError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// A possibly compressed name, as dotted labels without the trailing dot
+    fn name(&mut self) -> Result<String, AudioRemoteError> {
+        let mut labels = Vec::new();
+        let mut position = self.position;
+        let mut resume = None;
+        for _ in 0..MAX_POINTER_HOPS {
+            loop {
+                let length = *self.bytes.get(position).ok_or_else(|| invalid("truncated name"))? as usize;
+                match length {
+                    0 => {
+                        self.position = resume.unwrap_or(position + 1);
+                        return Ok(labels.join("."));
+                    }
+                    0xc0.. => {
+                        let low = *self.bytes.get(position + 1).ok_or_else(|| invalid("truncated name"))? as usize;
+                        resume.get_or_insert(position + 2);
+                        position = (length & 0x3f) << 8 | low;
+                        break;
+                    }
+                    0x40.. => return Err(invalid("unknown label type")),
+                    _ => {
+                        let label = self.bytes.get(position + 1..position + 1 + length).ok_or_else(|| invalid("truncated name"))?;
+                        labels.push(String::from_utf8_lossy(label).into_owned());
+                        position += 1 + length;
+                    }
+                }
+            }
+        }
+        Err(invalid("compression loop"))
+    }
+
+    fn question(&mut self) -> Result<Question, AudioRemoteError> {
+        let name = self.name()?;
+        let qtype = self.u16()?;
+        let class = self.u16()?;
+        Ok(Question { name, qtype, unicast: class & CLASS_FLAG != 0 })
+    }
+
+    fn record(&mut self) -> Result<Record, AudioRemoteError> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let length = self.u16()? as usize;
+        let end = self.position + length;
+        if end > self.bytes.len() {
+            return Err(invalid("truncated record"));
+        }
+        let data = match rtype {
+            TYPE_A if length == 4 => {
+                let b = self.take(4)?;
+                RData::A(Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            }
+            TYPE_AAAA if length == 16 => {
+                let b: [u8; 16] = self.take(16)?.try_into().map_err(|_| invalid("AAAA"))?;
+                RData::Aaaa(Ipv6Addr::from(b))
+            }
+            TYPE_PTR => RData::Ptr(self.name()?),
+            TYPE_SRV => RData::Srv { priority: self.u16()?, weight: self.u16()?, port: self.u16()?, target: self.name()? },
+            TYPE_TXT => {
+                let mut entries = Vec::new();
+                while self.position < end {
+                    let count = self.u8()? as usize;
+                    let entry = self.take(count)?;
+                    if !entry.is_empty() {
+                        entries.push(entry.to_vec());
+                    }
+                }
+                RData::Txt(entries)
+            }
+            _ => RData::Other(rtype, self.take(length)?.to_vec()),
+        };
+        if self.position != end {
+            return Err(invalid("record length doesn't match its data"));
+        }
+        Ok(Record { name, cache_flush: class & CLASS_FLAG != 0, ttl, data })
+    }
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Message, AudioRemoteError> {
+    let mut reader = Reader { bytes, position: 0 };
+    let id = reader.u16()?;
+    let flags = reader.u16()?;
+    let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+    let questions = (0..counts[0]).map(|_| reader.question()).collect::<Result<_, _>>()?;
+    let mut sections = counts[1..].iter().map(|count| (0..*count).map(|_| reader.record()).collect::<Result<Vec<_>, _>>());
+    let (Some(answers), Some(authorities), Some(additionals)) = (sections.next(), sections.next(), sections.next()) else {
+        unreachable!("three record sections");
+    };
+    Ok(Message { id, flags, questions, answers: answers?, authorities: authorities?, additionals: additionals? })
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+    /// Offsets of name suffixes already written, for compression
+    names: BTreeMap<String, usize>,
+}
+
+impl Writer {
+    fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn name(&mut self, name: &str) {
+        let name = name.trim_end_matches('.');
+        let labels: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".").to_ascii_lowercase();
+            if let Some(&offset) = self.names.get(&suffix) {
+                self.u16(0xc000 | offset as u16);
+                return;
+            }
+            if self.bytes.len() < 0x4000 {
+                self.names.insert(suffix, self.bytes.len());
+            }
+            // Labels are at most 63 bytes; longer ones are cut rather than corrupting the message
+            let label = &labels[i].as_bytes()[..labels[i].len().min(63)];
+            self.bytes.push(label.len() as u8);
+            self.bytes.extend_from_slice(label);
+        }
+        self.bytes.push(0);
+    }
+
+    fn record(&mut self, record: &Record) {
+        self.name(&record.name);
+        self.u16(record.data.rtype());
+        self.u16(CLASS_IN | if record.cache_flush { CLASS_FLAG } else { 0 });
+        self.bytes.extend_from_slice(&record.ttl.to_be_bytes());
+        let length_at = self.bytes.len();
+        self.u16(0);
+        match &record.data {
+            RData::A(address) => self.bytes.extend_from_slice(&address.octets()),
+            RData::Aaaa(address) => self.bytes.extend_from_slice(&address.octets()),
+            RData::Ptr(name) => self.name(name),
+            RData::Srv { priority, weight, port, target } => {
+                self.u16(*priority);
+                self.u16(*weight);
+                self.u16(*port);
+                self.name(target);
+            }
+            RData::Txt(entries) if entries.is_empty() => self.bytes.push(0),
+            RData::Txt(entries) => {
+                for entry in entries {
+                    let entry = &entry[..entry.len().min(255)];
+                    self.bytes.push(entry.len() as u8);
+                    self.bytes.extend_from_slice(entry);
+                }
+            }
+            RData::Other(_, data) => self.bytes.extend_from_slice(data),
+        }
+        let length = (self.bytes.len() - length_at - 2) as u16;
+        self.bytes[length_at..length_at + 2].copy_from_slice(&length.to_be_bytes());
+    }
+}
+
+pub fn encode(message: &Message) -> Vec<u8> {
+    let mut writer = Writer { bytes: Vec::with_capacity(512), names: BTreeMap::new() };
+    writer.u16(message.id);
+    writer.u16(message.flags);
+    for count in [message.questions.len(), message.answers.len(), message.authorities.len(), message.additionals.len()] {
+        writer.u16(count as u16);
+    }
+    for question in &message.questions {
+        writer.name(&question.name);
+        writer.u16(question.qtype);
+        writer.u16(CLASS_IN | if question.unicast { CLASS_FLAG } else { 0 });
+    }
+    for record in message.records() {
+        writer.record(record);
+    }
+    writer.bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_with_compression() {
+        let message = Message {
+            id: 0,
+            flags: FLAG_RESPONSE | FLAG_AUTHORITATIVE,
+            questions: vec![Question { name: "_audioremote._tcp.local".into(), qtype: TYPE_PTR, unicast: true }],
+            answers: vec![Record {
+                name: "_audioremote._tcp.local".into(),
+                cache_flush: false,
+                ttl: 4500,
+                data: RData::Ptr("Studio Mac._audioremote._tcp.local".into()),
+            }],
+            authorities: vec![],
+            additionals: vec![
+                Record {
+                    name: "Studio Mac._audioremote._tcp.local".into(),
+                    cache_flush: true,
+                    ttl: 120,
+                    data: RData::Srv { priority: 0, weight: 0, port: 8765, target: "studio.local".into() },
+                },
+                Record {
+                    name: "Studio Mac._audioremote._tcp.local".into(),
+                    cache_flush: true,
+                    ttl: 4500,
+                    data: RData::Txt(vec![b"version=2.6.0".to_vec(), b"caps=volume".to_vec()]),
+                },
+                Record { name: "studio.local".into(), cache_flush: true, ttl: 120, data: RData::A(Ipv4Addr::new(192, 168, 1, 20)) },
+                Record { name: "studio.local".into(), cache_flush: true, ttl: 120, data: RData::Aaaa("fe80::1".parse().unwrap()) },
+            ],
+        };
+        let bytes = encode(&message);
+        assert_eq!(decode(&bytes).unwrap(), message);
+        // The service type is written once, then pointed to
+        assert_eq!(bytes.windows(12).filter(|w| *w == b"_audioremote").count(), 1);
+
+        let txt = parse_txt(&[b"Version=2.6.0".to_vec(), b"flag".to_vec(), b"=x".to_vec()]);
+        assert_eq!(txt, BTreeMap::from([("version".into(), "2.6.0".into()), ("flag".into(), String::new())]));
+        assert!(same_name("Studio.LOCAL.", "studio.local"));
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        assert!(decode(&[0; 5]).is_err());
+        // One question whose name points at itself
+        let looping = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 1, 0, 1];
+        assert!(matches!(decode(&looping), Err(AudioRemoteError::InvalidData(m)) if m.contains("compression loop")));
+        let truncated = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 5, b'a'];
+        assert!(decode(&truncated).is_err());
+    }
+}
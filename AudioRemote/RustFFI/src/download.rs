@@ -0,0 +1,495 @@
+use std::ffi::{c_char, c_void};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::handle::Handle;
+use crate::runtime::{self, JobCompletionCallback, JobContext, JobProgressCallback};
+use crate::{guard, http, record, str_arg, AudioRemoteError};
+
+const BUFFER_SIZE: usize = 64 * 1024;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Progress callback with transfer rate: (done, total, bytes/sec, eta seconds, ctx) -> keep going?
+/// `total` is 0 and `eta_secs` is -1 when the size isn't known.
+pub type DownloadProgressCallback =
+    extern "C" fn(done: u64, total: u64, bytes_per_sec: f64, eta_secs: f64, ctx: *mut c_void) -> bool;
+
+#[derive(Debug, Clone)]
+pub struct DownloadOptions {
+    /// Retries after the first attempt fails with a transient error
+    pub max_retries: u32,
+    /// Delay before the first retry; doubled after each failure up to 30s
+    pub initial_backoff: Duration,
+}
+
+impl Default for DownloadOptions {
+    fn default() -> Self {
+        Self { max_retries: 5, initial_backoff: Duration::from_secs(1) }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Progress {
+    pub done: u64,
+    pub total: Option<u64>,
+    pub bytes_per_sec: f64,
+    pub eta_secs: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DownloadError {
+    Cancelled,
+    Http(u16),
+    Io(String),
+    Network(String),
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cancelled => write!(f, "download cancelled"),
+            Self::Http(code) => write!(f, "server responded with HTTP {code}"),
+            Self::Io(e) => write!(f, "file error: {e}"),
+            Self::Network(e) => write!(f, "network error: {e}"),
+        }
+    }
+}
+
+impl From<DownloadError> for AudioRemoteError {
+    fn from(error: DownloadError) -> Self {
+        match error {
+            DownloadError::Cancelled => Self::Cancelled,
+            DownloadError::Io(_) => Self::Io(error.to_string()),
+            DownloadError::Http(_) | DownloadError::Network(_) => Self::Network(error.to_string()),
+        }
+    }
+}
+
+enum AttemptError {
+    Retryable(DownloadError),
+    Fatal(DownloadError),
+}
+
+use AttemptError::{Fatal, Retryable};
+
+/// Partial downloads are kept next to the destination as "<name>.part"
+pub fn part_path(dest: &Path) -> PathBuf {
+    let mut name = dest.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    dest.with_file_name(name)
+}
+
+/// The ETag or Last-Modified the partial file was downloaded under, as "<name>.part.validator";
+/// a resume sends it in If-Range so the server only continues the same version of the file
+fn validator_path(part: &Path) -> PathBuf {
+    let mut name = part.file_name().unwrap_or_default().to_os_string();
+    name.push(".validator");
+    part.with_file_name(name)
+}
+
+/// What identifies this version of the file; weak ETags aren't allowed in If-Range
+fn validator(response: &ureq::Response) -> Option<String> {
+    let etag = response.header("ETag").filter(|tag| !tag.starts_with("W/"));
+    etag.or_else(|| response.header("Last-Modified")).map(|value| value.trim().to_owned())
+}
+
+fn discard(part: &Path) {
+    let _ = fs::remove_file(part);
+    let _ = fs::remove_file(validator_path(part));
+}
+
+/// Download `url` to `dest`, resuming from a previous ".part" file when the
+/// server supports Range requests and still has the same version of the file,
+/// and retrying transient failures with backoff.
+/// `on_progress` returns false to cancel; the partial file is kept for a later resume.
+pub fn download(
+    agent: &ureq::Agent,
+    url: &str,
+    dest: &Path,
+    options: &DownloadOptions,
+    mut on_progress: impl FnMut(&Progress) -> bool,
+) -> Result<(), DownloadError> {
+    let part = part_path(dest);
+    let mut backoff = options.initial_backoff;
+    let mut retries = 0;
+
+    loop {
+        match attempt(agent, url, &part, &mut on_progress) {
+            Ok(()) => {
+                let _ = fs::remove_file(validator_path(&part));
+                return fs::rename(&part, dest).map_err(|e| DownloadError::Io(e.to_string()));
+            }
+            Err(Fatal(e)) => return Err(e),
+            Err(Retryable(e)) => {
+                if retries >= options.max_retries {
+                    return Err(e);
+                }
+                retries += 1;
+                log::warn!("download of {url} failed ({e}), retry {retries} in {backoff:?}");
+                sleep_unless_cancelled(backoff, &part, &mut on_progress)?;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Wait out the backoff in short slices so a cancel is noticed promptly
+fn sleep_unless_cancelled(
+    duration: Duration,
+    part: &Path,
+    on_progress: &mut impl FnMut(&Progress) -> bool,
+) -> Result<(), DownloadError> {
+    let deadline = Instant::now() + duration;
+    let done = fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+    while Instant::now() < deadline {
+        let progress = Progress { done, total: None, bytes_per_sec: 0.0, eta_secs: None };
+        if !on_progress(&progress) {
+            return Err(DownloadError::Cancelled);
+        }
+        std::thread::sleep((deadline - Instant::now()).min(Duration::from_millis(100)));
+    }
+    Ok(())
+}
+
+/// Parse "bytes 100-199/200" into (start, total)
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let spec = value.trim().strip_prefix("bytes")?.trim();
+    let (range, total) = spec.split_once('/')?;
+    let total = total.trim().parse().ok();
+    let start = match range.trim() {
+        "*" => return Some((0, total)),
+        range => range.split_once('-')?.0.trim().parse().ok()?,
+    };
+    Some((start, total))
+}
+
+fn attempt(
+    agent: &ureq::Agent,
+    url: &str,
+    part: &Path,
+    on_progress: &mut impl FnMut(&Progress) -> bool,
+) -> Result<(), AttemptError> {
+    let stored = fs::read_to_string(validator_path(part)).ok().filter(|v| !v.is_empty());
+    let mut offset = fs::metadata(part).map(|m| m.len()).unwrap_or(0);
+    if offset > 0 && stored.is_none() {
+        // Nothing to tell whether the server still has the same file
+        discard(part);
+        offset = 0;
+    }
+    let mut request = agent.get(url);
+    if let (true, Some(stored)) = (offset > 0, &stored) {
+        request = request.set("Range", &format!("bytes={offset}-")).set("If-Range", stored);
+    }
+
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(416, response)) => {
+            // Our partial file is complete if it is the version the server has; otherwise it is stale
+            let total = response.header("Content-Range").and_then(parse_content_range).and_then(|(_, t)| t);
+            if total == Some(offset) && validator(&response).is_some_and(|v| Some(v) == stored) {
+                return Ok(());
+            }
+            discard(part);
+            return Err(Retryable(DownloadError::Http(416)));
+        }
+        Err(ureq::Error::Status(code, _)) if code == 429 || code >= 500 => {
+            return Err(Retryable(DownloadError::Http(code)));
+        }
+        Err(ureq::Error::Status(code, _)) => return Err(Fatal(DownloadError::Http(code))),
+        Err(ureq::Error::Transport(e)) => return Err(Retryable(DownloadError::Network(e.to_string()))),
+    };
+
+    let content_length: Option<u64> = response.header("Content-Length").and_then(|l| l.trim().parse().ok());
+    let resumed = response.status() == 206
+        && response.header("Content-Range").and_then(parse_content_range).map(|(start, _)| start) == Some(offset);
+    let (mut done, total) = match resumed {
+        true => (offset, content_length.map(|l| l + offset)),
+        false => (0, content_length),
+    };
+
+    let io_error = |e: std::io::Error| Fatal(DownloadError::Io(e.to_string()));
+    let mut file = match resumed {
+        true => OpenOptions::new().append(true).open(part).map_err(io_error)?,
+        false => {
+            // A whole new body (a 200 answers If-Range when the file changed): start over under its validator
+            let file = File::create(part).map_err(io_error)?;
+            match validator(&response) {
+                Some(validator) => fs::write(validator_path(part), validator).map_err(io_error)?,
+                None => {
+                    let _ = fs::remove_file(validator_path(part));
+                }
+            }
+            file
+        }
+    };
+
+    let started = Instant::now();
+    let session_start = done;
+    let mut reader = response.into_reader();
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Retryable(DownloadError::Network(e.to_string()))),
+        };
+        file.write_all(&buffer[..n]).map_err(io_error)?;
+        done += n as u64;
+
+        let elapsed = started.elapsed().as_secs_f64();
+        let bytes_per_sec = if elapsed > 0.0 { (done - session_start) as f64 / elapsed } else { 0.0 };
+        let eta_secs = match total {
+            Some(total) if bytes_per_sec > 0.0 => Some(total.saturating_sub(done) as f64 / bytes_per_sec),
+            _ => None,
+        };
+        if !on_progress(&Progress { done, total, bytes_per_sec, eta_secs }) {
+            let _ = file.flush();
+            return Err(Fatal(DownloadError::Cancelled));
+        }
+    }
+    file.flush().map_err(io_error)?;
+
+    match total {
+        Some(total) if done < total => Err(Retryable(DownloadError::Network(format!(
+            "connection closed after {done} of {total} bytes"
+        )))),
+        _ => Ok(()),
+    }
+}
+
+/// Download a URL to a file, resuming an earlier partial download ("<dest>.part")
+/// when possible and retrying transient failures up to `max_retries` times with
+/// exponential backoff. Blocks until finished, so call from a background queue.
+/// `progress` (nullable) receives rate and ETA; return false from it to cancel.
+/// Returns: 1 on success, 0 if cancelled, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `url_ptr` and `dest_ptr` must point to valid NUL-terminated strings; `ctx`
+/// is passed through to `progress` untouched.
+#[no_mangle]
+pub unsafe extern "C" fn download_file(
+    url_ptr: *const c_char,
+    dest_ptr: *const c_char,
+    max_retries: u32,
+    progress: Option<DownloadProgressCallback>,
+    ctx: *mut c_void,
+) -> i32 {
+    guard("download_file", -999, || {
+        let result = str_arg(url_ptr, "url").and_then(|url| {
+            let dest = str_arg(dest_ptr, "dest")?;
+            let options = DownloadOptions { max_retries, ..Default::default() };
+            let on_progress = |p: &Progress| {
+                progress.is_none_or(|cb| {
+                    cb(p.done, p.total.unwrap_or(0), p.bytes_per_sec, p.eta_secs.unwrap_or(-1.0), ctx)
+                })
+            };
+            match download(&http::agent(), url, Path::new(dest), &options, on_progress) {
+                Ok(()) => Ok(true),
+                Err(DownloadError::Cancelled) => Ok(false),
+                Err(e) => Err(e.into()),
+            }
+        });
+        match record(result) {
+            Some(completed) => completed as i32,
+            None => -999,
+        }
+    })
+}
+
+/// Start download_file on the background runtime (see ar_init)
+/// `progress` (nullable) is called at most every 100 ms; `completion` receives the
+/// destination path on success. Cancel with ar_job_cancel; the partial file is kept.
+/// Returns: a job handle, or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `url_ptr` and `dest_ptr` must point to valid NUL-terminated strings; the callbacks
+/// and `ctx` must stay valid until `completion` has been called.
+#[no_mangle]
+pub unsafe extern "C" fn ar_download_file_async(
+    url_ptr: *const c_char,
+    dest_ptr: *const c_char,
+    max_retries: u32,
+    progress: Option<JobProgressCallback>,
+    completion: Option<JobCompletionCallback>,
+    ctx: *mut c_void,
+) -> Handle {
+    guard("ar_download_file_async", 0, || {
+        let result = str_arg(url_ptr, "url").and_then(|url| {
+            let (url, dest) = (url.to_owned(), PathBuf::from(str_arg(dest_ptr, "dest")?));
+            let job = move |job: &JobContext| {
+                let options = DownloadOptions { max_retries, ..Default::default() };
+                let on_progress = |p: &Progress| {
+                    job.progress(p.done, p.total.unwrap_or(0));
+                    !job.is_cancelled()
+                };
+                download(&http::agent(), &url, &dest, &options, on_progress)?;
+                Ok(dest.to_string_lossy().into_owned())
+            };
+            runtime::spawn(job, progress, completion, ctx)
+        });
+        record(result).unwrap_or(0)
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    const ETAG: &str = "\"v1\"";
+
+    /// Serve `body` with Range and If-Range support under ETAG; the first `drop_first` responses are
+    /// cut off halfway
+    pub(crate) fn serve(body: Vec<u8>, drop_first: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/update.zip", listener.local_addr().unwrap());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let n = counter.fetch_add(1, Ordering::SeqCst);
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let (mut range_start, mut if_range) = (0usize, None);
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(v) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        range_start = v.trim().trim_end_matches('-').parse().unwrap();
+                    }
+                    if let Some(v) = line.strip_prefix("If-Range: ").or_else(|| line.strip_prefix("if-range: ")) {
+                        if_range = Some(v.trim().to_owned());
+                    }
+                }
+                if range_start >= body.len() {
+                    let head = format!("HTTP/1.1 416 Range Not Satisfiable\r\nETag: {ETAG}\r\nContent-Range: bytes */{}\r\nContent-Length: 0\r\n\r\n", body.len());
+                    stream.write_all(head.as_bytes()).unwrap();
+                    continue;
+                }
+                if if_range.is_some_and(|tag| tag != ETAG) {
+                    range_start = 0;
+                }
+                let slice = &body[range_start..];
+                let head = if range_start > 0 {
+                    format!(
+                        "HTTP/1.1 206 Partial Content\r\nETag: {ETAG}\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                        slice.len(),
+                        range_start,
+                        body.len() - 1,
+                        body.len()
+                    )
+                } else {
+                    format!("HTTP/1.1 200 OK\r\nETag: {ETAG}\r\nContent-Length: {}\r\n\r\n", body.len())
+                };
+                stream.write_all(head.as_bytes()).unwrap();
+                let send = if n < drop_first { &slice[..slice.len() / 2] } else { slice };
+                let _ = stream.write_all(send);
+            }
+        });
+        (url, requests)
+    }
+
+    fn dest(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("audioremote-dl-{name}-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        discard(&part_path(&path));
+        path
+    }
+
+    fn fast_retry() -> DownloadOptions {
+        DownloadOptions { max_retries: 3, initial_backoff: Duration::from_millis(10) }
+    }
+
+    #[test]
+    fn test_download_resumes_after_drop() {
+        let body: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let (url, requests) = serve(body.clone(), 1);
+        let path = dest("resume");
+
+        let mut last = None;
+        download(&http::agent(), &url, &path, &fast_retry(), |p| {
+            last = Some(p.clone());
+            true
+        })
+        .unwrap();
+
+        assert_eq!(fs::read(&path).unwrap(), body);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(last.unwrap().total, Some(body.len() as u64));
+        assert!(!part_path(&path).exists());
+        assert!(!validator_path(&part_path(&path)).exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_download_resumes_only_the_same_file() {
+        let body: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let (url, requests) = serve(body.clone(), 0);
+        let path = dest("changed");
+        let part = part_path(&path);
+        let download_again = |stale: &[u8], validator: Option<&str>| {
+            fs::write(&part, stale).unwrap();
+            if let Some(validator) = validator {
+                fs::write(validator_path(&part), validator).unwrap();
+            }
+            download(&http::agent(), &url, &path, &fast_retry(), |_| true).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), body);
+            assert!(!part.exists());
+            assert!(!validator_path(&part).exists());
+        };
+
+        // Without a validator the part is thrown away rather than resumed
+        download_again(&[9; 1000], None);
+        // With an old one the server answers 200 with the new file
+        download_again(&[9; 1000], Some("\"v0\""));
+        // A stale part the size of the whole file isn't taken as finished
+        let before = requests.load(Ordering::SeqCst);
+        download_again(&vec![9; body.len()], Some("\"v0\""));
+        assert_eq!(requests.load(Ordering::SeqCst), before + 2);
+        // ... unless it's the same version
+        download_again(&body, Some(ETAG));
+        assert_eq!(requests.load(Ordering::SeqCst), before + 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_download_cancel_keeps_partial_file() {
+        let body = vec![1u8; 500_000];
+        let (url, _) = serve(body, 0);
+        let path = dest("cancel");
+
+        let result = download(&http::agent(), &url, &path, &fast_retry(), |p| p.done < 100_000);
+        assert_eq!(result, Err(DownloadError::Cancelled));
+        assert!(part_path(&path).exists());
+        assert_eq!(fs::read_to_string(validator_path(&part_path(&path))).unwrap(), ETAG);
+        assert!(!path.exists());
+        discard(&part_path(&path));
+    }
+
+    #[test]
+    fn test_download_gives_up_after_retries() {
+        // Nothing is listening on this port once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let path = dest("refused");
+        let result = download(&http::agent(), &format!("http://127.0.0.1:{port}/x"), &path, &fast_retry(), |_| true);
+        assert!(matches!(result, Err(DownloadError::Network(_))));
+    }
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range("bytes 100-199/200"), Some((100, Some(200))));
+        assert_eq!(parse_content_range("bytes */200"), Some((0, Some(200))));
+        assert_eq!(parse_content_range("bytes 0-9/*"), Some((0, None)));
+        assert_eq!(parse_content_range("items 0-9/10"), None);
+    }
+}
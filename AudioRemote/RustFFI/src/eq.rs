@@ -0,0 +1,459 @@
+//! A parametric EQ for the selected output device: a chain of biquad bands
+//! (peaking, low and high shelves, low and high passes) with coefficients from
+//! the Audio EQ Cookbook, run over interleaved float PCM from the Swift tap
+//! in place. Filters run in double precision in transposed direct form II,
+//! each channel with its own state.
+//!
+//! Changing the bands with ar_eq_update keeps each band's state when the
+//! channel count, sample rate and number of enabled bands stay the same, so
+//! dragging a band in the UI doesn't click.
+
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use rustfft::num_complex::Complex;
+use serde::{Deserialize, Serialize};
+
+use crate::error::string_result;
+use crate::handle::{Handle, Registry};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+const MAX_BANDS: usize = 32;
+
+/// One second-order section; `a` omits a0, which is normalized to 1
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct Biquad {
+    pub b: [f64; 3],
+    pub a: [f64; 2],
+    #[serde(skip)]
+    state: [f64; 2],
+}
+
+impl Biquad {
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self { b, a, state: [0.0; 2] }
+    }
+
+    fn normalized(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self::new([b[0] / a[0], b[1] / a[0], b[2] / a[0]], [a[1] / a[0], a[2] / a[0]])
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+
+    pub fn reset(&mut self) {
+        self.state = [0.0; 2];
+    }
+
+    /// The gain at `hz`, in dB
+    pub fn response_db(&self, hz: f64, sample_rate: f64) -> f64 {
+        let z = Complex::from_polar(1.0, -2.0 * std::f64::consts::PI * hz / sample_rate);
+        let numerator = self.b[0] + z * (self.b[1] + z * self.b[2]);
+        let denominator = 1.0 + z * (self.a[0] + z * self.a[1]);
+        20.0 * (numerator / denominator).norm().log10()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum BandKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Band {
+    #[serde(rename = "type")]
+    pub kind: BandKind,
+    pub frequency: f64,
+    /// Ignored by the passes
+    #[serde(default)]
+    pub gain_db: f64,
+    #[serde(default = "default_q")]
+    pub q: f64,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+/// Butterworth: flat up to the corner
+fn default_q() -> f64 {
+    std::f64::consts::FRAC_1_SQRT_2
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl Band {
+    fn validate(&self, sample_rate: f64) -> Result<(), AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(self.frequency > 0.0 && self.frequency < sample_rate / 2.0) {
+            return invalid(format!("frequency {} must be between 0 and {} Hz", self.frequency, sample_rate / 2.0));
+        }
+        if !(-30.0..=30.0).contains(&self.gain_db) {
+            return invalid(format!("gainDb {} is outside -30–30", self.gain_db));
+        }
+        if !(0.01..=100.0).contains(&self.q) {
+            return invalid(format!("q {} is outside 0.01–100", self.q));
+        }
+        Ok(())
+    }
+
+    /// The band's coefficients at `sample_rate`
+    pub fn biquad(&self, sample_rate: f64) -> Biquad {
+        let w0 = 2.0 * std::f64::consts::PI * self.frequency / sample_rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * self.q);
+        let a = 10f64.powf(self.gain_db / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+        match self.kind {
+            BandKind::Peaking => Biquad::normalized([1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a], [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a]),
+            BandKind::LowShelf => Biquad::normalized(
+                [a * ((a + 1.0) - (a - 1.0) * cos + shelf), 2.0 * a * ((a - 1.0) - (a + 1.0) * cos), a * ((a + 1.0) - (a - 1.0) * cos - shelf)],
+                [(a + 1.0) + (a - 1.0) * cos + shelf, -2.0 * ((a - 1.0) + (a + 1.0) * cos), (a + 1.0) + (a - 1.0) * cos - shelf],
+            ),
+            BandKind::HighShelf => Biquad::normalized(
+                [a * ((a + 1.0) + (a - 1.0) * cos + shelf), -2.0 * a * ((a - 1.0) + (a + 1.0) * cos), a * ((a + 1.0) + (a - 1.0) * cos - shelf)],
+                [(a + 1.0) - (a - 1.0) * cos + shelf, 2.0 * ((a - 1.0) - (a + 1.0) * cos), (a + 1.0) - (a - 1.0) * cos - shelf],
+            ),
+            BandKind::LowPass => Biquad::normalized([(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+            BandKind::HighPass => Biquad::normalized([(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0], [1.0 + alpha, -2.0 * cos, 1.0 - alpha]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct EqConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default)]
+    pub bands: Vec<Band>,
+    /// Gain before the bands, e.g. negative to leave headroom for boosts
+    #[serde(default)]
+    pub preamp_db: f64,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> f64 {
+    48_000.0
+}
+
+impl EqConfig {
+    fn validate(&self) -> Result<(), AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=MAX_CHANNELS).contains(&self.channels) {
+            return invalid(format!("channels {} is outside 1–{MAX_CHANNELS}", self.channels));
+        }
+        if !(8000.0..=768_000.0).contains(&self.sample_rate) {
+            return invalid(format!("sampleRate {} is outside 8000–768000", self.sample_rate));
+        }
+        if self.bands.len() > MAX_BANDS {
+            return invalid(format!("{} bands is more than {MAX_BANDS}", self.bands.len()));
+        }
+        if !(-30.0..=30.0).contains(&self.preamp_db) {
+            return invalid(format!("preampDb {} is outside -30–30", self.preamp_db));
+        }
+        self.bands.iter().try_for_each(|band| band.validate(self.sample_rate))
+    }
+}
+
+pub struct Equalizer {
+    config: EqConfig,
+    preamp: f64,
+    /// Each channel's enabled bands, in order
+    filters: Vec<Vec<Biquad>>,
+}
+
+impl Equalizer {
+    pub fn new(config: EqConfig) -> Result<Self, AudioRemoteError> {
+        config.validate()?;
+        let mut equalizer = Self { config, preamp: 1.0, filters: vec![] };
+        equalizer.build(false);
+        Ok(equalizer)
+    }
+
+    /// Make the filters from the config, keeping their state if `keep_state` and the chain's
+    /// shape is unchanged
+    fn build(&mut self, keep_state: bool) {
+        let rate = self.config.sample_rate;
+        let chain: Vec<Biquad> = self.config.bands.iter().filter(|band| band.enabled).map(|band| band.biquad(rate)).collect();
+        let channels = self.config.channels as usize;
+        let same_shape = self.filters.len() == channels && self.filters.iter().all(|filters| filters.len() == chain.len());
+        match keep_state && same_shape {
+            true => {
+                for filters in &mut self.filters {
+                    for (filter, new) in filters.iter_mut().zip(&chain) {
+                        (filter.b, filter.a) = (new.b, new.a);
+                    }
+                }
+            }
+            false => self.filters = vec![chain; channels],
+        }
+        self.preamp = 10f64.powf(self.config.preamp_db / 20.0);
+    }
+
+    /// Replace the bands and preamp; the channel count and sample rate can change too, which
+    /// starts the filters afresh
+    pub fn update(&mut self, config: EqConfig) -> Result<(), AudioRemoteError> {
+        config.validate()?;
+        let keep_state = config.channels == self.config.channels && config.sample_rate == self.config.sample_rate;
+        self.config = config;
+        self.build(keep_state);
+        Ok(())
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.filters.len()
+    }
+
+    /// Filter interleaved samples in place; a partial frame at the end is left alone and samples
+    /// that aren't finite are filtered as silence
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let preamp = self.preamp;
+        let channels = self.filters.len();
+        for frame in samples.chunks_exact_mut(channels) {
+            for (sample, filters) in frame.iter_mut().zip(&mut self.filters) {
+                let input = if sample.is_finite() { f64::from(*sample) * preamp } else { 0.0 };
+                *sample = filters.iter_mut().fold(input, |x, filter| filter.process(x)) as f32;
+            }
+        }
+    }
+
+    /// The whole chain's gain at `hz`, in dB, e.g. to draw the EQ curve
+    pub fn response_db(&self, hz: f64) -> f64 {
+        let chain = self.filters.first().map_or(&[][..], Vec::as_slice);
+        self.config.preamp_db + chain.iter().map(|filter| filter.response_db(hz, self.config.sample_rate)).sum::<f64>()
+    }
+
+    pub fn reset(&mut self) {
+        self.filters.iter_mut().flatten().for_each(Biquad::reset);
+    }
+}
+
+static EQUALIZERS: Registry<Mutex<Equalizer>> = Registry::new("equalizer");
+
+fn with_equalizer<T>(handle: Handle, body: impl FnOnce(&mut Equalizer) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let equalizer = EQUALIZERS.get(handle)?;
+    let mut equalizer = equalizer.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut equalizer)
+}
+
+unsafe fn json_arg<T: serde::de::DeserializeOwned>(ptr: *const c_char, name: &str) -> Result<T, AudioRemoteError> {
+    serde_json::from_str(str_arg(ptr, name)?).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid {name}: {e}")))
+}
+
+/// Compute one band's biquad coefficients. `band_json`: {"type": "peaking" | "lowShelf" |
+/// "highShelf" | "lowPass" | "highPass", "frequency", "gainDb", "q" (default 0.7071)}
+/// Returns: JSON {"b": [b0, b1, b2], "a": [a1, a2]} with a0 normalized to 1 (free with
+/// rust_string_free), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `band_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_eq_coefficients(band_json: *const c_char, sample_rate: f64) -> *mut c_char {
+    guard("ar_eq_coefficients", std::ptr::null_mut(), || {
+        let result = json_arg::<Band>(band_json, "band").and_then(|band| {
+            if !(8000.0..=768_000.0).contains(&sample_rate) {
+                return Err(AudioRemoteError::InvalidArgument(format!("sample rate {sample_rate} is outside 8000–768000")));
+            }
+            band.validate(sample_rate)?;
+            serde_json::to_string(&band.biquad(sample_rate)).map_err(|e| AudioRemoteError::Other(e.to_string()))
+        });
+        string_result(result)
+    })
+}
+
+/// Create an equalizer. `config_json`: {"channels" (default 2), "sampleRate" (default 48000),
+/// "bands" [band as for ar_eq_coefficients, plus "enabled" (default true)], "preampDb" (default 0)}
+/// Returns: a handle (free with ar_eq_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_eq_new(config_json: *const c_char) -> Handle {
+    guard("ar_eq_new", 0, || match record(json_arg(config_json, "config").and_then(Equalizer::new)) {
+        Some(equalizer) => EQUALIZERS.insert(Mutex::new(equalizer)),
+        None => 0,
+    })
+}
+
+/// Replace an equalizer's config, as for ar_eq_new
+/// Returns: 1 on success, -999 on error (see last_error_message), leaving the old config in place
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_eq_update(equalizer: Handle, config_json: *const c_char) -> i32 {
+    guard("ar_eq_update", -999, || {
+        let result = json_arg(config_json, "config").and_then(|config| with_equalizer(equalizer, |equalizer| equalizer.update(config)));
+        record(result).map_or(-999, |_| 1)
+    })
+}
+
+/// Filter `frames` frames of interleaved float samples in place
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels writable floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_eq_process(equalizer: Handle, samples: *mut f32, frames: usize) -> i32 {
+    guard("ar_eq_process", -999, || {
+        let result = with_equalizer(equalizer, |equalizer| {
+            let len = frames
+                .checked_mul(equalizer.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len == 0 {
+                return Ok(1);
+            }
+            if samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            equalizer.process(std::slice::from_raw_parts_mut(samples, len));
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Write the equalizer's gain in dB at each of `frequencies` to `out_db`, e.g. to draw its curve
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `frequencies` and `out_db` must each point to `count` doubles, or may be null when `count` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_eq_response(equalizer: Handle, frequencies: *const f64, out_db: *mut f64, count: usize) -> i32 {
+    guard("ar_eq_response", -999, || {
+        let result = with_equalizer(equalizer, |equalizer| {
+            if count == 0 {
+                return Ok(1);
+            }
+            if frequencies.is_null() || out_db.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("frequencies and out_db must not be null".into()));
+            }
+            let frequencies = std::slice::from_raw_parts(frequencies, count);
+            for (out, &hz) in std::slice::from_raw_parts_mut(out_db, count).iter_mut().zip(frequencies) {
+                *out = equalizer.response_db(hz);
+            }
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Clear an equalizer's filter state, e.g. when the tap restarts
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_eq_reset(equalizer: Handle) -> i32 {
+    guard("ar_eq_reset", -999, || {
+        record(with_equalizer(equalizer, |equalizer| {
+            equalizer.reset();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Release an equalizer from ar_eq_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_eq_free(equalizer: Handle) -> i32 {
+    guard("ar_eq_free", -999, || record(EQUALIZERS.remove(equalizer)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+
+    const RATE: f64 = 48_000.0;
+
+    fn band(kind: BandKind, frequency: f64, gain_db: f64) -> Band {
+        Band { kind, frequency, gain_db, q: default_q(), enabled: true }
+    }
+
+    #[test]
+    fn test_band_responses() {
+        let peak = band(BandKind::Peaking, 1000.0, 6.0).biquad(RATE);
+        assert!((peak.response_db(1000.0, RATE) - 6.0).abs() < 1e-9);
+        assert!(peak.response_db(20.0, RATE).abs() < 0.01 && peak.response_db(20_000.0, RATE).abs() < 0.01);
+
+        let low_shelf = band(BandKind::LowShelf, 200.0, -6.0).biquad(RATE);
+        assert!((low_shelf.response_db(20.0, RATE) + 6.0).abs() < 0.05 && low_shelf.response_db(10_000.0, RATE).abs() < 0.01);
+        let high_shelf = band(BandKind::HighShelf, 5000.0, 4.0).biquad(RATE);
+        assert!((high_shelf.response_db(20_000.0, RATE) - 4.0).abs() < 0.1 && high_shelf.response_db(100.0, RATE).abs() < 0.01);
+
+        // Butterworth passes are 3 dB down at the corner
+        let low_pass = band(BandKind::LowPass, 2000.0, 0.0).biquad(RATE);
+        assert!((low_pass.response_db(2000.0, RATE) + 3.0103).abs() < 0.01 && low_pass.response_db(20_000.0, RATE) < -30.0);
+        let high_pass = band(BandKind::HighPass, 80.0, 0.0).biquad(RATE);
+        assert!((high_pass.response_db(80.0, RATE) + 3.0103).abs() < 0.01 && high_pass.response_db(10_000.0, RATE).abs() < 0.01);
+
+        assert!(band(BandKind::Peaking, 30_000.0, 0.0).validate(RATE).is_err());
+        assert!(band(BandKind::Peaking, 1000.0, 40.0).validate(RATE).is_err());
+    }
+
+    #[test]
+    fn test_processing() {
+        let config = EqConfig { channels: 2, sample_rate: RATE, bands: vec![band(BandKind::Peaking, 1000.0, 6.0)], preamp_db: -6.0 };
+        let mut equalizer = Equalizer::new(config.clone()).unwrap();
+        assert!(equalizer.response_db(1000.0).abs() < 1e-9);
+
+        // A 1 kHz tone in the left channel comes out at the same level once settled; the right stays silent
+        let mut samples: Vec<f32> = (0..9600).flat_map(|i| [(2.0 * std::f64::consts::PI * 1000.0 * i as f64 / RATE).sin() as f32 * 0.5, 0.0]).collect();
+        equalizer.process(&mut samples);
+        let settled = &samples[9600..];
+        let peak = settled.chunks(2).map(|frame| frame[0].abs()).fold(0.0, f32::max);
+        assert!((peak - 0.5).abs() < 0.01, "{peak}");
+        assert!(settled.chunks(2).all(|frame| frame[1] == 0.0));
+
+        // Updating the gain keeps the state; disabling the band leaves just the preamp
+        let mut louder = config.clone();
+        louder.bands[0].gain_db = 12.0;
+        equalizer.update(louder).unwrap();
+        assert!((equalizer.response_db(1000.0) - 6.0).abs() < 1e-9);
+        let mut disabled = config;
+        disabled.bands[0].enabled = false;
+        equalizer.update(disabled).unwrap();
+        let mut impulse = vec![1.0, f32::NAN];
+        equalizer.process(&mut impulse);
+        assert!((impulse[0] - 0.5).abs() < 0.01 && impulse[1] == 0.0);
+        assert!(equalizer.update(EqConfig { channels: 0, sample_rate: RATE, bands: vec![], preamp_db: 0.0 }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let coefficients = unsafe { ar_eq_coefficients(c"{\"type\": \"lowPass\", \"frequency\": 1000}".as_ptr(), RATE) };
+        let coefficients: serde_json::Value = serde_json::from_str(&take_c_string(coefficients).unwrap()).unwrap();
+        assert_eq!((coefficients["b"].as_array().unwrap().len(), coefficients["a"].as_array().unwrap().len()), (3, 2));
+        assert!(unsafe { ar_eq_coefficients(c"{\"type\": \"notch\", \"frequency\": 1000}".as_ptr(), RATE) }.is_null());
+
+        let config = c"{\"channels\": 1, \"bands\": [{\"type\": \"highShelf\", \"frequency\": 4000, \"gainDb\": 3}]}";
+        let equalizer = unsafe { ar_eq_new(config.as_ptr()) };
+        assert_ne!(equalizer, 0);
+        let mut samples = [0.5f32, 0.25, -0.5];
+        assert_eq!(unsafe { ar_eq_process(equalizer, samples.as_mut_ptr(), samples.len()) }, 1);
+        assert_ne!(samples, [0.5, 0.25, -0.5]);
+        let (frequencies, mut response) = ([20.0, 20_000.0], [0.0; 2]);
+        assert_eq!(unsafe { ar_eq_response(equalizer, frequencies.as_ptr(), response.as_mut_ptr(), 2) }, 1);
+        assert!(response[0].abs() < 0.01 && (response[1] - 3.0).abs() < 0.1, "{response:?}");
+        assert_eq!(unsafe { ar_eq_update(equalizer, c"{\"preampDb\": 99}".as_ptr()) }, -999);
+        assert_eq!(ar_eq_reset(equalizer), 1);
+        assert_eq!(ar_eq_free(equalizer), 1);
+        assert_eq!(unsafe { ar_eq_process(equalizer, samples.as_mut_ptr(), 1) }, -999);
+    }
+}
@@ -0,0 +1,354 @@
+//! Errors and strings crossing the C boundary.
+//!
+//! String convention: every function returning `char*` hands the caller a
+//! fresh allocation made by Rust (via `into_c_string`), which must be released
+//! with exactly one call to `rust_string_free` — never `free()`, since the Rust
+//! allocator isn't guaranteed to be malloc. NULL means "no string" (usually an
+//! error, see last_error_message) and needs no free. Strings passed *into* the
+//! library are borrowed for the duration of the call only.
+//!
+//! Byte buffers (`uint8_t*` plus an out-parameter length) work the same way and
+//! are released with `ar_bytes_free`, passing the length back.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::ffi::{CString, c_char};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::lifecycle;
+
+/// Every way a call into this library can fail. The discriminant-like codes
+/// from `code()` are part of the C ABI and must never be renumbered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AudioRemoteError {
+    /// A null or non-UTF-8 pointer, an unknown enum value or an out-of-range number
+    InvalidArgument(String),
+    /// A version string that doesn't parse
+    InvalidVersion(String),
+    /// Malformed data from outside: feeds, release JSON, archives, delta files
+    InvalidData(String),
+    /// A bundle that fails validation (Info.plist, executable, identifier)
+    InvalidBundle(String),
+    /// A checksum or signature that doesn't match
+    VerificationFailed(String),
+    Io(String),
+    /// Connection failures and HTTP error statuses
+    Network(String),
+    /// The caller's progress callback asked to stop
+    Cancelled,
+    /// Nothing matched, e.g. no release on the requested channel
+    NotFound(String),
+    /// Refused by policy, e.g. a downgrade without an explicit rollback
+    Refused(String),
+    Unsupported(String),
+    /// A bug: an exported function panicked and the panic was contained
+    Panic(String),
+    /// Called before ar_init or after ar_shutdown
+    NotInitialized(String),
+    Other(String),
+}
+
+impl AudioRemoteError {
+    /// Stable code reported through ar_last_error_code()
+    pub fn code(&self) -> i32 {
+        match self {
+            Self::InvalidArgument(_) => 1,
+            Self::InvalidVersion(_) => 2,
+            Self::InvalidData(_) => 3,
+            Self::InvalidBundle(_) => 4,
+            Self::VerificationFailed(_) => 5,
+            Self::Io(_) => 6,
+            Self::Network(_) => 7,
+            Self::Cancelled => 8,
+            Self::NotFound(_) => 9,
+            Self::Refused(_) => 10,
+            Self::Unsupported(_) => 11,
+            Self::Panic(_) => 12,
+            Self::NotInitialized(_) => 13,
+            Self::Other(_) => 99,
+        }
+    }
+}
+
+impl fmt::Display for AudioRemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cancelled => f.write_str("cancelled"),
+            Self::InvalidArgument(m)
+            | Self::InvalidVersion(m)
+            | Self::InvalidData(m)
+            | Self::InvalidBundle(m)
+            | Self::VerificationFailed(m)
+            | Self::Io(m)
+            | Self::Network(m)
+            | Self::NotFound(m)
+            | Self::Refused(m)
+            | Self::Unsupported(m)
+            | Self::Panic(m)
+            | Self::NotInitialized(m)
+            | Self::Other(m) => f.write_str(m),
+        }
+    }
+}
+
+impl std::error::Error for AudioRemoteError {}
+
+/// Messages that weren't classified where they were raised
+impl From<String> for AudioRemoteError {
+    fn from(message: String) -> Self {
+        Self::Other(message)
+    }
+}
+
+impl From<std::io::Error> for AudioRemoteError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Io(error.to_string())
+    }
+}
+
+impl From<&str> for AudioRemoteError {
+    fn from(message: &str) -> Self {
+        Self::Other(message.to_owned())
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<AudioRemoteError>> = const { RefCell::new(None) };
+}
+
+/// Record the error of the current thread's failing call
+pub(crate) fn set_last_error(error: impl Into<AudioRemoteError>) {
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(error.into()));
+}
+
+/// Forget any error recorded for the current thread
+pub(crate) fn clear_last_error() {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+}
+
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Entry points that work before ar_init: the lifecycle itself, the handshake,
+/// error reporting and logging setup (so init can be logged)
+const USABLE_BEFORE_INIT: &[&str] = &[
+    "ar_init",
+    "ar_shutdown",
+    "ar_ffi_abi_version",
+    "ar_ffi_check_abi",
+    "ar_last_error_code",
+    "ar_last_error_message",
+    "last_error_message",
+    "rust_string_free",
+    "ar_bytes_free",
+    "ar_log_set_callback",
+    "ar_log_set_module_level",
+];
+
+/// Run the body of an exported function, turning a panic into an error result and
+/// refusing calls made before ar_init. Unwinding across `extern "C"` aborts the
+/// process, so every entry point goes through here; `on_error` is what the function
+/// returns for errors (-999, NULL, ...).
+pub(crate) fn guard<T>(name: &str, on_error: T, body: impl FnOnce() -> T) -> T {
+    if !lifecycle::is_initialized() && !USABLE_BEFORE_INIT.contains(&name) {
+        set_last_error(AudioRemoteError::NotInitialized(format!("{name} called before ar_init")));
+        return on_error;
+    }
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(value) => value,
+        Err(payload) => {
+            let message = format!("internal error in {name}: {}", panic_message(payload.as_ref()));
+            log::error!("{message}");
+            set_last_error(AudioRemoteError::Panic(message));
+            on_error
+        }
+    }
+}
+
+/// Convert a Rust string into a C string owned by the caller
+pub(crate) fn into_c_string(s: String) -> *mut c_char {
+    // Interior NULs can't cross the boundary; drop them rather than fail
+    let bytes: Vec<u8> = s.into_bytes().into_iter().filter(|&b| b != 0).collect();
+    CString::new(bytes).map(CString::into_raw).unwrap_or(std::ptr::null_mut())
+}
+
+/// The usual tail of a string-returning export: the string on success, or
+/// NULL with the error recorded
+pub(crate) fn string_result(result: Result<String, AudioRemoteError>) -> *mut c_char {
+    match crate::record(result) {
+        Some(s) => into_c_string(s),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// The usual tail of a buffer-returning export: the bytes with their length in
+/// `out_len`, or NULL (and 0) with the error recorded
+pub(crate) fn bytes_result(result: Result<Vec<u8>, AudioRemoteError>, out_len: *mut usize) -> *mut u8 {
+    let bytes = match out_len.is_null() {
+        true => Err(AudioRemoteError::InvalidArgument("out_len is null".into())),
+        false => result,
+    };
+    match crate::record(bytes) {
+        Some(bytes) => {
+            let bytes = bytes.into_boxed_slice();
+            unsafe { *out_len = bytes.len() };
+            Box::into_raw(bytes) as *mut u8
+        }
+        None => {
+            if !out_len.is_null() {
+                unsafe { *out_len = 0 };
+            }
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Describe why the last failing call on this thread failed
+/// Returns: a newly allocated string (free with rust_string_free), or NULL if there is no error
+#[no_mangle]
+pub extern "C" fn ar_last_error_message() -> *mut c_char {
+    guard("ar_last_error_message", std::ptr::null_mut(), || {
+        LAST_ERROR.with(|e| match e.borrow().as_ref() {
+            Some(error) => into_c_string(error.to_string()),
+            None => std::ptr::null_mut(),
+        })
+    })
+}
+
+/// Code of the last failing call on this thread (see AudioRemoteError::code)
+/// Returns: the error code, or 0 if the last call succeeded
+#[no_mangle]
+pub extern "C" fn ar_last_error_code() -> i32 {
+    guard("ar_last_error_code", -999, || {
+        LAST_ERROR.with(|e| e.borrow().as_ref().map_or(0, AudioRemoteError::code))
+    })
+}
+
+/// Older name of ar_last_error_message, kept for existing callers
+/// Returns: a newly allocated string (free with rust_string_free), or NULL if there is no error
+#[no_mangle]
+pub extern "C" fn last_error_message() -> *mut c_char {
+    guard("last_error_message", std::ptr::null_mut(), || {
+        ar_last_error_message()
+    })
+}
+
+/// Free a string returned by this library; NULL is ignored
+///
+/// # Safety
+/// `ptr` must be null or a pointer previously returned by this library that
+/// has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rust_string_free(ptr: *mut c_char) {
+    guard("rust_string_free", (), || {
+        if !ptr.is_null() {
+            drop(CString::from_raw(ptr));
+        }
+    })
+}
+
+/// Free a byte buffer returned by this library; NULL is ignored
+///
+/// # Safety
+/// `ptr` must be null or a buffer previously returned by this library, with the length it
+/// returned, that has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ar_bytes_free(ptr: *mut u8, len: usize) {
+    guard("ar_bytes_free", (), || {
+        if !ptr.is_null() {
+            drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len)));
+        }
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    /// Copy out and free a string returned by an export, the way Swift does
+    pub(crate) fn take_c_string(ptr: *mut c_char) -> Option<String> {
+        if ptr.is_null() {
+            return None;
+        }
+        let s = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_owned();
+        unsafe { rust_string_free(ptr) };
+        Some(s)
+    }
+
+    #[test]
+    fn test_last_error_round_trip() {
+        clear_last_error();
+        assert!(last_error_message().is_null());
+
+        assert_eq!(ar_last_error_code(), 0);
+
+        set_last_error(AudioRemoteError::InvalidVersion("invalid version \"2.6\"".into()));
+        let ptr = last_error_message();
+        assert_eq!(unsafe { CStr::from_ptr(ptr) }.to_str().unwrap(), "invalid version \"2.6\"");
+        unsafe { rust_string_free(ptr) };
+        assert_eq!(ar_last_error_code(), 2);
+
+        set_last_error(AudioRemoteError::Cancelled);
+        let ptr = ar_last_error_message();
+        assert_eq!(unsafe { CStr::from_ptr(ptr) }.to_str().unwrap(), "cancelled");
+        unsafe { rust_string_free(ptr) };
+
+        // Errors are per-thread
+        std::thread::spawn(|| assert!(last_error_message().is_null())).join().unwrap();
+    }
+
+    #[test]
+    fn test_guard_contains_panics() {
+        clear_last_error();
+        assert_eq!(guard("ok", -999, || 7), 7);
+        assert_eq!(ar_last_error_code(), 0);
+
+        let result = guard("exploding_call", -999, || -> i32 { panic!("index out of bounds") });
+        assert_eq!(result, -999);
+        assert_eq!(ar_last_error_code(), 12);
+        let ptr = ar_last_error_message();
+        let message = unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_owned();
+        unsafe { rust_string_free(ptr) };
+        assert_eq!(message, "internal error in exploding_call: index out of bounds");
+    }
+
+    #[test]
+    fn test_owned_string_round_trip() {
+        assert_eq!(take_c_string(into_c_string("1.2\0.3".into())).as_deref(), Some("1.2.3"));
+        assert_eq!(take_c_string(string_result(Ok("ok".into()))).as_deref(), Some("ok"));
+        assert!(string_result(Err(AudioRemoteError::NotFound("nothing".into()))).is_null());
+        assert_eq!(ar_last_error_code(), 9);
+        unsafe { rust_string_free(std::ptr::null_mut()) };
+    }
+
+    /// Every export returning `char*` must tell the caller to free it with rust_string_free
+    #[test]
+    fn test_string_exports_document_ownership() {
+        let src = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        for entry in std::fs::read_dir(src).unwrap() {
+            let path = entry.unwrap().path();
+            let text = std::fs::read_to_string(&path).unwrap();
+            let lines: Vec<&str> = text.lines().collect();
+            for (i, line) in lines.iter().enumerate() {
+                if !line.contains(concat!("extern ", "\"C\" fn ")) {
+                    continue;
+                }
+                let signature_end = lines[i..].iter().find(|l| l.trim_end().ends_with('{')).unwrap();
+                let free = match signature_end.trim_end() {
+                    end if end.ends_with("-> *mut c_char {") => "rust_string_free",
+                    end if end.ends_with("-> *mut u8 {") => "ar_bytes_free",
+                    _ => continue,
+                };
+                let mut docs = lines[..i].iter().rev().take_while(|l| l.starts_with("///") || l.starts_with("#["));
+                assert!(docs.any(|l| l.contains(free)), "{}:{} doesn't say how to free its result", path.display(), i + 1);
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! State pushed to remotes. Swift publishes the current volume, device list and
+//! now-playing info whenever they change; every subscriber (a WebSocket client,
+//! later other push transports) gets the topics it asked for.
+//!
+//! Subscribers don't queue events, they hold the newest value per topic: a slow
+//! remote skips intermediate volumes instead of falling behind, and a publisher
+//! never blocks on a subscriber.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::c_char;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::handle::{Handle, Registry};
+use crate::remote::{NowPlaying, OutputDevice, VolumeState};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+/// What a remote can subscribe to; values are part of the C ABI
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Topic {
+    Volume = 0,
+    Devices = 1,
+    NowPlaying = 2,
+}
+
+impl Topic {
+    pub const ALL: [Topic; 3] = [Topic::Volume, Topic::Devices, Topic::NowPlaying];
+
+    pub fn from_raw(value: i32) -> Option<Self> {
+        Self::ALL.into_iter().find(|topic| *topic as i32 == value)
+    }
+
+    /// Name used on the wire
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Volume => "volume",
+            Self::Devices => "devices",
+            Self::NowPlaying => "nowPlaying",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Result<Self, AudioRemoteError> {
+        Self::ALL
+            .into_iter()
+            .find(|topic| topic.name() == name)
+            .ok_or(AudioRemoteError::InvalidArgument(format!("unknown topic \"{name}\"")))
+    }
+
+    /// Check published JSON against the topic's type and re-encode it
+    fn decode(self, json: &str) -> Result<Value, AudioRemoteError> {
+        let invalid = |e: serde_json::Error| AudioRemoteError::InvalidArgument(format!("invalid {} state: {e}", self.name()));
+        let value = match self {
+            Self::Volume => serde_json::to_value(serde_json::from_str::<VolumeState>(json).map_err(invalid)?),
+            Self::Devices => serde_json::to_value(serde_json::from_str::<Vec<OutputDevice>>(json).map_err(invalid)?),
+            Self::NowPlaying => serde_json::to_value(serde_json::from_str::<Option<NowPlaying>>(json).map_err(invalid)?),
+        };
+        value.map_err(|e| AudioRemoteError::Other(e.to_string()))
+    }
+}
+
+/// Parse a comma-separated topic list, e.g. from a query string
+pub fn parse_topics(list: &str) -> Result<BTreeSet<Topic>, AudioRemoteError> {
+    list.split(',').map(str::trim).filter(|name| !name.is_empty()).map(Topic::from_name).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub topic: Topic,
+    pub data: Value,
+}
+
+impl Event {
+    /// `{"topic": "volume", "data": {...}}`
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({"topic": self.topic.name(), "data": self.data})
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    topics: BTreeSet<Topic>,
+    latest: BTreeMap<Topic, Value>,
+    closed: bool,
+}
+
+struct Subscriber {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl Subscriber {
+    fn queue(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn offer(&self, topic: Topic, data: &Value) {
+        let mut queue = self.queue();
+        if queue.topics.contains(&topic) {
+            queue.latest.insert(topic, data.clone());
+            self.ready.notify_all();
+        }
+    }
+}
+
+/// The last published state of every topic and everyone subscribed to it
+pub struct Hub {
+    snapshot: Mutex<BTreeMap<Topic, Value>>,
+    subscribers: Registry<Subscriber>,
+}
+
+pub static HUB: Hub = Hub::new();
+
+impl Hub {
+    pub const fn new() -> Self {
+        Self { snapshot: Mutex::new(BTreeMap::new()), subscribers: Registry::new("subscription") }
+    }
+
+    fn snapshot(&self) -> MutexGuard<'_, BTreeMap<Topic, Value>> {
+        self.snapshot.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn publish(&self, topic: Topic, data: Value) {
+        // Holding the snapshot lock orders publishes with subscribe()'s initial state
+        let mut snapshot = self.snapshot();
+        for subscriber in self.subscribers.all() {
+            subscriber.offer(topic, &data);
+        }
+        snapshot.insert(topic, data);
+    }
+
+    /// Subscribe to `topics`; the current state of each is delivered first
+    pub fn subscribe(&'static self, topics: BTreeSet<Topic>) -> Subscription {
+        let subscriber = Arc::new(Subscriber { queue: Mutex::new(Queue::default()), ready: Condvar::new() });
+        let subscription = Subscription { hub: self, handle: self.subscribers.insert_shared(subscriber.clone()), subscriber };
+        subscription.set_topics(topics);
+        subscription
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// End every subscription, e.g. when the server stops
+    pub fn close_all(&self) {
+        for subscriber in self.subscribers.all() {
+            subscriber.queue().closed = true;
+            subscriber.ready.notify_all();
+        }
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One subscriber's view of the hub; unsubscribes when dropped
+pub struct Subscription {
+    hub: &'static Hub,
+    handle: Handle,
+    subscriber: Arc<Subscriber>,
+}
+
+impl Subscription {
+    /// Replace the subscribed topics; topics not subscribed before get their current state
+    pub fn set_topics(&self, topics: BTreeSet<Topic>) {
+        let snapshot = self.hub.snapshot();
+        let mut queue = self.subscriber.queue();
+        let added: Vec<Topic> = topics.difference(&queue.topics).copied().collect();
+        for topic in added {
+            if let Some(data) = snapshot.get(&topic) {
+                queue.latest.insert(topic, data.clone());
+            }
+        }
+        queue.latest.retain(|topic, _| topics.contains(topic));
+        queue.topics = topics;
+        self.subscriber.ready.notify_all();
+    }
+
+    pub fn topics(&self) -> BTreeSet<Topic> {
+        self.subscriber.queue().topics.clone()
+    }
+
+    /// Wait up to `timeout` for events: None once closed, an empty list on timeout
+    pub fn next(&self, timeout: Duration) -> Option<Vec<Event>> {
+        let queue = self.subscriber.queue();
+        let (mut queue, _) = self
+            .subscriber
+            .ready
+            .wait_timeout_while(queue, timeout, |queue| queue.latest.is_empty() && !queue.closed)
+            .unwrap_or_else(|e| e.into_inner());
+        if queue.closed {
+            return None;
+        }
+        Some(std::mem::take(&mut queue.latest).into_iter().map(|(topic, data)| Event { topic, data }).collect())
+    }
+
+    /// Wake `next` with None, e.g. when the client disconnected
+    pub fn close(&self) {
+        self.subscriber.queue().closed = true;
+        self.subscriber.ready.notify_all();
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let _ = self.hub.subscribers.remove(self.handle);
+    }
+}
+
+/// Publish the new state of a topic (an ArStateTopic) to every subscribed remote. `state_json`
+/// has the same shape as the matching REST result: VolumeState, an array of OutputDevice, or
+/// NowPlaying/null. Call it on every change; it never blocks on slow remotes.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `state_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_state_publish(topic: i32, state_json: *const c_char) -> i32 {
+    guard("ar_state_publish", -999, || {
+        let result = Topic::from_raw(topic)
+            .ok_or(AudioRemoteError::InvalidArgument(format!("unknown topic {topic}")))
+            .and_then(|topic| Ok((topic, topic.decode(str_arg(state_json, "state")?)?)));
+        match record(result) {
+            Some((topic, data)) => {
+                HUB.publish(topic, data);
+                1
+            }
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_subscriptions_filter_and_coalesce() {
+        static LOCAL: Hub = Hub::new();
+        LOCAL.publish(Topic::Volume, json!({"volume": 0.1, "muted": false}));
+        let volume = LOCAL.subscribe(parse_topics("volume").unwrap());
+        let everything = LOCAL.subscribe(Topic::ALL.into_iter().collect());
+        let short = Duration::from_millis(10);
+
+        // Current state first, then only the newest value per topic
+        assert_eq!(volume.next(short).unwrap(), vec![Event { topic: Topic::Volume, data: json!({"volume": 0.1, "muted": false}) }]);
+        LOCAL.publish(Topic::Volume, json!({"volume": 0.2, "muted": false}));
+        LOCAL.publish(Topic::Volume, json!({"volume": 0.3, "muted": false}));
+        LOCAL.publish(Topic::NowPlaying, Value::Null);
+        assert_eq!(volume.next(short).unwrap(), vec![Event { topic: Topic::Volume, data: json!({"volume": 0.3, "muted": false}) }]);
+        assert_eq!(volume.next(short).unwrap(), vec![]);
+        let topics: Vec<Topic> = everything.next(short).unwrap().into_iter().map(|e| e.topic).collect();
+        assert_eq!(topics, vec![Topic::Volume, Topic::NowPlaying]);
+
+        volume.set_topics(parse_topics("volume, nowPlaying").unwrap());
+        assert_eq!(volume.next(short).unwrap(), vec![Event { topic: Topic::NowPlaying, data: Value::Null }]);
+        assert!(matches!(parse_topics("volume,bass"), Err(AudioRemoteError::InvalidArgument(m)) if m == "unknown topic \"bass\""));
+
+        drop(everything);
+        assert_eq!(LOCAL.subscriber_count(), 1);
+        LOCAL.close_all();
+        assert_eq!(volume.next(short), None);
+    }
+
+    #[test]
+    fn test_published_state_is_validated() {
+        assert!(Topic::Volume.decode(r#"{"volume": 0.5, "muted": true}"#).is_ok());
+        assert!(Topic::Devices.decode(r#"[{"id": "a", "name": "Speakers"}]"#).is_err());
+        assert_eq!(Topic::NowPlaying.decode("null").unwrap(), Value::Null);
+        assert_eq!(unsafe { ar_state_publish(7, c"{}".as_ptr()) }, -999);
+    }
+}
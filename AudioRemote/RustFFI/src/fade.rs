@@ -0,0 +1,346 @@
+//! Volume fades: sleep-timer fade-outs, crossfades when the output device
+//! changes and gentle unmutes. A fade runs on its own thread and hands the
+//! app a gain every `stepMs` until it reaches the target; the app sets each
+//! one on the device.
+//!
+//! Gains are 0.0–1.0. A `linear` fade moves the gain evenly, which sounds
+//! like nothing happens until the very end of a fade-out; `exponential` (the
+//! default) moves evenly through the exponential volume taper, the same
+//! perceived change every step; `sCurve` does that too but eases in and out.
+
+use std::ffi::{c_char, c_void};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::handle::{Handle, Registry};
+use crate::runtime::{self, SendPtr};
+use crate::volume_curve::Taper;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum FadeCurve {
+    Linear,
+    #[default]
+    Exponential,
+    SCurve,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct FadeConfig {
+    pub from: f64,
+    pub to: f64,
+    pub duration_ms: f64,
+    #[serde(default)]
+    pub curve: FadeCurve,
+    #[serde(default = "default_step_ms")]
+    pub step_ms: f64,
+    /// The taper's span for exponential and s-curve fades, as for ar_volume_convert
+    #[serde(default = "default_range_db")]
+    pub range_db: f64,
+}
+
+/// 50 steps a second, smoother than anyone hears
+fn default_step_ms() -> f64 {
+    20.0
+}
+
+fn default_range_db() -> f64 {
+    60.0
+}
+
+/// The gain over the course of a fade
+#[derive(Debug, Clone)]
+pub struct Ramp {
+    config: FadeConfig,
+    taper: Taper,
+}
+
+impl Ramp {
+    pub fn new(config: FadeConfig) -> Result<Self, AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        for (name, gain) in [("from", config.from), ("to", config.to)] {
+            if !(0.0..=1.0).contains(&gain) {
+                return invalid(format!("{name} {gain} is outside 0–1"));
+            }
+        }
+        if !(0.0..=3_600_000.0).contains(&config.duration_ms) {
+            return invalid(format!("durationMs {} is outside 0–3600000", config.duration_ms));
+        }
+        if !(5.0..=1000.0).contains(&config.step_ms) {
+            return invalid(format!("stepMs {} is outside 5–1000", config.step_ms));
+        }
+        let taper = Taper { range_db: config.range_db, ..Taper::default() };
+        taper.validate()?;
+        Ok(Self { config, taper })
+    }
+
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs_f64(self.config.duration_ms / 1000.0)
+    }
+
+    pub fn step(&self) -> Duration {
+        Duration::from_secs_f64(self.config.step_ms / 1000.0)
+    }
+
+    /// The gain `elapsed` into the fade: exactly `from` at the start and `to` from the end on
+    pub fn gain(&self, elapsed: Duration) -> f64 {
+        let FadeConfig { from, to, .. } = self.config;
+        let t = match self.config.duration_ms > 0.0 {
+            true => (elapsed.as_secs_f64() * 1000.0 / self.config.duration_ms).min(1.0),
+            false => 1.0,
+        };
+        if t >= 1.0 {
+            return to;
+        }
+        if t <= 0.0 {
+            return from;
+        }
+        let along = |t: f64| {
+            let (start, end) = (self.taper.position(from), self.taper.position(to));
+            self.taper.gain(start + (end - start) * t)
+        };
+        match self.config.curve {
+            FadeCurve::Linear => from + (to - from) * t,
+            FadeCurve::Exponential => along(t),
+            FadeCurve::SCurve => along(t * t * (3.0 - 2.0 * t)),
+        }
+    }
+}
+
+/// (fade, gain, finished, ctx): set `gain` now; `finished` on the last step, at the target
+pub type FadeCallback = extern "C" fn(fade: Handle, gain: f64, finished: bool, ctx: *mut c_void);
+
+pub struct Fade {
+    /// The last gain handed out, as f64 bits
+    gain: AtomicU64,
+    finished: AtomicBool,
+    stop: Mutex<Sender<()>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+static FADES: Registry<Fade> = Registry::new("fade");
+
+fn run(fade: Arc<Fade>, handle: Handle, ramp: Ramp, stop: Receiver<()>, callback: FadeCallback, ctx: SendPtr) {
+    let started = Instant::now();
+    let (mut elapsed, mut last) = (Duration::ZERO, None);
+    loop {
+        let gain = ramp.gain(elapsed);
+        let finished = elapsed >= ramp.duration();
+        fade.gain.store(gain.to_bits(), Ordering::Release);
+        fade.finished.store(finished, Ordering::Release);
+        // A step that doesn't change the gain isn't worth a call
+        if finished || last != Some(gain) {
+            runtime::deliver(move || callback(handle, gain, finished, ctx.get()));
+            last = Some(gain);
+        }
+        if finished {
+            return;
+        }
+        match stop.recv_timeout(ramp.step().min(ramp.duration() - elapsed)) {
+            Err(RecvTimeoutError::Timeout) => {}
+            Ok(()) | Err(RecvTimeoutError::Disconnected) => return,
+        }
+        elapsed = started.elapsed();
+    }
+}
+
+/// Start a fade; `callback` gets each step
+pub(crate) fn start(config: FadeConfig, callback: FadeCallback, ctx: SendPtr) -> Result<Handle, AudioRemoteError> {
+    let ramp = Ramp::new(config)?;
+    let (sender, stop) = mpsc::channel();
+    let fade = Arc::new(Fade {
+        gain: AtomicU64::new(ramp.config.from.to_bits()),
+        finished: AtomicBool::new(false),
+        stop: Mutex::new(sender),
+        thread: Mutex::new(None),
+    });
+    let handle = FADES.insert_shared(fade.clone());
+    let spawned = {
+        let fade = fade.clone();
+        thread::Builder::new().name("audioremote-fade".into()).spawn(move || run(fade, handle, ramp, stop, callback, ctx))
+    };
+    match spawned {
+        Ok(thread) => {
+            *fade.thread.lock().unwrap_or_else(|e| e.into_inner()) = Some(thread);
+            Ok(handle)
+        }
+        Err(e) => {
+            let _ = FADES.remove(handle);
+            Err(AudioRemoteError::Other(format!("can't start the fade thread: {e}")))
+        }
+    }
+}
+
+/// The last gain handed out, and whether the fade has finished
+pub fn gain(handle: Handle) -> Result<(f64, bool), AudioRemoteError> {
+    let fade = FADES.get(handle)?;
+    Ok((f64::from_bits(fade.gain.load(Ordering::Acquire)), fade.finished.load(Ordering::Acquire)))
+}
+
+fn shut(fade: &Fade) {
+    let _ = fade.stop.lock().unwrap_or_else(|e| e.into_inner()).send(());
+    let thread = fade.thread.lock().unwrap_or_else(|e| e.into_inner()).take();
+    // A callback may stop its own fade, on the fade's thread
+    if let Some(thread) = thread.filter(|thread| thread.thread().id() != thread::current().id()) {
+        let _ = thread.join();
+    }
+}
+
+/// Stop a fade where it is; returns the last gain handed out
+pub fn stop(handle: Handle) -> Result<f64, AudioRemoteError> {
+    let fade = FADES.remove(handle)?;
+    shut(&fade);
+    Ok(f64::from_bits(fade.gain.load(Ordering::Acquire)))
+}
+
+/// Stop every fade; done at shutdown
+pub fn close_all() {
+    for fade in FADES.all() {
+        shut(&fade);
+    }
+}
+
+/// Start fading the volume. `config_json`: {"from", "to" (gains, 0–1), "durationMs", "curve":
+/// "exponential" (default) | "sCurve" | "linear", "stepMs" (default 20), "rangeDb" (the taper's
+/// span for exponential and s-curve fades, default 60)}. `callback` gets the starting gain at once,
+/// then each step, the last with `finished` and the target.
+/// Returns: a handle (free with ar_fade_stop, finished or not), 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string; `ctx` is passed to `callback` as is,
+/// from the fade's thread or the callback queue.
+#[no_mangle]
+pub unsafe extern "C" fn ar_fade_start(config_json: *const c_char, callback: Option<FadeCallback>, ctx: *mut c_void) -> Handle {
+    guard("ar_fade_start", 0, || {
+        let result = str_arg(config_json, "config").and_then(|json| {
+            let config = serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid fade config: {e}")))?;
+            let callback = callback.ok_or(AudioRemoteError::InvalidArgument("callback must not be null".into()))?;
+            start(config, callback, SendPtr(ctx))
+        });
+        record(result).unwrap_or(0)
+    })
+}
+
+/// The gain the fade last handed out, written to `out_gain`
+/// Returns: 1 while fading, 0 once finished, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `out_gain` must point to a writable double.
+#[no_mangle]
+pub unsafe extern "C" fn ar_fade_gain(fade: Handle, out_gain: *mut f64) -> i32 {
+    guard("ar_fade_gain", -999, || {
+        let result = match out_gain.is_null() {
+            true => Err(AudioRemoteError::InvalidArgument("out_gain is null".into())),
+            false => gain(fade),
+        };
+        match record(result) {
+            Some((gain, finished)) => {
+                *out_gain = gain;
+                i32::from(!finished)
+            }
+            None => -999,
+        }
+    })
+}
+
+/// Stop a fade where it is, e.g. to fade back from there, and free the handle; no more steps follow
+/// from the fade's thread. `out_gain` (nullable) gets the gain it got to.
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+///
+/// # Safety
+/// `out_gain` must be null or point to a writable double.
+#[no_mangle]
+pub unsafe extern "C" fn ar_fade_stop(fade: Handle, out_gain: *mut f64) -> i32 {
+    guard("ar_fade_stop", -999, || match record(stop(fade)) {
+        Some(gain) => {
+            if !out_gain.is_null() {
+                *out_gain = gain;
+            }
+            1
+        }
+        None => -999,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::volume_curve;
+
+    fn ramp(from: f64, to: f64, curve: FadeCurve) -> Ramp {
+        Ramp::new(FadeConfig { from, to, duration_ms: 1000.0, curve, step_ms: default_step_ms(), range_db: default_range_db() }).unwrap()
+    }
+
+    fn at(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn test_curves() {
+        let linear = ramp(1.0, 0.0, FadeCurve::Linear);
+        assert_eq!((linear.gain(at(0)), linear.gain(at(250)), linear.gain(at(2000))), (1.0, 0.75, 0.0));
+        // Exponential fades lose the same dB each step: half way down a 60 dB taper is -30 dB
+        let exponential = ramp(1.0, 0.0, FadeCurve::Exponential);
+        assert!((volume_curve::gain_to_db(exponential.gain(at(500))) + 30.0).abs() < 1e-9);
+        assert!((volume_curve::gain_to_db(exponential.gain(at(300))) + 18.0).abs() < 1e-9);
+        // The s-curve starts and ends slowly and crosses the middle with the exponential
+        let s_curve = ramp(1.0, 0.0, FadeCurve::SCurve);
+        assert!(s_curve.gain(at(100)) > exponential.gain(at(100)) && s_curve.gain(at(900)) < exponential.gain(at(900)));
+        assert!((s_curve.gain(at(500)) - exponential.gain(at(500))).abs() < 1e-12);
+        for curve in [FadeCurve::Linear, FadeCurve::Exponential, FadeCurve::SCurve] {
+            let up = ramp(0.1, 0.8, curve);
+            let gains: Vec<f64> = (0..=50).map(|i| up.gain(at(i * 20))).collect();
+            assert!(gains.windows(2).all(|pair| pair[0] < pair[1]), "{curve:?}: {gains:?}");
+            assert_eq!((gains[0], gains[50]), (0.1, 0.8));
+        }
+        let instant = Ramp::new(FadeConfig { duration_ms: 0.0, ..ramp(0.2, 0.6, FadeCurve::Linear).config }).unwrap();
+        assert_eq!(instant.gain(at(0)), 0.6);
+        assert!(Ramp::new(FadeConfig { to: 1.5, ..ramp(0.0, 1.0, FadeCurve::Linear).config }).is_err());
+    }
+
+    static STEPS: Mutex<Vec<(Handle, f64, bool)>> = Mutex::new(vec![]);
+
+    extern "C" fn on_step(fade: Handle, gain: f64, finished: bool, _ctx: *mut c_void) {
+        STEPS.lock().unwrap().push((fade, gain, finished));
+    }
+
+    #[test]
+    fn test_ffi() {
+        let _runtime = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        STEPS.lock().unwrap().clear();
+        let config = c"{\"from\": 0, \"to\": 1, \"durationMs\": 200, \"stepMs\": 10, \"curve\": \"sCurve\"}";
+        let fade = unsafe { ar_fade_start(config.as_ptr(), Some(on_step), std::ptr::null_mut()) };
+        assert_ne!(fade, 0);
+        let mut gain = -1.0;
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while unsafe { ar_fade_gain(fade, &mut gain) } == 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(gain, 1.0);
+        let steps = STEPS.lock().unwrap().clone();
+        assert!(steps.len() > 5 && steps.iter().all(|step| step.0 == fade), "{steps:?}");
+        assert_eq!((steps[0].1, steps[0].2), (0.0, false));
+        assert_eq!(steps.last().map(|step| (step.1, step.2)), Some((1.0, true)));
+        assert!(steps.windows(2).all(|pair| pair[0].1 < pair[1].1), "{steps:?}");
+        assert_eq!(unsafe { ar_fade_stop(fade, std::ptr::null_mut()) }, 1);
+
+        // Stopped part way, it says where it got to and goes quiet
+        let config = c"{\"from\": 1, \"to\": 0, \"durationMs\": 60000}";
+        let fade = unsafe { ar_fade_start(config.as_ptr(), Some(on_step), std::ptr::null_mut()) };
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(unsafe { ar_fade_stop(fade, &mut gain) }, 1);
+        assert!(gain < 1.0 && gain > 0.9, "{gain}");
+        let count = STEPS.lock().unwrap().len();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(STEPS.lock().unwrap().len(), count);
+        assert_eq!(unsafe { ar_fade_gain(fade, &mut gain) }, -999);
+        assert_eq!(unsafe { ar_fade_start(c"{\"from\": 0, \"to\": 1}".as_ptr(), Some(on_step), std::ptr::null_mut()) }, 0);
+    }
+}
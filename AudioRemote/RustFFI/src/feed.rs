@@ -0,0 +1,213 @@
+//! Fetching release feeds (appcast XML, GitHub releases JSON) with conditional
+//! requests. The last response for each URL is cached on disk with its ETag and
+//! Last-Modified, so a periodic check that hits an unchanged feed costs a 304.
+
+use std::ffi::{c_char, c_void};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::checksum::to_hex;
+use crate::error::into_c_string;
+use crate::handle::Handle;
+use crate::runtime::{self, JobCompletionCallback, JobContext};
+use crate::{guard, http, record, store, str_arg, AudioRemoteError};
+
+/// Feeds are small; anything bigger is a misbehaving server
+const MAX_FEED_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    url: String,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    body: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feed {
+    pub body: String,
+    /// The server answered 304 and `body` came from the cache
+    pub not_modified: bool,
+}
+
+fn cache_path(cache_dir: &Path, url: &str) -> PathBuf {
+    cache_dir.join(format!("{}.json", to_hex(&Sha256::digest(url.as_bytes()))))
+}
+
+fn load_entry(path: &Path, url: &str) -> Option<CacheEntry> {
+    let entry: CacheEntry = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+    (entry.url == url).then_some(entry)
+}
+
+fn save_entry(path: &Path, entry: &CacheEntry) -> Result<(), AudioRemoteError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| AudioRemoteError::Io(format!("can't create {}: {e}", dir.display())))?;
+    }
+    let tmp = path.with_extension("json.tmp");
+    let data = serde_json::to_vec(entry).map_err(|e| e.to_string())?;
+    fs::write(&tmp, data).map_err(|e| AudioRemoteError::Io(format!("can't write {}: {e}", tmp.display())))?;
+    fs::rename(&tmp, path).map_err(|e| AudioRemoteError::Io(format!("can't replace {}: {e}", path.display())))
+}
+
+/// GET `url`, revalidating against the copy cached in `cache_dir`
+pub fn fetch(agent: &ureq::Agent, url: &str, cache_dir: &Path) -> Result<Feed, AudioRemoteError> {
+    let path = cache_path(cache_dir, url);
+    let cached = load_entry(&path, url);
+
+    let mut request = agent.get(url);
+    if let Some(entry) = &cached {
+        if let Some(etag) = &entry.etag {
+            request = request.set("If-None-Match", etag);
+        }
+        if let Some(modified) = &entry.last_modified {
+            request = request.set("If-Modified-Since", modified);
+        }
+    }
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(ureq::Error::Status(code, _)) => return Err(AudioRemoteError::Network(format!("HTTP {code} from {url}"))),
+        Err(e) => return Err(AudioRemoteError::Network(format!("can't fetch {url}: {e}"))),
+    };
+
+    if response.status() == 304 {
+        log::debug!("{url} not modified");
+        return match cached {
+            Some(entry) => Ok(Feed { body: entry.body, not_modified: true }),
+            None => Err(AudioRemoteError::Network(format!("{url} answered 304 to an unconditional request"))),
+        };
+    }
+
+    let etag = response.header("ETag").map(str::to_owned);
+    let last_modified = response.header("Last-Modified").map(str::to_owned);
+    let mut body = String::new();
+    response
+        .into_reader()
+        .take(MAX_FEED_SIZE)
+        .read_to_string(&mut body)
+        .map_err(|e| AudioRemoteError::Network(format!("can't read response from {url}: {e}")))?;
+
+    if etag.is_some() || last_modified.is_some() {
+        let entry = CacheEntry { url: url.to_owned(), etag, last_modified, body: body.clone() };
+        // A cache we can't write only costs a full download next time
+        let _ = save_entry(&path, &entry);
+    } else {
+        let _ = fs::remove_file(&path);
+    }
+    Ok(Feed { body, not_modified: false })
+}
+
+/// Fetch a release feed (appcast or GitHub releases JSON) with ETag/If-Modified-Since caching
+/// The cache lives in "feed-cache" under the store directory (see ar_store_set_directory).
+/// `not_modified` (nullable) is set to true when the server answered 304 and the body is the cached copy.
+/// Returns: response body (free with rust_string_free), or NULL on error (see last_error_message)
+///
+/// # Safety
+/// `url_ptr` must point to a valid NUL-terminated string; `not_modified` must be null
+/// or point to writable storage for one bool.
+#[no_mangle]
+pub unsafe extern "C" fn update_fetch_feed(url_ptr: *const c_char, not_modified: *mut bool) -> *mut c_char {
+    guard("update_fetch_feed", std::ptr::null_mut(), || {
+        if !not_modified.is_null() {
+            *not_modified = false;
+        }
+        let result = str_arg(url_ptr, "url").and_then(|url| fetch(&http::agent(), url, &store::directory().join("feed-cache")));
+        match record(result) {
+            Some(feed) => {
+                if !not_modified.is_null() {
+                    *not_modified = feed.not_modified;
+                }
+                into_c_string(feed.body)
+            }
+            None => std::ptr::null_mut(),
+        }
+    })
+}
+
+/// Start update_fetch_feed on the background runtime (see ar_init)
+/// `completion` receives the response body; whether it came from the cache isn't reported.
+/// Returns: a job handle, or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `url_ptr` must point to a valid NUL-terminated string; `completion` and `ctx` must
+/// stay valid until `completion` has been called.
+#[no_mangle]
+pub unsafe extern "C" fn ar_fetch_feed_async(
+    url_ptr: *const c_char,
+    completion: Option<JobCompletionCallback>,
+    ctx: *mut c_void,
+) -> Handle {
+    guard("ar_fetch_feed_async", 0, || {
+        let result = str_arg(url_ptr, "url").and_then(|url| {
+            let url = url.to_owned();
+            let job = move |_: &JobContext| Ok(fetch(&http::agent(), &url, &store::directory().join("feed-cache"))?.body);
+            runtime::spawn(job, None, completion, ctx)
+        });
+        record(result).unwrap_or(0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::tests::temp_dir;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Serve `body` with an ETag, answering 304 to a matching If-None-Match
+    fn serve_with_etag(body: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/appcast.xml", listener.local_addr().unwrap());
+        let full_responses = Arc::new(AtomicUsize::new(0));
+        let counter = full_responses.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut revalidated = false;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    revalidated |= line.to_ascii_lowercase().starts_with("if-none-match: \"v1\"");
+                }
+                let response = match revalidated {
+                    true => "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n".to_owned(),
+                    false => {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        format!("HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: {}\r\n\r\n{body}", body.len())
+                    }
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        (url, full_responses)
+    }
+
+    #[test]
+    fn test_fetch_revalidates_with_etag() {
+        let (url, full_responses) = serve_with_etag("<rss/>");
+        let cache = temp_dir("feed-cache");
+        let agent = http::agent();
+
+        let first = fetch(&agent, &url, &cache).unwrap();
+        assert_eq!(first, Feed { body: "<rss/>".into(), not_modified: false });
+        let second = fetch(&agent, &url, &cache).unwrap();
+        assert_eq!(second, Feed { body: "<rss/>".into(), not_modified: true });
+        assert_eq!(full_responses.load(Ordering::SeqCst), 1);
+
+        // Losing the cache falls back to a full download
+        fs::remove_dir_all(&cache).unwrap();
+        assert!(!fetch(&agent, &url, &cache).unwrap().not_modified);
+        assert_eq!(full_responses.load(Ordering::SeqCst), 2);
+        fs::remove_dir_all(&cache).unwrap();
+    }
+}
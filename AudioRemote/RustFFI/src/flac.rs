@@ -0,0 +1,289 @@
+//! FLAC encoding for bit-perfect monitoring on a remote, the lossless
+//! alternative to Opus for sessions that negotiated `FEATURE_LOSSLESS`. The
+//! stream is the FLAC header (`fLaC` and STREAMINFO) followed by one frame
+//! per block, so a remote can start decoding as soon as it has the header and
+//! the first frame. Each block is coded on its own, and the stream is live,
+//! so STREAMINFO leaves the length, frame sizes and MD5 unknown.
+//!
+//! The tap's float samples are quantised to `bitsPerSample` with `dither` (see
+//! dither.rs); 24 bits holds anything Core Audio's float mixer passes through
+//! from 16- or 24-bit sources exactly. Samples outside ±1.0 are clipped.
+
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use flacenc::bitsink::MemSink;
+use flacenc::component::{BitRepr, Stream, StreamInfo};
+use flacenc::error::Verify;
+use flacenc::source::{Fill, FrameBuf};
+use serde::Deserialize;
+
+use crate::dither::{DitherMode, Quantizer};
+use crate::error::bytes_result;
+use crate::handle::{Handle, Registry};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct FlacConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: u32,
+    #[serde(default = "default_bits_per_sample")]
+    pub bits_per_sample: u32,
+    /// Frames per FLAC frame
+    #[serde(default = "default_block_size")]
+    pub block_size: u32,
+    #[serde(default)]
+    pub dither: DitherMode,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> u32 {
+    48_000
+}
+
+fn default_bits_per_sample() -> u32 {
+    24
+}
+
+/// 21 ms at 48 kHz: small enough for monitoring, large enough to compress well
+fn default_block_size() -> u32 {
+    1024
+}
+
+impl Default for FlacConfig {
+    fn default() -> Self {
+        Self {
+            channels: default_channels(),
+            sample_rate: default_sample_rate(),
+            bits_per_sample: default_bits_per_sample(),
+            block_size: default_block_size(),
+            dither: DitherMode::default(),
+        }
+    }
+}
+
+impl FlacConfig {
+    fn validate(&self) -> Result<(), AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=8).contains(&self.channels) {
+            return invalid(format!("channels {} is outside 1–8", self.channels));
+        }
+        if !(8000..=384_000).contains(&self.sample_rate) {
+            return invalid(format!("sampleRate {} is outside 8000–384000", self.sample_rate));
+        }
+        if ![16, 24].contains(&self.bits_per_sample) {
+            return invalid(format!("bitsPerSample {} must be 16 or 24", self.bits_per_sample));
+        }
+        // The streamable subset, which every decoder handles
+        if !(64..=4608).contains(&self.block_size) {
+            return invalid(format!("blockSize {} is outside 64–4608", self.block_size));
+        }
+        Ok(())
+    }
+}
+
+fn encode_error(e: impl std::fmt::Debug) -> AudioRemoteError {
+    AudioRemoteError::Other(format!("can't encode FLAC: {e:?}"))
+}
+
+pub struct FlacEncoder {
+    config: FlacConfig,
+    coder: flacenc::error::Verified<flacenc::config::Encoder>,
+    stream_info: StreamInfo,
+    block: FrameBuf,
+    quantizer: Quantizer,
+    /// The block's samples as integers
+    pcm: Vec<i32>,
+    frame_number: usize,
+}
+
+impl FlacEncoder {
+    pub fn new(config: &FlacConfig) -> Result<Self, AudioRemoteError> {
+        config.validate()?;
+        let (channels, block_size) = (config.channels as usize, config.block_size as usize);
+        let mut coder = flacenc::config::Encoder::default();
+        coder.block_size = block_size;
+        let coder = coder.into_verified().map_err(|(_, e)| encode_error(e))?;
+        let mut stream_info = StreamInfo::new(config.sample_rate as usize, channels, config.bits_per_sample as usize).map_err(encode_error)?;
+        stream_info.set_block_sizes(block_size, block_size).map_err(encode_error)?;
+        Ok(Self {
+            config: config.clone(),
+            coder,
+            stream_info,
+            block: FrameBuf::with_size(channels, block_size).map_err(encode_error)?,
+            quantizer: Quantizer::new(config.dither, config.bits_per_sample, channels),
+            pcm: Vec::with_capacity(block_size * channels),
+            frame_number: 0,
+        })
+    }
+
+    pub fn config(&self) -> &FlacConfig {
+        &self.config
+    }
+
+    /// The stream's header, `fLaC` and STREAMINFO, which a remote needs before any frame
+    pub fn header(&self) -> Result<Vec<u8>, AudioRemoteError> {
+        let mut sink = MemSink::<u8>::new();
+        Stream::with_stream_info(self.stream_info.clone()).write(&mut sink).map_err(encode_error)?;
+        Ok(sink.into_inner())
+    }
+
+    /// Encode one block of interleaved samples (exactly blockSize × channels) as a FLAC frame
+    pub fn encode(&mut self, samples: &[f32]) -> Result<Vec<u8>, AudioRemoteError> {
+        let block_size = self.config.block_size as usize;
+        if samples.len() != block_size * self.config.channels as usize {
+            return Err(AudioRemoteError::InvalidArgument(format!("a block is {block_size} frames")));
+        }
+        self.pcm.clear();
+        self.quantizer.quantize(samples, &mut self.pcm);
+        self.block.fill_interleaved(&self.pcm).map_err(encode_error)?;
+        let frame = flacenc::encode_fixed_size_frame(&self.coder, &self.block, self.frame_number, &self.stream_info).map_err(encode_error)?;
+        self.frame_number += 1;
+        let mut sink = MemSink::<u8>::with_capacity(frame.count_bits());
+        frame.write(&mut sink).map_err(encode_error)?;
+        Ok(sink.into_inner())
+    }
+}
+
+static ENCODERS: Registry<Mutex<FlacEncoder>> = Registry::new("FLAC encoder");
+
+fn with_encoder<T>(handle: Handle, body: impl FnOnce(&mut FlacEncoder) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let encoder = ENCODERS.get(handle)?;
+    let mut encoder = encoder.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut encoder)
+}
+
+unsafe fn config_arg(config_json: *const c_char) -> Result<FlacConfig, AudioRemoteError> {
+    match config_json.is_null() {
+        true => Ok(FlacConfig::default()),
+        false => serde_json::from_str(str_arg(config_json, "config")?)
+            .map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid FLAC config: {e}"))),
+    }
+}
+
+/// Create a FLAC encoder. `config_json` (nullable): {"channels" (1–8, default 2), "sampleRate"
+/// (default 48000), "bitsPerSample" (16 or 24, default 24), "blockSize" (frames per FLAC frame,
+/// 64–4608, default 1024), "dither" ("tpdf" (default) | "shaped" | "none")}
+/// Returns: a handle (free with ar_flac_encoder_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_flac_encoder_new(config_json: *const c_char) -> Handle {
+    guard("ar_flac_encoder_new", 0, || match record(config_arg(config_json).and_then(|config| FlacEncoder::new(&config))) {
+        Some(encoder) => ENCODERS.insert(Mutex::new(encoder)),
+        None => 0,
+    })
+}
+
+/// The stream header (`fLaC` and STREAMINFO), to send before the first frame
+/// Returns: the bytes (free with ar_bytes_free, passing `out_len`), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `out_len` must point to a writable size_t.
+#[no_mangle]
+pub unsafe extern "C" fn ar_flac_header(encoder: Handle, out_len: *mut usize) -> *mut u8 {
+    guard("ar_flac_header", std::ptr::null_mut(), || bytes_result(with_encoder(encoder, |encoder| encoder.header()), out_len))
+}
+
+/// Encode one block: `frames` (which must be the blockSize) frames of interleaved float samples
+/// Returns: the FLAC frame (free with ar_bytes_free, passing `out_len`), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels floats and `out_len` to a writable size_t.
+#[no_mangle]
+pub unsafe extern "C" fn ar_flac_encode(encoder: Handle, samples: *const f32, frames: usize, out_len: *mut usize) -> *mut u8 {
+    guard("ar_flac_encode", std::ptr::null_mut(), || {
+        let result = with_encoder(encoder, |encoder| {
+            if samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            let len = frames
+                .checked_mul(encoder.config().channels as usize)
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            encoder.encode(std::slice::from_raw_parts(samples, len))
+        });
+        bytes_result(result, out_len)
+    })
+}
+
+/// Release an encoder; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_flac_encoder_free(encoder: Handle) -> i32 {
+    guard("ar_flac_encoder_free", -999, || record(ENCODERS.remove(encoder)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(stream: &[u8]) -> (claxon::metadata::StreamInfo, Vec<i32>) {
+        let mut reader = claxon::FlacReader::new(stream).unwrap();
+        let samples = reader.samples().collect::<Result<Vec<_>, _>>().unwrap();
+        (reader.streaminfo(), samples)
+    }
+
+    #[test]
+    fn test_bit_perfect() {
+        for bits in [16, 24] {
+            let config = FlacConfig { bits_per_sample: bits, block_size: 256, ..FlacConfig::default() };
+            let mut encoder = FlacEncoder::new(&config).unwrap();
+            let scale = (1 << (bits - 1)) as f32;
+            // Exactly representable: what a 16- or 24-bit source decodes to
+            let pcm: Vec<i32> = (0..256 * 2 * 8).map(|i| ((i as f32 * 0.01).sin() * (scale - 1.0) * 0.8) as i32 ^ (i % 3)).collect();
+            let samples: Vec<f32> = pcm.iter().map(|&sample| sample as f32 / scale).collect();
+
+            let mut stream = encoder.header().unwrap();
+            for block in samples.chunks(512) {
+                stream.extend(encoder.encode(block).unwrap());
+            }
+            assert!(stream.len() < pcm.len() * bits as usize / 8, "{bits}: {}", stream.len());
+            let (info, decoded) = decode(&stream);
+            assert_eq!((info.sample_rate, info.channels, info.bits_per_sample), (48_000, 2, bits));
+            assert_eq!(decoded, pcm);
+        }
+    }
+
+    #[test]
+    fn test_clipping_and_config() {
+        let config = FlacConfig { channels: 1, bits_per_sample: 16, block_size: 64, ..FlacConfig::default() };
+        let mut encoder = FlacEncoder::new(&config).unwrap();
+        let mut samples = vec![0.0f32; 64];
+        samples[..4].copy_from_slice(&[1.5, -1.5, 1.0, f32::NAN]);
+        let mut stream = encoder.header().unwrap();
+        stream.extend(encoder.encode(&samples).unwrap());
+        assert_eq!(decode(&stream).1[..5], [32767, -32768, 32767, 0, 0]);
+        assert!(encoder.encode(&samples[..63]).is_err());
+
+        assert!(FlacEncoder::new(&FlacConfig { bits_per_sample: 32, ..FlacConfig::default() }).is_err());
+        assert!(FlacEncoder::new(&FlacConfig { block_size: 8192, ..FlacConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let encoder = unsafe { ar_flac_encoder_new(c"{\"blockSize\": 128}".as_ptr()) };
+        assert_ne!(encoder, 0);
+        let mut len = 0;
+        let header = unsafe { ar_flac_header(encoder, &mut len) };
+        assert!(!header.is_null());
+        let mut stream = unsafe { std::slice::from_raw_parts(header, len) }.to_vec();
+        unsafe { crate::error::ar_bytes_free(header, len) };
+        let samples = vec![0.25f32; 256];
+        let frame = unsafe { ar_flac_encode(encoder, samples.as_ptr(), 128, &mut len) };
+        assert!(!frame.is_null());
+        stream.extend_from_slice(unsafe { std::slice::from_raw_parts(frame, len) });
+        unsafe { crate::error::ar_bytes_free(frame, len) };
+        assert_eq!(decode(&stream).1, vec![0x20_0000; 256]);
+
+        assert!(unsafe { ar_flac_encode(encoder, samples.as_ptr(), 64, &mut len) }.is_null());
+        assert_eq!(ar_flac_encoder_free(encoder), 1);
+        assert!(unsafe { ar_flac_header(encoder, &mut len) }.is_null());
+    }
+}
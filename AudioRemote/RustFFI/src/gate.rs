@@ -0,0 +1,423 @@
+//! A noise gate for monitoring the Mac's microphone on a remote, so keyboard
+//! clatter and room hiss don't come through between utterances. The channels
+//! are keyed together on their loudest sample, through an envelope that falls
+//! over a few milliseconds so the gate doesn't chatter on zero crossings.
+//!
+//! The gate opens when the envelope reaches `thresholdDb`, fading in over
+//! `attackMs`. It stays open while the envelope is over the threshold less
+//! `hysteresisDb`, then for `holdMs` more, and then fades down to `rangeDb`
+//! over `releaseMs`. A range of -20 dB or so turns the background down rather
+//! than off, which sounds less like the line dropped. Times are counted in
+//! frames, and the fades are one-pole in dB, so a release time is how long the
+//! gain takes to get most of the way down the range however deep it is.
+
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::handle::{Handle, Registry};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+/// How fast the key envelope falls (the time constant)
+const ENVELOPE_RELEASE_MS: f64 = 10.0;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct GateConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default = "default_threshold_db")]
+    pub threshold_db: f64,
+    /// How far under the threshold the envelope must fall before the hold starts
+    #[serde(default = "default_hysteresis_db")]
+    pub hysteresis_db: f64,
+    #[serde(default = "default_attack_ms")]
+    pub attack_ms: f64,
+    #[serde(default = "default_hold_ms")]
+    pub hold_ms: f64,
+    #[serde(default = "default_release_ms")]
+    pub release_ms: f64,
+    /// The gain while closed
+    #[serde(default = "default_range_db")]
+    pub range_db: f64,
+}
+
+fn default_channels() -> u32 {
+    1
+}
+
+fn default_sample_rate() -> f64 {
+    48_000.0
+}
+
+fn default_threshold_db() -> f64 {
+    -45.0
+}
+
+fn default_hysteresis_db() -> f64 {
+    6.0
+}
+
+fn default_attack_ms() -> f64 {
+    1.0
+}
+
+fn default_hold_ms() -> f64 {
+    150.0
+}
+
+fn default_release_ms() -> f64 {
+    100.0
+}
+
+fn default_range_db() -> f64 {
+    -80.0
+}
+
+impl Default for GateConfig {
+    fn default() -> Self {
+        Self {
+            channels: default_channels(),
+            sample_rate: default_sample_rate(),
+            threshold_db: default_threshold_db(),
+            hysteresis_db: default_hysteresis_db(),
+            attack_ms: default_attack_ms(),
+            hold_ms: default_hold_ms(),
+            release_ms: default_release_ms(),
+            range_db: default_range_db(),
+        }
+    }
+}
+
+impl GateConfig {
+    fn validate(&self) -> Result<(), AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=MAX_CHANNELS).contains(&self.channels) {
+            return invalid(format!("channels {} is outside 1–{MAX_CHANNELS}", self.channels));
+        }
+        if !(8000.0..=768_000.0).contains(&self.sample_rate) {
+            return invalid(format!("sampleRate {} is outside 8000–768000", self.sample_rate));
+        }
+        if !(-100.0..=0.0).contains(&self.threshold_db) {
+            return invalid(format!("thresholdDb {} is outside -100–0", self.threshold_db));
+        }
+        if !(0.0..=20.0).contains(&self.hysteresis_db) {
+            return invalid(format!("hysteresisDb {} is outside 0–20", self.hysteresis_db));
+        }
+        for (name, ms) in [("attackMs", self.attack_ms), ("holdMs", self.hold_ms), ("releaseMs", self.release_ms)] {
+            if !(0.0..=5000.0).contains(&ms) {
+                return invalid(format!("{name} {ms} is outside 0–5000"));
+            }
+        }
+        if !(-120.0..=0.0).contains(&self.range_db) {
+            return invalid(format!("rangeDb {} is outside -120–0", self.range_db));
+        }
+        Ok(())
+    }
+}
+
+fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+/// How much of the old value a one-pole filter with time constant `ms` keeps each frame
+fn coefficient(ms: f64, sample_rate: f64) -> f64 {
+    match ms > 0.0 {
+        true => (-1000.0 / (ms * sample_rate)).exp(),
+        false => 0.0,
+    }
+}
+
+pub struct Gate {
+    config: GateConfig,
+    open_level: f64,
+    close_level: f64,
+    envelope_release: f64,
+    attack: f64,
+    release: f64,
+    hold: u64,
+    envelope: f64,
+    open: bool,
+    /// Frames left before closing
+    holding: u64,
+    gain_db: f64,
+}
+
+impl Gate {
+    pub fn new(config: GateConfig) -> Result<Self, AudioRemoteError> {
+        config.validate()?;
+        let mut gate = Self {
+            config: config.clone(),
+            open_level: 0.0,
+            close_level: 0.0,
+            envelope_release: 0.0,
+            attack: 0.0,
+            release: 0.0,
+            hold: 0,
+            envelope: 0.0,
+            open: false,
+            holding: 0,
+            gain_db: config.range_db,
+        };
+        gate.update(config)?;
+        Ok(gate)
+    }
+
+    /// Take new settings, keeping whether the gate is open
+    pub fn update(&mut self, config: GateConfig) -> Result<(), AudioRemoteError> {
+        config.validate()?;
+        let rate = config.sample_rate;
+        self.open_level = db_to_gain(config.threshold_db);
+        self.close_level = db_to_gain(config.threshold_db - config.hysteresis_db);
+        self.envelope_release = coefficient(ENVELOPE_RELEASE_MS, rate);
+        self.attack = coefficient(config.attack_ms, rate);
+        self.release = coefficient(config.release_ms, rate);
+        self.hold = (config.hold_ms * rate / 1000.0).round() as u64;
+        if config.channels != self.config.channels {
+            self.reset();
+        }
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.config.channels as usize
+    }
+
+    /// Gate interleaved samples in place; a partial frame at the end is left alone and samples
+    /// that aren't finite count as silence
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_exact_mut(self.config.channels as usize) {
+            let peak = frame.iter().fold(0.0f64, |peak, &sample| if sample.is_finite() { peak.max(f64::from(sample).abs()) } else { peak });
+            self.envelope = peak.max(self.envelope * self.envelope_release);
+            if self.envelope >= self.open_level || (self.open && self.envelope >= self.close_level) {
+                self.open = true;
+                self.holding = self.hold;
+            } else if self.open {
+                match self.holding {
+                    0 => self.open = false,
+                    _ => self.holding -= 1,
+                }
+            }
+            let (target, keep) = if self.open { (0.0, self.attack) } else { (self.config.range_db, self.release) };
+            self.gain_db = target + (self.gain_db - target) * keep;
+            let gain = db_to_gain(self.gain_db);
+            for sample in frame.iter_mut() {
+                *sample = if sample.is_finite() { (f64::from(*sample) * gain) as f32 } else { 0.0 };
+            }
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    /// The gain applied now, in dB
+    pub fn gain_db(&self) -> f64 {
+        self.gain_db
+    }
+
+    /// Close the gate and forget the audio, e.g. when the microphone restarts
+    pub fn reset(&mut self) {
+        self.envelope = 0.0;
+        self.open = false;
+        self.holding = 0;
+        self.gain_db = self.config.range_db;
+    }
+}
+
+static GATES: Registry<Mutex<Gate>> = Registry::new("gate");
+
+fn with_gate<T>(handle: Handle, body: impl FnOnce(&mut Gate) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let gate = GATES.get(handle)?;
+    let mut gate = gate.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut gate)
+}
+
+unsafe fn config_arg(config_json: *const c_char) -> Result<GateConfig, AudioRemoteError> {
+    match config_json.is_null() {
+        true => Ok(GateConfig::default()),
+        false => serde_json::from_str(str_arg(config_json, "config")?)
+            .map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid gate config: {e}"))),
+    }
+}
+
+/// Create a noise gate. `config_json` (nullable): {"channels" (default 1), "sampleRate" (default
+/// 48000), "thresholdDb" (default -45), "hysteresisDb" (default 6), "attackMs" (default 1),
+/// "holdMs" (default 150), "releaseMs" (default 100), "rangeDb" (gain while closed, default -80)}
+/// Returns: a handle (free with ar_gate_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_gate_new(config_json: *const c_char) -> Handle {
+    guard("ar_gate_new", 0, || match record(config_arg(config_json).and_then(Gate::new)) {
+        Some(gate) => GATES.insert(Mutex::new(gate)),
+        None => 0,
+    })
+}
+
+/// Change a gate's settings, as for ar_gate_new, e.g. when the user moves the threshold
+/// Returns: 1 on success, -999 on error (see last_error_message), leaving the old settings in place
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_gate_update(gate: Handle, config_json: *const c_char) -> i32 {
+    guard("ar_gate_update", -999, || {
+        let result = config_arg(config_json).and_then(|config| with_gate(gate, |gate| gate.update(config)));
+        record(result).map_or(-999, |_| 1)
+    })
+}
+
+/// Gate `frames` frames of interleaved float samples in place
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels writable floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_gate_process(gate: Handle, samples: *mut f32, frames: usize) -> i32 {
+    guard("ar_gate_process", -999, || {
+        let result = with_gate(gate, |gate| {
+            let len = frames
+                .checked_mul(gate.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len == 0 {
+                return Ok(1);
+            }
+            if samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            gate.process(std::slice::from_raw_parts_mut(samples, len));
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Whether the gate is open, and the gain it applies now in dB through `out_gain_db` (may be NULL),
+/// for a "talking" light
+/// Returns: 1 when open (or holding), 0 when closed, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `out_gain_db` must be null or point to a writable double.
+#[no_mangle]
+pub unsafe extern "C" fn ar_gate_state(gate: Handle, out_gain_db: *mut f64) -> i32 {
+    guard("ar_gate_state", -999, || {
+        let result = with_gate(gate, |gate| {
+            if !out_gain_db.is_null() {
+                *out_gain_db = gate.gain_db();
+            }
+            Ok(i32::from(gate.is_open()))
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Close the gate and forget the audio, e.g. when the microphone restarts
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_gate_reset(gate: Handle) -> i32 {
+    guard("ar_gate_reset", -999, || {
+        record(with_gate(gate, |gate| {
+            gate.reset();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Release a gate from ar_gate_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_gate_free(gate: Handle) -> i32 {
+    guard("ar_gate_free", -999, || record(GATES.remove(gate)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: usize = 48_000;
+
+    /// A 200 Hz tone at `db` dBFS
+    fn tone(db: f64, frames: usize) -> Vec<f32> {
+        let amplitude = db_to_gain(db);
+        (0..frames).map(|i| (amplitude * (2.0 * std::f64::consts::PI * 200.0 * i as f64 / RATE as f64).sin()) as f32).collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn test_opens_holds_and_closes() {
+        let mut gate = Gate::new(GateConfig::default()).unwrap();
+        // Hiss under the threshold is turned down to the range
+        let mut hiss = tone(-60.0, RATE / 10);
+        gate.process(&mut hiss);
+        assert!(!gate.is_open() && peak(&hiss) < 1e-6, "{}", peak(&hiss));
+
+        // Speech opens it within the attack, untouched after that
+        let mut speech = tone(-20.0, RATE / 2);
+        let original = speech.clone();
+        gate.process(&mut speech);
+        assert!(gate.is_open());
+        assert!((speech[RATE / 4] - original[RATE / 4]).abs() < 1e-6);
+        assert!(gate.gain_db().abs() < 1e-6);
+
+        // Falling to just under the threshold keeps it open (hysteresis); real quiet holds, then fades
+        let mut between = tone(-48.0, RATE / 2);
+        gate.process(&mut between);
+        assert!(gate.is_open());
+        let mut quiet = tone(-70.0, RATE / 10);
+        gate.process(&mut quiet);
+        assert!(gate.is_open(), "still holding at 100 ms");
+        let mut quiet = tone(-70.0, RATE / 2);
+        gate.process(&mut quiet);
+        assert!(!gate.is_open());
+        assert!(gate.gain_db() < -75.0, "{}", gate.gain_db());
+        gate.reset();
+        assert_eq!(gate.gain_db(), -80.0);
+    }
+
+    #[test]
+    fn test_range_and_updates() {
+        // A -20 dB range ducks the background instead of muting it
+        let config = GateConfig { channels: 2, range_db: -20.0, ..GateConfig::default() };
+        let mut gate = Gate::new(config.clone()).unwrap();
+        let mut hiss: Vec<f32> = tone(-50.0, RATE).into_iter().flat_map(|sample| [sample, f32::NAN]).collect();
+        gate.process(&mut hiss);
+        assert!((gate.gain_db() + 20.0).abs() < 1e-6);
+        assert!((peak(&hiss) / db_to_gain(-70.0) as f32 - 1.0).abs() < 0.01, "{}", peak(&hiss));
+
+        // Lowering the threshold under the hiss opens it
+        gate.update(GateConfig { threshold_db: -55.0, ..config }).unwrap();
+        gate.process(&mut tone(-50.0, 2 * RATE / 10));
+        assert!(gate.is_open());
+        assert!(gate.update(GateConfig { range_db: 6.0, ..GateConfig::default() }).is_err());
+        assert!(Gate::new(GateConfig { hold_ms: -1.0, ..GateConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let gate = unsafe { ar_gate_new(c"{\"thresholdDb\": -30, \"rangeDb\": -40}".as_ptr()) };
+        assert_ne!(gate, 0);
+        let mut gain = 0.0;
+        assert_eq!(unsafe { ar_gate_state(gate, &mut gain) }, 0);
+        assert!((gain + 40.0).abs() < 1e-9);
+        let mut speech = tone(-10.0, 4800);
+        assert_eq!(unsafe { ar_gate_process(gate, speech.as_mut_ptr(), speech.len()) }, 1);
+        assert_eq!(unsafe { ar_gate_state(gate, std::ptr::null_mut()) }, 1);
+        assert_eq!(unsafe { ar_gate_update(gate, c"{\"attackMs\": 9000}".as_ptr()) }, -999);
+        assert_eq!(unsafe { ar_gate_process(gate, std::ptr::null_mut(), 1) }, -999);
+        assert_eq!(ar_gate_reset(gate), 1);
+        assert_eq!(unsafe { ar_gate_state(gate, &mut gain) }, 0);
+        assert_eq!(ar_gate_free(gate), 1);
+        assert_eq!(ar_gate_free(gate), -999);
+    }
+}
@@ -0,0 +1,257 @@
+use std::ffi::c_char;
+
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::channel::channel_arg;
+use crate::error::string_result;
+use crate::version::{self, ParseMode};
+use crate::{guard, str_arg, AudioRemoteError, UpdateChannel};
+
+/// Release object as returned by the GitHub Releases API
+#[derive(Debug, Clone, Deserialize)]
+pub struct GitHubRelease {
+    pub tag_name: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub body: Option<String>,
+    #[serde(default)]
+    pub draft: bool,
+    #[serde(default)]
+    pub prerelease: bool,
+    #[serde(default)]
+    pub published_at: Option<String>,
+    #[serde(default)]
+    pub html_url: Option<String>,
+    #[serde(default)]
+    pub assets: Vec<GitHubAsset>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GitHubAsset {
+    pub name: String,
+    pub browser_download_url: String,
+    #[serde(default)]
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    Arm64,
+    X86_64,
+}
+
+impl Arch {
+    /// Architecture this library was compiled for
+    pub fn current() -> Self {
+        if cfg!(target_arch = "aarch64") {
+            Self::Arm64
+        } else {
+            Self::X86_64
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "arm64" | "aarch64" => Some(Self::Arm64),
+            "x86_64" | "x64" | "intel" => Some(Self::X86_64),
+            _ => None,
+        }
+    }
+
+    fn markers(self) -> &'static [&'static str] {
+        match self {
+            Self::Arm64 => &["arm64", "aarch64", "apple-silicon", "applesilicon"],
+            Self::X86_64 => &["x86_64", "x86-64", "x64", "intel"],
+        }
+    }
+
+    fn other(self) -> Self {
+        match self {
+            Self::Arm64 => Self::X86_64,
+            Self::X86_64 => Self::Arm64,
+        }
+    }
+}
+
+/// The release the app should offer, with the asset chosen for this machine
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SelectedRelease {
+    pub tag: String,
+    pub version: String,
+    pub name: Option<String>,
+    pub release_notes: String,
+    pub published_at: Option<String>,
+    pub html_url: Option<String>,
+    pub prerelease: bool,
+    pub asset_name: String,
+    pub asset_url: String,
+    pub asset_size: Option<u64>,
+    pub is_dmg: bool,
+    #[serde(skip)]
+    pub parsed_version: Version,
+}
+
+/// Accept either the releases array or a single release object
+pub fn parse(json: &str) -> Result<Vec<GitHubRelease>, AudioRemoteError> {
+    let value: serde_json::Value = serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidData(format!("malformed releases JSON: {e}")))?;
+    let releases = if value.is_array() { value } else { serde_json::Value::Array(vec![value]) };
+    serde_json::from_value(releases).map_err(|e| AudioRemoteError::InvalidData(format!("unexpected releases JSON: {e}")))
+}
+
+/// Pick the best download for `arch`: arch-specific beats universal, DMG beats ZIP.
+/// Assets built only for the other architecture are never chosen.
+pub fn select_asset(assets: &[GitHubAsset], arch: Arch) -> Option<&GitHubAsset> {
+    let mentions = |name: &str, arch: Arch| arch.markers().iter().any(|m| name.contains(m));
+    assets
+        .iter()
+        .filter_map(|asset| {
+            let name = asset.name.to_ascii_lowercase();
+            let kind_score = if name.ends_with(".dmg") {
+                2
+            } else if name.ends_with(".zip") {
+                1
+            } else {
+                return None;
+            };
+            let arch_score = match (mentions(&name, arch), mentions(&name, arch.other())) {
+                (true, _) => 2,
+                (false, false) => 1,
+                (false, true) => return None,
+            };
+            Some((arch_score * 10 + kind_score, asset))
+        })
+        .max_by_key(|(score, _)| *score)
+        .map(|(_, asset)| asset)
+}
+
+/// Newest non-draft release on `channel` that has a downloadable asset for `arch`
+pub fn latest_release(releases: &[GitHubRelease], channel: UpdateChannel, arch: Arch) -> Option<SelectedRelease> {
+    releases
+        .iter()
+        .filter(|r| !r.draft)
+        .filter_map(|r| {
+            let parsed_version = version::parse(&r.tag_name, ParseMode::Lenient).ok()?;
+            let mut release_channel = UpdateChannel::of(&parsed_version);
+            if r.prerelease {
+                release_channel = release_channel.max(UpdateChannel::Beta);
+            }
+            if release_channel > channel {
+                return None;
+            }
+            let asset = select_asset(&r.assets, arch)?;
+            Some(SelectedRelease {
+                tag: r.tag_name.clone(),
+                version: parsed_version.to_string(),
+                name: r.name.clone(),
+                release_notes: r.body.clone().unwrap_or_default(),
+                published_at: r.published_at.clone(),
+                html_url: r.html_url.clone(),
+                prerelease: r.prerelease,
+                asset_name: asset.name.clone(),
+                asset_url: asset.browser_download_url.clone(),
+                asset_size: asset.size,
+                is_dmg: asset.name.to_ascii_lowercase().ends_with(".dmg"),
+                parsed_version,
+            })
+        })
+        .max_by(|a, b| a.parsed_version.cmp(&b.parsed_version))
+}
+
+/// Pick the latest applicable release from GitHub Releases API JSON
+/// `channel`: 0 = stable, 1 = beta, 2 = nightly, -1 = saved; `arch`: "arm64", "x86_64" or NULL for this machine
+/// Returns: JSON object (free with rust_string_free), or NULL on error (see last_error_message)
+///
+/// # Safety
+/// `json_ptr` must point to a valid NUL-terminated string; `arch_ptr` must be
+/// null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn github_latest_release(json_ptr: *const c_char, channel: i32, arch_ptr: *const c_char) -> *mut c_char {
+    guard("github_latest_release", std::ptr::null_mut(), || {
+        let result = str_arg(json_ptr, "json").and_then(|json| {
+            let channel = channel_arg(channel)?;
+            let arch = match arch_ptr.is_null() {
+                true => Arch::current(),
+                false => {
+                    let name = str_arg(arch_ptr, "arch")?;
+                    Arch::parse(name).ok_or(AudioRemoteError::InvalidArgument(format!("unknown architecture \"{name}\"")))?
+                }
+            };
+            let releases = parse(json)?;
+            let release = latest_release(&releases, channel, arch).ok_or(AudioRemoteError::NotFound("no applicable release found".into()))?;
+            serde_json::to_string(&release).map_err(|e| AudioRemoteError::Other(e.to_string()))
+        });
+        string_result(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+
+    const RELEASES: &str = r###"[
+      {"tag_name": "v2.7.0-beta.1", "prerelease": true, "body": "beta notes",
+       "assets": [{"name": "AudioRemote-2.7.0-beta.1.dmg", "browser_download_url": "https://example.com/b.dmg"}]},
+      {"tag_name": "v2.10.0", "draft": true,
+       "assets": [{"name": "AudioRemote-2.10.0.dmg", "browser_download_url": "https://example.com/draft.dmg"}]},
+      {"tag_name": "v2.6.0", "body": "## Fixes", "published_at": "2024-05-01T10:00:00Z",
+       "assets": [
+         {"name": "AudioRemote-2.6.0.zip", "browser_download_url": "https://example.com/u.zip", "size": 10},
+         {"name": "AudioRemote-2.6.0-arm64.dmg", "browser_download_url": "https://example.com/arm.dmg", "size": 20},
+         {"name": "AudioRemote-2.6.0-x86_64.dmg", "browser_download_url": "https://example.com/intel.dmg", "size": 30},
+         {"name": "checksums.txt", "browser_download_url": "https://example.com/sums.txt"}
+       ]},
+      {"tag_name": "v2.9.0", "assets": []},
+      {"tag_name": "v2.5.0",
+       "assets": [{"name": "AudioRemote-2.5.0.zip", "browser_download_url": "https://example.com/old.zip"}]}
+    ]"###;
+
+    #[test]
+    fn test_latest_release_per_arch() {
+        let releases = parse(RELEASES).unwrap();
+
+        let arm = latest_release(&releases, UpdateChannel::Stable, Arch::Arm64).unwrap();
+        assert_eq!(arm.version, "2.6.0");
+        assert_eq!(arm.asset_url, "https://example.com/arm.dmg");
+        assert!(arm.is_dmg);
+        assert_eq!(arm.release_notes, "## Fixes");
+
+        let intel = latest_release(&releases, UpdateChannel::Stable, Arch::X86_64).unwrap();
+        assert_eq!(intel.asset_url, "https://example.com/intel.dmg");
+    }
+
+    #[test]
+    fn test_prereleases_need_beta_channel() {
+        let releases = parse(RELEASES).unwrap();
+        let beta = latest_release(&releases, UpdateChannel::Beta, Arch::Arm64).unwrap();
+        assert_eq!(beta.version, "2.7.0-beta.1");
+        assert!(beta.prerelease);
+    }
+
+    #[test]
+    fn test_select_asset_falls_back_to_universal_zip() {
+        let assets = vec![
+            GitHubAsset { name: "App-x86_64.dmg".into(), browser_download_url: "intel".into(), size: None },
+            GitHubAsset { name: "App.zip".into(), browser_download_url: "universal".into(), size: None },
+        ];
+        assert_eq!(select_asset(&assets, Arch::Arm64).unwrap().browser_download_url, "universal");
+        assert_eq!(select_asset(&assets, Arch::X86_64).unwrap().browser_download_url, "intel");
+    }
+
+    #[test]
+    fn test_github_latest_release_ffi() {
+        let json = std::ffi::CString::new(RELEASES).unwrap();
+        let arch = std::ffi::CString::new("arm64").unwrap();
+        let ptr = unsafe { github_latest_release(json.as_ptr(), 0, arch.as_ptr()) };
+        let out = take_c_string(ptr).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(value["tag"], "v2.6.0");
+        assert_eq!(value["assetSize"], 20);
+
+        let bad = std::ffi::CString::new("{\"message\": \"API rate limit exceeded\"}").unwrap();
+        assert!(unsafe { github_latest_release(bad.as_ptr(), 0, std::ptr::null()) }.is_null());
+    }
+}
@@ -0,0 +1,358 @@
+//! The control surface as a gRPC service, for integrations that would rather
+//! generate a typed client from proto/audioremote.proto than write REST calls:
+//! desktop companion apps, scripts in other languages. Each RPC is a route of
+//! the REST API under another name and goes the same way, through
+//! `routes::perform` to the handlers Swift registered, so the two can't drift.
+//!
+//! tonic needs an async runtime, so the service runs on its own single-threaded
+//! tokio runtime on one thread, and each call waits for Swift on a blocking
+//! thread. Calls authenticate like REST, with the access token as
+//! `authorization: Bearer` metadata, and are held to the remote's scope.
+//! Commands aren't numbered here (see `commands`): a gRPC client that retries
+//! should resend the state it wants, which every RPC sets absolutely.
+
+use std::ffi::c_char;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::watch;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Request, Response, Status};
+
+use crate::remote::{self, VolumeState};
+use crate::routes::{self, Endpoint};
+use crate::sessions::{self, Scope};
+use crate::{auth, guard, record, server, str_arg, AudioRemoteError};
+
+/// How long `stop` lets calls in progress finish, and clients hang up
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// Messages and service generated from proto/audioremote.proto
+#[allow(clippy::all, clippy::pedantic)]
+pub mod proto {
+    tonic::include_proto!("audioremote.v1");
+}
+
+use proto::audio_remote_server::{AudioRemote, AudioRemoteServer};
+
+/// `ar_grpc_start` configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct GrpcConfig {
+    /// 0 picks a free port
+    #[serde(default)]
+    pub port: u16,
+    #[serde(default = "default_bind_address")]
+    pub bind_address: IpAddr,
+    /// Refuse calls without a paired remote's access token
+    #[serde(default)]
+    pub require_auth: bool,
+}
+
+impl Default for GrpcConfig {
+    fn default() -> Self {
+        Self { port: 0, bind_address: default_bind_address(), require_auth: false }
+    }
+}
+
+fn default_bind_address() -> IpAddr {
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+}
+
+/// The gRPC code for an HTTP error status, as the .proto lists them
+fn status(code: u16, message: String) -> Status {
+    let code = match code {
+        400 => Code::InvalidArgument,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        409 => Code::Aborted,
+        429 => Code::ResourceExhausted,
+        501 => Code::Unimplemented,
+        503 => Code::Unavailable,
+        504 => Code::DeadlineExceeded,
+        _ => Code::Internal,
+    };
+    Status::new(code, message)
+}
+
+/// The REST server's checks for a call: the token, if any, and the scope of its remote.
+/// Errors are HTTP statuses, as from `routes::perform`.
+fn permit(metadata: &MetadataMap, require_auth: bool, endpoint: Endpoint) -> Result<(), (u16, String)> {
+    let token = metadata.get("authorization").and_then(|value| value.to_str().ok()).and_then(|value| value.strip_prefix("Bearer ")).map(str::trim);
+    let remote = token.and_then(auth::authenticate);
+    if require_auth && remote.is_none() {
+        let message = match token {
+            Some(_) => "the access token is invalid or expired",
+            None => "pair with this Mac first",
+        };
+        return Err((401, message.into()));
+    }
+    let scope = remote.as_deref().map_or(Scope::FullControl, sessions::scope_of);
+    match scope.allows(endpoint) {
+        true => Ok(()),
+        false => Err((403, format!("this remote has {} access; {endpoint:?} needs {}", scope.name(), Scope::required_for(endpoint).name()))),
+    }
+}
+
+struct Service {
+    require_auth: bool,
+}
+
+impl Service {
+    /// Check the call and have Swift carry it out, off the runtime's thread
+    async fn perform<T: DeserializeOwned>(&self, metadata: &MetadataMap, endpoint: Endpoint, params: Value) -> Result<T, Status> {
+        let (metadata, require_auth) = (metadata.clone(), self.require_auth);
+        let result = tokio::task::spawn_blocking(move || {
+            permit(&metadata, require_auth, endpoint)?;
+            routes::perform(endpoint, params.to_string().as_bytes())
+        });
+        let result = result.await.map_err(|e| Status::internal(e.to_string()))?.map_err(|(code, message)| status(code, message))?;
+        serde_json::from_value(result).map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+impl From<VolumeState> for proto::VolumeState {
+    fn from(state: VolumeState) -> Self {
+        Self { volume: state.volume, muted: state.muted }
+    }
+}
+
+impl From<remote::OutputDevice> for proto::OutputDevice {
+    fn from(device: remote::OutputDevice) -> Self {
+        Self { id: device.id, name: device.name, is_current: device.is_current, battery_percent: device.battery_percent.map(u32::from) }
+    }
+}
+
+impl From<remote::NowPlaying> for proto::NowPlaying {
+    fn from(playing: remote::NowPlaying) -> Self {
+        Self {
+            title: playing.title,
+            artist: playing.artist,
+            album: playing.album,
+            app: playing.app,
+            is_playing: playing.is_playing,
+            elapsed: playing.elapsed,
+            duration: playing.duration,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl AudioRemote for Service {
+    async fn get_volume(&self, request: Request<proto::GetVolumeRequest>) -> Result<Response<proto::VolumeState>, Status> {
+        let state: VolumeState = self.perform(request.metadata(), Endpoint::GetVolume, json!({})).await?;
+        Ok(Response::new(state.into()))
+    }
+
+    async fn set_volume(&self, request: Request<proto::SetVolumeRequest>) -> Result<Response<proto::VolumeState>, Status> {
+        let params = json!({"volume": request.get_ref().volume});
+        let state: VolumeState = self.perform(request.metadata(), Endpoint::SetVolume, params).await?;
+        Ok(Response::new(state.into()))
+    }
+
+    async fn set_mute(&self, request: Request<proto::SetMuteRequest>) -> Result<Response<proto::VolumeState>, Status> {
+        let params = json!({"muted": request.get_ref().muted});
+        let state: VolumeState = self.perform(request.metadata(), Endpoint::SetMute, params).await?;
+        Ok(Response::new(state.into()))
+    }
+
+    async fn list_devices(&self, request: Request<proto::ListDevicesRequest>) -> Result<Response<proto::ListDevicesResponse>, Status> {
+        let devices: Vec<remote::OutputDevice> = self.perform(request.metadata(), Endpoint::ListDevices, json!({})).await?;
+        Ok(Response::new(proto::ListDevicesResponse { devices: devices.into_iter().map(Into::into).collect() }))
+    }
+
+    async fn select_device(&self, request: Request<proto::SelectDeviceRequest>) -> Result<Response<proto::OutputDevice>, Status> {
+        let params = json!({"id": request.get_ref().id});
+        let device: remote::OutputDevice = self.perform(request.metadata(), Endpoint::SelectDevice, params).await?;
+        Ok(Response::new(device.into()))
+    }
+
+    async fn get_now_playing(&self, request: Request<proto::GetNowPlayingRequest>) -> Result<Response<proto::GetNowPlayingResponse>, Status> {
+        let playing: Option<remote::NowPlaying> = self.perform(request.metadata(), Endpoint::NowPlaying, json!({})).await?;
+        Ok(Response::new(proto::GetNowPlayingResponse { now_playing: playing.map(Into::into) }))
+    }
+}
+
+struct Running {
+    address: SocketAddr,
+    shutdown: watch::Sender<bool>,
+    thread: JoinHandle<()>,
+}
+
+static GRPC: Mutex<Option<Running>> = Mutex::new(None);
+
+/// Resolves once `stop` is called
+async fn stopping(mut stopped: watch::Receiver<bool>) {
+    let _ = stopped.wait_for(|stopped| *stopped).await;
+}
+
+/// Start serving; fails if the service is already running or the address is taken
+pub fn start(config: &GrpcConfig) -> Result<SocketAddr, AudioRemoteError> {
+    let mut running = GRPC.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(running) = running.as_ref() {
+        return Err(AudioRemoteError::Refused(format!("the gRPC service is already running on {}", running.address)));
+    }
+    let listener = server::listen(config.bind_address, config.port)
+        .map_err(|e| AudioRemoteError::Network(format!("can't listen on port {}: {e}", config.port)))?;
+    listener.set_nonblocking(true)?;
+    let address = listener.local_addr()?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| AudioRemoteError::Other(format!("can't start the gRPC runtime: {e}")))?;
+    let service = AudioRemoteServer::new(Service { require_auth: config.require_auth });
+    let (shutdown, stopped) = watch::channel(false);
+    let thread = thread::Builder::new()
+        .name("audioremote-grpc".into())
+        .spawn(move || {
+            let served = runtime.block_on(async move {
+                let incoming = TcpListenerStream::new(tokio::net::TcpListener::from_std(listener)?);
+                let serve = tonic::transport::Server::builder().add_service(service).serve_with_incoming_shutdown(incoming, stopping(stopped.clone()));
+                // Connections still open after the grace period are dropped with the runtime
+                let grace = async move {
+                    stopping(stopped).await;
+                    tokio::time::sleep(SHUTDOWN_GRACE).await;
+                };
+                tokio::select! {
+                    served = serve => served.map_err(|e| AudioRemoteError::Network(e.to_string())),
+                    () = grace => Ok(()),
+                }
+            });
+            if let Err(e) = served {
+                log::error!("gRPC service failed: {e}");
+            }
+        })
+        .map_err(|e| AudioRemoteError::Other(format!("can't start the gRPC thread: {e}")))?;
+    *running = Some(Running { address, shutdown, thread });
+    log::info!("gRPC service on {address}");
+    Ok(address)
+}
+
+/// Stop serving once the calls in progress are answered, or after SHUTDOWN_GRACE; a no-op if not running
+pub fn stop() {
+    let Some(running) = GRPC.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    let _ = running.shutdown.send(true);
+    let _ = running.thread.join();
+    log::info!("gRPC service stopped");
+}
+
+/// Where the service is listening, if it's running
+pub fn local_address() -> Option<SocketAddr> {
+    GRPC.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|running| running.address)
+}
+
+/// Serve the REST API's operations over gRPC as well, for clients generated from the
+/// audioremote.proto shipped with the library. `config_json` (nullable): {"port" (0: any free
+/// port), "bindAddress" ("::", IPv4 and IPv6), "requireAuth" (refuse calls without an access token)}.
+/// Calls reach the ar_server_set_handler handlers, whether or not the HTTP server is running.
+/// Returns: the port listened on, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_grpc_start(config_json: *const c_char) -> i32 {
+    guard("ar_grpc_start", -999, || {
+        let config = match config_json.is_null() {
+            true => Ok(GrpcConfig::default()),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid gRPC config: {e}")))
+            }),
+        };
+        match record(config.and_then(|config| start(&config))) {
+            Some(address) => i32::from(address.port()),
+            None => -999,
+        }
+    })
+}
+
+/// Stop the gRPC service; a no-op if not running. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_grpc_stop() {
+    guard("ar_grpc_stop", (), stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::Handle;
+    use crate::routes::{ar_server_reject, ar_server_respond, ar_server_set_handler};
+    use proto::audio_remote_client::AudioRemoteClient;
+    use std::ffi::{c_void, CStr, CString};
+
+    extern "C" fn audio(request: Handle, endpoint: i32, params: *const c_char, _ctx: *mut c_void) {
+        let params: Value = serde_json::from_str(unsafe { CStr::from_ptr(params) }.to_str().unwrap()).unwrap();
+        let answer = |json: String| {
+            let json = CString::new(json).unwrap();
+            assert_eq!(unsafe { ar_server_respond(request, json.as_ptr()) }, 1);
+        };
+        match Endpoint::from_raw(endpoint).unwrap() {
+            Endpoint::SetVolume => answer(format!(r#"{{"volume": {}, "muted": false}}"#, params["volume"])),
+            Endpoint::ListDevices => answer(r#"[{"id": "built-in", "name": "MacBook Speakers", "isCurrent": true}]"#.into()),
+            Endpoint::NowPlaying => answer("null".into()),
+            _ => {
+                assert_eq!(unsafe { ar_server_reject(request, 404, std::ptr::null()) }, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_status_codes() {
+        let (_store, dir) = crate::store::tests::with_temp_store("grpc");
+        assert_eq!(status(400, "bad".into()).code(), Code::InvalidArgument);
+        assert_eq!(status(504, "late".into()).code(), Code::DeadlineExceeded);
+        assert_eq!(status(502, "odd".into()).code(), Code::Internal);
+        let mut metadata = MetadataMap::new();
+        assert!(permit(&metadata, false, Endpoint::SelectDevice).is_ok());
+        assert_eq!(permit(&metadata, true, Endpoint::GetVolume).unwrap_err().0, 401);
+        metadata.insert("authorization", "Bearer nonsense".parse().unwrap());
+        assert_eq!(permit(&metadata, true, Endpoint::GetVolume).unwrap_err(), (401, "the access token is invalid or expired".into()));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_calls_over_http2() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        for endpoint in [Endpoint::SetVolume, Endpoint::ListDevices, Endpoint::SelectDevice, Endpoint::NowPlaying] {
+            assert_eq!(unsafe { ar_server_set_handler(endpoint as i32, Some(audio), std::ptr::null_mut()) }, 1);
+        }
+        assert_eq!(unsafe { ar_server_set_handler(Endpoint::GetVolume as i32, None, std::ptr::null_mut()) }, 1);
+        let address = start(&GrpcConfig { bind_address: "127.0.0.1".parse().unwrap(), ..GrpcConfig::default() }).unwrap();
+        assert!(start(&GrpcConfig::default()).is_err());
+        assert_eq!(local_address(), Some(address));
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let mut client = AudioRemoteClient::connect(format!("http://{address}")).await.unwrap();
+            let state = client.set_volume(proto::SetVolumeRequest { volume: 0.375 }).await.unwrap().into_inner();
+            assert_eq!(state, proto::VolumeState { volume: 0.375, muted: false });
+            let error = client.set_volume(proto::SetVolumeRequest { volume: 2.0 }).await.unwrap_err();
+            assert_eq!(error.code(), Code::InvalidArgument);
+            assert!(error.message().contains("outside 0.0"), "{}", error.message());
+
+            let devices = client.list_devices(proto::ListDevicesRequest {}).await.unwrap().into_inner().devices;
+            assert_eq!(devices, [proto::OutputDevice { id: "built-in".into(), name: "MacBook Speakers".into(), is_current: true, battery_percent: None }]);
+            let error = client.select_device(proto::SelectDeviceRequest { id: "usb".into() }).await.unwrap_err();
+            assert_eq!((error.code(), error.message()), (Code::NotFound, "Not Found"));
+            assert_eq!(client.get_now_playing(proto::GetNowPlayingRequest {}).await.unwrap().into_inner().now_playing, None);
+            assert_eq!(client.get_volume(proto::GetVolumeRequest {}).await.unwrap_err().code(), Code::Unimplemented);
+        });
+        // Hang up, so stop doesn't wait out the grace period
+        drop(runtime);
+
+        stop();
+        for endpoint in [Endpoint::SetVolume, Endpoint::ListDevices, Endpoint::SelectDevice, Endpoint::NowPlaying] {
+            assert_eq!(unsafe { ar_server_set_handler(endpoint as i32, None, std::ptr::null_mut()) }, 1);
+        }
+        assert_eq!(local_address(), None);
+        assert!(std::net::TcpStream::connect(address).is_err());
+    }
+}
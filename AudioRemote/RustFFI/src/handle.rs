@@ -0,0 +1,95 @@
+//! Opaque handles for long-lived Rust objects held by Swift. A handle is a
+//! number, never a pointer: every call looks it up in the registry for its
+//! type, so a stale, freed or wrong-kind handle is an error instead of a crash.
+//! Handle values come from one counter shared by all registries and are never
+//! reused; 0 is never a valid handle and is what `ar_<thing>_new` returns on error.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::AudioRemoteError;
+
+pub type Handle = u64;
+
+static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+/// Live objects of one kind. Objects are shared as `Arc`s, so a call running on
+/// one thread keeps its object alive even if another thread frees the handle.
+pub struct Registry<T> {
+    kind: &'static str,
+    objects: Mutex<BTreeMap<Handle, Arc<T>>>,
+}
+
+impl<T> Registry<T> {
+    pub const fn new(kind: &'static str) -> Self {
+        Self { kind, objects: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn objects(&self) -> std::sync::MutexGuard<'_, BTreeMap<Handle, Arc<T>>> {
+        self.objects.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn invalid(&self, handle: Handle) -> AudioRemoteError {
+        AudioRemoteError::InvalidArgument(format!("invalid {} handle {handle}", self.kind))
+    }
+
+    pub fn insert(&self, value: T) -> Handle {
+        self.insert_shared(Arc::new(value))
+    }
+
+    /// Register an object the caller keeps a reference to as well
+    pub fn insert_shared(&self, value: Arc<T>) -> Handle {
+        let handle = NEXT_HANDLE.fetch_add(1, Ordering::Relaxed);
+        self.objects().insert(handle, value);
+        handle
+    }
+
+    pub fn get(&self, handle: Handle) -> Result<Arc<T>, AudioRemoteError> {
+        self.objects().get(&handle).cloned().ok_or_else(|| self.invalid(handle))
+    }
+
+    pub fn remove(&self, handle: Handle) -> Result<Arc<T>, AudioRemoteError> {
+        self.objects().remove(&handle).ok_or_else(|| self.invalid(handle))
+    }
+
+    /// Every live object, e.g. to cancel them all at shutdown
+    pub fn all(&self) -> Vec<Arc<T>> {
+        self.objects().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handles_are_validated() {
+        static STRINGS: Registry<String> = Registry::new("string");
+        static NUMBERS: Registry<u32> = Registry::new("number");
+
+        let a = STRINGS.insert("a".into());
+        let n = NUMBERS.insert(7);
+        assert_ne!(a, 0);
+        assert_eq!(*STRINGS.get(a).unwrap(), "a");
+        // A handle from another registry is never valid here
+        assert!(matches!(STRINGS.get(n), Err(AudioRemoteError::InvalidArgument(m)) if m == format!("invalid string handle {n}")));
+        assert!(STRINGS.get(0).is_err());
+
+        let kept = STRINGS.get(a).unwrap();
+        STRINGS.remove(a).unwrap();
+        assert!(STRINGS.get(a).is_err());
+        assert!(STRINGS.remove(a).is_err());
+        assert_eq!(*kept, "a");
+        assert!(STRINGS.is_empty());
+        assert_eq!(NUMBERS.len(), 1);
+    }
+}
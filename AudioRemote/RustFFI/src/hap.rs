@@ -0,0 +1,1430 @@
+//! HomeKit: the Mac as a HomeKit speaker, so the Home app, Siri and HomeKit
+//! automations can set its volume and mute it. This is the HomeKit Accessory
+//! Protocol over IP, served on a port of its own and advertised as
+//! `_hap._tcp`:
+//!
+//! - Pair setup (`/pair-setup`): the controller proves it knows the 8-digit
+//!   setup code the Mac shows, by SRP (see `srp`), and the two exchange
+//!   long-term Ed25519 keys, encrypted under the SRP session key.
+//! - Pair verify (`/pair-verify`): on every connection, an X25519 exchange
+//!   signed with those keys. Everything after it is encrypted, in frames of at
+//!   most 1024 bytes sealed with ChaCha20-Poly1305, one key each way.
+//! - Then `/accessories`, `/characteristics` (reads, writes and event
+//!   subscriptions) and `/pairings` (admins adding and removing controllers),
+//!   with JSON bodies.
+//!
+//! There is one accessory with a Speaker service whose Mute and Volume
+//! characteristics go to Swift as `SetMute` and `SetVolume` requests, the way
+//! REST requests do. Volume changes from anywhere else reach subscribed
+//! controllers as events. Pairing state lives in the store; the setup code is
+//! Swift's to show, and once a controller has paired, pair setup is closed
+//! until the pairings are reset.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::c_char;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::error::string_result;
+use crate::events::{Subscription, Topic, HUB};
+use crate::mdns::{self, Advertisement, Service};
+use crate::pairing::random_bytes;
+use crate::remote::VolumeState;
+use crate::routes::{self, Endpoint};
+use crate::server::{self, ReadError, Request, Response};
+use crate::{dns, guard, record, srp, store, str_arg, AudioRemoteError};
+
+pub const SERVICE_TYPE: &str = "_hap._tcp.local";
+const PROTOCOL_VERSION: &str = "1.1.0";
+/// The accessory category HomeKit shows: a speaker
+const CATEGORY_SPEAKER: u8 = 26;
+const MAX_CONNECTIONS: usize = 16;
+/// Plaintext bytes per encrypted frame
+const MAX_FRAME: usize = 1024;
+const TAG_LENGTH: usize = 16;
+/// Failed pair setups before setup is refused for good (until the pairings are reset)
+const MAX_SETUP_ATTEMPTS: u32 = 100;
+const SRP_USERNAME: &[u8] = b"Pair-Setup";
+
+/// The identity's seed and the setup code are secrets; the store file is readable only by the user
+const IDENTITY_KEY: &str = "hap.accessory";
+const CONTROLLERS_KEY: &str = "hap.controllers";
+const SETUP_CODE_KEY: &str = "hap.setupCode";
+const DATABASE_KEY: &str = "hap.database";
+
+const TLV_CONTENT_TYPE: &str = "application/pairing+tlv8";
+const JSON_CONTENT_TYPE: &str = "application/hap+json";
+
+// TLV types
+const TLV_METHOD: u8 = 0x00;
+const TLV_IDENTIFIER: u8 = 0x01;
+const TLV_SALT: u8 = 0x02;
+const TLV_PUBLIC_KEY: u8 = 0x03;
+const TLV_PROOF: u8 = 0x04;
+const TLV_ENCRYPTED_DATA: u8 = 0x05;
+const TLV_STATE: u8 = 0x06;
+const TLV_ERROR: u8 = 0x07;
+const TLV_SIGNATURE: u8 = 0x0a;
+const TLV_PERMISSIONS: u8 = 0x0b;
+const TLV_SEPARATOR: u8 = 0xff;
+
+// TLV errors
+const ERROR_UNKNOWN: u8 = 0x01;
+const ERROR_AUTHENTICATION: u8 = 0x02;
+const ERROR_MAX_TRIES: u8 = 0x05;
+const ERROR_UNAVAILABLE: u8 = 0x06;
+const ERROR_BUSY: u8 = 0x07;
+
+// /pairings methods
+const METHOD_ADD_PAIRING: u8 = 3;
+const METHOD_REMOVE_PAIRING: u8 = 4;
+const METHOD_LIST_PAIRINGS: u8 = 5;
+
+// Characteristic statuses
+const STATUS_INSUFFICIENT_PRIVILEGES: i64 = -70401;
+const STATUS_COMMUNICATION_FAILURE: i64 = -70402;
+const STATUS_READ_ONLY: i64 = -70404;
+const STATUS_WRITE_ONLY: i64 = -70405;
+const STATUS_NOTIFICATION_NOT_SUPPORTED: i64 = -70406;
+const STATUS_NOT_FOUND: i64 = -70409;
+const STATUS_INVALID_VALUE: i64 = -70410;
+
+const AID: u64 = 1;
+const IID_IDENTIFY: u64 = 2;
+const IID_MUTE: u64 = 11;
+const IID_VOLUME: u64 = 12;
+
+/// `ar_hap_start` configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct HapConfig {
+    /// Shown in the Home app, e.g. "Studio Mac"
+    pub name: String,
+    /// "XXX-XX-XXX"; default: one made up on first start and kept
+    pub setup_code: Option<String>,
+    /// 0 picks a free port
+    #[serde(default)]
+    pub port: u16,
+    /// Advertise `_hap._tcp` over Bonjour; off if Swift advertises it
+    #[serde(default = "default_advertise")]
+    pub advertise: bool,
+}
+
+fn default_advertise() -> bool {
+    true
+}
+
+// ---- TLV8
+
+/// Encode items, splitting values over 255 bytes into fragments
+pub(crate) fn encode_tlv(items: &[(u8, &[u8])]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for (kind, value) in items {
+        if value.is_empty() {
+            encoded.extend_from_slice(&[*kind, 0]);
+        }
+        for chunk in value.chunks(255) {
+            encoded.push(*kind);
+            encoded.push(chunk.len() as u8);
+            encoded.extend_from_slice(chunk);
+        }
+    }
+    encoded
+}
+
+/// Decode items, joining fragments back together
+pub(crate) fn decode_tlv(bytes: &[u8]) -> Result<Vec<(u8, Vec<u8>)>, AudioRemoteError> {
+    let mut items: Vec<(u8, Vec<u8>)> = Vec::new();
+    let mut at = 0;
+    let mut fragment = false;
+    while at < bytes.len() {
+        let (kind, length) = match bytes.get(at..at + 2) {
+            Some(header) => (header[0], usize::from(header[1])),
+            None => return Err(AudioRemoteError::InvalidData("TLV cut short".into())),
+        };
+        let value = bytes.get(at + 2..at + 2 + length).ok_or(AudioRemoteError::InvalidData("TLV cut short".into()))?;
+        match items.last_mut() {
+            Some((last, joined)) if fragment && *last == kind => joined.extend_from_slice(value),
+            _ => items.push((kind, value.to_vec())),
+        }
+        fragment = length == 255;
+        at += 2 + length;
+    }
+    Ok(items)
+}
+
+fn tlv_get(items: &[(u8, Vec<u8>)], kind: u8) -> Option<&[u8]> {
+    items.iter().find(|(k, _)| *k == kind).map(|(_, value)| value.as_slice())
+}
+
+fn tlv_required<'a>(items: &'a [(u8, Vec<u8>)], kind: u8, what: &str) -> Result<&'a [u8], AudioRemoteError> {
+    tlv_get(items, kind).ok_or_else(|| AudioRemoteError::InvalidData(format!("the {what} is missing")))
+}
+
+fn tlv_response(items: &[(u8, &[u8])]) -> Response {
+    Response::new(200).with_header("Content-Type", TLV_CONTENT_TYPE).with_body(encode_tlv(items))
+}
+
+fn tlv_error(state: u8, error: u8) -> Response {
+    tlv_response(&[(TLV_STATE, &[state]), (TLV_ERROR, &[error])])
+}
+
+// ---- Crypto
+
+fn derive_key(secret: &[u8], salt: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    Hkdf::<Sha512>::new(Some(salt), secret).expand(info, &mut key).expect("32 bytes is a valid HKDF-SHA512 length");
+    key
+}
+
+/// The nonces of the pairing messages: four zero bytes and an 8-byte label like "PS-Msg05"
+fn label_nonce(label: &[u8; 8]) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(label);
+    nonce
+}
+
+/// The nonces of session frames: four zero bytes and the frame counter, little-endian
+fn counter_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+fn seal(key: &[u8; 32], nonce: [u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into()).encrypt(&nonce.into(), Payload { msg: plaintext, aad }).expect("sealing can't fail")
+}
+
+fn open(key: &[u8; 32], nonce: [u8; 12], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, AudioRemoteError> {
+    ChaCha20Poly1305::new(key.into())
+        .decrypt(&nonce.into(), Payload { msg: ciphertext, aad })
+        .map_err(|_| AudioRemoteError::VerificationFailed("the message doesn't decrypt".into()))
+}
+
+fn verify_signature(public_key: &[u8], message: &[u8], signature: &[u8]) -> Result<(), AudioRemoteError> {
+    let invalid = || AudioRemoteError::VerificationFailed("the controller's signature doesn't verify".into());
+    let public_key = VerifyingKey::from_bytes(public_key.try_into().map_err(|_| invalid())?).map_err(|_| invalid())?;
+    let signature = Signature::from_slice(signature).map_err(|_| invalid())?;
+    public_key.verify_strict(message, &signature).map_err(|_| invalid())
+}
+
+/// One direction of an encrypted session
+struct Cipher {
+    key: [u8; 32],
+    counter: u64,
+}
+
+impl Cipher {
+    fn new(key: [u8; 32]) -> Self {
+        Self { key, counter: 0 }
+    }
+
+    fn nonce(&mut self) -> [u8; 12] {
+        let nonce = counter_nonce(self.counter);
+        self.counter += 1;
+        nonce
+    }
+}
+
+/// The reading half of a connection, decrypting once the session is verified
+struct Reader {
+    stream: TcpStream,
+    cipher: Option<Cipher>,
+    plaintext: Vec<u8>,
+    at: usize,
+}
+
+impl Read for Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(cipher) = &mut self.cipher else {
+            return self.stream.read(buf);
+        };
+        if self.at == self.plaintext.len() {
+            let mut length = [0; 2];
+            match self.stream.read_exact(&mut length) {
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                other => other?,
+            }
+            let size = usize::from(u16::from_le_bytes(length));
+            if size > MAX_FRAME {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "frame over 1024 bytes"));
+            }
+            let mut sealed = vec![0; size + TAG_LENGTH];
+            self.stream.read_exact(&mut sealed)?;
+            let nonce = cipher.nonce();
+            self.plaintext = open(&cipher.key, nonce, &length, &sealed).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            self.at = 0;
+        }
+        let count = buf.len().min(self.plaintext.len() - self.at);
+        buf[..count].copy_from_slice(&self.plaintext[self.at..self.at + count]);
+        self.at += count;
+        Ok(count)
+    }
+}
+
+/// The writing half, shared with the event thread
+struct Writer {
+    stream: TcpStream,
+    cipher: Option<Cipher>,
+}
+
+impl Writer {
+    fn send(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let Some(cipher) = &mut self.cipher else {
+            return self.stream.write_all(bytes);
+        };
+        let mut sealed = Vec::with_capacity(bytes.len() + bytes.len().div_ceil(MAX_FRAME) * (2 + TAG_LENGTH));
+        for frame in bytes.chunks(MAX_FRAME) {
+            let length = (frame.len() as u16).to_le_bytes();
+            let nonce = cipher.nonce();
+            sealed.extend_from_slice(&length);
+            sealed.extend_from_slice(&seal(&cipher.key, nonce, &length, frame));
+        }
+        self.stream.write_all(&sealed)
+    }
+}
+
+fn send_response(writer: &Mutex<Writer>, response: &Response) -> io::Result<()> {
+    let mut bytes = Vec::new();
+    server::write_response(&mut bytes, response, true)?;
+    writer.lock().unwrap_or_else(|e| e.into_inner()).send(&bytes)
+}
+
+// ---- Pairing state
+
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    id: String,
+    /// The Ed25519 seed, base64
+    seed: String,
+}
+
+/// The accessory's long-term identity, created on first use
+struct Identity {
+    /// "XX:XX:XX:XX:XX:XX", random; what controllers know the accessory by
+    id: String,
+    key: SigningKey,
+}
+
+fn identity() -> Result<Identity, AudioRemoteError> {
+    let store = store::global();
+    if let Some(stored) = store.get::<StoredIdentity>(IDENTITY_KEY) {
+        if let Ok(seed) = STANDARD.decode(&stored.seed).map_err(|_| ()).and_then(|seed| <[u8; 32]>::try_from(seed).map_err(|_| ())) {
+            return Ok(Identity { id: stored.id, key: SigningKey::from_bytes(&seed) });
+        }
+        log::warn!("the stored HomeKit identity is damaged; making a new one");
+    }
+    let id = random_bytes::<6>()?.iter().map(|b| format!("{b:02X}")).collect::<Vec<_>>().join(":");
+    let seed = random_bytes::<32>()?;
+    store.set(IDENTITY_KEY, &StoredIdentity { id: id.clone(), seed: STANDARD.encode(seed) })?;
+    Ok(Identity { id, key: SigningKey::from_bytes(&seed) })
+}
+
+/// An iOS device (or a home hub) paired with the accessory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Controller {
+    pub id: String,
+    /// Ed25519, base64
+    pub public_key: String,
+    pub admin: bool,
+}
+
+impl Controller {
+    fn public_key(&self) -> Vec<u8> {
+        STANDARD.decode(&self.public_key).unwrap_or_default()
+    }
+}
+
+pub fn controllers() -> Vec<Controller> {
+    store::global().get(CONTROLLERS_KEY).unwrap_or_default()
+}
+
+fn save_controllers(controllers: &[Controller]) -> Result<(), AudioRemoteError> {
+    store::global().set(CONTROLLERS_KEY, &controllers)
+}
+
+/// Codes HomeKit refuses as too easy to guess
+fn is_trivial(digits: &str) -> bool {
+    digits.bytes().all(|b| b == digits.as_bytes()[0]) || digits == "12345678" || digits == "87654321"
+}
+
+fn validate_setup_code(code: &str) -> Result<(), AudioRemoteError> {
+    let digits: String = code.chars().filter(|c| *c != '-').collect();
+    let shaped = code.len() == 10 && code.as_bytes()[3] == b'-' && code.as_bytes()[6] == b'-';
+    if !shaped || digits.len() != 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(AudioRemoteError::InvalidArgument(format!("the setup code must look like 123-45-678, not \"{code}\"")));
+    }
+    if is_trivial(&digits) {
+        return Err(AudioRemoteError::InvalidArgument(format!("HomeKit doesn't accept the setup code {code}")));
+    }
+    Ok(())
+}
+
+/// The configured setup code, else the one made up on the first start
+fn setup_code(configured: Option<&str>) -> Result<String, AudioRemoteError> {
+    if let Some(code) = configured {
+        validate_setup_code(code)?;
+        return Ok(code.into());
+    }
+    let store = store::global();
+    if let Some(code) = store.get::<String>(SETUP_CODE_KEY) {
+        return Ok(code);
+    }
+    let code = loop {
+        let value = u32::from_le_bytes(random_bytes()?) % 100_000_000;
+        let digits = format!("{value:08}");
+        if !is_trivial(&digits) {
+            break format!("{}-{}-{}", &digits[..3], &digits[3..5], &digits[5..]);
+        }
+    };
+    store.set(SETUP_CODE_KEY, &code)?;
+    Ok(code)
+}
+
+// ---- The accessory
+
+fn characteristic(iid: u64, kind: &str, perms: &[&str], format: &str, value: Option<Value>) -> Value {
+    let mut characteristic = json!({"iid": iid, "type": kind, "perms": perms, "format": format});
+    if let Some(value) = value {
+        characteristic["value"] = value;
+    }
+    characteristic
+}
+
+/// The accessory database, with the current values when `state` is given
+fn database(name: &str, serial: &str, state: Option<&VolumeState>) -> Value {
+    let string = |iid: u64, kind: &str, value: &str| characteristic(iid, kind, &["pr"], "string", Some(json!(value)));
+    let mut volume = characteristic(IID_VOLUME, "119", &["pr", "pw", "ev"], "uint8", state.map(|s| json!(volume_percent(s))));
+    volume["unit"] = json!("percentage");
+    volume["minValue"] = json!(0);
+    volume["maxValue"] = json!(100);
+    volume["minStep"] = json!(1);
+    json!({"accessories": [{
+        "aid": AID,
+        "services": [
+            {
+                "iid": 1,
+                "type": "3E",
+                "characteristics": [
+                    characteristic(IID_IDENTIFY, "14", &["pw"], "bool", None),
+                    string(3, "20", "Audio Remote"),
+                    string(4, "21", "Mac"),
+                    string(5, "23", name),
+                    string(6, "30", serial),
+                    string(7, "52", env!("CARGO_PKG_VERSION")),
+                ],
+            },
+            {
+                "iid": 8,
+                "type": "A2",
+                "characteristics": [string(9, "37", PROTOCOL_VERSION)],
+            },
+            {
+                "iid": 10,
+                "type": "113",
+                "primary": true,
+                "characteristics": [
+                    characteristic(IID_MUTE, "11A", &["pr", "pw", "ev"], "bool", state.map(|s| json!(s.muted))),
+                    volume,
+                ],
+            },
+        ],
+    }]})
+}
+
+fn volume_percent(state: &VolumeState) -> u8 {
+    (state.volume.clamp(0.0, 1.0) * 100.0).round() as u8
+}
+
+/// The value of a readable characteristic
+fn read_value(database: &Value, iid: u64) -> Result<Value, i64> {
+    let services = database["accessories"][0]["services"].as_array().into_iter().flatten();
+    let characteristic = services
+        .flat_map(|service| service["characteristics"].as_array().into_iter().flatten())
+        .find(|characteristic| characteristic["iid"] == iid)
+        .ok_or(STATUS_NOT_FOUND)?;
+    characteristic.get("value").cloned().ok_or(STATUS_WRITE_ONLY)
+}
+
+/// A write turned into a request for Swift, or None for Identify
+fn write_request(iid: u64, value: &Value) -> Result<Option<(Endpoint, Value)>, i64> {
+    match iid {
+        IID_IDENTIFY => Ok(None),
+        IID_MUTE => {
+            let muted = value.as_bool().or_else(|| value.as_u64().filter(|v| *v <= 1).map(|v| v == 1)).ok_or(STATUS_INVALID_VALUE)?;
+            Ok(Some((Endpoint::SetMute, json!({"muted": muted}))))
+        }
+        IID_VOLUME => {
+            let percent = value.as_f64().filter(|v| (0.0..=100.0).contains(v)).ok_or(STATUS_INVALID_VALUE)?;
+            Ok(Some((Endpoint::SetVolume, json!({"volume": percent.round() / 100.0}))))
+        }
+        3..=9 => Err(STATUS_READ_ONLY),
+        _ => Err(STATUS_NOT_FOUND),
+    }
+}
+
+/// `"1.11,1.12"` into instance IDs; only accessory 1 exists
+fn parse_ids(ids: &str) -> Result<Vec<(u64, u64)>, AudioRemoteError> {
+    ids.split(',')
+        .map(|id| {
+            id.split_once('.')
+                .and_then(|(aid, iid)| Some((aid.trim().parse().ok()?, iid.trim().parse().ok()?)))
+                .ok_or_else(|| AudioRemoteError::InvalidArgument(format!("\"{id}\" isn't an aid.iid pair")))
+        })
+        .collect()
+}
+
+fn hap_json(status: u16, value: &Value) -> Response {
+    Response::new(status).with_header("Content-Type", JSON_CONTENT_TYPE).with_body(value.to_string().into_bytes())
+}
+
+fn hap_status(status: u16, code: i64) -> Response {
+    hap_json(status, &json!({"status": code}))
+}
+
+// ---- The server
+
+/// A pair setup in progress, on one connection
+struct Setup {
+    connection: u64,
+    srp: srp::Server,
+    /// The SRP session key, once M3 checked out
+    key: Option<Vec<u8>>,
+}
+
+/// A pair verify in progress, on one connection
+struct Verify {
+    public: [u8; 32],
+    controller_public: [u8; 32],
+    shared: [u8; 32],
+}
+
+struct Shared {
+    name: String,
+    setup_code: String,
+    identity: Identity,
+    port: u16,
+    advertise: bool,
+    stopping: AtomicBool,
+    setup: Mutex<Option<Setup>>,
+    failed_setups: AtomicU32,
+    next_connection: AtomicU64,
+    /// Open connections and the controller each verified as, so stop() and removed pairings can end them
+    connections: Mutex<BTreeMap<u64, (TcpStream, Option<String>)>>,
+    advertisement: Mutex<Option<Advertisement>>,
+    /// The last volume state seen, for reads when Swift doesn't answer
+    state: Mutex<Option<VolumeState>>,
+}
+
+impl Shared {
+    fn connections(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, (TcpStream, Option<String>)>> {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The Bonjour TXT record: config number, identity, pairing state and category
+    fn txt(&self) -> BTreeMap<String, String> {
+        let paired = !controllers().is_empty();
+        BTreeMap::from([
+            ("c#".to_owned(), config_number(&self.name, &self.identity.id).to_string()),
+            ("ff".to_owned(), "0".to_owned()),
+            ("id".to_owned(), self.identity.id.clone()),
+            ("md".to_owned(), self.name.clone()),
+            ("pv".to_owned(), "1.1".to_owned()),
+            ("s#".to_owned(), "1".to_owned()),
+            ("sf".to_owned(), if paired { "0" } else { "1" }.to_owned()),
+            ("ci".to_owned(), CATEGORY_SPEAKER.to_string()),
+        ])
+    }
+
+    /// Advertise, or advertise again after the pairing state changed
+    fn advertise(&self) {
+        if !self.advertise {
+            return;
+        }
+        let mut advertisement = self.advertisement.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(previous) = advertisement.take() {
+            previous.stop();
+        }
+        let service = Service {
+            service_type: SERVICE_TYPE.into(),
+            instance: self.name.clone(),
+            host: mdns::host_name(),
+            port: self.port,
+            txt: dns::txt_entries(&self.txt()),
+            addresses: Vec::new(),
+        };
+        match mdns::advertise(service) {
+            Ok(running) => *advertisement = Some(running),
+            Err(e) => log::warn!("can't advertise the HomeKit accessory: {e}"),
+        }
+    }
+
+    /// The current volume state from Swift, else the last one seen
+    fn volume_state(&self) -> Option<VolumeState> {
+        let mut last = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if let Ok(Ok(state)) = routes::perform(Endpoint::GetVolume, b"").map(serde_json::from_value::<VolumeState>) {
+            *last = Some(state);
+        }
+        last.clone()
+    }
+}
+
+/// The configuration number, bumped whenever the accessory database changes
+fn config_number(name: &str, serial: &str) -> u32 {
+    #[derive(Serialize, Deserialize)]
+    struct Stored {
+        hash: String,
+        number: u32,
+    }
+    let hash: String = Sha256::digest(database(name, serial, None).to_string()).iter().take(8).map(|b| format!("{b:02x}")).collect();
+    let store = store::global();
+    match store.get::<Stored>(DATABASE_KEY) {
+        Some(stored) if stored.hash == hash => stored.number,
+        stored => {
+            let number = stored.map_or(1, |stored| stored.number % 65535 + 1);
+            if let Err(e) = store.set(DATABASE_KEY, &Stored { hash, number }) {
+                log::warn!("can't save the HomeKit configuration number: {e}");
+            }
+            number
+        }
+    }
+}
+
+/// What one connection knows
+struct Connection {
+    id: u64,
+    peer: SocketAddr,
+    verify: Option<Verify>,
+    /// The controller this connection verified as
+    controller: Option<Controller>,
+    /// Characteristics the controller subscribed to
+    events: Arc<Mutex<BTreeSet<u64>>>,
+}
+
+fn pair_setup(shared: &Shared, connection: &Connection, body: &[u8]) -> Result<Response, AudioRemoteError> {
+    let items = decode_tlv(body)?;
+    let state = tlv_required(&items, TLV_STATE, "state")?.first().copied().unwrap_or_default();
+    let mut setup = shared.setup.lock().unwrap_or_else(|e| e.into_inner());
+    match state {
+        1 => {
+            if !controllers().is_empty() {
+                return Ok(tlv_error(2, ERROR_UNAVAILABLE));
+            }
+            if shared.failed_setups.load(Ordering::Acquire) >= MAX_SETUP_ATTEMPTS {
+                return Ok(tlv_error(2, ERROR_MAX_TRIES));
+            }
+            if setup.as_ref().is_some_and(|setup| setup.connection != connection.id) {
+                return Ok(tlv_error(2, ERROR_BUSY));
+            }
+            let srp = srp::Server::new(SRP_USERNAME, shared.setup_code.as_bytes())?;
+            let response = tlv_response(&[(TLV_STATE, &[2]), (TLV_SALT, srp.salt()), (TLV_PUBLIC_KEY, &srp.public_key())]);
+            *setup = Some(Setup { connection: connection.id, srp, key: None });
+            Ok(response)
+        }
+        3 => {
+            let Some(current) = setup.as_mut().filter(|setup| setup.connection == connection.id) else {
+                return Ok(tlv_error(4, ERROR_UNKNOWN));
+            };
+            let public = tlv_required(&items, TLV_PUBLIC_KEY, "public key")?;
+            let proof = tlv_required(&items, TLV_PROOF, "proof")?;
+            match current.srp.verify(public, proof) {
+                Ok(verified) => {
+                    current.key = Some(verified.key);
+                    Ok(tlv_response(&[(TLV_STATE, &[4]), (TLV_PROOF, &verified.proof)]))
+                }
+                Err(e) => {
+                    setup.take();
+                    let failed = shared.failed_setups.fetch_add(1, Ordering::AcqRel) + 1;
+                    log::warn!("HomeKit pair setup from {} failed ({failed} so far): {e}", connection.peer);
+                    Ok(tlv_error(4, ERROR_AUTHENTICATION))
+                }
+            }
+        }
+        5 => {
+            let Some(key) = setup.as_ref().filter(|setup| setup.connection == connection.id).and_then(|setup| setup.key.clone()) else {
+                return Ok(tlv_error(6, ERROR_UNKNOWN));
+            };
+            setup.take();
+            let encryption_key = derive_key(&key, b"Pair-Setup-Encrypt-Salt", b"Pair-Setup-Encrypt-Info");
+            let sealed = tlv_required(&items, TLV_ENCRYPTED_DATA, "encrypted data")?;
+            let Ok(plaintext) = open(&encryption_key, label_nonce(b"PS-Msg05"), &[], sealed) else {
+                return Ok(tlv_error(6, ERROR_AUTHENTICATION));
+            };
+            let sub = decode_tlv(&plaintext)?;
+            let controller_id = tlv_required(&sub, TLV_IDENTIFIER, "controller ID")?;
+            let controller_key = tlv_required(&sub, TLV_PUBLIC_KEY, "controller key")?;
+            let signature = tlv_required(&sub, TLV_SIGNATURE, "signature")?;
+            let signed = [&derive_key(&key, b"Pair-Setup-Controller-Sign-Salt", b"Pair-Setup-Controller-Sign-Info")[..], controller_id, controller_key].concat();
+            if verify_signature(controller_key, &signed, signature).is_err() {
+                return Ok(tlv_error(6, ERROR_AUTHENTICATION));
+            }
+            let controller = Controller {
+                id: String::from_utf8_lossy(controller_id).into_owned(),
+                public_key: STANDARD.encode(controller_key),
+                admin: true,
+            };
+            save_controllers(std::slice::from_ref(&controller))?;
+            log::info!("HomeKit controller {} paired", controller.id);
+
+            let accessory_key = shared.identity.key.verifying_key().to_bytes();
+            let accessory_x = derive_key(&key, b"Pair-Setup-Accessory-Sign-Salt", b"Pair-Setup-Accessory-Sign-Info");
+            let signature = shared.identity.key.sign(&[&accessory_x[..], shared.identity.id.as_bytes(), &accessory_key].concat());
+            let sub = encode_tlv(&[
+                (TLV_IDENTIFIER, shared.identity.id.as_bytes()),
+                (TLV_PUBLIC_KEY, &accessory_key),
+                (TLV_SIGNATURE, &signature.to_bytes()),
+            ]);
+            let sealed = seal(&encryption_key, label_nonce(b"PS-Msg06"), &[], &sub);
+            drop(setup);
+            shared.advertise();
+            Ok(tlv_response(&[(TLV_STATE, &[6]), (TLV_ENCRYPTED_DATA, &sealed)]))
+        }
+        state => Err(AudioRemoteError::InvalidData(format!("pair setup has no state {state}"))),
+    }
+}
+
+/// Session keys once pair verify finishes: (controller to accessory, accessory to controller)
+type SessionKeys = ([u8; 32], [u8; 32]);
+
+fn pair_verify(shared: &Shared, connection: &mut Connection, body: &[u8]) -> Result<(Response, Option<SessionKeys>), AudioRemoteError> {
+    let items = decode_tlv(body)?;
+    let state = tlv_required(&items, TLV_STATE, "state")?.first().copied().unwrap_or_default();
+    match state {
+        1 => {
+            let controller_public: [u8; 32] = tlv_required(&items, TLV_PUBLIC_KEY, "public key")?
+                .try_into()
+                .map_err(|_| AudioRemoteError::InvalidData("the public key isn't 32 bytes".into()))?;
+            let secret = random_bytes::<32>()?;
+            let public = MontgomeryPoint::mul_base_clamped(secret).to_bytes();
+            let shared_secret = MontgomeryPoint(controller_public).mul_clamped(secret).to_bytes();
+            let signature = shared.identity.key.sign(&[&public[..], shared.identity.id.as_bytes(), &controller_public].concat());
+            let sub = encode_tlv(&[(TLV_IDENTIFIER, shared.identity.id.as_bytes()), (TLV_SIGNATURE, &signature.to_bytes())]);
+            let key = derive_key(&shared_secret, b"Pair-Verify-Encrypt-Salt", b"Pair-Verify-Encrypt-Info");
+            let sealed = seal(&key, label_nonce(b"PV-Msg02"), &[], &sub);
+            connection.verify = Some(Verify { public, controller_public, shared: shared_secret });
+            Ok((tlv_response(&[(TLV_STATE, &[2]), (TLV_PUBLIC_KEY, &public), (TLV_ENCRYPTED_DATA, &sealed)]), None))
+        }
+        3 => {
+            let Some(verify) = connection.verify.take() else {
+                return Ok((tlv_error(4, ERROR_AUTHENTICATION), None));
+            };
+            let key = derive_key(&verify.shared, b"Pair-Verify-Encrypt-Salt", b"Pair-Verify-Encrypt-Info");
+            let sealed = tlv_required(&items, TLV_ENCRYPTED_DATA, "encrypted data")?;
+            let Ok(plaintext) = open(&key, label_nonce(b"PV-Msg03"), &[], sealed) else {
+                return Ok((tlv_error(4, ERROR_AUTHENTICATION), None));
+            };
+            let sub = decode_tlv(&plaintext)?;
+            let controller_id = String::from_utf8_lossy(tlv_required(&sub, TLV_IDENTIFIER, "controller ID")?).into_owned();
+            let signature = tlv_required(&sub, TLV_SIGNATURE, "signature")?;
+            let Some(controller) = controllers().into_iter().find(|controller| controller.id == controller_id) else {
+                log::warn!("HomeKit controller {controller_id} at {} isn't paired", connection.peer);
+                return Ok((tlv_error(4, ERROR_AUTHENTICATION), None));
+            };
+            let signed = [&verify.controller_public[..], controller_id.as_bytes(), &verify.public].concat();
+            if verify_signature(&controller.public_key(), &signed, signature).is_err() {
+                return Ok((tlv_error(4, ERROR_AUTHENTICATION), None));
+            }
+            let read = derive_key(&verify.shared, b"Control-Salt", b"Control-Write-Encryption-Key");
+            let write = derive_key(&verify.shared, b"Control-Salt", b"Control-Read-Encryption-Key");
+            if let Some(entry) = shared.connections().get_mut(&connection.id) {
+                entry.1 = Some(controller.id.clone());
+            }
+            connection.controller = Some(controller);
+            Ok((tlv_response(&[(TLV_STATE, &[4])]), Some((read, write))))
+        }
+        state => Err(AudioRemoteError::InvalidData(format!("pair verify has no state {state}"))),
+    }
+}
+
+/// `/pairings`, for admins: add, remove and list controllers
+fn manage_pairings(shared: &Shared, connection: &Connection, body: &[u8]) -> Result<Response, AudioRemoteError> {
+    let items = decode_tlv(body)?;
+    if !connection.controller.as_ref().is_some_and(|controller| controller.admin) {
+        return Ok(tlv_error(2, ERROR_AUTHENTICATION));
+    }
+    let method = tlv_required(&items, TLV_METHOD, "method")?.first().copied().unwrap_or_default();
+    let mut controllers = controllers();
+    match method {
+        METHOD_ADD_PAIRING => {
+            let id = String::from_utf8_lossy(tlv_required(&items, TLV_IDENTIFIER, "controller ID")?).into_owned();
+            let public_key = STANDARD.encode(tlv_required(&items, TLV_PUBLIC_KEY, "controller key")?);
+            let admin = tlv_get(&items, TLV_PERMISSIONS).and_then(|p| p.first()).is_some_and(|p| p & 1 == 1);
+            match controllers.iter_mut().find(|controller| controller.id == id) {
+                Some(existing) if existing.public_key != public_key => return Ok(tlv_error(2, ERROR_UNKNOWN)),
+                Some(existing) => existing.admin = admin,
+                None => controllers.push(Controller { id, public_key, admin }),
+            }
+            save_controllers(&controllers)?;
+            Ok(tlv_response(&[(TLV_STATE, &[2])]))
+        }
+        METHOD_REMOVE_PAIRING => {
+            let id = String::from_utf8_lossy(tlv_required(&items, TLV_IDENTIFIER, "controller ID")?).into_owned();
+            controllers.retain(|controller| controller.id != id);
+            // Without an admin nobody could manage the rest
+            if !controllers.iter().any(|controller| controller.admin) {
+                controllers.clear();
+            }
+            save_controllers(&controllers)?;
+            log::info!("HomeKit controller {id} removed");
+            let response = tlv_response(&[(TLV_STATE, &[2])]);
+            end_sessions(shared, |controller| !controllers.iter().any(|c| c.id == controller), Some(connection.id));
+            if controllers.is_empty() {
+                shared.advertise();
+            }
+            Ok(response)
+        }
+        METHOD_LIST_PAIRINGS => {
+            let keys: Vec<Vec<u8>> = controllers.iter().map(Controller::public_key).collect();
+            let mut list: Vec<(u8, &[u8])> = vec![(TLV_STATE, &[2])];
+            for (i, (controller, key)) in controllers.iter().zip(&keys).enumerate() {
+                if i > 0 {
+                    list.push((TLV_SEPARATOR, &[]));
+                }
+                let permissions: &[u8] = if controller.admin { &[1] } else { &[0] };
+                list.extend([(TLV_IDENTIFIER, controller.id.as_bytes()), (TLV_PUBLIC_KEY, key.as_slice()), (TLV_PERMISSIONS, permissions)]);
+            }
+            Ok(tlv_response(&list))
+        }
+        method => Err(AudioRemoteError::InvalidData(format!("/pairings has no method {method}"))),
+    }
+}
+
+/// Close the sessions of controllers `removed` says are gone, except `except` (it gets its answer first)
+fn end_sessions(shared: &Shared, removed: impl Fn(&str) -> bool, except: Option<u64>) {
+    for (id, (stream, controller)) in shared.connections().iter() {
+        if Some(*id) != except && controller.as_deref().is_some_and(&removed) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+fn read_characteristics(shared: &Shared, request: &Request) -> Response {
+    let ids = match request.query_param("id").map(|ids| parse_ids(&ids)) {
+        Some(Ok(ids)) => ids,
+        _ => return hap_status(400, STATUS_INVALID_VALUE),
+    };
+    let state = shared.volume_state();
+    let database = database(&shared.name, &shared.identity.id, state.as_ref());
+    let mut failed = false;
+    let characteristics: Vec<Value> = ids
+        .iter()
+        .map(|&(aid, iid)| {
+            let value = match aid {
+                AID if state.is_none() && (iid == IID_MUTE || iid == IID_VOLUME) => Err(STATUS_COMMUNICATION_FAILURE),
+                AID => read_value(&database, iid),
+                _ => Err(STATUS_NOT_FOUND),
+            };
+            match value {
+                Ok(value) => json!({"aid": aid, "iid": iid, "value": value, "status": 0}),
+                Err(status) => {
+                    failed = true;
+                    json!({"aid": aid, "iid": iid, "status": status})
+                }
+            }
+        })
+        .collect();
+    match failed {
+        true => hap_json(207, &json!({"characteristics": characteristics})),
+        false => {
+            let characteristics: Vec<Value> = characteristics
+                .into_iter()
+                .map(|mut c| {
+                    c.as_object_mut().map(|c| c.remove("status"));
+                    c
+                })
+                .collect();
+            hap_json(200, &json!({"characteristics": characteristics}))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WriteRequest {
+    characteristics: Vec<CharacteristicWrite>,
+}
+
+#[derive(Deserialize)]
+struct CharacteristicWrite {
+    aid: u64,
+    iid: u64,
+    value: Option<Value>,
+    ev: Option<bool>,
+}
+
+fn write_characteristics(shared: &Shared, connection: &Connection, body: &[u8]) -> Response {
+    let Ok(request) = serde_json::from_slice::<WriteRequest>(body) else {
+        return hap_status(400, STATUS_INVALID_VALUE);
+    };
+    let mut statuses = Vec::new();
+    for write in request.characteristics {
+        let status = (|| {
+            if write.aid != AID {
+                return Err(STATUS_NOT_FOUND);
+            }
+            if let Some(ev) = write.ev {
+                if write.iid != IID_MUTE && write.iid != IID_VOLUME {
+                    return Err(STATUS_NOTIFICATION_NOT_SUPPORTED);
+                }
+                let mut events = connection.events.lock().unwrap_or_else(|e| e.into_inner());
+                match ev {
+                    true => events.insert(write.iid),
+                    false => events.remove(&write.iid),
+                };
+            }
+            let Some(value) = &write.value else {
+                return Ok(());
+            };
+            let Some((endpoint, body)) = write_request(write.iid, value)? else {
+                log::info!("HomeKit controller {} asked the Mac to identify itself", connection.peer);
+                return Ok(());
+            };
+            let state = routes::perform(endpoint, body.to_string().as_bytes()).map_err(|(status, message)| {
+                log::warn!("HomeKit write to {} failed: {status} {message}", write.iid);
+                STATUS_COMMUNICATION_FAILURE
+            })?;
+            if let Ok(state) = serde_json::from_value::<VolumeState>(state) {
+                *shared.state.lock().unwrap_or_else(|e| e.into_inner()) = Some(state);
+            }
+            Ok(())
+        })();
+        statuses.push((write.aid, write.iid, status.err().unwrap_or(0)));
+    }
+    match statuses.iter().all(|(_, _, status)| *status == 0) {
+        true => Response::new(204),
+        false => {
+            let characteristics: Vec<Value> = statuses.iter().map(|(aid, iid, status)| json!({"aid": aid, "iid": iid, "status": status})).collect();
+            hap_json(207, &json!({"characteristics": characteristics}))
+        }
+    }
+}
+
+/// Answer one request; the session keys when pair verify just finished
+fn respond(shared: &Shared, connection: &mut Connection, request: &Request) -> (Response, Option<SessionKeys>) {
+    let verified = connection.controller.is_some();
+    let tlv = |result: Result<Response, AudioRemoteError>| result.unwrap_or_else(|e| Response::error(400, e.to_string()));
+    let response = match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/pair-setup") => tlv(pair_setup(shared, connection, &request.body)),
+        ("POST", "/pair-verify") => match pair_verify(shared, connection, &request.body) {
+            Ok((response, keys)) => return (response, keys),
+            Err(e) => Response::error(400, e.to_string()),
+        },
+        ("POST", "/identify") if controllers().is_empty() => {
+            log::info!("HomeKit asked the Mac to identify itself");
+            Response::new(204)
+        }
+        ("POST", "/identify") => hap_status(400, STATUS_INSUFFICIENT_PRIVILEGES),
+        _ if !verified => hap_status(470, STATUS_INSUFFICIENT_PRIVILEGES),
+        ("GET", "/accessories") => {
+            hap_json(200, &database(&shared.name, &shared.identity.id, shared.volume_state().as_ref()))
+        }
+        ("GET", "/characteristics") => read_characteristics(shared, request),
+        ("PUT", "/characteristics") => write_characteristics(shared, connection, &request.body),
+        ("POST", "/pairings") => tlv(manage_pairings(shared, connection, &request.body)),
+        _ => Response::error(404, format!("no route for {} {}", request.method, request.path)),
+    };
+    (response, None)
+}
+
+/// Push volume and mute changes to a verified controller that subscribed to them
+fn push_events(shared: &Shared, subscription: &Subscription, writer: &Mutex<Writer>, events: &Mutex<BTreeSet<u64>>) -> io::Result<()> {
+    let mut last = shared.state.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    while let Some(batch) = subscription.next(Duration::from_secs(3600)) {
+        for event in batch {
+            let Ok(state) = serde_json::from_value::<VolumeState>(event.data) else {
+                continue;
+            };
+            *shared.state.lock().unwrap_or_else(|e| e.into_inner()) = Some(state.clone());
+            // With nothing sent before, every subscribed value is news
+            let previous = last.replace(state.clone());
+            let subscribed = events.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            let mut changed = Vec::new();
+            if subscribed.contains(&IID_MUTE) && previous.as_ref().is_none_or(|previous| previous.muted != state.muted) {
+                changed.push(json!({"aid": AID, "iid": IID_MUTE, "value": state.muted}));
+            }
+            if subscribed.contains(&IID_VOLUME) && previous.as_ref().is_none_or(|previous| volume_percent(previous) != volume_percent(&state)) {
+                changed.push(json!({"aid": AID, "iid": IID_VOLUME, "value": volume_percent(&state)}));
+            }
+            if changed.is_empty() {
+                continue;
+            }
+            let body = json!({"characteristics": changed}).to_string();
+            let message = format!("EVENT/1.0 200 OK\r\nContent-Type: {JSON_CONTENT_TYPE}\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+            writer.lock().unwrap_or_else(|e| e.into_inner()).send(message.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+fn serve(stream: TcpStream, connection: &mut Connection, shared: &Arc<Shared>) -> io::Result<()> {
+    let writer = Arc::new(Mutex::new(Writer { stream: stream.try_clone()?, cipher: None }));
+    let mut reader = BufReader::new(Reader { stream, cipher: None, plaintext: Vec::new(), at: 0 });
+    let mut pusher: Option<(Arc<Subscription>, JoinHandle<()>)> = None;
+    let result = loop {
+        let request = match server::read_request(&mut reader, connection.peer) {
+            Ok(request) => request,
+            Err(ReadError::Closed) => break Ok(()),
+            Err(ReadError::Rejected(response)) => {
+                let _ = send_response(&writer, &response);
+                break Ok(());
+            }
+        };
+        let (response, keys) = respond(shared, connection, &request);
+        log::debug!("HomeKit {} {} -> {} [{}]", request.method, request.path, response.status, connection.peer);
+        if let Err(e) = send_response(&writer, &response) {
+            break Err(e);
+        }
+        // Everything after pair verify's last answer is encrypted
+        let Some((read, write)) = keys else {
+            continue;
+        };
+        reader.get_mut().cipher = Some(Cipher::new(read));
+        writer.lock().unwrap_or_else(|e| e.into_inner()).cipher = Some(Cipher::new(write));
+        let subscription = Arc::new(HUB.subscribe(BTreeSet::from([Topic::Volume])));
+        let spawned = {
+            let (shared, subscription, writer, events) = (shared.clone(), subscription.clone(), writer.clone(), connection.events.clone());
+            thread::Builder::new()
+                .name(format!("audioremote-hap-events-{}", connection.id))
+                .spawn(move || drop(push_events(&shared, &subscription, &writer, &events)))
+        };
+        match spawned {
+            Ok(handle) => pusher = Some((subscription, handle)),
+            Err(e) => break Err(e),
+        }
+    };
+    if let Some((subscription, handle)) = pusher {
+        subscription.close();
+        let _ = handle.join();
+    }
+    let _ = writer.lock().unwrap_or_else(|e| e.into_inner()).stream.shutdown(Shutdown::Both);
+    result
+}
+
+fn accept_loop(listener: TcpListener, shared: Arc<Shared>) {
+    for stream in listener.incoming() {
+        if shared.stopping.load(Ordering::Acquire) {
+            return;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("HomeKit accept failed: {e}");
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        let Ok(peer) = stream.peer_addr().map(server::canonical_peer) else {
+            continue;
+        };
+        let id = shared.next_connection.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut connections = shared.connections();
+            if connections.len() >= MAX_CONNECTIONS {
+                log::warn!("refusing HomeKit connection from {peer}: {MAX_CONNECTIONS} connections open");
+                continue;
+            }
+            match stream.try_clone() {
+                Ok(clone) => connections.insert(id, (clone, None)),
+                Err(_) => continue,
+            };
+        }
+        let connection = shared.clone();
+        let spawned = thread::Builder::new().name(format!("audioremote-hap-{id}")).spawn(move || {
+            let mut state = Connection { id, peer, verify: None, controller: None, events: Arc::default() };
+            if let Err(e) = serve(stream, &mut state, &connection) {
+                log::debug!("HomeKit connection from {peer} ended: {e}");
+            }
+            connection.connections().remove(&id);
+            let mut setup = connection.setup.lock().unwrap_or_else(|e| e.into_inner());
+            if setup.as_ref().is_some_and(|setup| setup.connection == id) {
+                setup.take();
+            }
+        });
+        if let Err(e) = spawned {
+            log::warn!("can't start HomeKit connection thread: {e}");
+            shared.connections().remove(&id);
+        }
+    }
+}
+
+struct Running {
+    shared: Arc<Shared>,
+    acceptor: JoinHandle<()>,
+}
+
+static HAP: Mutex<Option<Running>> = Mutex::new(None);
+
+/// Start serving and advertising the accessory, replacing a running one; returns the port
+pub fn start(config: HapConfig) -> Result<u16, AudioRemoteError> {
+    if config.name.trim().is_empty() {
+        return Err(AudioRemoteError::InvalidArgument("the accessory needs a name".into()));
+    }
+    let setup_code = setup_code(config.setup_code.as_deref())?;
+    stop();
+    let identity = identity()?;
+    let listener = server::listen(IpAddr::V6(Ipv6Addr::UNSPECIFIED), config.port)
+        .map_err(|e| AudioRemoteError::Network(format!("can't listen on port {}: {e}", config.port)))?;
+    let port = listener.local_addr()?.port();
+    let shared = Arc::new(Shared {
+        name: config.name.trim().into(),
+        setup_code,
+        identity,
+        port,
+        advertise: config.advertise,
+        stopping: AtomicBool::new(false),
+        setup: Mutex::new(None),
+        failed_setups: AtomicU32::new(0),
+        next_connection: AtomicU64::new(1),
+        connections: Mutex::new(BTreeMap::new()),
+        advertisement: Mutex::new(None),
+        state: Mutex::new(None),
+    });
+    let acceptor = {
+        let shared = shared.clone();
+        thread::Builder::new()
+            .name("audioremote-hap".into())
+            .spawn(move || accept_loop(listener, shared))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the HomeKit thread: {e}")))?
+    };
+    shared.advertise();
+    log::info!("HomeKit accessory {} ({}) on port {port}", shared.name, shared.identity.id);
+    *HAP.lock().unwrap_or_else(|e| e.into_inner()) = Some(Running { shared, acceptor });
+    Ok(port)
+}
+
+/// Stop advertising and close every connection; a no-op if not running
+pub fn stop() {
+    let Some(running) = HAP.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    let shared = &running.shared;
+    shared.stopping.store(true, Ordering::Release);
+    if let Some(advertisement) = shared.advertisement.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        advertisement.stop();
+    }
+    // Wake the accept loop so it sees the flag
+    let _ = TcpStream::connect_timeout(&SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), shared.port), Duration::from_secs(1));
+    let _ = running.acceptor.join();
+    for (stream, _) in shared.connections().values() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    log::info!("HomeKit accessory stopped");
+}
+
+/// Forget every controller, so the accessory can be set up again; ends their sessions
+pub fn reset_pairings() -> Result<(), AudioRemoteError> {
+    save_controllers(&[])?;
+    if let Some(running) = HAP.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        running.shared.failed_setups.store(0, Ordering::Release);
+        end_sessions(&running.shared, |_| true, None);
+        running.shared.advertise();
+    }
+    log::info!("HomeKit pairings reset");
+    Ok(())
+}
+
+/// `{"running", "port", "accessoryId", "setupCode", "paired", "controllers": [{"id", "admin"}]}`
+pub fn status() -> Value {
+    let controllers: Vec<Value> = controllers().iter().map(|controller| json!({"id": controller.id, "admin": controller.admin})).collect();
+    let paired = !controllers.is_empty();
+    match HAP.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(running) => json!({
+            "running": true,
+            "port": running.shared.port,
+            "accessoryId": running.shared.identity.id,
+            "setupCode": running.shared.setup_code,
+            "paired": paired,
+            "controllers": controllers,
+        }),
+        None => json!({"running": false, "port": null, "accessoryId": null, "setupCode": null, "paired": paired, "controllers": controllers}),
+    }
+}
+
+/// Serve the Mac as a HomeKit speaker, replacing a running accessory. `config_json`: {"name",
+/// "setupCode" ("XXX-XX-XXX"; default: made up once and kept, see ar_hap_status), "port" (0: any
+/// free port), "advertise" (true: advertise _hap._tcp over Bonjour)}. Volume and mute changes
+/// from HomeKit arrive as SetVolume and SetMute server requests.
+/// Returns: the port listened on, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_hap_start(config_json: *const c_char) -> i32 {
+    guard("ar_hap_start", -999, || {
+        let result = str_arg(config_json, "config").and_then(|json| {
+            let config: HapConfig =
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid HomeKit config: {e}")))?;
+            start(config)
+        });
+        record(result).map_or(-999, i32::from)
+    })
+}
+
+/// Stop serving and advertising the accessory; pairings are kept. A no-op if not running. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_hap_stop() {
+    guard("ar_hap_stop", (), stop)
+}
+
+/// Returns: JSON {"running", "port", "accessoryId", "setupCode" (to show the user while
+/// unpaired), "paired", "controllers": [{"id", "admin"}]} (free with rust_string_free)
+#[no_mangle]
+pub extern "C" fn ar_hap_status() -> *mut c_char {
+    guard("ar_hap_status", std::ptr::null_mut(), || string_result(Ok(status().to_string())))
+}
+
+/// Forget every paired controller and end their sessions, so the accessory can be added to a home again.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_hap_reset_pairings() -> i32 {
+    guard("ar_hap_reset_pairings", -999, || record(reset_pairings()).map_or(-999, |()| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::Handle;
+    use crate::routes::{ar_server_respond, ar_server_set_handler};
+    use std::ffi::{c_void, CStr, CString};
+
+    #[test]
+    fn test_tlv() {
+        let long = vec![7; 300];
+        let encoded = encode_tlv(&[(TLV_STATE, &[1]), (TLV_PUBLIC_KEY, &long), (TLV_SEPARATOR, &[]), (TLV_SALT, &[1, 2])]);
+        assert_eq!(encoded.len(), 3 + (2 + 255) + (2 + 45) + 2 + 4);
+        assert_eq!(&encoded[3..5], &[TLV_PUBLIC_KEY, 255]);
+        let decoded = decode_tlv(&encoded).unwrap();
+        assert_eq!(decoded, [(TLV_STATE, vec![1]), (TLV_PUBLIC_KEY, long), (TLV_SEPARATOR, vec![]), (TLV_SALT, vec![1, 2])]);
+        // Equal types in a row only join after a full fragment
+        assert_eq!(decode_tlv(&[1, 1, b'a', 1, 1, b'b']).unwrap(), [(1, b"a".to_vec()), (1, b"b".to_vec())]);
+        assert!(decode_tlv(&[6, 2, 1]).is_err());
+        assert!(decode_tlv(&[6]).is_err());
+    }
+
+    #[test]
+    fn test_characteristics() {
+        assert_eq!(parse_ids("1.11,1.12").unwrap(), [(1, 11), (1, 12)]);
+        assert!(parse_ids("1.11,x").is_err());
+        let state = VolumeState { volume: 0.456, muted: true };
+        let database = database("Studio", "AA:BB:CC:DD:EE:FF", Some(&state));
+        assert_eq!(read_value(&database, IID_VOLUME), Ok(json!(46)));
+        assert_eq!(read_value(&database, IID_MUTE), Ok(json!(true)));
+        assert_eq!(read_value(&database, 5), Ok(json!("Studio")));
+        assert_eq!(read_value(&database, IID_IDENTIFY), Err(STATUS_WRITE_ONLY));
+        assert_eq!(read_value(&database, 99), Err(STATUS_NOT_FOUND));
+
+        assert_eq!(write_request(IID_VOLUME, &json!(30)), Ok(Some((Endpoint::SetVolume, json!({"volume": 0.3})))));
+        assert_eq!(write_request(IID_MUTE, &json!(1)), Ok(Some((Endpoint::SetMute, json!({"muted": true})))));
+        assert_eq!(write_request(IID_MUTE, &json!(false)), Ok(Some((Endpoint::SetMute, json!({"muted": false})))));
+        assert_eq!(write_request(IID_IDENTIFY, &json!(true)), Ok(None));
+        assert_eq!(write_request(IID_VOLUME, &json!(101)), Err(STATUS_INVALID_VALUE));
+        assert_eq!(write_request(IID_MUTE, &json!("yes")), Err(STATUS_INVALID_VALUE));
+        assert_eq!(write_request(5, &json!("x")), Err(STATUS_READ_ONLY));
+
+        validate_setup_code("031-45-154").unwrap();
+        for code in ["03145154", "031-45-15", "111-11-111", "123-45-678", "031-4a-154"] {
+            assert!(validate_setup_code(code).is_err(), "{code}");
+        }
+    }
+
+    extern "C" fn audio(request: Handle, endpoint: i32, params: *const c_char, _ctx: *mut c_void) {
+        let params: Value = serde_json::from_str(unsafe { CStr::from_ptr(params) }.to_str().unwrap()).unwrap();
+        let state = match Endpoint::from_raw(endpoint).unwrap() {
+            Endpoint::SetVolume => json!({"volume": params["volume"], "muted": false}),
+            Endpoint::SetMute => json!({"volume": 0.5, "muted": params["muted"]}),
+            _ => json!({"volume": 0.5, "muted": false}),
+        };
+        let state = CString::new(state.to_string()).unwrap();
+        assert_eq!(unsafe { ar_server_respond(request, state.as_ptr()) }, 1);
+    }
+
+    /// The controller's end of a connection
+    struct Client {
+        stream: TcpStream,
+        read: Option<Cipher>,
+        write: Option<Cipher>,
+        received: Vec<u8>,
+    }
+
+    impl Client {
+        fn connect(port: u16) -> Self {
+            let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            Self { stream, read: None, write: None, received: Vec::new() }
+        }
+
+        fn send(&mut self, method: &str, path: &str, content_type: &str, body: &[u8]) {
+            let head = format!("{method} {path} HTTP/1.1\r\nHost: mac\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n", body.len());
+            let bytes = [head.as_bytes(), body].concat();
+            let mut writer = Writer { stream: self.stream.try_clone().unwrap(), cipher: self.write.take() };
+            writer.send(&bytes).unwrap();
+            self.write = writer.cipher;
+        }
+
+        /// The next message: status line, then body
+        fn receive(&mut self) -> (String, Vec<u8>) {
+            loop {
+                if let Some(end) = self.received.windows(4).position(|w| w == b"\r\n\r\n") {
+                    let head = String::from_utf8(self.received[..end].to_vec()).unwrap();
+                    let length: usize = head
+                        .lines()
+                        .find_map(|line| line.to_ascii_lowercase().strip_prefix("content-length: ").map(|v| v.parse().unwrap()))
+                        .unwrap_or(0);
+                    if self.received.len() >= end + 4 + length {
+                        let body = self.received[end + 4..end + 4 + length].to_vec();
+                        self.received.drain(..end + 4 + length);
+                        return (head.lines().next().unwrap().to_owned(), body);
+                    }
+                }
+                let mut reader = Reader { stream: self.stream.try_clone().unwrap(), cipher: self.read.take(), plaintext: Vec::new(), at: 0 };
+                let mut chunk = [0; 4096];
+                let count = reader.read(&mut chunk).unwrap();
+                assert!(count > 0, "closed");
+                self.received.extend_from_slice(&chunk[..count]);
+                // A frame is read whole, so nothing is left behind in `reader`
+                assert_eq!(reader.at, reader.plaintext.len());
+                self.read = reader.cipher;
+            }
+        }
+
+        fn tlv(&mut self, path: &str, items: &[(u8, &[u8])]) -> Vec<(u8, Vec<u8>)> {
+            self.send("POST", path, TLV_CONTENT_TYPE, &encode_tlv(items));
+            let (status, body) = self.receive();
+            assert_eq!(status, "HTTP/1.1 200 OK");
+            decode_tlv(&body).unwrap()
+        }
+
+        fn json(&mut self, method: &str, path: &str, body: &str) -> (String, Value) {
+            self.send(method, path, JSON_CONTENT_TYPE, body.as_bytes());
+            let (status, body) = self.receive();
+            (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+        }
+    }
+
+    /// Pair setup as iOS does it; returns the accessory's ID and long-term public key
+    fn pair(client: &mut Client, controller: &SigningKey, setup_code: &str) -> (String, Vec<u8>) {
+        let m2 = client.tlv("/pair-setup", &[(TLV_METHOD, &[0]), (TLV_STATE, &[1])]);
+        assert_eq!(tlv_get(&m2, TLV_STATE), Some(&[2][..]));
+        let (salt, server_public) = (tlv_get(&m2, TLV_SALT).unwrap(), tlv_get(&m2, TLV_PUBLIC_KEY).unwrap());
+        let (a, proof, key) = srp::tests::client(SRP_USERNAME, setup_code.as_bytes(), salt, server_public);
+        let m4 = client.tlv("/pair-setup", &[(TLV_STATE, &[3]), (TLV_PUBLIC_KEY, &a), (TLV_PROOF, &proof)]);
+        assert_eq!(tlv_get(&m4, TLV_ERROR), None);
+        assert_eq!(tlv_get(&m4, TLV_PROOF).unwrap().len(), 64);
+
+        let controller_key = controller.verifying_key().to_bytes();
+        let x = derive_key(&key, b"Pair-Setup-Controller-Sign-Salt", b"Pair-Setup-Controller-Sign-Info");
+        let signature = controller.sign(&[&x[..], b"controller-1", &controller_key].concat()).to_bytes();
+        let sub = encode_tlv(&[(TLV_IDENTIFIER, b"controller-1"), (TLV_PUBLIC_KEY, &controller_key), (TLV_SIGNATURE, &signature)]);
+        let encryption_key = derive_key(&key, b"Pair-Setup-Encrypt-Salt", b"Pair-Setup-Encrypt-Info");
+        let sealed = seal(&encryption_key, label_nonce(b"PS-Msg05"), &[], &sub);
+        let m6 = client.tlv("/pair-setup", &[(TLV_STATE, &[5]), (TLV_ENCRYPTED_DATA, &sealed)]);
+        assert_eq!(tlv_get(&m6, TLV_STATE), Some(&[6][..]));
+        let sub = open(&encryption_key, label_nonce(b"PS-Msg06"), &[], tlv_get(&m6, TLV_ENCRYPTED_DATA).unwrap()).unwrap();
+        let sub = decode_tlv(&sub).unwrap();
+        let (id, ltpk) = (tlv_get(&sub, TLV_IDENTIFIER).unwrap(), tlv_get(&sub, TLV_PUBLIC_KEY).unwrap());
+        let x = derive_key(&key, b"Pair-Setup-Accessory-Sign-Salt", b"Pair-Setup-Accessory-Sign-Info");
+        verify_signature(ltpk, &[&x[..], id, ltpk].concat(), tlv_get(&sub, TLV_SIGNATURE).unwrap()).unwrap();
+        (String::from_utf8(id.to_vec()).unwrap(), ltpk.to_vec())
+    }
+
+    /// Pair verify, leaving the connection encrypted
+    fn verify(client: &mut Client, controller: &SigningKey, accessory_id: &str, accessory_key: &[u8]) {
+        let secret = [3; 32];
+        let public = MontgomeryPoint::mul_base_clamped(secret).to_bytes();
+        let m2 = client.tlv("/pair-verify", &[(TLV_STATE, &[1]), (TLV_PUBLIC_KEY, &public)]);
+        let accessory_public = tlv_get(&m2, TLV_PUBLIC_KEY).unwrap();
+        let shared = MontgomeryPoint(accessory_public.try_into().unwrap()).mul_clamped(secret).to_bytes();
+        let key = derive_key(&shared, b"Pair-Verify-Encrypt-Salt", b"Pair-Verify-Encrypt-Info");
+        let sub = decode_tlv(&open(&key, label_nonce(b"PV-Msg02"), &[], tlv_get(&m2, TLV_ENCRYPTED_DATA).unwrap()).unwrap()).unwrap();
+        assert_eq!(tlv_get(&sub, TLV_IDENTIFIER), Some(accessory_id.as_bytes()));
+        let signed = [accessory_public, accessory_id.as_bytes(), &public].concat();
+        verify_signature(accessory_key, &signed, tlv_get(&sub, TLV_SIGNATURE).unwrap()).unwrap();
+
+        let signature = controller.sign(&[&public[..], b"controller-1", accessory_public].concat()).to_bytes();
+        let sub = encode_tlv(&[(TLV_IDENTIFIER, b"controller-1"), (TLV_SIGNATURE, &signature)]);
+        let sealed = seal(&key, label_nonce(b"PV-Msg03"), &[], &sub);
+        let m4 = client.tlv("/pair-verify", &[(TLV_STATE, &[3]), (TLV_ENCRYPTED_DATA, &sealed)]);
+        assert_eq!(m4, [(TLV_STATE, vec![4])]);
+        client.write = Some(Cipher::new(derive_key(&shared, b"Control-Salt", b"Control-Write-Encryption-Key")));
+        client.read = Some(Cipher::new(derive_key(&shared, b"Control-Salt", b"Control-Read-Encryption-Key")));
+    }
+
+    #[test]
+    fn test_pair_and_control() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_store, dir) = store::tests::with_temp_store("hap");
+        for endpoint in [Endpoint::GetVolume, Endpoint::SetVolume, Endpoint::SetMute] {
+            assert_eq!(unsafe { ar_server_set_handler(endpoint as i32, Some(audio), std::ptr::null_mut()) }, 1);
+        }
+        let config = json!({"name": "Studio", "setupCode": "031-45-154", "advertise": false});
+        let port = start(serde_json::from_value(config).unwrap()).unwrap();
+        assert_eq!(status()["paired"], false);
+
+        let mut client = Client::connect(port);
+        let (status_line, body) = client.json("GET", "/accessories", "");
+        assert_eq!((status_line.as_str(), body), ("HTTP/1.1 470 Connection Authorization Required", json!({"status": -70401})));
+
+        // A wrong code fails; the right one pairs, and then setup is closed
+        let m2 = client.tlv("/pair-setup", &[(TLV_METHOD, &[0]), (TLV_STATE, &[1])]);
+        let (a, proof, _) = srp::tests::client(SRP_USERNAME, b"031-45-155", tlv_get(&m2, TLV_SALT).unwrap(), tlv_get(&m2, TLV_PUBLIC_KEY).unwrap());
+        let m4 = client.tlv("/pair-setup", &[(TLV_STATE, &[3]), (TLV_PUBLIC_KEY, &a), (TLV_PROOF, &proof)]);
+        assert_eq!(tlv_get(&m4, TLV_ERROR), Some(&[ERROR_AUTHENTICATION][..]));
+        let controller = SigningKey::from_bytes(&[9; 32]);
+        let (accessory_id, accessory_key) = pair(&mut client, &controller, "031-45-154");
+        assert_eq!(status()["accessoryId"], accessory_id);
+        assert_eq!(status()["controllers"], json!([{"id": "controller-1", "admin": true}]));
+        // The identity's seed and the setup code are kept from other users
+        assert!(store::global().get::<StoredIdentity>(IDENTITY_KEY).is_some());
+        assert_eq!(store::tests::file_mode(&dir), 0o600);
+        let again = client.tlv("/pair-setup", &[(TLV_METHOD, &[0]), (TLV_STATE, &[1])]);
+        assert_eq!(tlv_get(&again, TLV_ERROR), Some(&[ERROR_UNAVAILABLE][..]));
+
+        let mut client = Client::connect(port);
+        verify(&mut client, &controller, &accessory_id, &accessory_key);
+        let (status_line, accessories) = client.json("GET", "/accessories", "");
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(read_value(&accessories, IID_VOLUME), Ok(json!(50)));
+        let (status_line, _) = client.json("PUT", "/characteristics", r#"{"characteristics": [{"aid": 1, "iid": 12, "value": 30, "ev": true}]}"#);
+        assert_eq!(status_line, "HTTP/1.1 204 No Content");
+        let (status_line, values) = client.json("GET", "/characteristics?id=1.11,1.12", "");
+        assert_eq!(status_line, "HTTP/1.1 200 OK");
+        assert_eq!(values, json!({"characteristics": [{"aid": 1, "iid": 11, "value": false}, {"aid": 1, "iid": 12, "value": 50}]}));
+        let (status_line, values) = client.json("GET", "/characteristics?id=1.12,1.40", "");
+        assert_eq!(status_line, "HTTP/1.1 207 Multi-Status");
+        assert_eq!(values["characteristics"][1], json!({"aid": 1, "iid": 40, "status": STATUS_NOT_FOUND}));
+        let (_, statuses) = client.json("PUT", "/characteristics", r#"{"characteristics": [{"aid": 1, "iid": 12, "value": "loud"}]}"#);
+        assert_eq!(statuses["characteristics"][0]["status"], STATUS_INVALID_VALUE);
+
+        // Changes from elsewhere arrive as events
+        HUB.publish(Topic::Volume, json!({"volume": 0.8, "muted": false}));
+        let (status_line, event) = client.receive();
+        assert_eq!(status_line, "EVENT/1.0 200 OK");
+        assert_eq!(serde_json::from_slice::<Value>(&event).unwrap(), json!({"characteristics": [{"aid": 1, "iid": 12, "value": 80}]}));
+
+        client.send("POST", "/pairings", TLV_CONTENT_TYPE, &encode_tlv(&[(TLV_STATE, &[1]), (TLV_METHOD, &[METHOD_LIST_PAIRINGS])]));
+        let list = decode_tlv(&client.receive().1).unwrap();
+        assert_eq!(tlv_get(&list, TLV_IDENTIFIER), Some(&b"controller-1"[..]));
+        assert_eq!(tlv_get(&list, TLV_PERMISSIONS), Some(&[1][..]));
+
+        stop();
+        assert_eq!(status()["running"], false);
+        assert_eq!(ar_hap_reset_pairings(), 1);
+        assert_eq!(status()["paired"], false);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,398 @@
+//! Headphone safety: the listener's sound dose, after WHO/ITU-T H.870 "safe
+//! listening". Sound energy reaching the ears adds up as sound pressure
+//! squared × time (Pa²h); the weekly allowance is what `referenceDb` for
+//! `referenceHours` gives, 80 dB for 40 hours (1.6 Pa²h) by default, or 75 dB
+//! in H.870's sensitive mode. Every 3 dB louder halves the safe time.
+//!
+//! The metering path reports levels in dBFS with the time they covered; the
+//! output calibration, the SPL the headphones make for a full-scale signal at
+//! full volume, turns them into SPL. Daily totals are kept in the store for a
+//! week and the dose is their rolling seven-day sum, so listening a day
+//! stops counting a week later. Crossing one of the warning percents fires the
+//! callback, so the app can suggest turning it down.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::string_result;
+use crate::log_file::civil_date;
+use crate::runtime::{self, SendPtr};
+use crate::store;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const DAYS_KEY: &str = "hearing.days";
+/// Days the dose covers, today included
+const WINDOW_DAYS: i64 = 7;
+/// How often the totals are written while listening
+const SAVE_INTERVAL: u64 = 60;
+/// The squared reference pressure of 0 dB SPL, 20 µPa
+const REFERENCE_PA2: f64 = 4e-10;
+
+/// (threshold, percent, ctx): the seven-day dose, as a percent of the allowance, reached the
+/// `threshold` warning percent
+pub type HearingCallback = extern "C" fn(threshold: f64, percent: f64, ctx: *mut c_void);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct HearingConfig {
+    /// dB SPL from a full-scale signal at full volume; most earbuds and headphones make 95–110
+    #[serde(default = "default_full_scale_db_spl")]
+    pub full_scale_db_spl: f64,
+    #[serde(default = "default_reference_db")]
+    pub reference_db: f64,
+    #[serde(default = "default_reference_hours")]
+    pub reference_hours: f64,
+    #[serde(default = "default_warning_percents")]
+    pub warning_percents: Vec<f64>,
+    /// Where the listener's days start, e.g. TimeZone.current.secondsFromGMT() / 60
+    #[serde(default)]
+    pub utc_offset_minutes: i64,
+}
+
+fn default_full_scale_db_spl() -> f64 {
+    100.0
+}
+
+fn default_reference_db() -> f64 {
+    80.0
+}
+
+fn default_reference_hours() -> f64 {
+    40.0
+}
+
+fn default_warning_percents() -> Vec<f64> {
+    vec![50.0, 80.0, 100.0]
+}
+
+impl Default for HearingConfig {
+    fn default() -> Self {
+        Self {
+            full_scale_db_spl: default_full_scale_db_spl(),
+            reference_db: default_reference_db(),
+            reference_hours: default_reference_hours(),
+            warning_percents: default_warning_percents(),
+            utc_offset_minutes: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DayDose {
+    /// "2026-10-15", the listener's local date
+    pub date: String,
+    pub dose_pa2h: f64,
+    pub percent: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DoseStatus {
+    /// The seven-day dose as a percent of the weekly allowance
+    pub percent: f64,
+    pub today_percent: f64,
+    /// What's left of the allowance, as hours at the reference level
+    pub hours_left: f64,
+    /// The days with any listening, oldest first
+    pub days: Vec<DayDose>,
+}
+
+pub struct DoseTracker {
+    config: HearingConfig,
+    /// The weekly allowance, Pa²h
+    allowance: f64,
+    /// Pa²h by day, days counted from 1970-01-01 in the listener's time zone
+    days: BTreeMap<i64, f64>,
+}
+
+impl DoseTracker {
+    pub fn new(mut config: HearingConfig, days: BTreeMap<i64, f64>) -> Result<Self, AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(60.0..=140.0).contains(&config.full_scale_db_spl) {
+            return invalid(format!("fullScaleDbSpl {} is outside 60–140", config.full_scale_db_spl));
+        }
+        if !(60.0..=100.0).contains(&config.reference_db) {
+            return invalid(format!("referenceDb {} is outside 60–100", config.reference_db));
+        }
+        if !(1.0..=168.0).contains(&config.reference_hours) {
+            return invalid(format!("referenceHours {} is outside 1–168", config.reference_hours));
+        }
+        if let Some(percent) = config.warning_percents.iter().find(|percent| !(1.0..=1000.0).contains(*percent)) {
+            return invalid(format!("warning percent {percent} is outside 1–1000"));
+        }
+        if !(-840..=840).contains(&config.utc_offset_minutes) {
+            return invalid(format!("utcOffsetMinutes {} is outside -840–840", config.utc_offset_minutes));
+        }
+        config.warning_percents.sort_by(f64::total_cmp);
+        config.warning_percents.dedup();
+        let allowance = REFERENCE_PA2 * 10f64.powf(config.reference_db / 10.0) * config.reference_hours;
+        Ok(Self { config, allowance, days })
+    }
+
+    fn day(&self, now: u64) -> i64 {
+        (now as i64 + self.config.utc_offset_minutes * 60).div_euclid(86_400)
+    }
+
+    /// Pa²h over the seven days up to `now`
+    fn dose(&self, now: u64) -> f64 {
+        let today = self.day(now);
+        self.days.range(today - WINDOW_DAYS + 1..=today).map(|(_, dose)| dose).sum()
+    }
+
+    pub fn percent(&self, now: u64) -> f64 {
+        100.0 * self.dose(now) / self.allowance
+    }
+
+    /// Count `seconds` of listening at `level_db` dBFS with the output at `volume_db` (0 at full
+    /// volume; -∞ for either is silence) ending at `now`. Returns the warning percents crossed.
+    pub fn add(&mut self, level_db: f64, volume_db: f64, seconds: f64, now: u64) -> Result<Vec<f64>, AudioRemoteError> {
+        if level_db.is_nan() || level_db > 40.0 || volume_db.is_nan() || volume_db > 40.0 {
+            return Err(AudioRemoteError::InvalidArgument(format!("level {level_db} dBFS at volume {volume_db} dB isn't a level")));
+        }
+        if !(0.0..=3600.0).contains(&seconds) {
+            return Err(AudioRemoteError::InvalidArgument(format!("seconds {seconds} is outside 0–3600")));
+        }
+        let before = self.percent(now);
+        let spl = level_db + volume_db + self.config.full_scale_db_spl;
+        let today = self.day(now);
+        *self.days.entry(today).or_default() += REFERENCE_PA2 * 10f64.powf(spl / 10.0) * seconds / 3600.0;
+        // Days that have left the window can go
+        self.days = self.days.split_off(&(today - WINDOW_DAYS + 1));
+        let after = self.percent(now);
+        Ok(self.config.warning_percents.iter().copied().filter(|&threshold| before < threshold && threshold <= after).collect())
+    }
+
+    pub fn status(&self, now: u64) -> DoseStatus {
+        let today = self.day(now);
+        let percent = |dose: f64| 100.0 * dose / self.allowance;
+        let days: Vec<DayDose> = self
+            .days
+            .range(today - WINDOW_DAYS + 1..=today)
+            .map(|(&day, &dose)| {
+                let (year, month, date) = civil_date(day);
+                DayDose { date: format!("{year:04}-{month:02}-{date:02}"), dose_pa2h: dose, percent: percent(dose) }
+            })
+            .collect();
+        let reference_pa2 = REFERENCE_PA2 * 10f64.powf(self.config.reference_db / 10.0);
+        DoseStatus {
+            percent: self.percent(now),
+            today_percent: percent(self.days.get(&today).copied().unwrap_or(0.0)),
+            hours_left: (self.allowance - self.dose(now)).max(0.0) / reference_pa2,
+            days,
+        }
+    }
+}
+
+struct Hearing {
+    tracker: DoseTracker,
+    callback: Option<HearingCallback>,
+    ctx: SendPtr,
+    /// When the totals were last written, and whether they've changed since
+    saved_at: u64,
+    dirty: bool,
+}
+
+static HEARING: Mutex<Option<Hearing>> = Mutex::new(None);
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn write(hearing: &mut Hearing, now: u64) -> Result<(), AudioRemoteError> {
+    store::global().set(DAYS_KEY, &hearing.tracker.days)?;
+    (hearing.saved_at, hearing.dirty) = (now, false);
+    Ok(())
+}
+
+/// Run `body` on the tracker, set up with the defaults and the stored totals if it hasn't been
+fn with_hearing<T>(body: impl FnOnce(&mut Hearing) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let mut hearing = HEARING.lock().unwrap_or_else(|e| e.into_inner());
+    let hearing = match &mut *hearing {
+        Some(hearing) => hearing,
+        None => {
+            let tracker = DoseTracker::new(HearingConfig::default(), store::global().get(DAYS_KEY).unwrap_or_default())?;
+            hearing.insert(Hearing { tracker, callback: None, ctx: SendPtr(std::ptr::null_mut()), saved_at: now(), dirty: false })
+        }
+    };
+    body(hearing)
+}
+
+/// Write unsaved totals to the store; done at shutdown
+pub fn save() {
+    let mut hearing = HEARING.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(hearing) = hearing.as_mut().filter(|hearing| hearing.dirty) {
+        if let Err(e) = write(hearing, now()) {
+            log::warn!("can't save the hearing dose: {e}");
+        }
+    }
+}
+
+/// Set up dose tracking. `config_json` (nullable): {"fullScaleDbSpl" (dB SPL from a full-scale
+/// signal at full volume, default 100), "referenceDb" (80; 75 for H.870's sensitive mode),
+/// "referenceHours" (40), "warningPercents" ([50, 80, 100]), "utcOffsetMinutes" (0)}. `callback`
+/// (nullable) hears each warning percent the seven-day dose reaches. Totals so far are kept.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string; `callback` must stay
+/// callable, and `ctx` valid from any thread, until it is replaced.
+#[no_mangle]
+pub unsafe extern "C" fn ar_hearing_configure(config_json: *const c_char, callback: Option<HearingCallback>, ctx: *mut c_void) -> i32 {
+    guard("ar_hearing_configure", -999, || {
+        let config = match config_json.is_null() {
+            true => Ok(HearingConfig::default()),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid hearing config: {e}")))
+            }),
+        };
+        let result = config.and_then(|config| {
+            with_hearing(|hearing| {
+                hearing.tracker = DoseTracker::new(config, std::mem::take(&mut hearing.tracker.days))?;
+                (hearing.callback, hearing.ctx) = (callback, SendPtr(ctx));
+                Ok(())
+            })
+        });
+        record(result).map_or(-999, |()| 1)
+    })
+}
+
+/// Count `seconds` of listening at `level_db` dBFS (e.g. the loudness meter's short-term reading)
+/// with the output volume at `volume_db` (0 at full volume; -INFINITY when muted). Warning
+/// percents reached are reported to the callback, on the callback queue if one is set.
+/// Returns: the seven-day dose as a percent of the allowance, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_hearing_add(level_db: f64, volume_db: f64, seconds: f64) -> f64 {
+    guard("ar_hearing_add", -999.0, || {
+        let now = now();
+        let result = with_hearing(|hearing| {
+            let crossed = hearing.tracker.add(level_db, volume_db, seconds, now)?;
+            hearing.dirty = true;
+            if !crossed.is_empty() || now.saturating_sub(hearing.saved_at) >= SAVE_INTERVAL {
+                write(hearing, now)?;
+            }
+            Ok((crossed, hearing.tracker.percent(now), hearing.callback, hearing.ctx))
+        });
+        match record(result) {
+            Some((crossed, percent, callback, ctx)) => {
+                if let Some(callback) = callback {
+                    for threshold in crossed {
+                        runtime::deliver(move || callback(threshold, percent, ctx.get()));
+                    }
+                }
+                percent
+            }
+            None => -999.0,
+        }
+    })
+}
+
+/// Returns: JSON {"percent" (the seven-day dose as a percent of the allowance), "todayPercent",
+/// "hoursLeft" (at the reference level), "days": [{"date" ("2026-10-15"), "dosePa2h", "percent"}]}
+/// (free with rust_string_free), NULL on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_hearing_status() -> *mut c_char {
+    guard("ar_hearing_status", std::ptr::null_mut(), || {
+        let result = with_hearing(|hearing| {
+            serde_json::to_string(&hearing.tracker.status(now())).map_err(|e| AudioRemoteError::Other(e.to_string()))
+        });
+        string_result(result)
+    })
+}
+
+/// Forget every total, e.g. when the user asks to
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_hearing_reset() -> i32 {
+    guard("ar_hearing_reset", -999, || {
+        let result = with_hearing(|hearing| {
+            hearing.tracker.days.clear();
+            write(hearing, now())
+        });
+        record(result).map_or(-999, |()| 1)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+    use crate::store::tests::with_temp_store;
+
+    const DAY: u64 = 86_400;
+
+    #[test]
+    fn test_dose() {
+        let mut tracker = DoseTracker::new(HearingConfig::default(), BTreeMap::new()).unwrap();
+        // 100 dB SPL is 20 dB over the reference: a hundredth of the 40 hours, 24 minutes, is the week
+        let monday = 20_000 * DAY;
+        assert_eq!(tracker.add(0.0, 0.0, 6.0 * 60.0, monday).unwrap(), Vec::<f64>::new());
+        assert!((tracker.percent(monday) - 25.0).abs() < 1e-9);
+        assert_eq!(tracker.add(0.0, 0.0, 6.0 * 60.0, monday + 3600).unwrap(), vec![50.0]);
+        // Muted or silent adds nothing; 3 dB quieter counts half
+        tracker.add(f64::NEG_INFINITY, 0.0, 3600.0, monday + 2 * 3600).unwrap();
+        tracker.add(0.0, f64::NEG_INFINITY, 3600.0, monday + 2 * 3600).unwrap();
+        assert_eq!(tracker.add(-3.0, 0.0, 12.0 * 60.0, monday + DAY).unwrap(), Vec::<f64>::new());
+        assert!((tracker.percent(monday + DAY) - 75.0).abs() < 0.1);
+        assert_eq!(tracker.add(0.0, 0.0, 30.0 * 60.0, monday + 2 * DAY).unwrap(), vec![80.0, 100.0]);
+
+        let status = tracker.status(monday + 2 * DAY);
+        assert_eq!(status.days.iter().map(|day| day.date.as_str()).collect::<Vec<_>>(), ["2024-10-04", "2024-10-05", "2024-10-06"]);
+        assert!((status.today_percent - 125.0).abs() < 1e-6 && status.hours_left == 0.0, "{status:?}");
+        // A week on, the first day no longer counts
+        assert!((tracker.percent(monday + 7 * DAY) - 150.0).abs() < 0.1);
+        assert!((tracker.status(monday + 9 * DAY).percent).abs() < 1e-9);
+        assert!(tracker.add(f64::NAN, 0.0, 1.0, monday).is_err() && tracker.add(-10.0, 0.0, -1.0, monday).is_err());
+    }
+
+    #[test]
+    fn test_local_days() {
+        // 23:30 UTC is the next day in UTC+2 and the same day in UTC-5
+        let late = 20_000 * DAY + 23 * 3600 + 1800;
+        for (offset, date) in [(120, "2024-10-05"), (-300, "2024-10-04")] {
+            let config = HearingConfig { utc_offset_minutes: offset, reference_db: 75.0, ..HearingConfig::default() };
+            let mut tracker = DoseTracker::new(config, BTreeMap::new()).unwrap();
+            tracker.add(-20.0, 0.0, 60.0, late).unwrap();
+            assert_eq!(tracker.status(late).days[0].date, date);
+        }
+        let config = HearingConfig { warning_percents: vec![0.0], ..HearingConfig::default() };
+        assert!(DoseTracker::new(config, BTreeMap::new()).is_err());
+    }
+
+    static WARNINGS: Mutex<Vec<(f64, f64)>> = Mutex::new(vec![]);
+
+    extern "C" fn on_warning(threshold: f64, percent: f64, _ctx: *mut c_void) {
+        WARNINGS.lock().unwrap().push((threshold, percent));
+    }
+
+    #[test]
+    fn test_ffi() {
+        let _runtime = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_store, dir) = with_temp_store("hearing");
+        *HEARING.lock().unwrap() = None;
+
+        let config = c"{\"fullScaleDbSpl\": 110, \"warningPercents\": [10]}";
+        assert_eq!(unsafe { ar_hearing_configure(config.as_ptr(), Some(on_warning), std::ptr::null_mut()) }, 1);
+        // 100 dB SPL for 3 minutes is an eighth of the week, and the first add is written straight away
+        let percent = ar_hearing_add(-10.0, 0.0, 180.0);
+        assert!((percent - 12.5).abs() < 1e-6, "{percent}");
+        assert_eq!(*WARNINGS.lock().unwrap(), [(10.0, percent)]);
+        let status: serde_json::Value = serde_json::from_str(&take_c_string(ar_hearing_status()).unwrap()).unwrap();
+        assert!((status["days"][0]["dosePa2h"].as_f64().unwrap() - 0.2).abs() < 1e-9, "{status}");
+
+        // The totals outlast the tracker
+        *HEARING.lock().unwrap() = None;
+        assert!((ar_hearing_add(-100.0, 0.0, 1.0) - 12.5).abs() < 1e-3);
+        assert_eq!(ar_hearing_add(f64::NAN, 0.0, 1.0), -999.0);
+        assert_eq!(unsafe { ar_hearing_configure(c"{\"referenceDb\": 120}".as_ptr(), None, std::ptr::null_mut()) }, -999);
+        assert_eq!(ar_hearing_reset(), 1);
+        *HEARING.lock().unwrap() = None;
+        assert!(take_c_string(ar_hearing_status()).unwrap().contains("\"days\":[]"));
+        *HEARING.lock().unwrap() = None;
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
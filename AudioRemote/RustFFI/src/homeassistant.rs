@@ -0,0 +1,230 @@
+//! Home Assistant MQTT discovery, so a Mac mirrored to a broker by `mqtt`
+//! turns up in Home Assistant as a device with no YAML to write. Each entity
+//! is described by a retained config message under the discovery prefix
+//! (`homeassistant/<component>/<node>/<object>/config`) that points at the
+//! state and command topics `mqtt` already uses:
+//!
+//! - `number.<name>_volume`: a 0–100 % slider
+//! - `switch.<name>_mute`
+//! - `select.<name>_output`: the output devices, sent again when they change
+//! - `media_player.<name>`: volume, mute and what's playing, for integrations
+//!   that build media players from MQTT (Home Assistant's own MQTT integration
+//!   has no media player platform)
+//!
+//! All of them go unavailable when `<prefix>/status` says `offline`, and the
+//! configs are sent again whenever Home Assistant announces it has restarted.
+
+use serde_json::{json, Value};
+
+use crate::events::Topic;
+use crate::mdns;
+use crate::mqtt::Topics;
+use crate::remote::OutputDevice;
+
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+/// What Home Assistant publishes to `<discovery prefix>/status` once it has started
+pub const BIRTH_MESSAGE: &str = "online";
+/// The current output's name, from the devices state
+const CURRENT_OUTPUT_TEMPLATE: &str = "{{ (value_json | selectattr('isCurrent') | map(attribute='name') | list + [''])[0] }}";
+
+/// The device the entities belong to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Discovery {
+    pub prefix: String,
+    /// Unique per Mac: letters, digits, `_` and `-` only
+    pub node_id: String,
+    pub name: String,
+}
+
+/// "audioremote-0123abcd" → "audioremote-0123abcd"; anything else Home Assistant can't take becomes `_`
+pub fn node_id(client_id: &str) -> String {
+    client_id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect()
+}
+
+/// The advertised Bonjour name, else the Mac's host name
+pub fn device_name() -> String {
+    mdns::advertised_name().unwrap_or_else(|| {
+        let host = mdns::host_name();
+        match host.trim_end_matches(".local").trim_end_matches("audioremote").trim_end_matches('-') {
+            "" => "Audio Remote".into(),
+            label => label.into(),
+        }
+    })
+}
+
+impl Discovery {
+    /// Where Home Assistant announces its restarts
+    pub fn status_topic(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    fn config_topic(&self, component: &str, object_id: &str) -> String {
+        format!("{}/{component}/{}/{object_id}/config", self.prefix, self.node_id)
+    }
+
+    /// An entity's config: `fields` and what every entity shares, its ID, the device and availability
+    fn entity(&self, topics: &Topics, object_id: &str, name: Option<&str>, fields: Value) -> Value {
+        let mut entity = json!({
+            "name": name,
+            "unique_id": format!("{}_{object_id}", self.node_id),
+            "object_id": format!("{}_{object_id}", self.node_id),
+            "availability_topic": topics.status(),
+            "payload_available": "online",
+            "payload_not_available": "offline",
+            "device": {
+                "identifiers": [self.node_id],
+                "name": self.name,
+                "manufacturer": "Audio Remote",
+                "model": "Mac",
+                "sw_version": env!("CARGO_PKG_VERSION"),
+            },
+        });
+        if let (Some(entity), Value::Object(fields)) = (entity.as_object_mut(), fields) {
+            entity.extend(fields);
+        }
+        entity
+    }
+
+    /// Config messages that don't depend on state: topic and payload, to publish retained
+    pub fn messages(&self, topics: &Topics) -> Vec<(String, Value)> {
+        let volume = topics.state(Topic::Volume);
+        let now_playing = topics.state(Topic::NowPlaying);
+        vec![
+            (
+                self.config_topic("number", "volume"),
+                self.entity(
+                    topics,
+                    "volume",
+                    Some("Volume"),
+                    json!({
+                        "icon": "mdi:volume-high",
+                        "min": 0,
+                        "max": 100,
+                        "step": 1,
+                        "unit_of_measurement": "%",
+                        "mode": "slider",
+                        "state_topic": volume,
+                        "value_template": "{{ (value_json.volume * 100) | round(0) }}",
+                        "command_topic": topics.command("volume"),
+                        "command_template": "{{ value / 100 }}",
+                    }),
+                ),
+            ),
+            (
+                self.config_topic("switch", "mute"),
+                self.entity(
+                    topics,
+                    "mute",
+                    Some("Mute"),
+                    json!({
+                        "icon": "mdi:volume-off",
+                        "state_topic": volume,
+                        "value_template": "{{ 'ON' if value_json.muted else 'OFF' }}",
+                        "command_topic": topics.command("mute"),
+                        "payload_on": "ON",
+                        "payload_off": "OFF",
+                    }),
+                ),
+            ),
+            (
+                self.config_topic("media_player", "player"),
+                self.entity(
+                    topics,
+                    "player",
+                    None,
+                    json!({
+                        "state_topic": now_playing,
+                        "state_template": "{{ 'idle' if value_json is none else ('playing' if value_json.isPlaying else 'paused') }}",
+                        "media_title_template": "{{ value_json.title if value_json else '' }}",
+                        "media_artist_template": "{{ value_json.artist if value_json else '' }}",
+                        "media_album_name_template": "{{ value_json.album if value_json else '' }}",
+                        "app_name_template": "{{ value_json.app if value_json else '' }}",
+                        "volume_state_topic": volume,
+                        "volume_level_template": "{{ value_json.volume }}",
+                        "is_volume_muted_template": "{{ value_json.muted }}",
+                        "volume_set_topic": topics.command("volume"),
+                        "volume_mute_topic": topics.command("mute"),
+                    }),
+                ),
+            ),
+        ]
+    }
+
+    /// The output device select, whose options are the device names; the command template turns
+    /// the chosen name back into a device ID
+    pub fn output_message(&self, topics: &Topics, devices: &[OutputDevice]) -> (String, Value) {
+        let ids: serde_json::Map<String, Value> = devices.iter().map(|device| (device.name.clone(), json!(device.id))).collect();
+        let entity = self.entity(
+            topics,
+            "output",
+            Some("Output"),
+            json!({
+                "icon": "mdi:speaker",
+                "options": devices.iter().map(|device| device.name.as_str()).collect::<Vec<_>>(),
+                "state_topic": topics.state(Topic::Devices),
+                "value_template": CURRENT_OUTPUT_TEMPLATE,
+                "command_topic": topics.command("device"),
+                "command_template": format!("{{{{ {{\"id\": {}[value]}} | tojson }}}}", Value::Object(ids)),
+            }),
+        );
+        (self.config_topic("select", "output"), entity)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn discovery() -> (Discovery, Topics) {
+        let discovery = Discovery { prefix: DEFAULT_DISCOVERY_PREFIX.into(), node_id: node_id("audioremote-0123abcd"), name: "Studio".into() };
+        (discovery, Topics { prefix: "audioremote/studio".into() })
+    }
+
+    #[test]
+    fn test_node_id() {
+        assert_eq!(node_id("audioremote-0123abcd"), "audioremote-0123abcd");
+        assert_eq!(node_id("mac/1 (office)"), "mac_1__office_");
+    }
+
+    #[test]
+    fn test_config_messages() {
+        let (discovery, topics) = discovery();
+        let messages = discovery.messages(&topics);
+        let topics_sent: Vec<&str> = messages.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics_sent,
+            [
+                "homeassistant/number/audioremote-0123abcd/volume/config",
+                "homeassistant/switch/audioremote-0123abcd/mute/config",
+                "homeassistant/media_player/audioremote-0123abcd/player/config",
+            ]
+        );
+        for (_, config) in &messages {
+            assert_eq!(config["availability_topic"], "audioremote/studio/status");
+            assert_eq!(config["device"]["identifiers"][0], "audioremote-0123abcd");
+        }
+        let volume = &messages[0].1;
+        assert_eq!(volume["unique_id"], "audioremote-0123abcd_volume");
+        assert_eq!(volume["state_topic"], "audioremote/studio/volume");
+        assert_eq!(volume["command_topic"], "audioremote/studio/volume/set");
+        assert_eq!(messages[1].1["command_topic"], "audioremote/studio/mute/set");
+        assert_eq!(discovery.status_topic(), "homeassistant/status");
+    }
+
+    #[test]
+    fn test_output_select() {
+        let (discovery, topics) = discovery();
+        let devices = [
+            OutputDevice { id: "BuiltInSpeakerDevice".into(), name: "MacBook Pro Speakers".into(), is_current: true, battery_percent: None },
+            OutputDevice { id: "usb:1".into(), name: "Studio \"Display\"".into(), is_current: false, battery_percent: None },
+        ];
+        let (topic, config) = discovery.output_message(&topics, &devices);
+        assert_eq!(topic, "homeassistant/select/audioremote-0123abcd/output/config");
+        assert_eq!(config["options"], json!(["MacBook Pro Speakers", "Studio \"Display\""]));
+        assert_eq!(config["command_topic"], "audioremote/studio/device/set");
+        assert_eq!(
+            config["command_template"],
+            r#"{{ {"id": {"MacBook Pro Speakers":"BuiltInSpeakerDevice","Studio \"Display\"":"usb:1"}[value]} | tojson }}"#
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::ffi::c_char;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+
+use crate::{guard, interfaces, record, str_arg, AudioRemoteError};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Network settings applied to every agent; set from Swift for users behind
+/// corporate proxies or TLS-inspecting gateways
+#[derive(Debug, Clone, Default)]
+pub struct HttpConfig {
+    /// "http://[user:password@]host:port"
+    pub proxy: Option<String>,
+    /// Extra trust anchors, added to the built-in Mozilla roots
+    pub extra_roots: Vec<CertificateDer<'static>>,
+    pub ca_bundle: Option<PathBuf>,
+}
+
+static CONFIG: Mutex<Option<HttpConfig>> = Mutex::new(None);
+
+fn config() -> HttpConfig {
+    CONFIG.lock().unwrap_or_else(|e| e.into_inner()).clone().unwrap_or_default()
+}
+
+fn update_config(change: impl FnOnce(&mut HttpConfig)) {
+    let mut guard = CONFIG.lock().unwrap_or_else(|e| e.into_inner());
+    change(guard.get_or_insert_with(HttpConfig::default));
+}
+
+/// Build the proxy URL understood by ureq from its parts
+pub fn proxy_url(host: &str, port: u16, credentials: Option<(&str, &str)>) -> Result<String, AudioRemoteError> {
+    let host = host.trim();
+    if host.is_empty() || host.contains(['/', '@', ' ']) {
+        return Err(AudioRemoteError::InvalidArgument(format!("invalid proxy host \"{host}\"")));
+    }
+    let auth = match credentials {
+        Some((user, _)) if user.is_empty() || user.contains(':') => return Err(AudioRemoteError::InvalidArgument("invalid proxy username".into())),
+        Some((user, password)) => format!("{user}:{password}@"),
+        None => String::new(),
+    };
+    let url = format!("http://{auth}{}:{port}", interfaces::url_host(host));
+    ureq::Proxy::new(&url).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid proxy: {e}")))?;
+    Ok(url)
+}
+
+/// Read every certificate from a PEM bundle
+pub fn load_ca_bundle(path: &Path) -> Result<Vec<CertificateDer<'static>>, AudioRemoteError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .map_err(|e| AudioRemoteError::Io(format!("can't read {}: {e}", path.display())))?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| AudioRemoteError::InvalidData(format!("invalid PEM in {}: {e}", path.display())))?;
+    if certs.is_empty() {
+        return Err(AudioRemoteError::InvalidData(format!("{} contains no certificates", path.display())));
+    }
+    Ok(certs)
+}
+
+/// The Web PKI roots plus `extra_roots`; also used by `mqtt`
+pub(crate) fn tls_config(extra_roots: &[CertificateDer<'static>]) -> Result<rustls::ClientConfig, AudioRemoteError> {
+    let mut roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    for cert in extra_roots {
+        roots.add(cert.clone()).map_err(|e| AudioRemoteError::InvalidData(format!("invalid CA certificate: {e}")))?;
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    Ok(rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// User-Agent sent with every request made by the Rust layer
+pub(crate) fn user_agent() -> String {
+    format!("AudioRemote/{}", env!("CARGO_PKG_VERSION"))
+}
+
+pub(crate) fn agent_with(config: &HttpConfig) -> Result<ureq::Agent, AudioRemoteError> {
+    let mut builder = ureq::AgentBuilder::new()
+        .timeout_connect(CONNECT_TIMEOUT)
+        .timeout_read(READ_TIMEOUT)
+        .user_agent(&user_agent());
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(ureq::Proxy::new(proxy).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid proxy: {e}")))?);
+    }
+    if !config.extra_roots.is_empty() {
+        builder = builder.tls_config(Arc::new(tls_config(&config.extra_roots)?));
+    }
+    Ok(builder.build())
+}
+
+/// HTTP agent shared by the update client and downloader, honoring the proxy and CA settings
+pub(crate) fn agent() -> ureq::Agent {
+    // Settings are validated when they're set, so this only fails on a broken TLS provider
+    agent_with(&config()).unwrap_or_else(|_| agent_with(&HttpConfig::default()).expect("default agent"))
+}
+
+/// Send update traffic through an HTTP proxy (CONNECT for https)
+/// `host` NULL clears the proxy; `username`/`password` may be NULL for an open proxy.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// Each pointer must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn http_set_proxy(
+    host_ptr: *const c_char,
+    port: u16,
+    username_ptr: *const c_char,
+    password_ptr: *const c_char,
+) -> i32 {
+    guard("http_set_proxy", -999, || {
+        let result = (|| {
+            if host_ptr.is_null() {
+                return Ok(None);
+            }
+            let host = str_arg(host_ptr, "host")?;
+            let credentials = match username_ptr.is_null() {
+                true => None,
+                false => {
+                    let password = if password_ptr.is_null() { "" } else { str_arg(password_ptr, "password")? };
+                    Some((str_arg(username_ptr, "username")?, password))
+                }
+            };
+            proxy_url(host, port, credentials).map(Some)
+        })();
+        match record(result) {
+            Some(proxy) => {
+                update_config(|config| config.proxy = proxy);
+                1
+            }
+            None => -999,
+        }
+    })
+}
+
+/// Trust the certificates in a PEM bundle in addition to the built-in roots
+/// (for TLS-inspecting corporate gateways). `path` NULL removes the custom bundle.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `path_ptr` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn http_set_ca_bundle(path_ptr: *const c_char) -> i32 {
+    guard("http_set_ca_bundle", -999, || {
+        let result = (|| {
+            if path_ptr.is_null() {
+                return Ok((None, Vec::new()));
+            }
+            let path = Path::new(str_arg(path_ptr, "path")?);
+            let certs = load_ca_bundle(path)?;
+            // Fail now rather than on the next update check
+            tls_config(&certs)?;
+            Ok((Some(path.to_path_buf()), certs))
+        })();
+        match record(result) {
+            Some((path, certs)) => {
+                update_config(|config| {
+                    config.ca_bundle = path;
+                    config.extra_roots = certs;
+                });
+                1
+            }
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_proxy_url() {
+        assert_eq!(proxy_url("proxy.corp", 3128, None).unwrap(), "http://proxy.corp:3128");
+        assert_eq!(proxy_url("10.0.0.1", 8080, Some(("me", "p@ss:1"))).unwrap(), "http://me:p@ss:1@10.0.0.1:8080");
+        assert!(proxy_url("", 8080, None).is_err());
+        assert!(proxy_url("http://proxy", 8080, None).is_err());
+        assert!(proxy_url("proxy", 8080, Some(("a:b", "c"))).is_err());
+    }
+
+    #[test]
+    fn test_requests_go_through_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut head = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                head.push(line.trim().to_owned());
+            }
+            // Refuse the tunnel; we only care about what the client asked for
+            stream.write_all(b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n").unwrap();
+            head
+        });
+
+        let proxy = proxy_url("127.0.0.1", port, Some(("me", "secret"))).unwrap();
+        let config = HttpConfig { proxy: Some(proxy), ..Default::default() };
+        assert!(agent_with(&config).unwrap().get("https://updates.invalid/appcast.xml").call().is_err());
+
+        let head = handle.join().unwrap();
+        assert_eq!(head[0], "CONNECT updates.invalid:443 HTTP/1.1");
+        // base64("me:secret")
+        assert!(head.iter().any(|h| h.eq_ignore_ascii_case("Proxy-Authorization: basic bWU6c2VjcmV0")), "{head:?}");
+    }
+
+    #[test]
+    fn test_ca_bundle_errors() {
+        let path = std::env::temp_dir().join(format!("audioremote-ca-{}.pem", std::process::id()));
+        std::fs::write(&path, "not a certificate").unwrap();
+        assert!(matches!(load_ca_bundle(&path), Err(AudioRemoteError::InvalidData(m)) if m.contains("no certificates")));
+        assert!(load_ca_bundle(Path::new("/nonexistent/ca.pem")).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+use std::ffi::{c_char, CString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use crate::staging::{self, BundleInfo};
+use crate::version::{self, ParseMode};
+use crate::{guard, policy, record, store, str_arg, AudioRemoteError};
+
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Atomically exchange two paths on the same volume
+#[cfg(target_os = "macos")]
+fn swap_paths(a: &Path, b: &Path) -> io::Result<()> {
+    let (a, b) = (c_path(a)?, c_path(b)?);
+    match unsafe { libc::renamex_np(a.as_ptr(), b.as_ptr(), libc::RENAME_SWAP) } {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn swap_paths(a: &Path, b: &Path) -> io::Result<()> {
+    let (a, b) = (c_path(a)?, c_path(b)?);
+    let result = unsafe {
+        libc::renameat2(libc::AT_FDCWD, a.as_ptr(), libc::AT_FDCWD, b.as_ptr(), libc::RENAME_EXCHANGE)
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+fn swap_paths(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Swap, or fall back to two renames (with the first undone on failure) where
+/// the filesystem can't exchange atomically
+fn exchange(a: &Path, b: &Path) -> Result<(), AudioRemoteError> {
+    match swap_paths(a, b) {
+        Ok(()) => return Ok(()),
+        Err(e) if !matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOTSUP) | Some(libc::ENOSYS)) => {
+            return Err(AudioRemoteError::Io(format!("can't swap {} and {}: {e}", a.display(), b.display())));
+        }
+        Err(_) => {}
+    }
+
+    let aside = sibling(a, ".swap");
+    fs::rename(a, &aside).map_err(|e| AudioRemoteError::Io(format!("can't move {} aside: {e}", a.display())))?;
+    if let Err(e) = fs::rename(b, a) {
+        let _ = fs::rename(&aside, a);
+        return Err(AudioRemoteError::Io(format!("can't move {} into place: {e}", b.display())));
+    }
+    fs::rename(&aside, b).map_err(|e| AudioRemoteError::Io(format!("can't move old bundle to {}: {e}", b.display())))
+}
+
+/// "<dir>/.<name><suffix>" next to `path`
+pub(crate) fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}{suffix}"))
+}
+
+/// Copy a bundle with permissions, xattrs and ACLs (code signatures live in xattrs)
+#[cfg(target_os = "macos")]
+pub(crate) fn copy_bundle(src: &Path, dest: &Path) -> Result<(), AudioRemoteError> {
+    let (c_src, c_dest) = (c_path(src)?, c_path(dest)?);
+    let flags = libc::COPYFILE_DATA | libc::COPYFILE_METADATA | libc::COPYFILE_RECURSIVE | libc::COPYFILE_NOFOLLOW;
+    match unsafe { libc::copyfile(c_src.as_ptr(), c_dest.as_ptr(), std::ptr::null_mut(), flags) } {
+        0 => Ok(()),
+        _ => Err(AudioRemoteError::Io(format!("can't copy {}: {}", src.display(), io::Error::last_os_error()))),
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+pub(crate) fn copy_bundle(src: &Path, dest: &Path) -> Result<(), AudioRemoteError> {
+    crate::delta::copy_tree(src, dest, |_| true)
+}
+
+/// Replace `installed` with the bundle at `staged`.
+///
+/// The staged bundle is validated against the installed bundle's identifier and
+/// moved onto the same volume if needed, then the two are exchanged in one
+/// rename so the app path is never missing. If the swapped-in bundle fails
+/// validation the swap is reversed. The previous version is kept at
+/// "<dir>/.<name>.previous" when `keep_backup` is set.
+pub fn apply_staged(staged: &Path, installed: &Path, keep_backup: bool) -> Result<BundleInfo, AudioRemoteError> {
+    let current = staging::validate_bundle(installed, None)?;
+    staging::validate_bundle(staged, Some(&current.bundle_identifier))?;
+
+    let parent = installed.parent().ok_or(AudioRemoteError::InvalidArgument("installed bundle has no parent directory".into()))?;
+    let same_volume = match (fs::metadata(staged), fs::metadata(parent)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev(),
+        _ => false,
+    };
+    let incoming = sibling(installed, ".incoming");
+    let _ = fs::remove_dir_all(&incoming);
+    if same_volume {
+        fs::rename(staged, &incoming).map_err(|e| AudioRemoteError::Io(format!("can't move staged bundle: {e}")))?;
+    } else {
+        copy_bundle(staged, &incoming)?;
+    }
+
+    if let Err(e) = exchange(installed, &incoming) {
+        let _ = fs::remove_dir_all(&incoming);
+        return Err(e);
+    }
+
+    // `incoming` now holds the previous version
+    let previous = incoming;
+    let info = match staging::validate_bundle(installed, Some(&current.bundle_identifier)) {
+        Ok(info) => info,
+        Err(e) => {
+            let rollback = exchange(installed, &previous);
+            let _ = fs::remove_dir_all(&previous);
+            return Err(AudioRemoteError::InvalidBundle(match rollback {
+                Ok(()) => format!("new bundle failed validation, rolled back: {e}"),
+                Err(r) => format!("new bundle failed validation ({e}) and rollback failed: {r}"),
+            }));
+        }
+    };
+
+    let backup = sibling(installed, ".previous");
+    let _ = fs::remove_dir_all(&backup);
+    if keep_backup {
+        fs::rename(&previous, &backup).map_err(|e| AudioRemoteError::Io(format!("can't keep backup: {e}")))?;
+    } else {
+        let _ = fs::remove_dir_all(&previous);
+    }
+    log::info!("installed {} {} at {}", info.bundle_identifier, info.short_version.as_deref().unwrap_or("?"), installed.display());
+    Ok(info)
+}
+
+/// Reopen `app` once process `pid` has exited, from a detached helper that outlives us
+pub fn relaunch_after_exit(app: &Path, pid: u32) -> Result<(), AudioRemoteError> {
+    let script = "while kill -0 \"$0\" 2>/dev/null; do sleep 0.2; done; exec /usr/bin/open \"$1\"";
+    Command::new("/bin/sh")
+        .arg("-c")
+        .arg(script)
+        .arg(pid.to_string())
+        .arg(app)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map(drop)
+        .map_err(|e| AudioRemoteError::Io(format!("can't start relaunch helper: {e}")))
+}
+
+/// A misconfigured feed must never silently take users below the version they last ran
+fn refuse_downgrade(staged: &Path) -> Result<(), AudioRemoteError> {
+    let info = staging::validate_bundle(staged, None)?;
+    let Some(raw) = info.short_version else { return Ok(()) };
+    let candidate = version::parse(&raw, ParseMode::Lenient).map_err(|e| AudioRemoteError::InvalidBundle(format!("invalid bundle version \"{raw}\": {e}")))?;
+    if policy::is_downgrade(&store::global(), &candidate) {
+        return Err(AudioRemoteError::Refused(format!("refusing to downgrade to {candidate}; call update_set_allow_downgrade to roll back")));
+    }
+    Ok(())
+}
+
+/// Atomically replace the installed app bundle with a staged update
+/// Refuses to install a version below the last launched one unless a rollback was allowed.
+/// The swap is a single rename on APFS (rollback if the new bundle fails validation);
+/// permissions and xattrs are preserved. The previous version is kept as
+/// "<dir>/.<name>.previous" when `keep_backup` is true.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// Both pointers must point to valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn update_apply_staged(
+    staged_app_ptr: *const c_char,
+    installed_app_ptr: *const c_char,
+    keep_backup: bool,
+) -> i32 {
+    guard("update_apply_staged", -999, || {
+        let result = str_arg(staged_app_ptr, "staged_app").and_then(|staged| {
+            let installed = str_arg(installed_app_ptr, "installed_app")?;
+            refuse_downgrade(Path::new(staged))?;
+            apply_staged(Path::new(staged), Path::new(installed), keep_backup)
+        });
+        match record(result) {
+            Some(_) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Relaunch `app_path` after process `pid` (usually the running app) exits;
+/// call this, then terminate the app
+/// Returns: 1 if the relaunch helper started, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `app_path_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn update_relaunch_after_exit(app_path_ptr: *const c_char, pid: u32) -> i32 {
+    guard("update_relaunch_after_exit", -999, || {
+        let result = str_arg(app_path_ptr, "app_path").and_then(|app| relaunch_after_exit(Path::new(app), pid));
+        match record(result) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::tests::temp_dir;
+    use crate::staging::tests::INFO_PLIST;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn make_app(path: &Path, plist: &str, binary: &[u8]) {
+        fs::create_dir_all(path.join("Contents/MacOS")).unwrap();
+        fs::write(path.join("Contents/Info.plist"), plist).unwrap();
+        fs::write(path.join("Contents/MacOS/AudioRemote"), binary).unwrap();
+        fs::set_permissions(path.join("Contents/MacOS/AudioRemote"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_apply_staged_swaps_and_keeps_backup() {
+        let root = temp_dir("install-swap");
+        let (installed, staged) = (root.join("Applications/AudioRemote.app"), root.join("staging/AudioRemote.app"));
+        make_app(&installed, &INFO_PLIST.replace("2.7.0", "2.6.0"), b"old");
+        make_app(&staged, INFO_PLIST, b"new");
+
+        let info = apply_staged(&staged, &installed, true).unwrap();
+        assert_eq!(info.short_version.as_deref(), Some("2.7.0"));
+        assert_eq!(fs::read(installed.join("Contents/MacOS/AudioRemote")).unwrap(), b"new");
+        let backup = root.join("Applications/.AudioRemote.app.previous");
+        assert_eq!(fs::read(backup.join("Contents/MacOS/AudioRemote")).unwrap(), b"old");
+        assert!(!staged.exists());
+        assert!(!root.join("Applications/.AudioRemote.app.incoming").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_apply_staged_refuses_other_bundle() {
+        let root = temp_dir("install-other");
+        let (installed, staged) = (root.join("AudioRemote.app"), root.join("staging/Other.app"));
+        make_app(&installed, INFO_PLIST, b"old");
+        make_app(&staged, &INFO_PLIST.replace("com.audioremote.app", "com.evil.app"), b"evil");
+
+        assert!(apply_staged(&staged, &installed, false).is_err());
+        assert_eq!(fs::read(installed.join("Contents/MacOS/AudioRemote")).unwrap(), b"old");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_exchange() {
+        let root = temp_dir("install-exchange");
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::create_dir_all(root.join("b")).unwrap();
+        fs::write(root.join("a/marker"), "a").unwrap();
+        exchange(&root.join("a"), &root.join("b")).unwrap();
+        assert!(root.join("b/marker").exists());
+        assert!(!root.join("a/marker").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,222 @@
+//! The Mac's own network addresses, for advertising and discovery. Link-local
+//! IPv6 addresses are only meaningful with their interface, so every address
+//! carries the index and name of the interface it was found on.
+//!
+//! Hosts the caller hands in may be names, IPv4 or IPv6 literals, or
+//! link-local literals with their zone ("fe80::1%en0"), bracketed or not; the
+//! helpers here turn them into socket addresses, URL hosts and Host headers.
+
+use std::ffi::{c_char, CStr, CString};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
+
+use crate::error::string_result;
+use crate::{guard, str_arg, AudioRemoteError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    /// e.g. "en0"
+    pub interface: String,
+    pub index: u32,
+    pub address: IpAddr,
+}
+
+impl InterfaceAddress {
+    pub fn is_link_local(&self) -> bool {
+        match self.address {
+            IpAddr::V4(address) => address.is_link_local(),
+            IpAddr::V6(address) => is_link_local_v6(&address),
+        }
+    }
+}
+
+pub fn is_link_local_v6(address: &Ipv6Addr) -> bool {
+    address.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// Name of the interface with `index`, e.g. "en0"
+pub fn interface_name(index: u32) -> Option<String> {
+    let mut buffer = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let name = unsafe { libc::if_indextoname(index, buffer.as_mut_ptr()) };
+    match name.is_null() {
+        true => None,
+        false => Some(unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned()),
+    }
+}
+
+/// An address as remotes should see it: link-local IPv6 with its zone ("fe80::1%en0")
+pub fn format_address(address: IpAddr, scope: u32) -> String {
+    match address {
+        IpAddr::V6(v6) if is_link_local_v6(&v6) && scope != 0 => match interface_name(scope) {
+            Some(interface) => format!("{v6}%{interface}"),
+            None => format!("{v6}%{scope}"),
+        },
+        address => address.to_string(),
+    }
+}
+
+/// Index of the interface named `name`, e.g. "en0"
+pub fn interface_index(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => None,
+        index => Some(index),
+    }
+}
+
+/// `host` without URL brackets or zone: what TLS checks the certificate for
+pub fn bare_host(host: &str) -> &str {
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    match host.contains(':') {
+        true => host.split('%').next().unwrap_or(host),
+        false => host,
+    }
+}
+
+/// An IP literal and its zone's interface index (0 without one): "192.168.1.2", "2001:db8::1",
+/// "fe80::1%en0", "fe80::1%4" or, from a URL, "[fe80::1%25en0]". None for names
+pub fn parse_scoped(host: &str) -> Option<(IpAddr, u32)> {
+    let (host, bracketed) = match host.strip_prefix('[').and_then(|host| host.strip_suffix(']')) {
+        Some(host) => (host, true),
+        None => (host, false),
+    };
+    let (address, zone) = match host.split_once('%') {
+        // RFC 6874: in a URL the zone's "%" is itself escaped
+        Some((address, zone)) if bracketed => (address, Some(zone.strip_prefix("25").unwrap_or(zone))),
+        Some((address, zone)) => (address, Some(zone)),
+        None => (host, None),
+    };
+    let address: IpAddr = address.parse().ok()?;
+    match (address, zone) {
+        (_, None) => Some((address, 0)),
+        (IpAddr::V6(_), Some(zone)) => Some((address, zone.parse().ok().or_else(|| interface_index(zone))?)),
+        (IpAddr::V4(_), Some(_)) => None,
+    }
+}
+
+/// `host` for a URL: IPv6 literals in brackets with the zone escaped (RFC 6874), names as they are
+pub fn url_host(host: &str) -> String {
+    match host.starts_with('[') || !host.contains(':') {
+        true => host.to_owned(),
+        false => format!("[{}]", host.replacen('%', "%25", 1)),
+    }
+}
+
+/// `host:port` for a Host header. The zone is left out: it only means something on this machine
+pub fn host_header(host: &str, port: u16) -> String {
+    let host = bare_host(host);
+    match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    }
+}
+
+/// The addresses to try for `host`, including zoned link-local literals, which the system
+/// resolver doesn't take everywhere
+pub fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    match parse_scoped(host) {
+        Some((IpAddr::V6(address), scope)) => Ok(vec![SocketAddr::V6(SocketAddrV6::new(address, port, 0, scope))]),
+        Some((address, _)) => Ok(vec![SocketAddr::new(address, port)]),
+        None if host.contains('%') => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no interface {host}"))),
+        None => Ok((bare_host(host), port).to_socket_addrs()?.collect()),
+    }
+}
+
+/// Addresses of every interface that is up, loopback excluded
+pub fn local_addresses() -> Result<Vec<InterfaceAddress>, AudioRemoteError> {
+    let mut list: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut list) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let mut addresses = Vec::new();
+    let mut entry = list;
+    while !entry.is_null() {
+        let ifaddr = unsafe { &*entry };
+        entry = ifaddr.ifa_next;
+        let flags = ifaddr.ifa_flags as libc::c_int;
+        if ifaddr.ifa_addr.is_null() || flags & libc::IFF_UP == 0 || flags & libc::IFF_LOOPBACK != 0 {
+            continue;
+        }
+        let Some(address) = (unsafe { socket_address(ifaddr.ifa_addr) }) else {
+            continue;
+        };
+        let interface = unsafe { CStr::from_ptr(ifaddr.ifa_name) }.to_string_lossy().into_owned();
+        let index = unsafe { libc::if_nametoindex(ifaddr.ifa_name) };
+        addresses.push(InterfaceAddress { interface, index, address });
+    }
+    unsafe { libc::freeifaddrs(list) };
+    Ok(addresses)
+}
+
+unsafe fn socket_address(address: *const libc::sockaddr) -> Option<IpAddr> {
+    match i32::from((*address).sa_family) {
+        libc::AF_INET => {
+            let address = &*(address as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(address.sin_addr.s_addr))))
+        }
+        libc::AF_INET6 => {
+            let address = &*(address as *const libc::sockaddr_in6);
+            Some(IpAddr::V6(Ipv6Addr::from(address.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+/// `host` (a name, an IP, or link-local IPv6 with its zone as in peer addresses) for a URL:
+/// "fe80::1%en0" becomes "[fe80::1%25en0]", "2001:db8::1" "[2001:db8::1]"; names are unchanged.
+/// Returns: the host (free with rust_string_free), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `host` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_url_host(host: *const c_char) -> *mut c_char {
+    guard("ar_url_host", std::ptr::null_mut(), || string_result(str_arg(host, "host").map(url_host)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_addresses() {
+        let addresses = local_addresses().unwrap();
+        assert!(addresses.iter().all(|a| !a.address.is_loopback() && !a.interface.is_empty()));
+        assert!(is_link_local_v6(&"fe80::1c2b:3dff:fe4e:5f60".parse().unwrap()));
+        assert_eq!(format_address("fe80::1".parse().unwrap(), 0), "fe80::1");
+        assert_eq!(format_address("2001:db8::1".parse().unwrap(), 3), "2001:db8::1");
+        if let Some(first) = addresses.first() {
+            let name = interface_name(first.index).unwrap();
+            assert_eq!(format_address("fe80::1".parse().unwrap(), first.index), format!("fe80::1%{name}"));
+        }
+        assert!(!is_link_local_v6(&"2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_hosts() {
+        let loopback = interface_name(1).unwrap();
+        let v6: IpAddr = "fe80::1".parse().unwrap();
+        assert_eq!(parse_scoped(&format!("fe80::1%{loopback}")), Some((v6, 1)));
+        assert_eq!(parse_scoped("fe80::1%1"), Some((v6, 1)));
+        assert_eq!(parse_scoped(&format!("[fe80::1%25{loopback}]")), Some((v6, 1)));
+        assert_eq!(parse_scoped("192.168.1.2"), Some(("192.168.1.2".parse().unwrap(), 0)));
+        assert_eq!(parse_scoped("fe80::1%nosuchinterface"), None);
+        assert_eq!(parse_scoped("192.168.1.2%1"), None);
+        assert_eq!(parse_scoped("studio.local"), None);
+
+        assert_eq!(url_host("fe80::1%en0"), "[fe80::1%25en0]");
+        assert_eq!(url_host("2001:db8::1"), "[2001:db8::1]");
+        assert_eq!(url_host("[::1]"), "[::1]");
+        assert_eq!(url_host("studio.local"), "studio.local");
+        let host = crate::error::tests::take_c_string(unsafe { ar_url_host(c"fe80::1%en0".as_ptr()) });
+        assert_eq!(host.as_deref(), Some("[fe80::1%25en0]"));
+        assert_eq!(host_header("fe80::1%en0", 8765), "[fe80::1]:8765");
+        assert_eq!(host_header("[::1]", 80), "[::1]:80");
+        assert_eq!(host_header("192.168.1.2", 80), "192.168.1.2:80");
+        assert_eq!(bare_host("[fe80::1%25en0]"), "fe80::1");
+
+        let scoped = resolve("fe80::1%1", 8765).unwrap();
+        assert!(matches!(scoped[..], [SocketAddr::V6(address)] if address.scope_id() == 1 && address.port() == 8765));
+        assert_eq!(resolve("[::1]", 80).unwrap(), ["[::1]:80".parse().unwrap()]);
+        assert!(resolve("fe80::1%nosuchinterface", 80).is_err());
+    }
+}
@@ -0,0 +1,377 @@
+//! Local control over a Unix domain socket, for scripts, a command-line tool
+//! and helper apps on the same Mac, without the network stack or pairing. The
+//! protocol is JSON-RPC 2.0, one request or batch per line and one response
+//! per line:
+//!
+//! ```json
+//! {"jsonrpc": "2.0", "id": 1, "method": "volume.set", "params": {"volume": 0.5}}
+//! {"jsonrpc": "2.0", "id": 1, "result": {"volume": 0.5, "muted": false}}
+//! ```
+//!
+//! The methods are the REST API's operations (see `METHODS`), which reach
+//! Swift's handlers through `routes::perform`, and everything registered with
+//! `dispatch`. Nothing is authenticated: the socket is the user's alone
+//! (mode 0600, in the app's container), so whoever can connect could run the
+//! app anyway.
+
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::error::string_result;
+use crate::routes::{self, Endpoint};
+use crate::{dispatch, guard, store, str_arg, AudioRemoteError};
+
+/// In the store directory, unless the config names another path
+pub const SOCKET_NAME: &str = "control.sock";
+const MAX_CONNECTIONS: usize = 16;
+const MAX_LINE_BYTES: u64 = 1024 * 1024;
+const MAX_BATCH: usize = 64;
+
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+/// Everything else; `data` has the AudioRemoteError code or the HTTP status
+const SERVER_ERROR: i32 = -32000;
+
+/// The REST API's operations by method name
+pub const METHODS: &[(&str, Endpoint)] = &[
+    ("volume.get", Endpoint::GetVolume),
+    ("volume.set", Endpoint::SetVolume),
+    ("mute.set", Endpoint::SetMute),
+    ("devices.list", Endpoint::ListDevices),
+    ("devices.select", Endpoint::SelectDevice),
+    ("nowPlaying.get", Endpoint::NowPlaying),
+];
+
+/// `ar_ipc_start` configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct IpcConfig {
+    /// Default: control.sock in the store directory
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct RpcError {
+    code: i32,
+    message: String,
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), data: None }
+    }
+}
+
+impl From<AudioRemoteError> for RpcError {
+    fn from(e: AudioRemoteError) -> Self {
+        let code = match e {
+            AudioRemoteError::InvalidArgument(_) => INVALID_PARAMS,
+            _ => SERVER_ERROR,
+        };
+        Self { code, message: e.to_string(), data: Some(json!({"code": e.code()})) }
+    }
+}
+
+/// A request object, less its "id", which is taken as it is
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+fn call(method: &str, params: Value) -> Result<Value, RpcError> {
+    if let Some(&(_, endpoint)) = METHODS.iter().find(|(name, _)| *name == method) {
+        let params = if params.is_null() { json!({}) } else { params };
+        return routes::perform(endpoint, params.to_string().as_bytes()).map_err(|(status, message)| RpcError {
+            code: if status == 400 { INVALID_PARAMS } else { SERVER_ERROR },
+            message,
+            data: Some(json!({"status": status})),
+        });
+    }
+    let handler = dispatch::handler(method).ok_or_else(|| RpcError::new(METHOD_NOT_FOUND, format!("unknown method \"{method}\"")))?;
+    handler(params).map_err(RpcError::from)
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err(error) => {
+            let mut object = json!({"code": error.code, "message": error.message});
+            if let Some(data) = error.data {
+                object["data"] = data;
+            }
+            json!({"jsonrpc": "2.0", "id": id, "error": object})
+        }
+    }
+}
+
+/// Run one request object; None for a notification (no "id"), which isn't answered
+fn answer(request: Value) -> Option<Value> {
+    let id = request.get("id").cloned();
+    match serde_json::from_value::<Request>(request) {
+        Ok(request) if request.jsonrpc == "2.0" => {
+            let result = call(&request.method, request.params);
+            id.map(|id| response(id, result))
+        }
+        Ok(_) => Some(response(id.unwrap_or_default(), Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"")))),
+        Err(e) => Some(response(id.unwrap_or_default(), Err(RpcError::new(INVALID_REQUEST, format!("invalid request: {e}"))))),
+    }
+}
+
+/// Answer one line: a request or a batch of them. None when there's nothing to send back.
+pub fn handle_line(line: &str) -> Option<String> {
+    let answered = match serde_json::from_str::<Value>(line) {
+        Err(e) => Some(response(Value::Null, Err(RpcError::new(PARSE_ERROR, format!("invalid JSON: {e}"))))),
+        Ok(Value::Array(batch)) if batch.is_empty() || batch.len() > MAX_BATCH => {
+            Some(response(Value::Null, Err(RpcError::new(INVALID_REQUEST, format!("a batch holds 1 to {MAX_BATCH} requests")))))
+        }
+        Ok(Value::Array(batch)) => {
+            let answers: Vec<Value> = batch.into_iter().filter_map(answer).collect();
+            (!answers.is_empty()).then_some(Value::Array(answers))
+        }
+        Ok(request) => answer(request),
+    };
+    answered.map(|answer| answer.to_string())
+}
+
+fn serve(stream: UnixStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    loop {
+        let mut line = Vec::new();
+        if reader.by_ref().take(MAX_LINE_BYTES + 1).read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        if line.len() as u64 > MAX_LINE_BYTES {
+            let error = response(Value::Null, Err(RpcError::new(INVALID_REQUEST, format!("requests are limited to {MAX_LINE_BYTES} bytes"))));
+            return writeln!(writer, "{error}");
+        }
+        let line = String::from_utf8_lossy(&line);
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(answer) = handle_line(&line) {
+            writeln!(writer, "{answer}")?;
+        }
+    }
+}
+
+struct Running {
+    path: PathBuf,
+    stopping: Arc<AtomicBool>,
+    connections: Arc<Mutex<BTreeMap<u64, UnixStream>>>,
+    acceptor: JoinHandle<()>,
+}
+
+static IPC: Mutex<Option<Running>> = Mutex::new(None);
+
+fn accept_loop(listener: UnixListener, stopping: Arc<AtomicBool>, connections: Arc<Mutex<BTreeMap<u64, UnixStream>>>) {
+    let next = AtomicU64::new(1);
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::Acquire) {
+            return;
+        }
+        let Ok(stream) = stream else {
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        };
+        let id = next.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut open = connections.lock().unwrap_or_else(|e| e.into_inner());
+            if open.len() >= MAX_CONNECTIONS {
+                log::warn!("refusing local connection: {MAX_CONNECTIONS} connections open");
+                continue;
+            }
+            match stream.try_clone() {
+                Ok(clone) => open.insert(id, clone),
+                Err(_) => continue,
+            };
+        }
+        let connections = connections.clone();
+        let spawned = thread::Builder::new().name(format!("audioremote-ipc-{id}")).spawn(move || {
+            if let Err(e) = serve(stream) {
+                log::debug!("local connection {id} ended: {e}");
+            }
+            connections.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+        });
+        if let Err(e) = spawned {
+            log::warn!("can't start local connection thread: {e}");
+        }
+    }
+}
+
+/// Bind `path`, replacing a socket a crashed instance left behind
+fn bind(path: &Path) -> Result<UnixListener, AudioRemoteError> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+    if fs::symlink_metadata(path).is_ok() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(AudioRemoteError::Refused(format!("another process is listening on {}", path.display())));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path).map_err(|e| AudioRemoteError::Network(format!("can't listen on {}: {e}", path.display())))?;
+    fs::set_permissions(path, Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+/// Start listening; fails if the server is already running or the path is taken
+pub fn start(config: &IpcConfig) -> Result<PathBuf, AudioRemoteError> {
+    let mut running = IPC.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(running) = running.as_ref() {
+        return Err(AudioRemoteError::Refused(format!("the local control socket is already open at {}", running.path.display())));
+    }
+    let path = config.path.clone().unwrap_or_else(|| store::directory().join(SOCKET_NAME));
+    let listener = bind(&path)?;
+    let stopping = Arc::new(AtomicBool::new(false));
+    let connections = Arc::new(Mutex::new(BTreeMap::new()));
+    let acceptor = {
+        let (stopping, connections) = (stopping.clone(), connections.clone());
+        thread::Builder::new()
+            .name("audioremote-ipc".into())
+            .spawn(move || accept_loop(listener, stopping, connections))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the local control thread: {e}")))?
+    };
+    log::info!("local control socket at {}", path.display());
+    *running = Some(Running { path: path.clone(), stopping, connections, acceptor });
+    Ok(path)
+}
+
+/// Stop listening, close every connection and remove the socket; a no-op if not running
+pub fn stop() {
+    let Some(running) = IPC.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    running.stopping.store(true, Ordering::Release);
+    // Wake the accept loop so it sees the flag
+    let _ = UnixStream::connect(&running.path);
+    let _ = running.acceptor.join();
+    for stream in running.connections.lock().unwrap_or_else(|e| e.into_inner()).values() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    let _ = fs::remove_file(&running.path);
+    log::info!("local control socket closed");
+}
+
+/// Open a Unix domain socket for local control by scripts, a CLI or a helper app, speaking
+/// JSON-RPC 2.0 with one message per line. Methods: volume.get, volume.set {"volume"}, mute.set
+/// {"muted"}, devices.list, devices.select {"id"}, nowPlaying.get (answered by the
+/// ar_server_set_handler handlers) and the ar_dispatch methods. There's no authentication; the
+/// socket is owner-only. `config_json` (nullable): {"path" (default: control.sock in the store directory)}.
+/// Returns: the socket's path (free with rust_string_free), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_ipc_start(config_json: *const c_char) -> *mut c_char {
+    guard("ar_ipc_start", std::ptr::null_mut(), || {
+        let config = match config_json.is_null() {
+            true => Ok(IpcConfig::default()),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid local control config: {e}")))
+            }),
+        };
+        string_result(config.and_then(|config| start(&config)).map(|path| path.display().to_string()))
+    })
+}
+
+/// Close the local control socket and its connections; a no-op if not open. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_ipc_stop() {
+    guard("ar_ipc_stop", (), stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::tests::temp_dir;
+    use crate::handle::Handle;
+    use crate::routes::{ar_server_respond, ar_server_set_handler};
+    use std::ffi::{c_void, CStr, CString};
+
+    fn rpc(line: &str) -> Value {
+        serde_json::from_str(&handle_line(line).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_messages() {
+        assert_eq!(
+            rpc(r#"{"jsonrpc": "2.0", "id": 1, "method": "version.compare", "params": {"v1": "2.6.0", "v2": "2.10.0"}}"#),
+            json!({"jsonrpc": "2.0", "id": 1, "result": -1})
+        );
+        assert_eq!(rpc("{not json")["error"]["code"], PARSE_ERROR);
+        assert_eq!(rpc(r#"{"jsonrpc": "1.0", "id": 2, "method": "methods"}"#), json!({"jsonrpc": "2.0", "id": 2, "error": {"code": INVALID_REQUEST, "message": "jsonrpc must be \"2.0\""}}));
+        assert_eq!(rpc(r#"{"jsonrpc": "2.0", "id": "x", "method": "nope"}"#)["error"]["code"], METHOD_NOT_FOUND);
+        let invalid = rpc(r#"{"jsonrpc": "2.0", "id": 3, "method": "version.compare", "params": {"v1": "2.6.0"}}"#);
+        assert_eq!(invalid["error"], json!({"code": INVALID_PARAMS, "message": "invalid params: missing field `v2`", "data": {"code": 1}}));
+
+        // Notifications aren't answered, in a batch or alone
+        assert_eq!(handle_line(r#"{"jsonrpc": "2.0", "method": "abi.version"}"#), None);
+        let batch = rpc(r#"[{"jsonrpc": "2.0", "id": 1, "method": "abi.version"}, {"jsonrpc": "2.0", "method": "abi.version"}, 5]"#);
+        assert_eq!(batch.as_array().unwrap().len(), 2);
+        assert_eq!(batch[0]["result"], crate::abi::ABI_VERSION);
+        assert_eq!(batch[1]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(rpc("[]")["error"]["code"], INVALID_REQUEST);
+    }
+
+    extern "C" fn mute(request: Handle, _endpoint: i32, params: *const c_char, _ctx: *mut c_void) {
+        let params: Value = serde_json::from_str(unsafe { CStr::from_ptr(params) }.to_str().unwrap()).unwrap();
+        let state = CString::new(json!({"volume": 0.625, "muted": params["muted"]}).to_string()).unwrap();
+        assert_eq!(unsafe { ar_server_respond(request, state.as_ptr()) }, 1);
+    }
+
+    #[test]
+    fn test_socket() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir("ipc");
+        let path = dir.join(SOCKET_NAME);
+        // A socket left behind by a crash is replaced
+        fs::create_dir_all(&dir).unwrap();
+        drop(UnixListener::bind(&path).unwrap());
+        assert_eq!(start(&IpcConfig { path: Some(path.clone()) }).unwrap(), path);
+        assert!(start(&IpcConfig { path: Some(path.clone()) }).is_err());
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(unsafe { ar_server_set_handler(Endpoint::SetMute as i32, Some(mute), std::ptr::null_mut()) }, 1);
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        let mut exchange = |request: &str| {
+            writeln!(writer, "{request}").unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+        let muted = exchange(r#"{"jsonrpc": "2.0", "id": 1, "method": "mute.set", "params": {"muted": true}}"#);
+        assert_eq!(muted, json!({"jsonrpc": "2.0", "id": 1, "result": {"volume": 0.625, "muted": true}}));
+        let invalid = exchange(r#"{"jsonrpc": "2.0", "id": 2, "method": "volume.set", "params": {"volume": 4}}"#);
+        assert_eq!((invalid["error"]["code"].clone(), invalid["error"]["data"].clone()), (json!(INVALID_PARAMS), json!({"status": 400})));
+        assert_eq!(exchange(r#"{"jsonrpc": "2.0", "id": 3, "method": "methods"}"#)["result"], json!(dispatch::methods()));
+
+        stop();
+        assert_eq!(unsafe { ar_server_set_handler(Endpoint::SetMute as i32, None, std::ptr::null_mut()) }, 1);
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+        assert!(!path.exists());
+        let _ = fs::remove_dir_all(dir);
+    }
+}
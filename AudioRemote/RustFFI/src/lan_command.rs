@@ -0,0 +1,477 @@
+//! Signed commands over UDP, for the paths that have no TLS or token to lean
+//! on: a remote that just woke the Mac with a magic packet (see `wol`), or one
+//! on a network where it found the Mac by discovery query, can send a command
+//! to the discovery port without setting up a connection first.
+//!
+//! Every command is signed by its remote: HMAC-SHA256 with a key derived from
+//! the pairing key, over the whole datagram. It names the remote, carries a
+//! timestamp and a random nonce, and is refused if the clock is off by more
+//! than `WINDOW` or the nonce was seen inside it, so a recorded command can't
+//! be played again and a device that isn't paired can't make one up. The
+//! answer is signed with the same key and names the command's nonce. A
+//! command resent because its answer was lost gets that answer again, as in
+//! `commands`, instead of running twice.
+//!
+//! A datagram is the magic line, a JSON body and the 32-byte tag. Unsigned or
+//! stale datagrams get no answer at all.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void, CString};
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::Sha256;
+
+use crate::discovery::DISCOVERY_PORT;
+use crate::error::string_result;
+use crate::ipc;
+use crate::pairing::{self, random_bytes};
+use crate::routes;
+use crate::runtime::{self, SendPtr};
+use crate::sessions;
+use crate::{guard, interfaces, str_arg, AudioRemoteError};
+
+const COMMAND_MAGIC: &[u8] = b"AUDIOREMOTE-COMMAND/1\n";
+const ANSWER_MAGIC: &[u8] = b"AUDIOREMOTE-ANSWER/1\n";
+const KEY_INFO: &[u8] = b"audioremote lan command v1";
+const TAG_BYTES: usize = 32;
+const NONCE_BYTES: usize = 16;
+/// How far a command's timestamp may be from the Mac's clock, in seconds
+pub const WINDOW: u64 = 30;
+/// Commands remembered at once, for refusing replays
+const MAX_SEEN: usize = 1024;
+const MAX_DATAGRAM: usize = 65507;
+/// How often a remote resends a command that hasn't been answered
+const RESEND_INTERVAL: Duration = Duration::from_millis(500);
+/// The method that wakes the display, on top of the ones `ipc` serves
+pub const WAKE_METHOD: &str = "wake";
+
+/// The signing key for a pairing key
+pub fn command_key(pairing_key: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(None, pairing_key).expand(KEY_INFO, &mut key).expect("32 bytes is a valid HKDF-SHA256 length");
+    key
+}
+
+fn tag(key: &[u8; 32], magic: &[u8], body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(magic);
+    mac.update(body);
+    mac
+}
+
+fn seal(key: &[u8; 32], magic: &[u8], body: &impl Serialize) -> Vec<u8> {
+    let body = serde_json::to_vec(body).unwrap_or_default();
+    let tag = tag(key, magic, &body).finalize().into_bytes();
+    [magic, &body, &tag].concat()
+}
+
+/// The body and tag of a datagram with `magic`, unchecked
+fn split<'a>(packet: &'a [u8], magic: &[u8]) -> Option<(&'a [u8], &'a [u8])> {
+    let rest = packet.strip_prefix(magic)?;
+    rest.split_at_checked(rest.len().checked_sub(TAG_BYTES)?)
+}
+
+fn is_signed(key: &[u8; 32], magic: &[u8], body: &[u8], signature: &[u8]) -> bool {
+    tag(key, magic, body).verify_slice(signature).is_ok()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Command {
+    pub remote_id: String,
+    /// Seconds since 1970 on the remote's clock
+    pub timestamp: u64,
+    /// Base64, 16 random bytes
+    pub nonce: String,
+    /// A local control method (see `ipc::METHODS`) or "wake"
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Answer {
+    /// The command's
+    pub nonce: String,
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    /// The HTTP status the command would have got over REST, when it failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+/// A command that's been verified, with the key to sign its answer
+struct Verified {
+    command: Command,
+    key: [u8; 32],
+}
+
+/// Check a command datagram against the remote's pairing key. Doesn't look for replays.
+fn verify(packet: &[u8], now: u64) -> Result<Verified, AudioRemoteError> {
+    let refused = |why: &str| AudioRemoteError::VerificationFailed(format!("LAN command refused: {why}"));
+    let (body, signature) = split(packet, COMMAND_MAGIC).ok_or_else(|| refused("not a command"))?;
+    let command: Command = serde_json::from_slice(body).map_err(|e| refused(&e.to_string()))?;
+    // An unknown remote fails the same way as a wrong key
+    let pairing = pairing::pairings().remove(&command.remote_id).ok_or_else(|| refused("bad signature"))?;
+    let key = command_key(&STANDARD.decode(&pairing.key).map_err(|_| refused("bad signature"))?);
+    if !is_signed(&key, COMMAND_MAGIC, body, signature) {
+        return Err(refused("bad signature"));
+    }
+    if command.timestamp.abs_diff(now) > WINDOW {
+        return Err(refused(&format!("its clock is {} s off", command.timestamp.abs_diff(now))));
+    }
+    Ok(Verified { command, key })
+}
+
+struct SeenCommand {
+    /// The later of its timestamp and when it arrived
+    timestamp: u64,
+    /// None while it's running
+    answer: Option<Vec<u8>>,
+}
+
+/// Recently seen commands by remote and nonce, and the answers sent to them
+#[derive(Default)]
+struct Seen {
+    commands: BTreeMap<(String, String), SeenCommand>,
+}
+
+/// What to do with a verified command
+#[derive(Debug, PartialEq)]
+enum Fate {
+    Run,
+    /// Still running; its answer goes out when it's done
+    Ignore,
+    Resend(Vec<u8>),
+}
+
+impl Seen {
+    fn check(&mut self, command: &Command, now: u64) -> Result<Fate, AudioRemoteError> {
+        self.commands.retain(|_, seen| seen.timestamp + WINDOW >= now);
+        let key = (command.remote_id.clone(), command.nonce.clone());
+        if let Some(seen) = self.commands.get(&key) {
+            return Ok(seen.answer.clone().map_or(Fate::Ignore, Fate::Resend));
+        }
+        if self.commands.len() >= MAX_SEEN {
+            return Err(AudioRemoteError::Refused(format!("{MAX_SEEN} LAN commands in {WINDOW} s")));
+        }
+        self.commands.insert(key, SeenCommand { timestamp: command.timestamp.max(now), answer: None });
+        Ok(Fate::Run)
+    }
+
+    fn answered(&mut self, command: &Command, answer: Vec<u8>) {
+        if let Some(seen) = self.commands.get_mut(&(command.remote_id.clone(), command.nonce.clone())) {
+            seen.answer = Some(answer);
+        }
+    }
+}
+
+static SEEN: Mutex<Seen> = Mutex::new(Seen { commands: BTreeMap::new() });
+
+fn seen() -> std::sync::MutexGuard<'static, Seen> {
+    SEEN.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Called with the paired remote's ID (only valid during the call) when it asks to wake the display
+pub type WakeHandler = extern "C" fn(remote_id: *const c_char, ctx: *mut c_void);
+
+static WAKE: Mutex<Option<(WakeHandler, SendPtr)>> = Mutex::new(None);
+
+/// Carry out a verified command, held to the remote's scope
+fn run(command: &Command) -> routes::Reply {
+    if command.method == WAKE_METHOD {
+        let Some((handler, ctx)) = *WAKE.lock().unwrap_or_else(|e| e.into_inner()) else {
+            return Err((501, "this Mac doesn't support that yet".into()));
+        };
+        let remote_id = CString::new(command.remote_id.as_str()).unwrap_or_default();
+        runtime::deliver(move || handler(remote_id.as_ptr(), ctx.get()));
+        return Ok(Value::Null);
+    }
+    let Some(&(_, endpoint)) = ipc::METHODS.iter().find(|(name, _)| *name == command.method) else {
+        return Err((404, format!("unknown method \"{}\"", command.method)));
+    };
+    let scope = sessions::scope_of(&command.remote_id);
+    if !scope.allows(endpoint) {
+        return Err((403, format!("this remote has {} access; {endpoint:?} needs {}", scope.name(), sessions::Scope::required_for(endpoint).name())));
+    }
+    let params = if command.params.is_null() { serde_json::json!({}) } else { command.params.clone() };
+    routes::perform(endpoint, params.to_string().as_bytes())
+}
+
+fn answer_for(verified: &Verified, reply: routes::Reply) -> Vec<u8> {
+    let nonce = verified.command.nonce.clone();
+    let answer = match reply {
+        Ok(result) => Answer { nonce, ok: true, result: Some(result), status: None, message: None },
+        Err((status, message)) => Answer { nonce, ok: false, result: None, status: Some(status), message: Some(message) },
+    };
+    seal(&verified.key, ANSWER_MAGIC, &answer)
+}
+
+/// Whether a datagram to the discovery port is a command rather than a query
+pub(crate) fn is_command(packet: &[u8]) -> bool {
+    packet.starts_with(COMMAND_MAGIC)
+}
+
+/// Take a command the discovery responder received: verify it, and run it on its own
+/// thread so queries keep being answered, sending the answer to `from`
+pub(crate) fn receive(socket: &UdpSocket, packet: &[u8], from: SocketAddr) {
+    let now = pairing::now();
+    let verified = match verify(packet, now) {
+        Ok(verified) => verified,
+        Err(e) => return log::debug!("ignoring datagram from {from}: {e}"),
+    };
+    let fate = seen().check(&verified.command, now);
+    match fate {
+        Ok(Fate::Run) => {}
+        Ok(Fate::Resend(answer)) => {
+            let _ = socket.send_to(&answer, from);
+            return;
+        }
+        Ok(Fate::Ignore) => return,
+        Err(e) => return log::warn!("ignoring LAN command from {}: {e}", verified.command.remote_id),
+    }
+    let Ok(socket) = socket.try_clone() else {
+        return;
+    };
+    let spawned = thread::Builder::new().name("audioremote-lan-command".into()).spawn(move || {
+        log::info!("LAN command {} from {} at {from}", verified.command.method, verified.command.remote_id);
+        let answer = answer_for(&verified, run(&verified.command));
+        seen().answered(&verified.command, answer.clone());
+        let _ = socket.send_to(&answer, from);
+    });
+    if let Err(e) = spawned {
+        log::warn!("can't start LAN command thread: {e}");
+    }
+}
+
+/// `ar_lan_command_send` request
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SendRequest {
+    /// The Mac: an IPv4 address or a name
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    pub remote_id: String,
+    /// The pairing key, base64
+    pub key: String,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default = "default_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+fn default_port() -> u16 {
+    DISCOVERY_PORT
+}
+
+fn default_timeout_ms() -> u64 {
+    2000
+}
+
+/// The error a failed answer stands for
+fn answer_error(status: u16, message: String) -> AudioRemoteError {
+    match status {
+        400 => AudioRemoteError::InvalidArgument(message),
+        401 | 403 => AudioRemoteError::Refused(message),
+        404 => AudioRemoteError::NotFound(message),
+        501 => AudioRemoteError::Unsupported(message),
+        _ => AudioRemoteError::Other(format!("{status}: {message}")),
+    }
+}
+
+/// Send a signed command to a Mac and wait for its signed answer, resending until `timeout_ms`
+pub fn send(request: &SendRequest) -> Result<Value, AudioRemoteError> {
+    let network = |e: std::io::Error| AudioRemoteError::Network(format!("LAN command failed: {e}"));
+    let pairing_key = STANDARD.decode(&request.key).map_err(|_| AudioRemoteError::InvalidArgument("key isn't base64".into()))?;
+    let key = command_key(&pairing_key);
+    let target = interfaces::resolve(&request.host, request.port)
+        .map_err(network)?
+        .into_iter()
+        .find(SocketAddr::is_ipv4)
+        .ok_or_else(|| AudioRemoteError::Network(format!("{} has no IPv4 address", request.host)))?;
+    let nonce = STANDARD.encode(random_bytes::<NONCE_BYTES>()?);
+    let command = Command {
+        remote_id: request.remote_id.clone(),
+        timestamp: pairing::now(),
+        nonce: nonce.clone(),
+        method: request.method.clone(),
+        params: request.params.clone(),
+    };
+    let packet = seal(&key, COMMAND_MAGIC, &command);
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(network)?;
+    let deadline = Instant::now() + Duration::from_millis(request.timeout_ms);
+    let mut buffer = vec![0; MAX_DATAGRAM];
+    loop {
+        socket.send_to(&packet, target).map_err(network)?;
+        let resend = Instant::now() + RESEND_INTERVAL;
+        while let Some(left) = resend.min(deadline).checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+            socket.set_read_timeout(Some(left)).map_err(network)?;
+            let Ok((length, from)) = socket.recv_from(&mut buffer) else {
+                continue;
+            };
+            let Some((body, signature)) = split(&buffer[..length], ANSWER_MAGIC) else {
+                continue;
+            };
+            let answer = match serde_json::from_slice::<Answer>(body) {
+                Ok(answer) if from == target && answer.nonce == nonce && is_signed(&key, ANSWER_MAGIC, body, signature) => answer,
+                _ => continue,
+            };
+            return match answer.ok {
+                true => Ok(answer.result.unwrap_or_default()),
+                false => Err(answer_error(answer.status.unwrap_or(500), answer.message.unwrap_or_default())),
+            };
+        }
+        if Instant::now() >= deadline {
+            return Err(AudioRemoteError::Network(format!("no answer from {target} within {} ms", request.timeout_ms)));
+        }
+    }
+}
+
+/// Register the handler for signed "wake" commands from paired remotes, e.g. to wake the display;
+/// NULL removes it, and wake commands are answered as unsupported. It runs on the callback queue
+/// set with ar_runtime_set_callback_queue.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `ctx` is passed back to `handler` from other threads; it must stay valid until replaced.
+#[no_mangle]
+pub unsafe extern "C" fn ar_lan_set_wake_handler(handler: Option<WakeHandler>, ctx: *mut c_void) -> i32 {
+    guard("ar_lan_set_wake_handler", -999, || {
+        *WAKE.lock().unwrap_or_else(|e| e.into_inner()) = handler.map(|handler| (handler, SendPtr(ctx)));
+        1
+    })
+}
+
+/// Send a command to a paired Mac's discovery port, signed with the pairing key, e.g. right after
+/// waking it with ar_send_wol. `request_json`: {"host", "port" (default: the discovery port),
+/// "remoteId", "key" (the pairing key, base64), "method" ("volume.set", "mute.set", "wake", or
+/// another local control method), "params", "timeoutMs" (default 2000)}.
+/// Returns: the answer's result as JSON (free with rust_string_free), NULL on error or when
+/// the Mac refused the command (see last_error_message)
+///
+/// # Safety
+/// `request_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_lan_command_send(request_json: *const c_char) -> *mut c_char {
+    guard("ar_lan_command_send", std::ptr::null_mut(), || {
+        let request = str_arg(request_json, "request").and_then(|json| {
+            serde_json::from_str::<SendRequest>(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid LAN command: {e}")))
+        });
+        string_result(request.and_then(|request| send(&request)).map(|result| result.to_string()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::Handle;
+    use crate::pairing::Pairing;
+    use crate::routes::{ar_server_respond, ar_server_set_handler, Endpoint};
+    use crate::sessions::Scope;
+    use std::ffi::CStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn command(nonce: &str, timestamp: u64) -> Command {
+        Command { remote_id: "phone".into(), timestamp, nonce: nonce.into(), method: "mute.set".into(), params: serde_json::json!({"muted": true}) }
+    }
+
+    #[test]
+    fn test_signing_and_replays() {
+        let (_store, dir) = crate::store::tests::with_temp_store("lan-command");
+        let key = [3; 32];
+        pairing::save(Pairing { remote_id: "phone".into(), remote_name: "Phone".into(), key: STANDARD.encode(key), paired_at: 0 }).unwrap();
+        let now = 1_700_000_000;
+        let packet = seal(&command_key(&key), COMMAND_MAGIC, &command("n1", now));
+        assert_eq!(verify(&packet, now + 5).unwrap().command, command("n1", now));
+
+        // A wrong key, a stranger, tampering, a stale clock and an answer passed off as a command
+        assert!(verify(&seal(&command_key(&[4; 32]), COMMAND_MAGIC, &command("n1", now)), now).is_err());
+        let stranger = Command { remote_id: "stranger".into(), ..command("n1", now) };
+        assert!(verify(&seal(&command_key(&key), COMMAND_MAGIC, &stranger), now).is_err());
+        let tampered = String::from_utf8_lossy(&packet).replace("\"n1\"", "\"n9\"").into_bytes();
+        assert!(verify(&tampered, now).is_err());
+        assert!(matches!(verify(&packet, now + WINDOW + 1), Err(e) if e.to_string().contains("clock is 31 s off")));
+        assert!(verify(&seal(&command_key(&key), ANSWER_MAGIC, &command("n1", now)), now).is_err());
+        assert!(verify(&packet[..20], now).is_err());
+
+        let mut seen = Seen::default();
+        assert_eq!(seen.check(&command("n1", now), now).unwrap(), Fate::Run);
+        assert_eq!(seen.check(&command("n1", now), now).unwrap(), Fate::Ignore);
+        seen.answered(&command("n1", now), b"answer".to_vec());
+        assert_eq!(seen.check(&command("n1", now), now + 1).unwrap(), Fate::Resend(b"answer".to_vec()));
+        assert_eq!(seen.check(&command("n2", now), now).unwrap(), Fate::Run);
+        // Forgotten once it's too old to pass the clock check anyway
+        seen.check(&command("n3", now + WINDOW + 1), now + WINDOW + 1).unwrap();
+        assert_eq!(seen.commands.len(), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    static WOKEN: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn wake(remote_id: *const c_char, _ctx: *mut c_void) {
+        assert_eq!(unsafe { CStr::from_ptr(remote_id) }.to_str().unwrap(), "phone");
+        WOKEN.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn mute(request: Handle, _endpoint: i32, params: *const c_char, _ctx: *mut c_void) {
+        let params: Value = serde_json::from_str(unsafe { CStr::from_ptr(params) }.to_str().unwrap()).unwrap();
+        let state = CString::new(serde_json::json!({"volume": 0.5, "muted": params["muted"]}).to_string()).unwrap();
+        assert_eq!(unsafe { ar_server_respond(request, state.as_ptr()) }, 1);
+    }
+
+    #[test]
+    fn test_commands_through_discovery() {
+        let _runtime = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_store, dir) = crate::store::tests::with_temp_store("lan-command-discovery");
+        let key = [5; 32];
+        pairing::save(Pairing { remote_id: "phone".into(), remote_name: "Phone".into(), key: STANDARD.encode(key), paired_at: 0 }).unwrap();
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(250))).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let config = crate::mdns::AdvertiseConfig { name: "Studio Mac".into(), version: "2.1.0".into(), port: Some(9000), capabilities: vec![] };
+        crate::discovery::tests::spawn_responder(socket, config);
+        assert_eq!(unsafe { ar_server_set_handler(Endpoint::SetMute as i32, Some(mute), std::ptr::null_mut()) }, 1);
+        assert_eq!(unsafe { ar_lan_set_wake_handler(Some(wake), std::ptr::null_mut()) }, 1);
+        let request = |method: &str, params: Value, key: [u8; 32]| SendRequest {
+            host: "127.0.0.1".into(),
+            port,
+            remote_id: "phone".into(),
+            key: STANDARD.encode(key),
+            method: method.into(),
+            params,
+            timeout_ms: 1000,
+        };
+
+        let muted = send(&request("mute.set", serde_json::json!({"muted": true}), key)).unwrap();
+        assert_eq!(muted, serde_json::json!({"volume": 0.5, "muted": true}));
+        assert_eq!(send(&request("wake", Value::Null, key)).unwrap(), Value::Null);
+        assert_eq!(WOKEN.load(Ordering::SeqCst), 1);
+        assert!(matches!(send(&request("volume.set", serde_json::json!({"volume": 9}), key)), Err(AudioRemoteError::InvalidArgument(_))));
+        assert!(matches!(send(&request("nope", Value::Null, key)), Err(AudioRemoteError::NotFound(_))));
+        sessions::set_scope("phone", Scope::VolumeOnly).unwrap();
+        assert!(matches!(send(&request("devices.select", serde_json::json!({"id": "x"}), key)), Err(AudioRemoteError::Refused(_))));
+        // A device without the key gets no answer at all
+        let spoofed = request("mute.set", serde_json::json!({"muted": false}), [6; 32]);
+        assert!(matches!(send(&SendRequest { timeout_ms: 300, ..spoofed }), Err(AudioRemoteError::Network(_))));
+
+        crate::discovery::stop();
+        assert_eq!(unsafe { ar_lan_set_wake_handler(None, std::ptr::null_mut()) }, 1);
+        assert_eq!(unsafe { ar_server_set_handler(Endpoint::SetMute as i32, None, std::ptr::null_mut()) }, 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -1,61 +1,453 @@
-use std::ffi::{CStr, c_char};
-use semver::Version;
+use std::ffi::{CStr, c_char, c_void};
+use semver::{Version, VersionReq};
+
+pub mod abi;
+pub mod appcast;
+pub mod audio_file;
+pub mod auth;
+pub mod autoswitch;
+pub mod balance;
+pub mod bluetooth;
+pub mod browse;
+pub mod channel;
+pub mod checksum;
+pub mod clock_sync;
+pub mod commands;
+pub mod connection;
+pub mod crossfade;
+pub mod delta;
+pub mod device_class;
+pub mod devices;
+pub mod discovery;
+pub mod dispatch;
+pub mod dither;
+pub mod dns;
+pub mod download;
+mod error;
+pub mod eq;
+pub mod events;
+pub mod fade;
+pub mod feed;
+pub mod flac;
+pub mod gate;
+pub mod github;
+pub mod grpc;
+pub mod handle;
+pub mod hap;
+pub mod hearing;
+pub mod homeassistant;
+pub mod http;
+pub mod install;
+pub mod interfaces;
+pub mod ipc;
+pub mod lan_command;
+pub mod lifecycle;
+pub mod limiter;
+pub mod log_file;
+pub mod logging;
+pub mod loudness;
+pub mod mdns;
+pub mod meter;
+pub mod mixer;
+pub mod mqtt;
+pub mod noise;
+pub mod offline;
+pub mod openapi;
+pub mod opus;
+pub mod pairing;
+pub mod policy;
+pub mod portmap;
+pub mod protocol;
+pub mod qr;
+pub mod raop;
+pub mod ratelimit;
+pub mod relay;
+pub mod release_notes;
+pub mod remote;
+pub mod replaygain;
+pub mod rollout;
+pub mod resampler;
+pub mod ring;
+pub mod routes;
+pub mod rtp;
+pub mod runtime;
+pub mod schedule;
+pub mod server;
+pub mod sessions;
+pub mod signature;
+pub mod simd;
+pub mod silence;
+pub mod spectrogram;
+pub mod spectrum;
+pub mod srp;
+pub mod ssdp;
+pub mod sse;
+pub mod staging;
+pub mod store;
+pub mod tls;
+pub mod true_peak;
+pub mod utf16;
+pub mod version;
+pub mod volume_curve;
+pub mod webrtc;
+mod webrtc_crypto;
+pub mod websocket;
+pub mod wol;
+
+pub use error::{ar_last_error_code, ar_last_error_message, last_error_message, rust_string_free, AudioRemoteError};
+use channel::channel_arg;
+use error::{clear_last_error, guard, set_last_error, string_result};
+use version::ParseMode;
+
+/// Progress callback shared by long-running calls: (done, total, ctx) -> keep going?
+/// `total` is 0 when the size isn't known; returning false cancels the operation.
+pub type ProgressCallback = extern "C" fn(done: u64, total: u64, ctx: *mut c_void) -> bool;
+
+/// Read a C string argument as UTF-8, describing why it can't be read
+unsafe fn str_arg<'a>(ptr: *const c_char, name: &str) -> Result<&'a str, AudioRemoteError> {
+    if ptr.is_null() {
+        return Err(AudioRemoteError::InvalidArgument(format!("{name} is null")));
+    }
+    CStr::from_ptr(ptr)
+        .to_str()
+        .map_err(|e| AudioRemoteError::InvalidArgument(format!("{name} is not valid UTF-8: {e}")))
+}
+
+/// Parse a C string as a semantic version, stripping a leading 'v' prefix
+unsafe fn version_arg(ptr: *const c_char, name: &str) -> Result<Version, AudioRemoteError> {
+    version_arg_with_mode(ptr, name, ParseMode::Strict)
+}
+
+unsafe fn version_arg_with_mode(ptr: *const c_char, name: &str, mode: ParseMode) -> Result<Version, AudioRemoteError> {
+    parse_version_arg(str_arg(ptr, name)?, name, mode)
+}
+
+fn parse_version_arg(s: &str, name: &str, mode: ParseMode) -> Result<Version, AudioRemoteError> {
+    version::parse(s, mode).map_err(|e| AudioRemoteError::InvalidVersion(format!("invalid {name} \"{s}\": {e}")))
+}
+
+fn mode_arg(mode: i32) -> Result<ParseMode, AudioRemoteError> {
+    ParseMode::from_raw(mode).ok_or(AudioRemoteError::InvalidArgument(format!("unknown parse mode {mode}")))
+}
+
+/// Store the error of a failed call so `ar_last_error_code()`/`ar_last_error_message()` can report it
+fn record<T>(result: Result<T, AudioRemoteError>) -> Option<T> {
+    match result {
+        Ok(value) => {
+            clear_last_error();
+            Some(value)
+        }
+        Err(error) => {
+            set_last_error(error);
+            None
+        }
+    }
+}
+
+fn ordering_code(ordering: std::cmp::Ordering) -> i32 {
+    match ordering {
+        std::cmp::Ordering::Less => -1,
+        std::cmp::Ordering::Equal => 0,
+        std::cmp::Ordering::Greater => 1,
+    }
+}
 
 /// Compare two semantic version strings
-/// Returns: -1 if v1 < v2, 0 if equal, 1 if v1 > v2, -999 on parse error
+/// Returns: -1 if v1 < v2, 0 if equal, 1 if v1 > v2, -999 on parse error (see last_error_message)
+// Safe since the first release; both pointers must be null or point to valid NUL-terminated strings
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[no_mangle]
 pub extern "C" fn version_compare(v1_ptr: *const c_char, v2_ptr: *const c_char) -> i32 {
-    unsafe {
-        // Validate pointers
-        if v1_ptr.is_null() || v2_ptr.is_null() {
+    guard("version_compare", -999, || {
+        // Parse as semantic versions
+        let parsed = unsafe { version_arg(v1_ptr, "v1").and_then(|v1| Ok((v1, version_arg(v2_ptr, "v2")?))) };
+        let Some((v1, v2)) = record(parsed) else {
             return -999;
-        }
-
-        // Convert C strings to Rust strings
-        let v1_str = match CStr::from_ptr(v1_ptr).to_str() {
-            Ok(s) => s,
-            Err(_) => return -999,
-        };
-        let v2_str = match CStr::from_ptr(v2_ptr).to_str() {
-            Ok(s) => s,
-            Err(_) => return -999,
         };
 
-        // Strip 'v' prefix if present
-        let v1_clean = v1_str.strip_prefix('v').unwrap_or(v1_str);
-        let v2_clean = v2_str.strip_prefix('v').unwrap_or(v2_str);
+        // Compare and return result
+        ordering_code(v1.cmp(&v2))
+    })
+}
 
-        // Parse as semantic versions
-        let v1 = match Version::parse(v1_clean) {
-            Ok(v) => v,
-            Err(_) => return -999,
+/// Compare two version strings, choosing strict (0) or lenient (1) parsing
+/// Returns: -1 if v1 < v2, 0 if equal, 1 if v1 > v2, -999 on parse error (see last_error_message)
+///
+/// # Safety
+/// Both pointers must be null or point to valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn version_compare_with_mode(v1_ptr: *const c_char, v2_ptr: *const c_char, mode: i32) -> i32 {
+    guard("version_compare_with_mode", -999, || {
+        let parsed = mode_arg(mode).and_then(|mode| {
+            Ok((version_arg_with_mode(v1_ptr, "v1", mode)?, version_arg_with_mode(v2_ptr, "v2", mode)?))
+        });
+        let Some((v1, v2)) = record(parsed) else {
+            return -999;
         };
-        let v2 = match Version::parse(v2_clean) {
-            Ok(v) => v,
-            Err(_) => return -999,
+
+        ordering_code(v1.cmp(&v2))
+    })
+}
+
+/// Normalize a version string into canonical semver using the given parse mode
+/// Returns: newly allocated string (free with rust_string_free), or NULL on error
+///
+/// # Safety
+/// `version_ptr` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn version_normalize(version_ptr: *const c_char, mode: i32) -> *mut c_char {
+    guard("version_normalize", std::ptr::null_mut(), || {
+        let parsed = mode_arg(mode).and_then(|mode| version_arg_with_mode(version_ptr, "version", mode));
+        string_result(parsed.map(|v| v.to_string()))
+    })
+}
+
+/// Compare marketing versions, falling back to CFBundleVersion build numbers when they are equal
+/// Returns: -1 if current < latest, 0 if equal, 1 if current > latest, -999 on parse error
+///
+/// Build metadata in the marketing versions is ignored. A NULL or empty build
+/// number on either side means the builds can't break the tie, so 0 is returned.
+///
+/// # Safety
+/// All pointers must be null (builds only) or point to valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn version_compare_with_build(
+    current_ptr: *const c_char,
+    current_build_ptr: *const c_char,
+    latest_ptr: *const c_char,
+    latest_build_ptr: *const c_char,
+) -> i32 {
+    guard("version_compare_with_build", -999, || {
+        let optional_build = |ptr: *const c_char, name: &str| -> Result<Option<&str>, AudioRemoteError> {
+            if ptr.is_null() {
+                return Ok(None);
+            }
+            let s = str_arg(ptr, name)?;
+            Ok(Some(s).filter(|s| !s.trim().is_empty()))
         };
 
-        // Compare and return result
-        match v1.cmp(&v2) {
-            std::cmp::Ordering::Less => -1,
-            std::cmp::Ordering::Equal => 0,
-            std::cmp::Ordering::Greater => 1,
+        let ordering = version_arg(current_ptr, "current").and_then(|current| {
+            let latest = version_arg(latest_ptr, "latest")?;
+            let current_build = optional_build(current_build_ptr, "current_build")?;
+            let latest_build = optional_build(latest_build_ptr, "latest_build")?;
+
+            match (current.cmp_precedence(&latest), current_build, latest_build) {
+                (std::cmp::Ordering::Equal, Some(a), Some(b)) => {
+                    version::compare_build_numbers(a, b).map_err(AudioRemoteError::InvalidVersion)
+                }
+                (ordering, _, _) => Ok(ordering),
+            }
+        });
+        match record(ordering) {
+            Some(ordering) => ordering_code(ordering),
+            None => -999,
         }
-    }
+    })
 }
 
 /// Check if update is available (latest > current)
 /// Returns: true if latest > current, false otherwise
+// Safe since the first release, like version_compare
 #[no_mangle]
 pub extern "C" fn version_has_update(current_ptr: *const c_char, latest_ptr: *const c_char) -> bool {
-    version_compare(current_ptr, latest_ptr) == 1
+    guard("version_has_update", false, || {
+        // latest first: version_compare(current, latest) == 1 would offer downgrades instead
+        version_compare(latest_ptr, current_ptr) == 1
+    })
+}
+
+/// Check whether a version satisfies a requirement such as ">=2.5.0, <3.0.0"
+/// Returns: 1 if it matches, 0 if not, -999 on parse error (see last_error_message)
+///
+/// # Safety
+/// Both pointers must be null or point to valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn version_satisfies(version_ptr: *const c_char, range_ptr: *const c_char) -> i32 {
+    guard("version_satisfies", -999, || {
+        let result = version_arg(version_ptr, "version").and_then(|version| satisfies(&version, str_arg(range_ptr, "range")?));
+        match record(result) {
+            Some(matches) => matches as i32,
+            None => -999,
+        }
+    })
+}
+
+fn satisfies(version: &Version, range: &str) -> Result<bool, AudioRemoteError> {
+    let req = VersionReq::parse(range).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid range \"{range}\": {e}")))?;
+    Ok(req.matches(version))
+}
+
+/// Update channels, ordered from most to least conservative
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum UpdateChannel {
+    Stable = 0,
+    Beta = 1,
+    Nightly = 2,
+}
+
+impl UpdateChannel {
+    pub fn from_raw(raw: i32) -> Option<Self> {
+        match raw {
+            0 => Some(Self::Stable),
+            1 => Some(Self::Beta),
+            2 => Some(Self::Nightly),
+            _ => None,
+        }
+    }
+
+    /// The least conservative channel a release belongs to.
+    /// "beta"/"rc" prereleases are beta builds, any other prerelease is a nightly.
+    pub fn of(version: &Version) -> Self {
+        if version.pre.is_empty() {
+            return Self::Stable;
+        }
+        let tag = version.pre.as_str().split('.').next().unwrap_or("").to_ascii_lowercase();
+        match tag.as_str() {
+            "beta" | "rc" => Self::Beta,
+            _ => Self::Nightly,
+        }
+    }
+}
+
+/// Check if an update is available on the given channel (0 = stable, 1 = beta, 2 = nightly)
+/// Returns: true if latest > current and latest is published on the channel
+///
+/// Stable users are never offered prereleases; beta users also get "beta"/"rc"
+/// prereleases, nightly users get everything. Prereleases sort by semver rules,
+/// so 2.7.0-beta.1 < 2.7.0-beta.2 < 2.7.0-rc.1 < 2.7.0.
+///
+/// # Safety
+/// Both pointers must be null or point to valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn version_has_update_on_channel(
+    current_ptr: *const c_char,
+    latest_ptr: *const c_char,
+    channel: i32,
+) -> bool {
+    guard("version_has_update_on_channel", false, || {
+        let parsed = version_arg(current_ptr, "current").and_then(|current| {
+            let latest = version_arg(latest_ptr, "latest")?;
+            let channel = channel_arg(channel)?;
+            Ok((current, latest, channel))
+        });
+        let Some((current, latest, channel)) = record(parsed) else {
+            return false;
+        };
+
+        has_update_on_channel(&current, &latest, channel)
+    })
+}
+
+fn has_update_on_channel(current: &Version, latest: &Version, channel: UpdateChannel) -> bool {
+    UpdateChannel::of(latest) <= channel && latest > current
+}
+
+/// Most significant version component that differs between two versions
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VersionDiffKind {
+    None = 0,
+    Major = 1,
+    Minor = 2,
+    Patch = 3,
+    Prerelease = 4,
+}
+
+impl VersionDiffKind {
+    /// Classify the change between two versions; build metadata is ignored
+    pub fn between(a: &Version, b: &Version) -> Self {
+        if a.major != b.major {
+            Self::Major
+        } else if a.minor != b.minor {
+            Self::Minor
+        } else if a.patch != b.patch {
+            Self::Patch
+        } else if a.pre != b.pre {
+            Self::Prerelease
+        } else {
+            Self::None
+        }
+    }
+}
+
+/// Classify which component changed between current and latest
+/// Returns: 0 = none, 1 = major, 2 = minor, 3 = patch, 4 = prerelease, -999 on parse error
+///
+/// # Safety
+/// Both pointers must be null or point to valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn version_diff_kind(current_ptr: *const c_char, latest_ptr: *const c_char) -> i32 {
+    guard("version_diff_kind", -999, || {
+        let parsed = version_arg(current_ptr, "current").and_then(|c| Ok((c, version_arg(latest_ptr, "latest")?)));
+        let Some((current, latest)) = record(parsed) else {
+            return -999;
+        };
+
+        VersionDiffKind::between(&current, &latest) as i32
+    })
+}
+
+/// Order version strings by semver precedence (not lexically), for the release picker
+/// Writes the indices of `versions` in ascending order to `out_indices` (room for `count`
+/// entries). Duplicates ("2.6" and "v2.6.0" in lenient mode) keep only their first index,
+/// and strings that don't parse in the given mode (0 = strict, 1 = lenient) are left out.
+/// Returns: number of indices written, or -999 on error (see last_error_message)
+///
+/// # Safety
+/// `versions` must point to `count` valid NUL-terminated strings and `out_indices`
+/// to writable storage for `count` values.
+#[no_mangle]
+pub unsafe extern "C" fn version_sort(
+    versions: *const *const c_char,
+    count: usize,
+    mode: i32,
+    out_indices: *mut usize,
+) -> i32 {
+    guard("version_sort", -999, || {
+        let result = mode_arg(mode).and_then(|mode| {
+            if count == 0 {
+                return Ok(Vec::new());
+            }
+            if versions.is_null() || out_indices.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("versions and out_indices must not be null".into()));
+            }
+            let strings = std::slice::from_raw_parts(versions, count)
+                .iter()
+                .enumerate()
+                .map(|(i, &ptr)| str_arg(ptr, &format!("versions[{i}]")))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(version::sort_indices(&strings, mode))
+        });
+        let Some(indices) = record(result) else {
+            return -999;
+        };
+
+        if !indices.is_empty() {
+            std::slice::from_raw_parts_mut(out_indices, indices.len()).copy_from_slice(&indices);
+        }
+        indices.len() as i32
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use error::tests::take_c_string;
     use std::ffi::CString;
 
+    fn compare(v1: &str, v2: &str) -> i32 {
+        let v1 = CString::new(v1).unwrap();
+        let v2 = CString::new(v2).unwrap();
+        version_compare(v1.as_ptr(), v2.as_ptr())
+    }
+
+    fn satisfies(version: &str, range: &str) -> i32 {
+        let version = CString::new(version).unwrap();
+        let range = CString::new(range).unwrap();
+        unsafe { version_satisfies(version.as_ptr(), range.as_ptr()) }
+    }
+
     #[test]
     fn test_version_compare() {
         let v1 = CString::new("2.6.0").unwrap();
@@ -103,4 +495,161 @@ mod tests {
         let latest = CString::new("2.6.0").unwrap();
         assert!(!version_has_update(current.as_ptr(), latest.as_ptr()));
     }
+
+    #[test]
+    fn test_version_has_update_compares_latest_against_current() {
+        // Offered only when latest is newer, never when the installed version is
+        for (current, latest, expected) in [("2.9.0", "2.10.0", true), ("v2.10.0", "2.9.0", false), ("3.0.0", "v2.99.0", false), ("2.6.0-beta.1", "2.6.0", true)] {
+            let (current, latest) = (CString::new(current).unwrap(), CString::new(latest).unwrap());
+            assert_eq!(version_has_update(current.as_ptr(), latest.as_ptr()), expected, "{current:?} -> {latest:?}");
+        }
+    }
+
+    fn has_update_on(current: &str, latest: &str, channel: UpdateChannel) -> bool {
+        let current = CString::new(current).unwrap();
+        let latest = CString::new(latest).unwrap();
+        unsafe { version_has_update_on_channel(current.as_ptr(), latest.as_ptr(), channel as i32) }
+    }
+
+    #[test]
+    fn test_version_has_update_on_channel() {
+        use UpdateChannel::*;
+
+        // Stable users never see prereleases
+        assert!(!has_update_on("2.6.0", "2.7.0-beta.1", Stable));
+        assert!(!has_update_on("2.6.0", "2.7.0-nightly.20240101", Stable));
+        assert!(has_update_on("2.6.0", "2.7.0", Stable));
+
+        // Beta users get beta/rc builds, ranked by semver
+        assert!(has_update_on("2.6.0", "2.7.0-beta.1", Beta));
+        assert!(has_update_on("2.7.0-beta.1", "2.7.0-beta.2", Beta));
+        assert!(has_update_on("2.7.0-beta.2", "2.7.0-rc.1", Beta));
+        assert!(has_update_on("2.7.0-rc.1", "2.7.0", Beta));
+        assert!(!has_update_on("2.7.0", "2.7.0-rc.1", Beta));
+        assert!(!has_update_on("2.6.0", "2.7.0-alpha.1", Beta));
+
+        // Nightly users get everything
+        assert!(has_update_on("2.6.0", "2.7.0-alpha.1", Nightly));
+        assert!(has_update_on("2.6.0", "2.7.0-beta.1", Nightly));
+
+        // Unknown channel is never offered anything
+        let current = CString::new("2.6.0").unwrap();
+        let latest = CString::new("2.7.0").unwrap();
+        assert!(!unsafe { version_has_update_on_channel(current.as_ptr(), latest.as_ptr(), 7) });
+    }
+
+    fn last_error() -> String {
+        take_c_string(last_error_message()).expect("an error was recorded")
+    }
+
+    #[test]
+    fn test_parse_error_messages() {
+        assert_eq!(compare("2.6", "2.5.0"), -999);
+        let message = last_error();
+        assert!(message.starts_with("invalid v1 \"2.6\""), "{message}");
+        assert_eq!(ar_last_error_code(), AudioRemoteError::InvalidVersion(String::new()).code());
+
+        assert_eq!(compare("2.6.0", "2.6.0 build 42"), -999);
+        let message = last_error();
+        assert!(message.starts_with("invalid v2 \"2.6.0 build 42\""), "{message}");
+
+        assert_eq!(version_compare(std::ptr::null(), std::ptr::null()), -999);
+        assert_eq!(ar_last_error_code(), AudioRemoteError::InvalidArgument(String::new()).code());
+        assert_eq!(last_error(), "v1 is null");
+
+        assert_eq!(satisfies("2.6.0", ">>2.5.0"), -999);
+        assert!(last_error().starts_with("invalid range"));
+
+        // A successful call clears the previous error
+        assert_eq!(compare("2.6.0", "2.5.0"), 1);
+        assert!(last_error_message().is_null());
+        assert_eq!(ar_last_error_code(), 0);
+    }
+
+    #[test]
+    fn test_version_diff_kind() {
+        let diff = |current: &str, latest: &str| {
+            let current = CString::new(current).unwrap();
+            let latest = CString::new(latest).unwrap();
+            unsafe { version_diff_kind(current.as_ptr(), latest.as_ptr()) }
+        };
+
+        assert_eq!(diff("2.6.0", "3.0.0"), VersionDiffKind::Major as i32);
+        assert_eq!(diff("2.6.0", "2.7.0"), VersionDiffKind::Minor as i32);
+        assert_eq!(diff("2.6.0", "2.6.1"), VersionDiffKind::Patch as i32);
+        assert_eq!(diff("2.7.0-beta.1", "2.7.0"), VersionDiffKind::Prerelease as i32);
+        assert_eq!(diff("v2.6.0", "2.6.0+142"), VersionDiffKind::None as i32);
+        assert_eq!(diff("2.6", "2.6.0"), -999);
+    }
+
+    #[test]
+    fn test_version_compare_with_mode() {
+        let compare_mode = |v1: &str, v2: &str, mode: ParseMode| {
+            let v1 = CString::new(v1).unwrap();
+            let v2 = CString::new(v2).unwrap();
+            unsafe { version_compare_with_mode(v1.as_ptr(), v2.as_ptr(), mode as i32) }
+        };
+
+        assert_eq!(compare_mode("2.6", "2.5.0", ParseMode::Strict), -999);
+        assert_eq!(compare_mode("2.6", "2.5.0", ParseMode::Lenient), 1);
+        assert_eq!(compare_mode("2.6.0.1", "2.6.0", ParseMode::Lenient), 1);
+        assert_eq!(compare_mode("v2.6.0 (142)", "2.6.0 (150)", ParseMode::Lenient), -1);
+        assert_eq!(compare_mode("2.6.0", "2.6", ParseMode::Lenient), 0);
+
+        let v = CString::new("v2.6 (142)").unwrap();
+        let normalized = unsafe { version_normalize(v.as_ptr(), ParseMode::Lenient as i32) };
+        assert_eq!(take_c_string(normalized).as_deref(), Some("2.6.0+142"));
+        assert!(unsafe { version_normalize(v.as_ptr(), ParseMode::Strict as i32) }.is_null());
+    }
+
+    #[test]
+    fn test_version_compare_with_build() {
+        let compare_build = |current: &str, current_build: Option<&str>, latest: &str, latest_build: Option<&str>| {
+            let c = CString::new(current).unwrap();
+            let l = CString::new(latest).unwrap();
+            let cb = current_build.map(|b| CString::new(b).unwrap());
+            let lb = latest_build.map(|b| CString::new(b).unwrap());
+            let ptr = |b: &Option<CString>| b.as_ref().map_or(std::ptr::null(), |b| b.as_ptr());
+            unsafe { version_compare_with_build(c.as_ptr(), ptr(&cb), l.as_ptr(), ptr(&lb)) }
+        };
+
+        assert_eq!(compare_build("2.6.0", Some("142"), "2.6.0", Some("150")), -1);
+        assert_eq!(compare_build("2.6.0", Some("150"), "2.6.0", Some("142")), 1);
+        assert_eq!(compare_build("2.6.0", Some("142"), "2.6.0", Some("142")), 0);
+
+        // Marketing version wins over build number
+        assert_eq!(compare_build("2.6.0", Some("900"), "2.7.0", Some("1")), -1);
+
+        // Missing builds can't break the tie
+        assert_eq!(compare_build("2.6.0", None, "2.6.0", Some("150")), 0);
+        assert_eq!(compare_build("2.6.0", Some(""), "2.6.0", Some("150")), 0);
+
+        assert_eq!(compare_build("2.6.0", Some("14x"), "2.6.0", Some("150")), -999);
+        assert_eq!(compare_build("2.6", Some("142"), "2.6.0", Some("150")), -999);
+    }
+
+    #[test]
+    fn test_version_satisfies() {
+        assert_eq!(satisfies("2.6.0", ">=2.5.0, <3.0.0"), 1);
+        assert_eq!(satisfies("v2.5.0", ">=2.5.0, <3.0.0"), 1);
+        assert_eq!(satisfies("3.0.0", ">=2.5.0, <3.0.0"), 0);
+        assert_eq!(satisfies("2.4.9", ">=2.5.0"), 0);
+        assert_eq!(satisfies("2.6.3", "~2.6"), 1);
+
+        // Invalid version or requirement
+        assert_eq!(satisfies("2.6", ">=2.5.0"), -999);
+        assert_eq!(satisfies("2.6.0", ">>2.5.0"), -999);
+    }
+
+    #[test]
+    fn test_version_sort() {
+        let owned: Vec<CString> = ["2.10.0", "2.9.0", "2.10.0"].iter().map(|s| CString::new(*s).unwrap()).collect();
+        let ptrs: Vec<*const c_char> = owned.iter().map(|s| s.as_ptr()).collect();
+        let mut out = [usize::MAX; 3];
+        assert_eq!(unsafe { version_sort(ptrs.as_ptr(), ptrs.len(), 0, out.as_mut_ptr()) }, 2);
+        assert_eq!(out[..2], [1, 0]);
+
+        assert_eq!(unsafe { version_sort(ptrs.as_ptr(), ptrs.len(), 7, out.as_mut_ptr()) }, -999);
+        assert_eq!(unsafe { version_sort(std::ptr::null(), 0, 0, std::ptr::null_mut()) }, 0);
+    }
 }
@@ -0,0 +1,213 @@
+//! Library lifecycle. `ar_init` applies the app's configuration (persistence
+//! directory, file logging, worker count) and starts the runtime exactly once;
+//! until then every entry point except a few listed in `error` fails with
+//! AR_ERROR_NOT_INITIALIZED. `ar_shutdown` undoes it, and a later `ar_init`
+//! starts over with a new configuration.
+
+use std::ffi::c_char;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::{browse, clock_sync, connection, devices, discovery, fade, grpc, guard, hap, hearing, ipc, log_file, logging, mdns, mqtt, noise, pairing, portmap, raop, record, relay, runtime, server, ssdp, store, str_arg, webrtc, AudioRemoteError};
+
+/// Configuration passed to ar_init; every field is optional
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Config {
+    /// Persisted state and caches (default: ~/Library/Application Support/AudioRemote)
+    pub store_directory: Option<PathBuf>,
+    pub log_file: Option<LogFileConfig>,
+    /// Background worker threads (default: one per core, up to 4)
+    pub workers: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct LogFileConfig {
+    /// Default: ~/Library/Logs/AudioRemote
+    pub directory: Option<PathBuf>,
+    /// An ArLogLevel
+    pub level: i32,
+    /// Rotation size; 0 or absent means 5 MB
+    #[serde(default)]
+    pub max_file_mb: u32,
+}
+
+/// Serializes init and shutdown; INITIALIZED is what every call checks
+static LIFECYCLE: Mutex<()> = Mutex::new(());
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn is_initialized() -> bool {
+    INITIALIZED.load(Ordering::Acquire) || skip_check_in_tests()
+}
+
+#[cfg(not(test))]
+fn skip_check_in_tests() -> bool {
+    false
+}
+
+/// Unit tests call entry points without the lifecycle, except the ones testing it
+#[cfg(test)]
+fn skip_check_in_tests() -> bool {
+    !tests::ENFORCE.with(std::cell::Cell::get)
+}
+
+pub fn parse_config(json: &str) -> Result<Config, AudioRemoteError> {
+    serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid config: {e}")))
+}
+
+/// Apply `config` and start the runtime. Returns false if already initialized, in
+/// which case `config` is ignored.
+pub fn init(config: &Config) -> Result<bool, AudioRemoteError> {
+    let _lock = LIFECYCLE.lock().unwrap_or_else(|e| e.into_inner());
+    if INITIALIZED.load(Ordering::Acquire) {
+        return Ok(false);
+    }
+    if let Some(dir) = &config.store_directory {
+        store::set_directory(dir);
+    }
+    if let Some(file) = &config.log_file {
+        log_file::enable_with_defaults(file.directory.as_deref(), logging::level_arg(file.level)?, file.max_file_mb)?;
+    }
+    if let Err(e) = runtime::start(config.workers) {
+        log_file::disable();
+        return Err(e);
+    }
+    INITIALIZED.store(true, Ordering::Release);
+    log::info!("initialized audioremote-ffi {}", env!("CARGO_PKG_VERSION"));
+    Ok(true)
+}
+
+/// Stop everything the library started, remote-facing services first and the log file last
+pub fn shutdown() {
+    let _lock = LIFECYCLE.lock().unwrap_or_else(|e| e.into_inner());
+    if !INITIALIZED.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    log::info!("shutting down");
+    // No new remotes while the rest goes
+    pairing::cancel();
+    // Outgoing connections to the MQTT broker and the relay
+    mqtt::stop();
+    relay::stop();
+    // The HomeKit accessory, the gRPC service and the local control socket
+    hap::stop();
+    grpc::stop();
+    ipc::stop();
+    // The SSDP responder, the Noise listener and the WebRTC peers
+    ssdp::stop();
+    noise::stop();
+    webrtc::stop();
+    // Delete the router's port mapping
+    portmap::stop();
+    // AirPlay sessions, clock sync clients and server, fades and remote connections
+    raop::close_all();
+    clock_sync::close_all();
+    clock_sync::stop();
+    fade::close_all();
+    connection::close_all();
+    // Stop browsing for peers and answering discovery probes, then withdraw the Bonjour service
+    browse::stop();
+    discovery::stop();
+    mdns::stop();
+    // Close every HTTP connection; requests still waiting on Swift fail
+    server::stop();
+    // Every pending job completes as cancelled
+    runtime::stop();
+    // Listening totals and device volumes, once nothing adds to them
+    hearing::save();
+    devices::save();
+    log_file::disable();
+}
+
+/// Initialize the library; call once at launch, before anything but the ABI check and logging setup
+/// `config_json` (nullable) is a JSON object, all keys optional:
+/// {"storeDirectory": path, "logFile": {"directory": path, "level": ArLogLevel, "maxFileMb": n}, "workers": n}
+/// Thread-safe and idempotent: later calls succeed without applying their config until ar_shutdown.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_ptr` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_init(config_ptr: *const c_char) -> i32 {
+    guard("ar_init", -999, || {
+        let config = match config_ptr.is_null() {
+            true => Ok(Config::default()),
+            false => str_arg(config_ptr, "config").and_then(parse_config),
+        };
+        match record(config.and_then(|config| init(&config))) {
+            Some(_) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Stop advertising and serving, cancel all jobs, stop the runtime and close the log file; a no-op if not initialized.
+/// Afterwards calls fail with AR_ERROR_NOT_INITIALIZED until the next ar_init.
+/// Don't call from a serial callback queue: the remaining job completions are delivered there.
+#[no_mangle]
+pub extern "C" fn ar_shutdown() {
+    guard("ar_shutdown", (), shutdown)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::delta::tests::temp_dir;
+    use crate::runtime::tests::RUNTIME_LOCK;
+    use crate::store::tests::with_temp_store;
+    use std::cell::Cell;
+    use std::ffi::CString;
+
+    thread_local! {
+        /// Set by tests that need calls to be refused before ar_init
+        pub(crate) static ENFORCE: Cell<bool> = const { Cell::new(false) };
+    }
+
+    #[test]
+    fn test_config_parsing() {
+        let config = parse_config(r#"{"storeDirectory": "/tmp/ar", "logFile": {"level": 3}, "workers": 2}"#).unwrap();
+        assert_eq!(config.store_directory, Some(PathBuf::from("/tmp/ar")));
+        assert_eq!(config.log_file.unwrap().max_file_mb, 0);
+        assert!(parse_config("{}").unwrap().log_file.is_none());
+        assert!(matches!(parse_config(r#"{"storDirectory": "/tmp"}"#), Err(AudioRemoteError::InvalidArgument(m)) if m.contains("unknown field")));
+    }
+
+    #[test]
+    fn test_calls_refused_until_init() {
+        let _runtime = RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_store, dir) = with_temp_store("lifecycle");
+        let logs = temp_dir("lifecycle-logs");
+        ENFORCE.with(|e| e.set(true));
+        let (a, b) = (CString::new("1.0.0").unwrap(), CString::new("2.0.0").unwrap());
+
+        assert_eq!(crate::version_compare(a.as_ptr(), b.as_ptr()), -999);
+        assert_eq!(crate::ar_last_error_code(), 13);
+        assert_eq!(crate::abi::ar_ffi_check_abi(crate::abi::ABI_VERSION), 1);
+
+        let config = serde_json::json!({
+            "storeDirectory": dir,
+            "logFile": {"directory": logs, "level": 3},
+            "workers": 1,
+        });
+        let config = CString::new(config.to_string()).unwrap();
+        assert_eq!(unsafe { ar_init(config.as_ptr()) }, 1);
+        assert_eq!(unsafe { ar_init(std::ptr::null()) }, 1);
+        assert_eq!(crate::version_compare(a.as_ptr(), b.as_ptr()), -1);
+        assert_eq!(store::directory(), dir);
+        assert!(logs.join("AudioRemote.log").exists());
+
+        ar_shutdown();
+        ar_shutdown();
+        assert_eq!(crate::version_compare(a.as_ptr(), b.as_ptr()), -999);
+        assert_eq!(crate::ar_last_error_code(), 13);
+        let bad = CString::new(r#"{"workers": "many"}"#).unwrap();
+        assert_eq!(unsafe { ar_init(bad.as_ptr()) }, -999);
+        assert_eq!(crate::ar_last_error_code(), 1);
+        ENFORCE.with(|e| e.set(false));
+        let _ = std::fs::remove_dir_all(&logs);
+    }
+}
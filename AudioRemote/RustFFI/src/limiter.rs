@@ -0,0 +1,483 @@
+//! A lookahead limiter for when a remote boosts above 0 dBFS, so louder never
+//! means distorted. The audio is boosted by `gainDb`, then delayed by the
+//! lookahead while the gain each frame needs to stay under the threshold is
+//! worked out; that gain is held at its minimum over the lookahead and
+//! averaged over it, which ramps down smoothly and reaches the needed gain
+//! exactly as the peak leaves the delay. Recovery follows `releaseMs`.
+//!
+//! Changes to the boost are smoothed over a few milliseconds so a remote's
+//! volume steps don't click. The gain reduction is metered as the gain applied
+//! now and the lowest since the last read, both in dB (0 when not limiting).
+//!
+//! With `truePeak` the needed gain comes from the true peak (see `true_peak`)
+//! instead of the sample peak, so what a DAC or a lossy encoder reconstructs
+//! between samples stays under the threshold too. The oversampler looks a few
+//! frames back, and the audio is delayed that much more to line up with it.
+
+use std::collections::VecDeque;
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::handle::{Handle, Registry};
+use crate::simd;
+use crate::true_peak::{self, TruePeak};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+/// How long a change to the boost takes to settle (the time constant)
+const BOOST_SMOOTHING_MS: f64 = 10.0;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct LimiterConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// The boost in front of the limiter
+    #[serde(default)]
+    pub gain_db: f64,
+    #[serde(default = "default_threshold_db")]
+    pub threshold_db: f64,
+    #[serde(default = "default_release_ms")]
+    pub release_ms: f64,
+    #[serde(default = "default_lookahead_ms")]
+    pub lookahead_ms: f64,
+    /// Limit the 4× oversampled true peak rather than the sample peak
+    #[serde(default)]
+    pub true_peak: bool,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> f64 {
+    48_000.0
+}
+
+/// A little under full scale, for the inter-sample peaks of whatever decodes it next
+fn default_threshold_db() -> f64 {
+    -1.0
+}
+
+fn default_release_ms() -> f64 {
+    100.0
+}
+
+fn default_lookahead_ms() -> f64 {
+    5.0
+}
+
+impl Default for LimiterConfig {
+    fn default() -> Self {
+        Self {
+            channels: default_channels(),
+            sample_rate: default_sample_rate(),
+            gain_db: 0.0,
+            threshold_db: default_threshold_db(),
+            release_ms: default_release_ms(),
+            lookahead_ms: default_lookahead_ms(),
+            true_peak: false,
+        }
+    }
+}
+
+impl LimiterConfig {
+    fn validate(&self) -> Result<(), AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=MAX_CHANNELS).contains(&self.channels) {
+            return invalid(format!("channels {} is outside 1–{MAX_CHANNELS}", self.channels));
+        }
+        if !(8000.0..=768_000.0).contains(&self.sample_rate) {
+            return invalid(format!("sampleRate {} is outside 8000–768000", self.sample_rate));
+        }
+        if !(-60.0..=24.0).contains(&self.gain_db) {
+            return invalid(format!("gainDb {} is outside -60–24", self.gain_db));
+        }
+        if !(-30.0..=0.0).contains(&self.threshold_db) {
+            return invalid(format!("thresholdDb {} is outside -30–0", self.threshold_db));
+        }
+        if !(1.0..=5000.0).contains(&self.release_ms) {
+            return invalid(format!("releaseMs {} is outside 1–5000", self.release_ms));
+        }
+        if !(0.1..=50.0).contains(&self.lookahead_ms) {
+            return invalid(format!("lookaheadMs {} is outside 0.1–50", self.lookahead_ms));
+        }
+        Ok(())
+    }
+
+    /// The lookahead in frames
+    fn lookahead(&self) -> usize {
+        ((self.lookahead_ms * self.sample_rate / 1000.0).round() as usize).max(1)
+    }
+
+    /// Frames the audio waits for the peak detector
+    fn detector_latency(&self) -> usize {
+        if self.true_peak { true_peak::LATENCY } else { 0 }
+    }
+}
+
+fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f64) -> f64 {
+    20.0 * gain.log10()
+}
+
+pub struct Limiter {
+    config: LimiterConfig,
+    threshold: f64,
+    release: f64,
+    boost_smoothing: f64,
+    boost: f64,
+    boost_target: f64,
+    /// Frames not yet output, as boosted, in a ring of `latency` + 1 frames
+    delay: Vec<f32>,
+    /// Where in the ring this frame goes
+    slot: usize,
+    /// (frame number, needed gain), ascending in gain: the minimum over the lookahead is first
+    minimum: VecDeque<(u64, f64)>,
+    /// The last lookahead's held minimums and their sum
+    held: VecDeque<f64>,
+    held_sum: f64,
+    frame: u64,
+    gain: f64,
+    lowest_gain: f64,
+    /// With truePeak, and the boosted frame it's fed
+    detector: Option<TruePeak>,
+    boosted: Vec<f64>,
+}
+
+impl Limiter {
+    pub fn new(config: LimiterConfig) -> Result<Self, AudioRemoteError> {
+        config.validate()?;
+        let rate = config.sample_rate;
+        let lookahead = config.lookahead();
+        Ok(Self {
+            threshold: db_to_gain(config.threshold_db),
+            release: (-1000.0 / (config.release_ms * rate)).exp(),
+            boost_smoothing: (-1000.0 / (BOOST_SMOOTHING_MS * rate)).exp(),
+            boost: db_to_gain(config.gain_db),
+            boost_target: db_to_gain(config.gain_db),
+            delay: vec![0.0; (lookahead + config.detector_latency()) * config.channels as usize],
+            slot: 0,
+            minimum: VecDeque::new(),
+            held: VecDeque::from(vec![1.0; lookahead]),
+            held_sum: lookahead as f64,
+            frame: 0,
+            gain: 1.0,
+            lowest_gain: 1.0,
+            detector: config.true_peak.then(|| TruePeak::new(config.channels as usize)),
+            boosted: vec![0.0; config.channels as usize],
+            config,
+        })
+    }
+
+    /// Take new settings; the audio in flight is kept unless the channels, sample rate,
+    /// lookahead or peak detection change
+    pub fn update(&mut self, config: LimiterConfig) -> Result<(), AudioRemoteError> {
+        config.validate()?;
+        let same = (config.channels, config.sample_rate, config.lookahead(), config.true_peak)
+            == (self.config.channels, self.config.sample_rate, self.config.lookahead(), self.config.true_peak);
+        if !same {
+            *self = Self::new(config)?;
+            return Ok(());
+        }
+        self.threshold = db_to_gain(config.threshold_db);
+        self.release = (-1000.0 / (config.release_ms * config.sample_rate)).exp();
+        self.boost_target = db_to_gain(config.gain_db);
+        self.config = config;
+        Ok(())
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.config.channels as usize
+    }
+
+    /// Output lags input by this many frames
+    pub fn latency(&self) -> usize {
+        self.config.lookahead() - 1 + self.config.detector_latency()
+    }
+
+    /// Boost and limit interleaved samples in place; a partial frame at the end is left alone and
+    /// samples that aren't finite count as silence
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let channels = self.channel_count();
+        let lookahead = self.held.len() as u64;
+        let slots = self.delay.len() / channels;
+        for frame in samples.chunks_exact_mut(channels) {
+            self.boost = self.boost_target + (self.boost - self.boost_target) * self.boost_smoothing;
+            let boosted = &mut self.delay[self.slot * channels..][..channels];
+            let sample_peak = simd::scale_finite_peak(frame, self.boost as f32, boosted);
+            let peak = match &mut self.detector {
+                Some(detector) => {
+                    for (wide, &sample) in self.boosted.iter_mut().zip(boosted.iter()) {
+                        *wide = f64::from(sample);
+                    }
+                    detector.push(&self.boosted)
+                }
+                None => f64::from(sample_peak),
+            };
+            let needed = if peak > self.threshold { self.threshold / peak } else { 1.0 };
+
+            // The minimum needed over the lookahead, then its average over the lookahead
+            while self.minimum.back().is_some_and(|&(_, gain)| gain >= needed) {
+                self.minimum.pop_back();
+            }
+            self.minimum.push_back((self.frame, needed));
+            while self.minimum.front().is_some_and(|&(frame, _)| frame + lookahead <= self.frame) {
+                self.minimum.pop_front();
+            }
+            let held = self.minimum.front().map_or(1.0, |&(_, gain)| gain);
+            self.held_sum += held - self.held.pop_front().unwrap_or(1.0);
+            self.held.push_back(held);
+            let target = (self.held_sum / lookahead as f64).min(1.0);
+            self.frame += 1;
+
+            self.gain = match target < self.gain {
+                true => target,
+                false => target + (self.gain - target) * self.release,
+            };
+            self.lowest_gain = self.lowest_gain.min(self.gain);
+            // The oldest frame, `latency` behind; the average can land a rounding error over the threshold
+            self.slot = (self.slot + 1) % slots;
+            simd::scale_clamp(&self.delay[self.slot * channels..][..channels], self.gain as f32, self.threshold as f32, frame);
+        }
+    }
+
+    /// The gain applied now and the lowest since the last call, in dB; 0 when not limiting
+    pub fn take_gain_reduction(&mut self) -> (f64, f64) {
+        let reduction = (gain_to_db(self.gain), gain_to_db(self.lowest_gain));
+        self.lowest_gain = self.gain;
+        reduction
+    }
+
+    pub fn reset(&mut self) {
+        if let Ok(fresh) = Self::new(self.config.clone()) {
+            *self = fresh;
+        }
+    }
+}
+
+static LIMITERS: Registry<Mutex<Limiter>> = Registry::new("limiter");
+
+fn with_limiter<T>(handle: Handle, body: impl FnOnce(&mut Limiter) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let limiter = LIMITERS.get(handle)?;
+    let mut limiter = limiter.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut limiter)
+}
+
+unsafe fn config_arg(config_json: *const c_char) -> Result<LimiterConfig, AudioRemoteError> {
+    match config_json.is_null() {
+        true => Ok(LimiterConfig::default()),
+        false => serde_json::from_str(str_arg(config_json, "config")?)
+            .map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid limiter config: {e}"))),
+    }
+}
+
+/// Create a limiter. `config_json` (nullable): {"channels" (default 2), "sampleRate" (default
+/// 48000), "gainDb" (boost, default 0), "thresholdDb" (default -1), "releaseMs" (default 100),
+/// "lookaheadMs" (default 5), "truePeak" (limit the 4× oversampled peak, for inter-sample peaks;
+/// adds 6 frames of latency; default false)}
+/// Returns: a handle (free with ar_limiter_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_limiter_new(config_json: *const c_char) -> Handle {
+    guard("ar_limiter_new", 0, || match record(config_arg(config_json).and_then(Limiter::new)) {
+        Some(limiter) => LIMITERS.insert(Mutex::new(limiter)),
+        None => 0,
+    })
+}
+
+/// Change a limiter's settings, as for ar_limiter_new, e.g. when a remote changes the boost
+/// Returns: 1 on success, -999 on error (see last_error_message), leaving the old settings in place
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_limiter_update(limiter: Handle, config_json: *const c_char) -> i32 {
+    guard("ar_limiter_update", -999, || {
+        let result = config_arg(config_json).and_then(|config| with_limiter(limiter, |limiter| limiter.update(config)));
+        record(result).map_or(-999, |_| 1)
+    })
+}
+
+/// Boost and limit `frames` frames of interleaved float samples in place
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels writable floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_limiter_process(limiter: Handle, samples: *mut f32, frames: usize) -> i32 {
+    guard("ar_limiter_process", -999, || {
+        let result = with_limiter(limiter, |limiter| {
+            let len = frames
+                .checked_mul(limiter.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len == 0 {
+                return Ok(1);
+            }
+            if samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            limiter.process(std::slice::from_raw_parts_mut(samples, len));
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Read the gain reduction: the gain applied now and the lowest since the last read, in dB (0 when
+/// not limiting, negative when limiting). Either out pointer may be NULL.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// Each out pointer must be null or point to a writable double.
+#[no_mangle]
+pub unsafe extern "C" fn ar_limiter_gain_reduction(limiter: Handle, out_current_db: *mut f64, out_lowest_db: *mut f64) -> i32 {
+    guard("ar_limiter_gain_reduction", -999, || {
+        let result = with_limiter(limiter, |limiter| {
+            let (current, lowest) = limiter.take_gain_reduction();
+            for (out, value) in [(out_current_db, current), (out_lowest_db, lowest)] {
+                if !out.is_null() {
+                    *out = value;
+                }
+            }
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Returns: how many frames output lags input, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_limiter_latency(limiter: Handle) -> i64 {
+    guard("ar_limiter_latency", -999, || record(with_limiter(limiter, |limiter| Ok(limiter.latency() as i64))).unwrap_or(-999))
+}
+
+/// Drop the audio in flight and the gain reduction, e.g. when the tap restarts
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_limiter_reset(limiter: Handle) -> i32 {
+    guard("ar_limiter_reset", -999, || {
+        record(with_limiter(limiter, |limiter| {
+            limiter.reset();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Release a limiter from ar_limiter_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_limiter_free(limiter: Handle) -> i32 {
+    guard("ar_limiter_free", -999, || record(LIMITERS.remove(limiter)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 48_000.0;
+
+    fn sine(frames: usize, amplitude: f32) -> Vec<f32> {
+        (0..frames).map(|i| amplitude * (2.0 * std::f64::consts::PI * 440.0 * i as f64 / RATE).sin() as f32).collect()
+    }
+
+    #[test]
+    fn test_limiting() {
+        // A 12 dB boost of a full-scale tone never goes over the threshold, and settles at it
+        let config = LimiterConfig { channels: 1, gain_db: 12.0, ..LimiterConfig::default() };
+        let mut limiter = Limiter::new(config).unwrap();
+        let threshold = db_to_gain(-1.0) as f32;
+        let mut samples = sine(48_000, 1.0);
+        limiter.process(&mut samples);
+        assert!(samples.iter().all(|sample| sample.abs() <= threshold), "{}", samples.iter().fold(0.0f32, |a, b| a.max(b.abs())));
+        let settled = samples[40_000..].iter().fold(0.0f32, |a, b| a.max(b.abs()));
+        assert!(settled > threshold * 0.98, "{settled}");
+        let (current, lowest) = limiter.take_gain_reduction();
+        assert!((current + 13.0).abs() < 0.5 && lowest <= current, "{current} {lowest}");
+
+        // A sudden peak is caught by the lookahead, not clipped
+        let mut limiter = Limiter::new(LimiterConfig { channels: 1, ..LimiterConfig::default() }).unwrap();
+        let latency = limiter.latency();
+        let mut impulse = vec![0.0f32; 1000];
+        impulse[500] = 4.0;
+        impulse[499] = 0.5;
+        limiter.process(&mut impulse);
+        assert!((impulse[500 + latency] - threshold).abs() < 1e-4, "{}", impulse[500 + latency]);
+        assert!(impulse[499 + latency] > 0.0 && impulse[499 + latency] < 0.5);
+
+        // Quiet audio passes through untouched, just delayed
+        let mut limiter = Limiter::new(LimiterConfig { channels: 1, ..LimiterConfig::default() }).unwrap();
+        let mut quiet = sine(48_000, 0.5);
+        let original = quiet.clone();
+        limiter.process(&mut quiet);
+        assert_eq!(quiet[20_000 + latency], original[20_000]);
+        assert_eq!(limiter.take_gain_reduction().0, 0.0);
+    }
+
+    #[test]
+    fn test_true_peak() {
+        // A tone at a quarter of the rate, sampled off its crests, peaks 3 dB over its samples
+        let tone: Vec<f32> =
+            (0..48_000).map(|i| (1.2 * (std::f64::consts::FRAC_PI_2 * i as f64 + std::f64::consts::FRAC_PI_4).sin()) as f32).collect();
+        let true_peak_of = |samples: &[f32]| {
+            let mut meter = TruePeak::new(1);
+            meter.process(samples);
+            meter.peak_db()
+        };
+        let mut sample_peak = tone.clone();
+        Limiter::new(LimiterConfig { channels: 1, ..LimiterConfig::default() }).unwrap().process(&mut sample_peak);
+        assert!(true_peak_of(&sample_peak) > 1.0, "{}", true_peak_of(&sample_peak));
+
+        let mut limiter = Limiter::new(LimiterConfig { channels: 1, true_peak: true, ..LimiterConfig::default() }).unwrap();
+        assert_eq!(limiter.latency(), 239 + true_peak::LATENCY);
+        let mut limited = tone.clone();
+        limiter.process(&mut limited);
+        assert!((true_peak_of(&limited) + 1.0).abs() < 0.1, "{}", true_peak_of(&limited));
+    }
+
+    #[test]
+    fn test_updates() {
+        let mut limiter = Limiter::new(LimiterConfig { channels: 2, ..LimiterConfig::default() }).unwrap();
+        let mut samples: Vec<f32> = sine(4800, 0.25).into_iter().flat_map(|sample| [sample, -sample]).collect();
+        limiter.process(&mut samples);
+        limiter.update(LimiterConfig { channels: 2, gain_db: 6.0, ..LimiterConfig::default() }).unwrap();
+        // The boost ramps in rather than jumping
+        let mut next: Vec<f32> = vec![0.25; 2 * 4800];
+        limiter.process(&mut next);
+        let latency = limiter.latency();
+        assert!(next[2 * (latency + 1)] < 0.3 && (next[2 * 4000] - 0.25 * db_to_gain(6.0) as f32).abs() < 1e-3);
+
+        // A new lookahead starts afresh
+        limiter.update(LimiterConfig { channels: 2, lookahead_ms: 1.0, ..LimiterConfig::default() }).unwrap();
+        assert_eq!(limiter.latency(), 47);
+        assert!(limiter.update(LimiterConfig { threshold_db: 3.0, ..LimiterConfig::default() }).is_err());
+        assert!(Limiter::new(LimiterConfig { release_ms: 0.0, ..LimiterConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let limiter = unsafe { ar_limiter_new(c"{\"channels\": 1, \"gainDb\": 6}".as_ptr()) };
+        assert_ne!(limiter, 0);
+        let mut samples = sine(4800, 1.0);
+        assert_eq!(unsafe { ar_limiter_process(limiter, samples.as_mut_ptr(), samples.len()) }, 1);
+        let (mut current, mut lowest) = (0.0, 0.0);
+        assert_eq!(unsafe { ar_limiter_gain_reduction(limiter, &mut current, &mut lowest) }, 1);
+        assert!(current < -5.0 && lowest <= current);
+        assert_eq!(ar_limiter_latency(limiter), 239);
+        assert_eq!(unsafe { ar_limiter_update(limiter, c"{\"channels\": 1, \"gainDb\": 99}".as_ptr()) }, -999);
+        assert_eq!(unsafe { ar_limiter_process(limiter, std::ptr::null_mut(), 1) }, -999);
+        assert_eq!(ar_limiter_reset(limiter), 1);
+        assert_eq!(ar_limiter_free(limiter), 1);
+        assert_eq!(ar_limiter_free(limiter), -999);
+    }
+}
@@ -0,0 +1,280 @@
+//! Opt-in file logging for diagnostics. Lines go to `AudioRemote.log` in the log
+//! directory (normally ~/Library/Logs/AudioRemote); when it reaches the size limit
+//! it is renamed with a timestamp and a fresh file started. Rotated files older
+//! than `MAX_AGE` are deleted, so the directory never grows without bound.
+
+use std::ffi::c_char;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::logging::{self, LogLevel};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const FILE_NAME: &str = "AudioRemote.log";
+const ROTATED_PREFIX: &str = "AudioRemote-";
+pub const DEFAULT_MAX_BYTES: u64 = 5 * 1024 * 1024;
+pub const MAX_AGE: Duration = Duration::from_secs(14 * 24 * 3600);
+
+pub struct FileLogger {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_age: Duration,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+fn open_current(dir: &Path) -> Result<(BufWriter<File>, u64), AudioRemoteError> {
+    let path = dir.join(FILE_NAME);
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| AudioRemoteError::Io(format!("can't open {}: {e}", path.display())))?;
+    let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+    Ok((BufWriter::new(file), size))
+}
+
+/// (year, month, day) of the day `days` after 1970-01-01, without pulling in a date crate
+pub(crate) fn civil_date(days: i64) -> (i64, i64, i64) {
+    // Civil-from-days, as in Howard Hinnant's date algorithms
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// "2026-10-14T09:30:00.250Z"
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (year, month, day) = civil_date((secs / 86_400) as i64);
+    let rem = secs % 86_400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+fn level_name(level: LogLevel) -> &'static str {
+    match level {
+        LogLevel::Off => "OFF",
+        LogLevel::Error => "ERROR",
+        LogLevel::Warn => "WARN",
+        LogLevel::Info => "INFO",
+        LogLevel::Debug => "DEBUG",
+        LogLevel::Trace => "TRACE",
+    }
+}
+
+impl FileLogger {
+    pub fn open(dir: &Path, max_bytes: u64, max_age: Duration) -> Result<Self, AudioRemoteError> {
+        fs::create_dir_all(dir).map_err(|e| AudioRemoteError::Io(format!("can't create {}: {e}", dir.display())))?;
+        let (file, size) = open_current(dir)?;
+        let logger = Self { dir: dir.to_path_buf(), max_bytes, max_age, file, size };
+        logger.prune(SystemTime::now());
+        Ok(logger)
+    }
+
+    pub fn write_line(&mut self, level: LogLevel, module: &str, message: &str) -> Result<(), AudioRemoteError> {
+        let line = format!("{} {:<5} {module}: {message}\n", format_timestamp(SystemTime::now()), level_name(level));
+        if self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        // Errors are what diagnostics are for; don't leave them in the buffer
+        if level == LogLevel::Error {
+            self.file.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), AudioRemoteError> {
+        Ok(self.file.flush()?)
+    }
+
+    fn rotate(&mut self) -> Result<(), AudioRemoteError> {
+        self.file.flush()?;
+        let now = SystemTime::now();
+        let mut millis = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let rotated = loop {
+            let candidate = self.dir.join(format!("{ROTATED_PREFIX}{millis}.log"));
+            if !candidate.exists() {
+                break candidate;
+            }
+            millis += 1;
+        };
+        let current = self.dir.join(FILE_NAME);
+        fs::rename(&current, &rotated).map_err(|e| AudioRemoteError::Io(format!("can't rotate {}: {e}", current.display())))?;
+        (self.file, self.size) = open_current(&self.dir)?;
+        self.prune(now);
+        Ok(())
+    }
+
+    /// Delete rotated files last written more than `max_age` before `now`
+    fn prune(&self, now: SystemTime) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if !(name.starts_with(ROTATED_PREFIX) && name.ends_with(".log")) {
+                continue;
+            }
+            let modified = entry.metadata().and_then(|m| m.modified());
+            if modified.is_ok_and(|m| now.duration_since(m).unwrap_or_default() > self.max_age) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+static FILE: Mutex<Option<FileLogger>> = Mutex::new(None);
+
+/// Append a record to the log file, if one is open. Failures are dropped: there
+/// is nowhere left to report them.
+pub(crate) fn write(level: LogLevel, module: &str, message: &str) {
+    if let Some(logger) = FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        let _ = logger.write_line(level, module, message);
+    }
+}
+
+pub(crate) fn flush() -> Result<(), AudioRemoteError> {
+    match FILE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        Some(logger) => logger.flush(),
+        None => Ok(()),
+    }
+}
+
+/// Start writing logs at `level` and below to `dir`, replacing any earlier file logger
+pub fn enable(dir: &Path, level: LogLevel, max_bytes: u64) -> Result<(), AudioRemoteError> {
+    let logger = match level {
+        LogLevel::Off => None,
+        _ => Some(FileLogger::open(dir, max_bytes, MAX_AGE)?),
+    };
+    if let Some(mut previous) = std::mem::replace(&mut *FILE.lock().unwrap_or_else(|e| e.into_inner()), logger) {
+        let _ = previous.flush();
+    }
+    logging::update_filters(|filters| filters.file = level);
+    Ok(())
+}
+
+fn default_dir() -> Result<PathBuf, AudioRemoteError> {
+    let home = std::env::var_os("HOME").ok_or(AudioRemoteError::NotFound("HOME is not set".into()))?;
+    Ok(PathBuf::from(home).join("Library/Logs/AudioRemote"))
+}
+
+/// `enable` with the C defaults: no directory means ~/Library/Logs/AudioRemote, 0 MB means 5 MB
+pub(crate) fn enable_with_defaults(dir: Option<&Path>, level: LogLevel, max_file_mb: u32) -> Result<(), AudioRemoteError> {
+    let dir = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => default_dir()?,
+    };
+    let max_bytes = match max_file_mb {
+        0 => DEFAULT_MAX_BYTES,
+        mb => u64::from(mb) * 1024 * 1024,
+    };
+    enable(&dir, level, max_bytes)
+}
+
+/// Flush and close the log file
+pub fn disable() {
+    if let Some(mut previous) = FILE.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        let _ = previous.flush();
+    }
+    logging::update_filters(|filters| filters.file = LogLevel::Off);
+}
+
+/// Also write logs at `level` and below to a rotating file; AR_LOG_OFF closes it
+/// `directory` NULL means ~/Library/Logs/AudioRemote. The file is rotated when it
+/// reaches `max_file_mb` (0 = 5 MB) and rotated files older than 14 days are deleted.
+/// Per-module overrides from ar_log_set_module_level apply here too.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `directory_ptr` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_log_enable_file(directory_ptr: *const c_char, level: i32, max_file_mb: u32) -> i32 {
+    guard("ar_log_enable_file", -999, || {
+        let result = logging::level_arg(level).and_then(|level| {
+            let dir = match directory_ptr.is_null() {
+                true => None,
+                false => Some(Path::new(str_arg(directory_ptr, "directory")?)),
+            };
+            enable_with_defaults(dir, level, max_file_mb)
+        });
+        match record(result) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Write buffered log lines to disk, e.g. before exporting diagnostics
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_log_flush() -> i32 {
+    guard("ar_log_flush", -999, || {
+        match record(flush()) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::tests::temp_dir;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_210_096_789);
+        assert_eq!(format_timestamp(time), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn test_rotates_and_prunes() {
+        let dir = temp_dir("log-file");
+        fs::create_dir_all(&dir).unwrap();
+        let stale = dir.join("AudioRemote-1000.log");
+        let recent = dir.join("AudioRemote-2000.log");
+        for path in [&stale, &recent] {
+            fs::write(path, "old\n").unwrap();
+        }
+        let month_ago = SystemTime::now() - Duration::from_secs(30 * 24 * 3600);
+        File::options().write(true).open(&stale).unwrap().set_modified(month_ago).unwrap();
+
+        let mut logger = FileLogger::open(&dir, 200, MAX_AGE).unwrap();
+        assert!(!stale.exists());
+        assert!(recent.exists());
+
+        for i in 0..10 {
+            logger.write_line(LogLevel::Info, "feed", &format!("line {i} {}", "x".repeat(40))).unwrap();
+        }
+        logger.flush().unwrap();
+
+        let mut files: Vec<_> = fs::read_dir(&dir).unwrap().map(|e| e.unwrap().path()).collect();
+        files.sort();
+        assert!(files.len() > 3, "{files:?}");
+        for path in &files {
+            assert!(fs::metadata(path).unwrap().len() <= 200, "{}", path.display());
+        }
+        let current = fs::read_to_string(dir.join(FILE_NAME)).unwrap();
+        assert!(current.trim_end().ends_with(&format!("INFO  feed: line 9 {}", "x".repeat(40))), "{current}");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+//! Forwarding Rust-side `log` records to the app. Swift registers one callback
+//! (typically writing to os_log) with a default level; individual modules can be
+//! made louder or quieter at runtime without touching the rest.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void, CString};
+use std::sync::{Once, RwLock};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::{guard, log_file, record, str_arg, AudioRemoteError};
+
+/// Log levels as seen from C; ordered from quietest to loudest like `log::LevelFilter`
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl LogLevel {
+    pub fn from_raw(raw: i32) -> Option<Self> {
+        match raw {
+            0 => Some(Self::Off),
+            1 => Some(Self::Error),
+            2 => Some(Self::Warn),
+            3 => Some(Self::Info),
+            4 => Some(Self::Debug),
+            5 => Some(Self::Trace),
+            _ => None,
+        }
+    }
+
+    fn filter(self) -> LevelFilter {
+        match self {
+            Self::Off => LevelFilter::Off,
+            Self::Error => LevelFilter::Error,
+            Self::Warn => LevelFilter::Warn,
+            Self::Info => LevelFilter::Info,
+            Self::Debug => LevelFilter::Debug,
+            Self::Trace => LevelFilter::Trace,
+        }
+    }
+
+    fn of(level: Level) -> Self {
+        match level {
+            Level::Error => Self::Error,
+            Level::Warn => Self::Warn,
+            Level::Info => Self::Info,
+            Level::Debug => Self::Debug,
+            Level::Trace => Self::Trace,
+        }
+    }
+}
+
+pub(crate) fn level_arg(raw: i32) -> Result<LogLevel, AudioRemoteError> {
+    LogLevel::from_raw(raw).ok_or(AudioRemoteError::InvalidArgument(format!("unknown log level {raw}")))
+}
+
+/// Receives each log line: (level, module, message, ctx). `module` is the path
+/// inside this library, e.g. "feed"; both strings are only valid during the call.
+/// May be called from any thread.
+pub type LogCallback = extern "C" fn(level: i32, module: *const c_char, message: *const c_char, ctx: *mut c_void);
+
+#[derive(Clone, Copy)]
+struct Sink {
+    callback: LogCallback,
+    ctx: *mut c_void,
+}
+
+// The caller promises the context can be used from any thread (see ar_log_set_callback)
+unsafe impl Send for Sink {}
+unsafe impl Sync for Sink {}
+
+/// Levels applied to records: per-module overrides, falling back to each sink's level
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filters {
+    /// Level of the app callback
+    pub default: LogLevel,
+    /// Level of the file logger (see log_file)
+    pub file: LogLevel,
+    /// Module path ("http", "feed::cache") to level; the longest matching prefix wins
+    pub modules: BTreeMap<String, LogLevel>,
+}
+
+impl Filters {
+    const fn new() -> Self {
+        Self { default: LogLevel::Off, file: LogLevel::Off, modules: BTreeMap::new() }
+    }
+
+    /// Level for `module` in a sink whose own level is `base`
+    pub fn level_for(&self, module: &str, base: LogLevel) -> LogLevel {
+        let mut best: Option<(&str, LogLevel)> = None;
+        for (prefix, level) in &self.modules {
+            let matches = module == prefix || module.strip_prefix(prefix.as_str()).is_some_and(|rest| rest.starts_with("::"));
+            if matches && best.is_none_or(|(b, _)| prefix.len() > b.len()) {
+                best = Some((prefix, *level));
+            }
+        }
+        best.map_or(base, |(_, level)| level)
+    }
+
+    /// The loudest level anything could be logged at, so `log` can skip the rest cheaply
+    fn max(&self) -> LogLevel {
+        self.modules.values().copied().fold(self.default.max(self.file), LogLevel::max)
+    }
+}
+
+struct Logger {
+    sink: RwLock<Option<Sink>>,
+    filters: RwLock<Filters>,
+}
+
+static LOGGER: Logger = Logger { sink: RwLock::new(None), filters: RwLock::new(Filters::new()) };
+
+/// Module path relative to this crate: "audioremote_ffi::feed" -> "feed"
+fn module_of(target: &str) -> &str {
+    let crate_name = module_path!().split("::").next().unwrap_or_default();
+    match target.strip_prefix(crate_name) {
+        Some("") => "",
+        Some(rest) => rest.strip_prefix("::").unwrap_or(target),
+        None => target,
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let filters = self.filters.read().unwrap_or_else(|e| e.into_inner());
+        let loudest = filters.default.max(filters.file);
+        LogLevel::of(metadata.level()) <= filters.level_for(module_of(metadata.target()), loudest)
+    }
+
+    fn log(&self, record: &Record) {
+        let level = LogLevel::of(record.level());
+        let module = module_of(record.target());
+        let (to_callback, to_file) = {
+            let filters = self.filters.read().unwrap_or_else(|e| e.into_inner());
+            (level <= filters.level_for(module, filters.default), level <= filters.level_for(module, filters.file))
+        };
+        if !to_callback && !to_file {
+            return;
+        }
+        let message = record.args().to_string();
+        if to_file {
+            log_file::write(level, module, &message);
+        }
+        let sink = *self.sink.read().unwrap_or_else(|e| e.into_inner());
+        if let (true, Some(sink)) = (to_callback, sink) {
+            let strip = |s: &str| CString::new(s.replace('\0', "")).unwrap_or_default();
+            let (module, message) = (strip(module), strip(&message));
+            (sink.callback)(level as i32, module.as_ptr(), message.as_ptr(), sink.ctx);
+        }
+    }
+
+    fn flush(&self) {
+        let _ = log_file::flush();
+    }
+}
+
+fn install() {
+    static INSTALL: Once = Once::new();
+    // If the host already installed a logger, records keep going there instead
+    INSTALL.call_once(|| {
+        let _ = log::set_logger(&LOGGER);
+    });
+}
+
+/// Change the filters and update the global max level to match
+pub fn update_filters(change: impl FnOnce(&mut Filters)) {
+    install();
+    let mut filters = LOGGER.filters.write().unwrap_or_else(|e| e.into_inner());
+    change(&mut filters);
+    log::set_max_level(filters.max().filter());
+}
+
+/// Forward logs at `level` and below to `callback`; NULL stops forwarding
+/// Per-module overrides set with ar_log_set_module_level are kept.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `callback` must stay callable, and `ctx` valid from any thread, until it is replaced.
+#[no_mangle]
+pub unsafe extern "C" fn ar_log_set_callback(level: i32, callback: Option<LogCallback>, ctx: *mut c_void) -> i32 {
+    guard("ar_log_set_callback", -999, || {
+        let Some(level) = record(level_arg(level)) else {
+            return -999;
+        };
+        *LOGGER.sink.write().unwrap_or_else(|e| e.into_inner()) = callback.map(|callback| Sink { callback, ctx });
+        update_filters(|filters| filters.default = level);
+        1
+    })
+}
+
+/// Override the level for one module and everything below it (e.g. "http", "feed");
+/// `level` -1 removes the override so the module follows the default again.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `module_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_log_set_module_level(module_ptr: *const c_char, level: i32) -> i32 {
+    guard("ar_log_set_module_level", -999, || {
+        let result = str_arg(module_ptr, "module").and_then(|module| {
+            let level = match level {
+                -1 => None,
+                raw => Some(level_arg(raw)?),
+            };
+            Ok((module.trim().trim_matches(':').to_owned(), level))
+        });
+        let Some((module, level)) = record(result) else {
+            return -999;
+        };
+        update_filters(|filters| match level {
+            Some(level) => {
+                filters.modules.insert(module, level);
+            }
+            None => {
+                filters.modules.remove(&module);
+            }
+        });
+        1
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::sync::Mutex;
+
+    static LINES: Mutex<Vec<(i32, String, String)>> = Mutex::new(Vec::new());
+
+    extern "C" fn collect(level: i32, module: *const c_char, message: *const c_char, _ctx: *mut c_void) {
+        let module = unsafe { CStr::from_ptr(module) }.to_str().unwrap().to_owned();
+        let message = unsafe { CStr::from_ptr(message) }.to_str().unwrap().to_owned();
+        // Other tests log too; keep only ours
+        if module.starts_with("logging") {
+            LINES.lock().unwrap().push((level, module, message));
+        }
+    }
+
+    #[test]
+    fn test_module_level_lookup() {
+        let mut filters = Filters::new();
+        filters.default = LogLevel::Warn;
+        filters.modules.insert("feed".into(), LogLevel::Debug);
+        filters.modules.insert("feed::cache".into(), LogLevel::Off);
+        assert_eq!(filters.level_for("feed", filters.default), LogLevel::Debug);
+        assert_eq!(filters.level_for("feed::http", filters.default), LogLevel::Debug);
+        assert_eq!(filters.level_for("feed::cache", filters.default), LogLevel::Off);
+        assert_eq!(filters.level_for("feedback", filters.default), LogLevel::Warn);
+        assert_eq!(filters.level_for("feedback", LogLevel::Error), LogLevel::Error);
+        assert_eq!(filters.max(), LogLevel::Debug);
+        assert_eq!(module_of("audioremote_ffi::feed"), "feed");
+        assert_eq!(module_of("ureq::unit"), "ureq::unit");
+    }
+
+    #[test]
+    fn test_callback_receives_filtered_records() {
+        assert_eq!(unsafe { ar_log_set_callback(LogLevel::Info as i32, Some(collect), std::ptr::null_mut()) }, 1);
+        let module = CString::new("logging::tests").unwrap();
+        log::debug!("hidden");
+        log::info!("shown {}", 1);
+        assert_eq!(unsafe { ar_log_set_module_level(module.as_ptr(), LogLevel::Trace as i32) }, 1);
+        log::trace!("now visible");
+        assert_eq!(unsafe { ar_log_set_module_level(module.as_ptr(), -1) }, 1);
+        log::trace!("hidden again");
+        assert_eq!(unsafe { ar_log_set_callback(LogLevel::Off as i32, None, std::ptr::null_mut()) }, 1);
+        log::error!("nobody listening");
+        assert_eq!(unsafe { ar_log_set_callback(9, None, std::ptr::null_mut()) }, -999);
+
+        let lines = LINES.lock().unwrap().clone();
+        let expected = [(3, "logging::tests".to_owned(), "shown 1".to_owned()), (5, "logging::tests".to_owned(), "now visible".to_owned())];
+        assert_eq!(lines, expected);
+    }
+}
@@ -0,0 +1,420 @@
+//! Loudness in LUFS as in ITU-R BS.1770-4 and EBU R128, for the loudness
+//! display and auto-leveling. Each channel is K-weighted (a high shelf for the
+//! head, then a high pass), squared and weighted (surround channels count
+//! 1.41, LFE not at all), and summed into 100 ms steps. Momentary loudness is
+//! the last 400 ms and short-term the last 3 s; integrated loudness is every
+//! 400 ms block so far (overlapping by 75%) above the -70 LUFS absolute gate,
+//! then above a relative gate 10 LU under those blocks' loudness.
+//!
+//! Blocks are kept in a histogram of 0.1 LU bins, so integrated loudness costs
+//! the same after an hour as after a second; the relative gate is applied at
+//! the bin, which moves the result by well under the 0.1 LU R128 allows.
+//!
+//! The meter also keeps the true peak since the last reset (see `true_peak`),
+//! which R128 reports next to the integrated loudness.
+
+use std::collections::VecDeque;
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::eq::Biquad;
+use crate::handle::{Handle, Registry};
+use crate::true_peak::TruePeak;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+const BIN_LU: f64 = 0.1;
+/// Blocks louder than this go in the top bin
+const HISTOGRAM_TOP: f64 = 5.0;
+/// Surround channels in the usual 5.1 order, L R C LFE Ls Rs
+const SURROUND_WEIGHTS: [f64; 6] = [1.0, 1.0, 1.0, 0.0, 1.41, 1.41];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct LoudnessConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// Each channel's weight; default 1.0 each, or SURROUND_WEIGHTS for six channels
+    #[serde(default)]
+    pub channel_weights: Option<Vec<f64>>,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> f64 {
+    48_000.0
+}
+
+impl Default for LoudnessConfig {
+    fn default() -> Self {
+        Self { channels: default_channels(), sample_rate: default_sample_rate(), channel_weights: None }
+    }
+}
+
+/// The loudness of a mean square
+fn lufs(power: f64) -> f64 {
+    match power > 0.0 {
+        true => -0.691 + 10.0 * power.log10(),
+        false => f64::NEG_INFINITY,
+    }
+}
+
+/// BS.1770's two K-weighting stages, recomputed for `rate` the way libebur128 does
+fn k_weighting(rate: f64) -> [Biquad; 2] {
+    let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (std::f64::consts::PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new([1.0, -2.0, 1.0], [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0]);
+    [shelf, high_pass]
+}
+
+/// Momentary, short-term and integrated loudness in LUFS, -∞ until there's enough audio
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    pub momentary: f64,
+    pub short_term: f64,
+    pub integrated: f64,
+}
+
+pub struct LoudnessMeter {
+    weights: Vec<f64>,
+    filters: Vec<[Biquad; 2]>,
+    step_frames: usize,
+    /// Frames and weighted power summed in the current step
+    frames: usize,
+    power: f64,
+    /// The mean power of the latest steps, newest last
+    steps: VecDeque<f64>,
+    /// Gated blocks by loudness: how many, and their summed power
+    histogram: Vec<(u64, f64)>,
+    true_peak: TruePeak,
+    frame: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(config: &LoudnessConfig) -> Result<Self, AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=MAX_CHANNELS).contains(&config.channels) {
+            return invalid(format!("channels {} is outside 1–{MAX_CHANNELS}", config.channels));
+        }
+        if !(8000.0..=768_000.0).contains(&config.sample_rate) {
+            return invalid(format!("sampleRate {} is outside 8000–768000", config.sample_rate));
+        }
+        let channels = config.channels as usize;
+        let weights = match &config.channel_weights {
+            Some(weights) if weights.len() != channels => {
+                return invalid(format!("{} channel weights for {channels} channels", weights.len()))
+            }
+            Some(weights) if weights.iter().any(|weight| !(0.0..=10.0).contains(weight)) => {
+                return invalid("channel weights must be 0–10".into())
+            }
+            Some(weights) => weights.clone(),
+            None if channels == SURROUND_WEIGHTS.len() => SURROUND_WEIGHTS.to_vec(),
+            None => vec![1.0; channels],
+        };
+        let bins = ((HISTOGRAM_TOP - ABSOLUTE_GATE) / BIN_LU).round() as usize;
+        Ok(Self {
+            weights,
+            filters: vec![k_weighting(config.sample_rate); channels],
+            step_frames: (config.sample_rate / 10.0).round() as usize,
+            frames: 0,
+            power: 0.0,
+            steps: VecDeque::with_capacity(SHORT_TERM_STEPS + 1),
+            histogram: vec![(0, 0.0); bins],
+            true_peak: TruePeak::new(channels),
+            frame: vec![0.0; channels],
+        })
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.weights.len()
+    }
+
+    /// Measure interleaved samples; a partial frame at the end is ignored and samples that
+    /// aren't finite count as silence
+    pub fn process(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.weights.len()) {
+            for (out, &sample) in self.frame.iter_mut().zip(frame) {
+                *out = if sample.is_finite() { f64::from(sample) } else { 0.0 };
+            }
+            for ((&sample, [shelf, high_pass]), &weight) in self.frame.iter().zip(&mut self.filters).zip(&self.weights) {
+                let filtered = high_pass.process(shelf.process(sample));
+                self.power += weight * filtered * filtered;
+            }
+            self.true_peak.push(&self.frame);
+            self.frames += 1;
+            if self.frames == self.step_frames {
+                self.end_step();
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        self.steps.push_back(self.power / self.frames as f64);
+        if self.steps.len() > SHORT_TERM_STEPS {
+            self.steps.pop_front();
+        }
+        (self.frames, self.power) = (0, 0.0);
+        if let Some(block) = self.mean(MOMENTARY_STEPS) {
+            let loudness = lufs(block);
+            if loudness > ABSOLUTE_GATE {
+                let bin = (((loudness - ABSOLUTE_GATE) / BIN_LU) as usize).min(self.histogram.len() - 1);
+                self.histogram[bin].0 += 1;
+                self.histogram[bin].1 += block;
+            }
+        }
+    }
+
+    /// The mean power of the last `count` steps, if there have been that many
+    fn mean(&self, count: usize) -> Option<f64> {
+        (self.steps.len() >= count).then(|| self.steps.iter().rev().take(count).sum::<f64>() / count as f64)
+    }
+
+    fn integrated(&self) -> f64 {
+        let gated = |bins: &[(u64, f64)]| {
+            let (count, power) = bins.iter().fold((0, 0.0), |(count, power), bin| (count + bin.0, power + bin.1));
+            match count {
+                0 => f64::NEG_INFINITY,
+                count => lufs(power / count as f64),
+            }
+        };
+        let threshold = gated(&self.histogram) + RELATIVE_GATE;
+        if threshold == f64::NEG_INFINITY {
+            return threshold;
+        }
+        let first = ((threshold - ABSOLUTE_GATE).max(0.0) / BIN_LU) as usize;
+        gated(&self.histogram[first.min(self.histogram.len() - 1)..])
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        let lufs_of = |count| self.mean(count).map_or(f64::NEG_INFINITY, lufs);
+        Loudness { momentary: lufs_of(MOMENTARY_STEPS), short_term: lufs_of(SHORT_TERM_STEPS), integrated: self.integrated() }
+    }
+
+    /// The highest true peak since the last reset over all channels, in dBTP; -∞ for silence
+    pub fn true_peak_db(&self) -> f64 {
+        self.true_peak.peak_db()
+    }
+
+    /// Start over, e.g. for a new track
+    pub fn reset(&mut self) {
+        self.true_peak.reset();
+        self.filters.iter_mut().flatten().for_each(Biquad::reset);
+        (self.frames, self.power) = (0, 0.0);
+        self.steps.clear();
+        self.histogram.fill((0, 0.0));
+    }
+}
+
+static METERS: Registry<Mutex<LoudnessMeter>> = Registry::new("loudness meter");
+
+fn with_meter<T>(handle: Handle, body: impl FnOnce(&mut LoudnessMeter) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let meter = METERS.get(handle)?;
+    let mut meter = meter.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut meter)
+}
+
+/// Create a loudness meter. `config_json` (nullable): {"channels" (default 2), "sampleRate"
+/// (default 48000), "channelWeights" (default 1.0 each; for six channels L R C LFE Ls Rs)}
+/// Returns: a handle (free with ar_loudness_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_loudness_new(config_json: *const c_char) -> Handle {
+    guard("ar_loudness_new", 0, || {
+        let config = match config_json.is_null() {
+            true => Ok(LoudnessConfig::default()),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid loudness config: {e}")))
+            }),
+        };
+        match record(config.and_then(|config| LoudnessMeter::new(&config))) {
+            Some(meter) => METERS.insert(Mutex::new(meter)),
+            None => 0,
+        }
+    })
+}
+
+/// Measure `frames` frames of interleaved float samples, one per channel per frame
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_loudness_process(meter: Handle, samples: *const f32, frames: usize) -> i32 {
+    guard("ar_loudness_process", -999, || {
+        let result = with_meter(meter, |meter| {
+            let len = frames
+                .checked_mul(meter.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len == 0 {
+                return Ok(1);
+            }
+            if samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            meter.process(std::slice::from_raw_parts(samples, len));
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Read the momentary, short-term and integrated loudness in LUFS; each is -INFINITY until
+/// there's enough audio for it and while it's silent. Any of the out pointers may be NULL.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// Each out pointer must be null or point to a writable double.
+#[no_mangle]
+pub unsafe extern "C" fn ar_loudness_read(meter: Handle, out_momentary: *mut f64, out_short_term: *mut f64, out_integrated: *mut f64) -> i32 {
+    guard("ar_loudness_read", -999, || {
+        let result = with_meter(meter, |meter| {
+            let loudness = meter.loudness();
+            for (out, value) in [(out_momentary, loudness.momentary), (out_short_term, loudness.short_term), (out_integrated, loudness.integrated)] {
+                if !out.is_null() {
+                    *out = value;
+                }
+            }
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Read the highest true peak (BS.1770-4, 4× oversampled) since the meter was created or reset,
+/// over all channels, in dBTP; -INFINITY while it's been silent
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `out_db` must point to a writable double.
+#[no_mangle]
+pub unsafe extern "C" fn ar_loudness_true_peak(meter: Handle, out_db: *mut f64) -> i32 {
+    guard("ar_loudness_true_peak", -999, || {
+        let result = with_meter(meter, |meter| {
+            if out_db.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("out_db must not be null".into()));
+            }
+            *out_db = meter.true_peak_db();
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Start a meter's measurement over, e.g. for a new track
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_loudness_reset(meter: Handle) -> i32 {
+    guard("ar_loudness_reset", -999, || {
+        record(with_meter(meter, |meter| {
+            meter.reset();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Release a meter from ar_loudness_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_loudness_free(meter: Handle) -> i32 {
+    guard("ar_loudness_free", -999, || record(METERS.remove(meter)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stereo 1 kHz at `dbfs`, the same in both channels, as in EBU Tech 3341's tests
+    fn tone(rate: f64, seconds: f64, dbfs: f64) -> Vec<f32> {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        let frames = (rate * seconds) as usize;
+        (0..frames)
+            .map(|i| (amplitude * (2.0 * std::f64::consts::PI * 1000.0 * i as f64 / rate).sin()) as f32)
+            .flat_map(|sample| [sample, sample])
+            .collect()
+    }
+
+    #[test]
+    fn test_reference_levels() {
+        // BS.1770's published 48 kHz coefficients
+        let [shelf, high_pass] = k_weighting(48_000.0);
+        assert!((shelf.b[0] - 1.53512485958697).abs() < 1e-9 && (shelf.a[0] + 1.69065929318241).abs() < 1e-9);
+        assert!((high_pass.a[0] + 1.99004745483398).abs() < 1e-9 && (high_pass.a[1] - 0.99007225036621).abs() < 1e-9);
+
+        for rate in [44_100.0, 48_000.0] {
+            let mut meter = LoudnessMeter::new(&LoudnessConfig { sample_rate: rate, ..LoudnessConfig::default() }).unwrap();
+            assert_eq!(meter.loudness().integrated, f64::NEG_INFINITY);
+            meter.process(&tone(rate, 4.0, -23.0));
+            let loudness = meter.loudness();
+            for value in [loudness.momentary, loudness.short_term, loudness.integrated] {
+                assert!((value + 23.0).abs() < 0.1, "{rate}: {loudness:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_gating() {
+        // Tech 3341 case 3 shortened: quiet passages 13 LU down are gated out, silence too
+        let rate = 16_000.0;
+        let mut meter = LoudnessMeter::new(&LoudnessConfig { sample_rate: rate, ..LoudnessConfig::default() }).unwrap();
+        for (seconds, dbfs) in [(5.0, -36.0), (30.0, -23.0), (5.0, -36.0), (2.0, -100.0)] {
+            meter.process(&tone(rate, seconds, dbfs));
+        }
+        let loudness = meter.loudness();
+        assert!((loudness.integrated + 23.0).abs() < 0.1, "{loudness:?}");
+        assert!(loudness.momentary < -90.0);
+        meter.reset();
+        assert_eq!(meter.loudness(), Loudness { momentary: f64::NEG_INFINITY, short_term: f64::NEG_INFINITY, integrated: f64::NEG_INFINITY });
+
+        // Six channels default to 5.1 weights, so the LFE isn't counted
+        let mut surround = LoudnessMeter::new(&LoudnessConfig { channels: 6, ..LoudnessConfig::default() }).unwrap();
+        let lfe: Vec<f32> = tone(48_000.0, 1.0, 0.0).chunks(2).flat_map(|frame| [0.0, 0.0, 0.0, frame[0], 0.0, 0.0]).collect();
+        surround.process(&lfe);
+        assert_eq!(surround.loudness().integrated, f64::NEG_INFINITY);
+        let weights = LoudnessConfig { channel_weights: Some(vec![1.0]), ..LoudnessConfig::default() };
+        assert!(LoudnessMeter::new(&weights).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let meter = unsafe { ar_loudness_new(std::ptr::null()) };
+        assert_ne!(meter, 0);
+        let samples = tone(48_000.0, 0.5, -20.0);
+        assert_eq!(unsafe { ar_loudness_process(meter, samples.as_ptr(), samples.len() / 2) }, 1);
+        assert_eq!(unsafe { ar_loudness_process(meter, std::ptr::null(), 1) }, -999);
+        let (mut momentary, mut short_term) = (0.0, 0.0);
+        assert_eq!(unsafe { ar_loudness_read(meter, &mut momentary, &mut short_term, std::ptr::null_mut()) }, 1);
+        assert!((momentary + 20.0).abs() < 0.1, "{momentary}");
+        assert_eq!(short_term, f64::NEG_INFINITY);
+        let mut true_peak = 0.0;
+        assert_eq!(unsafe { ar_loudness_true_peak(meter, &mut true_peak) }, 1);
+        assert!((true_peak + 20.0).abs() < 0.1, "{true_peak}");
+        assert_eq!(unsafe { ar_loudness_true_peak(meter, std::ptr::null_mut()) }, -999);
+        assert_eq!(ar_loudness_reset(meter), 1);
+        assert_eq!(ar_loudness_free(meter), 1);
+        assert_eq!(ar_loudness_free(meter), -999);
+        assert_eq!(unsafe { ar_loudness_new(c"{\"sampleRate\": 100}".as_ptr()) }, 0);
+    }
+}
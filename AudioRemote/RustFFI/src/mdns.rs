@@ -0,0 +1,559 @@
+//! Bonjour advertisement of the remote-control server (`_audioremote._tcp`),
+//! as a small multicast DNS responder (RFC 6762) publishing one DNS-SD service
+//! (RFC 6763). The responder shares port 5353 with the system's mDNSResponder
+//! and answers only for its own names: the service instance, and a host name of
+//! its own so it never contends with the Mac's.
+//!
+//! On start it probes for the instance name, renaming "Name" to "Name (2)" and
+//! so on if another device answers, then announces twice; on stop it sends
+//! goodbyes so remotes drop the service at once instead of when it expires.
+//! Other services (`hap`'s `_hap._tcp`) run a responder of their own through
+//! `advertise`.
+
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::dns::{self, Message, Question, RData, Record};
+use crate::error::string_result;
+use crate::interfaces::{self, InterfaceAddress};
+use crate::{guard, record, server, str_arg, AudioRemoteError};
+
+pub const SERVICE_TYPE: &str = "_audioremote._tcp.local";
+const SERVICE_ENUMERATION: &str = "_services._dns-sd._udp.local";
+pub const MDNS_PORT: u16 = 5353;
+pub const MDNS_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+pub const MDNS_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+/// RFC 6762 §10: records naming a host expire sooner than the others
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+const PROBE_INTERVAL: Duration = Duration::from_millis(250);
+const PROBES: u32 = 3;
+const ANNOUNCEMENTS: u32 = 2;
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the answer loop checks for stop and re-reads interface addresses
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const ADDRESS_REFRESH: Duration = Duration::from_secs(10);
+
+/// `ar_mdns_start` configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AdvertiseConfig {
+    /// Shown in pickers, e.g. "Studio Mac"
+    pub name: String,
+    /// The app version
+    pub version: String,
+    /// Default: the running server's port
+    pub port: Option<u16>,
+    #[serde(default = "default_capabilities")]
+    pub capabilities: Vec<String>,
+}
+
+fn default_capabilities() -> Vec<String> {
+    ["volume", "mute", "devices", "nowPlaying", "events"].map(String::from).to_vec()
+}
+
+/// The records of one advertised service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Service {
+    /// e.g. "_audioremote._tcp.local"
+    pub service_type: String,
+    pub instance: String,
+    pub host: String,
+    pub port: u16,
+    pub txt: Vec<Vec<u8>>,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Instance names are one label of at most 63 bytes; `dns` doesn't escape dots, so they become dashes
+fn instance_label(name: &str) -> String {
+    let mut label: String = name.trim().chars().filter(|c| !c.is_control()).map(|c| if c == '.' { '-' } else { c }).collect();
+    while label.len() > 63 {
+        label.pop();
+    }
+    label
+}
+
+/// Our own host name: the Mac's, made a valid label, with a suffix so we never claim the Mac's name
+pub(crate) fn host_name() -> String {
+    let mut buffer = [0u8; 256];
+    let hostname = match unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut c_char, buffer.len()) } {
+        0 => String::from_utf8_lossy(&buffer[..buffer.iter().position(|b| *b == 0).unwrap_or(0)]).into_owned(),
+        _ => String::new(),
+    };
+    let label: String = hostname
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .take(40)
+        .collect();
+    match label.trim_matches('-') {
+        "" => "audioremote.local".into(),
+        label => format!("{label}-audioremote.local"),
+    }
+}
+
+impl Service {
+    pub fn new(config: &AdvertiseConfig, port: u16) -> Result<Self, AudioRemoteError> {
+        let instance = instance_label(&config.name);
+        if instance.is_empty() {
+            return Err(AudioRemoteError::InvalidArgument("the service name is empty".into()));
+        }
+        let txt = BTreeMap::from([
+            ("txtvers".to_owned(), "1".to_owned()),
+            ("version".to_owned(), config.version.clone()),
+            ("name".to_owned(), config.name.clone()),
+            ("caps".to_owned(), config.capabilities.join(",")),
+            ("path".to_owned(), "/api/v1".to_owned()),
+        ]);
+        Ok(Self { service_type: SERVICE_TYPE.into(), instance, host: host_name(), port, txt: dns::txt_entries(&txt), addresses: Vec::new() })
+    }
+
+    pub fn instance_name(&self) -> String {
+        format!("{}.{}", self.instance, self.service_type)
+    }
+
+    /// Pick the next name after a conflict: "Name" -> "Name (2)" -> "Name (3)"
+    fn rename(&mut self) {
+        let (base, n) = match self.instance.rsplit_once(" (") {
+            Some((base, n)) => match n.strip_suffix(')').and_then(|n| n.parse::<u32>().ok()) {
+                Some(n) => (base.to_owned(), n + 1),
+                None => (self.instance.clone(), 2),
+            },
+            None => (self.instance.clone(), 2),
+        };
+        self.instance = instance_label(&format!("{base} ({n})"));
+    }
+
+    fn ptr(&self, ttl: u32) -> Record {
+        Record { name: self.service_type.clone(), cache_flush: false, ttl, data: RData::Ptr(self.instance_name()) }
+    }
+
+    fn srv(&self, ttl: u32) -> Record {
+        let data = RData::Srv { priority: 0, weight: 0, port: self.port, target: self.host.clone() };
+        Record { name: self.instance_name(), cache_flush: true, ttl: ttl.min(HOST_TTL), data }
+    }
+
+    fn txt(&self, ttl: u32) -> Record {
+        Record { name: self.instance_name(), cache_flush: true, ttl, data: RData::Txt(self.txt.clone()) }
+    }
+
+    fn address_records(&self, ttl: u32) -> Vec<Record> {
+        let ttl = ttl.min(HOST_TTL);
+        self.addresses
+            .iter()
+            .map(|address| {
+                let data = match address {
+                    IpAddr::V4(address) => RData::A(*address),
+                    IpAddr::V6(address) => RData::Aaaa(*address),
+                };
+                Record { name: self.host.clone(), cache_flush: true, ttl, data }
+            })
+            .collect()
+    }
+
+    /// Every record, unsolicited: announcements, and goodbyes with `ttl` 0
+    fn announcement(&self, ttl: u32) -> Message {
+        let mut answers = vec![self.ptr(ttl), self.srv(ttl), self.txt(ttl)];
+        answers.extend(self.address_records(ttl));
+        let enumeration = Record { name: SERVICE_ENUMERATION.into(), cache_flush: false, ttl, data: RData::Ptr(self.service_type.clone()) };
+        answers.push(enumeration);
+        Message { flags: dns::FLAG_RESPONSE | dns::FLAG_AUTHORITATIVE, answers, ..Message::default() }
+    }
+
+    /// "Is anyone using this name?", with our proposed records in the authority section (§8.1)
+    fn probe(&self) -> Message {
+        let host = Question { name: self.host.clone(), qtype: dns::TYPE_ANY, unicast: true };
+        let instance = Question { name: self.instance_name(), qtype: dns::TYPE_ANY, unicast: true };
+        let mut authorities = vec![self.srv(HOST_TTL), self.txt(SERVICE_TTL)];
+        authorities.extend(self.address_records(HOST_TTL));
+        Message { questions: vec![instance, host], authorities, ..Message::default() }
+    }
+
+    /// A response claiming our instance name with records that aren't ours
+    fn conflicts(&self, message: &Message) -> bool {
+        let ours = [self.srv(HOST_TTL).data, self.txt(SERVICE_TTL).data];
+        message.is_response()
+            && message
+                .records()
+                .any(|record| dns::same_name(&record.name, &self.instance_name()) && record.ttl > 0 && !ours.contains(&record.data))
+    }
+
+    /// The response to a query, if any question is about us. Answers the query
+    /// already lists (known-answer suppression, §7.1) are left out.
+    pub fn answer(&self, query: &Message) -> Option<Message> {
+        if query.is_response() {
+            return None;
+        }
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+        for question in &query.questions {
+            let any = question.qtype == dns::TYPE_ANY;
+            let wants = |rtype: u16| any || question.qtype == rtype;
+            if dns::same_name(&question.name, &self.service_type) && wants(dns::TYPE_PTR) {
+                answers.push(self.ptr(SERVICE_TTL));
+                additionals.extend([self.srv(HOST_TTL), self.txt(SERVICE_TTL)]);
+                additionals.extend(self.address_records(HOST_TTL));
+            } else if dns::same_name(&question.name, SERVICE_ENUMERATION) && wants(dns::TYPE_PTR) {
+                let data = RData::Ptr(self.service_type.clone());
+                answers.push(Record { name: SERVICE_ENUMERATION.into(), cache_flush: false, ttl: SERVICE_TTL, data });
+            } else if dns::same_name(&question.name, &self.instance_name()) {
+                if wants(dns::TYPE_SRV) {
+                    answers.push(self.srv(HOST_TTL));
+                    additionals.extend(self.address_records(HOST_TTL));
+                }
+                if wants(dns::TYPE_TXT) {
+                    answers.push(self.txt(SERVICE_TTL));
+                }
+            } else if dns::same_name(&question.name, &self.host) {
+                let addresses = self.address_records(HOST_TTL);
+                answers.extend(addresses.into_iter().filter(|record| wants(record.data.rtype())));
+            }
+        }
+        let known = |record: &Record| {
+            query.answers.iter().any(|k| dns::same_name(&k.name, &record.name) && k.data == record.data && k.ttl >= record.ttl / 2)
+        };
+        answers.retain(|record| !known(record));
+        if answers.is_empty() {
+            return None;
+        }
+        additionals.retain(|record| !answers.contains(record));
+        additionals.dedup();
+        Some(Message { flags: dns::FLAG_RESPONSE | dns::FLAG_AUTHORITATIVE, answers, additionals, ..Message::default() })
+    }
+}
+
+/// The multicast sockets, joined on every interface with an address of their family
+pub(crate) struct Sockets {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+    pub(crate) interfaces: Vec<InterfaceAddress>,
+}
+
+pub(crate) fn multicast_socket(domain: Domain) -> io::Result<Socket> {
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    if domain == Domain::IPV6 {
+        socket.set_only_v6(true)?;
+        socket.set_multicast_hops_v6(255)?;
+    } else {
+        socket.set_multicast_ttl_v4(255)?;
+    }
+    Ok(socket)
+}
+
+impl Sockets {
+    pub(crate) fn open(interfaces: Vec<InterfaceAddress>) -> Result<Self, AudioRemoteError> {
+        let v4 = multicast_socket(Domain::IPV4).and_then(|socket| {
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+            for interface in &interfaces {
+                if let IpAddr::V4(address) = interface.address {
+                    let _ = socket.join_multicast_v4(&MDNS_V4, &address);
+                }
+            }
+            Ok(UdpSocket::from(socket))
+        });
+        let v6 = multicast_socket(Domain::IPV6).and_then(|socket| {
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+            let mut indexes: Vec<u32> = interfaces.iter().filter(|i| i.address.is_ipv6()).map(|i| i.index).collect();
+            indexes.dedup();
+            for index in indexes {
+                let _ = socket.join_multicast_v6(&MDNS_V6, index);
+            }
+            Ok(UdpSocket::from(socket))
+        });
+        if let (Err(e), Err(_)) = (&v4, &v6) {
+            return Err(AudioRemoteError::Network(format!("can't listen for mDNS on port {MDNS_PORT}: {e}")));
+        }
+        Ok(Self { v4: v4.ok(), v6: v6.ok(), interfaces })
+    }
+
+    /// Send to the mDNS group on every interface
+    pub(crate) fn multicast(&self, message: &Message) {
+        let bytes = dns::encode(message);
+        for interface in &self.interfaces {
+            let result = match (interface.address, &self.v4, &self.v6) {
+                (IpAddr::V4(address), Some(socket), _) => {
+                    let socket = socket2::SockRef::from(socket);
+                    socket.set_multicast_if_v4(&address).and_then(|()| socket.send_to(&bytes, &SocketAddrV4::new(MDNS_V4, MDNS_PORT).into()))
+                }
+                (IpAddr::V6(_), _, Some(socket)) => {
+                    socket.send_to(&bytes, SocketAddrV6::new(MDNS_V6, MDNS_PORT, 0, interface.index))
+                }
+                _ => continue,
+            };
+            if let Err(e) = result {
+                log::debug!("mDNS send on {} failed: {e}", interface.interface);
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for a packet on either socket
+    pub(crate) fn receive(&self, buffer: &mut [u8], timeout: Duration) -> Option<(usize, SocketAddr, &UdpSocket)> {
+        let sockets: Vec<&UdpSocket> = self.v4.iter().chain(self.v6.iter()).collect();
+        let mut fds: Vec<libc::pollfd> = sockets.iter().map(|s| libc::pollfd { fd: s.as_raw_fd(), events: libc::POLLIN, revents: 0 }).collect();
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout.as_millis() as libc::c_int) };
+        if ready <= 0 {
+            return None;
+        }
+        let (socket, _) = sockets.into_iter().zip(&fds).find(|(_, fd)| fd.revents & libc::POLLIN != 0)?;
+        let (length, from) = socket.recv_from(buffer).ok()?;
+        Some((length, from, socket))
+    }
+
+    /// Addresses to advertise, once each
+    fn addresses(&self) -> Vec<IpAddr> {
+        let mut addresses: Vec<IpAddr> = self.interfaces.iter().map(|i| i.address).collect();
+        addresses.sort();
+        addresses.dedup();
+        addresses
+    }
+}
+
+enum Phase {
+    Probing { sent: u32 },
+    Announcing { sent: u32 },
+    Running,
+}
+
+/// A running responder for one service
+pub(crate) struct Advertisement {
+    stop: Arc<AtomicBool>,
+    /// The instance name in use, which changes on conflicts
+    name: Arc<Mutex<String>>,
+    thread: JoinHandle<()>,
+}
+
+impl Advertisement {
+    pub(crate) fn name(&self) -> String {
+        self.name.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Send goodbyes and stop responding
+    pub(crate) fn stop(self) {
+        self.stop.store(true, Ordering::Release);
+        let _ = self.thread.join();
+    }
+}
+
+static ADVERTISER: Mutex<Option<Advertisement>> = Mutex::new(None);
+
+fn respond(mut service: Service, sockets: Sockets, stop: Arc<AtomicBool>, name: Arc<Mutex<String>>) {
+    let mut phase = Phase::Probing { sent: 0 };
+    let mut next = Instant::now();
+    let mut refreshed = Instant::now();
+    let mut sockets = sockets;
+    let mut buffer = vec![0; 9000];
+    while !stop.load(Ordering::Acquire) {
+        if Instant::now() >= next {
+            (phase, next) = match phase {
+                Phase::Probing { sent } if sent < PROBES => {
+                    sockets.multicast(&service.probe());
+                    (Phase::Probing { sent: sent + 1 }, Instant::now() + PROBE_INTERVAL)
+                }
+                Phase::Probing { .. } => {
+                    *name.lock().unwrap_or_else(|e| e.into_inner()) = service.instance.clone();
+                    log::info!("advertising \"{}\" on port {}", service.instance, service.port);
+                    (Phase::Announcing { sent: 0 }, Instant::now())
+                }
+                Phase::Announcing { sent } if sent < ANNOUNCEMENTS => {
+                    sockets.multicast(&service.announcement(SERVICE_TTL));
+                    (Phase::Announcing { sent: sent + 1 }, Instant::now() + ANNOUNCE_INTERVAL)
+                }
+                Phase::Announcing { .. } | Phase::Running => (Phase::Running, Instant::now() + ADDRESS_REFRESH),
+            };
+        }
+        if refreshed.elapsed() >= ADDRESS_REFRESH {
+            refreshed = Instant::now();
+            if let Ok(interfaces) = interfaces::local_addresses() {
+                if interfaces != sockets.interfaces {
+                    // Rejoin the group on new interfaces and announce the new addresses
+                    if let Ok(reopened) = Sockets::open(interfaces) {
+                        sockets = reopened;
+                        service.addresses = sockets.addresses();
+                        phase = Phase::Announcing { sent: 0 };
+                        next = Instant::now();
+                    }
+                }
+            }
+        }
+        let Some((length, from, socket)) = sockets.receive(&mut buffer, POLL_INTERVAL.min(next.saturating_duration_since(Instant::now()))) else {
+            continue;
+        };
+        let Ok(message) = dns::decode(&buffer[..length]) else {
+            continue;
+        };
+        if service.conflicts(&message) {
+            let previous = service.instance.clone();
+            service.rename();
+            log::warn!("\"{previous}\" is taken on this network, renaming to \"{}\"", service.instance);
+            (phase, next) = (Phase::Probing { sent: 0 }, Instant::now());
+            continue;
+        }
+        if !matches!(phase, Phase::Running | Phase::Announcing { .. }) {
+            continue;
+        }
+        if let Some(mut response) = service.answer(&message) {
+            if from.port() == MDNS_PORT {
+                sockets.multicast(&response);
+            } else {
+                // A legacy unicast resolver (§6.7) expects its id and questions back
+                response.id = message.id;
+                response.questions = message.questions.clone();
+                let _ = socket.send_to(&dns::encode(&response), from);
+            }
+        }
+    }
+    sockets.multicast(&service.announcement(0));
+}
+
+/// Start advertising; replaces a running advertisement
+pub fn start(config: &AdvertiseConfig) -> Result<(), AudioRemoteError> {
+    let port = match config.port.or_else(|| server::local_address().map(|address| address.port())) {
+        Some(port) => port,
+        None => return Err(AudioRemoteError::InvalidArgument("no port given and the server isn't running".into())),
+    };
+    let service = Service::new(config, port)?;
+    stop();
+    let advertisement = advertise(service)?;
+    *ADVERTISER.lock().unwrap_or_else(|e| e.into_inner()) = Some(advertisement);
+    Ok(())
+}
+
+/// Probe for `service`'s name, announce it and answer for it until stopped; the addresses are
+/// filled in from the interfaces
+pub(crate) fn advertise(mut service: Service) -> Result<Advertisement, AudioRemoteError> {
+    let sockets = Sockets::open(interfaces::local_addresses()?)?;
+    service.addresses = sockets.addresses();
+    let stop = Arc::new(AtomicBool::new(false));
+    let name = Arc::new(Mutex::new(service.instance.clone()));
+    let thread = {
+        let (stop, name) = (stop.clone(), name.clone());
+        thread::Builder::new()
+            .name("audioremote-mdns".into())
+            .spawn(move || respond(service, sockets, stop, name))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the mDNS thread: {e}")))?
+    };
+    Ok(Advertisement { stop, name, thread })
+}
+
+/// Withdraw the service (sending goodbyes) and stop responding
+pub fn stop() {
+    if let Some(advertisement) = ADVERTISER.lock().unwrap_or_else(|e| e.into_inner()).take() {
+        advertisement.stop();
+    }
+}
+
+/// The instance name being advertised, after any conflict renames
+pub fn advertised_name() -> Option<String> {
+    ADVERTISER.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(Advertisement::name)
+}
+
+/// Advertise the server over Bonjour as `_audioremote._tcp`, replacing any running advertisement
+/// `config_json` keys: "name" (shown to remotes), "version" (the app's), optional "port" (default:
+/// the running server's) and "capabilities" (array of strings, published in the TXT record).
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_mdns_start(config_ptr: *const c_char) -> i32 {
+    guard("ar_mdns_start", -999, || {
+        let config = str_arg(config_ptr, "config").and_then(|json| {
+            serde_json::from_str::<AdvertiseConfig>(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid mDNS config: {e}")))
+        });
+        match record(config.and_then(|config| start(&config))) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Withdraw the advertisement; a no-op if not advertising. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_mdns_stop() {
+    guard("ar_mdns_stop", (), stop)
+}
+
+/// Returns: the advertised name, which differs from the configured one after a conflict
+/// (e.g. "Studio Mac (2)"); NULL if not advertising (free with rust_string_free)
+#[no_mangle]
+pub extern "C" fn ar_mdns_name() -> *mut c_char {
+    guard("ar_mdns_name", std::ptr::null_mut(), || {
+        string_result(advertised_name().ok_or(AudioRemoteError::NotFound("not advertising".into())))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service() -> Service {
+        let config = AdvertiseConfig { name: "Studio Mac".into(), version: "2.6.0".into(), port: None, capabilities: default_capabilities() };
+        let mut service = Service::new(&config, 8765).unwrap();
+        service.host = "studio-audioremote.local".into();
+        service.addresses = vec!["192.168.1.20".parse().unwrap(), "fe80::1".parse().unwrap()];
+        service
+    }
+
+    fn query(name: &str, qtype: u16) -> Message {
+        Message { questions: vec![Question { name: name.into(), qtype, unicast: false }], ..Message::default() }
+    }
+
+    #[test]
+    fn test_answers() {
+        let service = service();
+        let browse = service.answer(&query("_AudioRemote._tcp.local", dns::TYPE_PTR)).unwrap();
+        assert_eq!(browse.answers, vec![service.ptr(SERVICE_TTL)]);
+        assert_eq!(browse.additionals.len(), 4);
+        let txt = match &browse.additionals[1].data {
+            RData::Txt(entries) => dns::parse_txt(entries),
+            other => panic!("{other:?}"),
+        };
+        assert_eq!(txt["version"], "2.6.0");
+        assert_eq!(txt["caps"], "volume,mute,devices,nowPlaying,events");
+
+        let resolve = service.answer(&query("Studio Mac._audioremote._tcp.local", dns::TYPE_SRV)).unwrap();
+        assert!(matches!(&resolve.answers[0].data, RData::Srv { port: 8765, target, .. } if target == "studio-audioremote.local"));
+        let aaaa = service.answer(&query("studio-audioremote.local", dns::TYPE_AAAA)).unwrap();
+        assert_eq!(aaaa.answers.len(), 1);
+        assert!(service.answer(&query("_other._tcp.local", dns::TYPE_PTR)).is_none());
+
+        // A browser that already knows us gets no answer
+        let mut known = query(SERVICE_TYPE, dns::TYPE_PTR);
+        known.answers.push(service.ptr(SERVICE_TTL));
+        assert!(service.answer(&known).is_none());
+
+        let goodbye = dns::decode(&dns::encode(&service.announcement(0))).unwrap();
+        assert!(goodbye.answers.iter().all(|record| record.ttl == 0));
+    }
+
+    #[test]
+    fn test_conflicts_rename() {
+        let mut service = service();
+        let mut ours = service.announcement(SERVICE_TTL);
+        assert!(!service.conflicts(&ours));
+        ours.answers[1].data = RData::Srv { priority: 0, weight: 0, port: 9000, target: "other.local".into() };
+        assert!(service.conflicts(&ours));
+        assert!(!service.conflicts(&service.probe()));
+
+        service.rename();
+        assert_eq!(service.instance, "Studio Mac (2)");
+        service.rename();
+        assert_eq!(service.instance, "Studio Mac (3)");
+        assert_eq!(instance_label(&"x".repeat(80)).len(), 63);
+        assert!(host_name().ends_with("audioremote.local"));
+    }
+}
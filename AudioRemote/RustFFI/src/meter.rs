@@ -0,0 +1,322 @@
+//! Level meters for the remote's UI, fed with float PCM from the Swift audio
+//! tap. Each channel keeps a peak and an RMS level with meter ballistics: the
+//! peak follows |sample| and the RMS follows the square root of the signal's
+//! power averaged over `rmsWindowMs`, each rising with the attack time
+//! constant and falling with the release one. An attack of 0 (the default)
+//! catches every peak; the release is what makes a meter readable rather
+//! than a flicker.
+//!
+//! Levels are gains (0.0–1.0, over 1.0 when clipping); ar_volume_convert turns
+//! them into dB. The channels are metered four at a time (see `simd`).
+
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::handle::{Handle, Registry};
+use crate::simd::{F32x4, LANES};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MeterConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default)]
+    pub attack_ms: f64,
+    #[serde(default = "default_release_ms")]
+    pub release_ms: f64,
+    #[serde(default = "default_rms_window_ms")]
+    pub rms_window_ms: f64,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> f64 {
+    48_000.0
+}
+
+fn default_release_ms() -> f64 {
+    300.0
+}
+
+fn default_rms_window_ms() -> f64 {
+    300.0
+}
+
+impl Default for MeterConfig {
+    fn default() -> Self {
+        Self {
+            channels: default_channels(),
+            sample_rate: default_sample_rate(),
+            attack_ms: 0.0,
+            release_ms: default_release_ms(),
+            rms_window_ms: default_rms_window_ms(),
+        }
+    }
+}
+
+/// How much of the old level a one-pole filter with time constant `ms` keeps each sample
+fn coefficient(ms: f64, sample_rate: f64) -> f32 {
+    match ms > 0.0 {
+        true => (-1000.0 / (ms * sample_rate)).exp() as f32,
+        false => 0.0,
+    }
+}
+
+/// Each channel's levels, a lane each, padded out to whole vectors
+#[derive(Debug, Clone)]
+struct Levels {
+    peaks: Vec<f32>,
+    mean_squares: Vec<f32>,
+    rms: Vec<f32>,
+}
+
+impl Levels {
+    fn silent(channels: usize) -> Self {
+        let lanes = channels.next_multiple_of(LANES);
+        Self { peaks: vec![0.0; lanes], mean_squares: vec![0.0; lanes], rms: vec![0.0; lanes] }
+    }
+}
+
+#[derive(Debug)]
+pub struct Meter {
+    attack: f32,
+    release: f32,
+    window: f32,
+    channels: usize,
+    levels: Levels,
+    /// The frame being metered, padded with silence
+    frame: Vec<f32>,
+}
+
+/// Move `level` toward `input`, faster on the way up
+fn follow(level: F32x4, input: F32x4, attack: F32x4, release: F32x4) -> F32x4 {
+    let keep = input.select_gt(level, attack, release);
+    input + (level - input) * keep
+}
+
+impl Meter {
+    pub fn new(config: &MeterConfig) -> Result<Self, AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=MAX_CHANNELS).contains(&config.channels) {
+            return invalid(format!("channels {} is outside 1–{MAX_CHANNELS}", config.channels));
+        }
+        if !(1000.0..=768_000.0).contains(&config.sample_rate) {
+            return invalid(format!("sampleRate {} is outside 1000–768000", config.sample_rate));
+        }
+        for (name, ms) in [("attackMs", config.attack_ms), ("releaseMs", config.release_ms), ("rmsWindowMs", config.rms_window_ms)] {
+            if !(0.0..=60_000.0).contains(&ms) {
+                return invalid(format!("{name} {ms} is outside 0–60000"));
+            }
+        }
+        Ok(Self {
+            attack: coefficient(config.attack_ms, config.sample_rate),
+            release: coefficient(config.release_ms, config.sample_rate),
+            window: coefficient(config.rms_window_ms, config.sample_rate),
+            channels: config.channels as usize,
+            levels: Levels::silent(config.channels as usize),
+            frame: vec![0.0; (config.channels as usize).next_multiple_of(LANES)],
+        })
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    /// Meter interleaved samples; a partial frame at the end is ignored. Samples that aren't
+    /// finite count as silence, so one bad buffer can't stick the meter.
+    pub fn process(&mut self, samples: &[f32]) {
+        let (attack, release, window) = (F32x4::splat(self.attack), F32x4::splat(self.release), F32x4::splat(self.window));
+        let Levels { peaks, mean_squares, rms } = &mut self.levels;
+        for frame in samples.chunks_exact(self.channels) {
+            self.frame[..self.channels].copy_from_slice(frame);
+            let lanes = self.frame.chunks_exact(LANES).zip(peaks.chunks_exact_mut(LANES));
+            for ((samples, peak), (mean_square, rms)) in lanes.zip(mean_squares.chunks_exact_mut(LANES).zip(rms.chunks_exact_mut(LANES))) {
+                let sample = F32x4::load(samples).finite_or_zero();
+                let square = sample * sample;
+                let new_mean_square = square + (F32x4::load(mean_square) - square) * window;
+                follow(F32x4::load(peak), sample.abs(), attack, release).store(peak);
+                follow(F32x4::load(rms), new_mean_square.sqrt(), attack, release).store(rms);
+                new_mean_square.store(mean_square);
+            }
+        }
+    }
+
+    /// Each channel's (peak, RMS)
+    pub fn levels(&self) -> impl Iterator<Item = (f32, f32)> + '_ {
+        self.levels.peaks.iter().zip(&self.levels.rms).take(self.channels).map(|(&peak, &rms)| (peak, rms))
+    }
+
+    pub fn reset(&mut self) {
+        self.levels = Levels::silent(self.channels);
+    }
+}
+
+static METERS: Registry<Mutex<Meter>> = Registry::new("meter");
+
+fn with_meter<T>(handle: Handle, body: impl FnOnce(&mut Meter) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let meter = METERS.get(handle)?;
+    let mut meter = meter.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut meter)
+}
+
+/// Create a level meter. `config_json` (nullable): {"channels" (default 2), "sampleRate"
+/// (default 48000), "attackMs" (default 0), "releaseMs" (default 300), "rmsWindowMs" (default 300)}
+/// Returns: a handle (free with ar_meter_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_meter_new(config_json: *const c_char) -> Handle {
+    guard("ar_meter_new", 0, || {
+        let config = match config_json.is_null() {
+            true => Ok(MeterConfig::default()),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid meter config: {e}")))
+            }),
+        };
+        match record(config.and_then(|config| Meter::new(&config))) {
+            Some(meter) => METERS.insert(Mutex::new(meter)),
+            None => 0,
+        }
+    })
+}
+
+/// Meter `frames` frames of interleaved float samples, one per channel per frame
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_meter_process(meter: Handle, samples: *const f32, frames: usize) -> i32 {
+    guard("ar_meter_process", -999, || {
+        let result = with_meter(meter, |meter| {
+            let len = frames
+                .checked_mul(meter.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len == 0 {
+                return Ok(1);
+            }
+            if samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            meter.process(std::slice::from_raw_parts(samples, len));
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Read each channel's peak and RMS level into `out_peaks` and `out_rms`, up to `capacity` channels
+/// Returns: the meter's channel count, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `out_peaks` and `out_rms` must each have room for `capacity` floats.
+#[no_mangle]
+pub unsafe extern "C" fn ar_meter_levels(meter: Handle, out_peaks: *mut f32, out_rms: *mut f32, capacity: usize) -> i32 {
+    guard("ar_meter_levels", -999, || {
+        let result = with_meter(meter, |meter| {
+            if capacity > 0 && (out_peaks.is_null() || out_rms.is_null()) {
+                return Err(AudioRemoteError::InvalidArgument("out_peaks and out_rms must not be null".into()));
+            }
+            for (i, (peak, rms)) in meter.levels().take(capacity).enumerate() {
+                *out_peaks.add(i) = peak;
+                *out_rms.add(i) = rms;
+            }
+            Ok(meter.channel_count() as i32)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Drop a meter's levels to silence, e.g. when the tap restarts
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_meter_reset(meter: Handle) -> i32 {
+    guard("ar_meter_reset", -999, || {
+        record(with_meter(meter, |meter| {
+            meter.reset();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Release a meter from ar_meter_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_meter_free(meter: Handle) -> i32 {
+    guard("ar_meter_free", -999, || record(METERS.remove(meter)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: usize = 48_000;
+
+    fn sine(frames: usize, amplitude: f32) -> Vec<f32> {
+        let step = 2.0 * std::f32::consts::PI * 1000.0 / RATE as f32;
+        (0..frames).flat_map(|i| [amplitude * (i as f32 * step).sin(), 0.0]).collect()
+    }
+
+    #[test]
+    fn test_levels_and_ballistics() {
+        let mut meter = Meter::new(&MeterConfig::default()).unwrap();
+        meter.process(&sine(RATE * 3, 0.5));
+        let levels: Vec<_> = meter.levels().collect();
+        assert!((levels[0].0 - 0.5).abs() < 1e-3, "{levels:?}");
+        assert!((levels[0].1 - 0.5 / 2f32.sqrt()).abs() < 5e-3, "{levels:?}");
+        assert_eq!(levels[1], (0.0, 0.0));
+
+        // After one release time of silence the peak is down to 1/e
+        meter.process(&vec![0.0; RATE * 3 / 10 * 2]);
+        let (peak, _) = meter.levels().next().unwrap();
+        assert!((peak - 0.5 / std::f32::consts::E).abs() < 1e-3, "{peak}");
+
+        // A slow attack takes one attack time to reach 1 - 1/e; bad samples are silence
+        let config = MeterConfig { channels: 1, attack_ms: 100.0, ..MeterConfig::default() };
+        let mut meter = Meter::new(&config).unwrap();
+        meter.process(&vec![1.0; RATE / 10]);
+        let (peak, _) = meter.levels().next().unwrap();
+        assert!((peak - (1.0 - 1.0 / std::f32::consts::E)).abs() < 1e-3, "{peak}");
+        meter.process(&[f32::NAN, f32::INFINITY]);
+        assert!(meter.levels().all(|(peak, rms)| peak.is_finite() && rms.is_finite()));
+        meter.reset();
+        assert_eq!(meter.levels().next(), Some((0.0, 0.0)));
+
+        assert!(Meter::new(&MeterConfig { channels: 0, ..MeterConfig::default() }).is_err());
+        assert!(Meter::new(&MeterConfig { release_ms: -1.0, ..MeterConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let meter = unsafe { ar_meter_new(c"{\"channels\": 2, \"sampleRate\": 44100}".as_ptr()) };
+        assert_ne!(meter, 0);
+        let samples = [0.25f32, -0.75, 0.1, 0.2, 0.5];
+        // Two whole frames; the trailing half frame isn't read
+        assert_eq!(unsafe { ar_meter_process(meter, samples.as_ptr(), 2) }, 1);
+        assert_eq!(unsafe { ar_meter_process(meter, std::ptr::null(), 0) }, 1);
+        assert_eq!(unsafe { ar_meter_process(meter, std::ptr::null(), 1) }, -999);
+
+        let (mut peaks, mut rms) = ([0f32; 4], [0f32; 4]);
+        assert_eq!(unsafe { ar_meter_levels(meter, peaks.as_mut_ptr(), rms.as_mut_ptr(), 4) }, 2);
+        assert!(peaks[0] > 0.24 && peaks[1] > 0.74 && peaks[2] == 0.0);
+        assert!(rms[0] > 0.0 && rms[0] < peaks[0]);
+        assert_eq!(unsafe { ar_meter_levels(meter, std::ptr::null_mut(), std::ptr::null_mut(), 0) }, 2);
+        assert_eq!(ar_meter_reset(meter), 1);
+
+        assert_eq!(ar_meter_free(meter), 1);
+        assert_eq!(unsafe { ar_meter_process(meter, samples.as_ptr(), 1) }, -999);
+        assert_eq!(ar_meter_free(meter), -999);
+        assert_eq!(unsafe { ar_meter_new(c"{\"channels\": 100}".as_ptr()) }, 0);
+    }
+}
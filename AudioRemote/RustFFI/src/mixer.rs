@@ -0,0 +1,260 @@
+//! Channel mixing: a matrix of gains from each input channel to each output
+//! channel, applied to interleaved float PCM. Used to fold 5.1 down to stereo,
+//! stereo down to one speaker, and for the "mono audio" accessibility setting,
+//! which plays the same mono mix on every output channel.
+//!
+//! Without an explicit matrix the usual mixes are chosen from the channel
+//! counts: 5.1 (L R C LFE Ls Rs) to stereo as in ITU-R BS.775, with the centre
+//! and surrounds at -3 dB and the LFE left out, and any count down to mono as
+//! the average of that. Unless `normalize` is off, an output whose gains add up
+//! to more than 1 is scaled down so the mix can't clip.
+
+use std::ffi::c_char;
+
+use serde::Deserialize;
+
+use crate::error::string_result;
+use crate::handle::{Handle, Registry};
+use crate::simd;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: usize = 64;
+/// -3 dB
+const MINUS_3_DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MixerConfig {
+    pub input_channels: usize,
+    pub output_channels: usize,
+    /// One row per output channel, of one gain per input channel
+    #[serde(default)]
+    pub matrix: Option<Vec<Vec<f32>>>,
+    /// Play the default mono mix on every output channel
+    #[serde(default)]
+    pub mono: bool,
+    #[serde(default = "default_normalize")]
+    pub normalize: bool,
+}
+
+fn default_normalize() -> bool {
+    true
+}
+
+/// The usual mix from `inputs` to `outputs` channels, if there is one
+fn default_matrix(inputs: usize, outputs: usize) -> Option<Vec<Vec<f32>>> {
+    let identity = |inputs: usize, outputs: usize| {
+        (0..outputs).map(|output| (0..inputs).map(|input| if input == output { 1.0 } else { 0.0 }).collect()).collect()
+    };
+    match (inputs, outputs) {
+        (inputs, outputs) if inputs == outputs => Some(identity(inputs, outputs)),
+        (6, 2) => Some(vec![vec![1.0, 0.0, MINUS_3_DB, 0.0, MINUS_3_DB, 0.0], vec![0.0, 1.0, MINUS_3_DB, 0.0, 0.0, MINUS_3_DB]]),
+        (6, 1) => {
+            let stereo = default_matrix(6, 2)?;
+            Some(vec![stereo[0].iter().zip(&stereo[1]).map(|(left, right)| (left + right) / 2.0).collect()])
+        }
+        (inputs, 1) => Some(vec![vec![1.0 / inputs as f32; inputs]]),
+        // Mono to both front speakers
+        (1, outputs) => Some((0..outputs).map(|output| vec![if output < 2 { 1.0 } else { 0.0 }]).collect()),
+        // Fewer channels to more: each to its own, e.g. stereo to the front pair of 5.1
+        (inputs, outputs) if inputs < outputs => Some(identity(inputs, outputs)),
+        _ => None,
+    }
+}
+
+pub struct Mixer {
+    inputs: usize,
+    matrix: Vec<Vec<f32>>,
+}
+
+impl Mixer {
+    pub fn new(config: &MixerConfig) -> Result<Self, AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        let (inputs, outputs) = (config.input_channels, config.output_channels);
+        if !(1..=MAX_CHANNELS).contains(&inputs) || !(1..=MAX_CHANNELS).contains(&outputs) {
+            return invalid(format!("{inputs} → {outputs} channels is outside 1–{MAX_CHANNELS}"));
+        }
+        let mut matrix = match (&config.matrix, config.mono) {
+            (Some(_), true) => return invalid("pass a matrix or mono, not both".into()),
+            (Some(matrix), false) => {
+                if matrix.len() != outputs || matrix.iter().any(|row| row.len() != inputs) {
+                    return invalid(format!("the matrix must have {outputs} rows of {inputs} gains"));
+                }
+                if matrix.iter().flatten().any(|gain| !(-16.0..=16.0).contains(gain)) {
+                    return invalid("matrix gains must be -16–16".into());
+                }
+                matrix.clone()
+            }
+            (None, true) => {
+                let mono = default_matrix(inputs, 1).expect("every count mixes to mono");
+                vec![mono[0].clone(); outputs]
+            }
+            (None, false) => match default_matrix(inputs, outputs) {
+                Some(matrix) => matrix,
+                None => return invalid(format!("there's no usual mix from {inputs} to {outputs} channels; pass a matrix")),
+            },
+        };
+        if config.normalize {
+            for row in &mut matrix {
+                let total: f32 = row.iter().map(|gain| gain.abs()).sum();
+                if total > 1.0 {
+                    row.iter_mut().for_each(|gain| *gain /= total);
+                }
+            }
+        }
+        Ok(Self { inputs, matrix })
+    }
+
+    pub fn input_channels(&self) -> usize {
+        self.inputs
+    }
+
+    pub fn output_channels(&self) -> usize {
+        self.matrix.len()
+    }
+
+    pub fn matrix(&self) -> &[Vec<f32>] {
+        &self.matrix
+    }
+
+    /// Mix interleaved `input` into `output`, frame by frame, for as many whole frames as both
+    /// hold; samples that aren't finite count as silence. Returns the frames mixed.
+    pub fn process(&self, input: &[f32], output: &mut [f32]) -> usize {
+        let mut frames = 0;
+        for (input, output) in input.chunks_exact(self.inputs).zip(output.chunks_exact_mut(self.matrix.len())) {
+            for (out, row) in output.iter_mut().zip(&self.matrix) {
+                *out = simd::dot_finite(row, input);
+            }
+            frames += 1;
+        }
+        frames
+    }
+}
+
+static MIXERS: Registry<Mixer> = Registry::new("mixer");
+
+/// Create a channel mixer. `config_json`: {"inputChannels", "outputChannels", "matrix" (one
+/// row of input gains per output channel; default: the usual mix for the counts), "mono"
+/// (the mono mix on every output), "normalize" (default true)}
+/// Returns: a handle (free with ar_mixer_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_mixer_new(config_json: *const c_char) -> Handle {
+    guard("ar_mixer_new", 0, || {
+        let config = str_arg(config_json, "config").and_then(|json| {
+            serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid mixer config: {e}")))
+        });
+        match record(config.and_then(|config| Mixer::new(&config))) {
+            Some(mixer) => MIXERS.insert(mixer),
+            None => 0,
+        }
+    })
+}
+
+/// Mix `frames` frames of interleaved `input` (frames × input channels floats) into `output`
+/// (frames × output channels floats)
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `input` and `output` must point to that many floats and not overlap, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_mixer_process(mixer: Handle, input: *const f32, frames: usize, output: *mut f32) -> i32 {
+    guard("ar_mixer_process", -999, || {
+        let result = MIXERS.get(mixer).and_then(|mixer| {
+            let too_many = || AudioRemoteError::InvalidArgument(format!("{frames} frames is too many"));
+            let input_len = frames.checked_mul(mixer.input_channels()).ok_or_else(too_many)?;
+            let output_len = frames.checked_mul(mixer.output_channels()).ok_or_else(too_many)?;
+            if frames == 0 {
+                return Ok(1);
+            }
+            if input.is_null() || output.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("input and output must not be null".into()));
+            }
+            mixer.process(std::slice::from_raw_parts(input, input_len), std::slice::from_raw_parts_mut(output, output_len));
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// The gains in use, after defaults and normalizing, e.g. to show them
+/// Returns: JSON [[gain per input] per output] (free with rust_string_free), NULL on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_mixer_matrix(mixer: Handle) -> *mut c_char {
+    guard("ar_mixer_matrix", std::ptr::null_mut(), || {
+        string_result(MIXERS.get(mixer).map(|mixer| serde_json::to_string(mixer.matrix()).unwrap_or_default()))
+    })
+}
+
+/// Release a mixer from ar_mixer_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_mixer_free(mixer: Handle) -> i32 {
+    guard("ar_mixer_free", -999, || record(MIXERS.remove(mixer)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+
+    fn mixer(inputs: usize, outputs: usize) -> MixerConfig {
+        MixerConfig { input_channels: inputs, output_channels: outputs, matrix: None, mono: false, normalize: true }
+    }
+
+    #[test]
+    fn test_default_mixes() {
+        // 5.1 to stereo: -3 dB centre and surrounds, no LFE, scaled so a full-scale mix can't clip
+        let surround = Mixer::new(&mixer(6, 2)).unwrap();
+        let total = 1.0 + 2.0 * MINUS_3_DB;
+        let mut stereo = [0.0; 4];
+        assert_eq!(surround.process(&[1.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0], &mut stereo), 2);
+        for (actual, expected) in stereo.iter().zip([(1.0 + MINUS_3_DB) / total, MINUS_3_DB / total, 0.0, MINUS_3_DB / total]) {
+            assert!((actual - expected).abs() < 1e-6, "{stereo:?}");
+        }
+        let unnormalized = Mixer::new(&MixerConfig { normalize: false, ..mixer(6, 2) }).unwrap();
+        assert_eq!(unnormalized.matrix()[0], [1.0, 0.0, MINUS_3_DB, 0.0, MINUS_3_DB, 0.0]);
+
+        let mut mono = [0.0; 2];
+        Mixer::new(&mixer(2, 1)).unwrap().process(&[0.5, 0.25, 1.0, f32::NAN], &mut mono);
+        assert_eq!(mono, [0.375, 0.5]);
+        let mut up = [0.0; 6];
+        Mixer::new(&mixer(1, 6)).unwrap().process(&[0.5], &mut up);
+        assert_eq!(up, [0.5, 0.5, 0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(Mixer::new(&mixer(2, 6)).unwrap().matrix()[1], [0.0, 1.0]);
+        assert!(Mixer::new(&mixer(8, 6)).is_err());
+    }
+
+    #[test]
+    fn test_mono_and_custom() {
+        // The accessibility setting: the same mono mix in both ears
+        let mono = Mixer::new(&MixerConfig { mono: true, ..mixer(2, 2) }).unwrap();
+        let mut output = [0.0; 4];
+        mono.process(&[1.0, 0.0, 0.0, -0.5], &mut output);
+        assert_eq!(output, [0.5, 0.5, -0.25, -0.25]);
+
+        // Swap the channels and halve the right
+        let custom = MixerConfig { matrix: Some(vec![vec![0.0, 1.0], vec![0.5, 0.0]]), ..mixer(2, 2) };
+        let mut output = [0.0; 2];
+        Mixer::new(&custom).unwrap().process(&[0.25, 1.0], &mut output);
+        assert_eq!(output, [1.0, 0.125]);
+        assert!(Mixer::new(&MixerConfig { matrix: Some(vec![vec![1.0]]), ..mixer(2, 2) }).is_err());
+        assert!(Mixer::new(&MixerConfig { mono: true, ..custom }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let mixer = unsafe { ar_mixer_new(c"{\"inputChannels\": 2, \"outputChannels\": 1}".as_ptr()) };
+        assert_ne!(mixer, 0);
+        assert_eq!(take_c_string(ar_mixer_matrix(mixer)).unwrap(), "[[0.5,0.5]]");
+        let (input, mut output) = ([1.0f32, 0.0, 0.0, 1.0], [0.0f32; 2]);
+        assert_eq!(unsafe { ar_mixer_process(mixer, input.as_ptr(), 2, output.as_mut_ptr()) }, 1);
+        assert_eq!(output, [0.5, 0.5]);
+        assert_eq!(unsafe { ar_mixer_process(mixer, input.as_ptr(), 1, std::ptr::null_mut()) }, -999);
+        assert_eq!(ar_mixer_free(mixer), 1);
+        assert_eq!(ar_mixer_free(mixer), -999);
+        assert_eq!(unsafe { ar_mixer_new(c"{\"inputChannels\": 3, \"outputChannels\": 2}".as_ptr()) }, 0);
+    }
+}
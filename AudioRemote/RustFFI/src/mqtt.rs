@@ -0,0 +1,997 @@
+//! MQTT for home automation (Home Assistant, Node-RED). The Mac connects to a
+//! broker as an MQTT 3.1.1 client and mirrors what the WebSocket channel
+//! pushes: every topic's state, retained, under a prefix
+//! (`audioremote/<host>` unless configured):
+//!
+//! - `<prefix>/volume`, `<prefix>/devices`, `<prefix>/nowPlaying`: the state
+//!   JSON, as on the WebSocket
+//! - `<prefix>/status`: `online`, or `offline` as the connection's last will
+//!
+//! and takes commands on `<prefix>/volume/set` (`0.4` or `{"volume": 0.4}`),
+//! `<prefix>/mute/set` (`true`/`false`, `ON`/`OFF` or `{"muted": true}`) and
+//! `<prefix>/device/set` (a device ID or `{"id": ...}`), which go to Swift the
+//! same way REST requests do. Whoever may publish to the broker may use them,
+//! so brokers should be secured with TLS and a username and password. With
+//! `homeAssistant` set it also sends the discovery configs in `homeassistant`.
+//!
+//! One supervisor thread owns the connection and reconnects with backoff, like
+//! `connection` does for peers; a second writes state while it reads, and
+//! commands run on their own thread so a slow handler never stalls pings.
+
+use std::ffi::c_char;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::connection::{self, Backoff, ConnectionState};
+use crate::error::string_result;
+use crate::events::{Subscription, Topic, HUB};
+use crate::homeassistant::{self, Discovery};
+use crate::remote::OutputDevice;
+use crate::routes::{self, Endpoint};
+use crate::server::Stream;
+use crate::tls::{self, TlsStream};
+use crate::{guard, http, interfaces, mdns, pairing, record, str_arg, AudioRemoteError};
+
+pub const DEFAULT_PORT: u16 = 1883;
+pub const DEFAULT_TLS_PORT: u16 = 8883;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_PACKET_BYTES: usize = 256 * 1024;
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+const SUBSCRIBE_ID: u16 = 1;
+
+// Control packet types, already shifted into the high nibble
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x80;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+fn default_keep_alive() -> u16 {
+    30
+}
+
+fn default_retain() -> bool {
+    true
+}
+
+fn default_initial_backoff() -> u64 {
+    1_000
+}
+
+fn default_max_backoff() -> u64 {
+    60_000
+}
+
+/// `ar_mqtt_start` configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MqttConfig {
+    pub host: String,
+    /// Default 1883, or 8883 with TLS
+    pub port: Option<u16>,
+    /// Default "audioremote-" and the start of this Mac's pairing ID
+    pub client_id: Option<String>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub tls: bool,
+    /// With TLS, trust only the broker certificate with this SHA-256 fingerprint
+    pub cert_fingerprint: Option<String>,
+    /// With TLS, a PEM bundle of CAs to trust besides the public ones
+    pub ca_file: Option<PathBuf>,
+    /// Default "audioremote/<host>"
+    pub topic_prefix: Option<String>,
+    #[serde(default = "default_keep_alive")]
+    pub keep_alive_secs: u16,
+    /// Retain state messages, so new subscribers see the current state at once
+    #[serde(default = "default_retain")]
+    pub retain: bool,
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff_ms: u64,
+    /// Publish Home Assistant discovery configs
+    #[serde(default)]
+    pub home_assistant: bool,
+    /// Default "homeassistant", as in Home Assistant's MQTT settings
+    pub discovery_prefix: Option<String>,
+}
+
+impl MqttConfig {
+    fn validate(&self) -> Result<(), AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if self.host.trim().is_empty() {
+            return invalid("host is empty".into());
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return invalid("a password needs a username".into());
+        }
+        if !self.tls && (self.cert_fingerprint.is_some() || self.ca_file.is_some()) {
+            return invalid("certFingerprint and caFile need tls".into());
+        }
+        if !(5..=3600).contains(&self.keep_alive_secs) {
+            return invalid(format!("keepAliveSecs must be 5 to 3600, not {}", self.keep_alive_secs));
+        }
+        if self.initial_backoff_ms == 0 || self.max_backoff_ms < self.initial_backoff_ms {
+            return invalid("maxBackoffMs must be at least initialBackoffMs".into());
+        }
+        if let Some(prefix) = &self.topic_prefix {
+            validate_prefix(prefix)?;
+        }
+        if let Some(prefix) = &self.discovery_prefix {
+            if !self.home_assistant {
+                return invalid("discoveryPrefix needs homeAssistant".into());
+            }
+            validate_prefix(prefix)?;
+        }
+        Ok(())
+    }
+
+    fn port(&self) -> u16 {
+        self.port.unwrap_or(if self.tls { DEFAULT_TLS_PORT } else { DEFAULT_PORT })
+    }
+
+    fn client_config(&self) -> Result<Option<Arc<rustls::ClientConfig>>, AudioRemoteError> {
+        if !self.tls {
+            return Ok(None);
+        }
+        if let Some(fingerprint) = &self.cert_fingerprint {
+            return tls::pinned_client_config(fingerprint).map(Some);
+        }
+        let extra_roots = match &self.ca_file {
+            Some(path) => http::load_ca_bundle(path)?,
+            None => Vec::new(),
+        };
+        http::tls_config(&extra_roots).map(|config| Some(Arc::new(config)))
+    }
+}
+
+/// A prefix is a plain topic name: no wildcards, no empty levels at either end
+fn validate_prefix(prefix: &str) -> Result<(), AudioRemoteError> {
+    if prefix.is_empty() || prefix.starts_with('/') || prefix.ends_with('/') || prefix.contains(['+', '#', '\0']) {
+        return Err(AudioRemoteError::InvalidArgument(format!("\"{prefix}\" isn't a usable topic prefix")));
+    }
+    Ok(())
+}
+
+/// "audioremote/studio" on a Mac named Studio
+fn default_prefix() -> String {
+    let host = mdns::host_name();
+    match host.trim_end_matches(".local").trim_end_matches("audioremote").trim_end_matches('-') {
+        "" => "audioremote".into(),
+        label => format!("audioremote/{}", label.to_ascii_lowercase()),
+    }
+}
+
+/// Where each message goes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topics {
+    pub prefix: String,
+}
+
+impl Topics {
+    pub fn state(&self, topic: Topic) -> String {
+        format!("{}/{}", self.prefix, topic.name())
+    }
+
+    pub fn status(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    pub fn command(&self, name: &str) -> String {
+        format!("{}/{name}/set", self.prefix)
+    }
+
+    fn command_filter(&self) -> String {
+        self.command("+")
+    }
+}
+
+fn command_error(message: String) -> AudioRemoteError {
+    AudioRemoteError::InvalidArgument(message)
+}
+
+fn parse_bool(payload: &str) -> Option<bool> {
+    match payload.to_ascii_lowercase().as_str() {
+        "true" | "on" | "1" => Some(true),
+        "false" | "off" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+/// Turn a message on a command topic into an endpoint and its REST request body
+pub fn parse_command(topics: &Topics, topic: &str, payload: &[u8]) -> Result<(Endpoint, Vec<u8>), AudioRemoteError> {
+    let text = std::str::from_utf8(payload).map_err(|_| command_error(format!("the payload on {topic} isn't UTF-8")))?.trim();
+    let body = if text.starts_with('{') { Some(text.as_bytes().to_vec()) } else { None };
+    if topic == topics.command("volume") {
+        let body = match body {
+            Some(body) => body,
+            None => {
+                let volume: f64 = text.parse().map_err(|_| command_error(format!("\"{text}\" isn't a volume")))?;
+                json!({"volume": volume}).to_string().into_bytes()
+            }
+        };
+        return Ok((Endpoint::SetVolume, body));
+    }
+    if topic == topics.command("mute") {
+        let body = match body {
+            Some(body) => body,
+            None => {
+                let muted = parse_bool(text).ok_or_else(|| command_error(format!("\"{text}\" isn't true/false or ON/OFF")))?;
+                json!({"muted": muted}).to_string().into_bytes()
+            }
+        };
+        return Ok((Endpoint::SetMute, body));
+    }
+    if topic == topics.command("device") {
+        return Ok((Endpoint::SelectDevice, body.unwrap_or_else(|| json!({"id": text}).to_string().into_bytes())));
+    }
+    Err(command_error(format!("no command at {topic}")))
+}
+
+/// An incoming control packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Packet {
+    ConnAck { session_present: bool, code: u8 },
+    Publish { topic: String, payload: Vec<u8>, qos: u8, retain: bool, packet_id: Option<u16> },
+    PubAck(u16),
+    SubAck { packet_id: u16, codes: Vec<u8> },
+    PingResp,
+    /// Anything a client doesn't act on, by its first byte
+    Other(u8),
+}
+
+fn invalid_packet(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid MQTT packet: {message}"))
+}
+
+fn put_length(buffer: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        buffer.push(byte);
+        if length == 0 {
+            return;
+        }
+    }
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(bytes);
+}
+
+/// A packet with its fixed header
+fn packet(first: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![first];
+    put_length(&mut packet, body.len());
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// What a CONNECT says
+pub(crate) struct Connect<'a> {
+    pub client_id: &'a str,
+    pub keep_alive: u16,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    /// Topic and message the broker publishes, retained, if the connection drops
+    pub will: Option<(&'a str, &'a [u8])>,
+}
+
+pub(crate) fn connect_packet(connect: &Connect) -> Vec<u8> {
+    // Clean session: subscriptions are made again on every connection
+    let mut flags = 0x02;
+    if connect.will.is_some() {
+        flags |= 0x04 | 0x20;
+    }
+    if connect.password.is_some() {
+        flags |= 0x40;
+    }
+    if connect.username.is_some() {
+        flags |= 0x80;
+    }
+    let mut body = Vec::new();
+    put_bytes(&mut body, b"MQTT");
+    body.push(4);
+    body.push(flags);
+    body.extend_from_slice(&connect.keep_alive.to_be_bytes());
+    put_bytes(&mut body, connect.client_id.as_bytes());
+    if let Some((topic, message)) = connect.will {
+        put_bytes(&mut body, topic.as_bytes());
+        put_bytes(&mut body, message);
+    }
+    if let Some(username) = connect.username {
+        put_bytes(&mut body, username.as_bytes());
+    }
+    if let Some(password) = connect.password {
+        put_bytes(&mut body, password.as_bytes());
+    }
+    packet(CONNECT, &body)
+}
+
+/// A QoS 0 publish
+pub(crate) fn publish_packet(topic: &str, payload: &[u8], retain: bool) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+    put_bytes(&mut body, topic.as_bytes());
+    body.extend_from_slice(payload);
+    packet(PUBLISH | retain as u8, &body)
+}
+
+pub(crate) fn subscribe_packet(packet_id: u16, filters: &[(&str, u8)]) -> Vec<u8> {
+    let mut body = packet_id.to_be_bytes().to_vec();
+    for (filter, qos) in filters {
+        put_bytes(&mut body, filter.as_bytes());
+        body.push(*qos);
+    }
+    packet(SUBSCRIBE | 0x02, &body)
+}
+
+fn puback_packet(packet_id: u16) -> Vec<u8> {
+    packet(PUBACK, &packet_id.to_be_bytes())
+}
+
+/// Read one packet's first byte and body
+pub(crate) fn read_frame(reader: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut first = [0; 1];
+    reader.read_exact(&mut first)?;
+    let mut length = 0usize;
+    for shift in 0..4 {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte)?;
+        length |= usize::from(byte[0] & 0x7f) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            if length > MAX_PACKET_BYTES {
+                return Err(invalid_packet("too big"));
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body)?;
+            return Ok((first[0], body));
+        }
+    }
+    Err(invalid_packet("the remaining length runs over 4 bytes"))
+}
+
+fn take_u16(body: &[u8], at: usize) -> io::Result<u16> {
+    body.get(at..at + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]])).ok_or_else(|| invalid_packet("cut short"))
+}
+
+pub(crate) fn read_packet(reader: &mut impl Read) -> io::Result<Packet> {
+    let (first, body) = read_frame(reader)?;
+    Ok(match first & 0xf0 {
+        CONNACK if body.len() == 2 => Packet::ConnAck { session_present: body[0] & 1 != 0, code: body[1] },
+        CONNACK => return Err(invalid_packet("CONNACK isn't 2 bytes")),
+        PUBLISH => {
+            let qos = (first >> 1) & 0x03;
+            let topic_length = usize::from(take_u16(&body, 0)?);
+            let topic = body.get(2..2 + topic_length).ok_or_else(|| invalid_packet("cut short"))?;
+            let topic = String::from_utf8(topic.to_vec()).map_err(|_| invalid_packet("the topic isn't UTF-8"))?;
+            let (packet_id, payload_start) = match qos {
+                0 => (None, 2 + topic_length),
+                1 | 2 => (Some(take_u16(&body, 2 + topic_length)?), 4 + topic_length),
+                _ => return Err(invalid_packet("QoS 3")),
+            };
+            Packet::Publish { topic, payload: body[payload_start..].to_vec(), qos, retain: first & 1 != 0, packet_id }
+        }
+        PUBACK => Packet::PubAck(take_u16(&body, 0)?),
+        SUBACK => Packet::SubAck { packet_id: take_u16(&body, 0)?, codes: body[2..].to_vec() },
+        PINGRESP => Packet::PingResp,
+        _ => Packet::Other(first),
+    })
+}
+
+/// Why an attempt failed
+enum Failure {
+    Retry(String),
+    Permanent(String),
+}
+
+fn connack_failure(code: u8) -> Failure {
+    match code {
+        1 => Failure::Permanent("the broker doesn't speak MQTT 3.1.1".into()),
+        2 => Failure::Permanent("the broker rejected the client ID".into()),
+        3 => Failure::Retry("the broker is unavailable".into()),
+        4 => Failure::Permanent("the broker rejected the username or password".into()),
+        5 => Failure::Permanent("the broker doesn't authorize this client".into()),
+        code => Failure::Permanent(format!("the broker refused the connection ({code})")),
+    }
+}
+
+fn attempt_failed(error: io::Error) -> Failure {
+    match connection::is_certificate_error(&error) {
+        true => Failure::Permanent(format!("the broker's certificate isn't trusted: {error}")),
+        false => Failure::Retry(error.to_string()),
+    }
+}
+
+/// Connect and sign in; the stream is ready for SUBSCRIBE
+fn connect(config: &MqttConfig, topics: &Topics, client_id: &str, tls: Option<&Arc<rustls::ClientConfig>>) -> Result<Stream, Failure> {
+    let port = config.port();
+    let addresses = interfaces::resolve(&config.host, port).map_err(|e| Failure::Retry(format!("can't resolve {}: {e}", config.host)))?;
+    let mut last_error = format!("{} has no addresses", config.host);
+    let socket = addresses
+        .into_iter()
+        .find_map(|address| match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                last_error = format!("can't connect to {address}: {e}");
+                None
+            }
+        })
+        .ok_or(Failure::Retry(last_error))?;
+    let _ = socket.set_nodelay(true);
+    socket.set_read_timeout(Some(CONNECT_TIMEOUT)).map_err(attempt_failed)?;
+    let mut stream = match tls {
+        Some(tls) => Stream::Tls(TlsStream::client(socket, tls.clone(), &config.host).map_err(attempt_failed)?),
+        None => Stream::Plain(socket),
+    };
+    let status = topics.status();
+    let connect = Connect {
+        client_id,
+        keep_alive: config.keep_alive_secs,
+        username: config.username.as_deref(),
+        password: config.password.as_deref(),
+        will: Some((&status, OFFLINE.as_bytes())),
+    };
+    stream.write_all(&connect_packet(&connect)).map_err(attempt_failed)?;
+    match read_packet(&mut stream).map_err(attempt_failed)? {
+        Packet::ConnAck { code: 0, .. } => Ok(stream),
+        Packet::ConnAck { code, .. } => Err(connack_failure(code)),
+        other => Err(Failure::Retry(format!("the broker answered CONNECT with {other:?}"))),
+    }
+}
+
+/// A connected session, for stop() to end
+struct Live {
+    writer: Arc<Mutex<Stream>>,
+    subscription: Arc<Subscription>,
+}
+
+#[derive(Debug, Clone)]
+struct Status {
+    state: ConnectionState,
+    attempt: u32,
+    error: Option<String>,
+}
+
+struct Shared {
+    config: MqttConfig,
+    topics: Topics,
+    client_id: String,
+    discovery: Option<Discovery>,
+    /// The last devices state, for the output select's options
+    devices: Mutex<Vec<OutputDevice>>,
+    stopping: AtomicBool,
+    /// Wakes a backoff wait when stopping
+    wake: (Mutex<()>, Condvar),
+    live: Mutex<Option<Live>>,
+    status: Mutex<Status>,
+}
+
+impl Shared {
+    fn set_status(&self, state: ConnectionState, attempt: u32, error: Option<String>) {
+        log::debug!("MQTT {}: {state:?} {}", self.config.host, error.as_deref().unwrap_or_default());
+        *self.status.lock().unwrap_or_else(|e| e.into_inner()) = Status { state, attempt, error };
+    }
+
+    fn live(&self) -> std::sync::MutexGuard<'_, Option<Live>> {
+        self.live.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait out a backoff delay; false if stopped meanwhile
+    fn wait(&self, delay: Duration) -> bool {
+        let (lock, condvar) = &self.wake;
+        let guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = condvar.wait_timeout_while(guard, delay, |_| !self.stopping.load(Ordering::Acquire)).unwrap_or_else(|e| e.into_inner());
+        !self.stopping.load(Ordering::Acquire)
+    }
+}
+
+fn write(writer: &Mutex<Stream>, bytes: &[u8]) -> io::Result<()> {
+    let mut stream = writer.lock().unwrap_or_else(|e| e.into_inner());
+    stream.write_all(bytes)?;
+    stream.flush()
+}
+
+/// Send the Home Assistant discovery configs, if enabled; the output select waits for the devices
+fn publish_discovery(shared: &Shared, writer: &Mutex<Stream>) -> io::Result<()> {
+    let Some(discovery) = &shared.discovery else {
+        return Ok(());
+    };
+    let devices = shared.devices.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let output = (!devices.is_empty()).then(|| discovery.output_message(&shared.topics, &devices));
+    for (topic, config) in discovery.messages(&shared.topics).into_iter().chain(output) {
+        write(writer, &publish_packet(&topic, config.to_string().as_bytes(), true))?;
+    }
+    Ok(())
+}
+
+/// Publish state until the subscription closes; pings when there's nothing to say
+fn push(shared: &Shared, subscription: &Subscription, writer: &Mutex<Stream>) -> io::Result<()> {
+    let keep_alive = Duration::from_secs(u64::from(shared.config.keep_alive_secs));
+    while let Some(events) = subscription.next(keep_alive) {
+        if events.is_empty() {
+            write(writer, &packet(PINGREQ, &[]))?;
+        }
+        for event in events {
+            if let (Some(discovery), Topic::Devices) = (&shared.discovery, event.topic) {
+                // New devices are new options
+                let devices: Vec<OutputDevice> = serde_json::from_value(event.data.clone()).unwrap_or_default();
+                let (topic, config) = discovery.output_message(&shared.topics, &devices);
+                *shared.devices.lock().unwrap_or_else(|e| e.into_inner()) = devices;
+                write(writer, &publish_packet(&topic, config.to_string().as_bytes(), true))?;
+            }
+            let payload = event.data.to_string();
+            write(writer, &publish_packet(&shared.topics.state(event.topic), payload.as_bytes(), shared.config.retain))?;
+        }
+    }
+    Ok(())
+}
+
+/// Run one connected session; Err is why it was lost
+fn run_session(shared: &Shared, stream: Stream, commands: &Sender<(String, Vec<u8>)>) -> Result<(), String> {
+    let lost = |e: io::Error| e.to_string();
+    let writer = Arc::new(Mutex::new(stream.try_clone().map_err(lost)?));
+    let command_filter = shared.topics.command_filter();
+    let mut filters = vec![(command_filter.as_str(), 1)];
+    let discovery_status = shared.discovery.as_ref().map(Discovery::status_topic);
+    filters.extend(discovery_status.as_deref().map(|topic| (topic, 1)));
+    write(&writer, &subscribe_packet(SUBSCRIBE_ID, &filters)).map_err(lost)?;
+    write(&writer, &publish_packet(&shared.topics.status(), ONLINE.as_bytes(), true)).map_err(lost)?;
+    publish_discovery(shared, &writer).map_err(lost)?;
+    // The broker answers a ping within the keep-alive; nothing for half as long again means it's gone
+    let keep_alive = u64::from(shared.config.keep_alive_secs);
+    stream.set_read_timeout(Some(Duration::from_secs(keep_alive + keep_alive / 2))).map_err(lost)?;
+
+    let subscription = Arc::new(HUB.subscribe(Topic::ALL.into_iter().collect()));
+    *shared.live() = Some(Live { writer: writer.clone(), subscription: subscription.clone() });
+    // stop() may have looked for the session before it was there
+    let result = match shared.stopping.load(Ordering::Acquire) {
+        true => Ok(()),
+        false => read_until_lost(shared, stream, &writer, &subscription, commands),
+    };
+    shared.live().take();
+    let _ = writer.lock().unwrap_or_else(|e| e.into_inner()).shutdown(Shutdown::Both);
+    match shared.stopping.load(Ordering::Acquire) {
+        true => Ok(()),
+        false => result,
+    }
+}
+
+/// Read packets, publishing state from a second thread, until the connection fails
+fn read_until_lost(
+    shared: &Shared,
+    stream: Stream,
+    writer: &Mutex<Stream>,
+    subscription: &Subscription,
+    commands: &Sender<(String, Vec<u8>)>,
+) -> Result<(), String> {
+    thread::scope(|scope| {
+        let pusher = thread::Builder::new().name("audioremote-mqtt-push".into()).spawn_scoped(scope, || {
+            if push(shared, subscription, writer).is_err() {
+                // Unblock the reader too
+                let _ = writer.lock().unwrap_or_else(|e| e.into_inner()).shutdown(Shutdown::Both);
+            }
+        });
+        let pusher = match pusher {
+            Ok(pusher) => pusher,
+            Err(e) => return Err(format!("can't start the publishing thread: {e}")),
+        };
+        let mut reader = BufReader::new(stream);
+        let result = loop {
+            match read_packet(&mut reader) {
+                Ok(Packet::Publish { topic, payload, packet_id, .. }) => {
+                    if let Some(packet_id) = packet_id {
+                        if let Err(e) = write(writer, &puback_packet(packet_id)) {
+                            break Err(e.to_string());
+                        }
+                    }
+                    // Home Assistant restarted and forgot what it was told
+                    if shared.discovery.as_ref().is_some_and(|discovery| topic == discovery.status_topic()) {
+                        if payload == homeassistant::BIRTH_MESSAGE.as_bytes() {
+                            if let Err(e) = publish_discovery(shared, writer) {
+                                break Err(e.to_string());
+                            }
+                        }
+                        continue;
+                    }
+                    let _ = commands.send((topic, payload));
+                }
+                Ok(Packet::SubAck { codes, .. }) if codes.contains(&0x80) => {
+                    log::warn!("the MQTT broker refused the subscription to {}", shared.topics.command_filter());
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Err("the broker closed the connection".into()),
+                Err(e) => break Err(e.to_string()),
+            }
+        };
+        subscription.close();
+        let _ = pusher.join();
+        result
+    })
+}
+
+fn supervise(shared: Arc<Shared>, commands: Sender<(String, Vec<u8>)>) {
+    let tls = match shared.config.client_config() {
+        Ok(tls) => tls,
+        Err(e) => return shared.set_status(ConnectionState::Failed, 0, Some(e.to_string())),
+    };
+    let mut backoff = Backoff::new(Duration::from_millis(shared.config.initial_backoff_ms), Duration::from_millis(shared.config.max_backoff_ms));
+    let mut attempt = 0;
+    while !shared.stopping.load(Ordering::Acquire) {
+        let error = match connect(&shared.config, &shared.topics, &shared.client_id, tls.as_ref()) {
+            Ok(stream) => {
+                backoff.reset();
+                attempt = 0;
+                shared.set_status(ConnectionState::Connected, 0, None);
+                log::info!("connected to the MQTT broker at {}", shared.config.host);
+                match run_session(&shared, stream, &commands) {
+                    Ok(()) => break,
+                    Err(reason) => reason,
+                }
+            }
+            Err(Failure::Permanent(message)) => {
+                log::error!("MQTT: {message}");
+                return shared.set_status(ConnectionState::Failed, attempt, Some(message));
+            }
+            Err(Failure::Retry(reason)) => reason,
+        };
+        attempt += 1;
+        let delay = backoff.next(connection::jitter());
+        log::warn!("MQTT connection lost ({error}); retrying in {} ms", delay.as_millis());
+        shared.set_status(ConnectionState::Reconnecting, attempt, Some(error));
+        if !shared.wait(delay) {
+            break;
+        }
+    }
+    shared.set_status(ConnectionState::Closed, 0, None);
+}
+
+/// Hand commands to Swift one at a time, in the order they arrived
+fn run_commands(topics: Topics, commands: Receiver<(String, Vec<u8>)>) {
+    for (topic, payload) in commands {
+        let outcome = parse_command(&topics, &topic, &payload)
+            .map_err(|e| e.to_string())
+            .and_then(|(endpoint, body)| routes::perform(endpoint, &body).map_err(|(status, message)| format!("{status}: {message}")));
+        if let Err(message) = outcome {
+            log::warn!("MQTT command on {topic} failed: {message}");
+        }
+    }
+}
+
+struct Running {
+    shared: Arc<Shared>,
+    supervisor: JoinHandle<()>,
+}
+
+static MQTT: Mutex<Option<Running>> = Mutex::new(None);
+
+/// Connect to a broker, replacing any running client
+pub fn start(config: MqttConfig) -> Result<(), AudioRemoteError> {
+    config.validate()?;
+    stop();
+    let topics = Topics { prefix: config.topic_prefix.clone().unwrap_or_else(default_prefix) };
+    let client_id = match &config.client_id {
+        Some(client_id) => client_id.clone(),
+        None => format!("audioremote-{}", &pairing::mac_id()?[..11]),
+    };
+    let discovery = config.home_assistant.then(|| Discovery {
+        prefix: config.discovery_prefix.clone().unwrap_or_else(|| homeassistant::DEFAULT_DISCOVERY_PREFIX.into()),
+        node_id: homeassistant::node_id(&client_id),
+        name: homeassistant::device_name(),
+    });
+    let shared = Arc::new(Shared {
+        config,
+        topics: topics.clone(),
+        client_id,
+        discovery,
+        devices: Mutex::new(Vec::new()),
+        stopping: AtomicBool::new(false),
+        wake: (Mutex::new(()), Condvar::new()),
+        live: Mutex::new(None),
+        status: Mutex::new(Status { state: ConnectionState::Connecting, attempt: 0, error: None }),
+    });
+    let spawn_failed = |e: io::Error| AudioRemoteError::Other(format!("can't start the MQTT thread: {e}"));
+    let (sender, commands) = mpsc::channel();
+    // Ends once the supervisor drops its sender
+    thread::Builder::new().name("audioremote-mqtt-commands".into()).spawn(move || run_commands(topics, commands)).map_err(spawn_failed)?;
+    let supervisor = {
+        let shared = shared.clone();
+        thread::Builder::new().name("audioremote-mqtt".into()).spawn(move || supervise(shared, sender)).map_err(spawn_failed)?
+    };
+    *MQTT.lock().unwrap_or_else(|e| e.into_inner()) = Some(Running { shared, supervisor });
+    Ok(())
+}
+
+/// Disconnect cleanly, so the broker doesn't publish the last will; a no-op if not running
+pub fn stop() {
+    let Some(running) = MQTT.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    let shared = &running.shared;
+    shared.stopping.store(true, Ordering::Release);
+    {
+        let _wake = shared.wake.0.lock().unwrap_or_else(|e| e.into_inner());
+        shared.wake.1.notify_all();
+    }
+    if let Some(live) = shared.live().as_ref() {
+        let _ = write(&live.writer, &publish_packet(&shared.topics.status(), OFFLINE.as_bytes(), true));
+        let _ = write(&live.writer, &packet(DISCONNECT, &[]));
+        let _ = live.writer.lock().unwrap_or_else(|e| e.into_inner()).shutdown(Shutdown::Both);
+        live.subscription.close();
+    }
+    let _ = running.supervisor.join();
+    log::info!("MQTT client stopped");
+}
+
+/// `{"state": ArConnectionState, "attempt", "error", "topicPrefix"}`
+pub fn status() -> Value {
+    match MQTT.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(running) => {
+            let status = running.shared.status.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            json!({
+                "state": status.state as i32,
+                "attempt": status.attempt,
+                "error": status.error,
+                "topicPrefix": running.shared.topics.prefix,
+            })
+        }
+        None => json!({"state": ConnectionState::Closed as i32, "attempt": 0, "error": null, "topicPrefix": null}),
+    }
+}
+
+/// Connect to an MQTT broker and mirror state to it, replacing a running client. `config_json`:
+/// {"host", "port" (1883, 8883 with TLS), "clientId", "username", "password", "tls" (false),
+/// "certFingerprint" (pin the broker's certificate), "caFile" (PEM bundle of extra CAs),
+/// "topicPrefix" ("audioremote/<host>"), "keepAliveSecs" (30), "retain" (true),
+/// "initialBackoffMs" (1000), "maxBackoffMs" (60000), "homeAssistant" (false: send discovery
+/// configs), "discoveryPrefix" ("homeassistant")}. Runs until ar_mqtt_stop, reconnecting.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_mqtt_start(config_json: *const c_char) -> i32 {
+    guard("ar_mqtt_start", -999, || {
+        let result = str_arg(config_json, "config").and_then(|json| {
+            let config: MqttConfig =
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid MQTT config: {e}")))?;
+            start(config)
+        });
+        record(result).map_or(-999, |()| 1)
+    })
+}
+
+/// Publish "offline", disconnect and stop reconnecting; a no-op if not running. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_mqtt_stop() {
+    guard("ar_mqtt_stop", (), stop)
+}
+
+/// Returns: JSON {"state": ArConnectionState, "attempt", "error", "topicPrefix"}, state
+/// AR_CONNECTION_CLOSED when not started (free with rust_string_free)
+#[no_mangle]
+pub extern "C" fn ar_mqtt_status() -> *mut c_char {
+    guard("ar_mqtt_status", std::ptr::null_mut(), || string_result(Ok(status().to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    fn topics() -> Topics {
+        Topics { prefix: "audioremote/studio".into() }
+    }
+
+    #[test]
+    fn test_packets() {
+        for length in [0, 127, 128, 16_383, 16_384, 2_097_151, 2_097_152, 268_435_455] {
+            let mut encoded = Vec::new();
+            put_length(&mut encoded, length);
+            assert_eq!(encoded.len(), 1 + usize::from(length > 127) + usize::from(length > 16_383) + usize::from(length > 2_097_151));
+        }
+        let mut encoded = Vec::new();
+        put_length(&mut encoded, 321);
+        assert_eq!(encoded, [0xc1, 0x02]);
+
+        let connect = Connect { client_id: "mac", keep_alive: 30, username: Some("u"), password: Some("p"), will: Some(("s", b"offline")) };
+        let bytes = connect_packet(&connect);
+        assert_eq!(bytes[..12], [0x10, 33, 0, 4, b'M', b'Q', b'T', b'T', 4, 0xe6, 0, 30]);
+        assert_eq!(read_frame(&mut bytes.as_slice()).unwrap().1.len(), 33);
+
+        let publish = publish_packet("a/b", b"{}", true);
+        assert_eq!(publish, [0x31, 7, 0, 3, b'a', b'/', b'b', b'{', b'}']);
+        let decoded = read_packet(&mut publish.as_slice()).unwrap();
+        assert_eq!(decoded, Packet::Publish { topic: "a/b".into(), payload: b"{}".to_vec(), qos: 0, retain: true, packet_id: None });
+        let qos1 = [0x32, 9, 0, 3, b'a', b'/', b'b', 0, 7, b'o', b'n'];
+        let decoded = read_packet(&mut qos1.as_slice()).unwrap();
+        assert_eq!(decoded, Packet::Publish { topic: "a/b".into(), payload: b"on".to_vec(), qos: 1, retain: false, packet_id: Some(7) });
+
+        assert_eq!(subscribe_packet(1, &[("x/+/set", 1)]), [0x82, 12, 0, 1, 0, 7, b'x', b'/', b'+', b'/', b's', b'e', b't', 1]);
+        assert_eq!(read_packet(&mut [0x20, 2, 0, 5].as_slice()).unwrap(), Packet::ConnAck { session_present: false, code: 5 });
+        assert_eq!(read_packet(&mut [0x90, 3, 0, 1, 0x80].as_slice()).unwrap(), Packet::SubAck { packet_id: 1, codes: vec![0x80] });
+        assert!(read_packet(&mut [0x30, 0xff, 0xff, 0xff, 0xff].as_slice()).is_err());
+        assert!(read_packet(&mut [0x32, 3, 0, 3, b'a'].as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_commands() {
+        let topics = topics();
+        let parse = |name: &str, payload: &str| {
+            parse_command(&topics, &topics.command(name), payload.as_bytes()).map(|(e, body)| (e, String::from_utf8(body).unwrap()))
+        };
+        assert_eq!(parse("volume", "0.4").unwrap(), (Endpoint::SetVolume, r#"{"volume":0.4}"#.into()));
+        assert_eq!(parse("volume", r#"{"volume": 1}"#).unwrap(), (Endpoint::SetVolume, r#"{"volume": 1}"#.into()));
+        assert_eq!(parse("mute", "ON").unwrap(), (Endpoint::SetMute, r#"{"muted":true}"#.into()));
+        assert_eq!(parse("mute", " false ").unwrap(), (Endpoint::SetMute, r#"{"muted":false}"#.into()));
+        assert_eq!(parse("device", "BuiltInSpeakerDevice").unwrap(), (Endpoint::SelectDevice, r#"{"id":"BuiltInSpeakerDevice"}"#.into()));
+        assert!(parse("volume", "loud").is_err());
+        assert!(parse("mute", "maybe").is_err());
+        assert!(parse("bass", "1").is_err());
+        assert!(parse_command(&topics, "audioremote/studio/volume", b"0.4").is_err());
+    }
+
+    #[test]
+    fn test_config() {
+        let config = |json: Value| serde_json::from_value::<MqttConfig>(json).unwrap();
+        let plain = config(json!({"host": "broker"}));
+        plain.validate().unwrap();
+        assert_eq!((plain.port(), plain.keep_alive_secs, plain.retain), (DEFAULT_PORT, 30, true));
+        assert_eq!(config(json!({"host": "broker", "tls": true})).port(), DEFAULT_TLS_PORT);
+        assert!(config(json!({"host": "broker", "password": "p"})).validate().is_err());
+        assert!(config(json!({"host": "broker", "certFingerprint": "ab"})).validate().is_err());
+        assert!(config(json!({"host": "broker", "topicPrefix": "home/#"})).validate().is_err());
+        assert!(config(json!({"host": "broker", "topicPrefix": "home/"})).validate().is_err());
+        assert!(config(json!({"host": "broker", "keepAliveSecs": 1})).validate().is_err());
+        assert!(config(json!({"host": "broker", "discoveryPrefix": "ha"})).validate().is_err());
+        config(json!({"host": "broker", "homeAssistant": true, "discoveryPrefix": "ha"})).validate().unwrap();
+        assert!(serde_json::from_value::<MqttConfig>(json!({"host": "broker", "qos": 2})).is_err());
+        validate_prefix(&default_prefix()).unwrap();
+    }
+
+    fn expect(reader: &mut impl Read, kind: u8) -> Vec<u8> {
+        let (first, body) = read_frame(reader).unwrap();
+        assert_eq!(first & 0xf0, kind, "{body:?}");
+        body
+    }
+
+    fn wait_for(state: ConnectionState) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while status()["state"] != state as i32 {
+            assert!(Instant::now() < deadline, "{}", status());
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_session_with_a_broker() {
+        // The hub and the server's handlers are shared with the WebSocket tests
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let broker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = json!({
+            "host": "127.0.0.1",
+            "port": broker.local_addr().unwrap().port(),
+            "clientId": "test-mac",
+            "username": "ha",
+            "password": "secret",
+            "topicPrefix": "audioremote/studio",
+        });
+        start(serde_json::from_value(config).unwrap()).unwrap();
+        let (stream, _) = broker.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut stream = stream;
+
+        let connect = expect(&mut reader, CONNECT);
+        assert_eq!(connect[7], 0xe6);
+        assert!(connect.windows(8).any(|w| w == b"test-mac"));
+        assert!(connect.windows(25).any(|w| w == b"audioremote/studio/status"));
+        stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+        let subscribe = expect(&mut reader, SUBSCRIBE);
+        assert_eq!(&subscribe[4..subscribe.len() - 1], b"audioremote/studio/+/set");
+        stream.write_all(&[SUBACK, 3, 0, 1, 1]).unwrap();
+        let online = read_packet(&mut reader).unwrap();
+        assert!(matches!(online, Packet::Publish { ref topic, ref payload, retain: true, .. } if topic == "audioremote/studio/status" && payload == b"online"));
+        wait_for(ConnectionState::Connected);
+
+        HUB.publish(Topic::Volume, json!({"volume": 0.6, "muted": false}));
+        let state = loop {
+            match read_packet(&mut reader).unwrap() {
+                Packet::Publish { topic, payload, .. } if topic == "audioremote/studio/volume" && payload.windows(3).any(|w| w == b"0.6") => {
+                    break payload
+                }
+                _ => {}
+            }
+        };
+        assert_eq!(state, br#"{"muted":false,"volume":0.6}"#);
+
+        // A QoS 1 command is acknowledged
+        stream.write_all(&[PUBLISH | 0x02, 29, 0, 24]).unwrap();
+        stream.write_all(b"audioremote/studio/x/set").unwrap();
+        stream.write_all(&[0, 9, b'1']).unwrap();
+        while read_packet(&mut reader).unwrap() != Packet::PubAck(9) {}
+
+        stop();
+        let offline = read_packet(&mut reader).unwrap();
+        assert!(matches!(offline, Packet::Publish { ref payload, .. } if payload == b"offline"));
+        assert_eq!(read_packet(&mut reader).unwrap(), Packet::Other(DISCONNECT));
+        assert_eq!(status()["state"], ConnectionState::Closed as i32);
+
+        // A rejected password isn't retried
+        let config = json!({"host": "127.0.0.1", "port": broker.local_addr().unwrap().port(), "clientId": "test-mac"});
+        start(serde_json::from_value(config).unwrap()).unwrap();
+        let (mut stream, _) = broker.accept().unwrap();
+        expect(&mut stream, CONNECT);
+        stream.write_all(&[CONNACK, 2, 0, 4]).unwrap();
+        wait_for(ConnectionState::Failed);
+        assert!(status()["error"].as_str().unwrap().contains("username or password"));
+        stop();
+    }
+
+    #[test]
+    fn test_home_assistant_discovery() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let broker = TcpListener::bind("127.0.0.1:0").unwrap();
+        let config = json!({
+            "host": "127.0.0.1",
+            "port": broker.local_addr().unwrap().port(),
+            "clientId": "test-mac",
+            "topicPrefix": "audioremote/studio",
+            "homeAssistant": true,
+        });
+        start(serde_json::from_value(config).unwrap()).unwrap();
+        let (mut stream, _) = broker.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        expect(&mut reader, CONNECT);
+        stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+        let subscribe = expect(&mut reader, SUBSCRIBE);
+        assert!(subscribe.windows(20).any(|w| w == b"homeassistant/status"));
+
+        // Configs until the output select, which comes with the devices
+        HUB.publish(Topic::Devices, json!([{"id": "usb:1", "name": "Desk", "isCurrent": true}]));
+        let mut configs = Vec::new();
+        while !configs.iter().any(|topic: &String| topic.contains("/select/")) {
+            if let Packet::Publish { topic, payload, retain, .. } = read_packet(&mut reader).unwrap() {
+                if topic.starts_with("homeassistant/") {
+                    assert!(retain);
+                    let config: Value = serde_json::from_slice(&payload).unwrap();
+                    assert_eq!(config["device"]["identifiers"][0], "test-mac");
+                    configs.push(topic);
+                }
+            }
+        }
+        assert!(configs.contains(&"homeassistant/number/test-mac/volume/config".to_string()), "{configs:?}");
+
+        // Sent again when Home Assistant comes back
+        stream.write_all(&publish_packet("homeassistant/status", b"online", false)).unwrap();
+        loop {
+            if let Packet::Publish { topic, payload, .. } = read_packet(&mut reader).unwrap() {
+                if topic == "homeassistant/select/test-mac/output/config" {
+                    assert!(payload.windows(6).any(|w| w == b"\"Desk\""));
+                    break;
+                }
+            }
+        }
+        stop();
+    }
+}
@@ -0,0 +1,543 @@
+//! The raw TCP transport, encrypted with the Noise protocol instead of TLS:
+//! `Noise_XXpsk3_25519_ChaChaPoly_SHA256`. A handshake is three messages and
+//! a few X25519 operations, with no certificates to send or check, which
+//! suits remotes on small CPUs; each connection's keys come from fresh
+//! ephemeral keys, so recorded traffic stays secret even if a pairing key
+//! leaks later.
+//!
+//! The channel is bound to pairing (see `pairing`): the remote names itself in
+//! the first message's cleartext payload, and the pre-shared key mixed in at
+//! the end of the handshake is derived from that remote's pairing key. Only a
+//! paired remote can finish a handshake, and only with the right Mac; an
+//! unknown remote ID fails the same way a wrong key does.
+//!
+//! Every message on the wire, handshake or transport, is a 2-byte big-endian
+//! length and then the Noise message. Once the handshake is done each
+//! transport message holds one protocol envelope (see `protocol`), the same
+//! conversation as a MessagePack WebSocket: events out, `hello` and
+//! `subscribe` in. An empty message is a keep-alive. `relay` runs the same
+//! channels through a relay server, one Noise message per relay frame.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::c_char;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hkdf::Hkdf;
+use serde::Deserialize;
+use sha2::Sha256;
+use snow::{Builder, HandshakeState, StatelessTransportState};
+
+use crate::events::{Subscription, Topic, HUB};
+use crate::pairing::{self, random_bytes};
+use crate::protocol::{Envelope, Message, Session};
+use crate::sessions::{self, Scope};
+use crate::websocket;
+use crate::{guard, record, server, store, str_arg, AudioRemoteError};
+
+pub const NOISE_PARAMS: &str = "Noise_XXpsk3_25519_ChaChaPoly_SHA256";
+/// Binds handshakes to this protocol and version
+const PROLOGUE: &[u8] = b"audioremote noise v1";
+const PSK_INFO: &[u8] = b"audioremote noise psk v1";
+const PSK_LOCATION: usize = 3;
+/// The static private key, base64; the store file is readable only by the user
+const STATIC_KEY_KEY: &str = "noise.staticKey";
+const MAX_MESSAGE: usize = 65535;
+const TAG_LENGTH: usize = 16;
+const MAX_CONNECTIONS: usize = 32;
+/// Idle connections get a keep-alive this often...
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// ...and are closed if nothing at all arrives for this long
+const READ_TIMEOUT: Duration = Duration::from_secs(50);
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `ar_noise_start` configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NoiseConfig {
+    /// 0 picks a free port
+    #[serde(default)]
+    pub port: u16,
+    #[serde(default = "default_bind_address")]
+    pub bind_address: IpAddr,
+}
+
+fn default_bind_address() -> IpAddr {
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+}
+
+fn noise_error(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Noise: {e}"))
+}
+
+/// The pre-shared key for a pairing key
+pub fn psk(pairing_key: &[u8]) -> [u8; 32] {
+    let mut psk = [0; 32];
+    Hkdf::<Sha256>::new(None, pairing_key).expand(PSK_INFO, &mut psk).expect("32 bytes is a valid HKDF-SHA256 length");
+    psk
+}
+
+fn builder() -> Builder<'static> {
+    Builder::new(NOISE_PARAMS.parse().expect("the Noise parameters are valid")).prologue(PROLOGUE)
+}
+
+/// The Mac's static key, made on first use; it identifies the Mac across handshakes
+pub(crate) fn static_key() -> Result<Vec<u8>, AudioRemoteError> {
+    let store = store::global();
+    if let Some(key) = store.get::<String>(STATIC_KEY_KEY).and_then(|key| STANDARD.decode(key).ok()).filter(|key| key.len() == 32) {
+        return Ok(key);
+    }
+    let keypair = builder().generate_keypair().map_err(|e| AudioRemoteError::Other(format!("can't make a Noise key: {e}")))?;
+    store.set(STATIC_KEY_KEY, &STANDARD.encode(&keypair.private))?;
+    Ok(keypair.private)
+}
+
+pub(crate) fn write_message(writer: &mut impl Write, message: &[u8]) -> io::Result<()> {
+    let length = u16::try_from(message.len()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Noise message over 64 KiB"))?;
+    writer.write_all(&[&length.to_be_bytes()[..], message].concat())
+}
+
+pub(crate) fn read_message(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut length = [0; 2];
+    reader.read_exact(&mut length)?;
+    let mut message = vec![0; usize::from(u16::from_be_bytes(length))];
+    reader.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// The sending end of a channel's message pipe: a TCP stream, or a channel through the relay
+/// (see `relay`)
+pub(crate) trait Outgoing: Send {
+    fn send(&mut self, message: &[u8]) -> io::Result<()>;
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+/// The receiving end; fails once nothing has arrived for the timeout
+pub(crate) trait Incoming: Send {
+    fn receive(&mut self) -> io::Result<Vec<u8>>;
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+}
+
+impl Outgoing for TcpStream {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        write_message(self, message)
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+impl Incoming for TcpStream {
+    fn receive(&mut self) -> io::Result<Vec<u8>> {
+        read_message(self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.set_read_timeout(Some(timeout))
+    }
+}
+
+/// The receiving end for transports that hand over whole messages from a thread of their own
+/// (see `relay` and `webrtc`)
+pub(crate) struct Inbox {
+    messages: mpsc::Receiver<Vec<u8>>,
+    timeout: Duration,
+}
+
+impl Inbox {
+    pub(crate) fn new(messages: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self { messages, timeout: HANDSHAKE_TIMEOUT }
+    }
+}
+
+impl Incoming for Inbox {
+    fn receive(&mut self) -> io::Result<Vec<u8>> {
+        match self.messages.recv_timeout(self.timeout) {
+            Ok(message) => Ok(message),
+            Err(RecvTimeoutError::Timeout) => Err(io::Error::new(io::ErrorKind::TimedOut, "nothing from the remote")),
+            Err(RecvTimeoutError::Disconnected) => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+}
+
+/// Send a handshake message carrying `payload`
+fn send_handshake(outgoing: &mut dyn Outgoing, handshake: &mut HandshakeState, payload: &[u8]) -> io::Result<()> {
+    let mut message = vec![0; MAX_MESSAGE];
+    let length = handshake.write_message(payload, &mut message).map_err(noise_error)?;
+    outgoing.send(&message[..length])
+}
+
+/// Receive a handshake message; its payload
+fn receive_handshake(incoming: &mut dyn Incoming, handshake: &mut HandshakeState) -> io::Result<Vec<u8>> {
+    let message = incoming.receive()?;
+    let mut payload = vec![0; message.len()];
+    let length = handshake.read_message(&message, &mut payload).map_err(noise_error)?;
+    payload.truncate(length);
+    Ok(payload)
+}
+
+/// The sending half of a channel
+pub struct Sender {
+    outgoing: Box<dyn Outgoing>,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Sender {
+    pub fn send(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut message = vec![0; payload.len() + TAG_LENGTH];
+        let length = self.transport.write_message(self.nonce, payload, &mut message).map_err(noise_error)?;
+        self.nonce += 1;
+        self.outgoing.send(&message[..length])
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.outgoing.shutdown()
+    }
+}
+
+/// The receiving half of a channel
+pub struct Receiver {
+    incoming: Box<dyn Incoming>,
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl Receiver {
+    /// The next message's payload; empty for a keep-alive
+    pub fn receive(&mut self) -> io::Result<Vec<u8>> {
+        let message = self.incoming.receive()?;
+        let mut payload = vec![0; message.len()];
+        let length = self.transport.read_message(self.nonce, &message, &mut payload).map_err(noise_error)?;
+        self.nonce += 1;
+        payload.truncate(length);
+        Ok(payload)
+    }
+}
+
+/// Both halves, once a handshake finished
+fn split(outgoing: Box<dyn Outgoing>, incoming: Box<dyn Incoming>, handshake: HandshakeState) -> io::Result<(Sender, Receiver)> {
+    let transport = Arc::new(handshake.into_stateless_transport_mode().map_err(noise_error)?);
+    let receiver = Receiver { incoming, transport: transport.clone(), nonce: 0 };
+    Ok((Sender { outgoing, transport, nonce: 0 }, receiver))
+}
+
+/// The remote's side: handshake with a Mac as the paired remote `remote_id`; returns the
+/// channel and the Mac's ID
+pub fn connect(stream: TcpStream, remote_id: &str, pairing_key: &[u8]) -> io::Result<(Sender, Receiver, String)> {
+    initiate(Box::new(stream.try_clone()?), Box::new(stream), remote_id, pairing_key)
+}
+
+/// `connect` over any pipe
+pub(crate) fn initiate(
+    mut outgoing: Box<dyn Outgoing>,
+    mut incoming: Box<dyn Incoming>,
+    remote_id: &str,
+    pairing_key: &[u8],
+) -> io::Result<(Sender, Receiver, String)> {
+    let keypair = builder().generate_keypair().map_err(noise_error)?;
+    let mut handshake =
+        builder().local_private_key(&keypair.private).psk(PSK_LOCATION as u8, &psk(pairing_key)).build_initiator().map_err(noise_error)?;
+    send_handshake(outgoing.as_mut(), &mut handshake, remote_id.as_bytes())?;
+    let mac_id = String::from_utf8_lossy(&receive_handshake(incoming.as_mut(), &mut handshake)?).into_owned();
+    send_handshake(outgoing.as_mut(), &mut handshake, &[])?;
+    let (sender, receiver) = split(outgoing, incoming, handshake)?;
+    Ok((sender, receiver, mac_id))
+}
+
+/// The Mac's side: handshake with a remote; returns the channel and the remote's ID
+fn accept(mut outgoing: Box<dyn Outgoing>, mut incoming: Box<dyn Incoming>, static_key: &[u8]) -> io::Result<(Sender, Receiver, String)> {
+    let mut handshake = builder().local_private_key(static_key).build_responder().map_err(noise_error)?;
+    let remote_id = String::from_utf8_lossy(&receive_handshake(incoming.as_mut(), &mut handshake)?).into_owned();
+    // An unknown remote gets a key nobody has, and fails like a wrong one
+    let key = match pairing::pairings().get(&remote_id).and_then(|pairing| STANDARD.decode(&pairing.key).ok()) {
+        Some(key) => psk(&key),
+        None => random_bytes().map_err(|e| io::Error::other(e.to_string()))?,
+    };
+    handshake.set_psk(PSK_LOCATION, &key).map_err(noise_error)?;
+    let mac_id = pairing::mac_id().map_err(|e| io::Error::other(e.to_string()))?;
+    send_handshake(outgoing.as_mut(), &mut handshake, mac_id.as_bytes())?;
+    receive_handshake(incoming.as_mut(), &mut handshake)?;
+    let (sender, receiver) = split(outgoing, incoming, handshake)?;
+    Ok((sender, receiver, remote_id))
+}
+
+fn lock(sender: &Mutex<Sender>) -> std::sync::MutexGuard<'_, Sender> {
+    sender.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Push events as envelopes, and keep-alives when idle, until the subscription closes
+fn push(subscription: &Subscription, session: &Mutex<Session>, sender: &Mutex<Sender>) -> io::Result<()> {
+    while let Some(events) = subscription.next(PING_INTERVAL) {
+        let session = *session.lock().unwrap_or_else(|e| e.into_inner());
+        let mut sender = lock(sender);
+        if events.is_empty() {
+            sender.send(&[])?;
+        }
+        if !session.has(crate::protocol::FEATURE_EVENTS) {
+            continue;
+        }
+        for event in events {
+            let message = Message::from_event(&event).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            sender.send(&Envelope::in_session(&session, None, message).encode_for(&session))?;
+        }
+    }
+    Ok(())
+}
+
+/// Handshake with a remote, then carry envelopes until either side hangs up
+pub(crate) fn serve(outgoing: Box<dyn Outgoing>, mut incoming: Box<dyn Incoming>, peer: &str, static_key: &[u8]) -> io::Result<()> {
+    incoming.set_timeout(HANDSHAKE_TIMEOUT)?;
+    let (sender, mut receiver, remote_id) = accept(outgoing, incoming, static_key)?;
+    receiver.incoming.set_timeout(READ_TIMEOUT)?;
+    let scope: Scope = sessions::scope_of(&remote_id);
+    log::debug!("Noise channel from {peer} is remote {remote_id}");
+
+    let topics: BTreeSet<Topic> = Topic::ALL.into_iter().filter(|topic| scope.allows_topic(*topic)).collect();
+    let subscription = Arc::new(HUB.subscribe(topics));
+    let session = Arc::new(Mutex::new(Session::default()));
+    let sender = Arc::new(Mutex::new(sender));
+    let pusher = {
+        let (subscription, session, sender) = (subscription.clone(), session.clone(), sender.clone());
+        thread::Builder::new().name("audioremote-noise-push".into()).spawn(move || {
+            if push(&subscription, &session, &sender).is_err() {
+                // Unblock the reader too
+                let _ = lock(&sender).shutdown();
+            }
+        })?
+    };
+    let result = loop {
+        let payload = match receiver.receive() {
+            Ok(payload) => payload,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(e) => break Err(e),
+        };
+        if payload.is_empty() {
+            continue;
+        }
+        if let Some(answer) = websocket::handle_envelope(&subscription, scope, &session, &payload) {
+            let bytes = answer.encode_for(&session.lock().unwrap_or_else(|e| e.into_inner()));
+            if let Err(e) = lock(&sender).send(&bytes) {
+                break Err(e);
+            }
+        }
+    };
+    subscription.close();
+    let _ = pusher.join();
+    let _ = lock(&sender).shutdown();
+    log::debug!("Noise channel from {peer} closed");
+    result
+}
+
+struct Running {
+    address: SocketAddr,
+    stopping: Arc<AtomicBool>,
+    connections: Arc<Mutex<BTreeMap<u64, TcpStream>>>,
+    acceptor: JoinHandle<()>,
+}
+
+static LISTENER: Mutex<Option<Running>> = Mutex::new(None);
+
+fn accept_loop(listener: TcpListener, static_key: Vec<u8>, stopping: Arc<AtomicBool>, connections: Arc<Mutex<BTreeMap<u64, TcpStream>>>) {
+    let next = AtomicU64::new(1);
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::Acquire) {
+            return;
+        }
+        let Ok(stream) = stream else {
+            thread::sleep(Duration::from_millis(100));
+            continue;
+        };
+        let Ok(peer) = stream.peer_addr().map(server::canonical_peer) else {
+            continue;
+        };
+        let id = next.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut open = connections.lock().unwrap_or_else(|e| e.into_inner());
+            if open.len() >= MAX_CONNECTIONS {
+                log::warn!("refusing Noise connection from {peer}: {MAX_CONNECTIONS} connections open");
+                continue;
+            }
+            match stream.try_clone() {
+                Ok(clone) => open.insert(id, clone),
+                Err(_) => continue,
+            };
+        }
+        let (static_key, connections) = (static_key.clone(), connections.clone());
+        let spawned = thread::Builder::new().name(format!("audioremote-noise-{id}")).spawn(move || {
+            let served = match stream.try_clone() {
+                Ok(outgoing) => serve(Box::new(outgoing), Box::new(stream), &peer.to_string(), &static_key),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = served {
+                log::debug!("Noise connection from {peer} ended: {e}");
+            }
+            connections.lock().unwrap_or_else(|e| e.into_inner()).remove(&id);
+        });
+        if let Err(e) = spawned {
+            log::warn!("can't start Noise connection thread: {e}");
+        }
+    }
+}
+
+/// Start listening; fails if the listener is already running or the address is taken
+pub fn start(config: &NoiseConfig) -> Result<SocketAddr, AudioRemoteError> {
+    let mut running = LISTENER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(running) = running.as_ref() {
+        return Err(AudioRemoteError::Refused(format!("the Noise listener is already running on {}", running.address)));
+    }
+    let static_key = static_key()?;
+    let listener = server::listen(config.bind_address, config.port)
+        .map_err(|e| AudioRemoteError::Network(format!("can't listen on port {}: {e}", config.port)))?;
+    let address = listener.local_addr()?;
+    let stopping = Arc::new(AtomicBool::new(false));
+    let connections = Arc::new(Mutex::new(BTreeMap::new()));
+    let acceptor = {
+        let (stopping, connections) = (stopping.clone(), connections.clone());
+        thread::Builder::new()
+            .name("audioremote-noise".into())
+            .spawn(move || accept_loop(listener, static_key, stopping, connections))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the Noise thread: {e}")))?
+    };
+    *running = Some(Running { address, stopping, connections, acceptor });
+    log::info!("Noise listener on {address}");
+    Ok(address)
+}
+
+/// Stop accepting and close every channel; a no-op if not running
+pub fn stop() {
+    let Some(running) = LISTENER.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    running.stopping.store(true, Ordering::Release);
+    // Wake the accept loop so it sees the flag
+    let mut wake = running.address;
+    if wake.ip().is_unspecified() {
+        wake.set_ip(match wake {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    let _ = TcpStream::connect_timeout(&wake, Duration::from_secs(1));
+    let _ = running.acceptor.join();
+    for stream in running.connections.lock().unwrap_or_else(|e| e.into_inner()).values() {
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+    log::info!("Noise listener stopped");
+}
+
+/// Where the listener is, if it's running
+pub fn local_address() -> Option<SocketAddr> {
+    LISTENER.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|running| running.address)
+}
+
+/// Listen for Noise-encrypted connections from paired remotes, an alternative to the server's TLS
+/// for remotes that can't afford it. `config_json` (nullable): {"port" (0: any free port),
+/// "bindAddress" ("::", IPv4 and IPv6)}. Advertise the port to remotes yourself, e.g. in the mDNS capabilities.
+/// Returns: the port listened on, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_noise_start(config_json: *const c_char) -> i32 {
+    guard("ar_noise_start", -999, || {
+        let config = match config_json.is_null() {
+            true => Ok(NoiseConfig { port: 0, bind_address: default_bind_address() }),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid Noise config: {e}")))
+            }),
+        };
+        match record(config.and_then(|config| start(&config))) {
+            Some(address) => i32::from(address.port()),
+            None => -999,
+        }
+    })
+}
+
+/// Stop listening and close every Noise channel; a no-op if not running. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_noise_stop() {
+    guard("ar_noise_stop", (), stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pairing::Pairing;
+    use crate::protocol::Hello;
+    use serde_json::json;
+
+    #[test]
+    fn test_framing() {
+        let mut wire = Vec::new();
+        write_message(&mut wire, b"hello").unwrap();
+        write_message(&mut wire, b"").unwrap();
+        assert_eq!(wire, [0, 5, b'h', b'e', b'l', b'l', b'o', 0, 0]);
+        let mut reader = wire.as_slice();
+        assert_eq!(read_message(&mut reader).unwrap(), b"hello");
+        assert_eq!(read_message(&mut reader).unwrap(), b"");
+        assert!(read_message(&mut [0, 3, 1].as_slice()).is_err());
+        assert!(write_message(&mut Vec::new(), &vec![0; MAX_MESSAGE + 1]).is_err());
+        assert_ne!(psk(b"one key"), psk(b"another key"));
+    }
+
+    #[test]
+    fn test_channel() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_store, dir) = crate::store::tests::with_temp_store("noise");
+        let key = [5; 32];
+        pairing::save(Pairing { remote_id: "phone".into(), remote_name: "Phone".into(), key: STANDARD.encode(key), paired_at: 0 }).unwrap();
+        let address = start(&NoiseConfig { port: 0, bind_address: "127.0.0.1".parse().unwrap() }).unwrap();
+        assert!(start(&NoiseConfig { port: 0, bind_address: default_bind_address() }).is_err());
+
+        // A wrong key and an unknown remote both fail the handshake
+        for (remote_id, key) in [("phone", [6; 32]), ("stranger", [5; 32])] {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let (mut sender, mut receiver, _) = connect(stream, remote_id, &key).unwrap();
+            // The Mac only finds out on the last handshake message, and hangs up
+            let _ = sender.send(&[]);
+            assert!(receiver.receive().is_err());
+        }
+
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (mut sender, mut receiver, mac_id) = connect(stream, "phone", &key).unwrap();
+        assert_eq!(mac_id, pairing::mac_id().unwrap());
+        assert_eq!(static_key().unwrap().len(), 32);
+        assert_eq!(crate::store::tests::file_mode(&dir), 0o600);
+        sender.send(&Envelope::new(Some(1), Message::Hello(Hello::mac())).encode()).unwrap();
+        let welcome = loop {
+            let envelope = Envelope::decode(&receiver.receive().unwrap()).unwrap();
+            if envelope.id == Some(1) {
+                break envelope;
+            }
+        };
+        assert_eq!(welcome.message.kind(), "welcome");
+        HUB.publish(Topic::Volume, json!({"volume": 0.25, "muted": false}));
+        loop {
+            let envelope = Envelope::decode(&receiver.receive().unwrap()).unwrap();
+            if envelope.to_json()["body"]["volume"] == json!(0.25) {
+                break;
+            }
+        }
+
+        stop();
+        assert!(receiver.receive().is_err());
+        assert_eq!(local_address(), None);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,229 @@
+//! Installing an update from a file the user (or MDM) put on disk, for machines
+//! that can't reach the release feed. The file gets the same checks a
+//! downloaded update would: signature, checksum, bundle identity and version.
+//! All of them run on a private copy, so the file can't be swapped once it
+//! has been checked.
+
+use std::cmp::Ordering;
+use std::ffi::c_char;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::staging::{self, BundleInfo};
+use crate::version::{self, ParseMode};
+use crate::{checksum, guard, install, policy, record, signature, store, str_arg, AudioRemoteError};
+
+/// What the local file must prove before it is installed
+#[derive(Debug, Clone)]
+pub struct OfflineChecks<'a> {
+    /// Expected SHA-256 (hex) of the archive
+    pub sha256: Option<&'a str>,
+    /// Ed25519 signature and public key, both base64
+    pub signature: (&'a str, &'a str),
+}
+
+fn bundle_version(info: &BundleInfo) -> Result<semver::Version, AudioRemoteError> {
+    let raw = info.short_version.as_deref().ok_or(AudioRemoteError::InvalidBundle("bundle has no CFBundleShortVersionString".into()))?;
+    version::parse(raw, ParseMode::Lenient).map_err(|e| AudioRemoteError::InvalidBundle(format!("invalid bundle version \"{raw}\": {e}")))
+}
+
+/// Copy `archive` into a fresh directory only the user can enter, keeping its file name
+/// (the extension says how to unpack it)
+fn private_copy(archive: &Path, dir: &Path) -> Result<PathBuf, AudioRemoteError> {
+    let copy = dir.join(archive.file_name().ok_or_else(|| AudioRemoteError::InvalidArgument(format!("{} is not a file", archive.display())))?);
+    let result = (|| {
+        let _ = fs::remove_dir_all(dir);
+        DirBuilder::new().mode(0o700).create(dir)?;
+        let mut source = File::open(archive)?;
+        let mut dest = OpenOptions::new().write(true).create_new(true).mode(0o600).open(&copy)?;
+        io::copy(&mut source, &mut dest).map(|_| ())
+    })();
+    result.map_err(|e| AudioRemoteError::Io(format!("can't copy {}: {e}", archive.display())))?;
+    Ok(copy)
+}
+
+/// Verify `archive` and stage it next to `installed`, refusing anything older than
+/// what's installed or last launched unless a rollback was allowed
+pub fn stage_local(archive: &Path, installed: &Path, checks: &OfflineChecks) -> Result<BundleInfo, AudioRemoteError> {
+    let copy_dir = install::sibling(installed, ".offline-archive");
+    let result = private_copy(archive, &copy_dir).and_then(|copy| stage_copy(&copy, installed, checks));
+    let _ = fs::remove_dir_all(&copy_dir);
+    result
+}
+
+fn stage_copy(archive: &Path, installed: &Path, checks: &OfflineChecks) -> Result<BundleInfo, AudioRemoteError> {
+    let (signature_b64, public_key_b64) = checks.signature;
+    if !signature::verify_file(archive, signature_b64, public_key_b64)? {
+        return Err(AudioRemoteError::VerificationFailed("signature does not match the update file".into()));
+    }
+    if let Some(expected) = checks.sha256 {
+        let actual = checksum::sha256_file(archive, |_, _| true)?;
+        if actual != expected.trim().to_ascii_lowercase() {
+            return Err(AudioRemoteError::VerificationFailed(format!("checksum mismatch: expected {expected}, got {actual}")));
+        }
+    }
+
+    let current = staging::validate_bundle(installed, None)?;
+    let staging_dir = install::sibling(installed, ".offline");
+    let _ = fs::remove_dir_all(&staging_dir);
+    let staged = staging::stage_archive(archive, &staging_dir, Some(&current.bundle_identifier))?;
+
+    let sanity = (|| {
+        let candidate = bundle_version(&staged)?;
+        let older_than_installed = bundle_version(&current)
+            .is_ok_and(|installed| candidate.cmp_precedence(&installed) == Ordering::Less);
+        let store = store::global();
+        if (older_than_installed && !policy::allows_downgrade(&store)) || policy::is_downgrade(&store, &candidate) {
+            return Err(AudioRemoteError::Refused(format!("refusing to downgrade to {candidate}; call update_set_allow_downgrade to roll back")));
+        }
+        Ok(())
+    })();
+    if let Err(e) = sanity {
+        let _ = fs::remove_dir_all(&staging_dir);
+        return Err(e);
+    }
+    Ok(staged)
+}
+
+/// Verify and install a local update file over `installed`
+pub fn install_local(archive: &Path, installed: &Path, checks: &OfflineChecks, keep_backup: bool) -> Result<BundleInfo, AudioRemoteError> {
+    let staged = stage_local(archive, installed, checks)?;
+    let result = install::apply_staged(&staged.path, installed, keep_backup);
+    let _ = fs::remove_dir_all(install::sibling(installed, ".offline"));
+    result
+}
+
+/// Install an update from a local .zip/.tar.gz/.dmg (air-gapped or MDM-managed machines)
+/// `signature_b64` and `public_key_b64` are required; `sha256_hex` may be NULL to skip the
+/// checksum. The bundle must match the installed bundle's identifier and must not be older
+/// than it unless update_set_allow_downgrade was called.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `sha256_ptr` must be null or point to a valid NUL-terminated string; the other pointers
+/// must point to one.
+#[no_mangle]
+pub unsafe extern "C" fn update_install_local(
+    archive_ptr: *const c_char,
+    sha256_ptr: *const c_char,
+    signature_ptr: *const c_char,
+    public_key_ptr: *const c_char,
+    installed_app_ptr: *const c_char,
+    keep_backup: bool,
+) -> i32 {
+    guard("update_install_local", -999, || {
+        let optional = |ptr: *const c_char, name: &str| match ptr.is_null() {
+            true => Ok(None),
+            false => str_arg(ptr, name).map(Some),
+        };
+        let result = str_arg(archive_ptr, "archive").and_then(|archive| {
+            let installed = str_arg(installed_app_ptr, "installed_app")?;
+            let signature = (str_arg(signature_ptr, "signature")?, str_arg(public_key_ptr, "public_key")?);
+            let checks = OfflineChecks { sha256: optional(sha256_ptr, "sha256")?, signature };
+            install_local(Path::new(archive), Path::new(installed), &checks, keep_backup)
+        });
+        match record(result) {
+            Some(_) => 1,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::tests::temp_dir;
+    use crate::staging::tests::{write_zip, INFO_PLIST};
+    use crate::store::tests::with_temp_store;
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::ffi::CString;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn make_installed(app: &Path, version: &str) {
+        fs::create_dir_all(app.join("Contents/MacOS")).unwrap();
+        fs::write(app.join("Contents/Info.plist"), INFO_PLIST.replace("2.7.0", version)).unwrap();
+        fs::write(app.join("Contents/MacOS/AudioRemote"), b"old").unwrap();
+        fs::set_permissions(app.join("Contents/MacOS/AudioRemote"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Signature and public key for `archive`, base64
+    fn sign(archive: &Path) -> (String, String) {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let signature = key.sign(&fs::read(archive).unwrap()).to_bytes();
+        (STANDARD.encode(signature), STANDARD.encode(key.verifying_key().to_bytes()))
+    }
+
+    #[test]
+    fn test_install_local_verifies_file() {
+        let (_guard, _dir) = with_temp_store("offline");
+        let root = temp_dir("offline-install");
+        let installed = root.join("Applications/AudioRemote.app");
+        make_installed(&installed, "2.6.0");
+        let archive = root.join("AudioRemote-2.7.0.zip");
+        write_zip(&archive, "", 0o755);
+
+        let (signature, public_key) = sign(&archive);
+        let wrong_sha = "0".repeat(64);
+
+        let bad = OfflineChecks { sha256: Some(&wrong_sha), signature: (&signature, &public_key) };
+        assert!(matches!(install_local(&archive, &installed, &bad, false), Err(AudioRemoteError::VerificationFailed(_))));
+        let forged = OfflineChecks { sha256: None, signature: (&signature, "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=") };
+        assert!(install_local(&archive, &installed, &forged, false).is_err());
+
+        let sha = checksum::sha256_file(&archive, |_, _| true).unwrap();
+        let good = OfflineChecks { sha256: Some(&sha), signature: (&signature, &public_key) };
+        let info = install_local(&archive, &installed, &good, false).unwrap();
+        assert_eq!(info.short_version.as_deref(), Some("2.7.0"));
+        assert!(!root.join("Applications/.AudioRemote.app.offline").exists());
+        assert!(!root.join("Applications/.AudioRemote.app.offline-archive").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ffi_requires_a_signature() {
+        let (_guard, _dir) = with_temp_store("offline-unsigned");
+        let root = temp_dir("offline-unsigned");
+        let installed = root.join("AudioRemote.app");
+        make_installed(&installed, "2.6.0");
+        let archive = root.join("AudioRemote-2.7.0.zip");
+        write_zip(&archive, "", 0o755);
+
+        let (archive_c, installed_c) = (CString::new(archive.to_str().unwrap()).unwrap(), CString::new(installed.to_str().unwrap()).unwrap());
+        let (signature, public_key) = sign(&archive);
+        let (signature, public_key) = (CString::new(signature).unwrap(), CString::new(public_key).unwrap());
+        for (signature, public_key) in [(std::ptr::null(), std::ptr::null()), (signature.as_ptr(), std::ptr::null()), (std::ptr::null(), public_key.as_ptr())] {
+            let result = unsafe { update_install_local(archive_c.as_ptr(), std::ptr::null(), signature, public_key, installed_c.as_ptr(), false) };
+            assert_eq!(result, -999);
+            assert_eq!(crate::error::ar_last_error_code(), AudioRemoteError::InvalidArgument(String::new()).code());
+        }
+        assert_eq!(fs::read(installed.join("Contents/MacOS/AudioRemote")).unwrap(), b"old");
+
+        let result = unsafe { update_install_local(archive_c.as_ptr(), std::ptr::null(), signature.as_ptr(), public_key.as_ptr(), installed_c.as_ptr(), false) };
+        assert_eq!(result, 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_install_local_refuses_older_version() {
+        let (_guard, _dir) = with_temp_store("offline-old");
+        let root = temp_dir("offline-old");
+        let installed = root.join("AudioRemote.app");
+        make_installed(&installed, "2.8.0");
+        let archive = root.join("AudioRemote-2.7.0.zip");
+        write_zip(&archive, "", 0o755);
+
+        let (signature, public_key) = sign(&archive);
+        let checks = OfflineChecks { sha256: None, signature: (&signature, &public_key) };
+        let err = install_local(&archive, &installed, &checks, false).unwrap_err();
+        assert!(matches!(&err, AudioRemoteError::Refused(m) if m.contains("downgrade")), "{err}");
+        assert_eq!(fs::read(installed.join("Contents/MacOS/AudioRemote")).unwrap(), b"old");
+
+        policy::set_allow_downgrade(&store::global(), true).unwrap();
+        assert!(install_local(&archive, &installed, &checks, false).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
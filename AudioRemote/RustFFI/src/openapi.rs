@@ -0,0 +1,249 @@
+//! OpenAPI 3 description of the REST API, served at /openapi.json for
+//! integrations (Home Assistant, Stream Deck plugins) to build against. The
+//! paths come from `routes::ROUTES` and the schemas mirror the types in
+//! `remote`, so a route added there shows up here; the matches on `Endpoint`
+//! make sure it gets a schema too.
+
+use serde_json::{json, Map, Value};
+
+use crate::commands::COMMAND_HEADER;
+use crate::routes::{Endpoint, ROUTES};
+use crate::server::{Request, Response};
+use crate::sessions::Scope;
+
+pub const PATH: &str = "/openapi.json";
+const OPENAPI_VERSION: &str = "3.0.3";
+
+fn reference(name: &str) -> Value {
+    json!({"$ref": format!("#/components/schemas/{name}")})
+}
+
+/// The body a route takes, by schema name
+fn request_schema(endpoint: Endpoint) -> Option<&'static str> {
+    match endpoint {
+        Endpoint::GetVolume | Endpoint::ListDevices | Endpoint::NowPlaying => None,
+        Endpoint::SetVolume => Some("SetVolume"),
+        Endpoint::SetMute => Some("SetMute"),
+        Endpoint::SelectDevice => Some("SelectDevice"),
+    }
+}
+
+/// What a route answers with; see `Endpoint::result`
+fn result_schema(endpoint: Endpoint) -> Value {
+    match endpoint {
+        Endpoint::GetVolume | Endpoint::SetVolume | Endpoint::SetMute => reference("VolumeState"),
+        Endpoint::ListDevices => json!({"type": "array", "items": reference("OutputDevice")}),
+        Endpoint::SelectDevice => reference("OutputDevice"),
+        Endpoint::NowPlaying => json!({"allOf": [reference("NowPlaying")], "nullable": true}),
+    }
+}
+
+/// "getVolume" for Endpoint::GetVolume
+fn operation_id(endpoint: Endpoint) -> String {
+    let name = format!("{endpoint:?}");
+    let mut chars = name.chars();
+    chars.next().map(|first| first.to_ascii_lowercase().to_string() + chars.as_str()).unwrap_or_default()
+}
+
+fn error_response(description: &str) -> Value {
+    json!({"description": description, "content": {"application/json": {"schema": reference("Error")}}})
+}
+
+fn operation(method: &str, endpoint: Endpoint, summary: &str) -> Value {
+    let mut responses = Map::new();
+    responses.insert(
+        "200".into(),
+        json!({"description": summary, "content": {"application/json": {"schema": result_schema(endpoint)}}}),
+    );
+    if request_schema(endpoint).is_some() {
+        responses.insert("400".into(), error_response("The body is invalid"));
+    }
+    responses.insert("401".into(), error_response("The access token is missing or invalid and the Mac requires one"));
+    responses.insert("403".into(), error_response("The remote's scope doesn't allow this"));
+    responses.insert("429".into(), error_response("Too many requests; see Retry-After"));
+    responses.insert("501".into(), error_response("This Mac doesn't support it"));
+    responses.insert("504".into(), error_response("The Mac didn't answer in time"));
+
+    let mut operation = json!({
+        "operationId": operation_id(endpoint),
+        "summary": summary,
+        "x-audioremote-scope": Scope::required_for(endpoint),
+        "responses": responses,
+    });
+    if let Some(schema) = request_schema(endpoint) {
+        operation["requestBody"] = json!({"required": true, "content": {"application/json": {"schema": reference(schema)}}});
+    }
+    if method != "GET" {
+        operation["parameters"] = json!([{
+            "name": COMMAND_HEADER,
+            "in": "header",
+            "required": false,
+            "description": "<client>:<sequence>; a retried command with the same ID is applied once and answered again",
+            "schema": {"type": "string", "example": "phone:42"},
+        }]);
+    }
+    operation
+}
+
+fn schemas() -> Value {
+    let string = json!({"type": "string"});
+    let optional_string = json!({"type": "string", "nullable": true});
+    let optional_seconds = json!({"type": "number", "format": "double", "nullable": true, "minimum": 0});
+    let volume = json!({"type": "number", "format": "float", "minimum": 0, "maximum": 1});
+    json!({
+        "VolumeState": {
+            "type": "object",
+            "description": "Output volume and mute state of the current output device",
+            "required": ["volume", "muted"],
+            "properties": {"volume": volume, "muted": {"type": "boolean"}},
+        },
+        "OutputDevice": {
+            "type": "object",
+            "required": ["id", "name", "isCurrent"],
+            "properties": {
+                "id": {"type": "string", "description": "CoreAudio device UID, stable across launches"},
+                "name": string,
+                "isCurrent": {"type": "boolean"},
+                "batteryPercent": {"type": "integer", "minimum": 0, "maximum": 100, "description": "Headphones' charge, the lower earbud's for a pair; absent if unknown"},
+            },
+        },
+        "NowPlaying": {
+            "type": "object",
+            "required": ["isPlaying"],
+            "properties": {
+                "title": optional_string,
+                "artist": optional_string,
+                "album": optional_string,
+                "app": {"type": "string", "nullable": true, "description": "Name of the app playing"},
+                "isPlaying": {"type": "boolean"},
+                "elapsed": optional_seconds,
+                "duration": optional_seconds,
+            },
+        },
+        "SetVolume": {
+            "type": "object",
+            "required": ["volume"],
+            "additionalProperties": false,
+            "properties": {"volume": volume},
+        },
+        "SetMute": {
+            "type": "object",
+            "required": ["muted"],
+            "additionalProperties": false,
+            "properties": {"muted": {"type": "boolean"}},
+        },
+        "SelectDevice": {
+            "type": "object",
+            "required": ["id"],
+            "additionalProperties": false,
+            "properties": {"id": {"type": "string", "minLength": 1}},
+        },
+        "Error": {
+            "type": "object",
+            "required": ["error"],
+            "properties": {
+                "error": {
+                    "type": "object",
+                    "required": ["status", "message"],
+                    "properties": {"status": {"type": "integer"}, "message": string},
+                },
+            },
+        },
+    })
+}
+
+/// The whole document
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for route in ROUTES {
+        let path = paths.entry(route.path).or_insert_with(|| json!({}));
+        path[route.method.to_ascii_lowercase()] = operation(route.method, route.endpoint, route.summary);
+    }
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": {
+            "title": "Audio Remote",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "Control a Mac's audio output over the LAN. Paired remotes send their access token as a \
+                            bearer token; Macs that don't require pairing also accept requests without one.",
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {"bearer": {"type": "http", "scheme": "bearer"}},
+        },
+        "security": [{"bearer": []}, {}],
+    })
+}
+
+/// Serve the document; None for any other path
+pub(crate) fn route(request: &Request) -> Option<Response> {
+    if request.path != PATH {
+        return None;
+    }
+    Some(match request.method.as_str() {
+        "GET" => Response::json(200, &document()),
+        method => Response::error(405, format!("{method} isn't allowed here")).with_header("Allow", "GET"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Follow a local $ref
+    fn resolve<'a>(document: &'a Value, schema: &'a Value) -> &'a Value {
+        match schema["$ref"].as_str() {
+            Some(path) => document.pointer(path.trim_start_matches('#')).expect("the reference resolves"),
+            None => schema,
+        }
+    }
+
+    #[test]
+    fn test_document_covers_every_route() {
+        let document = document();
+        assert_eq!(document["openapi"], OPENAPI_VERSION);
+        for route in ROUTES {
+            let operation = &document["paths"][route.path][route.method.to_ascii_lowercase()];
+            assert_eq!(operation["summary"], route.summary, "{} {}", route.method, route.path);
+            let result = &operation["responses"]["200"]["content"]["application/json"]["schema"];
+            assert!(result.is_object());
+            if let Some(body) = operation.get("requestBody") {
+                let schema = resolve(&document, &body["content"]["application/json"]["schema"]);
+                assert_eq!(schema["type"], "object");
+            }
+        }
+        assert_eq!(document["paths"]["/api/v1/volume"]["put"]["operationId"], "setVolume");
+        assert_eq!(document["paths"]["/api/v1/now-playing"]["get"]["x-audioremote-scope"], "playback");
+        assert!(document["paths"]["/api/v1/volume"]["get"].get("parameters").is_none());
+    }
+
+    #[test]
+    fn test_schemas_match_the_types() {
+        use crate::remote::{NowPlaying, OutputDevice, VolumeState};
+
+        let document = document();
+        let schemas = &document["components"]["schemas"];
+        let check = |name: &str, value: Value| {
+            let properties = schemas[name]["properties"].as_object().unwrap();
+            let fields = value.as_object().unwrap();
+            assert_eq!(properties.keys().collect::<Vec<_>>(), fields.keys().collect::<Vec<_>>(), "{name}");
+            for required in schemas[name]["required"].as_array().unwrap() {
+                assert!(fields.contains_key(required.as_str().unwrap()));
+            }
+        };
+        check("VolumeState", serde_json::to_value(VolumeState { volume: 0.5, muted: false }).unwrap());
+        check("OutputDevice", serde_json::to_value(OutputDevice { id: "a".into(), name: "b".into(), is_current: true, battery_percent: Some(80) }).unwrap());
+        let now_playing = NowPlaying {
+            title: None,
+            artist: None,
+            album: None,
+            app: None,
+            is_playing: false,
+            elapsed: None,
+            duration: None,
+        };
+        check("NowPlaying", serde_json::to_value(now_playing).unwrap());
+        check("Error", serde_json::from_slice(&Response::error(400, "bad").body).unwrap());
+    }
+}
@@ -0,0 +1,422 @@
+//! Opus encoding and decoding for "listen on my phone": the Mac encodes the
+//! tap's audio in short frames and sends the packets to the remote, which
+//! decodes them. 10 ms frames (the default) keep the codec's share of the
+//! latency to around 15 ms, leaving room for the network in a 100 ms budget.
+//!
+//! In-band FEC makes the encoder repeat a coarse copy of each frame in the next
+//! packet so a receiver using libopus can rebuild a single lost one; it only
+//! works in the speech (SILK) modes, so it needs `"application": "voip"`. This
+//! decoder doesn't read FEC and conceals a lost packet instead.
+
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use opus_rs::{Application, OpusDecoder, OpusEncoder};
+use serde::Deserialize;
+
+use crate::handle::{Handle, Registry};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+/// The frame sizes Opus codes, in ms
+const FRAME_MS: [f64; 6] = [2.5, 5.0, 10.0, 20.0, 40.0, 60.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OpusApplication {
+    /// Music and everything else
+    Audio,
+    /// Speech, with FEC available
+    Voip,
+    /// The lowest latency, music-only modes
+    LowDelay,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct OpusConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: u32,
+    #[serde(default = "default_application")]
+    pub application: OpusApplication,
+    /// In bits per second
+    #[serde(default = "default_bitrate")]
+    pub bitrate: u32,
+    #[serde(default = "default_frame_ms")]
+    pub frame_ms: f64,
+    #[serde(default)]
+    pub fec: bool,
+    /// The packet loss FEC should be sized for, 0–100
+    #[serde(default)]
+    pub expected_loss_percent: u32,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> u32 {
+    48_000
+}
+
+fn default_application() -> OpusApplication {
+    OpusApplication::Audio
+}
+
+/// Transparent for most music in stereo
+fn default_bitrate() -> u32 {
+    96_000
+}
+
+fn default_frame_ms() -> f64 {
+    10.0
+}
+
+impl Default for OpusConfig {
+    fn default() -> Self {
+        Self {
+            channels: default_channels(),
+            sample_rate: default_sample_rate(),
+            application: default_application(),
+            bitrate: default_bitrate(),
+            frame_ms: default_frame_ms(),
+            fec: false,
+            expected_loss_percent: 0,
+        }
+    }
+}
+
+impl OpusConfig {
+    fn validate(&self) -> Result<(), AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=2).contains(&self.channels) {
+            return invalid(format!("channels {} must be 1 or 2", self.channels));
+        }
+        if ![8000, 12_000, 16_000, 24_000, 48_000].contains(&self.sample_rate) {
+            return invalid(format!("sampleRate {} must be 8000, 12000, 16000, 24000 or 48000", self.sample_rate));
+        }
+        if !(6000..=510_000).contains(&self.bitrate) {
+            return invalid(format!("bitrate {} is outside 6000–510000", self.bitrate));
+        }
+        if !FRAME_MS.contains(&self.frame_ms) {
+            return invalid(format!("frameMs {} must be 2.5, 5, 10, 20, 40 or 60", self.frame_ms));
+        }
+        if self.expected_loss_percent > 100 {
+            return invalid(format!("expectedLossPercent {} is over 100", self.expected_loss_percent));
+        }
+        Ok(())
+    }
+
+    /// Samples per channel in a frame
+    pub fn frame_size(&self) -> usize {
+        (self.frame_ms * f64::from(self.sample_rate) / 1000.0) as usize
+    }
+
+}
+
+pub struct Encoder {
+    config: OpusConfig,
+    opus: OpusEncoder,
+}
+
+impl Encoder {
+    pub fn new(config: &OpusConfig) -> Result<Self, AudioRemoteError> {
+        config.validate()?;
+        let application = match config.application {
+            OpusApplication::Audio => Application::Audio,
+            OpusApplication::Voip => Application::Voip,
+            OpusApplication::LowDelay => Application::RestrictedLowDelay,
+        };
+        let opus = OpusEncoder::new(config.sample_rate as i32, config.channels as usize, application)
+            .map_err(|e| AudioRemoteError::InvalidArgument(format!("can't create an opus encoder: {e}")))?;
+        let mut encoder = Self { config: config.clone(), opus };
+        encoder.apply();
+        Ok(encoder)
+    }
+
+    fn apply(&mut self) {
+        self.opus.bitrate_bps = self.config.bitrate as i32;
+        self.opus.use_inband_fec = self.config.fec;
+        self.opus.packet_loss_perc = self.config.expected_loss_percent as i32;
+    }
+
+    /// Change the settings; the bitrate, FEC and expected loss change in stream, anything else
+    /// starts a new one
+    pub fn update(&mut self, config: &OpusConfig) -> Result<(), AudioRemoteError> {
+        config.validate()?;
+        let same_stream = (config.channels, config.sample_rate, config.application, config.frame_ms)
+            == (self.config.channels, self.config.sample_rate, self.config.application, self.config.frame_ms);
+        match same_stream {
+            true => {
+                self.config = config.clone();
+                self.apply();
+            }
+            false => *self = Self::new(config)?,
+        }
+        Ok(())
+    }
+
+    pub fn config(&self) -> &OpusConfig {
+        &self.config
+    }
+
+    /// Encode one frame of interleaved samples (exactly frame_size × channels) into `packet`.
+    /// Returns the packet's length.
+    pub fn encode(&mut self, samples: &[f32], packet: &mut [u8]) -> Result<usize, AudioRemoteError> {
+        let frame_size = self.config.frame_size();
+        if samples.len() != frame_size * self.config.channels as usize {
+            return Err(AudioRemoteError::InvalidArgument(format!("a frame is {frame_size} samples per channel")));
+        }
+        self.opus.encode(samples, frame_size, packet).map_err(|e| AudioRemoteError::InvalidArgument(format!("can't encode: {e}")))
+    }
+}
+
+pub struct Decoder {
+    channels: usize,
+    /// Samples per channel to conceal for a lost packet
+    frame_size: usize,
+    opus: OpusDecoder,
+}
+
+impl Decoder {
+    /// Only the channels, sample rate and frame size matter here, so the encoder's config can be shared
+    pub fn new(config: &OpusConfig) -> Result<Self, AudioRemoteError> {
+        config.validate()?;
+        let opus = OpusDecoder::new(config.sample_rate as i32, config.channels as usize)
+            .map_err(|e| AudioRemoteError::InvalidArgument(format!("can't create an opus decoder: {e}")))?;
+        Ok(Self { channels: config.channels as usize, frame_size: config.frame_size(), opus })
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    /// Decode a packet into interleaved samples in `out`, or conceal a lost one (`None`) with a
+    /// frame's worth. Returns the samples per channel decoded.
+    pub fn decode(&mut self, packet: Option<&[u8]>, out: &mut [f32]) -> Result<usize, AudioRemoteError> {
+        let capacity = out.len() / self.channels;
+        let frame_size = match packet {
+            None => self.frame_size,
+            Some([]) => return Err(AudioRemoteError::InvalidData("an opus packet is never empty".into())),
+            Some(_) => capacity,
+        };
+        if capacity < frame_size {
+            return Err(AudioRemoteError::InvalidArgument(format!("room for {capacity} samples per channel isn't a frame")));
+        }
+        self.opus
+            .decode(packet.unwrap_or_default(), frame_size, out)
+            .map_err(|e| AudioRemoteError::InvalidData(format!("can't decode the opus packet: {e}")))
+    }
+}
+
+unsafe fn config_arg(config_json: *const c_char) -> Result<OpusConfig, AudioRemoteError> {
+    match config_json.is_null() {
+        true => Ok(OpusConfig::default()),
+        false => serde_json::from_str(str_arg(config_json, "config")?)
+            .map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid opus config: {e}"))),
+    }
+}
+
+static ENCODERS: Registry<Mutex<Encoder>> = Registry::new("opus encoder");
+static DECODERS: Registry<Mutex<Decoder>> = Registry::new("opus decoder");
+
+fn with_encoder<T>(handle: Handle, body: impl FnOnce(&mut Encoder) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let encoder = ENCODERS.get(handle)?;
+    let mut encoder = encoder.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut encoder)
+}
+
+fn with_decoder<T>(handle: Handle, body: impl FnOnce(&mut Decoder) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let decoder = DECODERS.get(handle)?;
+    let mut decoder = decoder.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut decoder)
+}
+
+/// Create an encoder. `config_json` (nullable): {"channels" (1 or 2, default 2), "sampleRate"
+/// (8000, 12000, 16000, 24000 or 48000, default 48000), "application" ("audio" (default),
+/// "voip" or "lowDelay"), "bitrate" (bits/s, default 96000), "frameMs" (2.5, 5, 10 (default),
+/// 20, 40 or 60), "fec" (default false), "expectedLossPercent" (default 0)}.
+/// Returns: a handle (free with ar_opus_encoder_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_opus_encoder_new(config_json: *const c_char) -> Handle {
+    guard("ar_opus_encoder_new", 0, || match record(config_arg(config_json).and_then(|config| Encoder::new(&config))) {
+        Some(encoder) => ENCODERS.insert(Mutex::new(encoder)),
+        None => 0,
+    })
+}
+
+/// Change the settings, as for ar_opus_encoder_new, e.g. to drop the bitrate while the Mac is
+/// silent. The bitrate, FEC and expected loss change within the stream; any other change starts a
+/// new one, which the remote's decoder should be reset for.
+/// Returns: 1 on success, -999 on error, keeping the old settings (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_opus_encoder_update(encoder: Handle, config_json: *const c_char) -> i32 {
+    guard("ar_opus_encoder_update", -999, || {
+        let config = config_arg(config_json);
+        record(config.and_then(|config| with_encoder(encoder, |encoder| encoder.update(&config)))).map_or(-999, |_| 1)
+    })
+}
+
+/// Returns: the samples per channel each ar_opus_encode takes, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_opus_frame_size(encoder: Handle) -> i64 {
+    guard("ar_opus_frame_size", -999, || record(with_encoder(encoder, |encoder| Ok(encoder.config().frame_size()))).map_or(-999, |size| size as i64))
+}
+
+/// Encode one frame: `frames` (which must be ar_opus_frame_size) frames of interleaved float
+/// samples, into `packet`, which has room for `capacity` bytes (4000 is always enough)
+/// Returns: the packet's length in bytes, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels floats and `packet` to `capacity` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ar_opus_encode(encoder: Handle, samples: *const f32, frames: usize, packet: *mut u8, capacity: usize) -> i64 {
+    guard("ar_opus_encode", -999, || {
+        let result = with_encoder(encoder, |encoder| {
+            if samples.is_null() || packet.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples and packet must not be null".into()));
+            }
+            let len = frames
+                .checked_mul(encoder.config().channels as usize)
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            encoder.encode(std::slice::from_raw_parts(samples, len), std::slice::from_raw_parts_mut(packet, capacity))
+        });
+        record(result).map_or(-999, |len| len as i64)
+    })
+}
+
+/// Release an encoder; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_opus_encoder_free(encoder: Handle) -> i32 {
+    guard("ar_opus_encoder_free", -999, || record(ENCODERS.remove(encoder)).map_or(-999, |_| 1))
+}
+
+/// Create a decoder. `config_json` (nullable) is as for ar_opus_encoder_new, of which only the
+/// channels, sample rate and frameMs (what a lost packet is concealed with) are used.
+/// Returns: a handle (free with ar_opus_decoder_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_opus_decoder_new(config_json: *const c_char) -> Handle {
+    guard("ar_opus_decoder_new", 0, || match record(config_arg(config_json).and_then(|config| Decoder::new(&config))) {
+        Some(decoder) => DECODERS.insert(Mutex::new(decoder)),
+        None => 0,
+    })
+}
+
+/// Decode a packet of `len` bytes into `out`, which has room for `frames` frames of interleaved
+/// floats (120 ms is always enough). A NULL `packet` marks one as lost and conceals a frame's worth.
+/// Returns: the frames decoded, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `packet` must be null or point to `len` bytes; `out` must point to `frames` × channels floats.
+#[no_mangle]
+pub unsafe extern "C" fn ar_opus_decode(decoder: Handle, packet: *const u8, len: usize, out: *mut f32, frames: usize) -> i64 {
+    guard("ar_opus_decode", -999, || {
+        let result = with_decoder(decoder, |decoder| {
+            if out.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("out must not be null".into()));
+            }
+            let len_out = frames
+                .checked_mul(decoder.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            let packet = (!packet.is_null()).then(|| std::slice::from_raw_parts(packet, len));
+            decoder.decode(packet, std::slice::from_raw_parts_mut(out, len_out))
+        });
+        record(result).map_or(-999, |frames| frames as i64)
+    })
+}
+
+/// Release a decoder; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_opus_decoder_free(decoder: Handle) -> i32 {
+    guard("ar_opus_decoder_free", -999, || record(DECODERS.remove(decoder)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frames: usize, start: usize) -> Vec<f32> {
+        let step = 2.0 * std::f32::consts::PI * 440.0 / 48_000.0;
+        (start..start + frames).flat_map(|i| [0.5 * (i as f32 * step).sin(), 0.25 * (i as f32 * step).sin()]).collect()
+    }
+
+    fn rms(samples: impl Iterator<Item = f32>) -> f32 {
+        let (sum, count) = samples.fold((0.0, 0), |(sum, count), sample| (sum + sample * sample, count + 1));
+        (sum / count as f32).sqrt()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for application in [OpusApplication::Audio, OpusApplication::Voip, OpusApplication::LowDelay] {
+            let config = OpusConfig { application, fec: true, expected_loss_percent: 10, ..OpusConfig::default() };
+            let mut encoder = Encoder::new(&config).unwrap();
+            let mut decoder = Decoder::new(&config).unwrap();
+            let frame = config.frame_size();
+            assert_eq!(frame, 480);
+            let (mut packet, mut out) = (vec![0u8; 4000], vec![0.0f32; 2 * 5760]);
+            let mut decoded = vec![];
+            for i in 0..50 {
+                let len = encoder.encode(&sine(frame, i * frame), &mut packet).unwrap();
+                assert!(len < 200, "{len}");
+                let frames = decoder.decode(Some(&packet[..len]), &mut out).unwrap();
+                assert_eq!(frames, frame);
+                decoded.extend_from_slice(&out[..frames * 2]);
+            }
+            // Past the codec's delay, each channel keeps its level
+            let tail = &decoded[decoded.len() / 2..];
+            let (left, right) = (rms(tail.iter().step_by(2).copied()), rms(tail.iter().skip(1).step_by(2).copied()));
+            assert!((left - 0.5 / 2f32.sqrt()).abs() < 0.05, "{application:?} {left}");
+            assert!((right - 0.25 / 2f32.sqrt()).abs() < 0.05, "{application:?} {right}");
+
+            assert_eq!(decoder.decode(None, &mut out).unwrap(), frame);
+            assert!(encoder.encode(&sine(frame - 1, 0), &mut packet).is_err());
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        let mut encoder = Encoder::new(&OpusConfig::default()).unwrap();
+        encoder.update(&OpusConfig { bitrate: 24_000, ..OpusConfig::default() }).unwrap();
+        assert_eq!(encoder.opus.bitrate_bps, 24_000);
+        encoder.update(&OpusConfig { channels: 1, frame_ms: 20.0, ..OpusConfig::default() }).unwrap();
+        assert_eq!(encoder.config().frame_size(), 960);
+        assert!(encoder.update(&OpusConfig { frame_ms: 15.0, ..OpusConfig::default() }).is_err());
+        assert_eq!(encoder.config().channels, 1);
+        assert!(Encoder::new(&OpusConfig { sample_rate: 44_100, ..OpusConfig::default() }).is_err());
+        assert!(Decoder::new(&OpusConfig { channels: 3, ..OpusConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let config = c"{\"channels\": 1, \"bitrate\": 32000, \"frameMs\": 20}";
+        let encoder = unsafe { ar_opus_encoder_new(config.as_ptr()) };
+        let decoder = unsafe { ar_opus_decoder_new(config.as_ptr()) };
+        assert!(encoder != 0 && decoder != 0);
+        assert_eq!(ar_opus_frame_size(encoder), 960);
+        let samples = vec![0.0f32; 960];
+        let mut packet = [0u8; 4000];
+        let len = unsafe { ar_opus_encode(encoder, samples.as_ptr(), 960, packet.as_mut_ptr(), packet.len()) };
+        assert!(len > 0);
+        let mut out = vec![1.0f32; 960];
+        assert_eq!(unsafe { ar_opus_decode(decoder, packet.as_ptr(), len as usize, out.as_mut_ptr(), 960) }, 960);
+        assert_eq!(unsafe { ar_opus_decode(decoder, std::ptr::null(), 0, out.as_mut_ptr(), 960) }, 960);
+        assert_eq!(unsafe { ar_opus_decode(decoder, packet.as_ptr(), len as usize, out.as_mut_ptr(), 480) }, -999);
+        assert_eq!(unsafe { ar_opus_encode(encoder, samples.as_ptr(), 480, packet.as_mut_ptr(), packet.len()) }, -999);
+        assert_eq!(unsafe { ar_opus_encoder_update(encoder, c"{\"bitrate\": 1}".as_ptr()) }, -999);
+        assert_eq!(ar_opus_encoder_free(encoder), 1);
+        assert_eq!(ar_opus_decoder_free(decoder), 1);
+        assert_eq!(ar_opus_frame_size(encoder), -999);
+    }
+}
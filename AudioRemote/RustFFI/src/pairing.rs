@@ -0,0 +1,492 @@
+//! Pairing a remote with this Mac. Swift opens a pairing window and shows the
+//! 6-digit PIN it gets back; the remote, having been told the PIN by the user,
+//! runs a SPAKE2 exchange with the server:
+//!
+//! 1. `POST /api/v1/pair/start` `{"remoteId", "remoteName", "message"}`: the
+//!    remote's SPAKE2 message. The Mac answers with its own message, the
+//!    fingerprint of its TLS certificate when serving HTTPS, and a key
+//!    confirmation covering that fingerprint,
+//!    `{"macId", "message", "certFingerprint", "confirm"}`.
+//! 2. `POST /api/v1/pair/finish` `{"remoteId", "confirm"}`: the remote's key
+//!    confirmation. If it matches, both sides hold the same pairing key and the
+//!    remote gets its first tokens (see `auth`), `{"paired": true, "tokens"}`.
+//!
+//! The PIN and the key never cross the network; someone listening learns
+//! nothing they can test PINs against offline, and someone guessing gets one
+//! guess per exchange, a handful of exchanges per window. Both confirmations
+//! are HMACs over the two SPAKE2 messages, so a tampered exchange fails too.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void, CString};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+use crate::error::string_result;
+use crate::runtime::{self, SendPtr};
+use crate::server::{self, Request, Response};
+use crate::{auth, guard, store, AudioRemoteError};
+
+pub const START_PATH: &str = "/api/v1/pair/start";
+pub const FINISH_PATH: &str = "/api/v1/pair/finish";
+
+const PIN_DIGITS: usize = 6;
+/// How long a PIN stays valid
+const PIN_LIFETIME: Duration = Duration::from_secs(300);
+/// Exchanges allowed per PIN before the window closes
+const MAX_ATTEMPTS: u32 = 5;
+/// How long the Mac keeps a started exchange waiting for `finish`
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MAC_ID_KEY: &str = "pairing.macId";
+/// Shared keys, base64; the store file is readable only by the user
+const PAIRINGS_KEY: &str = "pairing.remotes";
+
+const MAC_IDENTITY: &[u8] = b"audioremote mac";
+const KEY_INFO: &[u8] = b"audioremote pairing v1";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What happened in a pairing window; values are part of the C ABI
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairingEvent {
+    /// A remote paired; the window is closed
+    Paired = 0,
+    /// A remote got the PIN wrong
+    Failed = 1,
+    /// Too many wrong PINs; the window is closed
+    Locked = 2,
+}
+
+/// A remote this Mac has paired with
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Pairing {
+    pub remote_id: String,
+    pub remote_name: String,
+    /// The long-term key both sides derived, base64
+    pub key: String,
+    /// Unix time
+    pub paired_at: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct StartRequest {
+    pub remote_id: String,
+    pub remote_name: String,
+    /// The remote's SPAKE2 message, base64
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StartResponse {
+    pub mac_id: String,
+    /// The Mac's SPAKE2 message, base64
+    pub message: String,
+    /// SHA-256 of the Mac's TLS certificate, hex; remotes pin it. None over plain HTTP.
+    pub cert_fingerprint: Option<String>,
+    /// HMAC proving the Mac derived the same key, base64
+    pub confirm: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct FinishRequest {
+    pub remote_id: String,
+    /// HMAC proving the remote derived the same key, base64
+    pub confirm: String,
+}
+
+/// Keys derived from the SPAKE2 secret, bound to both messages
+struct Keys {
+    confirm_mac: [u8; 32],
+    confirm_remote: [u8; 32],
+    pairing: [u8; 32],
+}
+
+impl Keys {
+    fn derive(shared: &[u8], remote_message: &[u8], mac_message: &[u8]) -> Self {
+        let transcript = Sha256::new().chain_update(remote_message).chain_update(mac_message).finalize();
+        let hkdf = Hkdf::<Sha256>::new(Some(&transcript), shared);
+        let mut okm = [0; 96];
+        hkdf.expand(KEY_INFO, &mut okm).expect("96 bytes is a valid HKDF-SHA256 length");
+        let mut keys = Keys { confirm_mac: [0; 32], confirm_remote: [0; 32], pairing: [0; 32] };
+        keys.confirm_mac.copy_from_slice(&okm[..32]);
+        keys.confirm_remote.copy_from_slice(&okm[32..64]);
+        keys.pairing.copy_from_slice(&okm[64..]);
+        keys
+    }
+}
+
+/// The Mac's confirmation also vouches for its certificate
+fn mac_confirmation(keys: &Keys, cert_fingerprint: Option<&str>) -> HmacSha256 {
+    let mut mac = confirmation(&keys.confirm_mac, b"mac");
+    mac.update(cert_fingerprint.unwrap_or_default().as_bytes());
+    mac
+}
+
+fn confirmation(key: &[u8; 32], label: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes any key length");
+    mac.update(label);
+    mac
+}
+
+fn remote_identity(remote_id: &str) -> Identity {
+    Identity::new(format!("audioremote remote {remote_id}").as_bytes())
+}
+
+fn decode_base64(value: &str, what: &str) -> Result<Vec<u8>, AudioRemoteError> {
+    STANDARD.decode(value).map_err(|e| AudioRemoteError::InvalidArgument(format!("{what} isn't base64: {e}")))
+}
+
+pub(crate) fn random_bytes<const N: usize>() -> Result<[u8; N], AudioRemoteError> {
+    let mut bytes = [0; N];
+    getrandom::getrandom(&mut bytes).map_err(|e| AudioRemoteError::Other(format!("no randomness available: {e}")))?;
+    Ok(bytes)
+}
+
+/// A uniformly random PIN, zero-padded
+fn random_pin() -> Result<String, AudioRemoteError> {
+    let limit = 10u32.pow(PIN_DIGITS as u32);
+    // Rejection sampling keeps the distribution flat
+    loop {
+        let value = u32::from_le_bytes(random_bytes()?);
+        if value < u32::MAX - u32::MAX % limit {
+            return Ok(format!("{:0width$}", value % limit, width = PIN_DIGITS));
+        }
+    }
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// This Mac's pairing identity, created on first use
+pub fn mac_id() -> Result<String, AudioRemoteError> {
+    let store = store::global();
+    if let Some(id) = store.get::<String>(MAC_ID_KEY) {
+        return Ok(id);
+    }
+    let id: String = random_bytes::<16>()?.iter().map(|b| format!("{b:02x}")).collect();
+    store.set(MAC_ID_KEY, &id)?;
+    Ok(id)
+}
+
+/// The pairings as last read from or written to the store, with the store directory they
+/// belong to; every request checks them, so they aren't read from disk each time
+static PAIRINGS: Mutex<Option<(PathBuf, BTreeMap<String, Pairing>)>> = Mutex::new(None);
+
+fn with_pairings<T>(f: impl FnOnce(&mut BTreeMap<String, Pairing>) -> T) -> T {
+    let mut cached = PAIRINGS.lock().unwrap_or_else(|e| e.into_inner());
+    let dir = store::directory();
+    if !matches!(&*cached, Some((cached_dir, _)) if *cached_dir == dir) {
+        *cached = Some((dir, store::global().get(PAIRINGS_KEY).unwrap_or_default()));
+    }
+    f(&mut cached.as_mut().expect("the pairings were just loaded").1)
+}
+
+fn persist(pairings: &mut BTreeMap<String, Pairing>) -> Result<(), AudioRemoteError> {
+    let store = store::global();
+    let result = store.set(PAIRINGS_KEY, pairings);
+    if result.is_err() {
+        // Stay in step with what's on disk
+        *pairings = store.get(PAIRINGS_KEY).unwrap_or_default();
+    }
+    result
+}
+
+/// Every paired remote, by remote ID
+pub fn pairings() -> BTreeMap<String, Pairing> {
+    with_pairings(|pairings| pairings.clone())
+}
+
+pub fn is_paired(remote_id: &str) -> bool {
+    with_pairings(|pairings| pairings.contains_key(remote_id))
+}
+
+pub(crate) fn save(pairing: Pairing) -> Result<(), AudioRemoteError> {
+    with_pairings(|pairings| {
+        pairings.insert(pairing.remote_id.clone(), pairing);
+        persist(pairings)
+    })
+}
+
+/// Forget a pairing; use auth::revoke, which also revokes its tokens
+pub(crate) fn remove(remote_id: &str) -> Result<bool, AudioRemoteError> {
+    with_pairings(|pairings| {
+        if pairings.remove(remote_id).is_none() {
+            return Ok(false);
+        }
+        persist(pairings)?;
+        Ok(true)
+    })
+}
+
+/// (event, JSON, ctx): `{"remoteId", "remoteName"}`, only valid during the call
+pub type PairingCallback = extern "C" fn(event: i32, json: *const c_char, ctx: *mut c_void);
+
+struct Handshake {
+    remote_name: String,
+    started: Instant,
+    confirm_key: [u8; 32],
+    pairing_key: [u8; 32],
+}
+
+struct Window {
+    pin: String,
+    expires: Instant,
+    attempts: u32,
+    handshakes: BTreeMap<String, Handshake>,
+    callback: Option<(PairingCallback, SendPtr)>,
+}
+
+impl Window {
+    fn notify(&self, event: PairingEvent, remote_id: &str, remote_name: &str) {
+        if let Some((callback, ctx)) = self.callback {
+            let json = serde_json::json!({"remoteId": remote_id, "remoteName": remote_name}).to_string();
+            let json = CString::new(json).unwrap_or_default();
+            runtime::deliver(move || callback(event as i32, json.as_ptr(), ctx.get()));
+        }
+    }
+}
+
+static WINDOW: Mutex<Option<Window>> = Mutex::new(None);
+
+/// An HTTP status and message for the remote
+pub(crate) type Rejection = (u16, String);
+
+fn open_window(window: &mut Option<Window>) -> Result<&mut Window, Rejection> {
+    if window.as_ref().is_some_and(|w| w.expires <= Instant::now()) {
+        *window = None;
+    }
+    window.as_mut().ok_or((403, "pairing isn't open on this Mac".into()))
+}
+
+/// Open a pairing window, replacing any open one, and return its PIN
+pub(crate) fn begin(callback: Option<(PairingCallback, SendPtr)>) -> Result<String, AudioRemoteError> {
+    let pin = random_pin()?;
+    let window = Window { pin: pin.clone(), expires: Instant::now() + PIN_LIFETIME, attempts: 0, handshakes: BTreeMap::new(), callback };
+    *WINDOW.lock().unwrap_or_else(|e| e.into_inner()) = Some(window);
+    log::info!("pairing window open for {PIN_LIFETIME:?}");
+    Ok(pin)
+}
+
+/// The PIN of the open pairing window, if there is one
+pub(crate) fn current_pin() -> Option<String> {
+    let mut guard = WINDOW.lock().unwrap_or_else(|e| e.into_inner());
+    open_window(&mut guard).ok().map(|window| window.pin.clone())
+}
+
+pub fn cancel() {
+    WINDOW.lock().unwrap_or_else(|e| e.into_inner()).take();
+}
+
+/// The Mac's half of step 1
+pub fn start(request: &StartRequest) -> Result<StartResponse, Rejection> {
+    if request.remote_id.is_empty() || request.remote_name.is_empty() {
+        return Err((400, "remoteId and remoteName are required".into()));
+    }
+    let remote_message = decode_base64(&request.message, "message").map_err(|e| (400, e.to_string()))?;
+    let mac_id = mac_id().map_err(|e| (500, e.to_string()))?;
+    let mut guard = WINDOW.lock().unwrap_or_else(|e| e.into_inner());
+    let window = open_window(&mut guard)?;
+    if window.attempts >= MAX_ATTEMPTS {
+        return Err((429, "too many attempts; start pairing again on the Mac".into()));
+    }
+    window.attempts += 1;
+    let (spake, mac_message) =
+        Spake2::<Ed25519Group>::start_b(&Password::new(&window.pin), &remote_identity(&request.remote_id), &Identity::new(MAC_IDENTITY));
+    let shared = spake.finish(&remote_message).map_err(|e| (400, format!("invalid SPAKE2 message: {e:?}")))?;
+    let keys = Keys::derive(&shared, &remote_message, &mac_message);
+    let cert_fingerprint = server::certificate_fingerprint();
+    let confirm = mac_confirmation(&keys, cert_fingerprint.as_deref()).finalize().into_bytes();
+    window.handshakes.retain(|_, handshake| handshake.started.elapsed() < HANDSHAKE_TIMEOUT);
+    window.handshakes.insert(
+        request.remote_id.clone(),
+        Handshake {
+            remote_name: request.remote_name.clone(),
+            started: Instant::now(),
+            confirm_key: keys.confirm_remote,
+            pairing_key: keys.pairing,
+        },
+    );
+    Ok(StartResponse { mac_id, message: STANDARD.encode(mac_message), cert_fingerprint, confirm: STANDARD.encode(confirm) })
+}
+
+/// The Mac's half of step 2: checks the remote's confirmation and stores the pairing
+pub fn finish(request: &FinishRequest) -> Result<Pairing, Rejection> {
+    let confirm = decode_base64(&request.confirm, "confirm").map_err(|e| (400, e.to_string()))?;
+    let mut guard = WINDOW.lock().unwrap_or_else(|e| e.into_inner());
+    let window = open_window(&mut guard)?;
+    let handshake = window
+        .handshakes
+        .remove(&request.remote_id)
+        .filter(|handshake| handshake.started.elapsed() < HANDSHAKE_TIMEOUT)
+        .ok_or((409, "no pairing in progress for this remote; start again".into()))?;
+    if confirmation(&handshake.confirm_key, b"remote").verify_slice(&confirm).is_err() {
+        log::warn!("pairing with {} failed: wrong PIN", request.remote_id);
+        if window.attempts >= MAX_ATTEMPTS {
+            window.notify(PairingEvent::Locked, &request.remote_id, &handshake.remote_name);
+            *guard = None;
+        } else {
+            window.notify(PairingEvent::Failed, &request.remote_id, &handshake.remote_name);
+        }
+        return Err((403, "wrong PIN".into()));
+    }
+    let pairing = Pairing {
+        remote_id: request.remote_id.clone(),
+        remote_name: handshake.remote_name.clone(),
+        key: STANDARD.encode(handshake.pairing_key),
+        paired_at: now(),
+    };
+    save(pairing.clone()).map_err(|e| (500, e.to_string()))?;
+    log::info!("paired with {} ({})", pairing.remote_name, pairing.remote_id);
+    window.notify(PairingEvent::Paired, &pairing.remote_id, &pairing.remote_name);
+    *guard = None;
+    Ok(pairing)
+}
+
+/// Serve the pairing routes; None for any other path
+pub(crate) fn route(request: &Request) -> Option<Response> {
+    if request.path != START_PATH && request.path != FINISH_PATH {
+        return None;
+    }
+    if request.method != "POST" {
+        return Some(Response::error(405, format!("{} isn't allowed here", request.method)).with_header("Allow", "POST"));
+    }
+    let result = match request.path == START_PATH {
+        true => parse(&request.body).and_then(|body| start(&body)).map(|response| serde_json::json!(response)),
+        false => parse(&request.body).and_then(|body| finish(&body)).and_then(|pairing| {
+            let tokens = auth::issue(&pairing.remote_id).map_err(|e| (500, e.to_string()))?;
+            Ok(serde_json::json!({"paired": true, "tokens": tokens}))
+        }),
+    };
+    Some(match result {
+        Ok(body) => Response::json(200, &body),
+        Err((status, message)) => Response::error(status, message),
+    })
+}
+
+pub(crate) fn parse<T: for<'de> Deserialize<'de>>(body: &[u8]) -> Result<T, Rejection> {
+    serde_json::from_slice(body).map_err(|e| (400, format!("invalid JSON: {e}")))
+}
+
+/// The remote's side of the exchange, for remotes built on this library
+pub struct ClientHandshake {
+    spake: Spake2<Ed25519Group>,
+    remote_id: String,
+    message: Vec<u8>,
+}
+
+impl ClientHandshake {
+    pub fn start(pin: &str, remote_id: &str, remote_name: &str) -> (Self, StartRequest) {
+        let (spake, message) =
+            Spake2::<Ed25519Group>::start_a(&Password::new(pin), &remote_identity(remote_id), &Identity::new(MAC_IDENTITY));
+        let request = StartRequest { remote_id: remote_id.into(), remote_name: remote_name.into(), message: STANDARD.encode(&message) };
+        (Self { spake, remote_id: remote_id.into(), message }, request)
+    }
+
+    /// Check the Mac's confirmation; returns the finish request and the pairing key.
+    /// `observed_fingerprint` is the certificate the connection presented, None over plain HTTP.
+    pub fn finish(
+        self,
+        response: &StartResponse,
+        observed_fingerprint: Option<&str>,
+    ) -> Result<(FinishRequest, [u8; 32]), AudioRemoteError> {
+        if response.cert_fingerprint.as_deref() != observed_fingerprint {
+            return Err(AudioRemoteError::VerificationFailed("the connection's certificate isn't the Mac's".into()));
+        }
+        let mac_message = decode_base64(&response.message, "message")?;
+        let shared =
+            self.spake.finish(&mac_message).map_err(|e| AudioRemoteError::InvalidData(format!("invalid SPAKE2 message: {e:?}")))?;
+        let keys = Keys::derive(&shared, &self.message, &mac_message);
+        mac_confirmation(&keys, observed_fingerprint)
+            .verify_slice(&decode_base64(&response.confirm, "confirm")?)
+            .map_err(|_| AudioRemoteError::VerificationFailed("the Mac's confirmation doesn't match; wrong PIN?".into()))?;
+        let confirm = confirmation(&keys.confirm_remote, b"remote").finalize().into_bytes();
+        Ok((FinishRequest { remote_id: self.remote_id, confirm: STANDARD.encode(confirm) }, keys.pairing))
+    }
+}
+
+/// Open a pairing window for 5 minutes, replacing any open one, and return the PIN to show.
+/// `callback` (nullable) gets an ArPairingEvent and {"remoteId", "remoteName"} for each attempt;
+/// the window closes after a remote pairs or after 5 wrong PINs.
+/// Returns: the 6-digit PIN (free with rust_string_free), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `ctx` is passed to `callback` from other threads; it must stay valid until the window closes.
+#[no_mangle]
+pub unsafe extern "C" fn ar_pairing_begin(callback: Option<PairingCallback>, ctx: *mut c_void) -> *mut c_char {
+    guard("ar_pairing_begin", std::ptr::null_mut(), || string_result(begin(callback.map(|callback| (callback, SendPtr(ctx))))))
+}
+
+/// Close the pairing window; the PIN stops working at once
+#[no_mangle]
+pub extern "C" fn ar_pairing_cancel() {
+    guard("ar_pairing_cancel", (), cancel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::{file_mode, with_temp_store};
+
+    /// Run both halves against the global window, as the routes would
+    fn pair(pin: &str, remote_id: &str) -> Result<[u8; 32], (u16, String)> {
+        let (client, request) = ClientHandshake::start(pin, remote_id, "Alex's iPhone");
+        let response = start(&request)?;
+        match client.finish(&response, None) {
+            Ok((finish_request, key)) => finish(&finish_request).map(|_| key),
+            Err(_) => {
+                // A remote with the wrong PIN still reports in, so the Mac counts the failure
+                finish(&FinishRequest { remote_id: remote_id.into(), confirm: STANDARD.encode([0; 32]) }).map(|_| [0; 32])
+            }
+        }
+    }
+
+    #[test]
+    fn test_pairing_with_the_right_and_wrong_pin() {
+        let (_lock, dir) = with_temp_store("pairing");
+        assert_eq!(pair("123456", "phone").unwrap_err().0, 403, "no window open");
+
+        let pin = begin(None).unwrap();
+        assert_eq!(pin.len(), 6);
+        assert!(pin.bytes().all(|b| b.is_ascii_digit()));
+        let wrong = if pin == "000000" { "000001" } else { "000000" };
+        assert_eq!(pair(wrong, "phone").unwrap_err(), (403, "wrong PIN".into()));
+
+        let key = pair(&pin, "phone").unwrap();
+        let stored = &pairings()["phone"];
+        assert_eq!(stored.remote_name, "Alex's iPhone");
+        assert_eq!(STANDARD.decode(&stored.key).unwrap(), key);
+        // Nobody but the user can read the key
+        assert_eq!(file_mode(&dir), 0o600);
+        // The window closes once a remote has paired
+        assert_eq!(pair(&pin, "tablet").unwrap_err().0, 403);
+    }
+
+    #[test]
+    fn test_window_locks_after_too_many_attempts() {
+        let (_lock, _dir) = with_temp_store("pairing-lock");
+        let pin = begin(None).unwrap();
+        let wrong = if pin == "999999" { "999998" } else { "999999" };
+        for _ in 0..MAX_ATTEMPTS {
+            assert_eq!(pair(wrong, "laptop").unwrap_err().0, 403);
+        }
+        // Locked: even the right PIN is refused now
+        assert_eq!(pair(&pin, "laptop").unwrap_err().0, 403);
+        assert!(pairings().is_empty());
+        assert_eq!(mac_id().unwrap(), mac_id().unwrap());
+    }
+}
@@ -0,0 +1,207 @@
+//! Persisted update policy shared by every update check
+
+use std::cmp::Ordering;
+use std::ffi::c_char;
+
+use semver::Version;
+
+use crate::store::{self, Store};
+use crate::version::{self, ParseMode};
+use crate::{guard, record, version_arg_with_mode, AudioRemoteError};
+
+const SKIPPED_VERSION_KEY: &str = "update.skippedVersion";
+const LAST_LAUNCHED_KEY: &str = "update.lastLaunchedVersion";
+const ALLOW_DOWNGRADE_KEY: &str = "update.allowDowngrade";
+
+/// The version the user chose to skip, if any
+pub fn skipped_version(store: &Store) -> Option<Version> {
+    let raw: String = store.get(SKIPPED_VERSION_KEY)?;
+    version::parse(&raw, ParseMode::Lenient).ok()
+}
+
+/// Skipping a version also hides anything older; newer releases are offered again
+pub fn is_skipped(store: &Store, candidate: &Version) -> bool {
+    skipped_version(store).is_some_and(|skipped| candidate.cmp_precedence(&skipped) != Ordering::Greater)
+}
+
+pub fn skip_version(store: &Store, version: &Version) -> Result<(), AudioRemoteError> {
+    store.set(SKIPPED_VERSION_KEY, &version.to_string())
+}
+
+pub fn clear_skipped(store: &Store) -> Result<(), AudioRemoteError> {
+    store.remove(SKIPPED_VERSION_KEY)
+}
+
+/// The version that last launched successfully, if any was recorded
+pub fn last_launched_version(store: &Store) -> Option<Version> {
+    let raw: String = store.get(LAST_LAUNCHED_KEY)?;
+    version::parse(&raw, ParseMode::Lenient).ok()
+}
+
+/// Record a successful launch. This also consumes a pending downgrade override,
+/// so an explicit rollback is allowed once and then protection resumes.
+pub fn record_launch(store: &Store, version: &Version) -> Result<(), AudioRemoteError> {
+    store.set(LAST_LAUNCHED_KEY, &version.to_string())?;
+    store.remove(ALLOW_DOWNGRADE_KEY)
+}
+
+pub fn set_allow_downgrade(store: &Store, allow: bool) -> Result<(), AudioRemoteError> {
+    match allow {
+        true => store.set(ALLOW_DOWNGRADE_KEY, &true),
+        false => store.remove(ALLOW_DOWNGRADE_KEY),
+    }
+}
+
+pub fn allows_downgrade(store: &Store) -> bool {
+    store.get(ALLOW_DOWNGRADE_KEY).unwrap_or(false)
+}
+
+/// Whether installing `candidate` would go below the last launched version
+/// without the user having asked for a rollback
+pub fn is_downgrade(store: &Store, candidate: &Version) -> bool {
+    !allows_downgrade(store)
+        && last_launched_version(store).is_some_and(|last| candidate.cmp_precedence(&last) == Ordering::Less)
+}
+
+/// Remember "Skip this version" across restarts; update checks stop offering it
+/// (and anything older) until a newer release appears
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `version_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn update_skip_version(version_ptr: *const c_char) -> i32 {
+    guard("update_skip_version", -999, || {
+        let result =
+            version_arg_with_mode(version_ptr, "version", ParseMode::Lenient).and_then(|v| skip_version(&store::global(), &v));
+        match record(result) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Check whether a version was skipped by the user
+/// Returns: 1 if skipped, 0 if not, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `version_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn update_is_skipped(version_ptr: *const c_char) -> i32 {
+    guard("update_is_skipped", -999, || {
+        let result = version_arg_with_mode(version_ptr, "version", ParseMode::Lenient).map(|v| is_skipped(&store::global(), &v));
+        match record(result) {
+            Some(skipped) => skipped as i32,
+            None => -999,
+        }
+    })
+}
+
+/// Forget the skipped version
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn update_clear_skipped() -> i32 {
+    guard("update_clear_skipped", -999, || {
+        match record(clear_skipped(&store::global())) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Record that `version` launched successfully; call once per launch.
+/// Also clears a pending update_set_allow_downgrade override.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `version_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn update_record_launch(version_ptr: *const c_char) -> i32 {
+    guard("update_record_launch", -999, || {
+        let result =
+            version_arg_with_mode(version_ptr, "version", ParseMode::Lenient).and_then(|v| record_launch(&store::global(), &v));
+        match record(result) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Check whether installing `candidate` would downgrade below the last launched version
+/// Returns: 1 if it is a downgrade (and no override is set), 0 if not, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `candidate_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn update_is_downgrade(candidate_ptr: *const c_char) -> i32 {
+    guard("update_is_downgrade", -999, || {
+        let result = version_arg_with_mode(candidate_ptr, "candidate", ParseMode::Lenient)
+            .map(|v| is_downgrade(&store::global(), &v));
+        match record(result) {
+            Some(downgrade) => downgrade as i32,
+            None => -999,
+        }
+    })
+}
+
+/// Allow a downgrade for an explicit rollback; the override lasts until the next recorded launch
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn update_set_allow_downgrade(allow: bool) -> i32 {
+    guard("update_set_allow_downgrade", -999, || {
+        match record(set_allow_downgrade(&store::global(), allow)) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::with_temp_store;
+    use std::ffi::CString;
+
+    #[test]
+    fn test_skip_version_persists() {
+        let (_guard, dir) = with_temp_store("skip");
+        let v = |s: &str| CString::new(s).unwrap();
+
+        assert_eq!(unsafe { update_is_skipped(v("2.7.0").as_ptr()) }, 0);
+        assert_eq!(unsafe { update_skip_version(v("v2.7").as_ptr()) }, 1);
+        assert_eq!(unsafe { update_is_skipped(v("2.7.0").as_ptr()) }, 1);
+        assert_eq!(unsafe { update_is_skipped(v("2.6.9").as_ptr()) }, 1);
+        assert_eq!(unsafe { update_is_skipped(v("2.7.1").as_ptr()) }, 0);
+
+        // Survives a "restart": a fresh store on the same directory
+        assert!(is_skipped(&Store::open(&dir), &Version::new(2, 7, 0)));
+
+        assert_eq!(update_clear_skipped(), 1);
+        assert_eq!(unsafe { update_is_skipped(v("2.7.0").as_ptr()) }, 0);
+        assert_eq!(unsafe { update_skip_version(v("latest").as_ptr()) }, -999);
+    }
+
+    #[test]
+    fn test_downgrade_protection() {
+        let (_guard, dir) = with_temp_store("downgrade");
+        let store = Store::open(&dir);
+        let v = |s: &str| version::parse(s, ParseMode::Lenient).unwrap();
+
+        // Nothing recorded yet: nothing counts as a downgrade
+        assert!(!is_downgrade(&store, &v("1.0")));
+
+        record_launch(&store, &v("2.7.0")).unwrap();
+        assert!(is_downgrade(&store, &v("2.6.9")));
+        assert!(!is_downgrade(&store, &v("2.7.0")));
+        assert!(!is_downgrade(&store, &v("2.8")));
+
+        // An explicit rollback is allowed until the rolled-back version launches
+        let candidate = CString::new("2.6.0").unwrap();
+        assert_eq!(update_set_allow_downgrade(true), 1);
+        assert_eq!(unsafe { update_is_downgrade(candidate.as_ptr()) }, 0);
+        assert_eq!(unsafe { update_record_launch(candidate.as_ptr()) }, 1);
+        assert_eq!(last_launched_version(&store), Some(v("2.6.0")));
+        assert!(!allows_downgrade(&store));
+        assert!(is_downgrade(&store, &v("2.5.0")));
+    }
+}
@@ -0,0 +1,879 @@
+//! Port mapping, for users who want to reach the Mac from outside without a
+//! relay: the router is asked to forward a port to the server with PCP
+//! (RFC 6887), NAT-PMP (RFC 6886) or UPnP-IGD, in that order, and whichever
+//! answers reports the external address remotes should use.
+//!
+//! Mappings are leases. The client renews at half the granted lifetime, asks
+//! for the same external port every time, and deletes the mapping on stop so
+//! the router doesn't keep forwarding to a Mac that isn't listening. PCP and
+//! NAT-PMP talk to the default gateway on port 5351; UPnP-IGD finds the router
+//! with an SSDP search and drives its WANIPConnection service over SOAP.
+
+use std::ffi::c_char;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::connection::{self, Backoff, ConnectionState};
+use crate::error::string_result;
+use crate::pairing::random_bytes;
+use crate::{guard, http, record, server, ssdp, str_arg, AudioRemoteError};
+
+/// Where PCP and NAT-PMP servers listen on the gateway
+pub const GATEWAY_PORT: u16 = 5351;
+const NAT_PMP_VERSION: u8 = 0;
+const PCP_VERSION: u8 = 2;
+const PCP_MAP: u8 = 1;
+/// Set on answers, in both PCP and NAT-PMP
+const RESPONSE_BIT: u8 = 0x80;
+const PROTOCOL_TCP: u8 = 6;
+const PCP_PACKET_BYTES: usize = 60;
+/// RFC 6886 §3.1 starts at 250 ms and doubles; three tries keep a silent gateway from stalling the fallbacks
+const RETRIES: [Duration; 3] = [Duration::from_millis(250), Duration::from_millis(500), Duration::from_millis(1000)];
+const IGD_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// In order of preference
+const IGD_SERVICES: [&str; 3] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:2",
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+const IGD_SEARCH_TIME: Duration = Duration::from_millis(2500);
+const IGD_TIMEOUT: Duration = Duration::from_secs(5);
+/// What the router's mapping table shows
+const MAPPING_DESCRIPTION: &str = "Audio Remote";
+/// UPnP error: the router only keeps mappings without an expiry
+const ONLY_PERMANENT_LEASES: u32 = 725;
+const RETRY_INITIAL: Duration = Duration::from_secs(30);
+const RETRY_MAX: Duration = Duration::from_secs(30 * 60);
+/// Renewing more often than this is pointless, whatever the router grants
+const MIN_RENEWAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Method {
+    Pcp,
+    NatPmp,
+    Upnp,
+}
+
+impl Method {
+    pub const ALL: [Method; 3] = [Method::Pcp, Method::NatPmp, Method::Upnp];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Pcp => "PCP",
+            Self::NatPmp => "NAT-PMP",
+            Self::Upnp => "UPnP-IGD",
+        }
+    }
+}
+
+fn default_methods() -> Vec<Method> {
+    Method::ALL.to_vec()
+}
+
+fn default_lifetime() -> u32 {
+    7200
+}
+
+/// `ar_portmap_start` configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PortMapConfig {
+    /// The port to forward to; default: the running server's
+    pub port: Option<u16>,
+    /// The external port to ask for; default: `port`. The router may grant another
+    pub external_port: Option<u16>,
+    /// Tried in order
+    #[serde(default = "default_methods")]
+    pub methods: Vec<Method>,
+    /// Default: the default route's gateway
+    pub gateway: Option<Ipv4Addr>,
+    #[serde(default = "default_lifetime")]
+    pub lifetime_secs: u32,
+}
+
+/// A mapping the router granted
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mapping {
+    pub method: Method,
+    /// Null when the router won't say (UPnP routers sometimes don't)
+    pub external_address: Option<IpAddr>,
+    pub external_port: u16,
+    pub internal_port: u16,
+    /// Zero for a permanent UPnP mapping
+    pub lifetime: Duration,
+    lease: Lease,
+}
+
+/// What renewing or deleting the mapping needs
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Lease {
+    Pcp { gateway: SocketAddr, nonce: [u8; 12] },
+    NatPmp { gateway: SocketAddr },
+    Upnp { control_url: String, service: String, client: Ipv4Addr },
+}
+
+/// What to ask for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Request {
+    internal_port: u16,
+    external_port: u16,
+    lifetime_secs: u32,
+}
+
+/// The default route's gateway
+#[cfg(target_os = "macos")]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    let mut mib = [libc::CTL_NET, libc::PF_ROUTE, 0, libc::AF_INET, libc::NET_RT_FLAGS, libc::RTF_GATEWAY];
+    let mut length = 0;
+    if unsafe { libc::sysctl(mib.as_mut_ptr(), mib.len() as u32, std::ptr::null_mut(), &mut length, std::ptr::null_mut(), 0) } != 0 {
+        return None;
+    }
+    let mut buffer = vec![0u8; length];
+    if unsafe { libc::sysctl(mib.as_mut_ptr(), mib.len() as u32, buffer.as_mut_ptr().cast(), &mut length, std::ptr::null_mut(), 0) } != 0 {
+        return None;
+    }
+    buffer.truncate(length);
+    let header = std::mem::size_of::<libc::rt_msghdr>();
+    let mut offset = 0;
+    while offset + header <= buffer.len() {
+        let message: libc::rt_msghdr = unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+        let end = offset + usize::from(message.rtm_msglen);
+        if end <= offset || end > buffer.len() {
+            break;
+        }
+        // The addresses follow the header, each padded to 4 bytes, in RTA_* bit order
+        let mut addresses = &buffer[offset + header..end];
+        let (mut destination, mut gateway) = (None, None);
+        for bit in 0..libc::RTAX_MAX {
+            if message.rtm_addrs & (1 << bit) == 0 {
+                continue;
+            }
+            let Some(&length) = addresses.first() else {
+                break;
+            };
+            let length = usize::from(length).min(addresses.len());
+            let address = sockaddr_v4(&addresses[..length]);
+            match 1 << bit {
+                libc::RTA_DST => destination = address,
+                libc::RTA_GATEWAY => gateway = address,
+                _ => {}
+            }
+            let padded = if length == 0 { 4 } else { (length + 3) & !3 };
+            addresses = &addresses[padded.min(addresses.len())..];
+        }
+        if destination == Some(Ipv4Addr::UNSPECIFIED) {
+            if let Some(gateway) = gateway.filter(|gateway| !gateway.is_unspecified()) {
+                return Some(gateway);
+            }
+        }
+        offset = end;
+    }
+    None
+}
+
+/// A sockaddr_in's address; the kernel cuts trailing zeros, so a short one is still valid
+#[cfg(target_os = "macos")]
+fn sockaddr_v4(bytes: &[u8]) -> Option<Ipv4Addr> {
+    if bytes.len() >= 2 && bytes[1] != libc::AF_INET as u8 {
+        return None;
+    }
+    let mut octets = [0; 4];
+    for (i, octet) in octets.iter_mut().enumerate() {
+        *octet = bytes.get(4 + i).copied().unwrap_or(0);
+    }
+    Some(Ipv4Addr::from(octets))
+}
+
+/// The default route's gateway
+#[cfg(target_os = "linux")]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    parse_route_table(&std::fs::read_to_string("/proc/net/route").ok()?)
+}
+
+/// /proc/net/route: hex fields in host byte order, RTF_UP | RTF_GATEWAY is 0x3
+#[cfg(target_os = "linux")]
+fn parse_route_table(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let flags = u32::from_str_radix(fields.get(3)?, 16).ok()?;
+        if *fields.get(1)? != "00000000" || flags & 0x3 != 0x3 {
+            return None;
+        }
+        Some(Ipv4Addr::from(u32::from_str_radix(fields.get(2)?, 16).ok()?.to_le_bytes()))
+    })
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "linux")))]
+pub fn default_gateway() -> Option<Ipv4Addr> {
+    None
+}
+
+fn gateway_socket(gateway: SocketAddr) -> Result<UdpSocket, AudioRemoteError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect(gateway)?;
+    Ok(socket)
+}
+
+/// Send `request` until an answer `accept` takes arrives, backing off like RFC 6886 asks
+fn transact(socket: &UdpSocket, request: &[u8], accept: impl Fn(&[u8]) -> bool) -> Result<Vec<u8>, AudioRemoteError> {
+    let gateway = socket.peer_addr()?;
+    let mut buffer = [0; 1100];
+    for wait in RETRIES {
+        socket.send(request)?;
+        let deadline = Instant::now() + wait;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+            socket.set_read_timeout(Some(left))?;
+            match socket.recv(&mut buffer) {
+                Ok(length) if accept(&buffer[..length]) => return Ok(buffer[..length].to_vec()),
+                Ok(_) => {}
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => break,
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    return Err(AudioRemoteError::Network(format!("{gateway} doesn't take port mapping requests")))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+    Err(AudioRemoteError::Network(format!("no answer from {gateway}")))
+}
+
+fn nat_pmp_result(code: u16) -> Result<(), AudioRemoteError> {
+    let reason = match code {
+        0 => return Ok(()),
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown error",
+    };
+    Err(AudioRemoteError::Network(format!("the gateway refused: {reason} ({code})")))
+}
+
+fn nat_pmp_request(request: &Request) -> [u8; 12] {
+    let mut packet = [0; 12];
+    packet[0] = NAT_PMP_VERSION;
+    packet[1] = 2; // map TCP
+    packet[4..6].copy_from_slice(&request.internal_port.to_be_bytes());
+    packet[6..8].copy_from_slice(&request.external_port.to_be_bytes());
+    packet[8..12].copy_from_slice(&request.lifetime_secs.to_be_bytes());
+    packet
+}
+
+fn nat_pmp(gateway: SocketAddr, request: &Request) -> Result<Mapping, AudioRemoteError> {
+    let socket = gateway_socket(gateway)?;
+    let answer = transact(&socket, &[NAT_PMP_VERSION, 0], |p| p.len() >= 12 && p[0] == NAT_PMP_VERSION && p[1] == RESPONSE_BIT)?;
+    nat_pmp_result(u16::from_be_bytes([answer[2], answer[3]]))?;
+    let external_address = Ipv4Addr::new(answer[8], answer[9], answer[10], answer[11]);
+
+    let internal = request.internal_port.to_be_bytes();
+    let answer = transact(&socket, &nat_pmp_request(request), |p| {
+        p.len() >= 16 && p[0] == NAT_PMP_VERSION && p[1] == RESPONSE_BIT | 2 && p[8..10] == internal
+    })?;
+    nat_pmp_result(u16::from_be_bytes([answer[2], answer[3]]))?;
+    Ok(Mapping {
+        method: Method::NatPmp,
+        external_address: Some(IpAddr::V4(external_address)),
+        external_port: u16::from_be_bytes([answer[10], answer[11]]),
+        internal_port: request.internal_port,
+        lifetime: Duration::from_secs(u64::from(u32::from_be_bytes([answer[12], answer[13], answer[14], answer[15]]))),
+        lease: Lease::NatPmp { gateway },
+    })
+}
+
+fn pcp_result(code: u8) -> Result<(), AudioRemoteError> {
+    let reason = match code {
+        0 => return Ok(()),
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "malformed request",
+        4 => "unsupported opcode",
+        5 => "unsupported option",
+        6 => "malformed option",
+        7 => "network failure",
+        8 => "no resources",
+        9 => "unsupported protocol",
+        10 => "user over quota",
+        11 => "can't provide the external address",
+        12 => "address mismatch",
+        13 => "too many remote peers",
+        _ => "unknown error",
+    };
+    Err(AudioRemoteError::Network(format!("the gateway refused: {reason} ({code})")))
+}
+
+/// A MAP request: the 24-byte common header, then the MAP opcode's 36 bytes
+fn pcp_request(client: Ipv4Addr, nonce: &[u8; 12], request: &Request) -> [u8; PCP_PACKET_BYTES] {
+    let mut packet = [0; PCP_PACKET_BYTES];
+    packet[0] = PCP_VERSION;
+    packet[1] = PCP_MAP;
+    packet[4..8].copy_from_slice(&request.lifetime_secs.to_be_bytes());
+    packet[8..24].copy_from_slice(&client.to_ipv6_mapped().octets());
+    packet[24..36].copy_from_slice(nonce);
+    packet[36] = PROTOCOL_TCP;
+    packet[40..42].copy_from_slice(&request.internal_port.to_be_bytes());
+    packet[42..44].copy_from_slice(&request.external_port.to_be_bytes());
+    // No preference for the external address: the IPv4-mapped unspecified address
+    packet[44..60].copy_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    packet
+}
+
+fn pcp(gateway: SocketAddr, request: &Request, nonce: Option<[u8; 12]>) -> Result<Mapping, AudioRemoteError> {
+    let socket = gateway_socket(gateway)?;
+    let IpAddr::V4(client) = socket.local_addr()?.ip() else {
+        return Err(AudioRemoteError::Network("no IPv4 address toward the gateway".into()));
+    };
+    let nonce = match nonce {
+        Some(nonce) => nonce,
+        None => random_bytes()?,
+    };
+    let answer = transact(&socket, &pcp_request(client, &nonce, request), |p| {
+        // A NAT-PMP-only gateway answers in its own version
+        (p.len() >= 4 && p[0] != PCP_VERSION) || (p.len() >= PCP_PACKET_BYTES && p[1] == RESPONSE_BIT | PCP_MAP && p[24..36] == nonce)
+    })?;
+    if answer[0] != PCP_VERSION {
+        return Err(AudioRemoteError::Unsupported(format!("the gateway speaks NAT-PMP, not PCP (version {})", answer[0])));
+    }
+    pcp_result(answer[3])?;
+    let external: [u8; 16] = answer[44..60].try_into().expect("16 bytes");
+    let external = Ipv6Addr::from(external);
+    Ok(Mapping {
+        method: Method::Pcp,
+        external_address: Some(external.to_ipv4_mapped().map_or(IpAddr::V6(external), IpAddr::V4)),
+        external_port: u16::from_be_bytes([answer[42], answer[43]]),
+        internal_port: request.internal_port,
+        lifetime: Duration::from_secs(u64::from(u32::from_be_bytes([answer[4], answer[5], answer[6], answer[7]]))),
+        lease: Lease::Pcp { gateway, nonce },
+    })
+}
+
+/// LOCATION of an SSDP search answer
+fn search_location(packet: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(packet).ok()?;
+    let mut lines = text.split("\r\n");
+    if !lines.next()?.starts_with("HTTP/1.1 200") {
+        return None;
+    }
+    lines.take_while(|line| !line.is_empty()).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim().eq_ignore_ascii_case("LOCATION").then(|| value.trim().to_owned())
+    })
+}
+
+/// `path` as seen from `base`, e.g. a controlURL from the description at LOCATION
+fn resolve_url(base: &str, path: &str) -> String {
+    if path.starts_with("http://") || path.starts_with("https://") {
+        return path.to_owned();
+    }
+    let after_scheme = base.find("://").map_or(0, |i| i + 3);
+    let origin = match base[after_scheme..].find('/') {
+        Some(i) => &base[..after_scheme + i],
+        None => base,
+    };
+    match path.starts_with('/') {
+        true => format!("{origin}{path}"),
+        false => format!("{origin}/{path}"),
+    }
+}
+
+/// The address and port a URL points at
+fn url_address(url: &str) -> Option<SocketAddr> {
+    let rest = url.strip_prefix("http://")?;
+    let authority = rest.split('/').next()?;
+    match authority.contains(':') {
+        true => authority.to_socket_addrs().ok()?.next(),
+        false => (authority, 80).to_socket_addrs().ok()?.next(),
+    }
+}
+
+/// The WAN connection service in an IGD description: (control URL, service type)
+fn parse_igd_description(xml: &str, location: &str) -> Option<(String, String)> {
+    let document = roxmltree::Document::parse(xml).ok()?;
+    let child_text = |node: roxmltree::Node, name: &str| {
+        node.children().find(|child| child.has_tag_name(name)).and_then(|child| child.text()).map(|text| text.trim().to_owned())
+    };
+    let base = child_text(document.root_element(), "URLBase").unwrap_or_else(|| location.to_owned());
+    let services: Vec<(String, String)> = document
+        .descendants()
+        .filter(|node| node.has_tag_name("service"))
+        .filter_map(|node| Some((child_text(node, "serviceType")?, child_text(node, "controlURL")?)))
+        .collect();
+    IGD_SERVICES.iter().find_map(|wanted| {
+        let (service, control) = services.iter().find(|(service, _)| service == wanted)?;
+        Some((resolve_url(&base, control), service.clone()))
+    })
+}
+
+/// Search for the router's gateway device and fetch its description
+fn find_igd(agent: &ureq::Agent) -> Result<(String, String), AudioRemoteError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    let search = format!("M-SEARCH * HTTP/1.1\r\nHOST: {}:{}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: {IGD_DEVICE}\r\n\r\n", ssdp::SSDP_ADDRESS, ssdp::SSDP_PORT);
+    socket.send_to(search.as_bytes(), (ssdp::SSDP_ADDRESS, ssdp::SSDP_PORT))?;
+    let deadline = Instant::now() + IGD_SEARCH_TIME;
+    let mut buffer = [0; 2048];
+    let mut tried = Vec::new();
+    while let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) {
+        socket.set_read_timeout(Some(left))?;
+        let Ok(length) = socket.recv(&mut buffer) else {
+            break;
+        };
+        let Some(location) = search_location(&buffer[..length]).filter(|location| !tried.contains(location)) else {
+            continue;
+        };
+        tried.push(location.clone());
+        let description = match agent.get(&location).call() {
+            Ok(response) => response.into_string().map_err(AudioRemoteError::from),
+            Err(e) => Err(AudioRemoteError::Network(e.to_string())),
+        };
+        match description.map(|xml| parse_igd_description(&xml, &location)) {
+            Ok(Some(found)) => return Ok(found),
+            Ok(None) => log::debug!("{location} has no WAN connection service"),
+            Err(e) => log::debug!("can't fetch {location}: {e}"),
+        }
+    }
+    Err(AudioRemoteError::Network("no UPnP internet gateway answered".into()))
+}
+
+enum SoapError {
+    /// The router's UPnPError
+    Fault { code: u32, description: String },
+    Failed(AudioRemoteError),
+}
+
+impl From<SoapError> for AudioRemoteError {
+    fn from(error: SoapError) -> Self {
+        match error {
+            SoapError::Fault { code, description } => Self::Network(format!("the gateway refused: {description} ({code})")),
+            SoapError::Failed(e) => e,
+        }
+    }
+}
+
+/// UPnPError from a SOAP fault
+fn soap_fault(xml: &str) -> Option<(u32, String)> {
+    let document = roxmltree::Document::parse(xml).ok()?;
+    let text = |name: &str| document.descendants().find(|node| node.has_tag_name(name)).and_then(|node| node.text()).map(str::trim);
+    Some((text("errorCode")?.parse().ok()?, text("errorDescription").unwrap_or("error").to_owned()))
+}
+
+/// Call `action` on the connection service; the response body
+fn soap(agent: &ureq::Agent, control_url: &str, service: &str, action: &str, arguments: &[(&str, String)]) -> Result<String, SoapError> {
+    let arguments: String = arguments.iter().map(|(name, value)| format!("<{name}>{value}</{name}>")).collect();
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n<s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body><u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body></s:Envelope>\r\n"
+    );
+    let response = agent
+        .post(control_url)
+        .set("Content-Type", "text/xml; charset=\"utf-8\"")
+        .set("SOAPAction", &format!("\"{service}#{action}\""))
+        .send_string(&body);
+    match response {
+        Ok(response) => response.into_string().map_err(|e| SoapError::Failed(e.into())),
+        Err(ureq::Error::Status(code, response)) => match response.into_string().ok().as_deref().and_then(soap_fault) {
+            Some((code, description)) => Err(SoapError::Fault { code, description }),
+            None => Err(SoapError::Failed(AudioRemoteError::Network(format!("HTTP {code} from {control_url}")))),
+        },
+        Err(e) => Err(SoapError::Failed(AudioRemoteError::Network(e.to_string()))),
+    }
+}
+
+fn upnp_agent() -> ureq::Agent {
+    // The router is on the LAN: no update proxy
+    ureq::AgentBuilder::new().timeout(IGD_TIMEOUT).user_agent(&http::user_agent()).build()
+}
+
+fn upnp(request: &Request, lease: Option<&Lease>) -> Result<Mapping, AudioRemoteError> {
+    let agent = upnp_agent();
+    let (control_url, service, client) = match lease {
+        Some(Lease::Upnp { control_url, service, client }) => (control_url.clone(), service.clone(), *client),
+        _ => {
+            let (control_url, service) = find_igd(&agent)?;
+            let client = url_address(&control_url)
+                .and_then(ssdp::local_address_toward)
+                .and_then(|address| match address {
+                    IpAddr::V4(address) => Some(address),
+                    IpAddr::V6(_) => None,
+                })
+                .ok_or_else(|| AudioRemoteError::Network(format!("no IPv4 address toward {control_url}")))?;
+            (control_url, service, client)
+        }
+    };
+    let add = |lifetime_secs: u32| {
+        let arguments = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", request.external_port.to_string()),
+            ("NewProtocol", "TCP".into()),
+            ("NewInternalPort", request.internal_port.to_string()),
+            ("NewInternalClient", client.to_string()),
+            ("NewEnabled", "1".into()),
+            ("NewPortMappingDescription", MAPPING_DESCRIPTION.into()),
+            ("NewLeaseDuration", lifetime_secs.to_string()),
+        ];
+        soap(&agent, &control_url, &service, "AddPortMapping", &arguments)
+    };
+    let lifetime_secs = match add(request.lifetime_secs) {
+        Ok(_) => request.lifetime_secs,
+        Err(SoapError::Fault { code: ONLY_PERMANENT_LEASES, .. }) => add(0).map(|_| 0)?,
+        Err(e) => return Err(e.into()),
+    };
+    let external_address = soap(&agent, &control_url, &service, "GetExternalIPAddress", &[]).ok().and_then(|xml| {
+        let document = roxmltree::Document::parse(&xml).ok()?;
+        document.descendants().find(|node| node.has_tag_name("NewExternalIPAddress"))?.text()?.trim().parse().ok()
+    });
+    Ok(Mapping {
+        method: Method::Upnp,
+        external_address,
+        external_port: request.external_port,
+        internal_port: request.internal_port,
+        lifetime: Duration::from_secs(u64::from(lifetime_secs)),
+        lease: Lease::Upnp { control_url, service, client },
+    })
+}
+
+/// Ask for a mapping with each method in turn; `previous` is renewed with its own method first
+fn map(config: &PortMapConfig, internal_port: u16, previous: Option<&Mapping>) -> Result<Mapping, AudioRemoteError> {
+    let request = Request {
+        internal_port,
+        external_port: previous.map_or(config.external_port.unwrap_or(internal_port), |mapping| mapping.external_port),
+        lifetime_secs: config.lifetime_secs,
+    };
+    let mut methods = config.methods.clone();
+    if let Some(previous) = previous {
+        methods.sort_by_key(|method| *method != previous.method);
+    }
+    let gateway = config.gateway.or_else(default_gateway).map(|gateway| SocketAddr::from((gateway, GATEWAY_PORT)));
+    let mut errors = Vec::new();
+    for method in methods {
+        let lease = previous.filter(|previous| previous.method == method).map(|previous| &previous.lease);
+        let result = match (method, gateway) {
+            (Method::Pcp, Some(gateway)) => pcp(gateway, &request, lease.and_then(|lease| match lease {
+                Lease::Pcp { nonce, .. } => Some(*nonce),
+                _ => None,
+            })),
+            (Method::NatPmp, Some(gateway)) => nat_pmp(gateway, &request),
+            (Method::Pcp | Method::NatPmp, None) => Err(AudioRemoteError::Network("no default gateway".into())),
+            (Method::Upnp, _) => upnp(&request, lease),
+        };
+        match result {
+            Ok(mapping) => return Ok(mapping),
+            Err(e) => {
+                log::debug!("{} port mapping failed: {e}", method.label());
+                errors.push(format!("{}: {e}", method.label()));
+            }
+        }
+    }
+    Err(AudioRemoteError::Network(format!("the router didn't map the port ({})", errors.join("; "))))
+}
+
+/// Give the mapping back
+fn delete(mapping: &Mapping) -> Result<(), AudioRemoteError> {
+    let request = Request { internal_port: mapping.internal_port, external_port: mapping.external_port, lifetime_secs: 0 };
+    match &mapping.lease {
+        Lease::Pcp { gateway, nonce } => pcp(*gateway, &request, Some(*nonce)).map(drop),
+        // RFC 6886 §3.4: a deletion suggests no external port
+        Lease::NatPmp { gateway } => nat_pmp(*gateway, &Request { external_port: 0, ..request }).map(drop),
+        Lease::Upnp { control_url, service, .. } => {
+            let arguments = [("NewRemoteHost", String::new()), ("NewExternalPort", mapping.external_port.to_string()), ("NewProtocol", "TCP".into())];
+            soap(&upnp_agent(), control_url, service, "DeletePortMapping", &arguments).map(drop).map_err(AudioRemoteError::from)
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Status {
+    state: ConnectionState,
+    attempt: u32,
+    error: Option<String>,
+    mapping: Option<Mapping>,
+}
+
+struct Shared {
+    config: PortMapConfig,
+    internal_port: u16,
+    stopping: AtomicBool,
+    /// Wakes a renewal or retry wait when stopping
+    wake: (Mutex<()>, Condvar),
+    status: Mutex<Status>,
+}
+
+impl Shared {
+    fn set_status(&self, state: ConnectionState, attempt: u32, error: Option<String>, mapping: Option<Mapping>) {
+        *self.status.lock().unwrap_or_else(|e| e.into_inner()) = Status { state, attempt, error, mapping };
+    }
+
+    /// Wait until the next renewal or retry; false if stopped meanwhile
+    fn wait(&self, delay: Duration) -> bool {
+        let (lock, condvar) = &self.wake;
+        let guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = condvar.wait_timeout_while(guard, delay, |_| !self.stopping.load(Ordering::Acquire)).unwrap_or_else(|e| e.into_inner());
+        !self.stopping.load(Ordering::Acquire)
+    }
+}
+
+fn supervise(shared: Arc<Shared>) {
+    let mut backoff = Backoff::new(RETRY_INITIAL, RETRY_MAX);
+    let mut attempt = 0;
+    let mut mapping: Option<Mapping> = None;
+    while !shared.stopping.load(Ordering::Acquire) {
+        let delay = match map(&shared.config, shared.internal_port, mapping.as_ref()) {
+            Ok(granted) => {
+                let external = (granted.external_address, granted.external_port);
+                if mapping.as_ref().map(|mapping| (mapping.external_address, mapping.external_port)) != Some(external) {
+                    let address = granted.external_address.map_or_else(|| "?".to_owned(), |address| address.to_string());
+                    log::info!("port {} is mapped to {address}:{} with {}", granted.internal_port, granted.external_port, granted.method.label());
+                }
+                backoff.reset();
+                attempt = 0;
+                let lifetime = match granted.lifetime.is_zero() {
+                    true => Duration::from_secs(u64::from(shared.config.lifetime_secs)),
+                    false => granted.lifetime,
+                };
+                shared.set_status(ConnectionState::Connected, 0, None, Some(granted.clone()));
+                mapping = Some(granted);
+                (lifetime / 2).max(MIN_RENEWAL)
+            }
+            Err(e) => {
+                attempt += 1;
+                let delay = backoff.next(connection::jitter());
+                log::warn!("port mapping failed ({e}); retrying in {} s", delay.as_secs());
+                let state = if mapping.is_some() || attempt > 1 { ConnectionState::Reconnecting } else { ConnectionState::Connecting };
+                shared.set_status(state, attempt, Some(e.to_string()), None);
+                delay
+            }
+        };
+        if !shared.wait(delay) {
+            break;
+        }
+    }
+    if let Some(mapping) = mapping {
+        match delete(&mapping) {
+            Ok(()) => log::info!("removed the {} port mapping", mapping.method.label()),
+            Err(e) => log::warn!("can't remove the {} port mapping: {e}", mapping.method.label()),
+        }
+    }
+    shared.set_status(ConnectionState::Closed, 0, None, None);
+}
+
+struct Running {
+    shared: Arc<Shared>,
+    supervisor: JoinHandle<()>,
+}
+
+static PORT_MAP: Mutex<Option<Running>> = Mutex::new(None);
+
+/// Start mapping a port, replacing (and deleting) a running mapping
+pub fn start(config: PortMapConfig) -> Result<(), AudioRemoteError> {
+    if config.methods.is_empty() {
+        return Err(AudioRemoteError::InvalidArgument("no port mapping methods".into()));
+    }
+    if config.lifetime_secs < 120 {
+        return Err(AudioRemoteError::InvalidArgument(format!("lifetimeSecs {} is under 120", config.lifetime_secs)));
+    }
+    let internal_port = match config.port.or_else(|| server::local_address().map(|address| address.port())) {
+        Some(port) => port,
+        None => return Err(AudioRemoteError::InvalidArgument("no port given and the server isn't running".into())),
+    };
+    stop();
+    let shared = Arc::new(Shared {
+        config,
+        internal_port,
+        stopping: AtomicBool::new(false),
+        wake: (Mutex::new(()), Condvar::new()),
+        status: Mutex::new(Status { state: ConnectionState::Connecting, attempt: 0, error: None, mapping: None }),
+    });
+    let supervisor = {
+        let shared = shared.clone();
+        thread::Builder::new()
+            .name("audioremote-portmap".into())
+            .spawn(move || supervise(shared))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the port mapping thread: {e}")))?
+    };
+    *PORT_MAP.lock().unwrap_or_else(|e| e.into_inner()) = Some(Running { shared, supervisor });
+    Ok(())
+}
+
+/// Delete the mapping and stop renewing it; a no-op if not running
+pub fn stop() {
+    let Some(running) = PORT_MAP.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    let shared = &running.shared;
+    shared.stopping.store(true, Ordering::Release);
+    {
+        let _wake = shared.wake.0.lock().unwrap_or_else(|e| e.into_inner());
+        shared.wake.1.notify_all();
+    }
+    let _ = running.supervisor.join();
+    log::info!("port mapping stopped");
+}
+
+/// `{"state": ArConnectionState, "attempt", "error", "method", "externalAddress", "externalPort",
+/// "internalPort", "lifetimeSecs"}`
+pub fn status() -> Value {
+    let status = match PORT_MAP.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(running) => running.shared.status.lock().unwrap_or_else(|e| e.into_inner()).clone(),
+        None => Status { state: ConnectionState::Closed, attempt: 0, error: None, mapping: None },
+    };
+    let mapping = status.mapping.as_ref();
+    json!({
+        "state": status.state as i32,
+        "attempt": status.attempt,
+        "error": status.error,
+        "method": mapping.map(|mapping| mapping.method),
+        "externalAddress": mapping.and_then(|mapping| mapping.external_address).map(|address| address.to_string()),
+        "externalPort": mapping.map(|mapping| mapping.external_port),
+        "internalPort": mapping.map(|mapping| mapping.internal_port),
+        "lifetimeSecs": mapping.map(|mapping| mapping.lifetime.as_secs()),
+    })
+}
+
+/// Ask the router to forward a port to the server, for remote access without a relay, replacing
+/// a running mapping. `config_json`: {"port" (default: the running server's), "externalPort"
+/// (default: port; the router may grant another), "methods" (["pcp", "natPmp", "upnp"], tried in
+/// order), "gateway" (IPv4, default: the default route's), "lifetimeSecs" (7200, at least 120)}.
+/// Runs until ar_portmap_stop, renewing at half the lifetime and retrying failures with backoff.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_portmap_start(config_json: *const c_char) -> i32 {
+    guard("ar_portmap_start", -999, || {
+        let result = str_arg(config_json, "config").and_then(|json| {
+            let config: PortMapConfig =
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid port mapping config: {e}")))?;
+            start(config)
+        });
+        record(result).map_or(-999, |()| 1)
+    })
+}
+
+/// Delete the mapping from the router and stop renewing it; a no-op if not running. Also done by
+/// ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_portmap_stop() {
+    guard("ar_portmap_stop", (), stop)
+}
+
+/// Returns: JSON {"state": ArConnectionState (AR_CONNECTION_CONNECTED while mapped), "attempt",
+/// "error", "method" ("pcp", "natPmp" or "upnp"), "externalAddress" (what remotes outside connect
+/// to; null if the router won't say), "externalPort", "internalPort", "lifetimeSecs" (0: permanent)},
+/// the mapping fields null while unmapped (free with rust_string_free)
+#[no_mangle]
+pub extern "C" fn ar_portmap_status() -> *mut c_char {
+    guard("ar_portmap_status", std::ptr::null_mut(), || string_result(Ok(status().to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    /// A gateway on loopback: PCP or, if `pcp` is false, only NAT-PMP. Reports the lifetimes it's asked for
+    fn fake_gateway(pcp: bool) -> (SocketAddr, mpsc::Receiver<u32>) {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let address = socket.local_addr().unwrap();
+        let (lifetimes, received) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 1100];
+            while let Ok((length, peer)) = socket.recv_from(&mut buffer) {
+                let request = &buffer[..length];
+                let answer = match (request[0], request[1]) {
+                    (PCP_VERSION, PCP_MAP) if pcp => {
+                        let mut answer = request.to_vec();
+                        answer[1] |= RESPONSE_BIT;
+                        answer[42..44].copy_from_slice(&40000u16.to_be_bytes());
+                        answer[44..60].copy_from_slice(&Ipv4Addr::new(203, 0, 113, 7).to_ipv6_mapped().octets());
+                        answer[8..24].fill(0);
+                        let _ = lifetimes.send(u32::from_be_bytes(request[4..8].try_into().unwrap()));
+                        answer
+                    }
+                    // RFC 6887 §9: a NAT-PMP server answers PCP with "unsupported version"
+                    (PCP_VERSION, opcode) => vec![0, RESPONSE_BIT | opcode, 0, 1, 0, 0, 0, 9],
+                    (0, 0) => [&[0, RESPONSE_BIT, 0, 0, 0, 0, 0, 9][..], &[203, 0, 113, 7]].concat(),
+                    (0, 2) => {
+                        let lifetime = &request[8..12];
+                        let _ = lifetimes.send(u32::from_be_bytes(lifetime.try_into().unwrap()));
+                        [&[0, RESPONSE_BIT | 2, 0, 0, 0, 0, 0, 9][..], &request[4..6], &40001u16.to_be_bytes(), lifetime].concat()
+                    }
+                    _ => continue,
+                };
+                let _ = socket.send_to(&answer, peer);
+            }
+        });
+        (address, received)
+    }
+
+    #[test]
+    fn test_pcp_and_nat_pmp() {
+        let request = Request { internal_port: 8765, external_port: 8765, lifetime_secs: 3600 };
+
+        let (gateway, lifetimes) = fake_gateway(true);
+        let mapping = pcp(gateway, &request, None).unwrap();
+        assert_eq!((mapping.method, mapping.external_port, mapping.internal_port), (Method::Pcp, 40000, 8765));
+        assert_eq!(mapping.external_address, Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))));
+        assert_eq!(mapping.lifetime, Duration::from_secs(3600));
+        delete(&mapping).unwrap();
+        assert_eq!(lifetimes.try_iter().collect::<Vec<_>>(), [3600, 0]);
+
+        let (gateway, lifetimes) = fake_gateway(false);
+        assert!(matches!(pcp(gateway, &request, None), Err(AudioRemoteError::Unsupported(_))));
+        let mapping = nat_pmp(gateway, &request).unwrap();
+        assert_eq!((mapping.method, mapping.external_port), (Method::NatPmp, 40001));
+        assert_eq!(mapping.external_address, Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7))));
+        delete(&mapping).unwrap();
+        assert_eq!(lifetimes.try_iter().collect::<Vec<_>>(), [3600, 0]);
+
+    }
+
+    #[test]
+    fn test_igd_description() {
+        let answer = b"HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(search_location(answer).as_deref(), Some("http://192.168.1.1:5000/rootDesc.xml"));
+        assert_eq!(search_location(b"NOTIFY * HTTP/1.1\r\nLOCATION: http://x/\r\n\r\n"), None);
+
+        let description = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <deviceType>urn:schemas-upnp-org:device:InternetGatewayDevice:1</deviceType>
+    <deviceList><device>
+      <deviceType>urn:schemas-upnp-org:device:WANDevice:1</deviceType>
+      <deviceList><device>
+        <serviceList>
+          <service><serviceType>urn:schemas-upnp-org:service:WANPPPConnection:1</serviceType><controlURL>/ctl/PPP</controlURL></service>
+          <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType><controlURL>ctl/IPConn</controlURL></service>
+        </serviceList>
+      </device></deviceList>
+    </device></deviceList>
+  </device>
+</root>"#;
+        assert_eq!(
+            parse_igd_description(description, "http://192.168.1.1:5000/rootDesc.xml"),
+            Some(("http://192.168.1.1:5000/ctl/IPConn".into(), "urn:schemas-upnp-org:service:WANIPConnection:1".into()))
+        );
+        assert_eq!(parse_igd_description("<root/>", "http://192.168.1.1/"), None);
+        assert_eq!(url_address("http://192.168.1.1:5000/ctl/IPConn"), Some("192.168.1.1:5000".parse().unwrap()));
+
+        let fault = r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/"><s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring>
+<detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>725</errorCode><errorDescription>OnlyPermanentLeasesSupported</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>"#;
+        assert_eq!(soap_fault(fault), Some((ONLY_PERMANENT_LEASES, "OnlyPermanentLeasesSupported".into())));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_route_table() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n\
+                     eth0\t0002A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n\
+                     eth0\t00000000\t0102A8C0\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+        assert_eq!(parse_route_table(table), Some(Ipv4Addr::new(192, 168, 2, 1)));
+        assert_eq!(parse_route_table(table.lines().take(2).collect::<Vec<_>>().join("\n").as_str()), None);
+    }
+}
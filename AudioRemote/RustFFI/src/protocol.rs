@@ -0,0 +1,526 @@
+//! The remote protocol as typed messages, for transports that don't map onto
+//! REST paths (WebSocket today). On the wire every message is an envelope,
+//! `{"v": protocol version, "id": optional request ID, "type": name, "body": ...}`,
+//! encoded as a MessagePack map with named fields.
+//!
+//! Envelopes are built for forward compatibility: unknown envelope fields are
+//! ignored, and a type this version doesn't know decodes to `Message::Unknown`
+//! rather than failing, so a newer remote talking to an older Mac gets a
+//! sensible answer instead of a dropped connection.
+//!
+//! A remote opens with `hello`, its version range and feature bits; the Mac
+//! answers `welcome` with the highest version both speak and the features both
+//! have. Anything the session can't do is answered `unsupported`, naming the
+//! type, so the remote can fall back instead of waiting for a reply that never
+//! comes. Remotes that skip the hello get `Session::default()`: version 1 with
+//! events and subscribe, what the protocol offered before negotiation.
+//!
+//! A remote that wants bit-perfect monitoring on a strong network asks for
+//! the lossless feature; its session streams audio as FLAC (see `flac`), and
+//! any other session as Opus.
+//!
+//! Sessions with the compression feature get envelopes over
+//! `COMPRESSION_THRESHOLD` bytes (artwork, large snapshots) as a zstd frame
+//! instead. Decoding tells the two apart by the frame magic, which can't start
+//! a MessagePack map, so either side may skip compressing whenever it likes.
+
+use std::ffi::c_char;
+use std::io::Read;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::{bytes_result, string_result};
+use crate::events::{Event, Topic};
+use crate::remote::{NowPlaying, OutputDevice, SelectDevice, SetMute, SetVolume, VolumeState};
+use crate::{guard, str_arg, AudioRemoteError};
+
+/// The newest version this library speaks; envelopes carry the session's as "v"
+pub const PROTOCOL_VERSION: u16 = 1;
+/// The oldest version this library still speaks
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// State pushes: volume, devices, nowPlaying
+pub const FEATURE_EVENTS: u64 = 1 << 0;
+/// Changing topics with subscribe
+pub const FEATURE_SUBSCRIBE: u64 = 1 << 1;
+/// The commands (getVolume, setVolume, ...) over the connection instead of REST
+pub const FEATURE_COMMANDS: u64 = 1 << 2;
+/// Envelopes over `COMPRESSION_THRESHOLD` bytes may be sent as zstd frames
+pub const FEATURE_COMPRESSION: u64 = 1 << 3;
+/// Streamed audio is FLAC rather than Opus; a remote asks for it by setting the bit in its hello
+pub const FEATURE_LOSSLESS: u64 = 1 << 4;
+/// What the Mac offers today
+pub const MAC_FEATURES: u64 = FEATURE_EVENTS | FEATURE_SUBSCRIBE | FEATURE_COMPRESSION | FEATURE_LOSSLESS;
+
+/// Smaller envelopes aren't worth the CPU on either end
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+/// Fast enough not to add latency, still shrinks artwork metadata and device lists well
+const COMPRESSION_LEVEL: i32 = 3;
+/// What a compressed envelope may expand to, so a small frame can't exhaust memory
+const MAX_DECOMPRESSED_BYTES: u64 = 4 * 1024 * 1024;
+/// Every zstd frame starts with this; a MessagePack envelope starts with a map marker
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// One side's opening: the versions it speaks and the features it has
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Hello {
+    pub min_version: u16,
+    pub max_version: u16,
+    #[serde(default)]
+    pub features: u64,
+}
+
+impl Hello {
+    /// The Mac's hello
+    pub fn mac() -> Self {
+        Self { min_version: MIN_PROTOCOL_VERSION, max_version: PROTOCOL_VERSION, features: MAC_FEATURES }
+    }
+
+    fn validate(&self) -> Result<(), AudioRemoteError> {
+        if self.min_version == 0 || self.min_version > self.max_version {
+            return Err(AudioRemoteError::InvalidArgument(format!("invalid version range {}-{}", self.min_version, self.max_version)));
+        }
+        Ok(())
+    }
+
+    /// The session with `peer`: the highest version both speak, the features both have
+    pub fn negotiate(&self, peer: &Hello) -> Result<Session, AudioRemoteError> {
+        let version = self.max_version.min(peer.max_version);
+        if version < self.min_version.max(peer.min_version) {
+            return Err(AudioRemoteError::Unsupported(format!(
+                "no common protocol version: {}-{} here, {}-{} there",
+                self.min_version, self.max_version, peer.min_version, peer.max_version
+            )));
+        }
+        Ok(Session { version, features: self.features & peer.features })
+    }
+}
+
+/// What a connection agreed on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Session {
+    pub version: u16,
+    pub features: u64,
+}
+
+impl Default for Session {
+    /// For remotes that never say hello
+    fn default() -> Self {
+        Self { version: 1, features: FEATURE_EVENTS | FEATURE_SUBSCRIBE }
+    }
+}
+
+impl Session {
+    pub fn has(&self, feature: u64) -> bool {
+        self.features & feature == feature
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    // Remote to Mac
+    GetVolume,
+    SetVolume(SetVolume),
+    SetMute(SetMute),
+    ListDevices,
+    SelectDevice(SelectDevice),
+    GetNowPlaying,
+    Subscribe(Vec<Topic>),
+    Hello(Hello),
+    // Mac to remote
+    Welcome {
+        session: Session,
+        /// The Mac's own hello, so the remote knows what a newer version would offer
+        mac: Hello,
+    },
+    Volume(VolumeState),
+    Devices(Vec<OutputDevice>),
+    NowPlaying(Option<NowPlaying>),
+    Error {
+        status: u16,
+        message: String,
+    },
+    /// The answer to a message the session can't handle, naming its type
+    Unsupported {
+        kind: String,
+        message: String,
+    },
+    /// A type this version doesn't know, kept as it arrived
+    Unknown {
+        kind: String,
+        body: Value,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct SubscribeBody {
+    topics: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ErrorBody {
+    status: u16,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct WelcomeBody {
+    version: u16,
+    features: u64,
+    mac: Hello,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UnsupportedBody {
+    #[serde(rename = "type")]
+    kind: String,
+    message: String,
+}
+
+fn body<T: DeserializeOwned>(kind: &str, body: Value) -> Result<T, AudioRemoteError> {
+    serde_json::from_value(body).map_err(|e| AudioRemoteError::InvalidData(format!("invalid {kind} body: {e}")))
+}
+
+fn to_value(value: impl Serialize) -> Value {
+    // Every body type serializes to JSON without fail
+    serde_json::to_value(value).unwrap_or_default()
+}
+
+impl Message {
+    /// The envelope "type"
+    pub fn kind(&self) -> &str {
+        match self {
+            Self::GetVolume => "getVolume",
+            Self::SetVolume(_) => "setVolume",
+            Self::SetMute(_) => "setMute",
+            Self::ListDevices => "listDevices",
+            Self::SelectDevice(_) => "selectDevice",
+            Self::GetNowPlaying => "getNowPlaying",
+            Self::Subscribe(_) => "subscribe",
+            Self::Hello(_) => "hello",
+            Self::Welcome { .. } => "welcome",
+            Self::Volume(_) => "volume",
+            Self::Devices(_) => "devices",
+            Self::NowPlaying(_) => "nowPlaying",
+            Self::Error { .. } => "error",
+            Self::Unsupported { .. } => "unsupported",
+            Self::Unknown { kind, .. } => kind,
+        }
+    }
+
+    fn body(&self) -> Value {
+        match self {
+            Self::GetVolume | Self::ListDevices | Self::GetNowPlaying => Value::Null,
+            Self::SetVolume(command) => to_value(command),
+            Self::SetMute(command) => to_value(command),
+            Self::SelectDevice(command) => to_value(command),
+            Self::Subscribe(topics) => to_value(SubscribeBody { topics: topics.iter().map(|topic| topic.name().to_owned()).collect() }),
+            Self::Hello(hello) => to_value(hello),
+            Self::Welcome { session, mac } => to_value(WelcomeBody { version: session.version, features: session.features, mac: *mac }),
+            Self::Volume(state) => to_value(state),
+            Self::Devices(devices) => to_value(devices),
+            Self::NowPlaying(playing) => to_value(playing),
+            Self::Error { status, message } => to_value(ErrorBody { status: *status, message: message.clone() }),
+            Self::Unsupported { kind, message } => to_value(UnsupportedBody { kind: kind.clone(), message: message.clone() }),
+            Self::Unknown { body, .. } => body.clone(),
+        }
+    }
+
+    fn from_parts(kind: String, value: Value) -> Result<Self, AudioRemoteError> {
+        let message = match kind.as_str() {
+            "getVolume" => Self::GetVolume,
+            "setVolume" => {
+                let command: SetVolume = body(&kind, value)?;
+                command.validate()?;
+                Self::SetVolume(command)
+            }
+            "setMute" => Self::SetMute(body(&kind, value)?),
+            "listDevices" => Self::ListDevices,
+            "selectDevice" => {
+                let command: SelectDevice = body(&kind, value)?;
+                command.validate()?;
+                Self::SelectDevice(command)
+            }
+            "getNowPlaying" => Self::GetNowPlaying,
+            "subscribe" => {
+                let SubscribeBody { topics } = body(&kind, value)?;
+                Self::Subscribe(topics.iter().map(|name| Topic::from_name(name)).collect::<Result<_, _>>()?)
+            }
+            "hello" => {
+                let hello: Hello = body(&kind, value)?;
+                hello.validate()?;
+                Self::Hello(hello)
+            }
+            "welcome" => {
+                let WelcomeBody { version, features, mac } = body(&kind, value)?;
+                Self::Welcome { session: Session { version, features }, mac }
+            }
+            "volume" => Self::Volume(body(&kind, value)?),
+            "devices" => Self::Devices(body(&kind, value)?),
+            "nowPlaying" => Self::NowPlaying(body(&kind, value)?),
+            "error" => {
+                let ErrorBody { status, message } = body(&kind, value)?;
+                Self::Error { status, message }
+            }
+            "unsupported" => {
+                let UnsupportedBody { kind, message } = body(&kind, value)?;
+                Self::Unsupported { kind, message }
+            }
+            _ => Self::Unknown { kind, body: value },
+        };
+        Ok(message)
+    }
+
+    /// The state message for a published event
+    pub fn from_event(event: &Event) -> Result<Self, AudioRemoteError> {
+        let data = event.data.clone();
+        match event.topic {
+            Topic::Volume => Ok(Self::Volume(body("volume", data)?)),
+            Topic::Devices => Ok(Self::Devices(body("devices", data)?)),
+            Topic::NowPlaying => Ok(Self::NowPlaying(body("nowPlaying", data)?)),
+        }
+    }
+}
+
+/// The envelope fields as they're encoded, in MessagePack or JSON
+#[derive(Debug, Serialize, Deserialize)]
+struct Wire {
+    v: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<u64>,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    body: Value,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub version: u16,
+    /// Set by the sender of a request, echoed in the answer
+    pub id: Option<u64>,
+    pub message: Message,
+}
+
+impl Envelope {
+    pub fn new(id: Option<u64>, message: Message) -> Self {
+        Self { version: PROTOCOL_VERSION, id, message }
+    }
+
+    /// An envelope in a negotiated session's version
+    pub fn in_session(session: &Session, id: Option<u64>, message: Message) -> Self {
+        Self { version: session.version, id, message }
+    }
+
+    /// The `unsupported` answer to this envelope
+    pub fn unsupported(&self, session: &Session, message: impl Into<String>) -> Self {
+        Self::in_session(session, self.id, Message::Unsupported { kind: self.message.kind().to_owned(), message: message.into() })
+    }
+
+    fn to_wire(&self) -> Wire {
+        Wire { v: self.version, id: self.id, kind: self.message.kind().to_owned(), body: self.message.body() }
+    }
+
+    fn from_wire(wire: Wire) -> Result<Self, AudioRemoteError> {
+        if wire.v == 0 {
+            return Err(AudioRemoteError::InvalidData("protocol version 0 doesn't exist".into()));
+        }
+        Ok(Self { version: wire.v, id: wire.id, message: Message::from_parts(wire.kind, wire.body)? })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        // Named fields: a map, so fields can be added without breaking older readers
+        rmp_serde::to_vec_named(&self.to_wire()).unwrap_or_default()
+    }
+
+    /// Encode for a session: compressed when it agreed to and it pays off
+    pub fn encode_for(&self, session: &Session) -> Vec<u8> {
+        let bytes = self.encode();
+        if !session.has(FEATURE_COMPRESSION) || bytes.len() <= COMPRESSION_THRESHOLD {
+            return bytes;
+        }
+        match zstd::bulk::compress(&bytes, COMPRESSION_LEVEL) {
+            Ok(compressed) if compressed.len() < bytes.len() => compressed,
+            _ => bytes,
+        }
+    }
+
+    /// Decode an envelope, compressed or not
+    pub fn decode(bytes: &[u8]) -> Result<Self, AudioRemoteError> {
+        if bytes.starts_with(&ZSTD_MAGIC) {
+            return Self::decode(&decompress(bytes)?);
+        }
+        let wire = rmp_serde::from_slice(bytes).map_err(|e| AudioRemoteError::InvalidData(format!("invalid MessagePack envelope: {e}")))?;
+        Self::from_wire(wire)
+    }
+
+    pub fn to_json(&self) -> Value {
+        to_value(self.to_wire())
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, AudioRemoteError> {
+        let wire = serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid envelope JSON: {e}")))?;
+        Self::from_wire(wire)
+    }
+}
+
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>, AudioRemoteError> {
+    let invalid = |e: std::io::Error| AudioRemoteError::InvalidData(format!("invalid compressed envelope: {e}"));
+    let decoder = zstd::stream::read::Decoder::new(bytes).map_err(invalid)?;
+    let mut raw = Vec::new();
+    decoder.take(MAX_DECOMPRESSED_BYTES + 1).read_to_end(&mut raw).map_err(invalid)?;
+    if raw.len() as u64 > MAX_DECOMPRESSED_BYTES {
+        return Err(AudioRemoteError::InvalidData(format!("compressed envelope expands past {MAX_DECOMPRESSED_BYTES} bytes")));
+    }
+    if raw.starts_with(&ZSTD_MAGIC) {
+        return Err(AudioRemoteError::InvalidData("compressed envelope is compressed again".into()));
+    }
+    Ok(raw)
+}
+
+/// Encode a protocol envelope given as JSON, {"v", "id", "type", "body"} ("v" is required, use
+/// the current protocol version), into MessagePack. Message bodies are validated like the REST
+/// requests; unknown types are refused.
+/// Returns: the bytes (free with ar_bytes_free, passing `out_len`), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `envelope_json` must point to a valid NUL-terminated string and `out_len` to a writable size_t.
+#[no_mangle]
+pub unsafe extern "C" fn ar_protocol_encode(envelope_json: *const c_char, out_len: *mut usize) -> *mut u8 {
+    guard("ar_protocol_encode", std::ptr::null_mut(), || {
+        let result = str_arg(envelope_json, "envelope").and_then(Envelope::from_json).and_then(|envelope| match envelope.message {
+            Message::Unknown { kind, .. } => Err(AudioRemoteError::InvalidArgument(format!("unknown message type \"{kind}\""))),
+            _ => Ok(envelope.encode()),
+        });
+        bytes_result(result, out_len)
+    })
+}
+
+/// Decode a MessagePack envelope, plain or zstd-compressed, into JSON {"v", "id", "type", "body"}.
+/// A type this version doesn't know is passed through with its body, for the caller to answer
+/// as unsupported.
+/// Returns: the JSON (free with rust_string_free), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `bytes` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ar_protocol_decode(bytes: *const u8, len: usize) -> *mut c_char {
+    guard("ar_protocol_decode", std::ptr::null_mut(), || {
+        if bytes.is_null() {
+            return string_result(Err(AudioRemoteError::InvalidArgument("bytes is null".into())));
+        }
+        let bytes = std::slice::from_raw_parts(bytes, len);
+        string_result(Envelope::decode(bytes).map(|envelope| envelope.to_json().to_string()))
+    })
+}
+
+/// Negotiate with a remote's hello, given as JSON {"minVersion", "maxVersion", "features"}.
+/// Returns: the session as JSON {"version", "features"} (free with rust_string_free), NULL if
+/// there's no common version or on error (see last_error_message)
+///
+/// # Safety
+/// `hello_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_protocol_negotiate(hello_json: *const c_char) -> *mut c_char {
+    guard("ar_protocol_negotiate", std::ptr::null_mut(), || {
+        let result = str_arg(hello_json, "hello").and_then(|json| {
+            let hello: Hello =
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid hello JSON: {e}")))?;
+            hello.validate()?;
+            let session = Hello::mac().negotiate(&hello)?;
+            Ok(to_value(session).to_string())
+        });
+        string_result(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::tests::take_c_string;
+    use serde_json::json;
+
+    #[test]
+    fn test_envelopes_round_trip() {
+        let messages = [
+            Message::SetVolume(SetVolume { volume: 0.25 }),
+            Message::Subscribe(vec![Topic::Volume, Topic::NowPlaying]),
+            Message::NowPlaying(None),
+            Message::Error { status: 404, message: "no such device".into() },
+            Message::Hello(Hello::mac()),
+            Message::Welcome { session: Session::default(), mac: Hello::mac() },
+            Message::Unsupported { kind: "setBalance".into(), message: "not in version 1".into() },
+        ];
+        for (id, message) in messages.into_iter().enumerate() {
+            let envelope = Envelope::new(Some(id as u64), message);
+            assert_eq!(Envelope::decode(&envelope.encode()).unwrap(), envelope);
+            assert_eq!(Envelope::from_json(&envelope.to_json().to_string()).unwrap(), envelope);
+        }
+        // Bodies are validated on the way in
+        assert!(Envelope::from_json(r#"{"v": 1, "type": "setVolume", "body": {"volume": 3}}"#).is_err());
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let mac = Hello { min_version: 2, max_version: 4, features: FEATURE_EVENTS | FEATURE_COMMANDS };
+        let remote = Hello { min_version: 1, max_version: 3, features: FEATURE_EVENTS | FEATURE_SUBSCRIBE };
+        let session = mac.negotiate(&remote).unwrap();
+        assert_eq!(session, Session { version: 3, features: FEATURE_EVENTS });
+        assert_eq!(remote.negotiate(&mac).unwrap(), session);
+        assert!(session.has(FEATURE_EVENTS) && !session.has(FEATURE_COMMANDS));
+        // Lossless streaming is per session: only a remote that asks gets it
+        let lossless = Hello { min_version: 1, max_version: 1, features: FEATURE_EVENTS | FEATURE_LOSSLESS };
+        assert!(Hello::mac().negotiate(&lossless).unwrap().has(FEATURE_LOSSLESS));
+        assert!(!Hello::mac().negotiate(&Hello { features: FEATURE_EVENTS, ..lossless }).unwrap().has(FEATURE_LOSSLESS));
+
+        let old = Hello { min_version: 1, max_version: 1, features: MAC_FEATURES };
+        assert!(matches!(mac.negotiate(&old), Err(AudioRemoteError::Unsupported(_))));
+        assert!(Envelope::from_json(r#"{"v": 1, "type": "hello", "body": {"minVersion": 3, "maxVersion": 2}}"#).is_err());
+    }
+
+    #[test]
+    fn test_compression() {
+        let compressing = Session { version: 1, features: MAC_FEATURES };
+        let small = Envelope::new(None, Message::SetVolume(SetVolume { volume: 0.5 }));
+        assert_eq!(small.encode_for(&compressing), small.encode());
+
+        let large = Envelope::new(Some(4), Message::Error { status: 500, message: "no such device ".repeat(200) });
+        let compressed = large.encode_for(&compressing);
+        assert!(compressed.starts_with(&ZSTD_MAGIC) && compressed.len() < large.encode().len() / 4);
+        assert_eq!(Envelope::decode(&compressed).unwrap(), large);
+        // Only when the session agreed to it
+        assert_eq!(large.encode_for(&Session::default()), large.encode());
+
+        let bomb = zstd::bulk::compress(&vec![0; MAX_DECOMPRESSED_BYTES as usize + 1], 1).unwrap();
+        assert!(matches!(Envelope::decode(&bomb), Err(AudioRemoteError::InvalidData(_))));
+        let twice = zstd::bulk::compress(&compressed, 1).unwrap();
+        assert!(Envelope::decode(&twice).is_err());
+    }
+
+    #[test]
+    fn test_newer_envelopes_still_decode() {
+        // From a future version: an extra envelope field and a type we don't know
+        let future = rmp_serde::to_vec_named(&json!({"v": 3, "id": 9, "type": "setBalance", "body": {"pan": -0.5}, "trace": "x"})).unwrap();
+        let envelope = Envelope::decode(&future).unwrap();
+        assert_eq!(envelope.version, 3);
+        assert_eq!(envelope.message, Message::Unknown { kind: "setBalance".into(), body: json!({"pan": -0.5}) });
+
+        let mut len = 0;
+        let bytes = unsafe { ar_protocol_encode(c"{\"v\": 1, \"id\": 2, \"type\": \"getVolume\"}".as_ptr(), &mut len) };
+        let json = take_c_string(unsafe { ar_protocol_decode(bytes, len) }).unwrap();
+        unsafe { crate::error::ar_bytes_free(bytes, len) };
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), json!({"v": 1, "id": 2, "type": "getVolume", "body": null}));
+        assert!(unsafe { ar_protocol_encode(c"{\"v\": 1, \"type\": \"setBalance\"}".as_ptr(), &mut len) }.is_null());
+
+        let session = take_c_string(unsafe { ar_protocol_negotiate(c"{\"minVersion\": 1, \"maxVersion\": 4, \"features\": 15}".as_ptr()) });
+        assert_eq!(serde_json::from_str::<Value>(&session.unwrap()).unwrap(), json!({"version": 1, "features": MAC_FEATURES & 15}));
+        assert!(unsafe { ar_protocol_negotiate(c"{\"minVersion\": 2, \"maxVersion\": 4}".as_ptr()) }.is_null());
+
+        let header = std::fs::read_to_string(std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../Core/RustBridge.h")).unwrap();
+        assert!(header.contains(&format!("#define AR_PROTOCOL_VERSION {PROTOCOL_VERSION}\n")));
+        assert!(header.contains(&format!("#define AR_PROTOCOL_MIN_VERSION {MIN_PROTOCOL_VERSION}\n")));
+    }
+}
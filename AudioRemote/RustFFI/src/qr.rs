@@ -0,0 +1,248 @@
+//! Pairing by QR code. While a pairing window is open the Mac can show a QR
+//! code instead of just the PIN; the remote scans it and gets everything it
+//! would otherwise have the user type or browse for:
+//!
+//! `audioremote://pair?v=1&mac=<macId>&host=<name>&addr=<ip>&port=<n>&fp=<sha256>&code=<pin>`
+//!
+//! `host` is the Bonjour name, `addr` an address to fall back on where it
+//! doesn't resolve, and `fp` the certificate fingerprint, absent over plain
+//! HTTP. The remote still runs the SPAKE2 exchange with `code` as the PIN, and
+//! checks that the Mac's confirmation vouches for `fp` (see `pairing`), so a
+//! photo of an old code is worth nothing once its window has closed.
+
+use std::ffi::c_char;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use qrcode::{Color, EcLevel, QrCode};
+
+use crate::error::{bytes_result, string_result};
+use crate::{guard, interfaces, mdns, pairing, server, AudioRemoteError};
+
+pub const SCHEME: &str = "audioremote";
+pub const PAYLOAD_VERSION: u32 = 1;
+
+/// Modules of white border the QR spec asks for around the code
+const QUIET_ZONE: usize = 4;
+/// Pixels per module when the caller doesn't say
+const DEFAULT_SCALE: u32 = 8;
+const MAX_SCALE: u32 = 64;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// What a remote needs to pair with this Mac
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingPayload {
+    pub mac_id: String,
+    /// Bonjour host name, e.g. "studio-audioremote.local"
+    pub host: String,
+    /// An address the server can be reached on, in case the name doesn't resolve
+    pub address: Option<String>,
+    pub port: u16,
+    /// SHA-256 of the server's certificate, hex; None over plain HTTP
+    pub cert_fingerprint: Option<String>,
+    /// The open window's PIN
+    pub code: String,
+}
+
+impl PairingPayload {
+    /// The payload for the open pairing window of the running server
+    pub fn current() -> Result<Self, AudioRemoteError> {
+        let listening = server::local_address().ok_or(AudioRemoteError::Refused("the server isn't running".into()))?;
+        let code = pairing::current_pin().ok_or(AudioRemoteError::Refused("pairing isn't open on this Mac".into()))?;
+        Ok(Self {
+            mac_id: pairing::mac_id()?,
+            host: mdns::host_name(),
+            address: reachable_address(listening),
+            port: listening.port(),
+            cert_fingerprint: server::certificate_fingerprint(),
+            code,
+        })
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        query.append_pair("v", &PAYLOAD_VERSION.to_string());
+        query.append_pair("mac", &self.mac_id);
+        query.append_pair("host", &self.host);
+        if let Some(address) = &self.address {
+            query.append_pair("addr", address);
+        }
+        query.append_pair("port", &self.port.to_string());
+        if let Some(fingerprint) = &self.cert_fingerprint {
+            query.append_pair("fp", fingerprint);
+        }
+        query.append_pair("code", &self.code);
+        format!("{SCHEME}://pair?{}", query.finish())
+    }
+
+    /// Read a scanned payload; unknown parameters are ignored, a newer version is refused
+    pub fn parse(uri: &str) -> Result<Self, AudioRemoteError> {
+        let invalid = |message: String| AudioRemoteError::InvalidData(format!("not a pairing code: {message}"));
+        let query = uri
+            .strip_prefix(SCHEME)
+            .and_then(|rest| rest.strip_prefix("://pair?"))
+            .ok_or_else(|| invalid(format!("expected {SCHEME}://pair?…")))?;
+        let params: Vec<(String, String)> = form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        let param = |name: &str| params.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+        let required = |name: &str| param(name).filter(|value| !value.is_empty()).ok_or_else(|| invalid(format!("{name} is missing")));
+
+        let version: u32 = required("v")?.parse().map_err(|_| invalid("v isn't a number".into()))?;
+        if version > PAYLOAD_VERSION {
+            return Err(AudioRemoteError::Unsupported(format!("pairing code version {version} is newer than {PAYLOAD_VERSION}")));
+        }
+        Ok(Self {
+            mac_id: required("mac")?,
+            host: required("host")?,
+            address: param("addr").filter(|value| !value.is_empty()),
+            port: required("port")?.parse().map_err(|_| invalid("port isn't a port number".into()))?,
+            cert_fingerprint: param("fp").filter(|value| !value.is_empty()),
+            code: required("code")?,
+        })
+    }
+}
+
+/// The listening address if it's a specific one, else an interface address, IPv4 first
+fn reachable_address(listening: SocketAddr) -> Option<String> {
+    if !listening.ip().is_unspecified() {
+        return Some(listening.ip().to_string());
+    }
+    let mut addresses: Vec<IpAddr> =
+        interfaces::local_addresses().ok()?.into_iter().filter(|a| !a.is_link_local()).map(|a| a.address).collect();
+    addresses.sort_by_key(|address| address.is_ipv6());
+    addresses.first().map(|address| address.to_string())
+}
+
+/// Encode `text` as a QR code drawn black on white, `scale` pixels per module, as a grayscale PNG
+pub fn render_png(text: &str, scale: u32) -> Result<Vec<u8>, AudioRemoteError> {
+    if scale == 0 || scale > MAX_SCALE {
+        return Err(AudioRemoteError::InvalidArgument(format!("scale must be 1 to {MAX_SCALE}, not {scale}")));
+    }
+    let code = QrCode::with_error_correction_level(text, EcLevel::M)
+        .map_err(|e| AudioRemoteError::InvalidArgument(format!("can't make a QR code: {e}")))?;
+    let modules = code.width() + 2 * QUIET_ZONE;
+    let side = modules * scale as usize;
+
+    // Each row is a filter byte (0, none) and one byte per pixel
+    let mut pixels = Vec::with_capacity(side * (side + 1));
+    for y in 0..side {
+        pixels.push(0);
+        let module_y = y / scale as usize;
+        pixels.extend((0..side).map(|x| {
+            let module_x = x / scale as usize;
+            let inside = |m: usize| (QUIET_ZONE..QUIET_ZONE + code.width()).contains(&m);
+            let dark = inside(module_x) && inside(module_y) && code[(module_x - QUIET_ZONE, module_y - QUIET_ZONE)] == Color::Dark;
+            if dark { 0x00 } else { 0xff }
+        }));
+    }
+
+    let mut zlib = ZlibEncoder::new(Vec::new(), Compression::best());
+    zlib.write_all(&pixels)?;
+    let data = zlib.finish()?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(side as u32).to_be_bytes());
+    header.extend_from_slice(&(side as u32).to_be_bytes());
+    // 8-bit grayscale, deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+    let mut png = PNG_SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &data);
+    write_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/// The pairing payload for the open window, as the URI the QR code carries:
+/// audioremote://pair?v=1&mac=…&host=…&addr=…&port=…&fp=…&code=…
+/// Returns: the URI (free with rust_string_free), NULL if the server isn't running or no pairing
+/// window is open (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_pairing_payload() -> *mut c_char {
+    guard("ar_pairing_payload", std::ptr::null_mut(), || string_result(PairingPayload::current().map(|payload| payload.to_uri())))
+}
+
+/// The pairing payload for the open window as a QR code PNG, `scale` pixels per module (0 = 8)
+/// Returns: the PNG (free with ar_bytes_free, passing `out_len`), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `out_len` must point to a writable size_t.
+#[no_mangle]
+pub unsafe extern "C" fn ar_pairing_qr_png(scale: u32, out_len: *mut usize) -> *mut u8 {
+    guard("ar_pairing_qr_png", std::ptr::null_mut(), || {
+        let scale = if scale == 0 { DEFAULT_SCALE } else { scale };
+        let png = PairingPayload::current().and_then(|payload| render_png(&payload.to_uri(), scale));
+        bytes_result(png, out_len)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::ZlibDecoder;
+    use std::io::Read;
+
+    fn payload() -> PairingPayload {
+        PairingPayload {
+            mac_id: "0123abcd".into(),
+            host: "studio-audioremote.local".into(),
+            address: Some("192.168.1.20".into()),
+            port: 8765,
+            cert_fingerprint: Some("ab".repeat(32)),
+            code: "042917".into(),
+        }
+    }
+
+    #[test]
+    fn test_payload_round_trip() {
+        let uri = payload().to_uri();
+        assert!(uri.starts_with("audioremote://pair?v=1&mac=0123abcd&host=studio-audioremote.local&addr=192.168.1.20&port=8765&fp="));
+        assert!(uri.ends_with("&code=042917"));
+        assert_eq!(PairingPayload::parse(&uri).unwrap(), payload());
+
+        let plain = PairingPayload { address: None, cert_fingerprint: None, ..payload() };
+        assert_eq!(PairingPayload::parse(&plain.to_uri()).unwrap(), plain);
+
+        // Parameters from a newer Mac are ignored, a newer format isn't
+        assert_eq!(PairingPayload::parse(&format!("{uri}&extra=1")).unwrap(), payload());
+        assert!(matches!(PairingPayload::parse(&uri.replace("v=1", "v=2")), Err(AudioRemoteError::Unsupported(_))));
+        assert!(PairingPayload::parse(&uri.replace("&code=042917", "")).is_err());
+        assert!(PairingPayload::parse(&uri.replace("port=8765", "port=http")).is_err());
+        assert!(PairingPayload::parse("https://example.com/pair?v=1").is_err());
+    }
+
+    #[test]
+    fn test_render_png() {
+        let png = render_png(&payload().to_uri(), 4).unwrap();
+        assert_eq!(png[..8], PNG_SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        let side = u32::from_be_bytes(png[16..20].try_into().unwrap()) as usize;
+        assert_eq!(side % 4, 0);
+        assert_eq!(u32::from_be_bytes(png[20..24].try_into().unwrap()) as usize, side);
+        assert!(png.ends_with(&[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]));
+
+        // The image data holds a white border and both colours
+        let idat = png.windows(4).position(|w| w == b"IDAT").unwrap();
+        let len = u32::from_be_bytes(png[idat - 4..idat].try_into().unwrap()) as usize;
+        let mut pixels = Vec::new();
+        ZlibDecoder::new(&png[idat + 4..idat + 4 + len]).read_to_end(&mut pixels).unwrap();
+        assert_eq!(pixels.len(), side * (side + 1));
+        assert!(pixels[1..=side].iter().all(|&p| p == 0xff));
+        assert!(pixels.contains(&0x00));
+
+        assert!(render_png("x", 0).is_err());
+        assert!(render_png("x", MAX_SCALE + 1).is_err());
+    }
+}
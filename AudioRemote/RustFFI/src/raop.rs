@@ -0,0 +1,863 @@
+//! Sending the Mac's audio to AirPlay 1 (RAOP) speakers, so Audio Remote can
+//! redirect it to an AirPort Express, an AirPlay receiver or a shairport-sync
+//! box without going through macOS's own AirPlay picker.
+//!
+//! A session is RTSP over TCP to the speaker: OPTIONS, ANNOUNCE with the
+//! stream's SDP, SETUP to trade UDP ports, RECORD to start, SET_PARAMETER for
+//! the volume, FLUSH when playback skips and TEARDOWN at the end. Audio goes
+//! over UDP as RTP, each packet 352 frames of 44.1 kHz 16-bit stereo in an
+//! uncompressed ALAC frame, which every receiver decodes and costs nothing to
+//! make. Two more UDP channels run beside it: the speaker asks our timing port
+//! for the time, NTP-style, to line its clock up with ours, and on the control
+//! channel we send it a sync packet every second tying RTP timestamps to that
+//! clock and resend the packets it asks for.
+//!
+//! Streams go in the clear (`et=0`). RSA-wrapped AES keys, passwords and the
+//! pairing AirPlay 2 devices want aren't supported, so a speaker that insists
+//! on them refuses ANNOUNCE and the session fails. Finding speakers isn't done
+//! here either: `host` and `port` come from their `_raop._tcp` SRV record.
+
+use std::collections::VecDeque;
+use std::ffi::{c_char, c_void, CString};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::dither::{DitherMode, Quantizer};
+use crate::handle::{Handle, Registry};
+use crate::interfaces;
+use crate::pairing::random_bytes;
+use crate::rtp::{Packetizer, PacketizerConfig};
+use crate::runtime::{self, SendPtr};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+pub const SAMPLE_RATE: u32 = 44_100;
+pub const FRAMES_PER_PACKET: usize = 352;
+const CHANNELS: usize = 2;
+/// What the SDP maps to AppleLossless
+const PAYLOAD_TYPE: u8 = 96;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RTSP_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEAD_BYTES: usize = 16 * 1024;
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+/// How far behind the sync packets' "now" the speaker plays: 250 ms, as iTunes asks
+const LATENCY_FRAMES: u32 = 11_025;
+/// Sent packets kept for resending, about 8 s
+const HISTORY: usize = 1000;
+/// Seconds from 1900, NTP's epoch, to 1970
+const NTP_EPOCH_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i32)]
+pub enum RaopState {
+    /// Setting up the RTSP session
+    Connecting = 0,
+    /// Recording: ar_raop_send plays
+    Streaming = 1,
+    /// The speaker refused us or went away
+    Failed = 2,
+    /// Closed by ar_raop_close
+    Closed = 3,
+}
+
+impl RaopState {
+    pub fn from_raw(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Connecting),
+            1 => Some(Self::Streaming),
+            2 => Some(Self::Failed),
+            3 => Some(Self::Closed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RaopConfig {
+    /// A name or an IP, link-local IPv6 with its zone
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// 0–1, spread over AirPlay's -30–0 dB; 0 mutes
+    #[serde(default = "default_volume")]
+    pub volume: f64,
+    /// How the float samples become ALAC's 16 bits
+    #[serde(default)]
+    pub dither: DitherMode,
+}
+
+fn default_port() -> u16 {
+    5000
+}
+
+fn default_volume() -> f64 {
+    0.5
+}
+
+impl RaopConfig {
+    fn validate(&self) -> Result<(), AudioRemoteError> {
+        if self.host.is_empty() {
+            return Err(AudioRemoteError::InvalidArgument("host is empty".into()));
+        }
+        check_volume(self.volume)
+    }
+}
+
+fn check_volume(volume: f64) -> Result<(), AudioRemoteError> {
+    match (0.0..=1.0).contains(&volume) {
+        true => Ok(()),
+        false => Err(AudioRemoteError::InvalidArgument(format!("volume {volume} is outside 0–1"))),
+    }
+}
+
+/// The dB AirPlay wants for a 0–1 volume: -30–0, and -144 for mute
+pub fn airplay_volume(volume: f64) -> f64 {
+    match volume > 0.0 {
+        true => -30.0 + 30.0 * volume.min(1.0),
+        false => -144.0,
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    used: u32,
+}
+
+impl BitWriter {
+    fn put(&mut self, value: u32, count: u32) {
+        for bit in (0..count).rev() {
+            if self.used.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if let Some(last) = self.bytes.last_mut() {
+                *last |= (((value >> bit) & 1) as u8) << (7 - self.used % 8);
+            }
+            self.used += 1;
+        }
+    }
+}
+
+/// An uncompressed ("escape") ALAC frame of interleaved stereo samples. A frame of other
+/// than FRAMES_PER_PACKET frames says how many it has.
+pub fn alac_frame(samples: &[i16]) -> Vec<u8> {
+    let frames = samples.len() / CHANNELS;
+    let partial = frames != FRAMES_PER_PACKET;
+    let mut bits = BitWriter::default();
+    bits.put(1, 3); // a channel pair element
+    bits.put(0, 4); // its instance
+    bits.put(0, 12); // unused
+    bits.put(partial as u32, 1);
+    bits.put(0, 2); // no bytes shifted out
+    bits.put(1, 1); // uncompressed
+    if partial {
+        bits.put(frames as u32, 32);
+    }
+    for &sample in &samples[..frames * CHANNELS] {
+        bits.put(u32::from(sample as u16), 16);
+    }
+    bits.put(7, 3); // the end of the frame
+    bits.bytes
+}
+
+/// Now as a 64-bit NTP timestamp: seconds since 1900 above a binary fraction
+fn ntp_now() -> u64 {
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    ((since.as_secs() + NTP_EPOCH_OFFSET) << 32) | ((u64::from(since.subsec_nanos()) << 32) / 1_000_000_000)
+}
+
+/// Ties `timestamp`, the next packet's, to the NTP time `now`; the first one after RECORD or
+/// FLUSH has the extension bit set
+fn sync_packet(first: bool, timestamp: u32, now: u64) -> [u8; 20] {
+    let mut packet = [0; 20];
+    packet[..4].copy_from_slice(&[if first { 0x90 } else { 0x80 }, 0xd4, 0x00, 0x07]);
+    packet[4..8].copy_from_slice(&timestamp.wrapping_sub(LATENCY_FRAMES).to_be_bytes());
+    packet[8..16].copy_from_slice(&now.to_be_bytes());
+    packet[16..20].copy_from_slice(&timestamp.to_be_bytes());
+    packet
+}
+
+/// The answer to a speaker's timing request, None if `request` isn't one
+fn timing_reply(request: &[u8], now: u64) -> Option<[u8; 32]> {
+    if request.len() < 32 || request[1] & 0x7f != 0x52 {
+        return None;
+    }
+    let mut reply = [0; 32];
+    reply[..4].copy_from_slice(&[0x80, 0xd3, 0x00, 0x07]);
+    // Its send time comes back as the origin, so it can take the round trip out
+    reply[8..16].copy_from_slice(&request[24..32]);
+    reply[16..24].copy_from_slice(&now.to_be_bytes());
+    reply[24..32].copy_from_slice(&now.to_be_bytes());
+    Some(reply)
+}
+
+/// (first sequence number, count) of a speaker's resend request, None if `packet` isn't one
+fn resend_request(packet: &[u8]) -> Option<(u16, u16)> {
+    (packet.len() >= 8 && packet[1] & 0x7f == 0x55)
+        .then(|| (u16::from_be_bytes([packet[4], packet[5]]), u16::from_be_bytes([packet[6], packet[7]])))
+}
+
+/// (audio port, control port) from a SETUP reply's Transport header
+fn transport_ports(transport: &str) -> Result<(u16, u16), AudioRemoteError> {
+    let port = |key: &str| {
+        transport
+            .split(';')
+            .filter_map(|part| part.split_once('='))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case(key))
+            .and_then(|(_, value)| value.trim().parse().ok())
+    };
+    match (port("server_port"), port("control_port")) {
+        (Some(audio), Some(control)) => Ok((audio, control)),
+        _ => Err(AudioRemoteError::InvalidData(format!("the speaker's Transport has no ports: {transport}"))),
+    }
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+}
+
+fn network(e: io::Error) -> AudioRemoteError {
+    AudioRemoteError::Network(format!("RTSP to the speaker failed: {e}"))
+}
+
+fn read_response(reader: &mut impl BufRead) -> Result<Response, AudioRemoteError> {
+    let mut lines = Vec::new();
+    let mut total = 0;
+    loop {
+        let mut line = String::new();
+        let read = reader.read_line(&mut line).map_err(network)?;
+        if read == 0 {
+            return Err(AudioRemoteError::Network("the speaker closed the RTSP connection".into()));
+        }
+        total += read;
+        if total > MAX_HEAD_BYTES {
+            return Err(AudioRemoteError::InvalidData("the speaker's RTSP reply is too long".into()));
+        }
+        match line.trim_end() {
+            "" => break,
+            line => lines.push(line.to_owned()),
+        }
+    }
+    let status_line = lines.first().map(String::as_str).unwrap_or_default();
+    let mut parts = status_line.splitn(3, ' ');
+    let status = match (parts.next(), parts.next().and_then(|status| status.parse().ok())) {
+        (Some(version), Some(status)) if version.starts_with("RTSP/") => status,
+        _ => return Err(AudioRemoteError::InvalidData(format!("not an RTSP reply: {status_line:?}"))),
+    };
+    let headers = lines[1..]
+        .iter()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_owned(), value.trim().to_owned()))
+        .collect();
+    let response = Response { status, reason: parts.next().unwrap_or_default().to_owned(), headers };
+    // Nothing we ask for needs the body, but it has to come off the stream
+    let length: usize = response.header("Content-Length").and_then(|length| length.parse().ok()).unwrap_or(0);
+    if length > MAX_HEAD_BYTES {
+        return Err(AudioRemoteError::InvalidData("the speaker's RTSP reply is too long".into()));
+    }
+    reader.read_exact(&mut vec![0; length]).map_err(network)?;
+    Ok(response)
+}
+
+struct Rtsp {
+    reader: BufReader<TcpStream>,
+    url: String,
+    /// Sent as Client-Instance and DACP-ID
+    client: String,
+    cseq: u32,
+    session: Option<String>,
+}
+
+impl Rtsp {
+    fn request(&mut self, method: &str, headers: &[(&str, String)], body: Option<(&str, &[u8])>) -> Result<Response, AudioRemoteError> {
+        self.cseq += 1;
+        let target = if method == "OPTIONS" { "*" } else { &self.url };
+        let mut head = format!(
+            "{method} {target} RTSP/1.0\r\nCSeq: {}\r\nUser-Agent: AudioRemote/{}\r\nClient-Instance: {}\r\nDACP-ID: {}\r\n",
+            self.cseq,
+            env!("CARGO_PKG_VERSION"),
+            self.client,
+            self.client
+        );
+        if let Some(session) = &self.session {
+            head += &format!("Session: {session}\r\n");
+        }
+        for (name, value) in headers {
+            head += &format!("{name}: {value}\r\n");
+        }
+        if let Some((kind, body)) = body {
+            head += &format!("Content-Type: {kind}\r\nContent-Length: {}\r\n", body.len());
+        }
+        head += "\r\n";
+        let mut message = head.into_bytes();
+        message.extend_from_slice(body.map_or(&[][..], |(_, body)| body));
+        self.reader.get_mut().write_all(&message).map_err(network)?;
+        let response = read_response(&mut self.reader)?;
+        match response.status {
+            200 => Ok(response),
+            401 | 403 => Err(AudioRemoteError::Refused(format!("the speaker wants a password or pairing ({method}: {})", response.status))),
+            status => Err(AudioRemoteError::Refused(format!("the speaker answered {method} with {status} {}", response.reason))),
+        }
+    }
+}
+
+/// A recording session with a speaker
+struct Session {
+    rtsp: Rtsp,
+    audio: UdpSocket,
+    control: UdpSocket,
+    timing: UdpSocket,
+    control_to: SocketAddr,
+    packetizer: Packetizer,
+    /// Sent packets by sequence number, oldest first and consecutive
+    history: VecDeque<(u16, Vec<u8>)>,
+    quantizer: Quantizer,
+    /// Samples short of a whole packet, and the latest quantised
+    carry: Vec<i16>,
+    pcm: Vec<i32>,
+    first_sync: bool,
+    latency_ms: Option<u64>,
+}
+
+fn connect(config: &RaopConfig) -> Result<TcpStream, AudioRemoteError> {
+    let host = &config.host;
+    let addresses = interfaces::resolve(host, config.port).map_err(|e| AudioRemoteError::Network(format!("can't resolve {host}: {e}")))?;
+    let mut last_error = format!("{host} has no addresses");
+    let socket = addresses
+        .into_iter()
+        .find_map(|address| match TcpStream::connect_timeout(&address, CONNECT_TIMEOUT) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                last_error = format!("can't connect to {address}: {e}");
+                None
+            }
+        })
+        .ok_or(AudioRemoteError::Network(last_error))?;
+    let _ = socket.set_nodelay(true);
+    socket.set_read_timeout(Some(RTSP_TIMEOUT)).map_err(network)?;
+    Ok(socket)
+}
+
+impl Session {
+    fn start(config: &RaopConfig) -> Result<Self, AudioRemoteError> {
+        let socket = connect(config)?;
+        let (local, remote) = (socket.local_addr().map_err(network)?, socket.peer_addr().map_err(network)?);
+        let random: [u8; 12] = random_bytes()?;
+        let id = u32::from_be_bytes([random[0], random[1], random[2], random[3]]);
+        let client = format!("{:016X}", u64::from_be_bytes([random[4], random[5], random[6], random[7], random[8], random[9], random[10], random[11]]));
+        let url = format!("rtsp://{}/{id}", interfaces::url_host(&local.ip().to_string()));
+        let bind = |what: &str| {
+            UdpSocket::bind(if remote.is_ipv6() { "[::]:0" } else { "0.0.0.0:0" })
+                .map_err(|e| AudioRemoteError::Network(format!("can't open the {what} socket: {e}")))
+        };
+        let (audio, control, timing) = (bind("audio")?, bind("control")?, bind("timing")?);
+        let port = |socket: &UdpSocket| socket.local_addr().map(|address| address.port()).map_err(network);
+        let mut rtsp = Rtsp { reader: BufReader::new(socket), url, client, cseq: 0, session: None };
+
+        rtsp.request("OPTIONS", &[], None)?;
+        let family = if remote.is_ipv6() { "IP6" } else { "IP4" };
+        let sdp = format!(
+            "v=0\r\no=AudioRemote {id} 0 IN {family} {}\r\ns=AudioRemote\r\nc=IN {family} {}\r\nt=0 0\r\nm=audio 0 RTP/AVP {PAYLOAD_TYPE}\r\n\
+             a=rtpmap:{PAYLOAD_TYPE} AppleLossless\r\na=fmtp:{PAYLOAD_TYPE} {FRAMES_PER_PACKET} 0 16 40 10 14 2 255 0 0 {SAMPLE_RATE}\r\n",
+            local.ip(),
+            remote.ip()
+        );
+        rtsp.request("ANNOUNCE", &[], Some(("application/sdp", sdp.as_bytes())))?;
+        let transport =
+            format!("RTP/AVP/UDP;unicast;interleaved=0-1;mode=record;control_port={};timing_port={}", port(&control)?, port(&timing)?);
+        let reply = rtsp.request("SETUP", &[("Transport", transport)], None)?;
+        let session = reply.header("Session").and_then(|session| session.split(';').next()).map(str::trim).filter(|session| !session.is_empty());
+        rtsp.session = Some(session.ok_or(AudioRemoteError::InvalidData("the speaker's SETUP reply has no Session".into()))?.to_owned());
+        let (audio_port, control_port) =
+            transport_ports(reply.header("Transport").ok_or(AudioRemoteError::InvalidData("the speaker's SETUP reply has no Transport".into()))?)?;
+        // Keeping `remote` keeps a link-local address's zone
+        let (mut audio_to, mut control_to) = (remote, remote);
+        audio_to.set_port(audio_port);
+        control_to.set_port(control_port);
+        audio.connect(audio_to).map_err(network)?;
+
+        let packetizer = Packetizer::new(&PacketizerConfig { payload_type: PAYLOAD_TYPE, ssrc: None })?;
+        let (sequence, timestamp) = packetizer.next();
+        let reply = rtsp.request("RECORD", &[("Range", "npt=0-".into()), ("RTP-Info", format!("seq={sequence};rtptime={timestamp}"))], None)?;
+        let latency_ms = reply.header("Audio-Latency").and_then(|frames| frames.parse::<u64>().ok()).map(|frames| frames * 1000 / u64::from(SAMPLE_RATE));
+        control.set_nonblocking(true).map_err(network)?;
+        timing.set_nonblocking(true).map_err(network)?;
+        Ok(Self {
+            rtsp,
+            audio,
+            control,
+            timing,
+            control_to,
+            packetizer,
+            history: VecDeque::new(),
+            quantizer: Quantizer::new(config.dither, 16, CHANNELS),
+            carry: Vec::new(),
+            pcm: Vec::new(),
+            first_sync: true,
+            latency_ms,
+        })
+    }
+
+    /// Send whole packets of `samples` (interleaved stereo), keeping the rest for next time.
+    /// UDP drops are the speaker's to ask about, so send errors are ignored.
+    fn send_audio(&mut self, samples: &[f32]) {
+        self.pcm.clear();
+        self.quantizer.quantize(samples, &mut self.pcm);
+        // The quantizer clamps to 16 bits
+        self.carry.extend(self.pcm.iter().map(|&sample| sample as i16));
+        let packet_len = FRAMES_PER_PACKET * CHANNELS;
+        let mut start = 0;
+        while self.carry.len() - start >= packet_len {
+            let frame = alac_frame(&self.carry[start..start + packet_len]);
+            let (sequence, _) = self.packetizer.next();
+            let packet = self.packetizer.packetize(&frame, FRAMES_PER_PACKET as u32);
+            let _ = self.audio.send(&packet);
+            if self.history.len() == HISTORY {
+                self.history.pop_front();
+            }
+            self.history.push_back((sequence, packet));
+            start += packet_len;
+        }
+        self.carry.drain(..start);
+    }
+
+    fn sync(&mut self) {
+        let (_, timestamp) = self.packetizer.next();
+        let _ = self.control.send_to(&sync_packet(self.first_sync, timestamp, ntp_now()), self.control_to);
+        self.first_sync = false;
+    }
+
+    /// Answer whatever timing and resend requests have come in
+    fn answer(&mut self) {
+        let mut buffer = [0; 128];
+        while let Ok((length, from)) = self.timing.recv_from(&mut buffer) {
+            if let Some(reply) = timing_reply(&buffer[..length], ntp_now()) {
+                let _ = self.timing.send_to(&reply, from);
+            }
+        }
+        while let Ok((length, from)) = self.control.recv_from(&mut buffer) {
+            let (Some((first, count)), Some(&(oldest, _))) = (resend_request(&buffer[..length]), self.history.front()) else {
+                continue;
+            };
+            for sequence in (0..count.min(HISTORY as u16)).map(|i| first.wrapping_add(i)) {
+                if let Some((_, packet)) = self.history.get(sequence.wrapping_sub(oldest) as usize) {
+                    let mut resent = vec![0x80, 0xd6, 0x00, 0x01];
+                    resent.extend_from_slice(packet);
+                    let _ = self.control.send_to(&resent, from);
+                }
+            }
+        }
+    }
+
+    fn set_volume(&mut self, volume: f64) -> Result<(), AudioRemoteError> {
+        let body = format!("volume: {:.6}\r\n", airplay_volume(volume));
+        self.rtsp.request("SET_PARAMETER", &[], Some(("text/parameters", body.as_bytes()))).map(|_| ())
+    }
+
+    /// Drop what the speaker has buffered, e.g. on a seek or pause, and start over marked
+    fn flush(&mut self) -> Result<(), AudioRemoteError> {
+        let (sequence, timestamp) = self.packetizer.next();
+        self.rtsp.request("FLUSH", &[("RTP-Info", format!("seq={sequence};rtptime={timestamp}"))], None)?;
+        self.carry.clear();
+        self.history.clear();
+        self.packetizer.restart();
+        self.first_sync = true;
+        Ok(())
+    }
+
+    fn teardown(mut self) {
+        let _ = self.rtsp.request("TEARDOWN", &[], None);
+    }
+}
+
+enum Input {
+    Audio(Vec<f32>),
+    Volume(f64),
+    Flush,
+    Close,
+}
+
+/// (speaker, ArRaopState, detail JSON, ctx); the JSON is only valid during the call
+pub type StateCallback = extern "C" fn(speaker: Handle, state: i32, detail_json: *const c_char, ctx: *mut c_void);
+
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Detail {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    latency_ms: Option<u64>,
+}
+
+pub struct Speaker {
+    state: AtomicI32,
+    input: Mutex<Sender<Input>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+static SPEAKERS: Registry<Speaker> = Registry::new("AirPlay speaker");
+
+struct Callbacks {
+    handle: Handle,
+    on_state: StateCallback,
+    ctx: SendPtr,
+}
+
+impl Callbacks {
+    fn state(&self, speaker: &Speaker, state: RaopState, detail: Detail) {
+        speaker.state.store(state as i32, Ordering::Release);
+        log::debug!("AirPlay speaker {}: {state:?} {detail:?}", self.handle);
+        let json = CString::new(serde_json::to_string(&detail).unwrap_or_default()).unwrap_or_default();
+        let (handle, callback, ctx) = (self.handle, self.on_state, self.ctx);
+        runtime::deliver(move || callback(handle, state as i32, json.as_ptr(), ctx.get()));
+    }
+
+    fn failed(&self, speaker: &Speaker, error: AudioRemoteError) {
+        self.state(speaker, RaopState::Failed, Detail { error: Some(error.to_string()), ..Detail::default() });
+    }
+}
+
+fn supervise(speaker: Arc<Speaker>, config: RaopConfig, inputs: Receiver<Input>, callbacks: Callbacks) {
+    callbacks.state(&speaker, RaopState::Connecting, Detail::default());
+    let started = Session::start(&config).and_then(|mut session| session.set_volume(config.volume).map(|()| session));
+    let mut session = match started {
+        Ok(session) => session,
+        Err(e) => return callbacks.failed(&speaker, e),
+    };
+    callbacks.state(&speaker, RaopState::Streaming, Detail { latency_ms: session.latency_ms, ..Detail::default() });
+    let mut next_sync = Instant::now();
+    loop {
+        let now = Instant::now();
+        if now >= next_sync {
+            session.sync();
+            next_sync = now + SYNC_INTERVAL;
+        }
+        session.answer();
+        let done = match inputs.recv_timeout(POLL_INTERVAL) {
+            Ok(Input::Audio(samples)) => {
+                session.send_audio(&samples);
+                Ok(())
+            }
+            Ok(Input::Volume(volume)) => session.set_volume(volume),
+            Ok(Input::Flush) => session.flush(),
+            Ok(Input::Close) | Err(RecvTimeoutError::Disconnected) => {
+                session.teardown();
+                return callbacks.state(&speaker, RaopState::Closed, Detail::default());
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(()),
+        };
+        if let Err(e) = done {
+            return callbacks.failed(&speaker, e);
+        }
+    }
+}
+
+/// Start a session with a speaker; `on_state` hears how it goes
+pub(crate) fn open(config: RaopConfig, on_state: StateCallback, ctx: SendPtr) -> Result<Handle, AudioRemoteError> {
+    config.validate()?;
+    let (sender, inputs) = mpsc::channel();
+    let speaker = Arc::new(Speaker { state: AtomicI32::new(RaopState::Connecting as i32), input: Mutex::new(sender), thread: Mutex::new(None) });
+    let handle = SPEAKERS.insert_shared(speaker.clone());
+    let callbacks = Callbacks { handle, on_state, ctx };
+    let spawned = {
+        let speaker = speaker.clone();
+        thread::Builder::new().name("audioremote-raop".into()).spawn(move || supervise(speaker, config, inputs, callbacks))
+    };
+    match spawned {
+        Ok(thread) => {
+            *speaker.thread.lock().unwrap_or_else(|e| e.into_inner()) = Some(thread);
+            Ok(handle)
+        }
+        Err(e) => {
+            let _ = SPEAKERS.remove(handle);
+            Err(AudioRemoteError::Other(format!("can't start the AirPlay thread: {e}")))
+        }
+    }
+}
+
+pub fn state(handle: Handle) -> Result<RaopState, AudioRemoteError> {
+    let speaker = SPEAKERS.get(handle)?;
+    Ok(RaopState::from_raw(speaker.state.load(Ordering::Acquire)).unwrap_or(RaopState::Failed))
+}
+
+fn input(handle: Handle, input: Input) -> Result<(), AudioRemoteError> {
+    let speaker = SPEAKERS.get(handle)?;
+    if speaker.state.load(Ordering::Acquire) != RaopState::Streaming as i32 {
+        return Err(AudioRemoteError::Refused("the AirPlay speaker isn't streaming".into()));
+    }
+    let sender = speaker.input.lock().unwrap_or_else(|e| e.into_inner());
+    sender.send(input).map_err(|_| AudioRemoteError::Refused("the AirPlay session has ended".into()))
+}
+
+/// Queue interleaved stereo samples at 44.1 kHz; refused unless streaming
+pub fn send(handle: Handle, samples: Vec<f32>) -> Result<(), AudioRemoteError> {
+    input(handle, Input::Audio(samples))
+}
+
+pub fn set_volume(handle: Handle, volume: f64) -> Result<(), AudioRemoteError> {
+    check_volume(volume)?;
+    input(handle, Input::Volume(volume))
+}
+
+pub fn flush(handle: Handle) -> Result<(), AudioRemoteError> {
+    input(handle, Input::Flush)
+}
+
+fn shut(speaker: &Speaker) {
+    let _ = speaker.input.lock().unwrap_or_else(|e| e.into_inner()).send(Input::Close);
+    let thread = speaker.thread.lock().unwrap_or_else(|e| e.into_inner()).take();
+    // A state callback may close its own speaker, on the session's thread
+    if let Some(thread) = thread.filter(|thread| thread.thread().id() != thread::current().id()) {
+        let _ = thread.join();
+    }
+}
+
+pub fn close(handle: Handle) -> Result<(), AudioRemoteError> {
+    let speaker = SPEAKERS.remove(handle)?;
+    shut(&speaker);
+    Ok(())
+}
+
+/// Close every session, at shutdown
+pub fn close_all() {
+    for speaker in SPEAKERS.all() {
+        shut(&speaker);
+    }
+}
+
+/// Start playing to an AirPlay 1 speaker. `config_json`: {"host" (a name or an IP, link-local
+/// IPv6 with its zone), "port" (5000), "volume" (0–1, 0.5), "dither" ("tpdf" (default) | "shaped" |
+/// "none")}. `on_state` gets every ArRaopState
+/// change with a detail JSON {"error", "latencyMs" (how far behind the speaker plays, if it
+/// says)}, on the ar_runtime_set_callback_queue queue or the session's thread.
+/// Returns: the speaker handle, 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string; `ctx` is passed to `on_state`
+/// from another thread and must stay valid until ar_raop_close.
+#[no_mangle]
+pub unsafe extern "C" fn ar_raop_open(config_json: *const c_char, on_state: Option<StateCallback>, ctx: *mut c_void) -> Handle {
+    guard("ar_raop_open", 0, || {
+        let result = str_arg(config_json, "config").and_then(|json| {
+            let config: RaopConfig =
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid AirPlay config: {e}")))?;
+            let on_state = on_state.ok_or(AudioRemoteError::InvalidArgument("on_state is null".into()))?;
+            open(config, on_state, SendPtr(ctx))
+        });
+        record(result).unwrap_or(0)
+    })
+}
+
+/// Returns: the speaker's ArRaopState, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_raop_state(speaker: Handle) -> i32 {
+    guard("ar_raop_state", -999, || record(state(speaker)).map_or(-999, |state| state as i32))
+}
+
+/// Play `frames` frames of interleaved stereo float samples at 44.1 kHz. Feed it in real time:
+/// the speaker plays packets as they come, 352 frames each, and keeps a short remainder for
+/// the next call.
+/// Returns: 1 if queued, -999 if not streaming or on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` * 2 readable floats.
+#[no_mangle]
+pub unsafe extern "C" fn ar_raop_send(speaker: Handle, samples: *const f32, frames: usize) -> i32 {
+    guard("ar_raop_send", -999, || {
+        if samples.is_null() {
+            record::<()>(Err(AudioRemoteError::InvalidArgument("samples is null".into())));
+            return -999;
+        }
+        let samples = std::slice::from_raw_parts(samples, frames * CHANNELS).to_vec();
+        record(send(speaker, samples)).map_or(-999, |()| 1)
+    })
+}
+
+/// Set the speaker's volume, 0–1 (0 mutes)
+/// Returns: 1 if queued, -999 if not streaming or on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_raop_set_volume(speaker: Handle, volume: f64) -> i32 {
+    guard("ar_raop_set_volume", -999, || record(set_volume(speaker, volume)).map_or(-999, |()| 1))
+}
+
+/// Drop what the speaker has buffered, for a pause or a seek, so what's sent next plays promptly
+/// Returns: 1 if queued, -999 if not streaming or on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_raop_flush(speaker: Handle) -> i32 {
+    guard("ar_raop_flush", -999, || record(flush(speaker)).map_or(-999, |()| 1))
+}
+
+/// End the session (TEARDOWN) and free the handle; the state callback gets AR_RAOP_CLOSED, and
+/// nothing after it returns except callbacks already queued. Also done by ar_shutdown.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_raop_close(speaker: Handle) -> i32 {
+    guard("ar_raop_close", -999, || record(close(speaker)).map_or(-999, |()| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        at: usize,
+    }
+
+    impl BitReader<'_> {
+        fn take(&mut self, count: usize) -> u32 {
+            (0..count).fold(0, |value, _| {
+                let bit = (self.bytes[self.at / 8] >> (7 - self.at % 8)) & 1;
+                self.at += 1;
+                (value << 1) | u32::from(bit)
+            })
+        }
+    }
+
+    #[test]
+    fn test_alac_frame() {
+        let samples: Vec<i16> = (0..FRAMES_PER_PACKET * 2).map(|i| (i as i16 - 300).wrapping_mul(97)).collect();
+        let frame = alac_frame(&samples);
+        assert_eq!(frame.len(), (23 + samples.len() * 16 + 3).div_ceil(8));
+        let mut bits = BitReader { bytes: &frame, at: 0 };
+        assert_eq!((bits.take(3), bits.take(4), bits.take(12)), (1, 0, 0));
+        assert_eq!((bits.take(1), bits.take(2), bits.take(1)), (0, 0, 1));
+        assert!(samples.iter().all(|&sample| bits.take(16) as u16 as i16 == sample));
+        assert_eq!(bits.take(3), 7);
+
+        // A short frame carries its length
+        let mut bits = BitReader { bytes: &alac_frame(&[1, -1, 2, -2]), at: 0 };
+        bits.take(19);
+        assert_eq!((bits.take(1), bits.take(3), bits.take(32)), (1, 1, 2));
+        assert_eq!((0..4).map(|_| bits.take(16) as u16 as i16).collect::<Vec<_>>(), [1, -1, 2, -2]);
+    }
+
+    #[test]
+    fn test_control_and_timing_packets() {
+        let mut request = [0; 32];
+        request[..4].copy_from_slice(&[0x80, 0xd2, 0x00, 0x07]);
+        request[24..32].copy_from_slice(&42u64.to_be_bytes());
+        let reply = timing_reply(&request, 7 << 32).unwrap();
+        assert_eq!(reply[..4], [0x80, 0xd3, 0x00, 0x07]);
+        assert_eq!((&reply[8..16], &reply[16..24]), (&42u64.to_be_bytes()[..], &(7u64 << 32).to_be_bytes()[..]));
+        assert_eq!(timing_reply(&request[..20], 0), None);
+
+        assert_eq!(resend_request(&[0x80, 0xd5, 0x00, 0x01, 0xff, 0xfe, 0x00, 0x03]), Some((0xfffe, 3)));
+        assert_eq!(resend_request(&[0x80, 0xd4, 0x00, 0x07, 0, 0, 0, 0]), None);
+
+        let sync = sync_packet(true, 20_000, 5);
+        assert_eq!(sync[..4], [0x90, 0xd4, 0x00, 0x07]);
+        assert_eq!(u32::from_be_bytes(sync[4..8].try_into().unwrap()), 20_000 - LATENCY_FRAMES);
+        assert_eq!(sync_packet(false, 0, 5)[0], 0x80);
+
+        assert_eq!(transport_ports("RTP/AVP/UDP;unicast;mode=record;server_port=6000;control_port=6001;timing_port=6002").unwrap(), (6000, 6001));
+        assert!(transport_ports("RTP/AVP/UDP;unicast").is_err());
+        assert_eq!((airplay_volume(0.0), airplay_volume(0.5), airplay_volume(1.0)), (-144.0, -15.0, 0.0));
+    }
+
+    /// Answers RTSP like a speaker would, recording each request's method and body
+    fn fake_speaker(listener: TcpListener, audio_port: u16, control_port: u16, requests: Arc<Mutex<Vec<(String, String)>>>) {
+        let (socket, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(socket);
+        loop {
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                    return;
+                }
+                match line.trim_end() {
+                    "" => break,
+                    line => lines.push(line.to_owned()),
+                }
+            }
+            let header = |name: &str| lines.iter().find_map(|line| line.strip_prefix(name)).map(|value| value.trim().to_owned());
+            let mut body = vec![0; header("Content-Length:").map_or(0, |length| length.parse().unwrap())];
+            reader.read_exact(&mut body).unwrap();
+            let method = lines[0].split(' ').next().unwrap().to_owned();
+            let mut reply = format!("RTSP/1.0 200 OK\r\nCSeq: {}\r\n", header("CSeq:").unwrap());
+            match method.as_str() {
+                "SETUP" => reply += &format!("Session: DEADBEEF;timeout=60\r\nTransport: RTP/AVP/UDP;unicast;mode=record;server_port={audio_port};control_port={control_port};timing_port=0\r\n"),
+                "RECORD" => reply += "Audio-Latency: 11025\r\n",
+                _ => {}
+            }
+            reader.get_mut().write_all(format!("{reply}\r\n").as_bytes()).unwrap();
+            requests.lock().unwrap().push((method, String::from_utf8(body).unwrap()));
+        }
+    }
+
+    extern "C" fn on_state(_: Handle, state: i32, detail: *const c_char, ctx: *mut c_void) {
+        let states = unsafe { &*(ctx as *const Mutex<Vec<(i32, String)>>) };
+        let detail = unsafe { std::ffi::CStr::from_ptr(detail) }.to_string_lossy().into_owned();
+        states.lock().unwrap().push((state, detail));
+    }
+
+    #[test]
+    fn test_session_with_a_speaker() {
+        let _runtime = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (audio, control) = (UdpSocket::bind("127.0.0.1:0").unwrap(), UdpSocket::bind("127.0.0.1:0").unwrap());
+        audio.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        control.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let speaker = {
+            let (audio_port, control_port, requests) = (audio.local_addr().unwrap().port(), control.local_addr().unwrap().port(), requests.clone());
+            thread::spawn(move || fake_speaker(listener, audio_port, control_port, requests))
+        };
+
+        let states: &'static Mutex<Vec<(i32, String)>> = Box::leak(Box::new(Mutex::new(Vec::new())));
+        let ctx = SendPtr(states as *const Mutex<Vec<(i32, String)>> as *mut c_void);
+        let config = RaopConfig { host: "127.0.0.1".into(), port, volume: 0.5, dither: DitherMode::default() };
+        let handle = open(config, on_state, ctx).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while state(handle).unwrap() != RaopState::Streaming {
+            assert!(Instant::now() < deadline, "states so far {:?}", states.lock().unwrap());
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(states.lock().unwrap().last().unwrap().1.contains("\"latencyMs\":250"));
+
+        let mut buffer = [0; 2048];
+        let (length, _) = control.recv_from(&mut buffer).unwrap();
+        assert_eq!((length, &buffer[..2]), (20, &[0x90, 0xd4][..]));
+
+        // Two packets and a bit
+        send(handle, vec![0.25; (FRAMES_PER_PACKET * 2 + 10) * 2]).unwrap();
+        let mut sequences = Vec::new();
+        for marker in [true, false] {
+            let length = audio.recv(&mut buffer).unwrap();
+            let (header, payload) = crate::rtp::RtpHeader::parse(&buffer[..length]).unwrap();
+            assert_eq!((header.marker, header.payload_type, payload.len()), (marker, PAYLOAD_TYPE, 1412));
+            sequences.push(header.sequence);
+        }
+        assert_eq!(sequences[1], sequences[0].wrapping_add(1));
+
+        close(handle).unwrap();
+        speaker.join().unwrap();
+        let requests = requests.lock().unwrap();
+        let methods: Vec<&str> = requests.iter().map(|(method, _)| method.as_str()).collect();
+        assert_eq!(methods, ["OPTIONS", "ANNOUNCE", "SETUP", "RECORD", "SET_PARAMETER", "TEARDOWN"]);
+        assert!(requests[1].1.contains("a=rtpmap:96 AppleLossless"));
+        assert_eq!(requests[4].1, "volume: -15.000000\r\n");
+        assert_eq!(states.lock().unwrap().last().unwrap().0, RaopState::Closed as i32);
+    }
+}
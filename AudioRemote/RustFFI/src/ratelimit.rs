@@ -0,0 +1,293 @@
+//! Abuse protection for the server: a token bucket per client, so a script
+//! looping on `PUT /api/v1/volume` is slowed to a sustained rate without
+//! bothering a person dragging a slider, and a lockout for addresses that keep
+//! failing to sign in, so tokens can't be guessed at speed.
+//!
+//! Clients are paired remotes by ID, or addresses for requests without a valid
+//! token; lockouts are always by address, since a guesser has no remote ID.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::server::{Request, Response};
+use crate::AudioRemoteError;
+
+/// Buckets and failure counts kept at most; full buckets are the first to go
+const MAX_TRACKED: usize = 1024;
+
+/// The server's `rateLimit` configuration; every field is optional
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Sustained requests per second per client; 0 turns rate limiting off
+    #[serde(default = "default_requests_per_second")]
+    pub requests_per_second: f64,
+    /// Requests a client may make at once before the sustained rate applies
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+    /// Failed sign-ins from one address, within `failure_window_secs`, that lock it out; 0 never does
+    #[serde(default = "default_max_auth_failures")]
+    pub max_auth_failures: u32,
+    #[serde(default = "default_failure_window_secs")]
+    pub failure_window_secs: u64,
+    #[serde(default = "default_lockout_secs")]
+    pub lockout_secs: u64,
+}
+
+fn default_requests_per_second() -> f64 {
+    10.0
+}
+
+fn default_burst() -> u32 {
+    30
+}
+
+fn default_max_auth_failures() -> u32 {
+    10
+}
+
+fn default_failure_window_secs() -> u64 {
+    60
+}
+
+fn default_lockout_secs() -> u64 {
+    300
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            requests_per_second: default_requests_per_second(),
+            burst: default_burst(),
+            max_auth_failures: default_max_auth_failures(),
+            failure_window_secs: default_failure_window_secs(),
+            lockout_secs: default_lockout_secs(),
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<(), AudioRemoteError> {
+        if !self.requests_per_second.is_finite() || self.requests_per_second < 0.0 {
+            return Err(AudioRemoteError::InvalidArgument(format!(
+                "requestsPerSecond must be 0 or more, not {}",
+                self.requests_per_second
+            )));
+        }
+        if self.requests_per_second > 0.0 && self.burst == 0 {
+            return Err(AudioRemoteError::InvalidArgument("burst must be at least 1".into()));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    since: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Limiter {
+    config: RateLimitConfig,
+    buckets: BTreeMap<String, Bucket>,
+    failures: BTreeMap<IpAddr, Failures>,
+}
+
+impl Limiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self { config, buckets: BTreeMap::new(), failures: BTreeMap::new() }
+    }
+
+    /// How long `address` is still locked out, if it is
+    pub fn locked_out(&mut self, address: IpAddr, now: Instant) -> Option<Duration> {
+        let failures = self.failures.get(&address)?;
+        match failures.locked_until {
+            Some(until) if until > now => Some(until - now),
+            Some(_) => {
+                self.failures.remove(&address);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Count a failed sign-in; returns the lockout it started, if this was one too many
+    pub fn auth_failed(&mut self, address: IpAddr, now: Instant) -> Option<Duration> {
+        if self.config.max_auth_failures == 0 {
+            return None;
+        }
+        if !self.failures.contains_key(&address) && self.failures.len() >= MAX_TRACKED {
+            self.failures.retain(|_, failures| failures.locked_until.is_some_and(|until| until > now));
+        }
+        let window = Duration::from_secs(self.config.failure_window_secs);
+        let failures = self.failures.entry(address).or_insert(Failures { count: 0, since: now, locked_until: None });
+        if now.duration_since(failures.since) > window {
+            *failures = Failures { count: 0, since: now, locked_until: None };
+        }
+        failures.count += 1;
+        if failures.count < self.config.max_auth_failures {
+            return None;
+        }
+        let lockout = Duration::from_secs(self.config.lockout_secs);
+        failures.locked_until = Some(now + lockout);
+        Some(lockout)
+    }
+
+    /// A good sign-in clears the address's failures
+    pub fn auth_succeeded(&mut self, address: IpAddr) {
+        if self.failures.get(&address).is_some_and(|failures| failures.locked_until.is_none()) {
+            self.failures.remove(&address);
+        }
+    }
+
+    /// Take a token from `client`'s bucket; the error is how long until one is available
+    pub fn take(&mut self, client: &str, now: Instant) -> Result<(), Duration> {
+        let rate = self.config.requests_per_second;
+        if rate <= 0.0 {
+            return Ok(());
+        }
+        let burst = f64::from(self.config.burst);
+        if !self.buckets.contains_key(client) && self.buckets.len() >= MAX_TRACKED {
+            // Forget clients whose buckets have refilled: they'd start full anyway
+            self.buckets.retain(|_, bucket| bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst);
+        }
+        let bucket = self.buckets.entry(client.to_owned()).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+    }
+}
+
+fn too_many(message: &str, wait: Duration) -> Response {
+    // Round up, so a client that waits as told finds a token
+    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    Response::error(429, message).with_header("Retry-After", seconds.max(1).to_string())
+}
+
+fn lock(limiter: &Mutex<Limiter>) -> std::sync::MutexGuard<'_, Limiter> {
+    limiter.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The server's check before authorizing a request: refuses locked-out addresses
+pub(crate) fn check_address(limiter: &Mutex<Limiter>, peer: IpAddr) -> Result<(), Response> {
+    match lock(limiter).locked_out(peer, Instant::now()) {
+        Some(wait) => Err(too_many("too many failed sign-ins from this address; try again later", wait)),
+        None => Ok(()),
+    }
+}
+
+/// The server's check after authorizing a request: spends a token of its client's bucket
+pub(crate) fn check_request(limiter: &Mutex<Limiter>, request: &Request) -> Result<(), Response> {
+    let mut limiter = lock(limiter);
+    if request.remote.is_some() {
+        limiter.auth_succeeded(request.peer.ip());
+    }
+    let client = match &request.remote {
+        Some(remote_id) => remote_id.clone(),
+        None => request.peer.ip().to_string(),
+    };
+    limiter.take(&client, Instant::now()).map_err(|wait| too_many("too many requests; slow down", wait))
+}
+
+/// Note the response to a request: a 401 counts as a failed sign-in for its address
+pub(crate) fn record_response(limiter: &Mutex<Limiter>, peer: IpAddr, response: &Response) {
+    if response.status != 401 {
+        return;
+    }
+    if let Some(lockout) = lock(limiter).auth_failed(peer, Instant::now()) {
+        log::warn!("locking out {peer} for {} s after repeated failed sign-ins", lockout.as_secs());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::exchange;
+    use crate::server::{self, ServerConfig};
+
+    #[test]
+    fn test_token_bucket() {
+        let mut limiter = Limiter::new(RateLimitConfig { requests_per_second: 2.0, burst: 3, ..RateLimitConfig::default() });
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.take("script", start).is_ok());
+        }
+        assert_eq!(limiter.take("script", start), Err(Duration::from_millis(500)));
+        assert!(limiter.take("ipad", start).is_ok(), "buckets are per client");
+        assert!(limiter.take("script", start + Duration::from_millis(500)).is_ok());
+        assert!(limiter.take("script", start + Duration::from_millis(600)).is_err());
+        // Refilling stops at the burst
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..10).filter(|_| limiter.take("script", later).is_ok()).count(), 3);
+
+        let mut off = Limiter::new(RateLimitConfig { requests_per_second: 0.0, ..RateLimitConfig::default() });
+        assert!((0..1000).all(|_| off.take("script", start).is_ok()));
+        assert!(RateLimitConfig { requests_per_second: -1.0, ..RateLimitConfig::default() }.validate().is_err());
+        assert_eq!(too_many("slow down", Duration::from_millis(1)).headers.last().unwrap().1, "1");
+    }
+
+    #[test]
+    fn test_lockout_after_failed_sign_ins() {
+        let config = RateLimitConfig { max_auth_failures: 3, failure_window_secs: 60, lockout_secs: 300, ..RateLimitConfig::default() };
+        let mut limiter = Limiter::new(config);
+        let guesser: IpAddr = "10.0.0.66".parse().unwrap();
+        let start = Instant::now();
+        assert_eq!(limiter.auth_failed(guesser, start), None);
+        assert_eq!(limiter.auth_failed(guesser, start), None);
+        assert_eq!(limiter.auth_failed(guesser, start), Some(Duration::from_secs(300)));
+        assert_eq!(limiter.locked_out(guesser, start + Duration::from_secs(100)), Some(Duration::from_secs(200)));
+        limiter.auth_succeeded(guesser);
+        assert!(limiter.locked_out(guesser, start + Duration::from_secs(100)).is_some(), "a lockout runs its course");
+        assert_eq!(limiter.locked_out(guesser, start + Duration::from_secs(300)), None);
+
+        // Failures spread out over more than the window don't add up
+        let slow: IpAddr = "10.0.0.67".parse().unwrap();
+        for minute in 0..5 {
+            assert_eq!(limiter.auth_failed(slow, start + Duration::from_secs(61 * minute)), None);
+        }
+        limiter.auth_succeeded(slow);
+        assert!(limiter.failures.is_empty());
+    }
+
+    #[test]
+    fn test_limits_over_tcp() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let rate_limit = RateLimitConfig { requests_per_second: 0.01, burst: 3, max_auth_failures: 2, ..RateLimitConfig::default() };
+        let config =
+            ServerConfig { port: 0, bind_address: "127.0.0.1".parse().unwrap(), require_auth: true, rate_limit, ..Default::default() };
+        let address = server::start(&config).unwrap();
+        let get = |path: &str, token: &str| {
+            exchange(address, &format!("GET {path} HTTP/1.1\r\nAuthorization: Bearer {token}\r\nConnection: close\r\n\r\n"))
+        };
+        for _ in 0..3 {
+            assert_eq!(get("/", "").0, 200);
+        }
+        let (status, body) = get("/", "");
+        assert_eq!(status, 429);
+        assert!(body.contains("slow down"), "{body}");
+
+        // Failed sign-ins don't spend tokens, but enough of them lock the address out
+        assert_eq!(get("/api/v1/volume", "guess-1").0, 401);
+        assert_eq!(get("/api/v1/volume", "guess-2").0, 401);
+        let (status, body) = get("/api/v1/volume", "guess-3");
+        server::stop();
+        assert_eq!(status, 429);
+        assert!(body.contains("failed sign-ins"), "{body}");
+    }
+}
@@ -0,0 +1,645 @@
+//! Control from outside the LAN through a relay server, opt in. The Mac keeps
+//! an outbound WebSocket over TLS to a relay the user configures, at
+//! `<url>/mac/<relay ID>`; remotes connect to `<url>/remote/<relay ID>` and the
+//! relay pairs them up. The relay ID is a hash of the Mac's pairing ID (see
+//! `relay_id`), which paired remotes already know, so nothing more needs
+//! exchanging and the relay never learns the pairing ID itself.
+//!
+//! The relay only ever sees ciphertext: each remote runs the `noise`
+//! handshake with the Mac end to end, keyed by its pairing, so a relay can't
+//! read or forge messages, only drop them or see who talks when.
+//!
+//! On the Mac's connection each binary frame is a 4-byte big-endian channel
+//! number, one per connected remote, and then one Noise message. A channel
+//! number the Mac hasn't seen starts a handshake; an empty message closes the
+//! channel, from either side (Noise messages are never empty). Other frames
+//! are ignored. Remotes send and receive bare Noise messages, one per binary
+//! frame.
+//!
+//! A supervisor thread reconnects with backoff, like `mqtt`'s; a reader thread
+//! forwards frames to it, and each channel runs on its own thread, like a
+//! Noise connection on the LAN.
+
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::io::{self, BufReader};
+use std::net::Shutdown;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::checksum::to_hex;
+use crate::connection::{self, Backoff, Beat, ConnectionState, Failure, Heartbeat, Target};
+use crate::error::string_result;
+use crate::noise::{self, Inbox, Outgoing};
+use crate::server::Stream;
+use crate::tls;
+use crate::websocket::{self, Frame, Opcode};
+use crate::{guard, http, pairing, record, str_arg, AudioRemoteError};
+
+const DEFAULT_PORT: u16 = 443;
+const RELAY_ID_CONTEXT: &[u8] = b"audioremote relay v1";
+const MAX_CHANNELS: usize = 32;
+/// The relay gets a ping when it's been quiet this long...
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// ...and is given up on when nothing at all arrives for this long
+const DEAD_AFTER: Duration = Duration::from_secs(60);
+
+fn default_initial_backoff() -> u64 {
+    1_000
+}
+
+fn default_max_backoff() -> u64 {
+    60_000
+}
+
+/// `ar_relay_start` configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct RelayConfig {
+    /// "wss://relay.example.com/audioremote"
+    pub url: String,
+    /// Sent as a bearer token, for relays with accounts
+    pub token: Option<String>,
+    /// Trust only the relay certificate with this SHA-256 fingerprint
+    pub cert_fingerprint: Option<String>,
+    /// A PEM bundle of CAs to trust besides the public ones
+    pub ca_file: Option<PathBuf>,
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff_ms: u64,
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff_ms: u64,
+}
+
+impl RelayConfig {
+    fn validate(&self) -> Result<RelayUrl, AudioRemoteError> {
+        if self.initial_backoff_ms == 0 || self.max_backoff_ms < self.initial_backoff_ms {
+            return Err(AudioRemoteError::InvalidArgument("maxBackoffMs must be at least initialBackoffMs".into()));
+        }
+        if self.cert_fingerprint.is_some() && self.ca_file.is_some() {
+            return Err(AudioRemoteError::InvalidArgument("give certFingerprint or caFile, not both".into()));
+        }
+        RelayUrl::parse(&self.url)
+    }
+
+    fn client_config(&self) -> Result<Arc<rustls::ClientConfig>, AudioRemoteError> {
+        if let Some(fingerprint) = &self.cert_fingerprint {
+            return tls::pinned_client_config(fingerprint);
+        }
+        let extra_roots = match &self.ca_file {
+            Some(path) => http::load_ca_bundle(path)?,
+            None => Vec::new(),
+        };
+        http::tls_config(&extra_roots).map(Arc::new)
+    }
+}
+
+/// A relay URL's parts; only `wss` is accepted, since the token travels in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayUrl {
+    pub host: String,
+    pub port: u16,
+    /// Without a trailing slash; empty for the root
+    pub path: String,
+}
+
+impl RelayUrl {
+    pub fn parse(url: &str) -> Result<Self, AudioRemoteError> {
+        let invalid = |why: &str| AudioRemoteError::InvalidArgument(format!("\"{url}\" isn't a usable relay URL: {why}"));
+        let rest = match url.get(..6) {
+            Some(scheme) if scheme.eq_ignore_ascii_case("wss://") => &url[6..],
+            _ => return Err(invalid("it must start with wss://")),
+        };
+        if rest.contains(['?', '#', '@']) {
+            return Err(invalid("no query, fragment or user name allowed"));
+        }
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed.split_once(']').ok_or_else(|| invalid("unclosed IPv6 address"))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err(invalid("no host"));
+        }
+        let port = match port {
+            Some(port) => port.parse().ok().filter(|port| *port != 0).ok_or_else(|| invalid("bad port"))?,
+            None => DEFAULT_PORT,
+        };
+        Ok(Self { host: host.to_owned(), port, path: path.trim_end_matches('/').to_owned() })
+    }
+
+    /// Where the Mac connects
+    pub fn mac_path(&self, relay_id: &str) -> String {
+        format!("{}/mac/{relay_id}", self.path)
+    }
+}
+
+/// The relay ID for a Mac's pairing ID: 32 hex digits of SHA-256 over a context string and the ID
+pub fn relay_id(mac_id: &str) -> String {
+    to_hex(&Sha256::new().chain_update(RELAY_ID_CONTEXT).chain_update(mac_id.as_bytes()).finalize()[..16])
+}
+
+/// A channel number, then a Noise message; an empty message closes the channel
+pub(crate) fn channel_frame(channel: u32, message: &[u8]) -> Vec<u8> {
+    [&channel.to_be_bytes()[..], message].concat()
+}
+
+fn write(writer: &Mutex<Stream>, opcode: Opcode, payload: &[u8]) -> io::Result<()> {
+    websocket::write_client_frame(&mut *writer.lock().unwrap_or_else(|e| e.into_inner()), opcode, payload)
+}
+
+/// A channel's sending end: frames on the shared relay connection
+struct ChannelOut {
+    writer: Arc<Mutex<Stream>>,
+    channel: u32,
+    closed: AtomicBool,
+}
+
+impl Outgoing for ChannelOut {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        write(&self.writer, Opcode::Binary, &channel_frame(self.channel, message))
+    }
+
+    /// Tell the relay to hang up on the remote, once
+    fn shutdown(&self) -> io::Result<()> {
+        match self.closed.swap(true, Ordering::AcqRel) {
+            true => Ok(()),
+            false => write(&self.writer, Opcode::Binary, &channel_frame(self.channel, &[])),
+        }
+    }
+}
+
+/// Open channels by number: a serial, so a finished channel can't remove its successor, and its inbox
+type ChannelMap = BTreeMap<u32, (u64, mpsc::Sender<Vec<u8>>)>;
+type Channels = Mutex<ChannelMap>;
+
+/// A connected session, for stop() and status() to see
+struct Live {
+    writer: Arc<Mutex<Stream>>,
+    channels: Arc<Channels>,
+}
+
+#[derive(Debug, Clone)]
+struct Status {
+    state: ConnectionState,
+    attempt: u32,
+    error: Option<String>,
+}
+
+struct Shared {
+    config: RelayConfig,
+    url: RelayUrl,
+    relay_id: String,
+    static_key: Vec<u8>,
+    stopping: AtomicBool,
+    /// Wakes a backoff wait when stopping
+    wake: (Mutex<()>, Condvar),
+    live: Mutex<Option<Live>>,
+    status: Mutex<Status>,
+    next_serial: AtomicU64,
+}
+
+impl Shared {
+    fn set_status(&self, state: ConnectionState, attempt: u32, error: Option<String>) {
+        log::debug!("relay {}: {state:?} {}", self.url.host, error.as_deref().unwrap_or_default());
+        *self.status.lock().unwrap_or_else(|e| e.into_inner()) = Status { state, attempt, error };
+    }
+
+    fn live(&self) -> std::sync::MutexGuard<'_, Option<Live>> {
+        self.live.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Wait out a backoff delay; false if stopped meanwhile
+    fn wait(&self, delay: Duration) -> bool {
+        let (lock, condvar) = &self.wake;
+        let guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = condvar.wait_timeout_while(guard, delay, |_| !self.stopping.load(Ordering::Acquire)).unwrap_or_else(|e| e.into_inner());
+        !self.stopping.load(Ordering::Acquire)
+    }
+}
+
+fn lock(channels: &Channels) -> std::sync::MutexGuard<'_, ChannelMap> {
+    channels.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn attempt_failed(error: io::Error) -> Failure {
+    match connection::is_certificate_error(&error) {
+        true => Failure::Permanent { status: None, message: format!("the relay's certificate isn't trusted: {error}") },
+        false => Failure::Retry(error.to_string()),
+    }
+}
+
+/// Hand a message to its channel, starting the channel if it's new
+fn dispatch(shared: &Shared, writer: &Arc<Mutex<Stream>>, channels: &Arc<Channels>, frame: &[u8]) -> io::Result<()> {
+    let Some((channel, message)) = frame.split_first_chunk::<4>() else {
+        return Ok(());
+    };
+    let channel = u32::from_be_bytes(*channel);
+    let mut open = lock(channels);
+    if message.is_empty() {
+        // Dropping the inbox ends the channel's thread
+        open.remove(&channel);
+        return Ok(());
+    }
+    if let Some((_, inbox)) = open.get(&channel) {
+        let _ = inbox.send(message.to_vec());
+        return Ok(());
+    }
+    if open.len() >= MAX_CHANNELS {
+        drop(open);
+        log::warn!("refusing relay channel {channel}: {MAX_CHANNELS} channels open");
+        return write(writer, Opcode::Binary, &channel_frame(channel, &[]));
+    }
+    let (inbox, messages) = mpsc::channel();
+    let _ = inbox.send(message.to_vec());
+    let serial = shared.next_serial.fetch_add(1, Ordering::Relaxed);
+    open.insert(channel, (serial, inbox));
+    drop(open);
+
+    let outgoing = ChannelOut { writer: writer.clone(), channel, closed: AtomicBool::new(false) };
+    let incoming = Inbox::new(messages);
+    let (static_key, open) = (shared.static_key.clone(), channels.clone());
+    let spawned = thread::Builder::new().name(format!("audioremote-relay-{channel}")).spawn(move || {
+        if let Err(e) = noise::serve(Box::new(outgoing), Box::new(incoming), &format!("relay channel {channel}"), &static_key) {
+            log::debug!("relay channel {channel} ended: {e}");
+        }
+        let mut open = lock(&open);
+        if open.get(&channel).is_some_and(|(current, _)| *current == serial) {
+            open.remove(&channel);
+        }
+    });
+    if let Err(e) = spawned {
+        log::warn!("can't start relay channel thread: {e}");
+        lock(channels).remove(&channel);
+    }
+    Ok(())
+}
+
+fn read_frames(mut reader: BufReader<Stream>, frames: mpsc::Sender<io::Result<Frame>>) {
+    loop {
+        let frame = websocket::read_server_frame(&mut reader);
+        let failed = frame.is_err();
+        if frames.send(frame).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Route frames to channels and keep the connection alive until it's lost
+fn relay_frames(shared: &Shared, writer: &Arc<Mutex<Stream>>, channels: &Arc<Channels>, frames: &mpsc::Receiver<io::Result<Frame>>) -> Result<(), String> {
+    let lost = |e: io::Error| e.to_string();
+    let mut heartbeat = Heartbeat::new(PING_INTERVAL, DEAD_AFTER, Instant::now());
+    let mut message: Option<Vec<u8>> = None;
+    loop {
+        let wait = match heartbeat.check(Instant::now()) {
+            Beat::Wait(wait) => wait,
+            Beat::Ping => {
+                write(writer, Opcode::Ping, b"").map_err(lost)?;
+                continue;
+            }
+            Beat::Dead => return Err(format!("nothing from the relay for {} s", DEAD_AFTER.as_secs())),
+        };
+        let frame = match frames.recv_timeout(wait) {
+            Err(RecvTimeoutError::Timeout) => continue,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Err("the relay closed the connection".into()),
+            Ok(Err(e)) => return Err(e.to_string()),
+            Err(RecvTimeoutError::Disconnected) => return Err("the relay closed the connection".into()),
+            Ok(Ok(frame)) => frame,
+        };
+        heartbeat.received(Instant::now());
+        let payload = match frame.opcode {
+            Opcode::Ping => {
+                write(writer, Opcode::Pong, &frame.payload).map_err(lost)?;
+                continue;
+            }
+            Opcode::Pong | Opcode::Text => continue,
+            Opcode::Close => {
+                let _ = write(writer, Opcode::Close, &frame.payload);
+                return Err("the relay closed the connection".into());
+            }
+            Opcode::Binary => frame.payload,
+            Opcode::Continuation => match message.take() {
+                Some(mut so_far) => {
+                    so_far.extend_from_slice(&frame.payload);
+                    so_far
+                }
+                // The rest of a text message
+                None => continue,
+            },
+        };
+        match frame.fin {
+            true => dispatch(shared, writer, channels, &payload).map_err(lost)?,
+            false => message = Some(payload),
+        }
+    }
+}
+
+/// Run one connected session; Err is why it was lost
+fn run_session(shared: &Shared, reader: BufReader<Stream>) -> Result<(), String> {
+    let writer = Arc::new(Mutex::new(reader.get_ref().try_clone().map_err(|e| e.to_string())?));
+    let channels: Arc<Channels> = Arc::default();
+    *shared.live() = Some(Live { writer: writer.clone(), channels: channels.clone() });
+    let (sender, frames) = mpsc::channel();
+    let reader = thread::Builder::new().name("audioremote-relay-read".into()).spawn(move || read_frames(reader, sender));
+    // stop() may have looked for the session before it was there
+    let result = match (reader, shared.stopping.load(Ordering::Acquire)) {
+        (Err(e), _) => Err(format!("can't start the reader thread: {e}")),
+        (Ok(_), true) => Ok(()),
+        (Ok(_), false) => relay_frames(shared, &writer, &channels, &frames),
+    };
+    shared.live().take();
+    // Ends every channel, and unblocks the reader
+    lock(&channels).clear();
+    let _ = writer.lock().unwrap_or_else(|e| e.into_inner()).shutdown(Shutdown::Both);
+    match shared.stopping.load(Ordering::Acquire) {
+        true => Ok(()),
+        false => result,
+    }
+}
+
+fn supervise(shared: Arc<Shared>) {
+    let tls = match shared.config.client_config() {
+        Ok(tls) => tls,
+        Err(e) => return shared.set_status(ConnectionState::Failed, 0, Some(e.to_string())),
+    };
+    let path = shared.url.mac_path(&shared.relay_id);
+    let target = Target { host: &shared.url.host, port: shared.url.port, path: &path, peer: "the relay" };
+    let mut backoff = Backoff::new(Duration::from_millis(shared.config.initial_backoff_ms), Duration::from_millis(shared.config.max_backoff_ms));
+    let mut attempt = 0;
+    while !shared.stopping.load(Ordering::Acquire) {
+        let error = match connection::open_websocket(&target, Some(&tls), shared.config.token.as_deref(), attempt_failed) {
+            Ok(reader) => {
+                backoff.reset();
+                attempt = 0;
+                shared.set_status(ConnectionState::Connected, 0, None);
+                log::info!("connected to the relay at {}", shared.url.host);
+                match run_session(&shared, reader) {
+                    Ok(()) => break,
+                    Err(reason) => reason,
+                }
+            }
+            Err(Failure::Permanent { message, .. }) => {
+                log::error!("relay: {message}");
+                return shared.set_status(ConnectionState::Failed, attempt, Some(message));
+            }
+            Err(Failure::Retry(reason)) => reason,
+        };
+        attempt += 1;
+        let delay = backoff.next(connection::jitter());
+        log::warn!("relay connection lost ({error}); retrying in {} ms", delay.as_millis());
+        shared.set_status(ConnectionState::Reconnecting, attempt, Some(error));
+        if !shared.wait(delay) {
+            break;
+        }
+    }
+    shared.set_status(ConnectionState::Closed, 0, None);
+}
+
+struct Running {
+    shared: Arc<Shared>,
+    supervisor: JoinHandle<()>,
+}
+
+static RELAY: Mutex<Option<Running>> = Mutex::new(None);
+
+/// Connect to a relay, replacing any running client
+pub fn start(config: RelayConfig) -> Result<(), AudioRemoteError> {
+    let url = config.validate()?;
+    stop();
+    let shared = Arc::new(Shared {
+        config,
+        url,
+        relay_id: relay_id(&pairing::mac_id()?),
+        static_key: noise::static_key()?,
+        stopping: AtomicBool::new(false),
+        wake: (Mutex::new(()), Condvar::new()),
+        live: Mutex::new(None),
+        status: Mutex::new(Status { state: ConnectionState::Connecting, attempt: 0, error: None }),
+        next_serial: AtomicU64::new(1),
+    });
+    let supervisor = {
+        let shared = shared.clone();
+        thread::Builder::new()
+            .name("audioremote-relay".into())
+            .spawn(move || supervise(shared))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the relay thread: {e}")))?
+    };
+    *RELAY.lock().unwrap_or_else(|e| e.into_inner()) = Some(Running { shared, supervisor });
+    Ok(())
+}
+
+/// Disconnect, ending every relayed channel; a no-op if not running
+pub fn stop() {
+    let Some(running) = RELAY.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    let shared = &running.shared;
+    shared.stopping.store(true, Ordering::Release);
+    {
+        let _wake = shared.wake.0.lock().unwrap_or_else(|e| e.into_inner());
+        shared.wake.1.notify_all();
+    }
+    if let Some(live) = shared.live().as_ref() {
+        let _ = write(&live.writer, Opcode::Close, &1000u16.to_be_bytes());
+        let _ = live.writer.lock().unwrap_or_else(|e| e.into_inner()).shutdown(Shutdown::Both);
+    }
+    let _ = running.supervisor.join();
+    log::info!("relay client stopped");
+}
+
+/// `{"state": ArConnectionState, "attempt", "error", "relayId", "channels"}`
+pub fn status() -> Value {
+    match RELAY.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(running) => {
+            let shared = &running.shared;
+            let status = shared.status.lock().unwrap_or_else(|e| e.into_inner()).clone();
+            let channels = shared.live().as_ref().map_or(0, |live| lock(&live.channels).len());
+            json!({
+                "state": status.state as i32,
+                "attempt": status.attempt,
+                "error": status.error,
+                "relayId": shared.relay_id,
+                "channels": channels,
+            })
+        }
+        None => json!({
+            "state": ConnectionState::Closed as i32,
+            "attempt": 0,
+            "error": null,
+            "relayId": pairing::mac_id().ok().map(|mac_id| relay_id(&mac_id)),
+            "channels": 0,
+        }),
+    }
+}
+
+/// Keep a connection to a relay server open so paired remotes can reach this Mac from anywhere,
+/// replacing a running client. Each remote's channel is end-to-end encrypted as on the Noise
+/// listener, so the relay only passes ciphertext. `config_json`: {"url" ("wss://host[:port][/path]"),
+/// "token" (bearer token for the relay), "certFingerprint" (pin the relay's certificate), "caFile"
+/// (PEM bundle of extra CAs), "initialBackoffMs" (1000), "maxBackoffMs" (60000)}. Runs until
+/// ar_relay_stop, reconnecting.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_relay_start(config_json: *const c_char) -> i32 {
+    guard("ar_relay_start", -999, || {
+        let result = str_arg(config_json, "config").and_then(|json| {
+            let config: RelayConfig =
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid relay config: {e}")))?;
+            start(config)
+        });
+        record(result).map_or(-999, |()| 1)
+    })
+}
+
+/// Disconnect from the relay and stop reconnecting; a no-op if not running. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_relay_stop() {
+    guard("ar_relay_stop", (), stop)
+}
+
+/// Returns: JSON {"state": ArConnectionState, "attempt", "error", "relayId" (what remotes connect
+/// to; null before pairing is set up), "channels" (remotes connected through the relay)}, state
+/// AR_CONNECTION_CLOSED when not started (free with rust_string_free)
+#[no_mangle]
+pub extern "C" fn ar_relay_status() -> *mut c_char {
+    guard("ar_relay_status", std::ptr::null_mut(), || string_result(Ok(status().to_string())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Topic, HUB};
+    use crate::pairing::Pairing;
+    use crate::protocol::{Envelope, Hello, Message};
+    use crate::noise::Incoming;
+    use crate::tls::{Identity, TlsStream};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_urls() {
+        let url = RelayUrl::parse("wss://relay.example.com/audioremote/").unwrap();
+        assert_eq!(url, RelayUrl { host: "relay.example.com".into(), port: 443, path: "/audioremote".into() });
+        assert_eq!(url.mac_path("abc"), "/audioremote/mac/abc");
+        let url = RelayUrl::parse("WSS://[::1]:8443").unwrap();
+        assert_eq!((url.host.as_str(), url.port, url.path.as_str()), ("::1", 8443, ""));
+        assert_eq!(RelayUrl::parse("wss://relay:9000/r").unwrap().port, 9000);
+        for bad in ["ws://relay", "https://relay", "wss://", "wss://relay:0", "wss://relay:x", "wss://u@relay", "wss://relay/?a=1", "wss://[::1"] {
+            assert!(RelayUrl::parse(bad).is_err(), "{bad}");
+        }
+
+        assert_eq!(relay_id("mac").len(), 32);
+        assert_eq!(relay_id("mac"), relay_id("mac"));
+        assert_ne!(relay_id("mac"), relay_id("other mac"));
+        assert_eq!(channel_frame(258, b"x"), [0, 0, 1, 2, b'x']);
+        let config: RelayConfig = serde_json::from_value(json!({"url": "wss://relay", "maxBackoffMs": 10})).unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    /// The relay's side of channel 7, for a remote running the handshake through it
+    struct RemoteOut(Stream);
+
+    impl Outgoing for RemoteOut {
+        fn send(&mut self, message: &[u8]) -> io::Result<()> {
+            websocket::write_frame(&mut self.0, Opcode::Binary, &channel_frame(7, message))
+        }
+
+        fn shutdown(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct RemoteIn(BufReader<Stream>);
+
+    impl Incoming for RemoteIn {
+        fn receive(&mut self) -> io::Result<Vec<u8>> {
+            loop {
+                let frame = websocket::read_frame(&mut self.0)?;
+                match frame.opcode {
+                    Opcode::Binary => {
+                        assert_eq!(frame.payload[..4], 7u32.to_be_bytes());
+                        return match frame.payload.len() {
+                            4 => Err(io::ErrorKind::UnexpectedEof.into()),
+                            _ => Ok(frame.payload[4..].to_vec()),
+                        };
+                    }
+                    Opcode::Close => return Err(io::ErrorKind::UnexpectedEof.into()),
+                    _ => {}
+                }
+            }
+        }
+
+        fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_relayed_channel() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_store, dir) = crate::store::tests::with_temp_store("relay");
+        let key = [9; 32];
+        pairing::save(Pairing { remote_id: "phone".into(), remote_name: "Phone".into(), key: STANDARD.encode(key), paired_at: 0 }).unwrap();
+        let identity = Identity::generate().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("wss://localhost:{}/r", listener.local_addr().unwrap().port());
+        start(serde_json::from_value(json!({"url": url, "certFingerprint": identity.fingerprint()})).unwrap()).unwrap();
+
+        // Play the relay: take the Mac's connection and answer its handshake
+        let (socket, peer) = listener.accept().unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let stream = Stream::Tls(TlsStream::new(socket, identity.server_config().unwrap()).unwrap());
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let request = crate::server::read_request(&mut reader, peer).unwrap();
+        let expected_id = relay_id(&pairing::mac_id().unwrap());
+        assert_eq!(request.path, format!("/r/mac/{expected_id}"));
+        let accept = websocket::accept_key(request.header("sec-websocket-key").unwrap());
+        let mut writer = stream.try_clone().unwrap();
+        write!(writer, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n")
+            .unwrap();
+        writer.flush().unwrap();
+
+        let (mut sender, mut receiver, mac_id) = noise::initiate(Box::new(RemoteOut(writer)), Box::new(RemoteIn(reader)), "phone", &key).unwrap();
+        assert_eq!(relay_id(&mac_id), expected_id);
+        assert_eq!(status()["state"], json!(ConnectionState::Connected as i32));
+        assert_eq!(status()["channels"], json!(1));
+        sender.send(&Envelope::new(Some(1), Message::Hello(Hello::mac())).encode()).unwrap();
+        loop {
+            let envelope = Envelope::decode(&receiver.receive().unwrap()).unwrap();
+            if envelope.id == Some(1) {
+                assert_eq!(envelope.message.kind(), "welcome");
+                break;
+            }
+        }
+        HUB.publish(Topic::Volume, json!({"volume": 0.5, "muted": false}));
+        loop {
+            let envelope = Envelope::decode(&receiver.receive().unwrap()).unwrap();
+            if envelope.to_json()["body"]["volume"] == json!(0.5) {
+                break;
+            }
+        }
+
+        stop();
+        assert!(receiver.receive().is_err());
+        assert_eq!(status()["state"], json!(ConnectionState::Closed as i32));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+use std::ffi::c_char;
+
+use pulldown_cmark::{html, Options, Parser};
+
+use crate::error::string_result;
+use crate::{guard, str_arg};
+
+/// Render GitHub-flavored Markdown (tables, strikethrough, task lists) to HTML and
+/// sanitize it: scripts, styles, event handlers, iframes and non-http(s)/mailto links
+/// are removed, and links get rel="noopener noreferrer".
+pub fn to_html(markdown: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let mut raw = String::with_capacity(markdown.len() * 3 / 2);
+    html::push_html(&mut raw, Parser::new_ext(markdown, options));
+
+    ammonia::Builder::default()
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .add_tag_attributes("input", ["type", "checked", "disabled"])
+        .add_tags(["input"])
+        .clean(&raw)
+        .to_string()
+}
+
+/// Convert Markdown release notes (e.g. a GitHub release body) to sanitized HTML for the update dialog
+/// Returns: HTML fragment (free with rust_string_free), or NULL on error (see last_error_message)
+///
+/// # Safety
+/// `markdown_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn release_notes_to_html(markdown_ptr: *const c_char) -> *mut c_char {
+    guard("release_notes_to_html", std::ptr::null_mut(), || {
+        string_result(str_arg(markdown_ptr, "markdown").map(to_html))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renders_gfm() {
+        let html = to_html("## What's new\n\n- [x] ~~Old~~ **new** mixer\n\n| a | b |\n|---|---|\n| 1 | 2 |\n");
+        assert!(html.contains("<h2>What's new</h2>"), "{html}");
+        assert!(html.contains("<del>Old</del> <strong>new</strong>"), "{html}");
+        assert!(html.contains("<input"), "{html}");
+        assert!(html.contains("<td>1</td>"), "{html}");
+    }
+
+    #[test]
+    fn test_sanitizes_html() {
+        let html = to_html(
+            "<script>alert(1)</script><img src=x onerror=alert(1)>\n\n[click](javascript:alert(1)) [ok](https://example.com)",
+        );
+        assert!(!html.contains("script"), "{html}");
+        assert!(!html.contains("onerror"), "{html}");
+        assert!(!html.contains("javascript:"), "{html}");
+        assert!(html.contains(r#"<a href="https://example.com" rel="noopener noreferrer">ok</a>"#), "{html}");
+    }
+}
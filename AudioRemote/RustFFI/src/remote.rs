@@ -0,0 +1,96 @@
+//! What a remote sees of the Mac's audio: the state Swift reports and the
+//! commands remotes send, as typed values so every transport encodes them the
+//! same way. Commands are validated here, before they reach Swift.
+
+use serde::{Deserialize, Serialize};
+
+use crate::AudioRemoteError;
+
+/// Output volume (0.0–1.0) and mute state of the current output device
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VolumeState {
+    pub volume: f32,
+    pub muted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputDevice {
+    /// CoreAudio device UID, stable across launches
+    pub id: String,
+    pub name: String,
+    pub is_current: bool,
+    /// Headphones' charge, 0–100, the lower earbud's for a pair
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery_percent: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NowPlaying {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    /// Name of the app playing
+    pub app: Option<String>,
+    pub is_playing: bool,
+    /// Seconds
+    pub elapsed: Option<f64>,
+    pub duration: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SetVolume {
+    pub volume: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SetMute {
+    pub muted: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SelectDevice {
+    pub id: String,
+}
+
+impl SetVolume {
+    pub fn validate(&self) -> Result<(), AudioRemoteError> {
+        match (0.0..=1.0).contains(&self.volume) {
+            true => Ok(()),
+            false => Err(AudioRemoteError::InvalidArgument(format!("volume {} is outside 0.0–1.0", self.volume))),
+        }
+    }
+}
+
+impl SelectDevice {
+    pub fn validate(&self) -> Result<(), AudioRemoteError> {
+        match self.id.trim().is_empty() {
+            true => Err(AudioRemoteError::InvalidArgument("device id is empty".into())),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands_are_validated() {
+        let set: SetVolume = serde_json::from_str(r#"{"volume": 0.25}"#).unwrap();
+        assert!(set.validate().is_ok());
+        assert!(SetVolume { volume: 1.5 }.validate().is_err());
+        assert!(SetVolume { volume: f32::NAN }.validate().is_err());
+        assert!(serde_json::from_str::<SetMute>(r#"{"muted": true, "extra": 1}"#).is_err());
+        assert!(SelectDevice { id: " ".into() }.validate().is_err());
+
+        let playing: NowPlaying = serde_json::from_str(r#"{"title": "Song", "isPlaying": true}"#).unwrap();
+        assert_eq!(playing.artist, None);
+        assert_eq!(serde_json::to_value(&playing).unwrap()["isPlaying"], true);
+    }
+}
@@ -0,0 +1,209 @@
+//! ReplayGain 2.0 track analysis for the user's local files, so playback
+//! leveling can use gains worked out ahead of time instead of riding the
+//! volume live. A file is decoded (WAV, AIFF, FLAC, ALAC, MP3, AAC/M4A, Ogg
+//! Vorbis) and measured with the BS.1770 loudness meter; the track gain brings
+//! its integrated loudness to the reference, -18 LUFS by default as
+//! ReplayGain 2.0 has it. The peak is the highest sample, as ReplayGain tags
+//! carry it, and the true peak is alongside for clip-safe gains.
+//!
+//! Analysis runs on the caller's thread and takes a second or two per track;
+//! `progress` is told the frames done and lets the caller cancel.
+
+use std::ffi::{c_char, c_void};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error;
+
+use crate::audio_file::{self, decode_error};
+use crate::checksum::progress_fn;
+use crate::error::string_result;
+use crate::loudness::{LoudnessConfig, LoudnessMeter};
+use crate::{guard, str_arg, AudioRemoteError, ProgressCallback};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AnalysisOptions {
+    /// The loudness gains aim for
+    #[serde(default = "default_reference_lufs")]
+    pub reference_lufs: f64,
+}
+
+fn default_reference_lufs() -> f64 {
+    -18.0
+}
+
+impl Default for AnalysisOptions {
+    fn default() -> Self {
+        Self { reference_lufs: default_reference_lufs() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TrackGain {
+    /// None for a silent track, which no gain would bring to the reference
+    pub gain_db: Option<f64>,
+    /// The gain, lowered if need be so the true peak stays under full scale
+    pub safe_gain_db: Option<f64>,
+    /// The highest sample, linear (1.0 is full scale)
+    pub peak: f64,
+    pub true_peak_db: Option<f64>,
+    pub loudness_lufs: Option<f64>,
+    pub duration_seconds: f64,
+    pub sample_rate: u32,
+    pub channels: u32,
+}
+
+/// Decode `path` and measure it; `progress` gets (frames done, total frames or 0) after each
+/// packet and returns false to cancel
+pub fn analyze(path: &Path, options: &AnalysisOptions, mut progress: impl FnMut(u64, u64) -> bool) -> Result<TrackGain, AudioRemoteError> {
+    if !options.reference_lufs.is_finite() || !(-70.0..=0.0).contains(&options.reference_lufs) {
+        return Err(AudioRemoteError::InvalidArgument(format!("referenceLufs {} is outside -70–0", options.reference_lufs)));
+    }
+    let mut format = audio_file::open(path)?.format;
+    let track = audio_file::audio_track(path, format.as_ref())?;
+    let (track_id, total) = (track.id, track.codec_params.n_frames.unwrap_or(0));
+    let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default()).map_err(|e| decode_error(path, e))?;
+
+    let mut meter: Option<(LoudnessMeter, u32)> = None;
+    let mut buffer: Option<SampleBuffer<f32>> = None;
+    let (mut frames, mut peak) = (0u64, 0.0f32);
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(decode_error(path, e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A damaged packet is skipped, as a player would
+            Err(Error::DecodeError(e)) => {
+                log::debug!("skipping a bad packet in {}: {e}", path.display());
+                continue;
+            }
+            Err(e) => return Err(decode_error(path, e)),
+        };
+        let spec = *decoded.spec();
+        let channels = spec.channels.count() as u32;
+        let meter = match &mut meter {
+            Some((meter, rate)) if meter.channel_count() == channels as usize && *rate == spec.rate => meter,
+            Some(_) => return Err(AudioRemoteError::Unsupported(format!("{} changes format partway", path.display()))),
+            None => {
+                let config = LoudnessConfig { channels, sample_rate: f64::from(spec.rate), channel_weights: None };
+                &mut meter.insert((LoudnessMeter::new(&config)?, spec.rate)).0
+            }
+        };
+        let samples = match &mut buffer {
+            Some(samples) if samples.capacity() >= decoded.capacity() * channels as usize => samples,
+            _ => buffer.insert(SampleBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        samples.copy_interleaved_ref(decoded);
+        let samples = samples.samples();
+        peak = samples.iter().filter(|sample| sample.is_finite()).fold(peak, |peak, sample| peak.max(sample.abs()));
+        meter.process(samples);
+        frames += (samples.len() / channels as usize) as u64;
+        if !progress(frames, total) {
+            return Err(AudioRemoteError::Cancelled);
+        }
+    }
+
+    let (meter, rate) = meter.ok_or(AudioRemoteError::InvalidData(format!("{} has no audio", path.display())))?;
+    let loudness = Some(meter.loudness().integrated).filter(|lufs| lufs.is_finite());
+    let true_peak = Some(meter.true_peak_db()).filter(|db| db.is_finite());
+    let gain_db = loudness.map(|lufs| options.reference_lufs - lufs);
+    Ok(TrackGain {
+        gain_db,
+        safe_gain_db: gain_db.map(|gain| true_peak.map_or(gain, |true_peak| gain.min(-true_peak))),
+        peak: f64::from(peak),
+        true_peak_db: true_peak,
+        loudness_lufs: loudness,
+        duration_seconds: frames as f64 / f64::from(rate),
+        sample_rate: rate,
+        channels: meter.channel_count() as u32,
+    })
+}
+
+/// Work out a local audio file's ReplayGain 2.0 track gain and peak (WAV, AIFF, FLAC, ALAC, MP3,
+/// AAC/M4A, Ogg Vorbis). `options_json` (nullable): {"referenceLufs" (default -18)}. `progress`
+/// (nullable) gets (frames done, total frames or 0 if unknown) as it goes; return false to cancel.
+/// Returns: JSON {"gainDb", "safeGainDb" (lowered to keep the true peak under 0 dBTP), "peak"
+/// (linear), "truePeakDb", "loudnessLufs", "durationSeconds", "sampleRate", "channels"}, the
+/// gains and levels null for a silent track (free with rust_string_free), NULL on error or cancel
+/// (see last_error_message)
+///
+/// # Safety
+/// `path_ptr` must point to a valid NUL-terminated string and `options_json` be null or one;
+/// `ctx` is passed through to `progress` untouched.
+#[no_mangle]
+pub unsafe extern "C" fn ar_replaygain_analyze(
+    path_ptr: *const c_char,
+    options_json: *const c_char,
+    progress: Option<ProgressCallback>,
+    ctx: *mut c_void,
+) -> *mut c_char {
+    guard("ar_replaygain_analyze", std::ptr::null_mut(), || {
+        let result = str_arg(path_ptr, "path").and_then(|path| {
+            let options = match options_json.is_null() {
+                true => AnalysisOptions::default(),
+                false => serde_json::from_str(str_arg(options_json, "options")?)
+                    .map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid ReplayGain options: {e}")))?,
+            };
+            let gain = analyze(Path::new(path), &options, progress_fn(progress, ctx))?;
+            serde_json::to_string(&gain).map_err(|e| AudioRemoteError::Other(e.to_string()))
+        });
+        string_result(result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_file::tests::wav;
+    use crate::error::tests::take_c_string;
+    use std::ffi::CString;
+
+    #[test]
+    fn test_track_gain() {
+        let path = wav("rg-tone", 2, 5.0, -23.0);
+        let mut calls = Vec::new();
+        let gain = analyze(&path, &AnalysisOptions::default(), |done, total| {
+            calls.push((done, total));
+            true
+        })
+        .unwrap();
+        assert!((gain.gain_db.unwrap() - 5.0).abs() < 0.1, "{gain:?}");
+        assert!((gain.peak - 10f64.powf(-23.0 / 20.0)).abs() < 1e-3 && (gain.true_peak_db.unwrap() + 23.0).abs() < 0.1, "{gain:?}");
+        assert_eq!(gain.safe_gain_db, gain.gain_db);
+        assert_eq!((gain.sample_rate, gain.channels), (44_100, 2));
+        assert!((gain.duration_seconds - 5.0).abs() < 1e-6);
+        assert_eq!(calls.last(), Some(&(220_500, 220_500)));
+
+        // A loud track's gain is cut back to keep it from clipping; cancelling stops the scan
+        let loud = wav("rg-loud", 1, 3.0, -6.0);
+        let gain = analyze(&loud, &AnalysisOptions { reference_lufs: 0.0 }, |_, _| true).unwrap();
+        assert!((gain.gain_db.unwrap() - 9.0).abs() < 0.1 && (gain.safe_gain_db.unwrap() - 6.0).abs() < 0.1, "{gain:?}");
+        assert!(matches!(analyze(&loud, &AnalysisOptions::default(), |_, _| false), Err(AudioRemoteError::Cancelled)));
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&loud).unwrap();
+    }
+
+    #[test]
+    fn test_ffi() {
+        let path = wav("rg-ffi", 2, 1.0, -100.0);
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let json = take_c_string(unsafe { ar_replaygain_analyze(c_path.as_ptr(), std::ptr::null(), None, std::ptr::null_mut()) }).unwrap();
+        // -100 dBFS rounds to silence in 16 bits
+        assert!(json.contains("\"gainDb\":null") && json.contains("\"peak\":0.0"), "{json}");
+        std::fs::write(&path, b"not audio").unwrap();
+        assert_eq!(take_c_string(unsafe { ar_replaygain_analyze(c_path.as_ptr(), std::ptr::null(), None, std::ptr::null_mut()) }), None);
+        let options = c"{\"referenceLufs\": 5}";
+        assert_eq!(take_c_string(unsafe { ar_replaygain_analyze(c_path.as_ptr(), options.as_ptr(), None, std::ptr::null_mut()) }), None);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,424 @@
+//! Sample rate conversion for streaming to remotes when the device's rate
+//! isn't the stream's. A windowed-sinc filter (Kaiser window, cut off just
+//! under the lower of the two Nyquist frequencies) is tabulated at
+//! `PHASES` fractional positions, and each output frame interpolates between
+//! the two nearest phases. The position steps in exact fractions of the
+//! output rate, so 44.1 → 48 kHz never drifts however long it runs.
+//!
+//! Output lines up with input: the first output frame is at the first input
+//! frame's time. Output lags input by the filter's half-length (8, 16 or 32
+//! input frames); ar_resampler_flush pushes that much silence to drain it at
+//! the end of a stream.
+
+use std::collections::VecDeque;
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::handle::{Handle, Registry};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+/// Fractional positions in the filter table
+const PHASES: usize = 256;
+/// How many seconds of output may wait to be pulled
+const MAX_QUEUED_SECONDS: usize = 10;
+/// The passband, as a share of the lower Nyquist frequency
+const ROLLOFF: f64 = 0.95;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Quality {
+    Low,
+    Medium,
+    #[default]
+    High,
+}
+
+impl Quality {
+    /// Filter taps either side of the output position, and the Kaiser window's beta
+    fn filter(self) -> (usize, f64) {
+        match self {
+            Self::Low => (8, 6.0),
+            Self::Medium => (16, 8.0),
+            Self::High => (32, 10.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ResamplerConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    pub input_rate: u32,
+    pub output_rate: u32,
+    #[serde(default)]
+    pub quality: Quality,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+/// The zeroth-order modified Bessel function, for the Kaiser window
+fn bessel_i0(x: f64) -> f64 {
+    let (mut sum, mut term, mut k) = (1.0, 1.0, 1.0);
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)).powi(2);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    match b {
+        0 => a,
+        b => gcd(b, a % b),
+    }
+}
+
+pub struct Resampler {
+    channels: usize,
+    half: usize,
+    /// `PHASES` + 1 rows of 2 × `half` taps: row p is the filter at fraction p / PHASES
+    table: Vec<f32>,
+    /// The step per output frame is `step` / `denominator` input frames
+    step: u64,
+    denominator: u64,
+    /// Interleaved input, from `half` - 1 frames before the next output position
+    input: Vec<f32>,
+    /// The next output position: frame `index` of `input`, plus `fraction` / `denominator`
+    index: usize,
+    fraction: u64,
+    output: VecDeque<f32>,
+    max_queued: usize,
+}
+
+impl Resampler {
+    pub fn new(config: &ResamplerConfig) -> Result<Self, AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=MAX_CHANNELS).contains(&config.channels) {
+            return invalid(format!("channels {} is outside 1–{MAX_CHANNELS}", config.channels));
+        }
+        for (name, rate) in [("inputRate", config.input_rate), ("outputRate", config.output_rate)] {
+            if !(1000..=768_000).contains(&rate) {
+                return invalid(format!("{name} {rate} is outside 1000–768000"));
+            }
+        }
+        let (half, beta) = config.quality.filter();
+        let (input_rate, output_rate) = (u64::from(config.input_rate), u64::from(config.output_rate));
+        let cutoff = ROLLOFF * (output_rate as f64 / input_rate as f64).min(1.0);
+        let window_scale = bessel_i0(beta);
+        let mut table = Vec::with_capacity((PHASES + 1) * 2 * half);
+        for phase in 0..=PHASES {
+            for tap in 0..2 * half {
+                let distance = (tap as f64 - (half - 1) as f64) - phase as f64 / PHASES as f64;
+                let x = distance / half as f64;
+                let window = match x.abs() < 1.0 {
+                    true => bessel_i0(beta * (1.0 - x * x).sqrt()) / window_scale,
+                    false => 0.0,
+                };
+                let sinc = match distance == 0.0 {
+                    true => 1.0,
+                    false => (std::f64::consts::PI * cutoff * distance).sin() / (std::f64::consts::PI * cutoff * distance),
+                };
+                table.push((cutoff * sinc * window) as f32);
+            }
+        }
+        let divisor = gcd(input_rate, output_rate);
+        let channels = config.channels as usize;
+        let mut resampler = Self {
+            channels,
+            half,
+            table,
+            step: input_rate / divisor,
+            denominator: output_rate / divisor,
+            input: vec![],
+            index: 0,
+            fraction: 0,
+            output: VecDeque::new(),
+            max_queued: config.output_rate as usize * MAX_QUEUED_SECONDS * channels,
+        };
+        resampler.reset();
+        Ok(resampler)
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    /// Output frames waiting to be pulled
+    pub fn available(&self) -> usize {
+        self.output.len() / self.channels
+    }
+
+    /// Add interleaved input and convert as much of it as the filter can reach; a partial
+    /// frame at the end is ignored. Refused while too much output is waiting to be pulled.
+    pub fn push(&mut self, samples: &[f32]) -> Result<(), AudioRemoteError> {
+        if self.output.len() >= self.max_queued {
+            return Err(AudioRemoteError::Refused(format!("{} frames are waiting to be pulled", self.available())));
+        }
+        let whole = samples.len() - samples.len() % self.channels;
+        self.input.extend(samples[..whole].iter().map(|&sample| if sample.is_finite() { sample } else { 0.0 }));
+        self.convert();
+        Ok(())
+    }
+
+    fn convert(&mut self) {
+        let (channels, taps) = (self.channels, 2 * self.half);
+        let frames = self.input.len() / channels;
+        // The output at `index` needs input up to `index` + `half`
+        while self.index + self.half < frames {
+            let position = self.fraction as f64 * PHASES as f64 / self.denominator as f64;
+            let phase = (position as usize).min(PHASES - 1);
+            let blend = (position - phase as f64) as f32;
+            let (low, high) = (&self.table[phase * taps..][..taps], &self.table[(phase + 1) * taps..][..taps]);
+            let first = (self.index + 1 - self.half) * channels;
+            for channel in 0..channels {
+                let mut sum = 0.0f32;
+                for tap in 0..taps {
+                    let coefficient = low[tap] + (high[tap] - low[tap]) * blend;
+                    sum += coefficient * self.input[first + tap * channels + channel];
+                }
+                self.output.push_back(sum);
+            }
+            self.fraction += self.step;
+            self.index += (self.fraction / self.denominator) as usize;
+            self.fraction %= self.denominator;
+        }
+        // Keep only what the next output still needs
+        let consumed = (self.index + 1).saturating_sub(self.half).min(frames);
+        self.input.drain(..consumed * channels);
+        self.index -= consumed;
+    }
+
+    /// Move up to `out.len()` / channels converted frames into `out`, returning how many
+    pub fn pull(&mut self, out: &mut [f32]) -> usize {
+        let frames = (out.len() / self.channels).min(self.available());
+        for (slot, sample) in out.iter_mut().zip(self.output.drain(..frames * self.channels)) {
+            *slot = sample;
+        }
+        frames
+    }
+
+    /// Push the filter's half-length of silence, so all the input so far comes out
+    pub fn flush(&mut self) {
+        self.input.extend(std::iter::repeat_n(0.0, self.half * self.channels));
+        self.convert();
+    }
+
+    /// Drop all input and output, e.g. between streams
+    pub fn reset(&mut self) {
+        // Silence before the first frame, so it's converted with a full filter
+        self.input = vec![0.0; (self.half - 1) * self.channels];
+        (self.index, self.fraction) = (self.half - 1, 0);
+        self.output.clear();
+    }
+}
+
+static RESAMPLERS: Registry<Mutex<Resampler>> = Registry::new("resampler");
+
+fn with_resampler<T>(handle: Handle, body: impl FnOnce(&mut Resampler) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let resampler = RESAMPLERS.get(handle)?;
+    let mut resampler = resampler.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut resampler)
+}
+
+/// Create a sample rate converter. `config_json`: {"channels" (default 2), "inputRate",
+/// "outputRate", "quality": "low" | "medium" | "high" (default)}
+/// Returns: a handle (free with ar_resampler_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_resampler_new(config_json: *const c_char) -> Handle {
+    guard("ar_resampler_new", 0, || {
+        let config = str_arg(config_json, "config").and_then(|json| {
+            serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid resampler config: {e}")))
+        });
+        match record(config.and_then(|config| Resampler::new(&config))) {
+            Some(resampler) => RESAMPLERS.insert(Mutex::new(resampler)),
+            None => 0,
+        }
+    })
+}
+
+/// Push `frames` frames of interleaved float samples at the input rate
+/// Returns: 1 on success, -999 on error, e.g. too much output is waiting (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_resampler_push(resampler: Handle, samples: *const f32, frames: usize) -> i32 {
+    guard("ar_resampler_push", -999, || {
+        let result = with_resampler(resampler, |resampler| {
+            let len = frames
+                .checked_mul(resampler.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len == 0 {
+                return Ok(1);
+            }
+            if samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            resampler.push(std::slice::from_raw_parts(samples, len))?;
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Pull up to `capacity` frames of converted interleaved samples at the output rate into `out`
+/// Returns: the frames written (0 when none are ready), -999 on error (see last_error_message)
+///
+/// # Safety
+/// `out` must have room for `capacity` × channels floats, or may be null when `capacity` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_resampler_pull(resampler: Handle, out: *mut f32, capacity: usize) -> i64 {
+    guard("ar_resampler_pull", -999, || {
+        let result = with_resampler(resampler, |resampler| {
+            let len = capacity.saturating_mul(resampler.channel_count()).min(resampler.available() * resampler.channel_count());
+            if len == 0 {
+                return Ok(0);
+            }
+            if out.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("out must not be null".into()));
+            }
+            Ok(resampler.pull(std::slice::from_raw_parts_mut(out, len)) as i64)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Returns: converted frames waiting to be pulled, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_resampler_available(resampler: Handle) -> i64 {
+    guard("ar_resampler_available", -999, || {
+        record(with_resampler(resampler, |resampler| Ok(resampler.available() as i64))).unwrap_or(-999)
+    })
+}
+
+/// Convert the end of the input, e.g. when a stream stops
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_resampler_flush(resampler: Handle) -> i32 {
+    guard("ar_resampler_flush", -999, || {
+        record(with_resampler(resampler, |resampler| {
+            resampler.flush();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Drop all input and output, e.g. between streams
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_resampler_reset(resampler: Handle) -> i32 {
+    guard("ar_resampler_reset", -999, || {
+        record(with_resampler(resampler, |resampler| {
+            resampler.reset();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Release a converter from ar_resampler_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_resampler_free(resampler: Handle) -> i32 {
+    guard("ar_resampler_free", -999, || record(RESAMPLERS.remove(resampler)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(hz: f64, rate: u32, frames: usize) -> Vec<f32> {
+        (0..frames).map(|i| (2.0 * std::f64::consts::PI * hz * i as f64 / f64::from(rate)).sin() as f32 * 0.5).collect()
+    }
+
+    /// Convert in uneven chunks and return everything, flushed
+    fn convert(resampler: &mut Resampler, input: &[f32]) -> Vec<f32> {
+        let mut output = vec![];
+        let mut buffer = vec![0.0; 777 * resampler.channel_count()];
+        for chunk in input.chunks(1234 * resampler.channel_count()) {
+            resampler.push(chunk).unwrap();
+            while let frames @ 1.. = resampler.pull(&mut buffer) {
+                output.extend_from_slice(&buffer[..frames * resampler.channel_count()]);
+            }
+        }
+        resampler.flush();
+        let mut rest = vec![0.0; resampler.available() * resampler.channel_count()];
+        resampler.pull(&mut rest);
+        output.extend(rest);
+        output
+    }
+
+    #[test]
+    fn test_conversion() {
+        for (input_rate, output_rate) in [(44_100, 48_000), (48_000, 44_100), (48_000, 16_000)] {
+            let config = ResamplerConfig { channels: 1, input_rate, output_rate, quality: Quality::High };
+            let mut resampler = Resampler::new(&config).unwrap();
+            let output = convert(&mut resampler, &sine(1000.0, input_rate, input_rate as usize));
+
+            // A second in is (all but the flush) a second out, in phase with the ideal tone
+            assert!(output.len().abs_diff(output_rate as usize) <= 1, "{input_rate} → {output_rate}: {}", output.len());
+            let expected = sine(1000.0, output_rate, output.len());
+            let error = output[100..output.len() - 100].iter().zip(&expected[100..]).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+            assert!(error < 1e-3, "{input_rate} → {output_rate}: {error}");
+        }
+
+        // Above the output's Nyquist frequency is filtered out rather than aliased
+        let config = ResamplerConfig { channels: 1, input_rate: 48_000, output_rate: 16_000, quality: Quality::High };
+        let mut resampler = Resampler::new(&config).unwrap();
+        let output = convert(&mut resampler, &sine(12_000.0, 48_000, 48_000));
+        assert!(output[100..output.len() - 100].iter().all(|sample| sample.abs() < 1e-3));
+    }
+
+    #[test]
+    fn test_channels_and_limits() {
+        let config = ResamplerConfig { channels: 2, input_rate: 32_000, output_rate: 48_000, quality: Quality::Low };
+        let mut resampler = Resampler::new(&config).unwrap();
+        let input: Vec<f32> = sine(500.0, 32_000, 3200).into_iter().flat_map(|sample| [sample, -sample]).collect();
+        let output = convert(&mut resampler, &input);
+        assert!(output.chunks(2).all(|frame| (frame[0] + frame[1]).abs() < 1e-6));
+        assert!(output.chunks(2).map(|frame| frame[0].abs()).fold(0.0, f32::max) > 0.49);
+
+        // Nothing is pulled for ten seconds: the next push is refused until it is
+        let mut resampler = Resampler::new(&ResamplerConfig { channels: 1, input_rate: 8000, output_rate: 8000, quality: Quality::Low }).unwrap();
+        resampler.push(&vec![0.25; 80_100]).unwrap();
+        assert!(matches!(resampler.push(&[0.0]), Err(AudioRemoteError::Refused(_))));
+        let mut out = vec![0.0; 80_100];
+        assert_eq!(resampler.pull(&mut out), 80_100 - Quality::Low.filter().0);
+        assert!((out[100] - 0.25).abs() < 1e-3, "{}", out[100]);
+        resampler.push(&[0.0]).unwrap();
+        resampler.reset();
+        assert_eq!(resampler.available(), 0);
+
+        assert!(Resampler::new(&ResamplerConfig { channels: 1, input_rate: 0, output_rate: 8000, quality: Quality::Low }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let resampler = unsafe { ar_resampler_new(c"{\"channels\": 1, \"inputRate\": 48000, \"outputRate\": 24000}".as_ptr()) };
+        assert_ne!(resampler, 0);
+        let input = sine(440.0, 48_000, 4800);
+        assert_eq!(unsafe { ar_resampler_push(resampler, input.as_ptr(), input.len()) }, 1);
+        assert_eq!(ar_resampler_flush(resampler), 1);
+        assert_eq!(ar_resampler_available(resampler), 2400);
+        let mut out = vec![0.0f32; 1000];
+        assert_eq!(unsafe { ar_resampler_pull(resampler, out.as_mut_ptr(), 1000) }, 1000);
+        assert_eq!(unsafe { ar_resampler_pull(resampler, std::ptr::null_mut(), 0) }, 0);
+        assert_eq!(unsafe { ar_resampler_pull(resampler, std::ptr::null_mut(), 10) }, -999);
+        assert_eq!(ar_resampler_reset(resampler), 1);
+        assert_eq!(ar_resampler_available(resampler), 0);
+        assert_eq!(ar_resampler_free(resampler), 1);
+        assert_eq!(ar_resampler_free(resampler), -999);
+        assert_eq!(unsafe { ar_resampler_new(c"{\"inputRate\": 48000}".as_ptr()) }, 0);
+    }
+}
@@ -0,0 +1,334 @@
+//! Single-producer, single-consumer ring buffers for handing audio between
+//! the Core Audio render callback and the streaming thread. Writing and
+//! reading never allocate, lock or wait: each side only moves its own
+//! position, published with release/acquire atomics, so the render thread
+//! can't be held up by the network. A full ring drops what doesn't fit and
+//! counts it as an overrun; reading an empty one returns 0 frames.
+//!
+//! Each ring has one writing thread and one reading thread at a time; a second
+//! writer (or reader) overlapping the first is refused rather than racing it.
+//! Handle lookups go through a one-entry cache per thread and side, so after
+//! the first call on a thread the registry's lock isn't taken either. A freed
+//! ring is kept until no cache holds it, and then released by whoever creates
+//! or frees the next ring, so its memory never goes back on an audio thread.
+
+use std::cell::{RefCell, UnsafeCell};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::LocalKey;
+
+use crate::handle::{Handle, Registry};
+use crate::{guard, record, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+/// 16 M samples (64 MB), several minutes of stereo at 48 kHz
+const MAX_SAMPLES: usize = 1 << 24;
+
+pub struct Ring {
+    channels: usize,
+    /// In frames
+    capacity: u64,
+    buffer: Box<[UnsafeCell<f32>]>,
+    /// Frames written and read since creation; only the writer moves `written`, only the reader `read`
+    written: AtomicU64,
+    read: AtomicU64,
+    overruns: AtomicU64,
+    writing: AtomicBool,
+    reading: AtomicBool,
+    freed: AtomicBool,
+}
+
+// The buffer is only touched between a writer's or reader's claim of its side and its release
+// of the position, and each side has at most one claimant
+unsafe impl Sync for Ring {}
+
+/// Holds one side of a ring, releasing it when dropped
+struct Claim<'a>(&'a AtomicBool);
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+impl Ring {
+    pub fn new(capacity: usize, channels: u32) -> Result<Self, AudioRemoteError> {
+        if !(1..=MAX_CHANNELS).contains(&channels) {
+            return Err(AudioRemoteError::InvalidArgument(format!("channels {channels} is outside 1–{MAX_CHANNELS}")));
+        }
+        let samples = capacity.checked_mul(channels as usize).filter(|&samples| (1..=MAX_SAMPLES).contains(&samples));
+        let Some(samples) = samples else {
+            return Err(AudioRemoteError::InvalidArgument(format!("a capacity of {capacity} frames must be 1–{} samples", MAX_SAMPLES)));
+        };
+        Ok(Self {
+            channels: channels as usize,
+            capacity: capacity as u64,
+            buffer: (0..samples).map(|_| UnsafeCell::new(0.0)).collect(),
+            written: AtomicU64::new(0),
+            read: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            writing: AtomicBool::new(false),
+            reading: AtomicBool::new(false),
+            freed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    /// Frames waiting to be read
+    pub fn available(&self) -> usize {
+        (self.written.load(Ordering::Acquire) - self.read.load(Ordering::Acquire)) as usize
+    }
+
+    /// Frames dropped because the ring was full
+    pub fn overruns(&self) -> u64 {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    fn claim<'a>(&self, side: &'a AtomicBool, name: &str) -> Result<Claim<'a>, AudioRemoteError> {
+        match side.swap(true, Ordering::Acquire) {
+            true => Err(AudioRemoteError::Refused(format!("another thread is {name} this ring"))),
+            false => Ok(Claim(side)),
+        }
+    }
+
+    /// Copy `frames` frames between the ring starting at frame `position` and `samples`, in two
+    /// parts where the ring wraps
+    fn copy(&self, position: u64, frames: usize, mut each: impl FnMut(*mut f32, std::ops::Range<usize>)) {
+        let start = (position % self.capacity) as usize;
+        let first = frames.min(self.capacity as usize - start);
+        let base = UnsafeCell::raw_get(self.buffer.as_ptr());
+        // SAFETY: both ranges are inside the buffer: start + first <= capacity, frames - first < capacity
+        unsafe {
+            each(base.add(start * self.channels), 0..first * self.channels);
+            each(base, first * self.channels..frames * self.channels);
+        }
+    }
+
+    /// Append interleaved samples, ignoring a partial frame at the end. Frames that don't fit are
+    /// dropped and counted as overruns. Returns the frames written.
+    pub fn write(&self, samples: &[f32]) -> Result<usize, AudioRemoteError> {
+        let _claim = self.claim(&self.writing, "writing to")?;
+        let written = self.written.load(Ordering::Relaxed);
+        let space = (self.capacity - (written - self.read.load(Ordering::Acquire))) as usize;
+        let offered = samples.len() / self.channels;
+        let frames = offered.min(space);
+        self.copy(written, frames, |ring, range| {
+            let part = &samples[range];
+            // SAFETY: the reader doesn't touch frames past `written` until it's published below
+            unsafe { std::ptr::copy_nonoverlapping(part.as_ptr(), ring, part.len()) };
+        });
+        self.written.store(written + frames as u64, Ordering::Release);
+        if frames < offered {
+            self.overruns.fetch_add((offered - frames) as u64, Ordering::Relaxed);
+        }
+        Ok(frames)
+    }
+
+    /// Take up to `out.len()` / channels frames into `out`. Returns the frames read; the rest of
+    /// `out` is left as it was.
+    pub fn read(&self, out: &mut [f32]) -> Result<usize, AudioRemoteError> {
+        let _claim = self.claim(&self.reading, "reading from")?;
+        let read = self.read.load(Ordering::Relaxed);
+        let frames = (out.len() / self.channels).min((self.written.load(Ordering::Acquire) - read) as usize);
+        self.copy(read, frames, |ring, range| {
+            let part = &mut out[range];
+            // SAFETY: the writer doesn't touch frames before `read` + capacity until it's published below
+            unsafe { std::ptr::copy_nonoverlapping(ring, part.as_mut_ptr(), part.len()) };
+        });
+        self.read.store(read + frames as u64, Ordering::Release);
+        Ok(frames)
+    }
+}
+
+static RINGS: Registry<Ring> = Registry::new("ring buffer");
+/// Freed rings, until the thread caches let go of them
+static RETIRED: Mutex<Vec<Arc<Ring>>> = Mutex::new(Vec::new());
+
+/// Drop the freed rings nothing else holds any more; never called on the audio threads
+fn release_retired() {
+    RETIRED.lock().unwrap_or_else(|e| e.into_inner()).retain(|ring| Arc::strong_count(ring) > 1);
+}
+
+type Cache = RefCell<Option<(Handle, Arc<Ring>)>>;
+
+thread_local! {
+    static WRITER: Cache = const { RefCell::new(None) };
+    static READER: Cache = const { RefCell::new(None) };
+}
+
+/// Look `handle` up through this thread's cache for one side, going to the registry only on a miss
+fn cached(cache: &'static LocalKey<Cache>, handle: Handle) -> Result<Arc<Ring>, AudioRemoteError> {
+    cache.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        match &*cache {
+            Some((cached, ring)) if *cached == handle && !ring.freed.load(Ordering::Acquire) => Ok(ring.clone()),
+            _ => {
+                // Never the last reference: the registry or RETIRED has the ring
+                *cache = None;
+                let ring = RINGS.get(handle)?;
+                *cache = Some((handle, ring.clone()));
+                Ok(ring)
+            }
+        }
+    })
+}
+
+/// Create a ring holding `capacity` frames of `channels` interleaved floats, e.g. 100 ms worth
+/// to ride out network jitter
+/// Returns: a handle (free with ar_ring_free), or 0 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_ring_new(capacity: usize, channels: u32) -> Handle {
+    guard("ar_ring_new", 0, || {
+        release_retired();
+        match record(Ring::new(capacity, channels)) {
+            Some(ring) => RINGS.insert(ring),
+            None => 0,
+        }
+    })
+}
+
+/// Append `frames` frames of interleaved float samples, from the one producing thread (the render
+/// callback). Never allocates, locks or waits; frames that don't fit are dropped as overruns.
+/// Returns: the frames written, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_ring_write(ring: Handle, samples: *const f32, frames: usize) -> i64 {
+    guard("ar_ring_write", -999, || {
+        let result = cached(&WRITER, ring).and_then(|ring| {
+            let len = frames.checked_mul(ring.channel_count()).ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            match len {
+                0 => Ok(0),
+                _ if samples.is_null() => Err(AudioRemoteError::InvalidArgument("samples must not be null".into())),
+                len => ring.write(std::slice::from_raw_parts(samples, len)),
+            }
+        });
+        record(result).map_or(-999, |frames| frames as i64)
+    })
+}
+
+/// Take up to `frames` frames of interleaved float samples into `out`, from the one consuming
+/// thread (the streaming thread). Never allocates, locks or waits.
+/// Returns: the frames read (0 when empty), -999 on error (see last_error_message)
+///
+/// # Safety
+/// `out` must point to room for `frames` × channels floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_ring_read(ring: Handle, out: *mut f32, frames: usize) -> i64 {
+    guard("ar_ring_read", -999, || {
+        let result = cached(&READER, ring).and_then(|ring| {
+            let len = frames.checked_mul(ring.channel_count()).ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            match len {
+                0 => Ok(0),
+                _ if out.is_null() => Err(AudioRemoteError::InvalidArgument("out must not be null".into())),
+                len => ring.read(std::slice::from_raw_parts_mut(out, len)),
+            }
+        });
+        record(result).map_or(-999, |frames| frames as i64)
+    })
+}
+
+/// Returns: the frames waiting to be read, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_ring_available(ring: Handle) -> i64 {
+    guard("ar_ring_available", -999, || record(RINGS.get(ring)).map_or(-999, |ring| ring.available() as i64))
+}
+
+/// Returns: the frames dropped so far because the ring was full, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_ring_overruns(ring: Handle) -> i64 {
+    guard("ar_ring_overruns", -999, || record(RINGS.get(ring)).map_or(-999, |ring| ring.overruns() as i64))
+}
+
+/// Release a ring from ar_ring_new; the handle is invalid afterwards, including on threads that
+/// have used it
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_ring_free(ring: Handle) -> i32 {
+    guard("ar_ring_free", -999, || {
+        record(RINGS.remove(ring)).map_or(-999, |ring| {
+            ring.freed.store(true, Ordering::Release);
+            RETIRED.lock().unwrap_or_else(|e| e.into_inner()).push(ring);
+            release_retired();
+            1
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_and_overrun() {
+        let ring = Ring::new(4, 2).unwrap();
+        let mut out = [0.0f32; 8];
+        assert_eq!(ring.write(&[1.0, 1.5, 2.0, 2.5, 3.0]).unwrap(), 2);
+        assert_eq!(ring.read(&mut out[..2]).unwrap(), 1);
+        assert_eq!(out[..2], [1.0, 1.5]);
+        // Wraps past the end; the fifth frame doesn't fit
+        assert_eq!(ring.write(&[3.0, 3.5, 4.0, 4.5, 5.0, 5.5, 6.0, 6.5]).unwrap(), 3);
+        assert_eq!(ring.available(), 4);
+        assert_eq!(ring.overruns(), 1);
+        assert_eq!(ring.read(&mut out).unwrap(), 4);
+        assert_eq!(out, [2.0, 2.5, 3.0, 3.5, 4.0, 4.5, 5.0, 5.5]);
+        assert_eq!(ring.read(&mut out).unwrap(), 0);
+
+        let _writing = ring.claim(&ring.writing, "writing to").unwrap();
+        assert!(matches!(ring.write(&[0.0, 0.0]), Err(AudioRemoteError::Refused(_))));
+        assert!(Ring::new(0, 2).is_err());
+        assert!(Ring::new(usize::MAX, 2).is_err());
+    }
+
+    #[test]
+    fn test_threads() {
+        let ring = Arc::new(Ring::new(64, 1).unwrap());
+        let total = 100_000;
+        let producer = {
+            let ring = ring.clone();
+            std::thread::spawn(move || {
+                let mut next = 0;
+                while next < total {
+                    let chunk: Vec<f32> = (next..(next + 17).min(total)).map(|i| i as f32).collect();
+                    next += ring.write(&chunk).unwrap();
+                }
+            })
+        };
+        let mut received = Vec::with_capacity(total);
+        let mut out = [0.0f32; 23];
+        while received.len() < total {
+            let frames = ring.read(&mut out).unwrap();
+            received.extend_from_slice(&out[..frames]);
+        }
+        producer.join().unwrap();
+        assert!(received.iter().enumerate().all(|(i, &sample)| sample == i as f32));
+    }
+
+    #[test]
+    fn test_ffi() {
+        let ring = ar_ring_new(8, 2);
+        assert_ne!(ring, 0);
+        let samples = [0.25f32; 20];
+        assert_eq!(unsafe { ar_ring_write(ring, samples.as_ptr(), 10) }, 8);
+        assert_eq!(ar_ring_available(ring), 8);
+        assert_eq!(ar_ring_overruns(ring), 2);
+        let mut out = [0.0f32; 6];
+        assert_eq!(unsafe { ar_ring_read(ring, out.as_mut_ptr(), 3) }, 3);
+        assert_eq!(out, [0.25; 6]);
+        assert_eq!(unsafe { ar_ring_read(ring, std::ptr::null_mut(), 1) }, -999);
+        let freed = Arc::downgrade(&RINGS.get(ring).unwrap());
+        assert_eq!(ar_ring_free(ring), 1);
+        // Still in this thread's caches, so it isn't dropped here yet
+        assert!(freed.upgrade().is_some());
+        // The cached lookups notice the ring is gone and let go of it
+        assert_eq!(unsafe { ar_ring_read(ring, out.as_mut_ptr(), 3) }, -999);
+        assert_eq!(unsafe { ar_ring_write(ring, samples.as_ptr(), 1) }, -999);
+        assert_eq!(ar_ring_available(ring), -999);
+        assert_eq!(ar_ring_new(8, 0), 0);
+        assert!(freed.upgrade().is_none());
+    }
+}
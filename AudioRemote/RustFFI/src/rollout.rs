@@ -0,0 +1,90 @@
+use std::ffi::c_char;
+
+use sha2::{Digest, Sha256};
+
+use crate::version::{self, ParseMode};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+/// Resolution of rollout buckets: 10_000 buckets = 0.01% steps
+const BUCKETS: u64 = 10_000;
+
+/// Deterministic bucket in 0..BUCKETS for a device and release.
+///
+/// The version is part of the key so each release samples a different cohort,
+/// while raising the percentage for one release only ever adds devices.
+pub fn bucket(device_id: &str, version: &str) -> u64 {
+    let version = version::parse(version, ParseMode::Lenient)
+        .map(|v| v.to_string())
+        .unwrap_or_else(|_| version.trim().to_owned());
+    let digest = Sha256::digest(format!("{}:{version}", device_id.trim()).as_bytes());
+    u64::from_be_bytes(digest[..8].try_into().unwrap()) % BUCKETS
+}
+
+/// Whether a device falls inside a staged rollout of `percent` (0-100)
+pub fn applies(device_id: &str, version: &str, percent: f64) -> bool {
+    if percent.is_nan() || percent <= 0.0 {
+        return false;
+    }
+    if percent >= 100.0 {
+        return true;
+    }
+    (bucket(device_id, version) as f64) < percent / 100.0 * BUCKETS as f64
+}
+
+/// Decide whether a staged rollout (e.g. 10.0 = 10%) includes this device
+/// The device id should be stable across launches (e.g. a UUID stored in defaults)
+/// Returns: 1 if the update should be offered, 0 if not, -999 on error (see last_error_message)
+///
+/// # Safety
+/// Both pointers must point to valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn rollout_applies(device_id_ptr: *const c_char, version_ptr: *const c_char, rollout_percent: f64) -> i32 {
+    guard("rollout_applies", -999, || {
+        let result = str_arg(device_id_ptr, "device_id").and_then(|device_id| {
+            let version = str_arg(version_ptr, "version")?;
+            if device_id.trim().is_empty() {
+                return Err(AudioRemoteError::InvalidArgument("device_id is empty".into()));
+            }
+            Ok(applies(device_id, version, rollout_percent))
+        });
+        match record(result) {
+            Some(applies) => applies as i32,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rollout_is_deterministic_and_monotonic() {
+        let devices: Vec<String> = (0..2000).map(|i| format!("device-{i}")).collect();
+        let share = |percent: f64| devices.iter().filter(|d| applies(d, "2.7.0", percent)).count();
+
+        assert_eq!(share(0.0), 0);
+        assert_eq!(share(100.0), devices.len());
+        let (ten, fifty) = (share(10.0), share(50.0));
+        assert!((150..250).contains(&ten), "10% rollout picked {ten}");
+        assert!((900..1100).contains(&fifty), "50% rollout picked {fifty}");
+
+        // Everyone in the 10% cohort stays in at 50%
+        for d in &devices {
+            if applies(d, "2.7.0", 10.0) {
+                assert!(applies(d, "2.7.0", 50.0));
+            }
+        }
+        assert_eq!(bucket("device-1", "v2.7.0"), bucket("device-1", "2.7.0"));
+    }
+
+    #[test]
+    fn test_rollout_applies_ffi() {
+        let device = std::ffi::CString::new("5F1C0B7E-UUID").unwrap();
+        let version = std::ffi::CString::new("2.7.0").unwrap();
+        assert_eq!(unsafe { rollout_applies(device.as_ptr(), version.as_ptr(), 100.0) }, 1);
+        assert_eq!(unsafe { rollout_applies(device.as_ptr(), version.as_ptr(), 0.0) }, 0);
+        let empty = std::ffi::CString::new(" ").unwrap();
+        assert_eq!(unsafe { rollout_applies(empty.as_ptr(), version.as_ptr(), 50.0) }, -999);
+    }
+}
@@ -0,0 +1,383 @@
+//! The REST API served by `server`. Every route maps to an endpoint that Swift
+//! implements: Rust validates the request, calls the handler Swift registered
+//! for the endpoint with the decoded parameters and a request handle, and waits
+//! for Swift to answer with `ar_server_respond` or `ar_server_reject` (from any
+//! thread, e.g. after hopping to the main queue). The answer is checked against
+//! the endpoint's result type before it's sent, so the JSON a remote sees is
+//! always encoded here.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_void, CString};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::auth;
+use crate::commands;
+use crate::handle::{Handle, Registry};
+use crate::openapi;
+use crate::pairing;
+use crate::remote::{NowPlaying, OutputDevice, SelectDevice, SetMute, SetVolume, VolumeState};
+use crate::runtime::{self, SendPtr};
+use crate::server::{Request, Response};
+use crate::sessions;
+use crate::ssdp;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+/// How long a request waits for Swift's answer
+const HANDLER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The operations remotes can ask for; values are part of the C ABI
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Endpoint {
+    GetVolume = 0,
+    SetVolume = 1,
+    SetMute = 2,
+    ListDevices = 3,
+    SelectDevice = 4,
+    NowPlaying = 5,
+}
+
+impl Endpoint {
+    pub fn from_raw(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Self::GetVolume),
+            1 => Some(Self::SetVolume),
+            2 => Some(Self::SetMute),
+            3 => Some(Self::ListDevices),
+            4 => Some(Self::SelectDevice),
+            5 => Some(Self::NowPlaying),
+            _ => None,
+        }
+    }
+
+    /// Decode and validate a request body into the parameters Swift receives
+    fn params(self, body: &[u8]) -> Result<Value, AudioRemoteError> {
+        match self {
+            Self::GetVolume | Self::ListDevices | Self::NowPlaying => Ok(Value::Object(Default::default())),
+            Self::SetVolume => {
+                let command: SetVolume = decode(body)?;
+                command.validate()?;
+                to_value(command)
+            }
+            Self::SetMute => to_value(decode::<SetMute>(body)?),
+            Self::SelectDevice => {
+                let command: SelectDevice = decode(body)?;
+                command.validate()?;
+                to_value(command)
+            }
+        }
+    }
+
+    /// Check Swift's result JSON against the endpoint's result type and re-encode it
+    fn result(self, json: &str) -> Result<Value, AudioRemoteError> {
+        let json = json.as_bytes();
+        match self {
+            Self::GetVolume | Self::SetVolume | Self::SetMute => to_value(decode::<VolumeState>(json)?),
+            Self::ListDevices => to_value(decode::<Vec<OutputDevice>>(json)?),
+            Self::SelectDevice => to_value(decode::<OutputDevice>(json)?),
+            Self::NowPlaying => to_value(decode::<Option<NowPlaying>>(json)?),
+        }
+    }
+}
+
+fn decode<T: DeserializeOwned>(json: &[u8]) -> Result<T, AudioRemoteError> {
+    serde_json::from_slice(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid JSON: {e}")))
+}
+
+fn to_value(value: impl Serialize) -> Result<Value, AudioRemoteError> {
+    serde_json::to_value(value).map_err(|e| AudioRemoteError::Other(e.to_string()))
+}
+
+pub struct Route {
+    pub method: &'static str,
+    pub path: &'static str,
+    pub endpoint: Endpoint,
+    pub summary: &'static str,
+}
+
+pub const ROUTES: &[Route] = &[
+    Route { method: "GET", path: "/api/v1/volume", endpoint: Endpoint::GetVolume, summary: "Output volume and mute state" },
+    Route { method: "PUT", path: "/api/v1/volume", endpoint: Endpoint::SetVolume, summary: "Set the output volume (0.0–1.0)" },
+    Route { method: "PUT", path: "/api/v1/mute", endpoint: Endpoint::SetMute, summary: "Mute or unmute the output" },
+    Route { method: "GET", path: "/api/v1/devices", endpoint: Endpoint::ListDevices, summary: "Output devices" },
+    Route {
+        method: "PUT",
+        path: "/api/v1/devices/current",
+        endpoint: Endpoint::SelectDevice,
+        summary: "Switch the output device",
+    },
+    Route { method: "GET", path: "/api/v1/now-playing", endpoint: Endpoint::NowPlaying, summary: "What's playing, or null" },
+];
+
+/// A small control page for phone browsers, built on the API above
+const REMOTE_PAGE: &str = include_str!("remote.html");
+
+/// Called with a request handle, the endpoint and its validated parameters as JSON
+/// (only valid during the call). Answer with ar_server_respond or ar_server_reject.
+pub type ServerHandler = extern "C" fn(request: Handle, endpoint: i32, params_json: *const c_char, ctx: *mut c_void);
+
+static HANDLERS: Mutex<BTreeMap<Endpoint, (ServerHandler, SendPtr)>> = Mutex::new(BTreeMap::new());
+
+/// Swift's answer: a result to encode, or an HTTP error status and message
+pub(crate) type Reply = Result<Value, (u16, String)>;
+
+struct Pending {
+    endpoint: Endpoint,
+    reply: Mutex<Option<SyncSender<Reply>>>,
+}
+
+static PENDING: Registry<Pending> = Registry::new("server request");
+
+/// Route one request; never fails, errors become error responses
+pub(crate) fn handle(request: &Request) -> Response {
+    if request.method == "OPTIONS" {
+        return Response::new(204)
+            .with_header("Access-Control-Allow-Methods", "GET, PUT, POST, OPTIONS")
+            .with_header("Access-Control-Allow-Headers", "Content-Type, Authorization, X-Command-Id")
+            .with_header("Access-Control-Max-Age", "600");
+    }
+    if request.path == "/" && request.method == "GET" {
+        return Response::html(REMOTE_PAGE);
+    }
+    let json = request.header("content-type").and_then(|value| value.split(';').next()).is_some_and(|value| value.trim().eq_ignore_ascii_case("application/json"));
+    if request.method == "POST" && request.path.starts_with("/api/") && !json {
+        return Response::error(415, "send the request body as application/json");
+    }
+    if let Some(response) = pairing::route(request).or_else(|| auth::route(request)).or_else(|| openapi::route(request)).or_else(|| ssdp::route(request)) {
+        return response;
+    }
+    let matching: Vec<&Route> = ROUTES.iter().filter(|route| route.path == request.path).collect();
+    let Some(route) = matching.iter().find(|route| route.method == request.method) else {
+        return match matching.is_empty() {
+            true => Response::error(404, format!("no such path {}", request.path)),
+            false => {
+                let allowed: Vec<&str> = matching.iter().map(|route| route.method).collect();
+                Response::error(405, format!("{} isn't allowed here", request.method)).with_header("Allow", allowed.join(", "))
+            }
+        };
+    };
+    if let Err(response) = sessions::permit(request, route.endpoint) {
+        return response;
+    }
+    // Reads are safe to repeat; only changes go through the command log
+    let command = match route.method {
+        "GET" => None,
+        _ => match commands::begin(request) {
+            Ok(command) => command,
+            Err(response) => return response,
+        },
+    };
+    let response = match perform(route.endpoint, &request.body) {
+        Ok(result) => Response::json(200, &result),
+        Err((status, message)) => Response::error(status, message),
+    };
+    commands::finish(command, &response);
+    response
+}
+
+/// Validate a request body for `endpoint` and have Swift carry it out; shared with
+/// transports other than HTTP (see `mqtt`)
+pub(crate) fn perform(endpoint: Endpoint, body: &[u8]) -> Reply {
+    let params = endpoint.params(body).map_err(|e| (400, e.to_string()))?;
+    call_handler(endpoint, &params)
+}
+
+fn call_handler(endpoint: Endpoint, params: &Value) -> Reply {
+    let Some((handler, ctx)) = HANDLERS.lock().unwrap_or_else(|e| e.into_inner()).get(&endpoint).copied() else {
+        return Err((501, "this Mac doesn't support that yet".into()));
+    };
+    let (sender, receiver) = mpsc::sync_channel(1);
+    let request = PENDING.insert(Pending { endpoint, reply: Mutex::new(Some(sender)) });
+    let params = CString::new(params.to_string()).unwrap_or_default();
+    runtime::deliver(move || handler(request, endpoint as i32, params.as_ptr(), ctx.get()));
+    let reply = receiver.recv_timeout(HANDLER_TIMEOUT);
+    let _ = PENDING.remove(request);
+    reply.unwrap_or_else(|_| {
+        log::warn!("no answer to {endpoint:?} request {request} within {HANDLER_TIMEOUT:?}");
+        Err((504, "the Mac didn't answer in time".into()))
+    })
+}
+
+fn answer(request: Handle, reply: impl FnOnce(Endpoint) -> Reply) -> Result<(), AudioRemoteError> {
+    let pending = PENDING.remove(request)?;
+    let sender = pending.reply.lock().unwrap_or_else(|e| e.into_inner()).take();
+    let reply = reply(pending.endpoint);
+    if let Some(sender) = sender {
+        let _ = sender.send(reply);
+    }
+    Ok(())
+}
+
+/// Answer every request still waiting on Swift, when the server stops
+pub(crate) fn cancel_pending() {
+    for pending in PENDING.all() {
+        if let Some(sender) = pending.reply.lock().unwrap_or_else(|e| e.into_inner()).take() {
+            let _ = sender.send(Err((503, "the server is stopping".into())));
+        }
+    }
+}
+
+/// Register the handler for an endpoint (an ArServerEndpoint), replacing any previous one;
+/// NULL removes it, and its routes answer 501. Handlers run on the callback queue set with
+/// ar_runtime_set_callback_queue, or on the connection's thread if none is set.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `ctx` is passed back to `handler` from other threads; it must stay valid until replaced.
+#[no_mangle]
+pub unsafe extern "C" fn ar_server_set_handler(endpoint: i32, handler: Option<ServerHandler>, ctx: *mut c_void) -> i32 {
+    guard("ar_server_set_handler", -999, || {
+        let endpoint = Endpoint::from_raw(endpoint).ok_or(AudioRemoteError::InvalidArgument(format!("unknown endpoint {endpoint}")));
+        let Some(endpoint) = record(endpoint) else {
+            return -999;
+        };
+        let mut handlers = HANDLERS.lock().unwrap_or_else(|e| e.into_inner());
+        match handler {
+            Some(handler) => handlers.insert(endpoint, (handler, SendPtr(ctx))),
+            None => handlers.remove(&endpoint),
+        };
+        1
+    })
+}
+
+/// Answer a request with its result: VolumeState for the volume and mute endpoints, an array of
+/// OutputDevice for ListDevices, the selected OutputDevice for SelectDevice, and NowPlaying or
+/// null for NowPlaying (see the API description for the fields). A result that doesn't match is
+/// answered with 500 and reported here.
+/// Returns: 1 on success, -999 on error, e.g. the request already timed out (see last_error_message)
+///
+/// # Safety
+/// `result_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_server_respond(request: Handle, result_json: *const c_char) -> i32 {
+    guard("ar_server_respond", -999, || {
+        let mut outcome = Ok(());
+        let answered = answer(request, |endpoint| match str_arg(result_json, "result").and_then(|json| endpoint.result(json)) {
+            Ok(result) => Ok(result),
+            Err(e) => {
+                log::error!("bad result for {endpoint:?}: {e}");
+                let reply = Err((500, e.to_string()));
+                outcome = Err(e);
+                reply
+            }
+        });
+        match record(answered.and(outcome)) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Answer a request with an error: `status` is an HTTP status 400–599 (e.g. 404 for an unknown
+/// device, 503 if audio is unavailable) and `message` (nullable) is shown to the user.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `message` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_server_reject(request: Handle, status: i32, message: *const c_char) -> i32 {
+    guard("ar_server_reject", -999, || {
+        let result = match u16::try_from(status) {
+            Ok(status @ 400..=599) => {
+                let message = match message.is_null() {
+                    true => Ok(crate::server::reason(status).to_owned()),
+                    false => str_arg(message, "message").map(str::to_owned),
+                };
+                message.and_then(|message| answer(request, |_| Err((status, message))))
+            }
+            _ => Err(AudioRemoteError::InvalidArgument(format!("status {status} isn't an HTTP error status"))),
+        };
+        match record(result) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::exchange;
+    use crate::server::{self, ServerConfig};
+    use std::ffi::CStr;
+
+    extern "C" fn audio(request: Handle, endpoint: i32, params: *const c_char, _ctx: *mut c_void) {
+        let params: Value = serde_json::from_str(unsafe { CStr::from_ptr(params) }.to_str().unwrap()).unwrap();
+        let answer = |json: &str| {
+            let json = CString::new(json).unwrap();
+            assert_eq!(unsafe { ar_server_respond(request, json.as_ptr()) }, 1);
+        };
+        match Endpoint::from_raw(endpoint).unwrap() {
+            Endpoint::GetVolume => answer(r#"{"volume": 0.5, "muted": false}"#),
+            Endpoint::SetVolume => answer(&format!(r#"{{"volume": {}, "muted": false}}"#, params["volume"])),
+            Endpoint::SelectDevice => {
+                let message = CString::new("no such device").unwrap();
+                assert_eq!(unsafe { ar_server_reject(request, 404, message.as_ptr()) }, 1);
+            }
+            Endpoint::NowPlaying => {
+                let wrong = CString::new(r#"{"title": 1}"#).unwrap();
+                assert_eq!(unsafe { ar_server_respond(request, wrong.as_ptr()) }, -999);
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_routes_over_tcp() {
+        // ar_shutdown in the lifecycle test stops the server too
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        for endpoint in [Endpoint::GetVolume, Endpoint::SetVolume, Endpoint::SelectDevice, Endpoint::NowPlaying] {
+            assert_eq!(unsafe { ar_server_set_handler(endpoint as i32, Some(audio), std::ptr::null_mut()) }, 1);
+        }
+        assert_eq!(unsafe { ar_server_set_handler(99, Some(audio), std::ptr::null_mut()) }, -999);
+        let address = server::start(&ServerConfig { port: 0, bind_address: "127.0.0.1".parse().unwrap(), require_auth: false, ..ServerConfig::default() }).unwrap();
+        assert!(server::start(&ServerConfig::default()).is_err());
+        assert_eq!(server::ar_server_port(), i32::from(address.port()));
+        let send = |method: &str, path: &str, body: &str| {
+            let request = format!("{method} {path} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{body}", body.len());
+            exchange(address, &request)
+        };
+
+        assert_eq!(send("GET", "/api/v1/volume", ""), (200, r#"{"muted":false,"volume":0.5}"#.into()));
+        assert_eq!(send("PUT", "/api/v1/volume", r#"{"volume":0.25}"#), (200, r#"{"muted":false,"volume":0.25}"#.into()));
+        let (status, body) = send("PUT", "/api/v1/volume", r#"{"volume":3}"#);
+        assert_eq!(status, 400);
+        assert!(body.contains("outside 0.0"), "{body}");
+        assert_eq!(send("PUT", "/api/v1/devices/current", r#"{"id":"x"}"#).0, 404);
+        assert_eq!(send("GET", "/api/v1/now-playing", "").0, 500);
+        assert_eq!(send("GET", "/api/v1/devices", "").0, 501);
+        assert_eq!(send("DELETE", "/api/v1/volume", "").0, 405);
+        assert_eq!(send("GET", "/nope", "").0, 404);
+        assert_eq!(send("OPTIONS", "/api/v1/volume", "").0, 204);
+        assert!(send("GET", "/", "").1.contains("<html"));
+        assert!(send("GET", "/openapi.json", "").1.starts_with(r#"{"components":"#));
+        assert_eq!(unsafe { ar_server_respond(12345, c"{}".as_ptr()) }, -999);
+
+        // Only the server's own page gets CORS access, and a form-style POST from elsewhere is refused
+        let head = |origin: &str| {
+            use std::io::{Read, Write};
+            let mut stream = std::net::TcpStream::connect(address).unwrap();
+            let request = format!("GET /api/v1/volume HTTP/1.1\r\nHost: {address}\r\nOrigin: {origin}\r\nConnection: close\r\n\r\n");
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        };
+        assert!(head(&format!("http://{address}")).contains(&format!("Access-Control-Allow-Origin: http://{address}\r\n")));
+        assert!(!head("http://evil.example").contains("Access-Control-Allow-Origin"));
+        let form = "POST /api/v1/auth/refresh HTTP/1.1\r\nContent-Type: text/plain\r\nConnection: close\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(exchange(address, form).0, 415);
+        let json = "POST /api/v1/auth/refresh HTTP/1.1\r\nContent-Type: application/json; charset=utf-8\r\nConnection: close\r\nContent-Length: 2\r\n\r\n{}";
+        assert_eq!(exchange(address, json).0, 400);
+
+        server::stop();
+        assert_eq!(server::ar_server_port(), 0);
+        assert!(std::net::TcpStream::connect(address).is_err());
+    }
+}
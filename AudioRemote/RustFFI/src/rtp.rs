@@ -0,0 +1,633 @@
+//! RTP (RFC 3550) for streamed audio: the Mac wraps each encoded packet with a
+//! sequence number and a timestamp in samples, and the remote puts what
+//! arrives through a jitter buffer before decoding, so Wi-Fi's bursty delivery
+//! plays out evenly.
+//!
+//! The jitter buffer is pulled once per packet duration by the playout side.
+//! It holds packets back until it has `target` ms buffered, then plays them in
+//! sequence order; a packet that isn't there when it's due is reported lost
+//! (conceal it, e.g. ar_opus_decode with NULL) and dropped if it turns up
+//! later. The target follows the measured interarrival jitter, between
+//! `minDelayMs` and `maxDelayMs`. Running dry goes back to buffering, up to
+//! the target as it is then, so a worse network buys more delay; when the
+//! buffer runs well over the target a packet is skipped to bring the latency
+//! back down.
+
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{bytes_result, string_result};
+use crate::handle::{Handle, Registry};
+use crate::pairing::random_bytes;
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const HEADER_LEN: usize = 12;
+const VERSION: u8 = 2;
+/// Packets further ahead of playout than this mean the sender restarted its sequence
+const MAX_SEQUENCE_JUMP: i64 = 3000;
+
+/// The fixed RTP header; CSRCs and extensions are skipped when parsing and never written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtpHeader {
+    pub marker: bool,
+    pub payload_type: u8,
+    pub sequence: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+}
+
+impl RtpHeader {
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.push(VERSION << 6);
+        out.push(((self.marker as u8) << 7) | (self.payload_type & 0x7f));
+        out.extend_from_slice(&self.sequence.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.ssrc.to_be_bytes());
+    }
+
+    /// The header and payload of an RTP packet
+    pub fn parse(packet: &[u8]) -> Result<(Self, &[u8]), AudioRemoteError> {
+        let invalid = |message: &str| Err(AudioRemoteError::InvalidData(format!("invalid RTP packet: {message}")));
+        if packet.len() < HEADER_LEN {
+            return invalid("shorter than the header");
+        }
+        if packet[0] >> 6 != VERSION {
+            return invalid("not version 2");
+        }
+        let word = |at: usize| u32::from_be_bytes([packet[at], packet[at + 1], packet[at + 2], packet[at + 3]]);
+        let header = Self {
+            marker: packet[1] & 0x80 != 0,
+            payload_type: packet[1] & 0x7f,
+            sequence: u16::from_be_bytes([packet[2], packet[3]]),
+            timestamp: word(4),
+            ssrc: word(8),
+        };
+        let mut start = HEADER_LEN + 4 * (packet[0] & 0x0f) as usize;
+        if packet[0] & 0x10 != 0 && packet.len() >= start + 4 {
+            start += 4 + 4 * u16::from_be_bytes([packet[start + 2], packet[start + 3]]) as usize;
+        }
+        let padding = match packet[0] & 0x20 != 0 {
+            true => *packet.last().unwrap_or(&0) as usize,
+            false => 0,
+        };
+        match packet.len().checked_sub(padding) {
+            Some(end) if end >= start => Ok((header, &packet[start..end])),
+            _ => invalid("the header runs past the end"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PacketizerConfig {
+    /// 96–127 are for dynamic payloads; 111 is what most stacks use for Opus
+    #[serde(default = "default_payload_type")]
+    pub payload_type: u8,
+    /// Random when not given
+    #[serde(default)]
+    pub ssrc: Option<u32>,
+}
+
+fn default_payload_type() -> u8 {
+    111
+}
+
+impl Default for PacketizerConfig {
+    fn default() -> Self {
+        Self { payload_type: default_payload_type(), ssrc: None }
+    }
+}
+
+pub struct Packetizer {
+    payload_type: u8,
+    ssrc: u32,
+    sequence: u16,
+    timestamp: u32,
+    first: bool,
+}
+
+impl Packetizer {
+    /// The sequence number and timestamp start at random, as RFC 3550 asks
+    pub fn new(config: &PacketizerConfig) -> Result<Self, AudioRemoteError> {
+        if config.payload_type > 127 {
+            return Err(AudioRemoteError::InvalidArgument(format!("payloadType {} is over 127", config.payload_type)));
+        }
+        let random: [u8; 10] = random_bytes()?;
+        Ok(Self {
+            payload_type: config.payload_type,
+            ssrc: config.ssrc.unwrap_or(u32::from_be_bytes([random[0], random[1], random[2], random[3]])),
+            sequence: u16::from_be_bytes([random[4], random[5]]),
+            timestamp: u32::from_be_bytes([random[6], random[7], random[8], random[9]]),
+            first: true,
+        })
+    }
+
+    /// Wrap a payload carrying `frames` samples per channel; the marker is set on the first packet
+    pub fn packetize(&mut self, payload: &[u8], frames: u32) -> Vec<u8> {
+        let header = RtpHeader { marker: self.first, payload_type: self.payload_type, sequence: self.sequence, timestamp: self.timestamp, ssrc: self.ssrc };
+        let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+        header.write(&mut packet);
+        packet.extend_from_slice(payload);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(frames);
+        self.first = false;
+        packet
+    }
+
+    /// The sequence number and timestamp the next packet will carry
+    pub fn next(&self) -> (u16, u32) {
+        (self.sequence, self.timestamp)
+    }
+
+    /// Mark the next packet again, e.g. after a flush
+    pub fn restart(&mut self) {
+        self.first = true;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct JitterConfig {
+    /// The RTP timestamp rate, the audio's sample rate
+    #[serde(default = "default_clock_rate")]
+    pub clock_rate: u32,
+    #[serde(default = "default_min_delay_ms")]
+    pub min_delay_ms: f64,
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: f64,
+}
+
+fn default_clock_rate() -> u32 {
+    48_000
+}
+
+fn default_min_delay_ms() -> f64 {
+    20.0
+}
+
+/// Past this, a remote is better off with gaps than with lag
+fn default_max_delay_ms() -> f64 {
+    200.0
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        Self { clock_rate: default_clock_rate(), min_delay_ms: default_min_delay_ms(), max_delay_ms: default_max_delay_ms() }
+    }
+}
+
+/// What the playout side gets for one packet duration
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Playout {
+    /// Nothing to play yet: output silence
+    Buffering,
+    Packet(Vec<u8>),
+    /// The packet due now is missing: conceal one packet
+    Lost,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JitterStats {
+    pub received: u64,
+    pub lost: u64,
+    /// Arrived after they were due
+    pub late: u64,
+    pub duplicates: u64,
+    /// Skipped to bring the latency down
+    pub skipped: u64,
+    pub underruns: u64,
+    pub jitter_ms: f64,
+    pub target_ms: f64,
+    pub buffered_ms: f64,
+}
+
+pub struct JitterBuffer {
+    config: JitterConfig,
+    ssrc: Option<u32>,
+    /// By extended sequence number: (timestamp, payload)
+    packets: BTreeMap<i64, (u32, Vec<u8>)>,
+    /// The next sequence number to play, once one has arrived
+    next: Option<i64>,
+    playing: bool,
+    /// Timestamp ticks per packet, once two consecutive packets have arrived
+    packet_ticks: u32,
+    /// The RFC 3550 interarrival jitter estimate, in ticks
+    jitter: f64,
+    /// (arrival in ticks, timestamp) of the last packet
+    last_arrival: Option<(f64, u32)>,
+    epoch: Instant,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new(config: &JitterConfig) -> Result<Self, AudioRemoteError> {
+        if !(8000..=384_000).contains(&config.clock_rate) {
+            return Err(AudioRemoteError::InvalidArgument(format!("clockRate {} is outside 8000–384000", config.clock_rate)));
+        }
+        if !(0.0..=config.max_delay_ms).contains(&config.min_delay_ms) || config.max_delay_ms > 5000.0 {
+            return Err(AudioRemoteError::InvalidArgument("minDelayMs and maxDelayMs must be 0 ≤ min ≤ max ≤ 5000".into()));
+        }
+        Ok(Self {
+            config: config.clone(),
+            ssrc: None,
+            packets: BTreeMap::new(),
+            next: None,
+            playing: false,
+            packet_ticks: 0,
+            jitter: 0.0,
+            last_arrival: None,
+            epoch: Instant::now(),
+            stats: JitterStats::default(),
+        })
+    }
+
+    fn ms(&self, ticks: f64) -> f64 {
+        ticks * 1000.0 / f64::from(self.config.clock_rate)
+    }
+
+    /// How long to hold back: a packet plus four times the jitter, within the configured bounds
+    pub fn target_ms(&self) -> f64 {
+        (self.ms(f64::from(self.packet_ticks) + 4.0 * self.jitter)).clamp(self.config.min_delay_ms, self.config.max_delay_ms)
+    }
+
+    /// From the next packet due to the newest one held
+    pub fn buffered_ms(&self) -> f64 {
+        match (self.next, self.packets.last_key_value()) {
+            (Some(next), Some((&newest, _))) => self.ms(((newest - next + 1) * i64::from(self.packet_ticks)) as f64),
+            _ => 0.0,
+        }
+    }
+
+    /// Start over for a new stream, keeping the counters
+    pub fn reset(&mut self) {
+        self.packets.clear();
+        (self.ssrc, self.next, self.playing, self.packet_ticks, self.jitter, self.last_arrival) = (None, None, false, 0, 0.0, None);
+    }
+
+    /// Take in a packet that arrived at `now`. Returns false if it was dropped as late or a duplicate.
+    pub fn push(&mut self, packet: &[u8], now: Instant) -> Result<bool, AudioRemoteError> {
+        let (header, payload) = RtpHeader::parse(packet)?;
+        if self.ssrc.is_some_and(|ssrc| ssrc != header.ssrc) {
+            self.reset();
+        }
+        self.ssrc = Some(header.ssrc);
+        let reference = self.packets.last_key_value().map(|(&newest, _)| newest).or(self.next).unwrap_or(i64::from(header.sequence));
+        // The sequence number nearest the reference, so wrapping past 65535 keeps counting up
+        let sequence = reference + i64::from(header.sequence.wrapping_sub(reference as u16) as i16);
+        let next = *self.next.get_or_insert(sequence);
+        if sequence < next {
+            self.stats.late += 1;
+            return Ok(false);
+        }
+        if sequence - next > MAX_SEQUENCE_JUMP {
+            self.reset();
+            return self.push(packet, now);
+        }
+        if self.packets.contains_key(&sequence) {
+            self.stats.duplicates += 1;
+            return Ok(false);
+        }
+
+        let arrival = now.saturating_duration_since(self.epoch).as_secs_f64() * f64::from(self.config.clock_rate);
+        if let Some((last_arrival, last_timestamp)) = self.last_arrival {
+            let difference = (arrival - last_arrival) - f64::from(header.timestamp.wrapping_sub(last_timestamp) as i32);
+            self.jitter += (difference.abs() - self.jitter) / 16.0;
+        }
+        self.last_arrival = Some((arrival, header.timestamp));
+        let neighbour = |sequence: i64| self.packets.get(&sequence).map(|(timestamp, _)| *timestamp);
+        let ticks = match (neighbour(sequence - 1), neighbour(sequence + 1)) {
+            (Some(before), _) => header.timestamp.wrapping_sub(before),
+            (None, Some(after)) => after.wrapping_sub(header.timestamp),
+            (None, None) => self.packet_ticks,
+        };
+        if (1..self.config.clock_rate).contains(&ticks) {
+            self.packet_ticks = ticks;
+        }
+        self.packets.insert(sequence, (header.timestamp, payload.to_vec()));
+        self.stats.received += 1;
+        Ok(true)
+    }
+
+    /// What to play for the next packet duration
+    pub fn pop(&mut self) -> Playout {
+        let Some(next) = self.next else {
+            return Playout::Buffering;
+        };
+        if !self.playing {
+            if self.packets.is_empty() || self.packet_ticks == 0 || self.buffered_ms() < self.target_ms() {
+                return Playout::Buffering;
+            }
+            self.playing = true;
+            // Start from the first packet held, not one that never came
+            self.next = self.packets.first_key_value().map(|(&first, _)| first);
+            return self.pop();
+        }
+        if self.packets.is_empty() {
+            self.stats.underruns += 1;
+            self.playing = false;
+            return Playout::Buffering;
+        }
+        let target = self.target_ms();
+        let mut next = next;
+        if self.buffered_ms() > target + target.max(self.ms(f64::from(self.packet_ticks))) {
+            if self.packets.remove(&next).is_some() {
+                self.stats.skipped += 1;
+            }
+            next += 1;
+        }
+        self.next = Some(next + 1);
+        match self.packets.remove(&next) {
+            Some((_, payload)) => Playout::Packet(payload),
+            None => {
+                self.stats.lost += 1;
+                Playout::Lost
+            }
+        }
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        JitterStats { jitter_ms: self.ms(self.jitter), target_ms: self.target_ms(), buffered_ms: self.buffered_ms(), ..self.stats.clone() }
+    }
+}
+
+/// Values are part of the C ABI (ArJitterPlayout)
+fn playout_code(playout: &Playout) -> i32 {
+    match playout {
+        Playout::Buffering => 0,
+        Playout::Packet(_) => 1,
+        Playout::Lost => 2,
+    }
+}
+
+static PACKETIZERS: Registry<Mutex<Packetizer>> = Registry::new("RTP packetizer");
+static JITTER_BUFFERS: Registry<Mutex<JitterBuffer>> = Registry::new("jitter buffer");
+
+fn with_packetizer<T>(handle: Handle, body: impl FnOnce(&mut Packetizer) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let packetizer = PACKETIZERS.get(handle)?;
+    let mut packetizer = packetizer.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut packetizer)
+}
+
+fn with_jitter<T>(handle: Handle, body: impl FnOnce(&mut JitterBuffer) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let jitter = JITTER_BUFFERS.get(handle)?;
+    let mut jitter = jitter.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut jitter)
+}
+
+unsafe fn config_arg<T: serde::de::DeserializeOwned + Default>(config_json: *const c_char) -> Result<T, AudioRemoteError> {
+    match config_json.is_null() {
+        true => Ok(T::default()),
+        false => serde_json::from_str(str_arg(config_json, "config")?)
+            .map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid RTP config: {e}"))),
+    }
+}
+
+/// Create a packetizer for one outgoing stream. `config_json` (nullable): {"payloadType" (0–127,
+/// default 111), "ssrc" (default random)}
+/// Returns: a handle (free with ar_rtp_packetizer_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_rtp_packetizer_new(config_json: *const c_char) -> Handle {
+    guard("ar_rtp_packetizer_new", 0, || match record(config_arg(config_json).and_then(|config| Packetizer::new(&config))) {
+        Some(packetizer) => PACKETIZERS.insert(Mutex::new(packetizer)),
+        None => 0,
+    })
+}
+
+/// Wrap an encoded packet of `len` bytes, carrying `frames` samples per channel, in an RTP header
+/// Returns: the RTP packet (free with ar_bytes_free, passing `out_len`), NULL on error (see last_error_message)
+///
+/// # Safety
+/// `payload` must point to `len` bytes and `out_len` to a writable size_t.
+#[no_mangle]
+pub unsafe extern "C" fn ar_rtp_packetize(packetizer: Handle, payload: *const u8, len: usize, frames: u32, out_len: *mut usize) -> *mut u8 {
+    guard("ar_rtp_packetize", std::ptr::null_mut(), || {
+        let result = with_packetizer(packetizer, |packetizer| match payload.is_null() {
+            true => Err(AudioRemoteError::InvalidArgument("payload must not be null".into())),
+            false => Ok(packetizer.packetize(std::slice::from_raw_parts(payload, len), frames)),
+        });
+        bytes_result(result, out_len)
+    })
+}
+
+/// Release a packetizer; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_rtp_packetizer_free(packetizer: Handle) -> i32 {
+    guard("ar_rtp_packetizer_free", -999, || record(PACKETIZERS.remove(packetizer)).map_or(-999, |_| 1))
+}
+
+/// Create a jitter buffer for one incoming stream. `config_json` (nullable): {"clockRate" (the
+/// sample rate, default 48000), "minDelayMs" (default 20), "maxDelayMs" (default 200)}
+/// Returns: a handle (free with ar_jitter_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_jitter_new(config_json: *const c_char) -> Handle {
+    guard("ar_jitter_new", 0, || match record(config_arg(config_json).and_then(|config| JitterBuffer::new(&config))) {
+        Some(jitter) => JITTER_BUFFERS.insert(Mutex::new(jitter)),
+        None => 0,
+    })
+}
+
+/// Take in an RTP packet of `len` bytes as it arrives; a new SSRC starts the buffer over
+/// Returns: 1 if it was kept, 0 if dropped as late or a duplicate, -999 if it isn't RTP or on
+/// error (see last_error_message)
+///
+/// # Safety
+/// `packet` must point to `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ar_jitter_push(jitter: Handle, packet: *const u8, len: usize) -> i32 {
+    guard("ar_jitter_push", -999, || {
+        let result = with_jitter(jitter, |jitter| match packet.is_null() {
+            true => Err(AudioRemoteError::InvalidArgument("packet must not be null".into())),
+            false => jitter.push(std::slice::from_raw_parts(packet, len), Instant::now()),
+        });
+        record(result).map_or(-999, i32::from)
+    })
+}
+
+/// Call once per packet duration: for AR_JITTER_PACKET the payload is copied into `out`, which
+/// has room for `capacity` bytes, and its length stored in `out_len`
+/// Returns: the ArJitterPlayout, -999 on error, e.g. a payload larger than `capacity`, which is
+/// kept for the next call (see last_error_message)
+///
+/// # Safety
+/// `out` must point to `capacity` writable bytes and `out_len` to a writable size_t.
+#[no_mangle]
+pub unsafe extern "C" fn ar_jitter_pop(jitter: Handle, out: *mut u8, capacity: usize, out_len: *mut usize) -> i32 {
+    guard("ar_jitter_pop", -999, || {
+        let result = with_jitter(jitter, |jitter| {
+            if out.is_null() || out_len.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("out and out_len must not be null".into()));
+            }
+            let pending = jitter.next.and_then(|next| jitter.packets.get(&next)).map_or(0, |(_, payload)| payload.len());
+            if pending > capacity {
+                return Err(AudioRemoteError::InvalidArgument(format!("the next payload is {pending} bytes, more than {capacity}")));
+            }
+            let playout = jitter.pop();
+            *out_len = 0;
+            if let Playout::Packet(payload) = &playout {
+                std::ptr::copy_nonoverlapping(payload.as_ptr(), out, payload.len());
+                *out_len = payload.len();
+            }
+            Ok(playout_code(&playout))
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Returns: JSON {"received", "lost", "late", "duplicates", "skipped", "underruns", "jitterMs",
+/// "targetMs", "bufferedMs"} (free with rust_string_free), NULL on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_jitter_stats(jitter: Handle) -> *mut c_char {
+    guard("ar_jitter_stats", std::ptr::null_mut(), || {
+        string_result(with_jitter(jitter, |jitter| serde_json::to_string(&jitter.stats()).map_err(|e| AudioRemoteError::Other(e.to_string()))))
+    })
+}
+
+/// Drop what's buffered and start over, e.g. when the remote reconnects
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_jitter_reset(jitter: Handle) -> i32 {
+    guard("ar_jitter_reset", -999, || {
+        record(with_jitter(jitter, |jitter| {
+            jitter.reset();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Release a jitter buffer; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_jitter_free(jitter: Handle) -> i32 {
+    guard("ar_jitter_free", -999, || record(JITTER_BUFFERS.remove(jitter)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// 10 ms packets at 48 kHz, sequence numbers about to wrap
+    fn packets(count: u16) -> Vec<Vec<u8>> {
+        let mut packetizer = Packetizer::new(&PacketizerConfig { ssrc: Some(7), ..PacketizerConfig::default() }).unwrap();
+        packetizer.sequence = 65_530;
+        (0..count).map(|i| packetizer.packetize(&i.to_be_bytes(), 480)).collect()
+    }
+
+    fn payload(playout: &Playout) -> Option<u16> {
+        match playout {
+            Playout::Packet(payload) => Some(u16::from_be_bytes([payload[0], payload[1]])),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_packets() {
+        let packets = packets(2);
+        let (header, payload) = RtpHeader::parse(&packets[0]).unwrap();
+        assert!(header.marker);
+        assert_eq!((header.payload_type, header.sequence, header.ssrc), (111, 65_530, 7));
+        assert_eq!(payload, [0, 0]);
+        let (second, _) = RtpHeader::parse(&packets[1]).unwrap();
+        assert!(!second.marker);
+        assert_eq!(second.timestamp.wrapping_sub(header.timestamp), 480);
+
+        // A CSRC, an extension and padding are skipped
+        let packet = vec![0xb1, 96, 0, 1, 0, 0, 0, 9, 0, 0, 0, 5, 1, 2, 3, 4, 0xbe, 0xde, 0, 1, 9, 9, 9, 9, 0xaa, 0, 2];
+        assert_eq!(RtpHeader::parse(&packet).unwrap().1, [0xaa]);
+        assert!(RtpHeader::parse(&packet[..8]).is_err());
+        assert!(RtpHeader::parse(&[0x40; 12]).is_err());
+    }
+
+    #[test]
+    fn test_reordering_loss_and_wrap() {
+        let config = JitterConfig { min_delay_ms: 30.0, ..JitterConfig::default() };
+        let mut jitter = JitterBuffer::new(&config).unwrap();
+        let start = Instant::now();
+        let packets = packets(12);
+        // One arrival and one playout every 10 ms: 3 and 2 swap, 4 comes twice, 5 never arrives
+        let mut played = vec![];
+        for (i, &index) in [0, 1, 3, 2, 4, 4, 6, 7, 8, 9, 10, 11].iter().enumerate() {
+            let kept = jitter.push(&packets[index], start + Duration::from_millis(10 * i as u64)).unwrap();
+            assert_eq!(kept, i != 5, "{i}");
+            played.push(jitter.pop());
+        }
+        played.extend((0..3).map(|_| jitter.pop()));
+        assert_eq!(played[..2], [Playout::Buffering, Playout::Buffering]);
+        assert_eq!(played[2..7].iter().map(payload).collect::<Vec<_>>(), [0, 1, 2, 3, 4].map(Some));
+        assert_eq!(played[7], Playout::Lost);
+        assert_eq!(played[8..14].iter().map(payload).collect::<Vec<_>>(), (6..12).map(Some).collect::<Vec<_>>());
+        assert_eq!(played[14], Playout::Buffering);
+        // Too late to play
+        assert!(!jitter.push(&packets[5], start + Duration::from_millis(200)).unwrap());
+        let stats = jitter.stats();
+        assert_eq!((stats.received, stats.lost, stats.late, stats.duplicates, stats.underruns, stats.skipped), (11, 1, 1, 1, 1, 0));
+    }
+
+    #[test]
+    fn test_adapts_to_jitter() {
+        let mut jitter = JitterBuffer::new(&JitterConfig::default()).unwrap();
+        let start = Instant::now();
+        let packets = packets(400);
+        // Steady arrivals keep the minimum delay
+        for (i, packet) in packets[..100].iter().enumerate() {
+            jitter.push(packet, start + Duration::from_millis(10 * i as u64)).unwrap();
+            jitter.pop();
+        }
+        assert_eq!(jitter.target_ms(), 20.0);
+        // Bursts of 4 every 40 ms raise it, and nothing is lost keeping up
+        for (i, packet) in packets[100..].iter().enumerate() {
+            jitter.push(packet, start + Duration::from_millis(1000 + 40 * (i as u64 / 4))).unwrap();
+            if i % 4 == 3 {
+                (0..4).for_each(|_| assert_ne!(jitter.pop(), Playout::Lost));
+            }
+        }
+        let target = jitter.target_ms();
+        assert!(target > 40.0 && target < 200.0, "{target}");
+        assert_eq!(jitter.stats().lost, 0);
+
+        // A new stream starts over
+        let mut other = Packetizer::new(&PacketizerConfig::default()).unwrap();
+        jitter.push(&other.packetize(&[1], 480), start).unwrap();
+        assert_eq!(jitter.pop(), Playout::Buffering);
+        assert_eq!(jitter.stats().buffered_ms, 0.0);
+    }
+
+    #[test]
+    fn test_ffi() {
+        let packetizer = unsafe { ar_rtp_packetizer_new(c"{\"payloadType\": 96}".as_ptr()) };
+        let jitter = unsafe { ar_jitter_new(c"{\"minDelayMs\": 0}".as_ptr()) };
+        assert!(packetizer != 0 && jitter != 0);
+        for payload in [[1u8, 2, 3], [4, 5, 6]] {
+            let mut len = 0;
+            let packet = unsafe { ar_rtp_packetize(packetizer, payload.as_ptr(), 3, 480, &mut len) };
+            assert_eq!(len, 15);
+            assert_eq!(unsafe { ar_jitter_push(jitter, packet, len) }, 1);
+            unsafe { crate::error::ar_bytes_free(packet, len) };
+        }
+        let (mut out, mut len) = ([0u8; 8], 0);
+        assert_eq!(unsafe { ar_jitter_pop(jitter, out.as_mut_ptr(), 2, &mut len) }, -999);
+        assert_eq!(unsafe { ar_jitter_pop(jitter, out.as_mut_ptr(), out.len(), &mut len) }, 1);
+        assert_eq!(out[..len], [1, 2, 3]);
+        assert_eq!(unsafe { ar_jitter_push(jitter, out.as_ptr(), 3) }, -999);
+        let stats = crate::error::tests::take_c_string(ar_jitter_stats(jitter)).unwrap();
+        assert!(stats.contains("\"received\":2"), "{stats}");
+        assert_eq!(ar_jitter_reset(jitter), 1);
+        assert_eq!(unsafe { ar_jitter_pop(jitter, out.as_mut_ptr(), out.len(), &mut len) }, 0);
+        assert_eq!(ar_rtp_packetizer_free(packetizer), 1);
+        assert_eq!(ar_jitter_free(jitter), 1);
+        assert_eq!(ar_jitter_reset(jitter), -999);
+    }
+}
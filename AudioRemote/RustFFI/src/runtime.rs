@@ -0,0 +1,352 @@
+//! Background jobs. `ar_init` starts a small pool of worker threads; long-running
+//! operations (downloads, feed fetches, later discovery and network I/O) are
+//! submitted as jobs that report progress and completion through C callbacks and
+//! can be cancelled by handle. Callbacks run on the dispatch queue set with
+//! `ar_runtime_set_callback_queue`, or on the worker thread if none is set.
+//!
+//! Work that waits for a moment rather than for I/O (auto-switch rechecks,
+//! saving device states once they settle) sleeps on one tokio timer thread.
+
+use std::ffi::{c_char, c_void, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::error::panic_message;
+use crate::handle::{Handle, Registry};
+use crate::{guard, record, AudioRemoteError};
+
+const MAX_WORKERS: usize = 4;
+/// Progress callbacks are coalesced to at most one per interval
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Job progress: (job, done, total, ctx); `total` is 0 when unknown
+pub type JobProgressCallback = extern "C" fn(job: Handle, done: u64, total: u64, ctx: *mut c_void);
+
+/// Job completion: (job, error code, result, ctx). `error_code` is 0 on success and
+/// `result` the job's output; otherwise it is an AudioRemoteError code and `result`
+/// the message. `result` is only valid during the call.
+pub type JobCompletionCallback = extern "C" fn(job: Handle, error_code: i32, result: *const c_char, ctx: *mut c_void);
+
+type Task = Box<dyn FnOnce() + Send>;
+
+struct Runtime {
+    sender: Sender<Task>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+static RUNTIME: Mutex<Option<Runtime>> = Mutex::new(None);
+
+struct JobState {
+    cancelled: AtomicBool,
+}
+
+static JOBS: Registry<JobState> = Registry::new("job");
+
+static TIMERS: Mutex<Option<tokio::runtime::Handle>> = Mutex::new(None);
+
+/// The runtime timers wait on, started on first use: one thread however many are pending
+pub(crate) fn timers() -> Result<tokio::runtime::Handle, AudioRemoteError> {
+    let mut timers = TIMERS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(handle) = &*timers {
+        return Ok(handle.clone());
+    }
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .map_err(|e| AudioRemoteError::Other(format!("can't start the timers: {e}")))?;
+    let handle = runtime.handle().clone();
+    thread::Builder::new()
+        .name("audioremote-timers".into())
+        .spawn(move || runtime.block_on(std::future::pending::<()>()))
+        .map_err(|e| AudioRemoteError::Other(format!("can't start the timer thread: {e}")))?;
+    Ok(timers.insert(handle).clone())
+}
+
+/// A pointer the caller promised may be used from any thread
+#[derive(Clone, Copy)]
+pub(crate) struct SendPtr(pub(crate) *mut c_void);
+
+unsafe impl Send for SendPtr {}
+
+impl SendPtr {
+    // A method rather than `.0`, so closures capture the whole (Send) wrapper
+    pub(crate) fn get(self) -> *mut c_void {
+        self.0
+    }
+}
+
+/// dispatch_queue_t for callbacks, or null to call them on the worker thread
+static CALLBACK_QUEUE: Mutex<SendPtr> = Mutex::new(SendPtr(std::ptr::null_mut()));
+
+#[cfg(target_os = "macos")]
+extern "C" {
+    fn dispatch_async_f(queue: *mut c_void, context: *mut c_void, work: extern "C" fn(*mut c_void));
+    fn dispatch_retain(object: *mut c_void);
+    fn dispatch_release(object: *mut c_void);
+}
+
+#[cfg(target_os = "macos")]
+extern "C" fn run_delivery(context: *mut c_void) {
+    let delivery = unsafe { Box::from_raw(context as *mut Task) };
+    let _ = panic::catch_unwind(AssertUnwindSafe(delivery));
+}
+
+#[cfg(target_os = "macos")]
+fn dispatch(queue: *mut c_void, task: Task) {
+    unsafe { dispatch_async_f(queue, Box::into_raw(Box::new(task)) as *mut c_void, run_delivery) };
+}
+
+/// Queues can't be set elsewhere (see ar_runtime_set_callback_queue)
+#[cfg(not(target_os = "macos"))]
+fn dispatch(_queue: *mut c_void, task: Task) {
+    task();
+}
+
+/// Run `callback` on the callback queue
+pub(crate) fn deliver(callback: impl FnOnce() + Send + 'static) {
+    let queue = CALLBACK_QUEUE.lock().unwrap_or_else(|e| e.into_inner()).get();
+    match queue.is_null() {
+        true => callback(),
+        false => dispatch(queue, Box::new(callback)),
+    }
+}
+
+/// What a running job sees of the outside world
+pub struct JobContext {
+    handle: Handle,
+    state: Arc<JobState>,
+    progress: Option<JobProgressCallback>,
+    ctx: SendPtr,
+    last_progress: Mutex<Option<Instant>>,
+}
+
+impl JobContext {
+    pub fn handle(&self) -> Handle {
+        self.handle
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Report progress; calls closer together than PROGRESS_INTERVAL are dropped
+    /// except the final one (done == total)
+    pub fn progress(&self, done: u64, total: u64) {
+        let Some(callback) = self.progress else {
+            return;
+        };
+        let mut last = self.last_progress.lock().unwrap_or_else(|e| e.into_inner());
+        if last.is_some_and(|t| t.elapsed() < PROGRESS_INTERVAL) && done != total {
+            return;
+        }
+        *last = Some(Instant::now());
+        let (handle, ctx) = (self.handle, self.ctx);
+        deliver(move || callback(handle, done, total, ctx.get()));
+    }
+}
+
+fn complete(handle: Handle, completion: Option<JobCompletionCallback>, ctx: SendPtr, result: Result<String, AudioRemoteError>) {
+    let Some(completion) = completion else {
+        return;
+    };
+    let (code, text) = match result {
+        Ok(output) => (0, output),
+        Err(error) => (error.code(), error.to_string()),
+    };
+    let text = CString::new(text.replace('\0', "")).unwrap_or_default();
+    deliver(move || completion(handle, code, text.as_ptr(), ctx.get()));
+}
+
+fn panic_error(payload: Box<dyn std::any::Any + Send>) -> AudioRemoteError {
+    let message = format!("job panicked: {}", panic_message(payload.as_ref()));
+    log::error!("{message}");
+    AudioRemoteError::Panic(message)
+}
+
+/// Queue `job` on the worker pool. The callbacks and `ctx` come from C and must
+/// stay valid until the completion callback has run.
+pub fn spawn(
+    job: impl FnOnce(&JobContext) -> Result<String, AudioRemoteError> + Send + 'static,
+    progress: Option<JobProgressCallback>,
+    completion: Option<JobCompletionCallback>,
+    ctx: *mut c_void,
+) -> Result<Handle, AudioRemoteError> {
+    let runtime = RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(runtime) = runtime.as_ref() else {
+        return Err(AudioRemoteError::NotInitialized("the runtime isn't running; call ar_init first".into()));
+    };
+    let state = Arc::new(JobState { cancelled: AtomicBool::new(false) });
+    let handle = JOBS.insert_shared(state.clone());
+    let ctx = SendPtr(ctx);
+    let task = move || {
+        let context = JobContext { handle, state, progress, ctx, last_progress: Mutex::new(None) };
+        let result = match context.is_cancelled() {
+            true => Err(AudioRemoteError::Cancelled),
+            false => panic::catch_unwind(AssertUnwindSafe(|| job(&context))).unwrap_or_else(|p| Err(panic_error(p))),
+        };
+        let _ = JOBS.remove(handle);
+        complete(handle, completion, context.ctx, result);
+    };
+    if runtime.sender.send(Box::new(task)).is_err() {
+        let _ = JOBS.remove(handle);
+        return Err(AudioRemoteError::Refused("the runtime is shutting down".into()));
+    }
+    Ok(handle)
+}
+
+/// Ask a job to stop; returns false if it already finished
+pub fn cancel(job: Handle) -> bool {
+    match JOBS.get(job) {
+        Ok(state) => {
+            state.cancelled.store(true, Ordering::Relaxed);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+fn worker(receiver: Arc<Mutex<Receiver<Task>>>) {
+    loop {
+        let task = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+        match task {
+            Ok(task) => task(),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Start the worker pool (`workers` None = one per core, up to 4); does nothing if it's already running
+pub fn start(workers: Option<usize>) -> Result<(), AudioRemoteError> {
+    let mut runtime = RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+    if runtime.is_some() {
+        return Ok(());
+    }
+    let (sender, receiver) = mpsc::channel::<Task>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let count = workers.unwrap_or_else(|| thread::available_parallelism().map_or(2, |n| n.get()).min(MAX_WORKERS)).max(1);
+    let workers = (0..count)
+        .map(|i| {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("audioremote-worker-{i}"))
+                .spawn(move || worker(receiver))
+                .map_err(|e| AudioRemoteError::Other(format!("can't start worker thread: {e}")))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    *runtime = Some(Runtime { sender, workers });
+    log::debug!("runtime started with {count} workers");
+    Ok(())
+}
+
+/// Cancel every job, let the queued ones complete as cancelled and wait for the workers.
+/// Don't call from the callback queue when it is serial: pending callbacks would wait on it.
+pub fn stop() {
+    let Some(runtime) = RUNTIME.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    for job in JOBS.all() {
+        job.cancelled.store(true, Ordering::Relaxed);
+    }
+    drop(runtime.sender);
+    for worker in runtime.workers {
+        let _ = worker.join();
+    }
+    log::debug!("runtime stopped");
+}
+
+/// Request cancellation of a job; it completes with AR_ERROR_CANCELLED soon after
+/// Returns: 1 if the job was running, 0 if it already finished
+#[no_mangle]
+pub extern "C" fn ar_job_cancel(job: Handle) -> i32 {
+    guard("ar_job_cancel", -999, || cancel(job) as i32)
+}
+
+/// Deliver job callbacks on a dispatch queue (e.g. the main queue); NULL calls them on
+/// worker threads. The queue is retained until it's replaced.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `queue` must be null or a valid dispatch_queue_t.
+#[no_mangle]
+pub unsafe extern "C" fn ar_runtime_set_callback_queue(queue: *mut c_void) -> i32 {
+    guard("ar_runtime_set_callback_queue", -999, || {
+        let result = match queue.is_null() {
+            true => Ok(()),
+            false if cfg!(target_os = "macos") => Ok(()),
+            false => Err(AudioRemoteError::Unsupported("dispatch queues are only available on macOS".into())),
+        };
+        if record(result).is_none() {
+            return -999;
+        }
+        let mut current = CALLBACK_QUEUE.lock().unwrap_or_else(|e| e.into_inner());
+        #[cfg(target_os = "macos")]
+        {
+            if !queue.is_null() {
+                dispatch_retain(queue);
+            }
+            if !current.0.is_null() {
+                dispatch_release(current.0);
+            }
+        }
+        *current = SendPtr(queue);
+        1
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::mpsc::SyncSender;
+
+    /// Held by tests that start or stop the global runtime
+    pub(crate) static RUNTIME_LOCK: Mutex<()> = Mutex::new(());
+
+    extern "C" fn on_complete(job: Handle, code: i32, result: *const c_char, ctx: *mut c_void) {
+        let sender = unsafe { &*(ctx as *const SyncSender<(Handle, i32, String)>) };
+        let result = unsafe { std::ffi::CStr::from_ptr(result) }.to_str().unwrap().to_owned();
+        sender.send((job, code, result)).unwrap();
+    }
+
+    #[test]
+    fn test_jobs_complete_and_cancel() {
+        let _lock = RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (sender, results) = mpsc::sync_channel::<(Handle, i32, String)>(8);
+        let ctx = &sender as *const _ as *mut c_void;
+        let wait = || results.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        assert!(spawn(|_| Ok(String::new()), None, Some(on_complete), ctx).is_err());
+        start(Some(2)).unwrap();
+        start(None).unwrap();
+
+        let done = spawn(|_| Ok("done".into()), None, Some(on_complete), ctx).unwrap();
+        assert_eq!(wait(), (done, 0, "done".into()));
+
+        let failing = spawn(|_| Err(AudioRemoteError::NotFound("gone".into())), None, Some(on_complete), ctx).unwrap();
+        assert_eq!(wait(), (failing, 9, "gone".into()));
+
+        let panicking = spawn(|_| panic!("boom"), None, Some(on_complete), ctx).unwrap();
+        assert_eq!(wait(), (panicking, 12, "job panicked: boom".into()));
+
+        let spinning = |job: &JobContext| {
+            while !job.is_cancelled() {
+                thread::sleep(Duration::from_millis(5));
+            }
+            Err(AudioRemoteError::Cancelled)
+        };
+        let cancelled = spawn(spinning, None, Some(on_complete), ctx).unwrap();
+        assert_eq!(ar_job_cancel(cancelled), 1);
+        assert_eq!(wait(), (cancelled, 8, "cancelled".into()));
+        assert_eq!(ar_job_cancel(cancelled), 0);
+
+        // Shutdown stops jobs that are still running
+        let running = spawn(spinning, None, Some(on_complete), ctx).unwrap();
+        stop();
+        assert_eq!(wait(), (running, 8, "cancelled".into()));
+        assert!(spawn(|_| Ok(String::new()), None, Some(on_complete), ctx).is_err());
+        stop();
+    }
+}
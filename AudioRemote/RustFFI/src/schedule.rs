@@ -0,0 +1,138 @@
+//! Update check scheduling. Every component that wants to hit the release feed
+//! asks here first, so checks are spaced at least `min_interval` apart (plus a
+//! per-check random jitter) across the whole app and across restarts.
+
+use std::hash::{BuildHasher, RandomState};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::store::{self, Store};
+use crate::{guard, record, AudioRemoteError};
+
+const LAST_CHECK_KEY: &str = "update.lastCheck";
+const JITTER_KEY: &str = "update.checkJitter";
+
+/// Held while deciding and recording, so two callers can't both claim the same slot
+static CHECK_LOCK: Mutex<()> = Mutex::new(());
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Uniform-enough jitter in 0..=max_jitter without pulling in an RNG crate
+fn random_jitter(max_jitter: u64) -> u64 {
+    match max_jitter {
+        0 => 0,
+        max => RandomState::new().hash_one(now()) % (max + 1),
+    }
+}
+
+/// Unix time of the last recorded check, if any
+pub fn last_check(store: &Store) -> Option<u64> {
+    store.get(LAST_CHECK_KEY)
+}
+
+/// Unix time at which the next check is allowed, or None if we never checked
+pub fn next_check(store: &Store, min_interval: u64) -> Option<u64> {
+    let jitter: u64 = store.get(JITTER_KEY).unwrap_or(0);
+    last_check(store).map(|last| last.saturating_add(min_interval).saturating_add(jitter))
+}
+
+/// Seconds until a check is allowed at `now` (0 = check now)
+pub fn seconds_until_check(store: &Store, now: u64, min_interval: u64) -> u64 {
+    match (last_check(store), next_check(store, min_interval)) {
+        // A last check in the future means the clock was set back; don't wait on it
+        (Some(last), _) if last > now => 0,
+        (_, Some(next)) => next.saturating_sub(now),
+        _ => 0,
+    }
+}
+
+/// Record a check at `now` and pick the jitter applied before the next one
+pub fn record_check(store: &Store, now: u64, max_jitter: u64) -> Result<(), AudioRemoteError> {
+    store.set(JITTER_KEY, &random_jitter(max_jitter))?;
+    store.set(LAST_CHECK_KEY, &now)
+}
+
+/// Claim the next check slot: records the check and returns true if one is due
+pub fn try_begin_check(store: &Store, now: u64, min_interval: u64, max_jitter: u64) -> Result<bool, AudioRemoteError> {
+    let _guard = CHECK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if seconds_until_check(store, now, min_interval) > 0 {
+        return Ok(false);
+    }
+    record_check(store, now, max_jitter)?;
+    Ok(true)
+}
+
+/// Ask whether an update check may run now, and if so record it
+/// Checks are spaced at least `min_interval_secs` apart plus a random jitter of up to
+/// `max_jitter_secs` chosen per check; the last check time persists across launches.
+/// Returns: 1 if the caller should check now, 0 if it's too soon, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn update_begin_check(min_interval_secs: u64, max_jitter_secs: u64) -> i32 {
+    guard("update_begin_check", -999, || {
+        match record(try_begin_check(&store::global(), now(), min_interval_secs, max_jitter_secs)) {
+            Some(due) => due as i32,
+            None => -999,
+        }
+    })
+}
+
+/// Record a check that bypassed the scheduler (e.g. the user clicked "Check for Updates")
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn update_record_check(max_jitter_secs: u64) -> i32 {
+    guard("update_record_check", -999, || {
+        let _guard = CHECK_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        match record(record_check(&store::global(), now(), max_jitter_secs)) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+/// Seconds until update_begin_check would allow a check (0 = now), for timers
+#[no_mangle]
+pub extern "C" fn update_seconds_until_check(min_interval_secs: u64) -> u64 {
+    guard("update_seconds_until_check", 0, || {
+        seconds_until_check(&store::global(), now(), min_interval_secs)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::with_temp_store;
+
+    const HOUR: u64 = 3600;
+
+    #[test]
+    fn test_begin_check_enforces_interval() {
+        let (_guard, dir) = with_temp_store("schedule");
+        let store = Store::open(&dir);
+        let t0 = 1_700_000_000;
+
+        assert_eq!(seconds_until_check(&store, t0, HOUR), 0);
+        assert!(try_begin_check(&store, t0, HOUR, 0).unwrap());
+        assert!(!try_begin_check(&store, t0 + 10, HOUR, 0).unwrap());
+        assert_eq!(seconds_until_check(&store, t0 + 600, HOUR), HOUR - 600);
+        assert!(try_begin_check(&store, t0 + HOUR, HOUR, 0).unwrap());
+        assert_eq!(last_check(&Store::open(&dir)), Some(t0 + HOUR));
+
+        // Clock set back before the last check: don't block checks indefinitely
+        assert!(try_begin_check(&store, t0, HOUR, 0).unwrap());
+    }
+
+    #[test]
+    fn test_jitter_delays_next_check() {
+        let (_guard, dir) = with_temp_store("jitter");
+        let store = Store::open(&dir);
+        let t0 = 1_700_000_000;
+
+        record_check(&store, t0, 300).unwrap();
+        let next = next_check(&store, HOUR).unwrap();
+        assert!((t0 + HOUR..=t0 + HOUR + 300).contains(&next), "{next}");
+        assert!(!try_begin_check(&store, t0 + HOUR - 1, HOUR, 300).unwrap());
+        assert!(try_begin_check(&store, t0 + HOUR + 300, HOUR, 300).unwrap());
+    }
+}
@@ -0,0 +1,806 @@
+//! Embedded HTTP/1.1 server for remotes on the LAN. Rust owns the listener, the
+//! connections and the wire format; what each path does is decided in `routes`,
+//! which hands the actual audio work to handlers registered from Swift.
+//!
+//! Each connection gets its own thread: a Mac serves a handful of remotes, and
+//! blocking I/O keeps the server as simple as the rest of the crate. Threads
+//! are capped, per address as well as overall, and a request has a few seconds
+//! to arrive whole. Requests are small JSON documents, so bodies need a
+//! Content-Length and are capped.
+//! With TLS on, every connection is HTTPS (see `tls`).
+//!
+//! Browsers get no CORS access from other sites: only the server's own page
+//! may read its answers, and API POSTs must be JSON, which a page elsewhere
+//! can't send without a preflight.
+
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::ratelimit::{self, Limiter, RateLimitConfig};
+use crate::tls::{self, TlsStream};
+use crate::{auth, guard, record, routes, sessions, sse, str_arg, websocket, AudioRemoteError};
+
+/// The port the app has always used (Settings > HTTP port)
+pub const DEFAULT_PORT: u16 = 8765;
+const MAX_CONNECTIONS: usize = 32;
+/// A browser opens six per host; more from one address is a client that doesn't let go
+const MAX_CONNECTIONS_PER_PEER: usize = 8;
+const MAX_HEAD_BYTES: u64 = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+const MAX_BODY_BYTES: usize = 64 * 1024;
+/// How long an idle keep-alive connection stays open
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a client has to send a request once it starts one, and to start the first
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// Everything after '?', undecoded
+    pub query: String,
+    /// Lowercased names, in order
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub peer: SocketAddr,
+    /// The paired remote the request's access token belongs to (see auth::authorize)
+    pub remote: Option<String>,
+    keep_alive: bool,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+
+    /// The request's Origin if it's this server itself, i.e. a page it served; None for other
+    /// sites and for clients that aren't browsers
+    pub fn own_origin(&self) -> Option<&str> {
+        let origin = self.header("origin")?;
+        let authority = origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://"))?;
+        self.header("host").is_some_and(|host| authority.eq_ignore_ascii_case(host)).then_some(origin)
+    }
+
+    /// Whether a browser sent this from a page on another site
+    pub fn is_cross_site(&self) -> bool {
+        self.header("origin").is_some() && self.own_origin().is_none()
+    }
+
+    /// First value of a query parameter, percent-decoded
+    pub fn query_param(&self, name: &str) -> Option<String> {
+        form_urlencoded::parse(self.query.as_bytes()).find(|(n, _)| n == name).map(|(_, v)| v.into_owned())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Self { status, headers: Vec::new(), body: Vec::new() }
+    }
+
+    pub fn json(status: u16, value: &impl Serialize) -> Self {
+        // Serializing our own types can't fail
+        let body = serde_json::to_vec(value).unwrap_or_default();
+        Self::new(status).with_header("Content-Type", "application/json").with_body(body)
+    }
+
+    /// `{"error": {"status", "message"}}`
+    pub fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::json(status, &serde_json::json!({"error": {"status": status, "message": message.into()}}))
+    }
+
+    pub fn html(body: &str) -> Self {
+        Self::new(200).with_header("Content-Type", "text/html; charset=utf-8").with_body(body.as_bytes().to_vec())
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Self {
+        self.body = body;
+        self
+    }
+}
+
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        207 => "Multi-Status",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        415 => "Unsupported Media Type",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        470 => "Connection Authorization Required",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+/// Why no request could be read: the peer went away, or it sent something we answer with an error
+#[derive(Debug)]
+pub(crate) enum ReadError {
+    Closed,
+    Rejected(Response),
+}
+
+impl From<io::Error> for ReadError {
+    fn from(error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Self::Rejected(Response::error(408, "request timed out")),
+            _ => Self::Closed,
+        }
+    }
+}
+
+fn bad_request(message: impl Into<String>) -> ReadError {
+    ReadError::Rejected(Response::error(400, message))
+}
+
+/// One CRLF- (or LF-) terminated line from the request head, charged against `budget`
+fn read_head_line(reader: &mut impl BufRead, budget: &mut u64) -> Result<Option<String>, ReadError> {
+    let mut line = Vec::new();
+    let read = reader.take(*budget).read_until(b'\n', &mut line)?;
+    *budget -= read as u64;
+    if read == 0 {
+        return match *budget == 0 {
+            true => Err(ReadError::Rejected(Response::error(431, "request head is too large"))),
+            false => Ok(None),
+        };
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(match *budget == 0 {
+            true => ReadError::Rejected(Response::error(431, "request head is too large")),
+            false => ReadError::Closed,
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map(Some).map_err(|_| bad_request("request head is not UTF-8"))
+}
+
+/// Read the next request on a connection; `Closed` at a clean end of stream
+pub(crate) fn read_request(reader: &mut impl BufRead, peer: SocketAddr) -> Result<Request, ReadError> {
+    let mut budget = MAX_HEAD_BYTES;
+    let mut line = String::new();
+    // Tolerate stray blank lines between requests (RFC 9112 §2.2)
+    while line.is_empty() {
+        line = read_head_line(reader, &mut budget)?.ok_or(ReadError::Closed)?;
+    }
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next()) else {
+        return Err(bad_request("malformed request line"));
+    };
+    let keep_alive_by_default = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(ReadError::Rejected(Response::error(505, format!("unsupported protocol {version}")))),
+    };
+    if !target.starts_with('/') {
+        return Err(bad_request("request target must be an absolute path"));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_head_line(reader, &mut budget)?.ok_or(ReadError::Closed)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(ReadError::Rejected(Response::error(431, "too many header fields")));
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(bad_request(format!("malformed header field \"{line}\"")));
+        };
+        if name.is_empty() || name.ends_with(char::is_whitespace) {
+            return Err(bad_request(format!("malformed header field \"{line}\"")));
+        }
+        headers.push((name.to_ascii_lowercase(), value.trim().to_owned()));
+    }
+
+    let mut request = Request {
+        method: method.to_owned(),
+        path: path.to_owned(),
+        query: query.to_owned(),
+        headers,
+        body: Vec::new(),
+        peer,
+        remote: None,
+        keep_alive: keep_alive_by_default,
+    };
+    if let Some(connection) = request.header("connection") {
+        let has = |token: &str| connection.split(',').any(|t| t.trim().eq_ignore_ascii_case(token));
+        request.keep_alive = match keep_alive_by_default {
+            true => !has("close"),
+            false => has("keep-alive"),
+        };
+    }
+    if request.header("transfer-encoding").is_some() {
+        return Err(ReadError::Rejected(Response::error(411, "request bodies need a Content-Length")));
+    }
+    if let Some(length) = request.header("content-length") {
+        let length: usize = length.parse().map_err(|_| bad_request(format!("invalid Content-Length \"{length}\"")))?;
+        if length > MAX_BODY_BYTES {
+            return Err(ReadError::Rejected(Response::error(413, format!("request body is over {MAX_BODY_BYTES} bytes"))));
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body)?;
+    }
+    Ok(request)
+}
+
+pub(crate) fn write_response(writer: &mut impl Write, response: &Response, keep_alive: bool) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {} {}\r\n", response.status, reason(response.status));
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str(&format!(
+        "Server: AudioRemote/{}\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+        env!("CARGO_PKG_VERSION"),
+        response.body.len(),
+        if keep_alive { "keep-alive" } else { "close" }
+    ));
+    writer.write_all(head.as_bytes())?;
+    writer.write_all(&response.body)?;
+    writer.flush()
+}
+
+/// A connection's reader with one deadline for a whole request rather than a timeout per read,
+/// so a client trickling a byte at a time can't hold the connection open
+struct Deadline<'a> {
+    reader: &'a mut BufReader<Stream>,
+    until: Instant,
+    started: bool,
+}
+
+impl<'a> Deadline<'a> {
+    /// Wait up to `wait` for the request to start, then at most REQUEST_TIMEOUT for the rest of it
+    fn new(reader: &'a mut BufReader<Stream>, wait: Duration) -> Self {
+        Self { reader, until: Instant::now() + wait, started: false }
+    }
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for Deadline<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.reader.buffer().is_empty() {
+            let left = self.until.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.reader.get_ref().set_read_timeout(Some(left))?;
+        }
+        let buffered = self.reader.fill_buf()?;
+        if !self.started && !buffered.is_empty() {
+            self.started = true;
+            self.until = self.until.min(Instant::now() + REQUEST_TIMEOUT);
+        }
+        Ok(buffered)
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.reader.consume(amount);
+    }
+}
+
+/// A connection, plain or TLS
+pub(crate) enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Self::Plain(stream) => stream.try_clone().map(Self::Plain),
+            Self::Tls(stream) => Ok(Self::Tls(stream.clone())),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.set_read_timeout(timeout),
+            Self::Tls(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.shutdown(how),
+            Self::Tls(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.read(buf),
+            Self::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(stream) => stream.write(buf),
+            Self::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(stream) => stream.flush(),
+            Self::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// `ar_server_start` configuration; every field is optional
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ServerConfig {
+    /// 0 picks a free port
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Default: all interfaces, IPv4 and IPv6
+    #[serde(default = "default_bind_address")]
+    pub bind_address: IpAddr,
+    /// Refuse API requests without a paired remote's access token; off only for development
+    #[serde(default = "default_require_auth")]
+    pub require_auth: bool,
+    /// Serve HTTPS and WSS with this Mac's self-signed certificate
+    #[serde(default)]
+    pub tls: bool,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+}
+
+fn default_port() -> u16 {
+    DEFAULT_PORT
+}
+
+fn default_require_auth() -> bool {
+    true
+}
+
+fn default_bind_address() -> IpAddr {
+    IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+}
+
+/// A listener on `address`. On "::" it takes IPv4 too, and where IPv6 is off it falls back to 0.0.0.0
+pub(crate) fn listen(address: IpAddr, port: u16) -> io::Result<TcpListener> {
+    let bind = |address: SocketAddr, dual_stack: bool| -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+        // As TcpListener::bind does, so a restart doesn't wait out TIME_WAIT
+        socket.set_reuse_address(true)?;
+        if dual_stack {
+            socket.set_only_v6(false)?;
+        }
+        socket.bind(&address.into())?;
+        socket.listen(128)?;
+        Ok(socket.into())
+    };
+    match address {
+        IpAddr::V6(v6) if v6.is_unspecified() => bind(SocketAddr::new(address, port), true).or_else(|e| match e.kind() {
+            io::ErrorKind::AddrInUse => Err(e),
+            _ => bind(SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)), false),
+        }),
+        _ => bind(SocketAddr::new(address, port), false),
+    }
+}
+
+/// `peer` as the rest of the crate should see it: IPv4 remotes on a dual-stack listener arrive as
+/// ::ffff:a.b.c.d
+pub(crate) fn canonical_peer(peer: SocketAddr) -> SocketAddr {
+    SocketAddr::new(peer.ip().to_canonical(), peer.port())
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            port: DEFAULT_PORT,
+            bind_address: default_bind_address(),
+            require_auth: true,
+            tls: false,
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
+}
+
+/// State shared by the accept loop and the connection threads
+struct Shared {
+    stopping: AtomicBool,
+    require_auth: bool,
+    /// The TLS configuration and the certificate's fingerprint
+    tls: Option<(Arc<rustls::ServerConfig>, String)>,
+    next_connection: AtomicU64,
+    /// Clones of the open connections and who they're from, so stop() can shut them down
+    connections: Mutex<BTreeMap<u64, (IpAddr, TcpStream)>>,
+    limiter: Mutex<Limiter>,
+}
+
+impl Shared {
+    fn connections(&self) -> std::sync::MutexGuard<'_, BTreeMap<u64, (IpAddr, TcpStream)>> {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct Running {
+    address: SocketAddr,
+    shared: Arc<Shared>,
+    acceptor: JoinHandle<()>,
+}
+
+static SERVER: Mutex<Option<Running>> = Mutex::new(None);
+
+fn serve(mut stream: Stream, peer: SocketAddr, shared: &Shared) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    // Only a connection that has already sent a request may sit idle waiting for the next
+    let mut wait = REQUEST_TIMEOUT;
+    loop {
+        let request = read_request(&mut Deadline::new(&mut reader, wait), peer).and_then(|mut request| {
+            let checked = ratelimit::check_address(&shared.limiter, peer.ip())
+                .and_then(|()| auth::authorize(&mut request, shared.require_auth))
+                .and_then(|()| ratelimit::check_request(&shared.limiter, &request));
+            match checked {
+                Ok(()) => {
+                    sessions::touch(&request);
+                    Ok(request)
+                }
+                Err(response) => Err(ReadError::Rejected(response)),
+            }
+        });
+        let (response, keep_alive) = match request {
+            Ok(request) if websocket::is_upgrade(&request) => return websocket::serve(stream, reader, &request),
+            Ok(request) if sse::is_stream(&request) => return sse::serve(stream, &request),
+            Ok(request) => {
+                let started = Instant::now();
+                let mut response = routes::handle(&request);
+                if let Some(origin) = request.own_origin() {
+                    response = response.with_header("Access-Control-Allow-Origin", origin).with_header("Vary", "Origin");
+                }
+                log::debug!(
+                    "{} {} -> {} in {} ms [{peer}]",
+                    request.method,
+                    request.path,
+                    response.status,
+                    started.elapsed().as_millis()
+                );
+                (response, request.keep_alive)
+            }
+            Err(ReadError::Closed) => return Ok(()),
+            Err(ReadError::Rejected(response)) => (response, false),
+        };
+        ratelimit::record_response(&shared.limiter, peer.ip(), &response);
+        write_response(&mut stream, &response, keep_alive)?;
+        if !keep_alive {
+            return stream.shutdown(Shutdown::Both);
+        }
+        wait = IDLE_TIMEOUT;
+    }
+}
+
+fn accept_loop(listener: TcpListener, shared: Arc<Shared>) {
+    for stream in listener.incoming() {
+        if shared.stopping.load(Ordering::Acquire) {
+            return;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                // Usually out of file descriptors; don't spin
+                log::warn!("accept failed: {e}");
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        };
+        let Ok(peer) = stream.peer_addr().map(canonical_peer) else {
+            continue;
+        };
+        let id = shared.next_connection.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut connections = shared.connections();
+            if connections.len() >= MAX_CONNECTIONS {
+                drop(connections);
+                log::warn!("refusing connection from {peer}: {MAX_CONNECTIONS} connections open");
+                let mut stream = stream;
+                let _ = write_response(&mut stream, &Response::error(503, "too many connections"), false);
+                continue;
+            }
+            if connections.values().filter(|(ip, _)| *ip == peer.ip()).count() >= MAX_CONNECTIONS_PER_PEER {
+                drop(connections);
+                log::warn!("refusing connection from {peer}: {MAX_CONNECTIONS_PER_PEER} connections open from there");
+                let mut stream = stream;
+                let _ = write_response(&mut stream, &Response::error(503, "too many connections from this address"), false);
+                continue;
+            }
+            match stream.try_clone() {
+                Ok(clone) => connections.insert(id, (peer.ip(), clone)),
+                Err(_) => continue,
+            };
+        }
+        let connection = shared.clone();
+        let spawned = thread::Builder::new().name(format!("audioremote-http-{id}")).spawn(move || {
+            let stream = match &connection.tls {
+                Some((config, _)) => TlsStream::new(stream, config.clone()).map(Stream::Tls),
+                None => Ok(Stream::Plain(stream)),
+            };
+            if let Err(e) = stream.and_then(|stream| serve(stream, peer, &connection)) {
+                log::debug!("connection from {peer} ended: {e}");
+            }
+            connection.connections().remove(&id);
+        });
+        if let Err(e) = spawned {
+            log::warn!("can't start connection thread: {e}");
+            shared.connections().remove(&id);
+        }
+    }
+}
+
+/// Start listening; fails if the server is already running or the address is taken
+pub fn start(config: &ServerConfig) -> Result<SocketAddr, AudioRemoteError> {
+    config.rate_limit.validate()?;
+    let mut server = SERVER.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(running) = server.as_ref() {
+        return Err(AudioRemoteError::Refused(format!("the server is already running on {}", running.address)));
+    }
+    let tls = match config.tls {
+        true => {
+            let identity = tls::identity()?;
+            Some((identity.server_config()?, identity.fingerprint()))
+        }
+        false => None,
+    };
+    let listener = listen(config.bind_address, config.port)
+        .map_err(|e| AudioRemoteError::Network(format!("can't listen on port {}: {e}", config.port)))?;
+    let address = listener.local_addr()?;
+    let shared = Arc::new(Shared {
+        stopping: AtomicBool::new(false),
+        require_auth: config.require_auth,
+        tls,
+        next_connection: AtomicU64::new(1),
+        connections: Mutex::new(BTreeMap::new()),
+        limiter: Mutex::new(Limiter::new(config.rate_limit.clone())),
+    });
+    let acceptor = {
+        let shared = shared.clone();
+        thread::Builder::new()
+            .name("audioremote-http".into())
+            .spawn(move || accept_loop(listener, shared))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the server thread: {e}")))?
+    };
+    *server = Some(Running { address, shared, acceptor });
+    log::info!("server listening on {address}");
+    Ok(address)
+}
+
+/// Stop accepting, close every connection and fail requests still waiting on Swift
+pub fn stop() {
+    let Some(running) = SERVER.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    running.shared.stopping.store(true, Ordering::Release);
+    // Wake the accept loop so it sees the flag
+    let mut wake = running.address;
+    if wake.ip().is_unspecified() {
+        wake.set_ip(match wake {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        });
+    }
+    let _ = TcpStream::connect_timeout(&wake, Duration::from_secs(1));
+    let _ = running.acceptor.join();
+    for (_, connection) in running.shared.connections().values() {
+        let _ = connection.shutdown(Shutdown::Both);
+    }
+    routes::cancel_pending();
+    log::info!("server stopped");
+}
+
+/// Where the server is listening, if it's running
+pub fn local_address() -> Option<SocketAddr> {
+    SERVER.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|running| running.address)
+}
+
+/// The fingerprint of the certificate the server presents, if it's running with TLS
+pub fn certificate_fingerprint() -> Option<String> {
+    let server = SERVER.lock().unwrap_or_else(|e| e.into_inner());
+    server.as_ref().and_then(|running| running.shared.tls.as_ref()).map(|(_, fingerprint)| fingerprint.clone())
+}
+
+/// Start the remote-control server
+/// `config_json` (nullable) is a JSON object, all keys optional:
+/// {"port": n /* default 8765, 0 = any free port */, "bindAddress": "::" /* IPv4 and IPv6 */, "requireAuth": true, "tls": false}
+/// Returns: the port listened on, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_ptr` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_server_start(config_ptr: *const c_char) -> i32 {
+    guard("ar_server_start", -999, || {
+        let config = match config_ptr.is_null() {
+            true => Ok(ServerConfig::default()),
+            false => str_arg(config_ptr, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid server config: {e}")))
+            }),
+        };
+        match record(config.and_then(|config| start(&config))) {
+            Some(address) => i32::from(address.port()),
+            None => -999,
+        }
+    })
+}
+
+/// Stop the server; requests waiting on a handler are answered with 503. A no-op if it isn't running.
+#[no_mangle]
+pub extern "C" fn ar_server_stop() {
+    guard("ar_server_stop", (), stop)
+}
+
+/// Returns: the port the server listens on, 0 if it isn't running
+#[no_mangle]
+pub extern "C" fn ar_server_port() -> i32 {
+    guard("ar_server_port", 0, || local_address().map_or(0, |address| i32::from(address.port())))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Send raw HTTP/1.1 and read the status line and body of the reply
+    pub(crate) fn exchange(address: SocketAddr, request: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).unwrap();
+        let status = reply[9..12].parse().unwrap();
+        let body = reply.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+        (status, body.to_owned())
+    }
+
+    pub(crate) fn parse(raw: &str) -> Result<Request, ReadError> {
+        read_request(&mut raw.as_bytes(), "127.0.0.1:1".parse().unwrap())
+    }
+
+    fn rejected(result: Result<Request, ReadError>) -> u16 {
+        match result {
+            Err(ReadError::Rejected(response)) => response.status,
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_requests() {
+        let request = parse("PUT /api/v1/volume?x=1 HTTP/1.1\r\nHost: mac\r\nContent-Length: 15\r\n\r\n{\"volume\": 0.5}").unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str(), request.query.as_str()), ("PUT", "/api/v1/volume", "x=1"));
+        assert_eq!(request.header("HOST"), Some("mac"));
+        assert_eq!(request.query_param("x").as_deref(), Some("1"));
+        assert_eq!(request.body, b"{\"volume\": 0.5}");
+        assert!(request.keep_alive);
+
+        assert!(!parse("GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap().keep_alive);
+        assert!(!parse("GET / HTTP/1.0\n\n").unwrap().keep_alive);
+        assert!(parse("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap().keep_alive);
+        assert!(matches!(parse(""), Err(ReadError::Closed)));
+        assert!(matches!(parse("GET / HTTP/1.1\r\nHost: mac\r\n"), Err(ReadError::Closed)));
+
+        assert_eq!(rejected(parse("GET /\r\n\r\n")), 400);
+        assert_eq!(rejected(parse("GET / HTTP/2\r\n\r\n")), 505);
+        assert_eq!(rejected(parse("GET / HTTP/1.1\r\nbad header\r\n\r\n")), 400);
+        assert_eq!(rejected(parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")), 411);
+        assert_eq!(rejected(parse("POST / HTTP/1.1\r\nContent-Length: 999999\r\n\r\n")), 413);
+        let huge = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEAD_BYTES as usize));
+        assert_eq!(rejected(parse(&huge)), 431);
+    }
+
+    #[test]
+    fn test_auth_is_required_unless_turned_off() {
+        assert!(ServerConfig::default().require_auth);
+        assert!(serde_json::from_str::<ServerConfig>("{}").unwrap().require_auth);
+        assert!(!serde_json::from_str::<ServerConfig>(r#"{"requireAuth": false}"#).unwrap().require_auth);
+    }
+
+    #[test]
+    fn test_own_origin() {
+        let request = parse("GET / HTTP/1.1\r\nHost: mac.local:8765\r\nOrigin: http://mac.local:8765\r\n\r\n").unwrap();
+        assert_eq!((request.own_origin(), request.is_cross_site()), (Some("http://mac.local:8765"), false));
+        let request = parse("GET / HTTP/1.1\r\nHost: mac.local:8765\r\nOrigin: http://mac.local.evil.example\r\n\r\n").unwrap();
+        assert_eq!((request.own_origin(), request.is_cross_site()), (None, true));
+        let request = parse("GET / HTTP/1.1\r\nHost: mac.local:8765\r\n\r\n").unwrap();
+        assert_eq!((request.own_origin(), request.is_cross_site()), (None, false));
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut out = Vec::new();
+        write_response(&mut out, &Response::error(404, "no such path"), false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("HTTP/1.1 404 Not Found\r\nContent-Type: application/json\r\n"), "{out}");
+        assert!(out.contains("Content-Length: 49\r\nConnection: close\r\n\r\n"), "{out}");
+        assert!(out.ends_with(r#"{"error":{"message":"no such path","status":404}}"#), "{out}");
+    }
+
+    #[test]
+    fn test_slow_and_greedy_peers() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let address = start(&ServerConfig { port: 0, bind_address: "127.0.0.1".parse().unwrap(), require_auth: false, ..ServerConfig::default() }).unwrap();
+        let started = Instant::now();
+        let trickling: Vec<TcpStream> = (0..MAX_CONNECTIONS_PER_PEER)
+            .map(|_| {
+                let mut stream = TcpStream::connect(address).unwrap();
+                stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+                stream.write_all(b"GET /api/v1/vol").unwrap();
+                stream
+            })
+            .collect();
+        // Let the server register them all before the next one arrives
+        thread::sleep(Duration::from_millis(200));
+        let mut refused = TcpStream::connect(address).unwrap();
+        refused.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        let mut reply = String::new();
+        refused.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("HTTP/1.1 503 "), "{reply}");
+
+        for mut stream in trickling {
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            assert!(reply.starts_with("HTTP/1.1 408 "), "{reply}");
+        }
+        assert!(started.elapsed() < REQUEST_TIMEOUT + Duration::from_secs(2));
+        assert_eq!(exchange(address, "GET / HTTP/1.1\r\nHost: mac\r\nConnection: close\r\n\r\n").0, 200);
+        stop();
+    }
+
+    #[test]
+    fn test_dual_stack_listener() {
+        let listener = listen(default_bind_address(), 0).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        for client in [IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)] {
+            let _stream = TcpStream::connect((client, port)).unwrap();
+            let (_, peer) = listener.accept().unwrap();
+            assert_eq!(canonical_peer(peer).ip(), client);
+        }
+    }
+}
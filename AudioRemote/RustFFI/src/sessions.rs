@@ -0,0 +1,355 @@
+//! Who is connected and what they may do. Each paired remote has a scope,
+//! chosen in the settings UI: volume only, playback, full control or admin,
+//! each allowing everything the one before it does. The server checks the scope
+//! of the remote a request's token belongs to before routing it, and what a
+//! WebSocket client may subscribe to.
+//!
+//! A session is a remote seen recently: one per paired remote, and one per
+//! address for clients without a token (only possible when the server doesn't
+//! require auth; they get full control, as before scopes existed).
+
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::string_result;
+use crate::events::Topic;
+use crate::pairing;
+use crate::routes::Endpoint;
+use crate::server::{Request, Response};
+use crate::{guard, record, store, str_arg, AudioRemoteError};
+
+const SCOPES_KEY: &str = "sessions.scopes";
+/// A session without an open stream is dropped after this many seconds without a request
+const SESSION_IDLE: u64 = 300;
+const MAX_SESSIONS: usize = 256;
+
+/// What a remote may do; values are part of the C ABI
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Scope {
+    /// Volume and mute
+    VolumeOnly = 0,
+    /// Also what's playing
+    Playback = 1,
+    /// Also output devices
+    FullControl = 2,
+    /// Everything, including managing the Mac's remote settings
+    Admin = 3,
+}
+
+impl Scope {
+    /// What a newly paired remote gets
+    pub const DEFAULT: Scope = Scope::FullControl;
+
+    pub fn from_raw(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Self::VolumeOnly),
+            1 => Some(Self::Playback),
+            2 => Some(Self::FullControl),
+            3 => Some(Self::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn required_for(endpoint: Endpoint) -> Self {
+        match endpoint {
+            Endpoint::GetVolume | Endpoint::SetVolume | Endpoint::SetMute => Self::VolumeOnly,
+            Endpoint::NowPlaying => Self::Playback,
+            Endpoint::ListDevices | Endpoint::SelectDevice => Self::FullControl,
+        }
+    }
+
+    pub fn required_for_topic(topic: Topic) -> Self {
+        match topic {
+            Topic::Volume => Self::VolumeOnly,
+            Topic::NowPlaying => Self::Playback,
+            Topic::Devices => Self::FullControl,
+        }
+    }
+
+    pub fn allows(self, endpoint: Endpoint) -> bool {
+        self >= Self::required_for(endpoint)
+    }
+
+    pub fn allows_topic(self, topic: Topic) -> bool {
+        self >= Self::required_for_topic(topic)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::VolumeOnly => "volume only",
+            Self::Playback => "playback",
+            Self::FullControl => "full control",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+/// Scopes set in the settings UI, by remote ID; remotes without one have the default
+fn scopes() -> BTreeMap<String, Scope> {
+    store::global().get(SCOPES_KEY).unwrap_or_default()
+}
+
+/// The scope of a paired remote
+pub fn scope_of(remote_id: &str) -> Scope {
+    scopes().get(remote_id).copied().unwrap_or(Scope::DEFAULT)
+}
+
+/// What a request may do: its remote's scope, or full control without a token
+pub(crate) fn scope(request: &Request) -> Scope {
+    request.remote.as_deref().map_or(Scope::FullControl, scope_of)
+}
+
+/// The server's check before routing to an endpoint; the error is a 403 to send instead
+pub(crate) fn permit(request: &Request, endpoint: Endpoint) -> Result<(), Response> {
+    let scope = scope(request);
+    match scope.allows(endpoint) {
+        true => Ok(()),
+        false => Err(Response::error(
+            403,
+            format!("this remote has {} access; {endpoint:?} needs {}", scope.name(), Scope::required_for(endpoint).name()),
+        )),
+    }
+}
+
+/// Change a paired remote's scope; it applies from the remote's next request.
+/// Returns whether the remote is paired.
+pub fn set_scope(remote_id: &str, scope: Scope) -> Result<bool, AudioRemoteError> {
+    if !pairing::is_paired(remote_id) {
+        return Ok(false);
+    }
+    let mut scopes = scopes();
+    scopes.insert(remote_id.to_owned(), scope);
+    store::global().set(SCOPES_KEY, &scopes)?;
+    log::info!("remote {remote_id} now has {} access", scope.name());
+    Ok(true)
+}
+
+/// Forget a remote that was unpaired: its scope and its session
+pub(crate) fn forget(remote_id: &str) -> Result<(), AudioRemoteError> {
+    live().remove(remote_id);
+    let mut scopes = scopes();
+    if scopes.remove(remote_id).is_some() {
+        store::global().set(SCOPES_KEY, &scopes)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+struct Live {
+    remote_id: Option<String>,
+    name: Option<String>,
+    address: IpAddr,
+    connected_since: u64,
+    last_seen: u64,
+    requests: u64,
+    /// Open WebSocket connections
+    streams: u32,
+}
+
+/// A live session, as the settings UI lists it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    /// None for a client without a token
+    pub remote_id: Option<String>,
+    /// The paired remote's name, or the client's User-Agent
+    pub name: Option<String>,
+    pub address: String,
+    pub scope: Scope,
+    /// Unix times
+    pub connected_since: u64,
+    pub last_seen: u64,
+    pub requests: u64,
+    pub streams: u32,
+}
+
+static LIVE: Mutex<BTreeMap<String, Live>> = Mutex::new(BTreeMap::new());
+
+fn live() -> std::sync::MutexGuard<'static, BTreeMap<String, Live>> {
+    LIVE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn key(request: &Request) -> String {
+    match &request.remote {
+        Some(remote_id) => remote_id.clone(),
+        None => format!("@{}", request.peer.ip()),
+    }
+}
+
+fn prune(sessions: &mut BTreeMap<String, Live>, now: u64) {
+    sessions.retain(|_, session| session.streams > 0 || session.last_seen + SESSION_IDLE > now);
+}
+
+/// Record a request in its session, starting one if needed. Called for every request
+/// that passed auth::authorize.
+pub(crate) fn touch(request: &Request) {
+    touch_at(request, pairing::now());
+}
+
+fn touch_at(request: &Request, now: u64) {
+    let mut sessions = live();
+    let key = key(request);
+    if !sessions.contains_key(&key) {
+        prune(&mut sessions, now);
+        if sessions.len() >= MAX_SESSIONS {
+            // Don't track more sessions than the UI can show; the request is still served
+            return;
+        }
+    }
+    let name = match &request.remote {
+        Some(remote_id) => pairing::pairings().remove(remote_id).map(|pairing| pairing.remote_name),
+        None => request.header("user-agent").map(str::to_owned),
+    };
+    let session = sessions.entry(key).or_insert_with(|| Live {
+        remote_id: request.remote.clone(),
+        name: None,
+        address: request.peer.ip(),
+        connected_since: now,
+        last_seen: now,
+        requests: 0,
+        streams: 0,
+    });
+    session.name = name.or(session.name.take());
+    session.address = request.peer.ip();
+    session.last_seen = now;
+    session.requests += 1;
+}
+
+/// Keeps a session live while a WebSocket connection is open
+pub(crate) struct StreamGuard {
+    key: String,
+}
+
+pub(crate) fn open_stream(request: &Request) -> StreamGuard {
+    let key = key(request);
+    if let Some(session) = live().get_mut(&key) {
+        session.streams += 1;
+    }
+    StreamGuard { key }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        if let Some(session) = live().get_mut(&self.key) {
+            session.streams = session.streams.saturating_sub(1);
+            session.last_seen = pairing::now();
+        }
+    }
+}
+
+pub fn sessions() -> Vec<SessionInfo> {
+    let now = pairing::now();
+    let scopes = scopes();
+    let mut sessions = live();
+    prune(&mut sessions, now);
+    let mut listed: Vec<SessionInfo> = sessions
+        .values()
+        .map(|session| SessionInfo {
+            remote_id: session.remote_id.clone(),
+            name: session.name.clone(),
+            address: session.address.to_string(),
+            scope: match &session.remote_id {
+                Some(remote_id) => scopes.get(remote_id).copied().unwrap_or(Scope::DEFAULT),
+                None => Scope::FullControl,
+            },
+            connected_since: session.connected_since,
+            last_seen: session.last_seen,
+            requests: session.requests,
+            streams: session.streams,
+        })
+        .collect();
+    listed.sort_by_key(|session| std::cmp::Reverse(session.last_seen));
+    listed
+}
+
+/// Returns: JSON array of live sessions, most recent first: [{"remoteId" (null without a token),
+/// "name", "address", "scope", "connectedSince", "lastSeen", "requests", "streams"}] with Unix
+/// times (free with rust_string_free), NULL on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_sessions() -> *mut c_char {
+    guard("ar_sessions", std::ptr::null_mut(), || {
+        string_result(serde_json::to_string(&sessions()).map_err(|e| AudioRemoteError::Other(e.to_string())))
+    })
+}
+
+/// Set a paired remote's ArScope; it applies from its next request.
+/// Returns: 1 if it's paired, 0 if not, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `remote_id` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_session_set_scope(remote_id: *const c_char, scope: i32) -> i32 {
+    guard("ar_session_set_scope", -999, || {
+        let result = str_arg(remote_id, "remote ID").and_then(|remote_id| {
+            let scope = Scope::from_raw(scope).ok_or(AudioRemoteError::InvalidArgument(format!("unknown scope {scope}")))?;
+            set_scope(remote_id, scope)
+        });
+        match record(result) {
+            Some(paired) => i32::from(paired),
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pairing::Pairing;
+    use crate::server::tests::parse;
+    use crate::store::tests::with_temp_store;
+
+    fn request(remote: Option<&str>, ip: &str) -> Request {
+        let mut request = parse("GET /api/v1/volume HTTP/1.1\r\nHost: mac\r\nUser-Agent: Safari\r\n\r\n").unwrap();
+        request.remote = remote.map(str::to_owned);
+        request.peer = format!("{ip}:50000").parse().unwrap();
+        request
+    }
+
+    #[test]
+    fn test_scopes_nest() {
+        assert!(Scope::VolumeOnly.allows(Endpoint::SetVolume));
+        assert!(!Scope::VolumeOnly.allows(Endpoint::NowPlaying));
+        assert!(Scope::Playback.allows(Endpoint::NowPlaying));
+        assert!(!Scope::Playback.allows(Endpoint::SelectDevice));
+        assert!(Scope::Admin.allows(Endpoint::SelectDevice));
+        assert!(!Scope::VolumeOnly.allows_topic(Topic::Devices));
+        assert_eq!(Scope::from_raw(Scope::Admin as i32), Some(Scope::Admin));
+        assert_eq!(Scope::from_raw(4), None);
+    }
+
+    #[test]
+    fn test_scope_enforced_and_sessions_listed() {
+        let (_lock, _dir) = with_temp_store("sessions");
+        let pairing = Pairing { remote_id: "kid".into(), remote_name: "Kid's iPad".into(), key: "a2V5".into(), paired_at: 1 };
+        pairing::save(pairing).unwrap();
+        assert!(permit(&request(Some("kid"), "10.0.0.9"), Endpoint::SelectDevice).is_ok());
+        assert!(set_scope("kid", Scope::VolumeOnly).unwrap());
+        assert!(!set_scope("nobody", Scope::Admin).unwrap());
+        assert!(permit(&request(Some("kid"), "10.0.0.9"), Endpoint::SetVolume).is_ok());
+        assert_eq!(permit(&request(Some("kid"), "10.0.0.9"), Endpoint::SelectDevice).unwrap_err().status, 403);
+        assert!(permit(&request(None, "10.0.0.10"), Endpoint::SelectDevice).is_ok());
+
+        let now = pairing::now();
+        touch_at(&request(Some("kid"), "10.0.0.9"), now);
+        touch_at(&request(Some("kid"), "10.0.0.9"), now);
+        touch_at(&request(None, "10.0.0.10"), now - SESSION_IDLE - 1);
+        let mine: Vec<SessionInfo> = sessions().into_iter().filter(|s| s.address.starts_with("10.0.0.")).collect();
+        assert_eq!(mine.len(), 1, "the idle anonymous session is gone");
+        assert_eq!(mine[0].name.as_deref(), Some("Kid's iPad"));
+        assert_eq!((mine[0].scope, mine[0].requests), (Scope::VolumeOnly, 2));
+
+        {
+            let _stream = open_stream(&request(Some("kid"), "10.0.0.9"));
+            assert_eq!(sessions().iter().find(|s| s.remote_id.as_deref() == Some("kid")).unwrap().streams, 1);
+        }
+        forget("kid").unwrap();
+        assert_eq!(scope_of("kid"), Scope::DEFAULT);
+        assert!(!sessions().iter().any(|s| s.remote_id.as_deref() == Some("kid")));
+    }
+}
@@ -0,0 +1,100 @@
+use std::ffi::c_char;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signature, VerifyingKey};
+
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+/// Decode a base64 value that must be exactly `N` bytes long
+pub(crate) fn decode_fixed<const N: usize>(b64: &str, what: &str) -> Result<[u8; N], AudioRemoteError> {
+    let bytes = STANDARD
+        .decode(b64.trim())
+        .map_err(|e| AudioRemoteError::InvalidArgument(format!("{what} is not valid base64: {e}")))?;
+    bytes
+        .try_into()
+        .map_err(|b: Vec<u8>| AudioRemoteError::InvalidArgument(format!("{what} must be {N} bytes, got {}", b.len())))
+}
+
+/// Check a Sparkle-style EdDSA signature (base64) over raw bytes with a base64 public key
+pub fn verify_bytes(data: &[u8], signature_b64: &str, public_key_b64: &str) -> Result<bool, AudioRemoteError> {
+    let key_bytes = decode_fixed::<32>(public_key_b64, "public key")?;
+    let key = VerifyingKey::from_bytes(&key_bytes).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid public key: {e}")))?;
+    let signature = Signature::from_bytes(&decode_fixed::<64>(signature_b64, "signature")?);
+    Ok(key.verify_strict(data, &signature).is_ok())
+}
+
+/// Check the EdDSA signature of a file on disk
+pub fn verify_file(path: &Path, signature_b64: &str, public_key_b64: &str) -> Result<bool, AudioRemoteError> {
+    let data = std::fs::read(path).map_err(|e| AudioRemoteError::Io(format!("can't read {}: {e}", path.display())))?;
+    verify_bytes(&data, signature_b64, public_key_b64)
+}
+
+/// Verify a downloaded update against its Ed25519 signature (Sparkle `edSignature` format)
+/// `signature_b64` is the base64 64-byte signature, `public_key` the base64 32-byte key
+/// Returns: 1 if the signature is valid, 0 if it doesn't match, -999 on error (see last_error_message)
+///
+/// # Safety
+/// All pointers must point to valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn verify_update_signature(
+    file_path_ptr: *const c_char,
+    signature_ptr: *const c_char,
+    public_key_ptr: *const c_char,
+) -> i32 {
+    guard("verify_update_signature", -999, || {
+        let result = str_arg(file_path_ptr, "file_path").and_then(|path| {
+            let signature = str_arg(signature_ptr, "signature")?;
+            let public_key = str_arg(public_key_ptr, "public_key")?;
+            verify_file(Path::new(path), signature, public_key)
+        });
+        match record(result) {
+            Some(valid) => valid as i32,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use std::ffi::CString;
+
+    fn keypair() -> (SigningKey, String) {
+        let signing = SigningKey::from_bytes(&[7u8; 32]);
+        let public = STANDARD.encode(signing.verifying_key().as_bytes());
+        (signing, public)
+    }
+
+    #[test]
+    fn test_verify_bytes() {
+        let (signing, public) = keypair();
+        let signature = STANDARD.encode(signing.sign(b"update payload").to_bytes());
+
+        assert!(verify_bytes(b"update payload", &signature, &public).unwrap());
+        assert!(!verify_bytes(b"tampered payload", &signature, &public).unwrap());
+        assert!(verify_bytes(b"update payload", "not base64!", &public).is_err());
+        assert!(verify_bytes(b"update payload", &signature, "AAAA").is_err());
+    }
+
+    #[test]
+    fn test_verify_update_signature_file() {
+        let (signing, public) = keypair();
+        let path = std::env::temp_dir().join(format!("audioremote-sig-{}.zip", std::process::id()));
+        std::fs::write(&path, b"zip bytes").unwrap();
+        let signature = STANDARD.encode(signing.sign(b"zip bytes").to_bytes());
+
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        let c_sig = CString::new(signature).unwrap();
+        let c_key = CString::new(public).unwrap();
+        assert_eq!(unsafe { verify_update_signature(c_path.as_ptr(), c_sig.as_ptr(), c_key.as_ptr()) }, 1);
+
+        std::fs::write(&path, b"evil bytes").unwrap();
+        assert_eq!(unsafe { verify_update_signature(c_path.as_ptr(), c_sig.as_ptr(), c_key.as_ptr()) }, 0);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(unsafe { verify_update_signature(c_path.as_ptr(), c_sig.as_ptr(), c_key.as_ptr()) }, -999);
+    }
+}
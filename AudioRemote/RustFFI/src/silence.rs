@@ -0,0 +1,348 @@
+//! Silence detection, so the app can pause metering, drop the stream's
+//! bitrate or tell remotes the Mac is idle while nothing is playing. The tap's
+//! audio is measured in short windows; once every window has been under the
+//! RMS threshold for `holdSeconds` the detector turns silent, and the first
+//! window over it turns it back at once. Time is counted in frames processed,
+//! not by the clock, so a tap that stops delivering buffers stops the count.
+
+use std::ffi::{c_char, c_void};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::handle::{Handle, Registry};
+use crate::runtime::{self, SendPtr};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+
+/// Values are part of the C ABI (ArSilenceState)
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SilenceState {
+    Sound = 0,
+    Silent = 1,
+}
+
+/// (detector, ArSilenceState, seconds, ctx): on turning silent, how long it's been quiet; on sound
+/// returning, how long the silence lasted
+pub type SilenceCallback = extern "C" fn(detector: Handle, state: i32, seconds: f64, ctx: *mut c_void);
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SilenceConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default = "default_threshold_db")]
+    pub threshold_db: f64,
+    #[serde(default = "default_hold_seconds")]
+    pub hold_seconds: f64,
+    #[serde(default = "default_window_ms")]
+    pub window_ms: f64,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> f64 {
+    48_000.0
+}
+
+/// Under any real recording's noise floor, over dither
+fn default_threshold_db() -> f64 {
+    -60.0
+}
+
+fn default_hold_seconds() -> f64 {
+    10.0
+}
+
+fn default_window_ms() -> f64 {
+    50.0
+}
+
+impl Default for SilenceConfig {
+    fn default() -> Self {
+        Self {
+            channels: default_channels(),
+            sample_rate: default_sample_rate(),
+            threshold_db: default_threshold_db(),
+            hold_seconds: default_hold_seconds(),
+            window_ms: default_window_ms(),
+        }
+    }
+}
+
+/// A change of state, and the seconds for the callback
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Change {
+    pub state: SilenceState,
+    pub seconds: f64,
+}
+
+pub struct SilenceDetector {
+    channels: usize,
+    sample_rate: f64,
+    /// The threshold as a mean square
+    threshold: f64,
+    hold_frames: u64,
+    window_frames: usize,
+    /// The window being measured
+    frames: usize,
+    power: f64,
+    /// Frames in a row in quiet windows
+    quiet_frames: u64,
+    state: SilenceState,
+}
+
+impl SilenceDetector {
+    pub fn new(config: &SilenceConfig) -> Result<Self, AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=MAX_CHANNELS).contains(&config.channels) {
+            return invalid(format!("channels {} is outside 1–{MAX_CHANNELS}", config.channels));
+        }
+        if !(8000.0..=768_000.0).contains(&config.sample_rate) {
+            return invalid(format!("sampleRate {} is outside 8000–768000", config.sample_rate));
+        }
+        if !(-120.0..=0.0).contains(&config.threshold_db) {
+            return invalid(format!("thresholdDb {} is outside -120–0", config.threshold_db));
+        }
+        if !(0.0..=3600.0).contains(&config.hold_seconds) {
+            return invalid(format!("holdSeconds {} is outside 0–3600", config.hold_seconds));
+        }
+        if !(1.0..=1000.0).contains(&config.window_ms) {
+            return invalid(format!("windowMs {} is outside 1–1000", config.window_ms));
+        }
+        Ok(Self {
+            channels: config.channels as usize,
+            sample_rate: config.sample_rate,
+            threshold: 10f64.powf(config.threshold_db / 10.0),
+            hold_frames: (config.hold_seconds * config.sample_rate).round() as u64,
+            window_frames: ((config.window_ms * config.sample_rate / 1000.0).round() as usize).max(1),
+            frames: 0,
+            power: 0.0,
+            quiet_frames: 0,
+            state: SilenceState::Sound,
+        })
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    pub fn state(&self) -> SilenceState {
+        self.state
+    }
+
+    /// Measure interleaved samples; a partial frame at the end is ignored and samples that aren't
+    /// finite count as silence. Returns the state changes, in order.
+    pub fn process(&mut self, samples: &[f32]) -> Vec<Change> {
+        let mut changes = vec![];
+        for frame in samples.chunks_exact(self.channels) {
+            self.power += frame.iter().filter(|sample| sample.is_finite()).map(|&sample| f64::from(sample).powi(2)).sum::<f64>();
+            self.frames += 1;
+            if self.frames == self.window_frames {
+                changes.extend(self.end_window());
+            }
+        }
+        changes
+    }
+
+    fn end_window(&mut self) -> Option<Change> {
+        let quiet = self.power < self.threshold * (self.frames * self.channels) as f64;
+        let frames = self.frames as u64;
+        (self.frames, self.power) = (0, 0.0);
+        let seconds = |frames: u64| frames as f64 / self.sample_rate;
+        match (quiet, self.state) {
+            (true, state) => {
+                self.quiet_frames += frames;
+                (state == SilenceState::Sound && self.quiet_frames >= self.hold_frames).then(|| {
+                    self.state = SilenceState::Silent;
+                    Change { state: SilenceState::Silent, seconds: seconds(self.quiet_frames) }
+                })
+            }
+            (false, SilenceState::Silent) => {
+                let lasted = std::mem::take(&mut self.quiet_frames);
+                self.state = SilenceState::Sound;
+                Some(Change { state: SilenceState::Sound, seconds: seconds(lasted) })
+            }
+            (false, SilenceState::Sound) => {
+                self.quiet_frames = 0;
+                None
+            }
+        }
+    }
+
+    /// Back to sound, with nothing measured
+    pub fn reset(&mut self) {
+        (self.frames, self.power, self.quiet_frames, self.state) = (0, 0.0, 0, SilenceState::Sound);
+    }
+}
+
+struct Detector {
+    silence: SilenceDetector,
+    callback: Option<SilenceCallback>,
+    ctx: SendPtr,
+}
+
+static DETECTORS: Registry<Mutex<Detector>> = Registry::new("silence detector");
+
+fn with_detector<T>(handle: Handle, body: impl FnOnce(&mut Detector) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let detector = DETECTORS.get(handle)?;
+    let mut detector = detector.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut detector)
+}
+
+/// Create a silence detector. `config_json` (nullable): {"channels" (default 2), "sampleRate"
+/// (default 48000), "thresholdDb" (RMS, default -60), "holdSeconds" (default 10), "windowMs"
+/// (default 50)}. `callback` (nullable) hears each change of state.
+/// Returns: a handle (free with ar_silence_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string; `ctx` is passed to `callback` as is.
+#[no_mangle]
+pub unsafe extern "C" fn ar_silence_new(config_json: *const c_char, callback: Option<SilenceCallback>, ctx: *mut c_void) -> Handle {
+    guard("ar_silence_new", 0, || {
+        let config = match config_json.is_null() {
+            true => Ok(SilenceConfig::default()),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid silence config: {e}")))
+            }),
+        };
+        match record(config.and_then(|config| SilenceDetector::new(&config))) {
+            Some(detector) => DETECTORS.insert(Mutex::new(Detector { silence: detector, callback, ctx: SendPtr(ctx) })),
+            None => 0,
+        }
+    })
+}
+
+/// Measure `frames` frames of interleaved float samples; changes of state are reported to the
+/// callback, on the callback queue if one is set, after the detector is unlocked
+/// Returns: the ArSilenceState afterwards, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_silence_process(detector: Handle, samples: *const f32, frames: usize) -> i32 {
+    guard("ar_silence_process", -999, || {
+        let result = with_detector(detector, |entry| {
+            let len = frames
+                .checked_mul(entry.silence.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len > 0 && samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            let changes = match len {
+                0 => vec![],
+                len => entry.silence.process(std::slice::from_raw_parts(samples, len)),
+            };
+            Ok((changes, entry.silence.state(), entry.callback, entry.ctx))
+        });
+        match record(result) {
+            Some((changes, state, callback, ctx)) => {
+                if let Some(callback) = callback {
+                    for change in changes {
+                        runtime::deliver(move || callback(detector, change.state as i32, change.seconds, ctx.get()));
+                    }
+                }
+                state as i32
+            }
+            None => -999,
+        }
+    })
+}
+
+/// Returns: the ArSilenceState, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_silence_state(detector: Handle) -> i32 {
+    guard("ar_silence_state", -999, || {
+        record(with_detector(detector, |entry| Ok(entry.silence.state()))).map_or(-999, |state| state as i32)
+    })
+}
+
+/// Start over as sound, without a callback, e.g. when the output device changes
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_silence_reset(detector: Handle) -> i32 {
+    guard("ar_silence_reset", -999, || {
+        record(with_detector(detector, |entry| {
+            entry.silence.reset();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Release a detector from ar_silence_new; the handle is invalid afterwards and no more callbacks follow
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_silence_free(detector: Handle) -> i32 {
+    guard("ar_silence_free", -999, || record(DETECTORS.remove(detector)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 48_000.0;
+
+    #[test]
+    fn test_detection() {
+        let config = SilenceConfig { channels: 1, hold_seconds: 2.0, ..SilenceConfig::default() };
+        let mut detector = SilenceDetector::new(&config).unwrap();
+        let second = RATE as usize;
+        // -50 dBFS noise-ish audio is sound; -70 dBFS is quiet
+        let sound = vec![0.003f32; second];
+        let quiet: Vec<f32> = (0..second).map(|i| if i % 2 == 0 { 0.0003 } else { -0.0003 }).collect();
+
+        assert!(detector.process(&sound).is_empty());
+        assert!(detector.process(&quiet).is_empty());
+        // A blip of sound restarts the hold
+        assert!(detector.process(&sound[..2400]).is_empty());
+        assert!(detector.process(&quiet).is_empty());
+        let changes = detector.process(&quiet);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].state, SilenceState::Silent);
+        assert!((changes[0].seconds - 2.0).abs() < 0.06, "{changes:?}");
+        assert!(detector.process(&quiet).is_empty());
+
+        // Sound returns within one window, with how long the silence lasted
+        let changes = detector.process(&sound[..2400]);
+        assert_eq!(changes, vec![Change { state: SilenceState::Sound, seconds: 3.0 }]);
+        assert_eq!(detector.state(), SilenceState::Sound);
+
+        detector.process(&[f32::NAN; 48_000 * 3]);
+        assert_eq!(detector.state(), SilenceState::Silent);
+        detector.reset();
+        assert_eq!(detector.state(), SilenceState::Sound);
+        assert!(SilenceDetector::new(&SilenceConfig { threshold_db: 6.0, ..SilenceConfig::default() }).is_err());
+    }
+
+    static CHANGES: Mutex<Vec<(Handle, i32, f64)>> = Mutex::new(vec![]);
+
+    extern "C" fn on_change(detector: Handle, state: i32, seconds: f64, _ctx: *mut c_void) {
+        // Calling back in from the callback doesn't deadlock
+        assert_eq!(ar_silence_state(detector), state);
+        CHANGES.lock().unwrap().push((detector, state, seconds));
+    }
+
+    #[test]
+    fn test_ffi() {
+        let _runtime = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let config = c"{\"channels\": 2, \"holdSeconds\": 0.5, \"windowMs\": 100}";
+        let detector = unsafe { ar_silence_new(config.as_ptr(), Some(on_change), std::ptr::null_mut()) };
+        assert_ne!(detector, 0);
+        let silence = vec![0.0f32; 2 * 48_000];
+        assert_eq!(unsafe { ar_silence_process(detector, silence.as_ptr(), 48_000) }, 1);
+        let sound = vec![0.5f32; 2 * 4800];
+        assert_eq!(unsafe { ar_silence_process(detector, sound.as_ptr(), 4800) }, 0);
+        assert_eq!(*CHANGES.lock().unwrap(), [(detector, 1, 0.5), (detector, 0, 1.0)]);
+        assert_eq!(unsafe { ar_silence_process(detector, std::ptr::null(), 1) }, -999);
+        assert_eq!(ar_silence_reset(detector), 1);
+        assert_eq!(ar_silence_free(detector), 1);
+        assert_eq!(ar_silence_state(detector), -999);
+    }
+}
@@ -0,0 +1,388 @@
+//! Four-lane float vectors for the per-callback DSP loops (metering, mixing,
+//! limiting), NEON on Apple silicon and plain arrays elsewhere, which the
+//! compiler turns into SSE on Intel. The two give the same results for finite
+//! samples: there's no fused multiply-add, and sums reduce pairwise in both.
+//! They part ways on NaN, which NEON's min and max pass on and `f32::min` and
+//! `f32::max` drop, so the kernels zero samples that aren't finite first.
+//!
+//! Audio runs per frame, and each frame's channels are a lane each, so 8
+//! channels is two vectors. Channel counts that aren't a multiple of four end
+//! with a scalar tail, and the kernels here take any length.
+
+#[cfg(target_arch = "aarch64")]
+pub use neon::F32x4;
+#[cfg(not(target_arch = "aarch64"))]
+pub use portable::F32x4;
+
+pub const LANES: usize = 4;
+
+#[cfg(target_arch = "aarch64")]
+mod neon {
+    use std::arch::aarch64::*;
+    use std::ops::{Add, Mul, Sub};
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct F32x4(float32x4_t);
+
+    // NEON is part of the aarch64 baseline, so these intrinsics are always there
+    impl F32x4 {
+        #[inline(always)]
+        pub fn splat(value: f32) -> Self {
+            Self(unsafe { vdupq_n_f32(value) })
+        }
+
+        /// The first four of `values`
+        #[inline(always)]
+        pub fn load(values: &[f32]) -> Self {
+            assert!(values.len() >= super::LANES);
+            Self(unsafe { vld1q_f32(values.as_ptr()) })
+        }
+
+        /// Into the first four of `out`
+        #[inline(always)]
+        pub fn store(self, out: &mut [f32]) {
+            assert!(out.len() >= super::LANES);
+            unsafe { vst1q_f32(out.as_mut_ptr(), self.0) }
+        }
+
+        #[inline(always)]
+        pub fn max(self, other: Self) -> Self {
+            Self(unsafe { vmaxq_f32(self.0, other.0) })
+        }
+
+        #[inline(always)]
+        pub fn min(self, other: Self) -> Self {
+            Self(unsafe { vminq_f32(self.0, other.0) })
+        }
+
+        #[inline(always)]
+        pub fn abs(self) -> Self {
+            Self(unsafe { vabsq_f32(self.0) })
+        }
+
+        #[inline(always)]
+        pub fn sqrt(self) -> Self {
+            Self(unsafe { vsqrtq_f32(self.0) })
+        }
+
+        /// NaN and ±infinity become 0
+        #[inline(always)]
+        pub fn finite_or_zero(self) -> Self {
+            unsafe {
+                let finite = vcltq_f32(vabsq_f32(self.0), vdupq_n_f32(f32::INFINITY));
+                Self(vbslq_f32(finite, self.0, vdupq_n_f32(0.0)))
+            }
+        }
+
+        /// Each lane of `then` where this is greater than `other`, else of `otherwise`
+        #[inline(always)]
+        pub fn select_gt(self, other: Self, then: Self, otherwise: Self) -> Self {
+            Self(unsafe { vbslq_f32(vcgtq_f32(self.0, other.0), then.0, otherwise.0) })
+        }
+
+        #[inline(always)]
+        pub fn sum(self) -> f32 {
+            unsafe {
+                let pairs = vpaddq_f32(self.0, self.0);
+                vgetq_lane_f32(pairs, 0) + vgetq_lane_f32(pairs, 1)
+            }
+        }
+
+        #[inline(always)]
+        pub fn max_lane(self) -> f32 {
+            unsafe { vmaxvq_f32(self.0) }
+        }
+    }
+
+    impl Add for F32x4 {
+        type Output = Self;
+
+        #[inline(always)]
+        fn add(self, other: Self) -> Self {
+            Self(unsafe { vaddq_f32(self.0, other.0) })
+        }
+    }
+
+    impl Sub for F32x4 {
+        type Output = Self;
+
+        #[inline(always)]
+        fn sub(self, other: Self) -> Self {
+            Self(unsafe { vsubq_f32(self.0, other.0) })
+        }
+    }
+
+    impl Mul for F32x4 {
+        type Output = Self;
+
+        #[inline(always)]
+        fn mul(self, other: Self) -> Self {
+            Self(unsafe { vmulq_f32(self.0, other.0) })
+        }
+    }
+}
+
+#[cfg(not(target_arch = "aarch64"))]
+mod portable {
+    use std::ops::{Add, Mul, Sub};
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct F32x4([f32; 4]);
+
+    impl F32x4 {
+        #[inline(always)]
+        fn map(self, f: impl Fn(f32) -> f32) -> Self {
+            Self(self.0.map(f))
+        }
+
+        #[inline(always)]
+        fn zip(self, other: Self, f: impl Fn(f32, f32) -> f32) -> Self {
+            Self([f(self.0[0], other.0[0]), f(self.0[1], other.0[1]), f(self.0[2], other.0[2]), f(self.0[3], other.0[3])])
+        }
+
+        #[inline(always)]
+        pub fn splat(value: f32) -> Self {
+            Self([value; 4])
+        }
+
+        /// The first four of `values`
+        #[inline(always)]
+        pub fn load(values: &[f32]) -> Self {
+            Self([values[0], values[1], values[2], values[3]])
+        }
+
+        /// Into the first four of `out`
+        #[inline(always)]
+        pub fn store(self, out: &mut [f32]) {
+            out[..4].copy_from_slice(&self.0);
+        }
+
+        #[inline(always)]
+        pub fn max(self, other: Self) -> Self {
+            self.zip(other, f32::max)
+        }
+
+        #[inline(always)]
+        pub fn min(self, other: Self) -> Self {
+            self.zip(other, f32::min)
+        }
+
+        #[inline(always)]
+        pub fn abs(self) -> Self {
+            self.map(f32::abs)
+        }
+
+        #[inline(always)]
+        pub fn sqrt(self) -> Self {
+            self.map(f32::sqrt)
+        }
+
+        /// NaN and ±infinity become 0
+        #[inline(always)]
+        pub fn finite_or_zero(self) -> Self {
+            self.map(|value| if value.is_finite() { value } else { 0.0 })
+        }
+
+        /// Each lane of `then` where this is greater than `other`, else of `otherwise`
+        #[inline(always)]
+        pub fn select_gt(self, other: Self, then: Self, otherwise: Self) -> Self {
+            let pick = |i: usize| if self.0[i] > other.0[i] { then.0[i] } else { otherwise.0[i] };
+            Self([pick(0), pick(1), pick(2), pick(3)])
+        }
+
+        #[inline(always)]
+        pub fn sum(self) -> f32 {
+            (self.0[0] + self.0[1]) + (self.0[2] + self.0[3])
+        }
+
+        #[inline(always)]
+        pub fn max_lane(self) -> f32 {
+            self.0[0].max(self.0[1]).max(self.0[2].max(self.0[3]))
+        }
+    }
+
+    impl Add for F32x4 {
+        type Output = Self;
+
+        #[inline(always)]
+        fn add(self, other: Self) -> Self {
+            self.zip(other, |a, b| a + b)
+        }
+    }
+
+    impl Sub for F32x4 {
+        type Output = Self;
+
+        #[inline(always)]
+        fn sub(self, other: Self) -> Self {
+            self.zip(other, |a, b| a - b)
+        }
+    }
+
+    impl Mul for F32x4 {
+        type Output = Self;
+
+        #[inline(always)]
+        fn mul(self, other: Self) -> Self {
+            self.zip(other, |a, b| a * b)
+        }
+    }
+}
+
+/// The sum of `gains` × `samples`, pairwise, with samples that aren't finite as 0
+#[inline]
+pub fn dot_finite(gains: &[f32], samples: &[f32]) -> f32 {
+    let len = gains.len().min(samples.len());
+    let split = len - len % LANES;
+    let mut sum = F32x4::splat(0.0);
+    for (gains, samples) in gains[..split].chunks_exact(LANES).zip(samples[..split].chunks_exact(LANES)) {
+        sum = sum + F32x4::load(gains) * F32x4::load(samples).finite_or_zero();
+    }
+    let mut sum = sum.sum();
+    for (gain, &sample) in gains[split..len].iter().zip(&samples[split..len]) {
+        sum += if sample.is_finite() { gain * sample } else { 0.0 };
+    }
+    sum
+}
+
+/// `input` × `gain` into `out`, with samples that aren't finite as 0; returns the highest |result|
+#[inline]
+pub fn scale_finite_peak(input: &[f32], gain: f32, out: &mut [f32]) -> f32 {
+    let len = input.len().min(out.len());
+    let split = len - len % LANES;
+    let (gain4, mut peak) = (F32x4::splat(gain), F32x4::splat(0.0));
+    for (input, out) in input[..split].chunks_exact(LANES).zip(out[..split].chunks_exact_mut(LANES)) {
+        let scaled = F32x4::load(input).finite_or_zero() * gain4;
+        peak = peak.max(scaled.abs());
+        scaled.store(out);
+    }
+    let mut peak = peak.max_lane();
+    for (&sample, out) in input[split..len].iter().zip(&mut out[split..len]) {
+        *out = if sample.is_finite() { sample * gain } else { 0.0 };
+        peak = peak.max(out.abs());
+    }
+    peak
+}
+
+/// `input` × `gain`, clamped to ±`limit`, into `out`, with samples that aren't finite as 0
+#[inline]
+pub fn scale_clamp(input: &[f32], gain: f32, limit: f32, out: &mut [f32]) {
+    let len = input.len().min(out.len());
+    let split = len - len % LANES;
+    let (gain4, high, low) = (F32x4::splat(gain), F32x4::splat(limit), F32x4::splat(-limit));
+    for (input, out) in input[..split].chunks_exact(LANES).zip(out[..split].chunks_exact_mut(LANES)) {
+        (F32x4::load(input).finite_or_zero() * gain4).min(high).max(low).store(out);
+    }
+    for (&sample, out) in input[split..len].iter().zip(&mut out[split..len]) {
+        *out = if sample.is_finite() { sample * gain } else { 0.0 }.min(limit).max(-limit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Awkward values: a tail past the lanes, NaN, infinities and negative zero
+    fn samples(len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| match i % 7 {
+                3 => f32::NAN,
+                5 if i % 2 == 1 => f32::NEG_INFINITY,
+                6 => -0.0,
+                _ => (i as f32 * 0.37).sin() * 1.5,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_kernels_match_scalar() {
+        for len in [0, 1, 3, 4, 6, 8, 11, 64] {
+            let input = samples(len);
+            let finite: Vec<f32> = input.iter().map(|&sample| if sample.is_finite() { sample } else { 0.0 }).collect();
+            let gains: Vec<f32> = (0..len).map(|i| 1.0 / (i + 1) as f32).collect();
+
+            let expected: f32 = gains.iter().zip(&finite).map(|(gain, sample)| gain * sample).sum();
+            assert!((dot_finite(&gains, &input) - expected).abs() < 1e-5, "{len}");
+
+            let mut out = vec![9.0; len];
+            let peak = scale_finite_peak(&input, 2.0, &mut out);
+            assert_eq!(out, finite.iter().map(|sample| sample * 2.0).collect::<Vec<_>>(), "{len}");
+            assert_eq!(peak, out.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs())));
+
+            let mut clamped = vec![9.0; len];
+            scale_clamp(&finite, 1.5, 1.0, &mut clamped);
+            assert_eq!(clamped, finite.iter().map(|sample| (sample * 1.5).clamp(-1.0, 1.0)).collect::<Vec<_>>(), "{len}");
+        }
+    }
+
+    #[test]
+    fn test_scale_clamp_zeroes_samples_that_arent_finite() {
+        // Lanes and tail alike; without zeroing, NaN came out as +limit here and as NaN on NEON
+        let input = [f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 0.5, -f32::NAN, f32::INFINITY, 2.0];
+        let mut out = [9.0; 7];
+        scale_clamp(&input, 1.5, 1.0, &mut out);
+        assert_eq!(out, [0.0, 0.0, 0.0, 0.75, 0.0, 0.0, 1.0]);
+    }
+
+    /// Real time at 96 kHz with 8 channels, in 512-frame callbacks as the tap delivers them; run
+    /// with `cargo test --release simd -- --ignored --nocapture` for the figures
+    #[test]
+    #[ignore = "a benchmark"]
+    fn bench_realtime_headroom() {
+        use std::time::{Duration, Instant};
+
+        use crate::limiter::{Limiter, LimiterConfig};
+        use crate::meter::{Meter, MeterConfig};
+        use crate::mixer::{Mixer, MixerConfig};
+
+        const RATE: usize = 96_000;
+        const CHANNELS: usize = 8;
+        const SECONDS: usize = 10;
+        let audio: Vec<f32> = (0..RATE * CHANNELS).map(|i| ((i as f32 * 0.013).sin() * 1.4).clamp(-1.0, 1.0)).collect();
+        let time = |name: &str, run: &mut dyn FnMut(&[f32])| {
+            let started = Instant::now();
+            for _ in 0..SECONDS {
+                audio.chunks(512 * CHANNELS).for_each(&mut *run);
+            }
+            let per_second = started.elapsed() / SECONDS as u32;
+            println!("{name}: {per_second:?} per second of audio, {:.0}× real time", Duration::from_secs(1).as_secs_f64() / per_second.as_secs_f64());
+            per_second
+        };
+
+        let mut meter = Meter::new(&MeterConfig { channels: CHANNELS as u32, sample_rate: RATE as f64, ..MeterConfig::default() }).unwrap();
+        let metering = time("meter", &mut |block| meter.process(block));
+        let mixer = Mixer::new(&MixerConfig { input_channels: CHANNELS, output_channels: 2, matrix: None, mono: true, normalize: true }).unwrap();
+        let mut output = vec![0.0; 512 * 2];
+        let mixing = time("mixer 8 → 2", &mut |block| _ = mixer.process(block, &mut output));
+        let config = LimiterConfig { channels: CHANNELS as u32, sample_rate: RATE as f64, gain_db: 6.0, ..LimiterConfig::default() };
+        let mut limiter = Limiter::new(config.clone()).unwrap();
+        let mut scratch = vec![0.0; 512 * CHANNELS];
+        let limiting = time("limiter", &mut |block| {
+            scratch[..block.len()].copy_from_slice(block);
+            limiter.process(&mut scratch[..block.len()]);
+        });
+        let mut limiter = Limiter::new(LimiterConfig { true_peak: true, ..config }).unwrap();
+        let true_peak = time("limiter, true peak", &mut |block| {
+            scratch[..block.len()].copy_from_slice(block);
+            limiter.process(&mut scratch[..block.len()]);
+        });
+
+        // The whole chain in a tenth of the callback, leaving the rest for everything else; an
+        // unoptimised build only prints
+        let total = metering + mixing + limiting + true_peak;
+        assert!(cfg!(debug_assertions) || total < Duration::from_millis(100), "{total:?}");
+    }
+
+    #[test]
+    fn test_lanes() {
+        let value = F32x4::load(&[1.0, -4.0, f32::NAN, f32::INFINITY]);
+        let mut out = [0.0; 4];
+        value.finite_or_zero().abs().sqrt().store(&mut out);
+        assert_eq!(out, [1.0, 2.0, 0.0, 0.0]);
+        let (low, high) = (F32x4::splat(-1.0), F32x4::splat(1.0));
+        value.finite_or_zero().select_gt(F32x4::splat(0.0), high, low).store(&mut out);
+        assert_eq!(out, [1.0, -1.0, -1.0, -1.0]);
+        assert_eq!(F32x4::load(&[1.0, 2.0, 3.0, 4.5]).sum(), 10.5);
+        assert_eq!(F32x4::load(&[1.0, 7.0, -3.0, 4.5]).max_lane(), 7.0);
+    }
+}
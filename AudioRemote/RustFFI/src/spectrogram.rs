@@ -0,0 +1,345 @@
+//! A rolling spectrogram for the diagnostics window, so a "no sound" report
+//! can be checked against what the audio tap is actually receiving. Samples go
+//! through a `spectrum::Analyzer` with one band per image row and no
+//! smoothing; each analysis (every `fftSize` / 2 frames) becomes a column, and
+//! the last `width` columns are kept.
+//!
+//! Rendering is on demand: an RGBA image, `width` × `height`, with the newest
+//! column on the right and the highest frequency at the top. Levels over
+//! `floorDb`–0 dB are coloured black through purple, red and orange to pale
+//! yellow; columns not yet filled are black, like silence.
+
+use std::collections::VecDeque;
+use std::ffi::c_char;
+use std::sync::Mutex;
+
+use serde::Deserialize;
+
+use crate::handle::{Handle, Registry};
+use crate::spectrum::{Analyzer, SpectrumConfig};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_WIDTH: usize = 4096;
+const MAX_HEIGHT: usize = 256;
+
+/// The colour map's stops, from level 0.0 to 1.0 evenly
+const PALETTE: [[f32; 3]; 5] = [[0.0, 0.0, 0.0], [40.0, 0.0, 90.0], [180.0, 30.0, 80.0], [250.0, 140.0, 20.0], [255.0, 255.0, 200.0]];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SpectrogramConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// A power of two; also sets the time per column, half of it
+    #[serde(default = "default_fft_size")]
+    pub fft_size: usize,
+    /// Columns kept
+    #[serde(default = "default_width")]
+    pub width: usize,
+    /// Log-spaced frequency rows
+    #[serde(default = "default_height")]
+    pub height: usize,
+    #[serde(default = "default_min_hz")]
+    pub min_hz: f64,
+    #[serde(default = "default_max_hz")]
+    pub max_hz: f64,
+    #[serde(default = "default_floor_db")]
+    pub floor_db: f32,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> f64 {
+    48_000.0
+}
+
+fn default_fft_size() -> usize {
+    1024
+}
+
+fn default_width() -> usize {
+    512
+}
+
+fn default_height() -> usize {
+    128
+}
+
+fn default_min_hz() -> f64 {
+    20.0
+}
+
+fn default_max_hz() -> f64 {
+    20_000.0
+}
+
+fn default_floor_db() -> f32 {
+    -100.0
+}
+
+impl Default for SpectrogramConfig {
+    fn default() -> Self {
+        Self {
+            channels: default_channels(),
+            sample_rate: default_sample_rate(),
+            fft_size: default_fft_size(),
+            width: default_width(),
+            height: default_height(),
+            min_hz: default_min_hz(),
+            max_hz: default_max_hz(),
+            floor_db: default_floor_db(),
+        }
+    }
+}
+
+pub struct Spectrogram {
+    analyzer: Analyzer,
+    width: usize,
+    height: usize,
+    /// Each column's levels, lowest row first, oldest column first
+    columns: VecDeque<Vec<f32>>,
+}
+
+/// A level's colour, interpolated between the palette's stops
+fn colour(level: f32) -> [u8; 4] {
+    let position = level.clamp(0.0, 1.0) * (PALETTE.len() - 1) as f32;
+    let index = (position as usize).min(PALETTE.len() - 2);
+    let t = position - index as f32;
+    let (low, high) = (PALETTE[index], PALETTE[index + 1]);
+    let channel = |i: usize| (low[i] + (high[i] - low[i]) * t).round() as u8;
+    [channel(0), channel(1), channel(2), 255]
+}
+
+impl Spectrogram {
+    pub fn new(config: &SpectrogramConfig) -> Result<Self, AudioRemoteError> {
+        if !(1..=MAX_WIDTH).contains(&config.width) {
+            return Err(AudioRemoteError::InvalidArgument(format!("width {} is outside 1–{MAX_WIDTH}", config.width)));
+        }
+        if !(1..=MAX_HEIGHT).contains(&config.height) {
+            return Err(AudioRemoteError::InvalidArgument(format!("height {} is outside 1–{MAX_HEIGHT}", config.height)));
+        }
+        let analyzer = Analyzer::new(&SpectrumConfig {
+            channels: config.channels,
+            sample_rate: config.sample_rate,
+            fft_size: config.fft_size,
+            bands: config.height,
+            min_hz: config.min_hz,
+            max_hz: config.max_hz,
+            smoothing: 0.0,
+            floor_db: config.floor_db,
+        })?;
+        Ok(Self { analyzer, width: config.width, height: config.height, columns: VecDeque::with_capacity(config.width) })
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.analyzer.channel_count()
+    }
+
+    /// Add interleaved samples, a column for every half window
+    pub fn process(&mut self, samples: &[f32]) {
+        let (columns, width) = (&mut self.columns, self.width);
+        self.analyzer.process_each(samples, |levels| {
+            let mut column = match columns.len() == width {
+                true => columns.pop_front().unwrap_or_default(),
+                false => Vec::with_capacity(levels.len()),
+            };
+            column.clear();
+            column.extend_from_slice(levels);
+            columns.push_back(column);
+        });
+    }
+
+    /// The image's size in bytes
+    pub fn image_len(&self) -> usize {
+        self.width * self.height * 4
+    }
+
+    /// Draw the image into `out`, `image_len` bytes, row by row from the top
+    pub fn render(&self, out: &mut [u8]) {
+        let blank = self.width - self.columns.len();
+        for (row, pixels) in out.chunks_exact_mut(self.width * 4).enumerate() {
+            let band = self.height - 1 - row;
+            for (x, pixel) in pixels.chunks_exact_mut(4).enumerate() {
+                let level = match x.checked_sub(blank) {
+                    Some(column) => self.columns[column][band],
+                    None => 0.0,
+                };
+                pixel.copy_from_slice(&colour(level));
+            }
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.analyzer.reset();
+        self.columns.clear();
+    }
+}
+
+static SPECTROGRAMS: Registry<Mutex<Spectrogram>> = Registry::new("spectrogram");
+
+fn with_spectrogram<T>(handle: Handle, body: impl FnOnce(&mut Spectrogram) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let spectrogram = SPECTROGRAMS.get(handle)?;
+    let mut spectrogram = spectrogram.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut spectrogram)
+}
+
+/// Create a spectrogram. `config_json` (nullable): {"channels" (default 2), "sampleRate"
+/// (default 48000), "fftSize" (default 1024), "width" (columns, default 512), "height" (rows,
+/// default 128), "minHz" (default 20), "maxHz" (default 20000), "floorDb" (default -100)}
+/// Returns: a handle (free with ar_spectrogram_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_spectrogram_new(config_json: *const c_char) -> Handle {
+    guard("ar_spectrogram_new", 0, || {
+        let config = match config_json.is_null() {
+            true => Ok(SpectrogramConfig::default()),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid spectrogram config: {e}")))
+            }),
+        };
+        match record(config.and_then(|config| Spectrogram::new(&config))) {
+            Some(spectrogram) => SPECTROGRAMS.insert(Mutex::new(spectrogram)),
+            None => 0,
+        }
+    })
+}
+
+/// Add `frames` frames of interleaved float samples, one per channel per frame
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_spectrogram_process(spectrogram: Handle, samples: *const f32, frames: usize) -> i32 {
+    guard("ar_spectrogram_process", -999, || {
+        let result = with_spectrogram(spectrogram, |spectrogram| {
+            let len = frames
+                .checked_mul(spectrogram.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len == 0 {
+                return Ok(1);
+            }
+            if samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            spectrogram.process(std::slice::from_raw_parts(samples, len));
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Render the spectrogram as RGBA into `out_rgba` if `capacity` is at least width × height × 4
+/// bytes; pass null and 0 to get the size
+/// Returns: the image's size in bytes, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `out_rgba` must have room for `capacity` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ar_spectrogram_render(spectrogram: Handle, out_rgba: *mut u8, capacity: usize) -> i32 {
+    guard("ar_spectrogram_render", -999, || {
+        let result = with_spectrogram(spectrogram, |spectrogram| {
+            let len = spectrogram.image_len();
+            if capacity >= len {
+                if out_rgba.is_null() {
+                    return Err(AudioRemoteError::InvalidArgument("out_rgba must not be null".into()));
+                }
+                spectrogram.render(std::slice::from_raw_parts_mut(out_rgba, len));
+            }
+            Ok(len as i32)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Clear the image and drop the audio, e.g. when the tap restarts
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_spectrogram_reset(spectrogram: Handle) -> i32 {
+    guard("ar_spectrogram_reset", -999, || {
+        record(with_spectrogram(spectrogram, |spectrogram| {
+            spectrogram.reset();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Release a spectrogram from ar_spectrogram_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_spectrogram_free(spectrogram: Handle) -> i32 {
+    guard("ar_spectrogram_free", -999, || record(SPECTROGRAMS.remove(spectrogram)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(hz: f64, frames: usize) -> Vec<f32> {
+        (0..frames).map(|i| (2.0 * std::f64::consts::PI * hz * i as f64 / 48_000.0).sin() as f32).collect()
+    }
+
+    fn pixel(image: &[u8], width: usize, x: usize, y: usize) -> [u8; 4] {
+        image[(y * width + x) * 4..][..4].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_rolling_image() {
+        let config = SpectrogramConfig { channels: 1, fft_size: 512, width: 8, height: 16, ..SpectrogramConfig::default() };
+        let mut spectrogram = Spectrogram::new(&config).unwrap();
+        let mut image = vec![0; spectrogram.image_len()];
+        assert_eq!(image.len(), 8 * 16 * 4);
+
+        // Four columns of a loud tone, drawn on the right; the rest is still black
+        spectrogram.process(&sine(1000.0, 1024));
+        spectrogram.render(&mut image);
+        let loudest = |image: &[u8], x: usize| (0..16).max_by_key(|&y| pixel(image, 8, x, y)[0] as u32 + pixel(image, 8, x, y)[1] as u32).unwrap();
+        assert_eq!(pixel(&image, 8, 0, 8), [0, 0, 0, 255]);
+        assert_eq!(pixel(&image, 8, 3, 8), [0, 0, 0, 255]);
+        let row = loudest(&image, 7);
+        let [red, green, _, _] = pixel(&image, 8, 7, row);
+        assert!(red == 255 && green > 240, "{red} {green}");
+        assert!(row > 3 && row < 12, "{row}");
+
+        // A higher tone scrolls in above it and pushes the oldest columns out
+        spectrogram.process(&sine(8000.0, 2048));
+        spectrogram.render(&mut image);
+        assert!(loudest(&image, 7) < row);
+        assert!((0..8).all(|x| pixel(&image, 8, x, 0)[3] == 255));
+
+        spectrogram.reset();
+        spectrogram.render(&mut image);
+        assert!(image.chunks_exact(4).all(|pixel| pixel == [0, 0, 0, 255]));
+
+        assert!(Spectrogram::new(&SpectrogramConfig { width: 0, ..SpectrogramConfig::default() }).is_err());
+        assert!(Spectrogram::new(&SpectrogramConfig { height: 1000, ..SpectrogramConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let spectrogram = unsafe { ar_spectrogram_new(c"{\"width\": 4, \"height\": 2}".as_ptr()) };
+        assert_ne!(spectrogram, 0);
+        let samples = vec![0.25f32; 4096];
+        assert_eq!(unsafe { ar_spectrogram_process(spectrogram, samples.as_ptr(), 2048) }, 1);
+        assert_eq!(unsafe { ar_spectrogram_process(spectrogram, std::ptr::null(), 1) }, -999);
+
+        assert_eq!(unsafe { ar_spectrogram_render(spectrogram, std::ptr::null_mut(), 0) }, 32);
+        let mut image = [7u8; 40];
+        assert_eq!(unsafe { ar_spectrogram_render(spectrogram, image.as_mut_ptr(), 16) }, 32);
+        assert!(image.iter().all(|&byte| byte == 7));
+        assert_eq!(unsafe { ar_spectrogram_render(spectrogram, image.as_mut_ptr(), image.len()) }, 32);
+        assert!(image[..32].chunks_exact(4).all(|pixel| pixel[3] == 255) && image[32..] == [7; 8]);
+        assert_eq!(ar_spectrogram_reset(spectrogram), 1);
+
+        assert_eq!(ar_spectrogram_free(spectrogram), 1);
+        assert_eq!(ar_spectrogram_free(spectrogram), -999);
+        assert_eq!(unsafe { ar_spectrogram_new(c"{\"fftSize\": 100}".as_ptr()) }, 0);
+    }
+}
@@ -0,0 +1,390 @@
+//! A spectrum analyzer for the remote's visualizer, so it can be drawn from a
+//! few dozen floats instead of the audio itself. Samples from the Swift tap
+//! are mixed to mono; every half window (`fftSize` / 2 frames) the latest
+//! window is Hann-windowed and transformed, and its bins are gathered into
+//! log-spaced bands between `minHz` and `maxHz`, each the loudest bin it
+//! spans (or, for a band narrower than a bin, the bin under its centre).
+//!
+//! Band levels are 0.0–1.0 over `floorDb`–0 dB, where a full-scale sine is
+//! 0 dB. They jump up with the audio and fall back with `smoothing`, the share
+//! of the last level kept each half window, so bars don't flicker.
+
+use std::collections::VecDeque;
+use std::ffi::c_char;
+use std::sync::{Arc, Mutex};
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::Deserialize;
+
+use crate::handle::{Handle, Registry};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const MAX_CHANNELS: u32 = 64;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SpectrumConfig {
+    #[serde(default = "default_channels")]
+    pub channels: u32,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// A power of two
+    #[serde(default = "default_fft_size")]
+    pub fft_size: usize,
+    #[serde(default = "default_bands")]
+    pub bands: usize,
+    #[serde(default = "default_min_hz")]
+    pub min_hz: f64,
+    /// Lowered to the Nyquist frequency if above it
+    #[serde(default = "default_max_hz")]
+    pub max_hz: f64,
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f32,
+    #[serde(default = "default_floor_db")]
+    pub floor_db: f32,
+}
+
+fn default_channels() -> u32 {
+    2
+}
+
+fn default_sample_rate() -> f64 {
+    48_000.0
+}
+
+fn default_fft_size() -> usize {
+    2048
+}
+
+fn default_bands() -> usize {
+    32
+}
+
+fn default_min_hz() -> f64 {
+    20.0
+}
+
+fn default_max_hz() -> f64 {
+    20_000.0
+}
+
+fn default_smoothing() -> f32 {
+    0.7
+}
+
+fn default_floor_db() -> f32 {
+    -90.0
+}
+
+impl Default for SpectrumConfig {
+    fn default() -> Self {
+        Self {
+            channels: default_channels(),
+            sample_rate: default_sample_rate(),
+            fft_size: default_fft_size(),
+            bands: default_bands(),
+            min_hz: default_min_hz(),
+            max_hz: default_max_hz(),
+            smoothing: default_smoothing(),
+            floor_db: default_floor_db(),
+        }
+    }
+}
+
+pub struct Analyzer {
+    channels: usize,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    /// Scales a bin's magnitude so a full-scale sine is 1.0
+    gain: f32,
+    /// Each band's first and last bin, inclusive
+    ranges: Vec<(usize, usize)>,
+    smoothing: f32,
+    floor_db: f32,
+    history: VecDeque<f32>,
+    /// Samples since the last analysis
+    pending: usize,
+    buffer: Vec<Complex<f32>>,
+    levels: Vec<f32>,
+}
+
+/// The bins each of `bands` log-spaced bands between `min_hz` and `max_hz` covers
+fn band_ranges(bands: usize, min_hz: f64, max_hz: f64, bin_hz: f64) -> Vec<(usize, usize)> {
+    let ratio = max_hz / min_hz;
+    let edge = |i: usize| min_hz * ratio.powf(i as f64 / bands as f64);
+    (0..bands)
+        .map(|i| {
+            let (low, high) = (edge(i), edge(i + 1));
+            let (first, last) = ((low / bin_hz).ceil() as usize, (high / bin_hz).floor() as usize);
+            match first <= last {
+                true => (first, last),
+                false => {
+                    let centre = ((low * high).sqrt() / bin_hz).round() as usize;
+                    (centre, centre)
+                }
+            }
+        })
+        .collect()
+}
+
+impl Analyzer {
+    pub fn new(config: &SpectrumConfig) -> Result<Self, AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1..=MAX_CHANNELS).contains(&config.channels) {
+            return invalid(format!("channels {} is outside 1–{MAX_CHANNELS}", config.channels));
+        }
+        if !(1000.0..=768_000.0).contains(&config.sample_rate) {
+            return invalid(format!("sampleRate {} is outside 1000–768000", config.sample_rate));
+        }
+        if !config.fft_size.is_power_of_two() || !(64..=16_384).contains(&config.fft_size) {
+            return invalid(format!("fftSize {} isn't a power of two from 64 to 16384", config.fft_size));
+        }
+        if !(1..=256).contains(&config.bands) {
+            return invalid(format!("bands {} is outside 1–256", config.bands));
+        }
+        let max_hz = config.max_hz.min(config.sample_rate / 2.0);
+        if !(config.min_hz > 0.0 && config.min_hz < max_hz) {
+            return invalid(format!("minHz {} must be above 0 and below maxHz {max_hz}", config.min_hz));
+        }
+        if !(0.0..1.0).contains(&config.smoothing) {
+            return invalid(format!("smoothing {} is outside 0–1", config.smoothing));
+        }
+        if !(-200.0..0.0).contains(&config.floor_db) {
+            return invalid(format!("floorDb {} is outside -200–0", config.floor_db));
+        }
+
+        let size = config.fft_size;
+        let window: Vec<f32> =
+            (0..size).map(|i| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / size as f32).cos()).collect();
+        let gain = 2.0 / window.iter().sum::<f32>();
+        let ranges = band_ranges(config.bands, config.min_hz, max_hz, config.sample_rate / size as f64);
+        Ok(Self {
+            channels: config.channels as usize,
+            fft: FftPlanner::new().plan_fft_forward(size),
+            window,
+            gain,
+            ranges,
+            smoothing: config.smoothing,
+            floor_db: config.floor_db,
+            history: VecDeque::from(vec![0.0; size]),
+            pending: 0,
+            buffer: vec![Complex::default(); size],
+            levels: vec![0.0; config.bands],
+        })
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.channels
+    }
+
+    /// Add interleaved samples, analyzing every half window; a partial frame at the end is
+    /// ignored and samples that aren't finite count as silence
+    pub fn process(&mut self, samples: &[f32]) {
+        self.process_each(samples, |_| {});
+    }
+
+    /// As `process`, handing the levels to `each` after every analysis
+    pub fn process_each(&mut self, samples: &[f32], mut each: impl FnMut(&[f32])) {
+        let hop = self.window.len() / 2;
+        for frame in samples.chunks_exact(self.channels) {
+            let sum: f32 = frame.iter().map(|&sample| if sample.is_finite() { sample } else { 0.0 }).sum();
+            self.history.pop_front();
+            self.history.push_back(sum / self.channels as f32);
+            self.pending += 1;
+            if self.pending == hop {
+                self.pending = 0;
+                self.analyze();
+                each(&self.levels);
+            }
+        }
+    }
+
+    fn analyze(&mut self) {
+        for ((slot, &sample), &weight) in self.buffer.iter_mut().zip(&self.history).zip(&self.window) {
+            *slot = Complex::new(sample * weight, 0.0);
+        }
+        self.fft.process(&mut self.buffer);
+        let (floor, smoothing) = (self.floor_db, self.smoothing);
+        for (level, &(first, last)) in self.levels.iter_mut().zip(&self.ranges) {
+            let magnitude = self.buffer[first..=last].iter().map(|bin| bin.norm()).fold(0.0f32, f32::max) * self.gain;
+            let db = 20.0 * magnitude.max(f32::MIN_POSITIVE).log10();
+            let new = ((db - floor) / -floor).clamp(0.0, 1.0);
+            *level = if new >= *level { new } else { *level * smoothing + new * (1.0 - smoothing) };
+        }
+    }
+
+    pub fn levels(&self) -> &[f32] {
+        &self.levels
+    }
+
+    /// Each band's lowest and highest frequency
+    pub fn band_edges(&self, sample_rate: f64) -> Vec<(f64, f64)> {
+        let bin_hz = sample_rate / self.window.len() as f64;
+        self.ranges.iter().map(|&(first, last)| (first as f64 * bin_hz, last as f64 * bin_hz)).collect()
+    }
+
+    pub fn reset(&mut self) {
+        self.history.iter_mut().for_each(|sample| *sample = 0.0);
+        self.pending = 0;
+        self.levels.fill(0.0);
+    }
+}
+
+static ANALYZERS: Registry<Mutex<Analyzer>> = Registry::new("spectrum");
+
+fn with_analyzer<T>(handle: Handle, body: impl FnOnce(&mut Analyzer) -> Result<T, AudioRemoteError>) -> Result<T, AudioRemoteError> {
+    let analyzer = ANALYZERS.get(handle)?;
+    let mut analyzer = analyzer.lock().unwrap_or_else(|e| e.into_inner());
+    body(&mut analyzer)
+}
+
+/// Create a spectrum analyzer. `config_json` (nullable): {"channels" (default 2), "sampleRate"
+/// (default 48000), "fftSize" (default 2048), "bands" (default 32), "minHz" (default 20),
+/// "maxHz" (default 20000), "smoothing" (default 0.7), "floorDb" (default -90)}
+/// Returns: a handle (free with ar_spectrum_free), or 0 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must be null or point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_spectrum_new(config_json: *const c_char) -> Handle {
+    guard("ar_spectrum_new", 0, || {
+        let config = match config_json.is_null() {
+            true => Ok(SpectrumConfig::default()),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid spectrum config: {e}")))
+            }),
+        };
+        match record(config.and_then(|config| Analyzer::new(&config))) {
+            Some(analyzer) => ANALYZERS.insert(Mutex::new(analyzer)),
+            None => 0,
+        }
+    })
+}
+
+/// Add `frames` frames of interleaved float samples, one per channel per frame
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `samples` must point to `frames` × channels floats, or may be null when `frames` is 0.
+#[no_mangle]
+pub unsafe extern "C" fn ar_spectrum_process(analyzer: Handle, samples: *const f32, frames: usize) -> i32 {
+    guard("ar_spectrum_process", -999, || {
+        let result = with_analyzer(analyzer, |analyzer| {
+            let len = frames
+                .checked_mul(analyzer.channel_count())
+                .ok_or(AudioRemoteError::InvalidArgument(format!("{frames} frames is too many")))?;
+            if len == 0 {
+                return Ok(1);
+            }
+            if samples.is_null() {
+                return Err(AudioRemoteError::InvalidArgument("samples must not be null".into()));
+            }
+            analyzer.process(std::slice::from_raw_parts(samples, len));
+            Ok(1)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Read the latest band levels, lowest band first, into `out_bands`, up to `capacity` bands
+/// Returns: the analyzer's band count, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `out_bands` must have room for `capacity` floats.
+#[no_mangle]
+pub unsafe extern "C" fn ar_spectrum_bands(analyzer: Handle, out_bands: *mut f32, capacity: usize) -> i32 {
+    guard("ar_spectrum_bands", -999, || {
+        let result = with_analyzer(analyzer, |analyzer| {
+            let levels = analyzer.levels();
+            if capacity > 0 {
+                if out_bands.is_null() {
+                    return Err(AudioRemoteError::InvalidArgument("out_bands must not be null".into()));
+                }
+                let count = capacity.min(levels.len());
+                std::slice::from_raw_parts_mut(out_bands, count).copy_from_slice(&levels[..count]);
+            }
+            Ok(levels.len() as i32)
+        });
+        record(result).unwrap_or(-999)
+    })
+}
+
+/// Drop the audio and levels, e.g. when the tap restarts
+/// Returns: 1 on success, -999 on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_spectrum_reset(analyzer: Handle) -> i32 {
+    guard("ar_spectrum_reset", -999, || {
+        record(with_analyzer(analyzer, |analyzer| {
+            analyzer.reset();
+            Ok(1)
+        }))
+        .unwrap_or(-999)
+    })
+}
+
+/// Release an analyzer from ar_spectrum_new; the handle is invalid afterwards
+/// Returns: 1 on success, -999 if the handle is invalid (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_spectrum_free(analyzer: Handle) -> i32 {
+    guard("ar_spectrum_free", -999, || record(ANALYZERS.remove(analyzer)).map_or(-999, |_| 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f64 = 48_000.0;
+
+    fn sine(hz: f64, frames: usize, amplitude: f32) -> Vec<f32> {
+        (0..frames).map(|i| amplitude * (2.0 * std::f64::consts::PI * hz * i as f64 / RATE).sin() as f32).collect()
+    }
+
+    #[test]
+    fn test_bands() {
+        let config = SpectrumConfig { channels: 1, ..SpectrumConfig::default() };
+        let mut analyzer = Analyzer::new(&config).unwrap();
+        let edges = analyzer.band_edges(RATE);
+        assert_eq!(edges.len(), 32);
+        assert!(edges.windows(2).all(|pair| pair[0].0 <= pair[1].0 && pair[0].1 <= pair[1].1), "{edges:?}");
+        assert!(edges[0].0 < 30.0 && edges[31].1 <= 20_000.0);
+
+        // A full-scale 1 kHz sine tops its band at 0 dB and leaves the far ones near the floor
+        analyzer.process(&sine(1000.0, 8192, 1.0));
+        let band = edges.iter().position(|&(low, high)| (low..=high).contains(&1000.0)).unwrap();
+        let levels = analyzer.levels();
+        assert!(levels[band] > 0.98, "{levels:?}");
+        assert!(levels[0] < 0.2 && levels[31] < 0.2, "{levels:?}");
+
+        // Once the window is silent, levels fall back by `smoothing` each half window
+        analyzer.process(&vec![0.0; 2048]);
+        let before = analyzer.levels()[band];
+        assert!(before > 0.0);
+        analyzer.process(&vec![0.0; 1024]);
+        assert!((analyzer.levels()[band] - before * 0.7).abs() < 1e-6);
+        analyzer.reset();
+        assert!(analyzer.levels().iter().all(|&level| level == 0.0));
+
+        assert!(Analyzer::new(&SpectrumConfig { fft_size: 1000, ..SpectrumConfig::default() }).is_err());
+        assert!(Analyzer::new(&SpectrumConfig { min_hz: 30_000.0, ..SpectrumConfig::default() }).is_err());
+    }
+
+    #[test]
+    fn test_ffi() {
+        let analyzer = unsafe { ar_spectrum_new(c"{\"channels\": 2, \"fftSize\": 512, \"bands\": 8}".as_ptr()) };
+        assert_ne!(analyzer, 0);
+        let samples: Vec<f32> = sine(5000.0, 1024, 0.5).into_iter().flat_map(|sample| [sample, sample]).collect();
+        assert_eq!(unsafe { ar_spectrum_process(analyzer, samples.as_ptr(), 1024) }, 1);
+        assert_eq!(unsafe { ar_spectrum_process(analyzer, std::ptr::null(), 1) }, -999);
+
+        let mut bands = [0f32; 16];
+        assert_eq!(unsafe { ar_spectrum_bands(analyzer, bands.as_mut_ptr(), bands.len()) }, 8);
+        assert!(bands[..8].iter().any(|&level| level > 0.9) && bands[8..].iter().all(|&level| level == 0.0), "{bands:?}");
+        assert_eq!(unsafe { ar_spectrum_bands(analyzer, std::ptr::null_mut(), 0) }, 8);
+        assert_eq!(ar_spectrum_reset(analyzer), 1);
+
+        assert_eq!(ar_spectrum_free(analyzer), 1);
+        assert_eq!(ar_spectrum_free(analyzer), -999);
+        assert_eq!(unsafe { ar_spectrum_new(c"{\"bands\": 0}".as_ptr()) }, 0);
+    }
+}
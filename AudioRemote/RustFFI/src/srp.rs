@@ -0,0 +1,185 @@
+//! The server side of SRP-6a (RFC 5054) with the 3072-bit group and SHA-512,
+//! as HomeKit pair setup uses it (see `hap`). The client proves it knows the
+//! setup code without sending it, and both sides end up with the same 64-byte
+//! session key. The quirks are HomeKit's: `k` and `u` hash padded values, the
+//! proofs hash `A`, `B` and `S` as their minimal big-endian bytes.
+
+use std::sync::OnceLock;
+
+use num_bigint::BigUint;
+use sha2::{Digest, Sha512};
+
+use crate::pairing::random_bytes;
+use crate::AudioRemoteError;
+
+/// RFC 5054 appendix A, the 3072-bit group
+const MODULUS_HEX: [&str; 8] = [
+    "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DD",
+    "EF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A637ED6B0BFF5CB6F406B7ED",
+    "EE386BFB5A899FA5AE9F24117C4B1FE649286651ECE45B3DC2007CB8A163BF0598DA48361C55D39A69163FA8FD24CF5F",
+    "83655D23DCA3AD961C62F356208552BB9ED529077096966D670C354E4ABC9804F1746C08CA18217C32905E462E36CE3B",
+    "E39E772C180E86039B2783A2EC07A28FB5C55DF06F4C52C9DE2BCBF6955817183995497CEA956AE515D2261898FA0510",
+    "15728E5A8AAAC42DAD33170D04507A33A85521ABDF1CBA64ECFB850458DBEF0A8AEA71575D060C7DB3970F85A6E1E4C7",
+    "ABF5AE8CDB0933D71E8C94E04A25619DCEE3D2261AD2EE6BF12FFA06D98A0864D87602733EC86A64521F2B18177B200C",
+    "BBE117577A615D6C770988C0BAD946E208E24FA074E5AB3143DB5BFCE0FD108E4B82D120A93AD2CAFFFFFFFFFFFFFFFF",
+];
+const GENERATOR: u32 = 5;
+/// Bytes in the modulus, and in a padded value
+const LENGTH: usize = 384;
+pub const SALT_LENGTH: usize = 16;
+const SECRET_LENGTH: usize = 32;
+
+fn modulus() -> &'static BigUint {
+    static MODULUS: OnceLock<BigUint> = OnceLock::new();
+    MODULUS.get_or_init(|| BigUint::parse_bytes(MODULUS_HEX.concat().as_bytes(), 16).expect("the modulus is hex"))
+}
+
+/// Left-pad to the modulus' length
+fn pad(value: &BigUint) -> Vec<u8> {
+    let bytes = value.to_bytes_be();
+    let mut padded = vec![0; LENGTH.saturating_sub(bytes.len())];
+    padded.extend_from_slice(&bytes);
+    padded
+}
+
+fn hash(parts: &[&[u8]]) -> Vec<u8> {
+    parts.iter().fold(Sha512::new(), |hasher, part| hasher.chain_update(part)).finalize().to_vec()
+}
+
+fn hash_number(parts: &[&[u8]]) -> BigUint {
+    BigUint::from_bytes_be(&hash(parts))
+}
+
+/// x = H(s | H(I ":" P))
+fn private_key(username: &[u8], password: &[u8], salt: &[u8]) -> BigUint {
+    hash_number(&[salt, &hash(&[username, b":", password])])
+}
+
+/// k = H(N | PAD(g))
+fn multiplier() -> BigUint {
+    hash_number(&[&modulus().to_bytes_be(), &pad(&BigUint::from(GENERATOR))])
+}
+
+/// M1 = H(H(N) xor H(g) | H(I) | s | A | B | K)
+fn client_proof(username: &[u8], salt: &[u8], a: &BigUint, b: &BigUint, key: &[u8]) -> Vec<u8> {
+    let group: Vec<u8> = hash(&[&modulus().to_bytes_be()])
+        .iter()
+        .zip(hash(&[&BigUint::from(GENERATOR).to_bytes_be()]))
+        .map(|(n, g)| n ^ g)
+        .collect();
+    hash(&[&group, &hash(&[username]), salt, &a.to_bytes_be(), &b.to_bytes_be(), key])
+}
+
+/// What a successful exchange gives the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Verified {
+    /// K = H(S), shared with the client
+    pub key: Vec<u8>,
+    /// M2 = H(A | M1 | K), proving to the client that the server knows the verifier too
+    pub proof: Vec<u8>,
+}
+
+/// One exchange, from the server's first message to checking the client's proof
+pub struct Server {
+    username: Vec<u8>,
+    salt: Vec<u8>,
+    verifier: BigUint,
+    secret: BigUint,
+    public: BigUint,
+}
+
+impl Server {
+    /// Start an exchange for `username` and `password` with a fresh salt and secret
+    pub fn new(username: &[u8], password: &[u8]) -> Result<Self, AudioRemoteError> {
+        Ok(Self::with_secrets(username, password, &random_bytes::<SALT_LENGTH>()?, &random_bytes::<SECRET_LENGTH>()?))
+    }
+
+    fn with_secrets(username: &[u8], password: &[u8], salt: &[u8], secret: &[u8]) -> Self {
+        let n = modulus();
+        let verifier = BigUint::from(GENERATOR).modpow(&private_key(username, password, salt), n);
+        let secret = BigUint::from_bytes_be(secret);
+        // B = k*v + g^b
+        let public = (multiplier() * &verifier + BigUint::from(GENERATOR).modpow(&secret, n)) % n;
+        Self { username: username.to_vec(), salt: salt.to_vec(), verifier, secret, public }
+    }
+
+    pub fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    /// B, padded to the modulus' length
+    pub fn public_key(&self) -> Vec<u8> {
+        pad(&self.public)
+    }
+
+    /// Check the client's public key A and proof M1; VerificationFailed if the password was wrong
+    pub fn verify(&self, client_public: &[u8], client_proof_bytes: &[u8]) -> Result<Verified, AudioRemoteError> {
+        let n = modulus();
+        let a = BigUint::from_bytes_be(client_public);
+        if client_public.len() > LENGTH || (&a % n) == BigUint::ZERO {
+            return Err(AudioRemoteError::InvalidData("the SRP public key is invalid".into()));
+        }
+        let u = hash_number(&[&pad(&a), &pad(&self.public)]);
+        if u == BigUint::ZERO {
+            return Err(AudioRemoteError::InvalidData("the SRP public key is invalid".into()));
+        }
+        // S = (A * v^u)^b
+        let shared = (a.clone() * self.verifier.modpow(&u, n)).modpow(&self.secret, n);
+        let key = hash(&[&shared.to_bytes_be()]);
+        let expected = client_proof(&self.username, &self.salt, &a, &self.public, &key);
+        if !constant_time_eq(&expected, client_proof_bytes) {
+            return Err(AudioRemoteError::VerificationFailed("the setup code is wrong".into()));
+        }
+        let proof = hash(&[&a.to_bytes_be(), &expected, &key]);
+        Ok(Verified { key, proof })
+    }
+}
+
+/// Compare without stopping at the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// The controller's side, as iOS computes it: A and M1 for a server's salt and B
+    pub(crate) fn client(username: &[u8], password: &[u8], salt: &[u8], server_public: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let n = modulus();
+        let a_secret = BigUint::from_bytes_be(&[7; 32]);
+        let a = BigUint::from(GENERATOR).modpow(&a_secret, n);
+        let b = BigUint::from_bytes_be(server_public);
+        let u = hash_number(&[&pad(&a), &pad(&b)]);
+        let x = private_key(username, password, salt);
+        // S = (B - k*g^x)^(a + u*x)
+        let kgx = multiplier() * BigUint::from(GENERATOR).modpow(&x, n) % n;
+        let base = (b.clone() + n - kgx) % n;
+        let shared = base.modpow(&(a_secret + u * x), n);
+        let key = hash(&[&shared.to_bytes_be()]);
+        let proof = client_proof(username, salt, &a, &b, &key);
+        (a.to_bytes_be(), proof, key)
+    }
+
+    #[test]
+    fn test_exchange() {
+        let server = Server::new(b"Pair-Setup", b"031-45-154").unwrap();
+        assert_eq!(server.salt().len(), SALT_LENGTH);
+        assert_eq!(server.public_key().len(), LENGTH);
+        let (a, proof, key) = client(b"Pair-Setup", b"031-45-154", server.salt(), &server.public_key());
+        let verified = server.verify(&a, &proof).unwrap();
+        assert_eq!(verified.key, key);
+        assert_eq!(verified.key.len(), 64);
+        assert_eq!(verified.proof, hash(&[&a, &proof, &key]));
+    }
+
+    #[test]
+    fn test_wrong_password_and_bad_keys() {
+        let server = Server::with_secrets(b"Pair-Setup", b"031-45-154", &[1; 16], &[2; 32]);
+        let (a, proof, _) = client(b"Pair-Setup", b"031-45-155", server.salt(), &server.public_key());
+        assert!(matches!(server.verify(&a, &proof), Err(AudioRemoteError::VerificationFailed(_))));
+        assert!(matches!(server.verify(&pad(modulus()), &proof), Err(AudioRemoteError::InvalidData(_))));
+        assert!(server.verify(&[0], &proof).is_err());
+        assert_eq!(modulus().bits(), 3072);
+    }
+}
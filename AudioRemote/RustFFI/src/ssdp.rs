@@ -0,0 +1,512 @@
+//! SSDP (UPnP device discovery) and DIAL, so TVs, media apps and UPnP control
+//! points that don't speak Bonjour can find the Mac. The responder listens on
+//! 239.255.255.250:1900, answers M-SEARCH requests for its targets after the
+//! random delay the request's MX allows, and multicasts `ssdp:alive` on start
+//! and every half max-age and `ssdp:byebye` on stop.
+//!
+//! Answers point at a device description served by the embedded HTTP server
+//! (`/upnp/device.xml`), which names the web remote as the presentation page
+//! and carries DIAL's `Application-URL` header. The DIAL application
+//! (`/dial/apps/AudioRemote`) is the remote-control server itself, so it's
+//! always running and launching it does nothing.
+
+use std::ffi::c_char;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use socket2::{Domain, SockRef};
+
+use crate::interfaces::{self, InterfaceAddress};
+use crate::mdns;
+use crate::pairing::{self, random_bytes};
+use crate::server::{self, Request, Response};
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+pub const SSDP_ADDRESS: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub const SSDP_PORT: u16 = 1900;
+pub const DEVICE_TYPE: &str = "urn:audioremote-app:device:RemoteControl:1";
+pub const SERVICE_TYPE: &str = "urn:audioremote-app:service:RemoteControl:1";
+pub const DIAL_SERVICE_TYPE: &str = "urn:dial-multiscreen-org:service:dial:1";
+pub const DESCRIPTION_PATH: &str = "/upnp/device.xml";
+pub const SCPD_PATH: &str = "/upnp/remote-control.xml";
+pub const DIAL_APPS_PATH: &str = "/dial/apps/";
+pub const DIAL_APP_NAME: &str = "AudioRemote";
+const MAX_AGE: u32 = 1800;
+/// UPnP 1.1 §1.1.2: don't let announcements wander far
+const MULTICAST_TTL: u32 = 2;
+/// Answers are spread over at most this many seconds, whatever MX asks for
+const MAX_DELAY_SECS: u64 = 5;
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const ADDRESS_REFRESH: Duration = Duration::from_secs(10);
+
+/// `ar_ssdp_start` configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct SsdpConfig {
+    /// The friendly name control points show, e.g. "Studio Mac"
+    pub name: String,
+    /// Default: the running server's port
+    pub port: Option<u16>,
+}
+
+/// What the description and the answers say about the device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub name: String,
+    /// "uuid:…", stable per Mac
+    pub udn: String,
+    pub port: u16,
+    pub tls: bool,
+    /// Changes on every start (UPnP 1.1 BOOTID.UPNP.ORG)
+    pub boot_id: u32,
+}
+
+/// A stable UUID for this Mac, derived from its pairing identity
+fn udn() -> Result<String, AudioRemoteError> {
+    let mut bytes: [u8; 16] = Sha256::digest(format!("ssdp:{}", pairing::mac_id()?))[..16].try_into().expect("16 bytes");
+    // A name-based UUID (version 5, RFC 4122 variant)
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    Ok(format!("uuid:{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&apos;")
+}
+
+impl Device {
+    /// Every search target the device answers to: (ST/NT, USN)
+    pub fn targets(&self) -> Vec<(String, String)> {
+        let mut targets = vec![("upnp:rootdevice".to_owned(), format!("{}::upnp:rootdevice", self.udn)), (self.udn.clone(), self.udn.clone())];
+        for kind in [DEVICE_TYPE, SERVICE_TYPE, DIAL_SERVICE_TYPE] {
+            targets.push((kind.to_owned(), format!("{}::{kind}", self.udn)));
+        }
+        targets
+    }
+
+    /// The targets a search for `st` matches; everything for "ssdp:all"
+    pub fn matching(&self, st: &str) -> Vec<(String, String)> {
+        self.targets().into_iter().filter(|(target, _)| st == "ssdp:all" || target == st).collect()
+    }
+
+    fn location(&self, host: IpAddr) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{scheme}://{}{DESCRIPTION_PATH}", SocketAddr::new(host, self.port))
+    }
+
+    fn common_headers(&self, host: IpAddr) -> String {
+        format!(
+            "CACHE-CONTROL: max-age={MAX_AGE}\r\nLOCATION: {}\r\nSERVER: macOS UPnP/1.1 AudioRemote/{}\r\nBOOTID.UPNP.ORG: {}\r\nCONFIGID.UPNP.ORG: 1\r\n",
+            self.location(host),
+            env!("CARGO_PKG_VERSION"),
+            self.boot_id,
+        )
+    }
+
+    /// The unicast answer to a search, for a requester that reaches the Mac at `host`
+    pub fn search_response(&self, host: IpAddr, st: &str, usn: &str) -> String {
+        format!("HTTP/1.1 200 OK\r\n{}EXT:\r\nST: {st}\r\nUSN: {usn}\r\n\r\n", self.common_headers(host))
+    }
+
+    /// An `ssdp:alive` or, with `alive` false, `ssdp:byebye` announcement
+    pub fn notify(&self, host: IpAddr, nt: &str, usn: &str, alive: bool) -> String {
+        let group = SocketAddrV4::new(SSDP_ADDRESS, SSDP_PORT);
+        match alive {
+            true => format!("NOTIFY * HTTP/1.1\r\nHOST: {group}\r\n{}NT: {nt}\r\nNTS: ssdp:alive\r\nUSN: {usn}\r\n\r\n", self.common_headers(host)),
+            false => format!(
+                "NOTIFY * HTTP/1.1\r\nHOST: {group}\r\nNT: {nt}\r\nNTS: ssdp:byebye\r\nUSN: {usn}\r\nBOOTID.UPNP.ORG: {}\r\nCONFIGID.UPNP.ORG: 1\r\n\r\n",
+                self.boot_id
+            ),
+        }
+    }
+
+    /// The UPnP device description
+    pub fn description(&self) -> String {
+        format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0" configId="1">
+  <specVersion><major>1</major><minor>1</minor></specVersion>
+  <device>
+    <deviceType>{DEVICE_TYPE}</deviceType>
+    <friendlyName>{}</friendlyName>
+    <manufacturer>Audio Remote</manufacturer>
+    <modelName>Mac</modelName>
+    <modelNumber>{}</modelNumber>
+    <UDN>{}</UDN>
+    <serviceList>
+      <service>
+        <serviceType>{SERVICE_TYPE}</serviceType>
+        <serviceId>urn:audioremote-app:serviceId:RemoteControl</serviceId>
+        <SCPDURL>{SCPD_PATH}</SCPDURL>
+        <controlURL>/api/v1</controlURL>
+        <eventSubURL>/api/v1/events</eventSubURL>
+      </service>
+    </serviceList>
+    <presentationURL>/</presentationURL>
+  </device>
+</root>
+"#,
+            escape(&self.name),
+            env!("CARGO_PKG_VERSION"),
+            self.udn,
+        )
+    }
+}
+
+/// The service description: no SOAP actions (the API is REST, see /openapi.json), just the state
+const SCPD: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<scpd xmlns="urn:schemas-upnp-org:service-1-0" configId="1">
+  <specVersion><major>1</major><minor>1</minor></specVersion>
+  <actionList/>
+  <serviceStateTable>
+    <stateVariable sendEvents="no"><name>Volume</name><dataType>ui1</dataType><allowedValueRange><minimum>0</minimum><maximum>100</maximum></allowedValueRange></stateVariable>
+    <stateVariable sendEvents="no"><name>Mute</name><dataType>boolean</dataType></stateVariable>
+  </serviceStateTable>
+</scpd>
+"#;
+
+/// A search's target and the longest it lets answers wait, if it's a valid M-SEARCH
+pub fn parse_search(packet: &[u8]) -> Option<(String, Duration)> {
+    let text = std::str::from_utf8(packet).ok()?;
+    let mut lines = text.split("\r\n");
+    if !lines.next()?.eq_ignore_ascii_case("M-SEARCH * HTTP/1.1") {
+        return None;
+    }
+    let (mut st, mut man, mut mx) = (None, None, None);
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        match name.trim().to_ascii_uppercase().as_str() {
+            "ST" => st = Some(value.trim().to_owned()),
+            "MAN" => man = Some(value.trim().trim_matches('"').to_owned()),
+            "MX" => mx = value.trim().parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+    if man.as_deref() != Some("ssdp:discover") {
+        return None;
+    }
+    // Unicast searches carry no MX and want an answer at once
+    Some((st?, Duration::from_secs(mx.unwrap_or(0).min(MAX_DELAY_SECS))))
+}
+
+/// The address the Mac has toward `peer`
+pub(crate) fn local_address_toward(peer: SocketAddr) -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(peer).ok()?;
+    socket.local_addr().ok().map(|address| address.ip())
+}
+
+struct Sockets {
+    socket: UdpSocket,
+    interfaces: Vec<InterfaceAddress>,
+}
+
+impl Sockets {
+    fn open(interfaces: Vec<InterfaceAddress>) -> Result<Self, AudioRemoteError> {
+        let interfaces: Vec<InterfaceAddress> = interfaces.into_iter().filter(|interface| interface.address.is_ipv4()).collect();
+        let socket = mdns::multicast_socket(Domain::IPV4)
+            .and_then(|socket| {
+                socket.set_multicast_ttl_v4(MULTICAST_TTL)?;
+                socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, SSDP_PORT)).into())?;
+                socket.set_read_timeout(Some(POLL_INTERVAL))?;
+                for interface in &interfaces {
+                    if let IpAddr::V4(address) = interface.address {
+                        let _ = socket.join_multicast_v4(&SSDP_ADDRESS, &address);
+                    }
+                }
+                Ok(UdpSocket::from(socket))
+            })
+            .map_err(|e| AudioRemoteError::Network(format!("can't listen for SSDP on port {SSDP_PORT}: {e}")))?;
+        Ok(Self { socket, interfaces })
+    }
+
+    /// Announce every target on every interface
+    fn notify(&self, device: &Device, alive: bool) {
+        for interface in &self.interfaces {
+            let IpAddr::V4(address) = interface.address else {
+                continue;
+            };
+            for (nt, usn) in device.targets() {
+                let message = device.notify(interface.address, &nt, &usn, alive);
+                let result = SockRef::from(&self.socket)
+                    .set_multicast_if_v4(&address)
+                    .and_then(|()| self.socket.send_to(message.as_bytes(), (SSDP_ADDRESS, SSDP_PORT)));
+                if let Err(e) = result {
+                    log::debug!("SSDP send on {} failed: {e}", interface.interface);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+fn respond(device: Device, mut sockets: Sockets, stop: Arc<AtomicBool>) {
+    sockets.notify(&device, true);
+    let mut announced = Instant::now();
+    let mut refreshed = Instant::now();
+    // Answers waiting out their delay
+    let mut pending: Vec<(Instant, SocketAddr, String)> = Vec::new();
+    let mut buffer = [0; 2048];
+    while !stop.load(Ordering::Acquire) {
+        let now = Instant::now();
+        for (_, peer, answer) in pending.extract_if(.., |(due, _, _)| *due <= now) {
+            if let Err(e) = sockets.socket.send_to(answer.as_bytes(), peer) {
+                log::debug!("SSDP answer to {peer} failed: {e}");
+            }
+        }
+        if announced.elapsed() >= Duration::from_secs(u64::from(MAX_AGE / 2)) {
+            announced = Instant::now();
+            sockets.notify(&device, true);
+        }
+        if refreshed.elapsed() >= ADDRESS_REFRESH {
+            refreshed = Instant::now();
+            if let Ok(interfaces) = interfaces::local_addresses() {
+                let interfaces: Vec<InterfaceAddress> = interfaces.into_iter().filter(|interface| interface.address.is_ipv4()).collect();
+                if interfaces != sockets.interfaces {
+                    // Join the group on new interfaces and announce there
+                    if let Ok(reopened) = Sockets::open(interfaces) {
+                        sockets = reopened;
+                        sockets.notify(&device, true);
+                    }
+                }
+            }
+        }
+        let Ok((length, peer)) = sockets.socket.recv_from(&mut buffer) else {
+            continue;
+        };
+        let Some((st, max_delay)) = parse_search(&buffer[..length]) else {
+            continue;
+        };
+        let Some(host) = local_address_toward(peer) else {
+            continue;
+        };
+        let matching = device.matching(&st);
+        if !matching.is_empty() {
+            log::debug!("SSDP search for {st} from {peer}");
+        }
+        for (st, usn) in matching {
+            let jitter = match max_delay.as_millis() as u64 {
+                0 => 0,
+                max => u64::from(u32::from_le_bytes(random_bytes().unwrap_or_default())) % max,
+            };
+            pending.push((Instant::now() + Duration::from_millis(jitter), peer, device.search_response(host, &st, &usn)));
+        }
+    }
+    sockets.notify(&device, false);
+}
+
+struct Running {
+    device: Device,
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+static RESPONDER: Mutex<Option<Running>> = Mutex::new(None);
+
+/// Start answering searches, replacing a running responder
+pub fn start(config: &SsdpConfig) -> Result<(), AudioRemoteError> {
+    let name = config.name.trim();
+    if name.is_empty() {
+        return Err(AudioRemoteError::InvalidArgument("the device name is empty".into()));
+    }
+    let port = match config.port.or_else(|| server::local_address().map(|address| address.port())) {
+        Some(port) => port,
+        None => return Err(AudioRemoteError::InvalidArgument("no port given and the server isn't running".into())),
+    };
+    stop();
+    let device = Device {
+        name: name.into(),
+        udn: udn()?,
+        port,
+        tls: server::certificate_fingerprint().is_some(),
+        boot_id: SystemTime::now().duration_since(UNIX_EPOCH).map_or(1, |d| d.as_secs() as u32),
+    };
+    let sockets = Sockets::open(interfaces::local_addresses()?)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread = {
+        let (device, stop) = (device.clone(), stop.clone());
+        thread::Builder::new()
+            .name("audioremote-ssdp".into())
+            .spawn(move || respond(device, sockets, stop))
+            .map_err(|e| AudioRemoteError::Other(format!("can't start the SSDP thread: {e}")))?
+    };
+    log::info!("answering SSDP searches as {} ({})", device.name, device.udn);
+    *RESPONDER.lock().unwrap_or_else(|e| e.into_inner()) = Some(Running { device, stop, thread });
+    Ok(())
+}
+
+/// Say goodbye and stop answering; a no-op if not running
+pub fn stop() {
+    let Some(running) = RESPONDER.lock().unwrap_or_else(|e| e.into_inner()).take() else {
+        return;
+    };
+    running.stop.store(true, Ordering::Release);
+    let _ = running.thread.join();
+    log::info!("stopped answering SSDP searches");
+}
+
+/// Paths the server answers without a token: control points fetch them anonymously
+pub(crate) fn is_public(path: &str) -> bool {
+    path == DESCRIPTION_PATH || path == SCPD_PATH || path.starts_with(DIAL_APPS_PATH)
+}
+
+fn xml(status: u16, body: String) -> Response {
+    Response::new(status).with_header("Content-Type", "text/xml; charset=\"utf-8\"").with_body(body.into_bytes())
+}
+
+/// The description documents and the DIAL application, while the responder runs
+pub(crate) fn route(request: &Request) -> Option<Response> {
+    if !is_public(&request.path) {
+        return None;
+    }
+    let device = RESPONDER.lock().unwrap_or_else(|e| e.into_inner()).as_ref().map(|running| running.device.clone());
+    let Some(device) = device else {
+        return Some(Response::error(404, format!("no such path {}", request.path)));
+    };
+    let method = request.method.as_str();
+    Some(match request.path.as_str() {
+        DESCRIPTION_PATH | SCPD_PATH if method != "GET" => Response::error(405, format!("{method} isn't allowed here")).with_header("Allow", "GET"),
+        DESCRIPTION_PATH => {
+            let response = xml(200, device.description());
+            // DIAL: where the applications are, as the control point reached us
+            match request.header("host") {
+                Some(host) => {
+                    let scheme = if device.tls { "https" } else { "http" };
+                    response.with_header("Application-URL", format!("{scheme}://{host}{DIAL_APPS_PATH}"))
+                }
+                None => response,
+            }
+        }
+        SCPD_PATH => xml(200, SCPD.into()),
+        path => match (path.strip_prefix(DIAL_APPS_PATH), method) {
+            (Some(DIAL_APP_NAME), "GET") => xml(
+                200,
+                format!(
+                    "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<service xmlns=\"urn:dial-multiscreen-org:schemas:dial\" dialVer=\"2.1\">\n  <name>{DIAL_APP_NAME}</name>\n  <options allowStop=\"false\"/>\n  <state>running</state>\n</service>\n"
+                ),
+            ),
+            // Already running: nothing to launch
+            (Some(DIAL_APP_NAME), "POST") => Response::new(200),
+            (Some(DIAL_APP_NAME), _) => Response::error(405, format!("{method} isn't allowed here")).with_header("Allow", "GET, POST"),
+            _ => Response::error(404, format!("no application at {path}")),
+        },
+    })
+}
+
+/// Answer SSDP searches and serve a UPnP device description and a DIAL application, so control
+/// points and TVs find the Mac. `config_json`: {"name", "port" (default: the running server's)}.
+/// Replaces a running responder.
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `config_json` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_ssdp_start(config_json: *const c_char) -> i32 {
+    guard("ar_ssdp_start", -999, || {
+        let result = str_arg(config_json, "config").and_then(|json| {
+            let config: SsdpConfig =
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid SSDP config: {e}")))?;
+            start(&config)
+        });
+        record(result).map_or(-999, |()| 1)
+    })
+}
+
+/// Announce ssdp:byebye and stop answering; a no-op if not running. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_ssdp_stop() {
+    guard("ar_ssdp_stop", (), stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::tests::parse;
+
+    fn device() -> Device {
+        Device { name: "Tom & Jerry's Mac".into(), udn: "uuid:0000-1".into(), port: 8765, tls: false, boot_id: 7 }
+    }
+
+    #[test]
+    fn test_parse_search() {
+        let search = "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\nST: upnp:rootdevice\r\n\r\n";
+        assert_eq!(parse_search(search.as_bytes()), Some(("upnp:rootdevice".into(), Duration::from_secs(2))));
+        let unicast = "m-search * HTTP/1.1\r\nman: \"ssdp:discover\"\r\nst: ssdp:all\r\n\r\n";
+        assert_eq!(parse_search(unicast.as_bytes()), Some(("ssdp:all".into(), Duration::ZERO)));
+        let patient = "M-SEARCH * HTTP/1.1\r\nMAN: \"ssdp:discover\"\r\nMX: 120\r\nST: ssdp:all\r\n\r\n";
+        assert_eq!(parse_search(patient.as_bytes()).unwrap().1, Duration::from_secs(MAX_DELAY_SECS));
+        assert_eq!(parse_search(b"M-SEARCH * HTTP/1.1\r\nST: ssdp:all\r\n\r\n"), None);
+        assert_eq!(parse_search(b"NOTIFY * HTTP/1.1\r\nMAN: \"ssdp:discover\"\r\nST: ssdp:all\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_targets_and_messages() {
+        let device = device();
+        assert_eq!(device.matching("ssdp:all").len(), 5);
+        assert_eq!(device.matching(DIAL_SERVICE_TYPE), [(DIAL_SERVICE_TYPE.to_owned(), format!("uuid:0000-1::{DIAL_SERVICE_TYPE}"))]);
+        assert_eq!(device.matching("uuid:0000-1"), [("uuid:0000-1".to_owned(), "uuid:0000-1".to_owned())]);
+        assert!(device.matching("urn:schemas-upnp-org:device:MediaRenderer:1").is_empty());
+
+        let host: IpAddr = "192.168.1.20".parse().unwrap();
+        let answer = device.search_response(host, "upnp:rootdevice", "uuid:0000-1::upnp:rootdevice");
+        assert!(answer.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(answer.contains("LOCATION: http://192.168.1.20:8765/upnp/device.xml\r\n"));
+        assert!(answer.contains("BOOTID.UPNP.ORG: 7\r\n") && answer.ends_with("\r\n\r\n"));
+        assert!(device.notify(host, "upnp:rootdevice", "u", true).contains("NTS: ssdp:alive\r\n"));
+        let byebye = device.notify(host, "upnp:rootdevice", "u", false);
+        assert!(byebye.contains("NTS: ssdp:byebye\r\n") && !byebye.contains("LOCATION"));
+
+        let description = device.description();
+        assert!(description.contains("<friendlyName>Tom &amp; Jerry&apos;s Mac</friendlyName>"));
+        assert!(description.contains("<UDN>uuid:0000-1</UDN>"));
+
+        let (_store, dir) = crate::store::tests::with_temp_store("ssdp");
+        let udn = udn().unwrap();
+        assert_eq!(udn, self::udn().unwrap());
+        assert_eq!((udn.len(), &udn[19..20]), (41, "5"), "{udn}");
+        assert!("89ab".contains(&udn[24..25]), "{udn}");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn test_responder_and_routes() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_store, dir) = crate::store::tests::with_temp_store("ssdp-responder");
+        let request = |raw: &str| route(&parse(raw).unwrap());
+        assert!(request("GET /api/v1/volume HTTP/1.1\r\n\r\n").is_none());
+        assert_eq!(request("GET /upnp/device.xml HTTP/1.1\r\n\r\n").unwrap().status, 404);
+
+        start(&SsdpConfig { name: "Studio".into(), port: Some(8765) }).unwrap();
+        let control_point = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        control_point.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let search = format!("M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {DIAL_SERVICE_TYPE}\r\n\r\n");
+        control_point.send_to(search.as_bytes(), (Ipv4Addr::LOCALHOST, SSDP_PORT)).unwrap();
+        let mut buffer = [0; 2048];
+        let (length, _) = control_point.recv_from(&mut buffer).unwrap();
+        let answer = std::str::from_utf8(&buffer[..length]).unwrap();
+        assert!(answer.contains(&format!("ST: {DIAL_SERVICE_TYPE}\r\n")), "{answer}");
+        assert!(answer.contains("LOCATION: http://127.0.0.1:8765/upnp/device.xml\r\n"), "{answer}");
+
+        let description = request("GET /upnp/device.xml HTTP/1.1\r\nHost: 127.0.0.1:8765\r\n\r\n").unwrap();
+        assert_eq!(description.status, 200);
+        assert!(description.headers.contains(&("Application-URL", "http://127.0.0.1:8765/dial/apps/".into())));
+        assert!(String::from_utf8(description.body).unwrap().contains("<friendlyName>Studio</friendlyName>"));
+        assert_eq!(request("GET /upnp/remote-control.xml HTTP/1.1\r\n\r\n").unwrap().status, 200);
+        let app = request("GET /dial/apps/AudioRemote HTTP/1.1\r\n\r\n").unwrap();
+        assert!(String::from_utf8(app.body).unwrap().contains("<state>running</state>"));
+        assert_eq!(request("POST /dial/apps/AudioRemote HTTP/1.1\r\nContent-Length: 0\r\n\r\n").unwrap().status, 200);
+        assert_eq!(request("DELETE /dial/apps/AudioRemote HTTP/1.1\r\n\r\n").unwrap().status, 405);
+        assert_eq!(request("GET /dial/apps/YouTube HTTP/1.1\r\n\r\n").unwrap().status, 404);
+        stop();
+        assert_eq!(request("GET /upnp/device.xml HTTP/1.1\r\n\r\n").unwrap().status, 404);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
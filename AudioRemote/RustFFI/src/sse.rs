@@ -0,0 +1,146 @@
+//! Server-sent events at /api/v1/events/stream, for clients that can't speak
+//! WebSocket: watch apps, `curl -N` in a script, an `EventSource` in a page.
+//! It's the WebSocket channel's JSON format over a plain response that never
+//! ends, one event per state change:
+//!
+//! ```text
+//! event: volume
+//! data: {"muted":false,"volume":0.75}
+//! ```
+//!
+//! Topics are chosen with `?topics=` as on the WebSocket and fixed for the
+//! life of the stream; the current state of each comes first, so a client that
+//! reconnects needs no `Last-Event-ID` replay. A comment line every 20 seconds
+//! keeps proxies from timing the stream out and finds clients that went away.
+
+use std::io::{self, Write};
+use std::net::Shutdown;
+use std::time::Duration;
+
+use crate::events::{Event, Subscription, HUB};
+use crate::server::{self, Request, Response, Stream};
+use crate::{sessions, websocket};
+
+pub const PATH: &str = "/api/v1/events/stream";
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(20);
+/// How long a client waits before reconnecting after the stream drops
+const RETRY_MILLIS: u32 = 3000;
+
+pub(crate) fn is_stream(request: &Request) -> bool {
+    request.path == PATH
+}
+
+/// One event in the wire format; JSON never contains a raw newline, so `data` is one line
+pub(crate) fn format_event(event: &Event) -> String {
+    format!("event: {}\ndata: {}\n\n", event.topic.name(), event.data)
+}
+
+/// Write events until the subscription closes or the client goes away
+fn push(subscription: &Subscription, stream: &mut Stream) -> io::Result<()> {
+    while let Some(events) = subscription.next(KEEP_ALIVE_INTERVAL) {
+        let text = match events.is_empty() {
+            true => ": keep-alive\n\n".to_string(),
+            false => events.iter().map(format_event).collect(),
+        };
+        stream.write_all(text.as_bytes())?;
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+/// Take over a connection that asked for the event stream
+pub(crate) fn serve(mut stream: Stream, request: &Request) -> io::Result<()> {
+    let topics = match request.method.as_str() {
+        "GET" => websocket::requested_topics(request),
+        method => Err(Response::error(405, format!("{method} isn't allowed here")).with_header("Allow", "GET")),
+    };
+    let topics = match topics {
+        Ok(topics) => topics,
+        Err(response) => return server::write_response(&mut stream, &response, false),
+    };
+    // No Content-Length: the body runs until either side closes the connection
+    let cors = request.own_origin().map(|origin| format!("Access-Control-Allow-Origin: {origin}\r\nVary: Origin\r\n")).unwrap_or_default();
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nServer: AudioRemote/{}\r\n\
+         {cors}Connection: close\r\n\r\nretry: {RETRY_MILLIS}\n\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.flush()?;
+    log::debug!("event stream client {} subscribed to {topics:?}", request.peer);
+
+    let _live = sessions::open_stream(request);
+    let subscription = HUB.subscribe(topics);
+    let result = push(&subscription, &mut stream);
+    log::debug!("event stream client {} disconnected", request.peer);
+    let _ = stream.shutdown(Shutdown::Both);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Topic;
+    use crate::server::ServerConfig;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+    use std::thread;
+
+    #[test]
+    fn test_event_format() {
+        let event = Event { topic: Topic::Volume, data: serde_json::json!({"volume": 0.5, "muted": true}) };
+        assert_eq!(format_event(&event), "event: volume\ndata: {\"muted\":true,\"volume\":0.5}\n\n");
+        let event = Event { topic: Topic::NowPlaying, data: serde_json::Value::Null };
+        assert_eq!(format_event(&event), "event: nowPlaying\ndata: null\n\n");
+    }
+
+    #[test]
+    fn test_stream_over_tcp() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let address = server::start(&ServerConfig { port: 0, bind_address: "127.0.0.1".parse().unwrap(), require_auth: false, ..ServerConfig::default() }).unwrap();
+        let exchange = |request: &str| {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            BufReader::new(stream)
+        };
+
+        let mut refused = exchange(&format!("POST {PATH} HTTP/1.1\r\nHost: mac\r\nContent-Length: 0\r\n\r\n"));
+        let mut status = String::new();
+        refused.read_line(&mut status).unwrap();
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed\r\n");
+        let mut refused = exchange(&format!("GET {PATH}?topics=bass HTTP/1.1\r\nHost: mac\r\n\r\n"));
+        let mut status = String::new();
+        refused.read_line(&mut status).unwrap();
+        assert_eq!(status, "HTTP/1.1 400 Bad Request\r\n");
+
+        let mut reader = exchange(&format!("GET {PATH}?topics=volume HTTP/1.1\r\nHost: mac\r\nAccept: text/event-stream\r\n\r\n"));
+        let mut head = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            head.push(line);
+        }
+        assert_eq!(head[0], "HTTP/1.1 200 OK\r\n");
+        assert!(head.contains(&"Content-Type: text/event-stream\r\n".to_string()));
+        while HUB.subscriber_count() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        HUB.publish(Topic::NowPlaying, serde_json::Value::Null);
+        HUB.publish(Topic::Volume, serde_json::json!({"volume": 0.25, "muted": false}));
+        let mut lines = Vec::new();
+        while !lines.last().is_some_and(|line: &String| line.contains("0.25")) {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            lines.push(line);
+        }
+        assert!(!lines.iter().any(|line| line.contains("nowPlaying")), "{lines:?}");
+        assert_eq!(lines[lines.len() - 2], "event: volume\n");
+        assert_eq!(lines[lines.len() - 1], "data: {\"muted\":false,\"volume\":0.25}\n");
+        server::stop();
+    }
+}
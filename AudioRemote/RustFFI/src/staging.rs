@@ -0,0 +1,356 @@
+use std::ffi::c_char;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::error::string_result;
+use crate::{guard, str_arg, AudioRemoteError};
+
+/// Identity of a validated app bundle
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleInfo {
+    pub path: PathBuf,
+    pub bundle_identifier: String,
+    pub executable: String,
+    pub short_version: Option<String>,
+    pub build: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ArchiveKind {
+    Zip,
+    TarGz,
+    Dmg,
+}
+
+fn archive_kind(path: &Path) -> Result<ArchiveKind, AudioRemoteError> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+    if name.ends_with(".zip") {
+        Ok(ArchiveKind::Zip)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Ok(ArchiveKind::TarGz)
+    } else if name.ends_with(".dmg") {
+        Ok(ArchiveKind::Dmg)
+    } else {
+        Err(AudioRemoteError::Unsupported(format!("unsupported archive type: {name}")))
+    }
+}
+
+/// Fail unless `target` would land inside `root` once the links on its way are followed
+fn check_inside(root: &Path, target: &Path) -> Result<(), AudioRemoteError> {
+    let existing = target.ancestors().find(|path| path.symlink_metadata().is_ok()).unwrap_or(root);
+    let resolved = existing.canonicalize()?;
+    match resolved.starts_with(root) && !target.symlink_metadata().is_ok_and(|meta| meta.file_type().is_symlink()) {
+        true => Ok(()),
+        false => Err(AudioRemoteError::InvalidData(format!("archive entry {} escapes the staging directory", target.display()))),
+    }
+}
+
+fn extract_zip(archive: &Path, dest: &Path) -> Result<(), AudioRemoteError> {
+    let file = File::open(archive).map_err(|e| AudioRemoteError::Io(format!("can't open {}: {e}", archive.display())))?;
+    let mut zip = zip::ZipArchive::new(file).map_err(|e| AudioRemoteError::InvalidData(format!("invalid zip: {e}")))?;
+    // `enclosed_name` only looks at the path text, so links extracted earlier are checked for too, as `tar` does
+    let root = dest.canonicalize()?;
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i).map_err(|e| AudioRemoteError::InvalidData(format!("invalid zip entry: {e}")))?;
+        let rel = entry.enclosed_name().ok_or_else(|| AudioRemoteError::InvalidData(format!("unsafe path in archive: {}", entry.name())))?;
+        let target = root.join(rel);
+        check_inside(&root, &target)?;
+        if entry.is_dir() {
+            fs::create_dir_all(&target)?;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if entry.is_symlink() {
+            let mut link = String::new();
+            io::Read::read_to_string(&mut entry, &mut link)?;
+            // Bundles only link within themselves, e.g. Versions/Current -> A
+            let escapes = Path::new(&link).components().any(|part| !matches!(part, Component::Normal(_) | Component::CurDir));
+            if escapes {
+                return Err(AudioRemoteError::InvalidData(format!("unsafe link in archive: {} -> {link}", entry.name())));
+            }
+            std::os::unix::fs::symlink(link, &target)?;
+            continue;
+        }
+        let mut out = File::create(&target).map_err(|e| AudioRemoteError::Io(format!("can't write {}: {e}", target.display())))?;
+        io::copy(&mut entry, &mut out).map_err(|e| AudioRemoteError::Io(format!("can't extract {}: {e}", entry.name())))?;
+        if let Some(mode) = entry.unix_mode() {
+            fs::set_permissions(&target, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+    }
+    Ok(())
+}
+
+fn extract_tar_gz(archive: &Path, dest: &Path) -> Result<(), AudioRemoteError> {
+    let file = File::open(archive).map_err(|e| AudioRemoteError::Io(format!("can't open {}: {e}", archive.display())))?;
+    let mut tar = tar::Archive::new(flate2::read::GzDecoder::new(file));
+    tar.set_preserve_permissions(true);
+    // `unpack` refuses entries that would land outside `dest`
+    tar.unpack(dest).map_err(|e| AudioRemoteError::InvalidData(format!("invalid tar.gz: {e}")))
+}
+
+/// Mount the disk image read-only, copy its top-level .app bundles out and detach it again
+#[cfg(target_os = "macos")]
+fn extract_dmg(archive: &Path, dest: &Path) -> Result<(), AudioRemoteError> {
+    use std::process::Command;
+
+    let mount_point = dest.join(".mount");
+    fs::create_dir_all(&mount_point)?;
+    let status = Command::new("/usr/bin/hdiutil")
+        .args(["attach", "-nobrowse", "-readonly", "-noautoopen", "-quiet", "-mountpoint"])
+        .arg(&mount_point)
+        .arg(archive)
+        .status()
+        .map_err(|e| AudioRemoteError::Io(format!("can't run hdiutil: {e}")))?;
+    if !status.success() {
+        let _ = fs::remove_dir(&mount_point);
+        return Err(AudioRemoteError::Io(format!("can't mount {}", archive.display())));
+    }
+
+    let copied = (|| {
+        for entry in fs::read_dir(&mount_point)? {
+            let path = entry?.path();
+            let is_app = path.extension().is_some_and(|ext| ext == "app") && !path.is_symlink();
+            if is_app {
+                crate::install::copy_bundle(&path, &dest.join(path.file_name().unwrap_or_default()))?;
+            }
+        }
+        Ok(())
+    })();
+    let _ = Command::new("/usr/bin/hdiutil").args(["detach", "-force", "-quiet"]).arg(&mount_point).status();
+    let _ = fs::remove_dir(&mount_point);
+    copied
+}
+
+#[cfg(not(target_os = "macos"))]
+fn extract_dmg(_archive: &Path, _dest: &Path) -> Result<(), AudioRemoteError> {
+    Err(AudioRemoteError::Unsupported("disk images can only be mounted on macOS".into()))
+}
+
+/// Find the single .app bundle at the top of the archive or one folder below it
+fn find_app(dir: &Path, depth: usize) -> Result<Vec<PathBuf>, AudioRemoteError> {
+    let mut apps = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with("__MACOSX") || !path.is_dir() || path.is_symlink() {
+            continue;
+        }
+        if name.ends_with(".app") {
+            apps.push(path);
+        } else if depth > 0 {
+            apps.extend(find_app(&path, depth - 1)?);
+        }
+    }
+    Ok(apps)
+}
+
+/// Check that `app` looks like a runnable bundle and read its identity from Info.plist
+pub fn validate_bundle(app: &Path, expected_bundle_id: Option<&str>) -> Result<BundleInfo, AudioRemoteError> {
+    let plist_path = app.join("Contents/Info.plist");
+    let plist = plist::Value::from_file(&plist_path).map_err(|e| AudioRemoteError::InvalidBundle(format!("can't read {}: {e}", plist_path.display())))?;
+    let dict = plist.as_dictionary().ok_or(AudioRemoteError::InvalidBundle("Info.plist is not a dictionary".into()))?;
+    let string = |key: &str| dict.get(key).and_then(|v| v.as_string()).map(str::to_owned);
+
+    let bundle_identifier = string("CFBundleIdentifier").ok_or(AudioRemoteError::InvalidBundle("Info.plist has no CFBundleIdentifier".into()))?;
+    if let Some(expected) = expected_bundle_id {
+        if bundle_identifier != expected {
+            return Err(AudioRemoteError::InvalidBundle(format!("bundle identifier is {bundle_identifier}, expected {expected}")));
+        }
+    }
+
+    let executable = string("CFBundleExecutable").ok_or(AudioRemoteError::InvalidBundle("Info.plist has no CFBundleExecutable".into()))?;
+    let binary = app.join("Contents/MacOS").join(&executable);
+    let meta = fs::metadata(&binary).map_err(|_| AudioRemoteError::InvalidBundle(format!("missing executable Contents/MacOS/{executable}")))?;
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        return Err(AudioRemoteError::InvalidBundle(format!("Contents/MacOS/{executable} is not executable")));
+    }
+
+    Ok(BundleInfo {
+        path: app.to_path_buf(),
+        bundle_identifier,
+        executable,
+        short_version: string("CFBundleShortVersionString"),
+        build: string("CFBundleVersion"),
+    })
+}
+
+/// Extract a .zip, .tar.gz or .dmg update into `staging_dir` and validate the bundle inside.
+/// `staging_dir` is created if needed and must be empty; it is cleared again on failure.
+pub fn stage_archive(archive: &Path, staging_dir: &Path, expected_bundle_id: Option<&str>) -> Result<BundleInfo, AudioRemoteError> {
+    let kind = archive_kind(archive)?;
+    if staging_dir.exists() && fs::read_dir(staging_dir)?.next().is_some() {
+        return Err(AudioRemoteError::InvalidArgument(format!("staging directory {} is not empty", staging_dir.display())));
+    }
+    fs::create_dir_all(staging_dir).map_err(|e| AudioRemoteError::Io(format!("can't create {}: {e}", staging_dir.display())))?;
+
+    let result = (|| {
+        match kind {
+            ArchiveKind::Zip => extract_zip(archive, staging_dir)?,
+            ArchiveKind::TarGz => extract_tar_gz(archive, staging_dir)?,
+            ArchiveKind::Dmg => extract_dmg(archive, staging_dir)?,
+        }
+        let apps = find_app(staging_dir, 1)?;
+        match apps.as_slice() {
+            [app] => validate_bundle(app, expected_bundle_id),
+            [] => Err(AudioRemoteError::InvalidBundle("archive contains no .app bundle".into())),
+            _ => Err(AudioRemoteError::InvalidBundle("archive contains more than one .app bundle".into())),
+        }
+    })();
+
+    if result.is_err() {
+        let _ = fs::remove_dir_all(staging_dir);
+    }
+    result
+}
+
+/// Unpack a downloaded .zip/.tar.gz/.dmg update into `staging_dir` and validate the bundle
+/// (Info.plist, CFBundleExecutable present and executable, optional bundle id check)
+/// `expected_bundle_id` may be NULL to skip the identifier check.
+/// Returns: path of the staged .app (free with rust_string_free), or NULL on error (see last_error_message)
+///
+/// # Safety
+/// `archive_ptr` and `staging_dir_ptr` must point to valid NUL-terminated strings;
+/// `expected_bundle_id_ptr` must be null or point to one.
+#[no_mangle]
+pub unsafe extern "C" fn update_stage_archive(
+    archive_ptr: *const c_char,
+    staging_dir_ptr: *const c_char,
+    expected_bundle_id_ptr: *const c_char,
+) -> *mut c_char {
+    guard("update_stage_archive", std::ptr::null_mut(), || {
+        let result = str_arg(archive_ptr, "archive").and_then(|archive| {
+            let staging = str_arg(staging_dir_ptr, "staging_dir")?;
+            let expected = match expected_bundle_id_ptr.is_null() {
+                true => None,
+                false => Some(str_arg(expected_bundle_id_ptr, "expected_bundle_id")?),
+            };
+            stage_archive(Path::new(archive), Path::new(staging), expected)
+        });
+        string_result(result.map(|info| info.path.to_string_lossy().into_owned()))
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::delta::tests::temp_dir;
+    use std::io::Write;
+
+    pub(crate) const INFO_PLIST: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0"><dict>
+  <key>CFBundleIdentifier</key><string>com.audioremote.app</string>
+  <key>CFBundleExecutable</key><string>AudioRemote</string>
+  <key>CFBundleShortVersionString</key><string>2.7.0</string>
+  <key>CFBundleVersion</key><string>160</string>
+</dict></plist>"#;
+
+    /// Write a zip containing AudioRemote.app under `prefix`
+    pub(crate) fn write_zip(path: &Path, prefix: &str, executable_mode: u32) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        let opts = zip::write::SimpleFileOptions::default();
+        zip.start_file(format!("{prefix}AudioRemote.app/Contents/Info.plist"), opts).unwrap();
+        zip.write_all(INFO_PLIST.as_bytes()).unwrap();
+        zip.start_file(format!("{prefix}AudioRemote.app/Contents/MacOS/AudioRemote"), opts.unix_permissions(executable_mode))
+            .unwrap();
+        zip.write_all(b"\xcf\xfa\xed\xfe binary").unwrap();
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_stage_zip() {
+        let root = temp_dir("stage-zip");
+        fs::create_dir_all(&root).unwrap();
+        let archive = root.join("AudioRemote-2.7.0.zip");
+        write_zip(&archive, "", 0o755);
+
+        let info = stage_archive(&archive, &root.join("staging"), Some("com.audioremote.app")).unwrap();
+        assert_eq!(info.path, root.join("staging/AudioRemote.app"));
+        assert_eq!(info.short_version.as_deref(), Some("2.7.0"));
+        assert_eq!(info.build.as_deref(), Some("160"));
+
+        // Staging into a non-empty directory is refused
+        assert!(stage_archive(&archive, &root.join("staging"), None).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_stage_tar_gz_in_subfolder() {
+        let root = temp_dir("stage-tgz");
+        let app = root.join("src/Release/AudioRemote.app");
+        fs::create_dir_all(app.join("Contents/MacOS")).unwrap();
+        fs::write(app.join("Contents/Info.plist"), INFO_PLIST).unwrap();
+        fs::write(app.join("Contents/MacOS/AudioRemote"), b"binary").unwrap();
+        fs::set_permissions(app.join("Contents/MacOS/AudioRemote"), fs::Permissions::from_mode(0o755)).unwrap();
+
+        let archive = root.join("update.tar.gz");
+        let gz = flate2::write::GzEncoder::new(File::create(&archive).unwrap(), flate2::Compression::default());
+        let mut tar = tar::Builder::new(gz);
+        tar.append_dir_all("Release", root.join("src/Release")).unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let info = stage_archive(&archive, &root.join("staging"), None).unwrap();
+        assert_eq!(info.path, root.join("staging/Release/AudioRemote.app"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_stage_zip_rejects_links_out_of_staging() {
+        let root = temp_dir("stage-zip-links");
+        let outside = root.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+        let opts = zip::write::SimpleFileOptions::default();
+        let malicious = |name: &str, link: &str| {
+            let archive = root.join(name);
+            let mut zip = zip::ZipWriter::new(File::create(&archive).unwrap());
+            zip.add_symlink("a", link, opts).unwrap();
+            zip.start_file("a/payload", opts).unwrap();
+            zip.write_all(b"owned").unwrap();
+            zip.finish().unwrap();
+            archive
+        };
+
+        for (name, link) in [("absolute.zip", outside.to_str().unwrap()), ("relative.zip", "../outside")] {
+            let staging = root.join("staging");
+            let err = stage_archive(&malicious(name, link), &staging, None).unwrap_err();
+            assert!(matches!(&err, AudioRemoteError::InvalidData(m) if m.contains("unsafe link")), "{err}");
+            assert!(!outside.join("payload").exists() && !staging.exists());
+        }
+
+        // A link that stays inside, as bundles' frameworks have, still writes through
+        fs::create_dir_all(root.join("dest/A")).unwrap();
+        let dest = root.join("dest").canonicalize().unwrap();
+        std::os::unix::fs::symlink("A", dest.join("Current")).unwrap();
+        check_inside(&dest, &dest.join("Current/file")).unwrap();
+        std::os::unix::fs::symlink(&outside, dest.join("away")).unwrap();
+        assert!(check_inside(&dest, &dest.join("away/file")).is_err());
+        assert!(check_inside(&dest, &dest.join("away")).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_stage_rejects_invalid_bundles() {
+        let root = temp_dir("stage-invalid");
+        fs::create_dir_all(&root).unwrap();
+        let archive = root.join("update.zip");
+        write_zip(&archive, "", 0o644);
+
+        let staging = root.join("staging");
+        let err = stage_archive(&archive, &staging, None).unwrap_err();
+        assert!(matches!(&err, AudioRemoteError::InvalidBundle(m) if m.contains("not executable")), "{err}");
+        assert!(!staging.exists());
+
+        write_zip(&archive, "", 0o755);
+        let err = stage_archive(&archive, &staging, Some("com.example.other")).unwrap_err();
+        assert!(matches!(&err, AudioRemoteError::InvalidBundle(m) if m.contains("bundle identifier")), "{err}");
+
+        assert!(stage_archive(&root.join("update.rar"), &staging, None).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,194 @@
+//! Small persisted key-value store for state the Rust layer owns
+//! (skipped versions, check timestamps, ...). Values live in one JSON file
+//! that is rewritten atomically on every change, readable only by the user
+//! since pairing keys and identities are kept in it.
+
+use std::ffi::c_char;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+const FILE_NAME: &str = "state.json";
+
+pub struct Store {
+    path: PathBuf,
+}
+
+/// Serializes read-modify-write cycles so concurrent writers don't drop each other's keys
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+impl Store {
+    pub fn open(directory: &Path) -> Self {
+        Self { path: directory.join(FILE_NAME) }
+    }
+
+    /// A missing file is an empty store; a corrupt one is moved aside to state.json.corrupt and
+    /// the store starts afresh, rather than being overwritten by the next change
+    fn load(&self) -> Result<Map<String, Value>, AudioRemoteError> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Map::new()),
+            Err(e) => return Err(AudioRemoteError::Io(format!("can't read {}: {e}", self.path.display()))),
+        };
+        match serde_json::from_slice(&data) {
+            Ok(map) => Ok(map),
+            Err(e) => {
+                let corrupt = self.path.with_extension("json.corrupt");
+                log::error!("{} is corrupt ({e}); moving it to {} and starting afresh", self.path.display(), corrupt.display());
+                fs::rename(&self.path, &corrupt).map_err(|e| AudioRemoteError::Io(format!("can't move aside {}: {e}", self.path.display())))?;
+                Ok(Map::new())
+            }
+        }
+    }
+
+    fn save(&self, map: &Map<String, Value>) -> Result<(), AudioRemoteError> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(|e| AudioRemoteError::Io(format!("can't create {}: {e}", dir.display())))?;
+        }
+        let tmp = self.path.with_extension("json.tmp");
+        let data = serde_json::to_vec_pretty(map).map_err(|e| e.to_string())?;
+        let write = || {
+            let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+            // `mode` only applies to new files; a tmp left behind keeps its own
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            file.write_all(&data)
+        };
+        write().map_err(|e| AudioRemoteError::Io(format!("can't write {}: {e}", tmp.display())))?;
+        fs::rename(&tmp, &self.path).map_err(|e| AudioRemoteError::Io(format!("can't replace {}: {e}", self.path.display())))
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        match self.load() {
+            Ok(mut map) => map.remove(key).and_then(|v| serde_json::from_value(v).ok()),
+            Err(e) => {
+                log::warn!("{e}");
+                None
+            }
+        }
+    }
+
+    pub fn set<T: Serialize>(&self, key: &str, value: &T) -> Result<(), AudioRemoteError> {
+        let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut map = self.load()?;
+        map.insert(key.to_owned(), serde_json::to_value(value).map_err(|e| e.to_string())?);
+        self.save(&map)
+    }
+
+    pub fn remove(&self, key: &str) -> Result<(), AudioRemoteError> {
+        let _guard = WRITE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut map = self.load()?;
+        if map.remove(key).is_some() {
+            self.save(&map)?;
+        }
+        Ok(())
+    }
+}
+
+static DIRECTORY: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Default location: ~/Library/Application Support/AudioRemote
+fn default_directory() -> PathBuf {
+    let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    home.join("Library/Application Support/AudioRemote")
+}
+
+pub fn set_directory(directory: &Path) {
+    *DIRECTORY.lock().unwrap_or_else(|e| e.into_inner()) = Some(directory.to_path_buf());
+}
+
+/// Directory holding the global state (and caches kept next to it)
+pub fn directory() -> PathBuf {
+    let dir = DIRECTORY.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    dir.unwrap_or_else(default_directory)
+}
+
+/// The store backing the global FFI state
+pub fn global() -> Store {
+    Store::open(&directory())
+}
+
+/// Use `directory` for all persisted Rust-side state (default: ~/Library/Application Support/AudioRemote)
+/// Returns: 1 on success, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `directory_ptr` must point to a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ar_store_set_directory(directory_ptr: *const c_char) -> i32 {
+    guard("ar_store_set_directory", -999, || {
+        let result = str_arg(directory_ptr, "directory").map(|dir| set_directory(Path::new(dir)));
+        match record(result) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::MutexGuard;
+
+    static GLOBAL_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Point the global store at a fresh temp directory for the duration of a test
+    pub(crate) fn with_temp_store(name: &str) -> (MutexGuard<'static, ()>, PathBuf) {
+        let guard = GLOBAL_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = std::env::temp_dir().join(format!("audioremote-store-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        set_directory(&dir);
+        (guard, dir)
+    }
+
+    /// Permission bits of the store in `dir`
+    pub(crate) fn file_mode(dir: &Path) -> u32 {
+        fs::metadata(dir.join(FILE_NAME)).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn test_store_persists_values() {
+        let dir = std::env::temp_dir().join(format!("audioremote-store-basic-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let store = Store::open(&dir);
+        assert_eq!(store.get::<String>("missing"), None);
+        store.set("skippedVersion", &"2.7.0").unwrap();
+        store.set("lastCheck", &1_700_000_000u64).unwrap();
+
+        let reopened = Store::open(&dir);
+        assert_eq!(reopened.get::<String>("skippedVersion").as_deref(), Some("2.7.0"));
+        assert_eq!(reopened.get::<u64>("lastCheck"), Some(1_700_000_000));
+
+        reopened.remove("skippedVersion").unwrap();
+        assert_eq!(Store::open(&dir).get::<String>("skippedVersion"), None);
+
+        // Corrupt files are moved aside instead of being overwritten
+        fs::write(dir.join(FILE_NAME), "{not json").unwrap();
+        assert_eq!(Store::open(&dir).get::<u64>("lastCheck"), None);
+        assert_eq!(fs::read_to_string(dir.join("state.json.corrupt")).unwrap(), "{not json");
+        Store::open(&dir).set("lastCheck", &1u64).unwrap();
+        assert_eq!(Store::open(&dir).get::<u64>("lastCheck"), Some(1));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_store_is_private() {
+        let dir = std::env::temp_dir().join(format!("audioremote-store-mode-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // Left by a version that wrote with the umask
+        fs::write(dir.join(FILE_NAME), "{}").unwrap();
+        fs::set_permissions(dir.join(FILE_NAME), fs::Permissions::from_mode(0o644)).unwrap();
+
+        Store::open(&dir).set("secret", &"key").unwrap();
+        assert_eq!(file_mode(&dir), 0o600);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+//! HTTPS and WSS for the server. On first use the Mac generates a self-signed
+//! certificate that it keeps in the store directory; no CA will vouch for a Mac
+//! on a LAN, so remotes pin its SHA-256 fingerprint instead. They learn it while
+//! pairing, where it's covered by the Mac's key confirmation (see `pairing`), so
+//! a man in the middle can't slip in a certificate of his own.
+//!
+//! The server's threads read and write connections concurrently (a WebSocket
+//! pushes while it reads), so `TlsStream` shares one rustls session between
+//! clones and only holds its lock while moving bytes, never while waiting.
+//! Connections to other Macs (see `connection`) use it too, as the client end,
+//! checking the server's certificate against the pinned fingerprint.
+
+use std::ffi::c_char;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use rcgen::{CertificateParams, DnType, KeyPair};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::{ServerName, UnixTime};
+use rustls::{ClientConnection, Connection, DigitallySignedStruct, ServerConnection, SignatureScheme};
+use sha2::{Digest, Sha256};
+
+use crate::error::string_result;
+use crate::{guard, interfaces, mdns, store, AudioRemoteError};
+
+const CERT_FILE: &str = "tls/certificate.der";
+const KEY_FILE: &str = "tls/key.der";
+
+/// The Mac's certificate and private key, DER
+pub struct Identity {
+    pub certificate: Vec<u8>,
+    key: Vec<u8>,
+}
+
+impl Identity {
+    /// Make a new self-signed certificate for this Mac's advertised host name
+    pub fn generate() -> Result<Self, AudioRemoteError> {
+        let failed = |e: rcgen::Error| AudioRemoteError::Other(format!("can't create a certificate: {e}"));
+        let mut params = CertificateParams::new(vec![mdns::host_name(), "localhost".into()]).map_err(failed)?;
+        params.distinguished_name.push(DnType::CommonName, "Audio Remote");
+        let key = KeyPair::generate().map_err(failed)?;
+        let certificate = params.self_signed(&key).map_err(failed)?;
+        Ok(Self { certificate: certificate.der().to_vec(), key: key.serialize_der() })
+    }
+
+    /// The identity kept in `directory`, created there if it's missing or unreadable
+    pub fn load_or_create(directory: &Path) -> Result<Self, AudioRemoteError> {
+        let (cert_path, key_path) = (directory.join(CERT_FILE), directory.join(KEY_FILE));
+        if let (Ok(certificate), Ok(key)) = (fs::read(&cert_path), fs::read(&key_path)) {
+            let identity = Self { certificate, key };
+            if identity.server_config().is_ok() {
+                return Ok(identity);
+            }
+            log::warn!("the stored certificate is unusable; making a new one");
+        }
+        let identity = Self::generate()?;
+        identity.save(&cert_path, &key_path)?;
+        log::info!("created a TLS certificate, fingerprint {}", identity.fingerprint());
+        Ok(identity)
+    }
+
+    fn save(&self, cert_path: &PathBuf, key_path: &PathBuf) -> Result<(), AudioRemoteError> {
+        let io = |path: &Path, e: io::Error| AudioRemoteError::Io(format!("can't write {}: {e}", path.display()));
+        if let Some(dir) = key_path.parent() {
+            fs::create_dir_all(dir).map_err(|e| io(dir, e))?;
+        }
+        fs::write(key_path, &self.key).map_err(|e| io(key_path, e))?;
+        fs::set_permissions(key_path, fs::Permissions::from_mode(0o600)).map_err(|e| io(key_path, e))?;
+        fs::write(cert_path, &self.certificate).map_err(|e| io(cert_path, e))
+    }
+
+    /// SHA-256 of the certificate, lowercase hex: what remotes pin
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.certificate)
+    }
+
+    pub fn server_config(&self) -> Result<Arc<rustls::ServerConfig>, AudioRemoteError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key.clone()));
+        let config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .with_no_client_auth()
+            .with_single_cert(vec![CertificateDer::from(self.certificate.clone())], key)
+            .map_err(|e| AudioRemoteError::InvalidData(format!("invalid certificate or key: {e}")))?;
+        Ok(Arc::new(config))
+    }
+
+    /// Sign with the certificate's key (ECDSA P-256 SHA-256, DER), so anyone who pinned the
+    /// certificate can tell the message came from this Mac
+    pub fn sign(&self, message: &[u8]) -> Result<Vec<u8>, AudioRemoteError> {
+        let key = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(self.key.clone()));
+        let key = rustls::crypto::ring::sign::any_ecdsa_type(&key).map_err(|e| AudioRemoteError::InvalidData(format!("invalid key: {e}")))?;
+        let signer = key
+            .choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256])
+            .ok_or_else(|| AudioRemoteError::Unsupported("the key isn't ECDSA P-256".into()))?;
+        signer.sign(message).map_err(|e| AudioRemoteError::Other(format!("can't sign: {e}")))
+    }
+}
+
+/// Check a signature made by `Identity::sign` against a certificate
+pub fn verify(certificate: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let certificate = CertificateDer::from(certificate);
+    webpki::EndEntityCert::try_from(&certificate)
+        .is_ok_and(|certificate| certificate.verify_signature(webpki::ring::ECDSA_P256_SHA256, message, signature).is_ok())
+}
+
+pub fn fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The identity in the global store directory
+pub fn identity() -> Result<Identity, AudioRemoteError> {
+    Identity::load_or_create(&store::directory())
+}
+
+/// Wait until `socket` has bytes to read; false on timeout
+fn wait_readable(socket: &TcpStream, timeout: Option<Duration>) -> io::Result<bool> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let millis = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()).as_millis().min(i32::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+        let mut fd = libc::pollfd { fd: socket.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        match unsafe { libc::poll(&mut fd, 1, millis) } {
+            0 => return Ok(false),
+            n if n > 0 => return Ok(true),
+            _ => {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    return Err(error);
+                }
+            }
+        }
+    }
+}
+
+struct Shared {
+    socket: TcpStream,
+    session: Mutex<Connection>,
+    read_timeout: Mutex<Option<Duration>>,
+}
+
+/// A TLS connection; clones share the session, one may read while another writes
+#[derive(Clone)]
+pub(crate) struct TlsStream {
+    shared: Arc<Shared>,
+}
+
+impl TlsStream {
+    /// Wrap an accepted connection; the handshake happens on the first read
+    pub(crate) fn new(socket: TcpStream, config: Arc<rustls::ServerConfig>) -> io::Result<Self> {
+        let session = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(Self::wrap(socket, session.into()))
+    }
+
+    /// Connect as a client; the handshake happens on the first read or write
+    pub(crate) fn client(socket: TcpStream, config: Arc<rustls::ClientConfig>, host: &str) -> io::Result<Self> {
+        let name = ServerName::try_from(interfaces::bare_host(host).to_owned()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let session = ClientConnection::new(config, name).map_err(io::Error::other)?;
+        Ok(Self::wrap(socket, session.into()))
+    }
+
+    fn wrap(socket: TcpStream, session: Connection) -> Self {
+        Self { shared: Arc::new(Shared { socket, session: Mutex::new(session), read_timeout: Mutex::new(None) }) }
+    }
+
+    fn session(&self) -> MutexGuard<'_, Connection> {
+        self.shared.session.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn flush_tls(&self, session: &mut Connection) -> io::Result<()> {
+        while session.wants_write() {
+            session.write_tls(&mut &self.shared.socket)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        *self.shared.read_timeout.lock().unwrap_or_else(|e| e.into_inner()) = timeout;
+        Ok(())
+    }
+
+    /// Send close_notify, then shut the socket down
+    pub(crate) fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        {
+            let mut session = self.session();
+            session.send_close_notify();
+            let _ = self.flush_tls(&mut session);
+        }
+        self.shared.socket.shutdown(how)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            let timeout = *self.shared.read_timeout.lock().unwrap_or_else(|e| e.into_inner());
+            if !wait_readable(&self.shared.socket, timeout)? {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "TLS read timed out"));
+            }
+            let mut session = self.session();
+            match session.read_tls(&mut &self.shared.socket) {
+                // The peer closed without close_notify
+                Ok(0) => return Ok(0),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            if let Err(e) = session.process_new_packets() {
+                // Tell the peer why before giving up
+                let _ = self.flush_tls(&mut session);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+            }
+            self.flush_tls(&mut session)?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session();
+        let written = session.writer().write(buf)?;
+        self.flush_tls(&mut session)?;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let mut session = self.session();
+        session.writer().flush()?;
+        self.flush_tls(&mut session)
+    }
+}
+
+/// Accepts exactly the certificate with a pinned fingerprint, whatever its name or issuer:
+/// the Mac's certificate is self-signed, and remotes learn its fingerprint while pairing
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+    provider: Arc<rustls::crypto::CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match fingerprint(end_entity).eq_ignore_ascii_case(&self.fingerprint) {
+            true => Ok(ServerCertVerified::assertion()),
+            false => Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure)),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// A client configuration that trusts only the certificate with `fingerprint` (SHA-256, hex)
+pub fn pinned_client_config(fingerprint: &str) -> Result<Arc<rustls::ClientConfig>, AudioRemoteError> {
+    if fingerprint.len() != 64 || !fingerprint.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(AudioRemoteError::InvalidArgument(format!("\"{fingerprint}\" isn't a SHA-256 fingerprint")));
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let verifier = Arc::new(PinnedCertificate { fingerprint: fingerprint.to_owned(), provider: provider.clone() });
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Returns: the SHA-256 fingerprint of this Mac's TLS certificate as lowercase hex, creating the
+/// certificate if there's none yet (free with rust_string_free), NULL on error (see last_error_message)
+#[no_mangle]
+pub extern "C" fn ar_tls_fingerprint() -> *mut c_char {
+    guard("ar_tls_fingerprint", std::ptr::null_mut(), || string_result(identity().map(|identity| identity.fingerprint())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delta::tests::temp_dir;
+    use crate::server;
+
+    #[test]
+    fn test_identity_persists() {
+        let dir = temp_dir("tls-identity");
+        let first = Identity::load_or_create(&dir).unwrap();
+        let again = Identity::load_or_create(&dir).unwrap();
+        assert_eq!(first.fingerprint(), again.fingerprint());
+        assert_eq!(first.fingerprint().len(), 64);
+        assert_eq!(fs::metadata(dir.join(KEY_FILE)).unwrap().permissions().mode() & 0o777, 0o600);
+
+        fs::write(dir.join(KEY_FILE), b"garbage").unwrap();
+        let second = Identity::load_or_create(&dir).unwrap();
+        assert_ne!(second.fingerprint(), first.fingerprint());
+
+        let signature = first.sign(b"hello").unwrap();
+        assert!(verify(&first.certificate, b"hello", &signature));
+        assert!(!verify(&first.certificate, b"hullo", &signature));
+        assert!(!verify(&second.certificate, b"hello", &signature));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_server_speaks_https() {
+        let _runtime = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_lock, _dir) = crate::store::tests::with_temp_store("tls-server");
+        let config = server::ServerConfig { port: 0, bind_address: "127.0.0.1".parse().unwrap(), require_auth: false, tls: true, ..Default::default() };
+        let address = server::start(&config).unwrap();
+        let fingerprint = server::certificate_fingerprint().unwrap();
+
+        // What a remote does after pairing: trust exactly the pinned certificate
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(CertificateDer::from(identity().unwrap().certificate)).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let session = rustls::ClientConnection::new(Arc::new(client), "localhost".try_into().unwrap()).unwrap();
+        let mut stream = rustls::StreamOwned::new(session, TcpStream::connect(address).unwrap());
+        stream.write_all(b"GET /api/v1/nothing HTTP/1.1\r\nHost: mac\r\nConnection: close\r\n\r\n").unwrap();
+        let mut reply = Vec::new();
+        let _ = stream.read_to_end(&mut reply);
+        let presented = stream.conn.peer_certificates().unwrap()[0].to_vec();
+        server::stop();
+
+        assert!(String::from_utf8_lossy(&reply).starts_with("HTTP/1.1 404"), "{}", String::from_utf8_lossy(&reply));
+        assert_eq!(super::fingerprint(&presented), fingerprint);
+    }
+}
@@ -0,0 +1,143 @@
+//! True-peak measurement as in ITU-R BS.1770-4 Annex 2: each channel is
+//! oversampled four times with the recommendation's 48-tap polyphase
+//! interpolator and the peak taken over the oversampled signal. Sample peaks
+//! miss the overshoot between samples that a DAC or a lossy decoder will
+//! reconstruct, by up to 3 dB on a full-scale tone at a quarter of the rate.
+//!
+//! The taps are exact in f32, and the four phases are worked out together as
+//! the lanes of a vector (see `simd`).
+//!
+//! The interpolator looks `LATENCY` frames back: the peak reported for a frame
+//! is that of the signal around the frame `LATENCY` earlier.
+
+use crate::simd::F32x4;
+
+/// The four phases of BS.1770-4's interpolating filter, 12 taps each
+const PHASES: [[f64; 12]; 4] = [
+    [
+        0.0017089843750, 0.0109863281250, -0.0196533203125, 0.0332031250000, -0.0594482421875, 0.1373291015625, 0.9721679687500,
+        -0.1022949218750, 0.0476074218750, -0.0266113281250, 0.0148925781250, -0.0083007812500,
+    ],
+    [
+        -0.0291748046875, 0.0292968750000, -0.0517578125000, 0.0891113281250, -0.1665039062500, 0.4650878906250, 0.7797851562500,
+        -0.2003173828125, 0.1015625000000, -0.0582275390625, 0.0330810546875, -0.0189208984375,
+    ],
+    [
+        -0.0189208984375, 0.0330810546875, -0.0582275390625, 0.1015625000000, -0.2003173828125, 0.7797851562500, 0.4650878906250,
+        -0.1665039062500, 0.0891113281250, -0.0517578125000, 0.0292968750000, -0.0291748046875,
+    ],
+    [
+        -0.0083007812500, 0.0148925781250, -0.0266113281250, 0.0476074218750, -0.1022949218750, 0.9721679687500, 0.1373291015625,
+        -0.0594482421875, 0.0332031250000, -0.0196533203125, 0.0109863281250, 0.0017089843750,
+    ],
+];
+const TAPS: usize = 12;
+
+/// Frames between a sample going in and the peak around it coming out
+pub const LATENCY: usize = TAPS / 2;
+
+/// A true-peak detector for interleaved audio
+#[derive(Debug, Clone)]
+pub struct TruePeak {
+    /// PHASES by tap, the four phases in a vector's lanes, so a window is interpolated at once
+    taps: [F32x4; TAPS],
+    /// Each channel's last TAPS samples, twice over so a window never wraps
+    history: Vec<[f32; TAPS * 2]>,
+    at: usize,
+    /// Each channel's highest true peak since the last reset, linear
+    peaks: Vec<f64>,
+}
+
+impl TruePeak {
+    pub fn new(channels: usize) -> Self {
+        let taps = std::array::from_fn(|tap| F32x4::load(&PHASES.map(|phase| phase[tap] as f32)));
+        Self { taps, history: vec![[0.0; TAPS * 2]; channels], at: 0, peaks: vec![0.0; channels] }
+    }
+
+    pub fn channel_count(&self) -> usize {
+        self.history.len()
+    }
+
+    /// Take one frame, one sample per channel; returns the frame's highest true peak, linear
+    pub fn push(&mut self, frame: &[f64]) -> f64 {
+        self.at = (self.at + 1) % TAPS;
+        let mut highest = 0.0f64;
+        for ((history, peak), &sample) in self.history.iter_mut().zip(&mut self.peaks).zip(frame) {
+            history[self.at] = sample as f32;
+            history[self.at + TAPS] = sample as f32;
+            // Newest first, as the taps expect
+            let window = &history[self.at + 1..self.at + 1 + TAPS];
+            let values = self.taps.iter().zip(window.iter().rev()).fold(F32x4::splat(0.0), |sum, (&tap, &sample)| sum + tap * F32x4::splat(sample));
+            let value = f64::from(values.abs().max_lane());
+            highest = highest.max(value);
+            *peak = peak.max(value);
+        }
+        highest
+    }
+
+    /// Measure interleaved samples; a partial frame at the end is ignored and samples that
+    /// aren't finite count as silence
+    pub fn process(&mut self, samples: &[f32]) {
+        let mut frame = vec![0.0; self.channel_count()];
+        for chunk in samples.chunks_exact(self.channel_count()) {
+            for (out, &sample) in frame.iter_mut().zip(chunk) {
+                *out = if sample.is_finite() { f64::from(sample) } else { 0.0 };
+            }
+            self.push(&frame);
+        }
+    }
+
+    /// Each channel's highest true peak since the last reset, linear
+    pub fn peaks(&self) -> &[f64] {
+        &self.peaks
+    }
+
+    /// The highest over all channels, in dBTP; -∞ for silence
+    pub fn peak_db(&self) -> f64 {
+        20.0 * self.peaks.iter().fold(0.0f64, |highest, &peak| highest.max(peak)).log10()
+    }
+
+    pub fn reset(&mut self) {
+        self.history.iter_mut().for_each(|history| history.fill(0.0));
+        self.peaks.fill(0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A full-scale sine at a quarter of the sample rate, sampled 45° off its peaks
+    fn quarter_rate(frames: usize) -> Vec<f32> {
+        (0..frames).map(|i| (std::f64::consts::FRAC_PI_2 * i as f64 + std::f64::consts::FRAC_PI_4).sin() as f32).collect()
+    }
+
+    #[test]
+    fn test_finds_inter_sample_peaks() {
+        let samples = quarter_rate(4800);
+        let sample_peak = samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!((20.0 * sample_peak.log10() + 3.01).abs() < 0.01, "{sample_peak}");
+        let mut meter = TruePeak::new(1);
+        meter.process(&samples);
+        // BS.1770's filter gets within a few tenths of a dB of the real 0 dBTP
+        assert!(meter.peak_db().abs() < 0.7, "{}", meter.peak_db());
+        meter.reset();
+        assert_eq!(meter.peak_db(), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn test_low_frequencies_match_sample_peak() {
+        let tone: Vec<f32> = (0..48_000).map(|i| (0.5 * (2.0 * std::f64::consts::PI * 997.0 * i as f64 / 48_000.0).sin()) as f32).collect();
+        let stereo: Vec<f32> = tone.iter().flat_map(|&sample| [sample, sample * 0.25]).collect();
+        let mut meter = TruePeak::new(2);
+        meter.process(&stereo);
+        assert!((meter.peaks()[0] - 0.5).abs() < 0.005 && (meter.peaks()[1] - 0.125).abs() < 0.002, "{:?}", meter.peaks());
+
+        // An impulse comes out LATENCY frames later at close to its own height; the frame before
+        // ends on the interpolated point a quarter sample short of it
+        let mut meter = TruePeak::new(1);
+        let heights: Vec<f64> = (0..TAPS).map(|i| meter.push(&[if i == 0 { 1.0 } else { 0.0 }])).collect();
+        assert!(heights[LATENCY] > 0.97 && heights[LATENCY - 1] > 0.97, "{heights:?}");
+        assert!(heights.iter().enumerate().all(|(i, &height)| i + 1 >= LATENCY && i <= LATENCY || height < 0.25), "{heights:?}");
+    }
+}
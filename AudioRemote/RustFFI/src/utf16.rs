@@ -0,0 +1,123 @@
+//! UTF-16 variants of the hot version helpers. Swift can hand over a
+//! `String.utf16` buffer (pointer + code unit count, no terminator) directly,
+//! instead of allocating a NUL-terminated C string for every comparison.
+
+use semver::Version;
+
+use crate::channel::channel_arg;
+use crate::version::ParseMode;
+use crate::{guard, has_update_on_channel, ordering_code, parse_version_arg, record, satisfies, AudioRemoteError};
+
+/// Decode `len` UTF-16 code units; NULL is only accepted for an empty string
+unsafe fn utf16_arg(ptr: *const u16, len: usize, name: &str) -> Result<String, AudioRemoteError> {
+    if ptr.is_null() {
+        return match len {
+            0 => Ok(String::new()),
+            _ => Err(AudioRemoteError::InvalidArgument(format!("{name} is null"))),
+        };
+    }
+    String::from_utf16(std::slice::from_raw_parts(ptr, len))
+        .map_err(|_| AudioRemoteError::InvalidArgument(format!("{name} is not valid UTF-16")))
+}
+
+unsafe fn version_arg_utf16(ptr: *const u16, len: usize, name: &str) -> Result<Version, AudioRemoteError> {
+    parse_version_arg(&utf16_arg(ptr, len, name)?, name, ParseMode::Strict)
+}
+
+/// version_compare for UTF-16 buffers
+/// Returns: -1 if v1 < v2, 0 if equal, 1 if v1 > v2, -999 on parse error (see last_error_message)
+///
+/// # Safety
+/// Each pointer must point to `len` readable UTF-16 code units (or be null when `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn version_compare_utf16(v1_ptr: *const u16, v1_len: usize, v2_ptr: *const u16, v2_len: usize) -> i32 {
+    guard("version_compare_utf16", -999, || {
+        let parsed = version_arg_utf16(v1_ptr, v1_len, "v1").and_then(|v1| Ok((v1, version_arg_utf16(v2_ptr, v2_len, "v2")?)));
+        match record(parsed) {
+            Some((v1, v2)) => ordering_code(v1.cmp(&v2)),
+            None => -999,
+        }
+    })
+}
+
+/// version_has_update_on_channel for UTF-16 buffers
+/// Returns: true if latest > current and latest is published on the channel
+///
+/// # Safety
+/// Each pointer must point to `len` readable UTF-16 code units (or be null when `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn version_has_update_on_channel_utf16(
+    current_ptr: *const u16,
+    current_len: usize,
+    latest_ptr: *const u16,
+    latest_len: usize,
+    channel: i32,
+) -> bool {
+    guard("version_has_update_on_channel_utf16", false, || {
+        let result = version_arg_utf16(current_ptr, current_len, "current").and_then(|current| {
+            let latest = version_arg_utf16(latest_ptr, latest_len, "latest")?;
+            Ok(has_update_on_channel(&current, &latest, channel_arg(channel)?))
+        });
+        record(result).unwrap_or(false)
+    })
+}
+
+/// version_satisfies for UTF-16 buffers
+/// Returns: 1 if it matches, 0 if not, -999 on parse error (see last_error_message)
+///
+/// # Safety
+/// Each pointer must point to `len` readable UTF-16 code units (or be null when `len` is 0).
+#[no_mangle]
+pub unsafe extern "C" fn version_satisfies_utf16(
+    version_ptr: *const u16,
+    version_len: usize,
+    range_ptr: *const u16,
+    range_len: usize,
+) -> i32 {
+    guard("version_satisfies_utf16", -999, || {
+        let result = version_arg_utf16(version_ptr, version_len, "version")
+            .and_then(|version| satisfies(&version, &utf16_arg(range_ptr, range_len, "range")?));
+        match record(result) {
+            Some(matches) => matches as i32,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::UpdateChannel;
+
+    fn utf16(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn test_utf16_variants_match_c_strings() {
+        let (a, b) = (utf16("2.6.0"), utf16("v2.10.0"));
+        assert_eq!(unsafe { version_compare_utf16(a.as_ptr(), a.len(), b.as_ptr(), b.len()) }, -1);
+        assert_eq!(unsafe { version_compare_utf16(b.as_ptr(), b.len(), a.as_ptr(), a.len()) }, 1);
+
+        let beta = utf16("2.7.0-beta.1");
+        let stable = UpdateChannel::Stable as i32;
+        assert!(!unsafe { version_has_update_on_channel_utf16(a.as_ptr(), a.len(), beta.as_ptr(), beta.len(), stable) });
+        let channel = UpdateChannel::Beta as i32;
+        assert!(unsafe { version_has_update_on_channel_utf16(a.as_ptr(), a.len(), beta.as_ptr(), beta.len(), channel) });
+
+        let range = utf16(">=2.5.0, <3.0.0");
+        assert_eq!(unsafe { version_satisfies_utf16(a.as_ptr(), a.len(), range.as_ptr(), range.len()) }, 1);
+    }
+
+    #[test]
+    fn test_utf16_errors() {
+        let lone_surrogate = [0x32, 0xd800];
+        let ok = utf16("1.0.0");
+        assert_eq!(unsafe { version_compare_utf16(lone_surrogate.as_ptr(), 2, ok.as_ptr(), ok.len()) }, -999);
+        assert!(matches!(unsafe { utf16_arg(lone_surrogate.as_ptr(), 2, "v1") }, Err(AudioRemoteError::InvalidArgument(m)) if m == "v1 is not valid UTF-16"));
+        assert_eq!(unsafe { utf16_arg(std::ptr::null(), 0, "v1") }.unwrap(), "");
+        assert!(unsafe { utf16_arg(std::ptr::null(), 3, "v1") }.is_err());
+        let two = utf16("2");
+        assert_eq!(unsafe { version_compare_utf16(two.as_ptr(), two.len(), ok.as_ptr(), ok.len()) }, -999);
+    }
+}
@@ -0,0 +1,168 @@
+use semver::Version;
+
+/// How strictly version strings are parsed
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Only valid semver, optionally with a leading 'v'
+    Strict = 0,
+    /// Real-world tags like "2.6", "2.6.0.1" or "v2.6.0 (142)" are normalized first
+    Lenient = 1,
+}
+
+impl ParseMode {
+    pub fn from_raw(raw: i32) -> Option<Self> {
+        match raw {
+            0 => Some(Self::Strict),
+            1 => Some(Self::Lenient),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a version string in the given mode
+pub fn parse(s: &str, mode: ParseMode) -> Result<Version, String> {
+    let stripped = s.strip_prefix('v').unwrap_or(s);
+    match mode {
+        ParseMode::Strict => Version::parse(stripped).map_err(|e| e.to_string()),
+        ParseMode::Lenient => {
+            let normalized = normalize_lenient(s)?;
+            Version::parse(&normalized).map_err(|e| e.to_string())
+        }
+    }
+}
+
+/// Rewrite a loosely formatted version tag as semver.
+///
+/// Missing minor/patch components become 0, a fourth component and a trailing
+/// "(142)" or "build 142" become build metadata, and leading zeros are dropped.
+/// Build metadata keeps "2.6.0.1" ordered after "2.6.0".
+pub fn normalize_lenient(s: &str) -> Result<String, String> {
+    let mut rest = s.trim();
+    rest = rest.strip_prefix(['v', 'V']).unwrap_or(rest);
+
+    let mut build: Vec<String> = Vec::new();
+
+    // Trailing "(142)" build annotation
+    if let Some(open) = rest.rfind('(') {
+        if let Some(inner) = rest[open + 1..].strip_suffix(')') {
+            push_build(&mut build, inner.trim());
+            rest = rest[..open].trim_end();
+        }
+    }
+    // Trailing "build 142" annotation
+    if let Some(idx) = rest.to_ascii_lowercase().rfind(" build ") {
+        push_build(&mut build, rest[idx + 7..].trim());
+        rest = rest[..idx].trim_end();
+    }
+
+    // Split the numeric core from any -prerelease/+build suffix
+    let core_end = rest.find(['-', '+']).unwrap_or(rest.len());
+    let (core, suffix) = rest.split_at(core_end);
+    let mut suffix = suffix.to_owned();
+
+    let mut numbers = Vec::new();
+    for part in core.split('.') {
+        let n: u64 = part
+            .parse()
+            .map_err(|_| format!("\"{part}\" is not a numeric version component"))?;
+        numbers.push(n);
+    }
+    while numbers.len() < 3 {
+        numbers.push(0);
+    }
+    for extra in numbers.drain(3..).rev() {
+        build.insert(0, extra.to_string());
+    }
+
+    let mut out = format!("{}.{}.{}", numbers[0], numbers[1], numbers[2]);
+    if !build.is_empty() {
+        let extra = build.join(".");
+        match suffix.find('+') {
+            Some(_) => suffix = format!("{suffix}.{extra}"),
+            None => suffix = format!("{suffix}+{extra}"),
+        }
+    }
+    out.push_str(&suffix);
+    Ok(out)
+}
+
+/// Compare CFBundleVersion-style build numbers ("142", "142.1") component-wise
+pub fn compare_build_numbers(a: &str, b: &str) -> Result<std::cmp::Ordering, String> {
+    let components = |s: &str| -> Result<Vec<u64>, String> {
+        s.trim()
+            .split('.')
+            .map(|p| p.parse().map_err(|_| format!("\"{s}\" is not a numeric build number")))
+            .collect()
+    };
+    let (mut a, mut b) = (components(a)?, components(b)?);
+    let len = a.len().max(b.len());
+    a.resize(len, 0);
+    b.resize(len, 0);
+    Ok(a.cmp(&b))
+}
+
+/// Indices of `versions` in ascending semver order, keeping only the first of
+/// entries that parse to the same version; unparseable entries are left out
+pub fn sort_indices(versions: &[&str], mode: ParseMode) -> Vec<usize> {
+    let mut parsed: Vec<(Version, usize)> =
+        versions.iter().enumerate().filter_map(|(i, s)| parse(s, mode).ok().map(|v| (v, i))).collect();
+    // Sorting by (version, index) keeps the earliest duplicate first
+    parsed.sort();
+    parsed.dedup_by(|later, first| later.0 == first.0);
+    parsed.into_iter().map(|(_, i)| i).collect()
+}
+
+/// Add a build annotation as dot-separated semver identifiers
+fn push_build(build: &mut Vec<String>, annotation: &str) {
+    build.extend(
+        annotation
+            .split(|c: char| !c.is_ascii_alphanumeric() && c != '-')
+            .filter(|p| !p.is_empty())
+            .map(str::to_owned),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sort_indices() {
+        let versions = ["2.10.0", "2.9.0", "v2.10", "2.10.0-rc.1", "garbage", "2.10.0-beta.2", "2.9.0.1"];
+        assert_eq!(sort_indices(&versions, ParseMode::Lenient), vec![1, 6, 5, 3, 0]);
+        assert_eq!(sort_indices(&versions, ParseMode::Strict), vec![1, 5, 3, 0]);
+    }
+
+    #[test]
+    fn test_normalize_lenient() {
+        assert_eq!(normalize_lenient("2.6").unwrap(), "2.6.0");
+        assert_eq!(normalize_lenient("2").unwrap(), "2.0.0");
+        assert_eq!(normalize_lenient("2.6.0.1").unwrap(), "2.6.0+1");
+        assert_eq!(normalize_lenient("v2.6.0 (142)").unwrap(), "2.6.0+142");
+        assert_eq!(normalize_lenient("2.6.0 build 42").unwrap(), "2.6.0+42");
+        assert_eq!(normalize_lenient(" V2.06.0-beta.1 ").unwrap(), "2.6.0-beta.1");
+        assert_eq!(normalize_lenient("2.6.0.1+abc").unwrap(), "2.6.0+abc.1");
+        assert!(normalize_lenient("two.six").is_err());
+        assert!(normalize_lenient("").is_err());
+    }
+
+    #[test]
+    fn test_lenient_ordering() {
+        let p = |s| parse(s, ParseMode::Lenient).unwrap();
+        assert!(p("2.6.0.1") > p("2.6"));
+        assert!(p("2.10") > p("2.9.9"));
+        assert!(p("v2.6.0 (150)") > p("v2.6.0 (142)"));
+        assert!(parse("2.6", ParseMode::Strict).is_err());
+    }
+
+    #[test]
+    fn test_compare_build_numbers() {
+        use std::cmp::Ordering::*;
+        assert_eq!(compare_build_numbers("142", "150").unwrap(), Less);
+        assert_eq!(compare_build_numbers("99", "100").unwrap(), Less);
+        assert_eq!(compare_build_numbers("142.1", "142").unwrap(), Greater);
+        assert_eq!(compare_build_numbers("142.0", "142").unwrap(), Equal);
+        assert!(compare_build_numbers("142a", "150").is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! Volume tapers: how far along a slider maps to the gain that's set. Loudness
+//! is heard roughly logarithmically, so a linear slider does almost nothing in
+//! its top half and everything near the bottom. The default taper is
+//! exponential instead, a fixed number of dB per unit of travel over
+//! `rangeDb`, with the bottom tenth faded linearly so the end of the slider is
+//! silence; `power` (gain = position^exponent) is the cheaper approximation
+//! some apps use, and `linear` is the identity.
+//!
+//! Positions and gains are 0.0–1.0; 0 dB is full scale and silence is
+//! negative infinity dB.
+
+use std::ffi::c_char;
+
+use serde::Deserialize;
+
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+/// Below this position the exponential taper fades linearly to silence
+const FADE_POSITION: f64 = 0.1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Curve {
+    #[default]
+    Exponential,
+    Power,
+    Linear,
+}
+
+/// A taper; every field is optional
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct Taper {
+    #[serde(default)]
+    pub curve: Curve,
+    /// The exponential curve's span, from the fade to full scale
+    #[serde(default = "default_range_db")]
+    pub range_db: f64,
+    /// The power curve's exponent
+    #[serde(default = "default_exponent")]
+    pub exponent: f64,
+    /// Steps from silence to full scale, for `step`
+    #[serde(default = "default_steps")]
+    pub steps: u32,
+}
+
+fn default_range_db() -> f64 {
+    60.0
+}
+
+fn default_exponent() -> f64 {
+    3.0
+}
+
+/// As many as the keyboard's volume keys
+fn default_steps() -> u32 {
+    16
+}
+
+impl Default for Taper {
+    fn default() -> Self {
+        Self { curve: Curve::Exponential, range_db: default_range_db(), exponent: default_exponent(), steps: default_steps() }
+    }
+}
+
+/// The scales a value can be converted between; values are part of the C ABI
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scale {
+    Position = 0,
+    Gain = 1,
+    Decibels = 2,
+}
+
+impl Scale {
+    pub fn from_raw(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(Self::Position),
+            1 => Some(Self::Gain),
+            2 => Some(Self::Decibels),
+            _ => None,
+        }
+    }
+}
+
+pub fn gain_to_db(gain: f64) -> f64 {
+    match gain <= 0.0 {
+        true => f64::NEG_INFINITY,
+        false => 20.0 * gain.log10(),
+    }
+}
+
+pub fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+impl Taper {
+    pub fn validate(&self) -> Result<(), AudioRemoteError> {
+        let invalid = |message: String| Err(AudioRemoteError::InvalidArgument(message));
+        if !(1.0..=120.0).contains(&self.range_db) {
+            return invalid(format!("rangeDb {} is outside 1–120", self.range_db));
+        }
+        if !(0.1..=10.0).contains(&self.exponent) {
+            return invalid(format!("exponent {} is outside 0.1–10", self.exponent));
+        }
+        if !(1..=1000).contains(&self.steps) {
+            return invalid(format!("steps {} is outside 1–1000", self.steps));
+        }
+        Ok(())
+    }
+
+    /// The exponential curve's gain where the fade ends
+    fn fade_gain(&self) -> f64 {
+        db_to_gain(self.range_db * (FADE_POSITION - 1.0))
+    }
+
+    pub fn gain(&self, position: f64) -> f64 {
+        let position = position.clamp(0.0, 1.0);
+        match self.curve {
+            Curve::Linear => position,
+            Curve::Power => position.powf(self.exponent),
+            Curve::Exponential if position < FADE_POSITION => self.fade_gain() * position / FADE_POSITION,
+            Curve::Exponential => db_to_gain(self.range_db * (position - 1.0)),
+        }
+    }
+
+    pub fn position(&self, gain: f64) -> f64 {
+        let gain = gain.clamp(0.0, 1.0);
+        match self.curve {
+            Curve::Linear => gain,
+            Curve::Power => gain.powf(1.0 / self.exponent),
+            Curve::Exponential if gain < self.fade_gain() => FADE_POSITION * gain / self.fade_gain(),
+            Curve::Exponential => (1.0 + gain_to_db(gain) / self.range_db).clamp(0.0, 1.0),
+        }
+    }
+
+    pub fn convert(&self, value: f64, from: Scale, to: Scale) -> Result<f64, AudioRemoteError> {
+        if value.is_nan() || (from != Scale::Decibels && value.is_infinite()) {
+            return Err(AudioRemoteError::InvalidArgument(format!("{value} isn't a volume")));
+        }
+        let gain = match from {
+            Scale::Position => self.gain(value),
+            Scale::Gain => value.clamp(0.0, 1.0),
+            Scale::Decibels => db_to_gain(value).min(1.0),
+        };
+        Ok(match to {
+            Scale::Position => self.position(gain),
+            Scale::Gain => gain,
+            Scale::Decibels => gain_to_db(gain),
+        })
+    }
+
+    /// The gain `steps` steps up (or down, if negative) from `gain`, counting from the nearest
+    /// step, so repeated presses land on the same gains whatever the volume started at
+    pub fn step(&self, gain: f64, steps: i32) -> f64 {
+        let count = f64::from(self.steps);
+        let current = (self.position(gain) * count).round();
+        self.gain(((current + f64::from(steps)) / count).clamp(0.0, 1.0))
+    }
+}
+
+fn taper_arg(taper_json: *const c_char) -> Result<Taper, AudioRemoteError> {
+    let taper = match taper_json.is_null() {
+        true => Taper::default(),
+        false => {
+            let json = unsafe { str_arg(taper_json, "taper") }?;
+            serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid taper: {e}")))?
+        }
+    };
+    taper.validate()?;
+    Ok(taper)
+}
+
+fn write_out(out: *mut f64, result: Result<f64, AudioRemoteError>) -> i32 {
+    let result = result.and_then(|value| match out.is_null() {
+        true => Err(AudioRemoteError::InvalidArgument("out is null".into())),
+        false => Ok(value),
+    });
+    match record(result) {
+        Some(value) => {
+            unsafe { *out = value };
+            1
+        }
+        None => -999,
+    }
+}
+
+/// Convert a volume between scales (ArVolumeScale): slider position and scalar gain (0.0–1.0,
+/// clamped) and dB (0 at full scale, -INFINITY for silence). `taper_json` (nullable): {"curve":
+/// "exponential" (default) | "power" | "linear", "rangeDb" (exponential, default 60), "exponent"
+/// (power, default 3), "steps" (for ar_volume_step, default 16)}.
+/// Returns: 1 with the result in `out`, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `taper_json` must be null or point to a valid NUL-terminated string, and `out` to a writable double.
+#[no_mangle]
+pub unsafe extern "C" fn ar_volume_convert(value: f64, from: i32, to: i32, taper_json: *const c_char, out: *mut f64) -> i32 {
+    guard("ar_volume_convert", -999, || {
+        let scale = |raw: i32| Scale::from_raw(raw).ok_or(AudioRemoteError::InvalidArgument(format!("unknown volume scale {raw}")));
+        let result = taper_arg(taper_json).and_then(|taper| taper.convert(value, scale(from)?, scale(to)?));
+        write_out(out, result)
+    })
+}
+
+/// The gain after `steps` volume-key presses (negative: down) from `gain`, the same perceived
+/// change each press; counts from the nearest of the taper's "steps". `taper_json` as for ar_volume_convert.
+/// Returns: 1 with the new gain in `out`, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `taper_json` must be null or point to a valid NUL-terminated string, and `out` to a writable double.
+#[no_mangle]
+pub unsafe extern "C" fn ar_volume_step(gain: f64, steps: i32, taper_json: *const c_char, out: *mut f64) -> i32 {
+    guard("ar_volume_step", -999, || {
+        let result = taper_arg(taper_json).and_then(|taper| match gain.is_finite() {
+            true => Ok(taper.step(gain, steps)),
+            false => Err(AudioRemoteError::InvalidArgument(format!("{gain} isn't a gain"))),
+        });
+        write_out(out, result)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn test_curves() {
+        let taper = Taper::default();
+        assert_eq!((taper.gain(0.0), taper.gain(1.0)), (0.0, 1.0));
+        // 60 dB over the slider: half way is -30 dB, and each tenth is 6 dB
+        assert!(close(gain_to_db(taper.gain(0.5)), -30.0));
+        assert!(close(gain_to_db(taper.gain(0.8)) - gain_to_db(taper.gain(0.7)), 6.0));
+        // The fade meets the curve, and silence is at the bottom
+        assert!(close(taper.gain(FADE_POSITION - 1e-12), taper.gain(FADE_POSITION)));
+        assert!(close(taper.gain(0.05), taper.fade_gain() / 2.0));
+        for curve in [Curve::Exponential, Curve::Power, Curve::Linear] {
+            let taper = Taper { curve, ..Taper::default() };
+            let mut last = -1.0;
+            for i in 0..=100 {
+                let position = f64::from(i) / 100.0;
+                let gain = taper.gain(position);
+                assert!(gain > last, "{curve:?} isn't increasing at {position}");
+                assert!(close(taper.position(gain), position), "{curve:?} at {position}");
+                last = gain;
+            }
+        }
+        assert!(close(Taper { curve: Curve::Power, exponent: 2.0, ..Taper::default() }.gain(0.5), 0.25));
+        assert!(Taper { range_db: 0.0, ..Taper::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn test_convert_and_step() {
+        let taper = Taper::default();
+        assert!(close(taper.convert(-30.0, Scale::Decibels, Scale::Position).unwrap(), 0.5));
+        assert_eq!(taper.convert(0.0, Scale::Gain, Scale::Decibels).unwrap(), f64::NEG_INFINITY);
+        assert_eq!(taper.convert(f64::NEG_INFINITY, Scale::Decibels, Scale::Position).unwrap(), 0.0);
+        assert_eq!(taper.convert(6.0, Scale::Decibels, Scale::Gain).unwrap(), 1.0);
+        assert!(taper.convert(f64::NAN, Scale::Gain, Scale::Position).is_err());
+
+        // Steps are even in dB, from the nearest step, and stop at the ends
+        let up = taper.step(taper.gain(0.5), 1);
+        assert!(close(gain_to_db(up) - gain_to_db(taper.gain(0.5)), 60.0 / 16.0));
+        assert!(close(taper.step(taper.gain(0.51), -2), taper.gain(6.0 / 16.0)));
+        assert_eq!(taper.step(0.9, 40), 1.0);
+        assert_eq!(taper.step(0.1, -40), 0.0);
+
+        let mut out = 0.0;
+        assert_eq!(unsafe { ar_volume_convert(0.5, 0, 2, std::ptr::null(), &mut out) }, 1);
+        assert!(close(out, -30.0));
+        let linear = c"{\"curve\": \"linear\"}";
+        assert_eq!(unsafe { ar_volume_convert(0.5, 0, 1, linear.as_ptr(), &mut out) }, 1);
+        assert_eq!(out, 0.5);
+        assert_eq!(unsafe { ar_volume_convert(0.5, 0, 7, std::ptr::null(), &mut out) }, -999);
+        assert_eq!(unsafe { ar_volume_step(0.5, 1, c"{\"steps\": 0}".as_ptr(), &mut out) }, -999);
+        assert_eq!(unsafe { ar_volume_step(0.0, 16, std::ptr::null(), std::ptr::null_mut()) }, -999);
+        assert_eq!(unsafe { ar_volume_step(0.0, 16, std::ptr::null(), &mut out) }, 1);
+        assert_eq!(out, 1.0);
+    }
+}
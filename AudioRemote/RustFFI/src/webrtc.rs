@@ -0,0 +1,527 @@
+//! WebRTC data channels, for a direct channel to a remote outside the LAN
+//! when the networks in between allow one (`relay` covers the rest). The
+//! remote makes an SDP offer with one data channel and gets it to the Mac by
+//! whatever signaling it has; `ar_webrtc_answer` takes the offer from Swift and
+//! returns the answer. Candidates aren't trickled: the answer holds them all,
+//! the Mac's own addresses and, with STUN servers configured, the public
+//! address a NAT maps each one to. ICE then finds a pair that works and DTLS
+//! encrypts it.
+//!
+//! Nothing ties the DTLS certificates to a pairing, so the data channel
+//! carries a `noise` channel just as `relay` does, one Noise message per
+//! data-channel message: whoever passes the offer and answer along can't read
+//! the channel or stand in for either end.
+//!
+//! str0m does the protocols without doing I/O. Each peer connection has a
+//! driver thread that feeds it datagrams and timeouts, and a reader thread per
+//! UDP socket.
+
+use std::collections::BTreeMap;
+use std::ffi::c_char;
+use std::io;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use str0m::change::{SdpAnswer, SdpOffer};
+use str0m::channel::ChannelId;
+use str0m::net::{Protocol, Receive};
+use str0m::{Candidate, Event, IceConnectionState, Input, Output, Rtc, RtcConfig};
+
+use crate::error::string_result;
+use crate::noise::{self, Inbox, Incoming, Outgoing};
+use crate::pairing::random_bytes;
+use crate::{guard, interfaces, str_arg, webrtc_crypto, AudioRemoteError};
+
+const STUN_PORT: u16 = 3478;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_RESPONSE: u16 = 0x0101;
+const STUN_MAPPED_ADDRESS: u16 = 0x0001;
+const STUN_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+/// How long STUN servers get to answer, all together
+const STUN_TIMEOUT: Duration = Duration::from_secs(1);
+/// ICE and DTLS must be done, and the channel open, within this
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// Reader threads look at whether the peer connection ended this often
+const READ_POLL: Duration = Duration::from_millis(500);
+const MAX_PEERS: usize = 8;
+const MAX_DATAGRAM: usize = 2048;
+
+/// `ar_webrtc_answer` configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct WebRtcConfig {
+    /// "host[:port]", port 3478 if not given
+    #[serde(default)]
+    pub stun_servers: Vec<String>,
+}
+
+/// A STUN Binding Request (RFC 8489) with `transaction` as its ID
+pub(crate) fn binding_request(transaction: &[u8; 12]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(20);
+    request.extend_from_slice(&STUN_BINDING_REQUEST.to_be_bytes());
+    request.extend_from_slice(&0u16.to_be_bytes());
+    request.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+    request.extend_from_slice(transaction);
+    request
+}
+
+/// The address a Binding Response to `transaction` says the request came from
+pub(crate) fn mapped_address(response: &[u8], transaction: &[u8; 12]) -> Option<SocketAddr> {
+    let header = response.get(..20)?;
+    if u16::from_be_bytes([header[0], header[1]]) != STUN_BINDING_RESPONSE
+        || header[4..8] != STUN_MAGIC_COOKIE.to_be_bytes()
+        || &header[8..20] != transaction
+    {
+        return None;
+    }
+    let length = usize::from(u16::from_be_bytes([header[2], header[3]]));
+    let mut attributes = response.get(20..20 + length)?;
+    let mut plain = None;
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let size = usize::from(u16::from_be_bytes([attributes[2], attributes[3]]));
+        let value = attributes.get(4..4 + size)?;
+        match kind {
+            STUN_XOR_MAPPED_ADDRESS => {
+                // The port is XORed with the cookie's top half, the address with the cookie and,
+                // for IPv6, the transaction ID after it
+                let mask: Vec<u8> = STUN_MAGIC_COOKIE.to_be_bytes().into_iter().chain(transaction.iter().copied()).collect();
+                let mut value = value.to_vec();
+                for (i, byte) in value.iter_mut().enumerate().skip(2) {
+                    *byte ^= mask.get(if i < 4 { i - 2 } else { i - 4 }).copied().unwrap_or(0);
+                }
+                return address_value(&value);
+            }
+            STUN_MAPPED_ADDRESS => plain = address_value(value),
+            _ => {}
+        }
+        // Attributes are padded to 4 bytes
+        attributes = attributes.get(4 + size.next_multiple_of(4)..).unwrap_or_default();
+    }
+    plain
+}
+
+/// A (XOR-)MAPPED-ADDRESS value, already unXORed: reserved, family, port, address
+fn address_value(value: &[u8]) -> Option<SocketAddr> {
+    let port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+    let ip = match value.get(1)? {
+        1 => IpAddr::from(<[u8; 4]>::try_from(value.get(4..8)?).ok()?),
+        2 => IpAddr::from(<[u8; 16]>::try_from(value.get(4..20)?).ok()?),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Ask the STUN servers what each socket looks like from outside: (public address, socket address)
+fn gather(sockets: &[UdpSocket], servers: &[SocketAddr]) -> Vec<(SocketAddr, SocketAddr)> {
+    let deadline = Instant::now() + STUN_TIMEOUT;
+    let mut mapped = Vec::new();
+    for socket in sockets {
+        let Ok(base) = socket.local_addr() else {
+            continue;
+        };
+        let mut transactions = Vec::new();
+        for server in servers.iter().filter(|server| server.is_ipv4() == base.is_ipv4()) {
+            let Ok(transaction) = random_bytes::<12>() else {
+                continue;
+            };
+            if socket.send_to(&binding_request(&transaction), server).is_ok() {
+                transactions.push(transaction);
+            }
+        }
+        let mut buffer = [0; MAX_DATAGRAM];
+        while !transactions.is_empty() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || socket.set_read_timeout(Some(remaining)).is_err() {
+                break;
+            }
+            let Ok((length, _)) = socket.recv_from(&mut buffer) else {
+                break;
+            };
+            // The first answer is enough
+            if let Some(address) = transactions.iter().find_map(|transaction| mapped_address(&buffer[..length], transaction)) {
+                if address != base && !mapped.contains(&(address, base)) {
+                    mapped.push((address, base));
+                }
+                break;
+            }
+        }
+    }
+    mapped
+}
+
+/// "stun.example.com" or "stun.example.com:19302" to addresses; servers that don't resolve are skipped
+fn resolve_stun_servers(servers: &[String]) -> Vec<SocketAddr> {
+    servers
+        .iter()
+        .flat_map(|server| {
+            let resolved = match server.rsplit_once(':').and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?))) {
+                Some((host, port)) => (host, port).to_socket_addrs(),
+                None => (server.as_str(), STUN_PORT).to_socket_addrs(),
+            };
+            resolved.inspect_err(|e| log::warn!("can't resolve STUN server {server}: {e}")).into_iter().flatten()
+        })
+        .collect()
+}
+
+fn new_rtc() -> Rtc {
+    RtcConfig::new().set_crypto_provider(Arc::new(webrtc_crypto::provider())).build(Instant::now())
+}
+
+/// What a peer connection's driver thread hears about
+enum Drive {
+    /// (the socket it arrived on, where from, the datagram)
+    Datagram(SocketAddr, SocketAddr, Vec<u8>),
+    /// A Noise message for the data channel
+    Send(Vec<u8>),
+    Close,
+}
+
+/// The data channel's sending end
+struct ChannelOut {
+    drive: mpsc::Sender<Drive>,
+    closed: AtomicBool,
+}
+
+impl Outgoing for ChannelOut {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.drive.send(Drive::Send(message.to_vec())).map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn shutdown(&self) -> io::Result<()> {
+        if !self.closed.swap(true, Ordering::AcqRel) {
+            let _ = self.drive.send(Drive::Close);
+        }
+        Ok(())
+    }
+}
+
+/// What runs once the data channel is open: the Mac serves a Noise channel on it
+type OnOpen = Box<dyn FnOnce(Box<dyn Outgoing>, Box<dyn Incoming>) + Send>;
+
+fn serving(static_key: Vec<u8>) -> OnOpen {
+    Box::new(move |outgoing, incoming| {
+        if let Err(e) = noise::serve(outgoing, incoming, "WebRTC data channel", &static_key) {
+            log::debug!("WebRTC data channel ended: {e}");
+        }
+    })
+}
+
+/// Open peer connections, to close at shutdown
+static PEERS: Mutex<BTreeMap<u64, mpsc::Sender<Drive>>> = Mutex::new(BTreeMap::new());
+static NEXT_PEER: AtomicU64 = AtomicU64::new(1);
+
+fn peers() -> std::sync::MutexGuard<'static, BTreeMap<u64, mpsc::Sender<Drive>>> {
+    PEERS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Run str0m until the connection fails, the channel closes or either side hangs up
+fn drive(mut rtc: Rtc, sockets: &[Arc<UdpSocket>], inputs: &mpsc::Receiver<Drive>, drive: &mpsc::Sender<Drive>, on_open: OnOpen) -> Result<(), String> {
+    let failed = |e: str0m::RtcError| e.to_string();
+    let started = Instant::now();
+    let mut on_open = Some(on_open);
+    let mut channel: Option<(ChannelId, mpsc::Sender<Vec<u8>>)> = None;
+    loop {
+        let deadline = loop {
+            match rtc.poll_output().map_err(failed)? {
+                Output::Timeout(deadline) => break deadline,
+                Output::Transmit(transmit) => {
+                    if let Some(socket) = sockets.iter().find(|socket| socket.local_addr().ok() == Some(transmit.source)) {
+                        let _ = socket.send_to(&transmit.contents, transmit.destination);
+                    }
+                }
+                Output::Event(Event::IceConnectionStateChange(IceConnectionState::Disconnected)) => return Ok(()),
+                Output::Event(Event::ChannelOpen(id, _)) if channel.is_none() => {
+                    let (inbox, messages) = mpsc::channel();
+                    channel = Some((id, inbox));
+                    let outgoing = ChannelOut { drive: drive.clone(), closed: AtomicBool::new(false) };
+                    if let Some(on_open) = on_open.take() {
+                        thread::Builder::new()
+                            .name("audioremote-webrtc-channel".into())
+                            .spawn(move || on_open(Box::new(outgoing), Box::new(Inbox::new(messages))))
+                            .map_err(|e| format!("can't start the channel thread: {e}"))?;
+                    }
+                }
+                Output::Event(Event::ChannelData(data)) => {
+                    if let Some((_, inbox)) = channel.as_ref().filter(|(id, _)| *id == data.id) {
+                        let _ = inbox.send(data.data);
+                    }
+                }
+                Output::Event(Event::ChannelClose(id)) if channel.as_ref().is_some_and(|(open, _)| *open == id) => return Ok(()),
+                Output::Event(_) => {}
+            }
+        };
+        if channel.is_none() && started.elapsed() >= CONNECT_TIMEOUT {
+            return Err(format!("no data channel within {} s", CONNECT_TIMEOUT.as_secs()));
+        }
+        // Wake now and then to check the connect timeout
+        let wait = deadline.saturating_duration_since(Instant::now()).min(READ_POLL);
+        let input = match inputs.recv_timeout(wait) {
+            Err(RecvTimeoutError::Timeout) => Input::Timeout(Instant::now()),
+            Err(RecvTimeoutError::Disconnected) | Ok(Drive::Close) => {
+                rtc.disconnect();
+                return Ok(());
+            }
+            Ok(Drive::Send(message)) => {
+                let Some(mut open) = channel.as_ref().and_then(|(id, _)| rtc.channel(*id)) else {
+                    return Err("the data channel closed".into());
+                };
+                if !open.write(true, &message).map_err(failed)? {
+                    return Err("the data channel is congested".into());
+                }
+                continue;
+            }
+            Ok(Drive::Datagram(destination, source, datagram)) => {
+                // Anything that isn't STUN, DTLS or media is noise on the port
+                if let Ok(receive) = Receive::new(Protocol::Udp, source, destination, &datagram) {
+                    rtc.handle_input(Input::Receive(Instant::now(), receive)).map_err(failed)?;
+                }
+                continue;
+            }
+        };
+        rtc.handle_input(input).map_err(failed)?;
+    }
+}
+
+fn read_datagrams(socket: &UdpSocket, drive: &mpsc::Sender<Drive>, done: &AtomicBool) {
+    let Ok(local) = socket.local_addr() else {
+        return;
+    };
+    let mut buffer = [0; MAX_DATAGRAM];
+    while !done.load(Ordering::Acquire) {
+        match socket.recv_from(&mut buffer) {
+            Ok((length, source)) => {
+                if drive.send(Drive::Datagram(local, source, buffer[..length].to_vec())).is_err() {
+                    return;
+                }
+            }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) => {}
+            Err(_) => return,
+        }
+    }
+}
+
+/// Start a peer connection's threads; it's in PEERS until it ends
+fn spawn_peer(rtc: Rtc, sockets: Vec<UdpSocket>, on_open: OnOpen) -> Result<(), AudioRemoteError> {
+    let spawn_failed = |e: io::Error| AudioRemoteError::Other(format!("can't start the WebRTC thread: {e}"));
+    let id = NEXT_PEER.fetch_add(1, Ordering::Relaxed);
+    let (sender, inputs) = mpsc::channel();
+    let done = Arc::new(AtomicBool::new(false));
+    let sockets: Vec<Arc<UdpSocket>> = sockets.into_iter().map(Arc::new).collect();
+    for socket in &sockets {
+        socket.set_read_timeout(Some(READ_POLL))?;
+        let (socket, sender, done) = (socket.clone(), sender.clone(), done.clone());
+        thread::Builder::new()
+            .name("audioremote-webrtc-read".into())
+            .spawn(move || read_datagrams(&socket, &sender, &done))
+            .map_err(spawn_failed)?;
+    }
+    peers().insert(id, sender.clone());
+    let spawned = {
+        let done = done.clone();
+        thread::Builder::new().name(format!("audioremote-webrtc-{id}")).spawn(move || {
+            match drive(rtc, &sockets, &inputs, &sender, on_open) {
+                Ok(()) => log::debug!("WebRTC peer {id} closed"),
+                Err(e) => log::info!("WebRTC peer {id} ended: {e}"),
+            }
+            done.store(true, Ordering::Release);
+            peers().remove(&id);
+        })
+    };
+    if let Err(e) = spawned {
+        done.store(true, Ordering::Release);
+        peers().remove(&id);
+        return Err(spawn_failed(e));
+    }
+    Ok(())
+}
+
+/// Answer `offer` with candidates on `addresses`, and run the connection
+fn accept(offer: SdpOffer, addresses: &[IpAddr], stun_servers: &[SocketAddr], on_open: OnOpen) -> Result<SdpAnswer, AudioRemoteError> {
+    if peers().len() >= MAX_PEERS {
+        return Err(AudioRemoteError::Refused(format!("{MAX_PEERS} WebRTC peers are connected already")));
+    }
+    let sockets: Vec<UdpSocket> = addresses.iter().filter_map(|address| UdpSocket::bind((*address, 0)).ok()).collect();
+    let mut rtc = new_rtc();
+    let mut candidates = 0;
+    for socket in &sockets {
+        if let Some(candidate) = socket.local_addr().ok().and_then(|address| Candidate::host(address, "udp").ok()) {
+            candidates += usize::from(rtc.add_local_candidate(candidate).is_some());
+        }
+    }
+    if candidates == 0 {
+        return Err(AudioRemoteError::Network("no address to offer WebRTC candidates on".into()));
+    }
+    for (address, base) in gather(&sockets, stun_servers) {
+        if let Ok(candidate) = Candidate::server_reflexive(address, base, "udp") {
+            rtc.add_local_candidate(candidate);
+        }
+    }
+    let answer = rtc.sdp_api().accept_offer(offer).map_err(|e| AudioRemoteError::InvalidArgument(format!("can't accept the offer: {e}")))?;
+    spawn_peer(rtc, sockets, on_open)?;
+    Ok(answer)
+}
+
+/// Answer a remote's offer with every candidate this Mac has, and serve the data channel it opens
+pub fn answer(offer: SdpOffer, config: &WebRtcConfig) -> Result<SdpAnswer, AudioRemoteError> {
+    // Link-local addresses need a zone, which candidates can't carry
+    let addresses: Vec<IpAddr> =
+        interfaces::local_addresses()?.into_iter().filter(|address| !address.is_link_local()).map(|address| address.address).collect();
+    accept(offer, &addresses, &resolve_stun_servers(&config.stun_servers), serving(noise::static_key()?))
+}
+
+/// Close every peer connection
+pub fn stop() {
+    for drive in peers().values() {
+        let _ = drive.send(Drive::Close);
+    }
+}
+
+/// Answer a remote's WebRTC offer, for a direct channel when it can't reach the Mac's server,
+/// e.g. on a cellular network. Carry the offer and answer over any channel you have; the data
+/// channel runs the Noise handshake (see ar_noise_start) before anything else, so only a paired
+/// remote gets through. `offer_json`: {"type": "offer", "sdp"}, as RTCPeerConnection makes it,
+/// with one data channel and no trickled candidates. `config_json` (nullable): {"stunServers"
+/// (["host[:port]"]: servers to learn the Mac's public address from, port 3478 by default)}.
+/// Returns: the answer JSON {"type": "answer", "sdp"} (free with rust_string_free), NULL on error
+/// (see last_error_message)
+///
+/// # Safety
+/// `offer_json` must point to a valid NUL-terminated string; `config_json` must be null or do so.
+#[no_mangle]
+pub unsafe extern "C" fn ar_webrtc_answer(offer_json: *const c_char, config_json: *const c_char) -> *mut c_char {
+    guard("ar_webrtc_answer", std::ptr::null_mut(), || {
+        let config = match config_json.is_null() {
+            true => Ok(WebRtcConfig::default()),
+            false => str_arg(config_json, "config").and_then(|json| {
+                serde_json::from_str(json).map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid WebRTC config: {e}")))
+            }),
+        };
+        let result = config.and_then(|config| {
+            let offer: SdpOffer = serde_json::from_str(str_arg(offer_json, "offer")?)
+                .map_err(|e| AudioRemoteError::InvalidArgument(format!("invalid WebRTC offer: {e}")))?;
+            let answer = answer(offer, &config)?;
+            serde_json::to_string(&answer).map_err(|e| AudioRemoteError::Other(e.to_string()))
+        });
+        string_result(result)
+    })
+}
+
+/// Close every WebRTC peer connection; the remotes may offer again. Also done by ar_shutdown.
+#[no_mangle]
+pub extern "C" fn ar_webrtc_stop() {
+    guard("ar_webrtc_stop", (), stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Topic, HUB};
+    use crate::pairing::{self, Pairing};
+    use crate::protocol::{Envelope, Hello, Message};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use serde_json::json;
+    use std::net::Ipv4Addr;
+
+    /// A Binding Response with one attribute
+    fn response(transaction: &[u8; 12], kind: u16, value: &[u8]) -> Vec<u8> {
+        let mut response = STUN_BINDING_RESPONSE.to_be_bytes().to_vec();
+        response.extend_from_slice(&(4 + value.len() as u16).to_be_bytes());
+        response.extend_from_slice(&STUN_MAGIC_COOKIE.to_be_bytes());
+        response.extend_from_slice(transaction);
+        response.extend_from_slice(&kind.to_be_bytes());
+        response.extend_from_slice(&(value.len() as u16).to_be_bytes());
+        response.extend_from_slice(value);
+        response
+    }
+
+    #[test]
+    fn test_stun() {
+        let transaction = [7; 12];
+        let request = binding_request(&transaction);
+        assert_eq!(request[..8], [0, 1, 0, 0, 0x21, 0x12, 0xa4, 0x42]);
+        assert_eq!(request.len(), 20);
+
+        // 203.0.113.5:40000, XORed with the cookie
+        let xored = [0, 1, 40000u16.to_be_bytes()[0] ^ 0x21, 40000u16.to_be_bytes()[1] ^ 0x12, 203 ^ 0x21, 0x12, 113 ^ 0xa4, 5 ^ 0x42];
+        let expected: SocketAddr = "203.0.113.5:40000".parse().unwrap();
+        assert_eq!(mapped_address(&response(&transaction, STUN_XOR_MAPPED_ADDRESS, &xored), &transaction), Some(expected));
+        assert_eq!(mapped_address(&response(&transaction, STUN_XOR_MAPPED_ADDRESS, &xored), &[8; 12]), None);
+        let plain = [0, 1, 0x9c, 0x40, 203, 0, 113, 5];
+        assert_eq!(mapped_address(&response(&transaction, STUN_MAPPED_ADDRESS, &plain), &transaction), Some(expected));
+        assert_eq!(mapped_address(&request, &transaction), None);
+        assert_eq!(mapped_address(&[1, 1, 0], &transaction), None);
+
+        // A STUN server on loopback that says the socket is 198.51.100.7:5000
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_address = server.local_addr().unwrap();
+        let answering = thread::spawn(move || {
+            let mut buffer = [0; 64];
+            let (length, from) = server.recv_from(&mut buffer).unwrap();
+            assert_eq!(length, 20);
+            let transaction: [u8; 12] = buffer[8..20].try_into().unwrap();
+            server.send_to(&response(&transaction, STUN_MAPPED_ADDRESS, &[0, 1, 0x13, 0x88, 198, 51, 100, 7]), from).unwrap();
+        });
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let base = socket.local_addr().unwrap();
+        assert_eq!(gather(&[socket], &[server_address]), [("198.51.100.7:5000".parse().unwrap(), base)]);
+        answering.join().unwrap();
+        assert_eq!(resolve_stun_servers(&["127.0.0.1:19302".into(), "127.0.0.1".into()]).len(), 2);
+    }
+
+    #[test]
+    fn test_data_channel() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (_store, dir) = crate::store::tests::with_temp_store("webrtc");
+        let key = [3; 32];
+        pairing::save(Pairing { remote_id: "phone".into(), remote_name: "Phone".into(), key: STANDARD.encode(key), paired_at: 0 }).unwrap();
+
+        // The remote's side, offering a data channel from loopback
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut remote = new_rtc();
+        remote.add_local_candidate(Candidate::host(socket.local_addr().unwrap(), "udp").unwrap());
+        let mut change = remote.sdp_api();
+        change.add_channel("audioremote".into());
+        let (offer, pending) = change.apply().unwrap();
+        let offer: SdpOffer = serde_json::from_str(&serde_json::to_string(&offer).unwrap()).unwrap();
+
+        let static_key = noise::static_key().unwrap();
+        let answer = accept(offer, &[IpAddr::V4(Ipv4Addr::LOCALHOST)], &[], serving(static_key)).unwrap();
+        let answer: SdpAnswer = serde_json::from_value(serde_json::to_value(&answer).unwrap()).unwrap();
+        remote.sdp_api().accept_answer(pending, answer).unwrap();
+        let (opened, channel) = mpsc::channel();
+        let initiate = Box::new(move |outgoing: Box<dyn Outgoing>, incoming: Box<dyn Incoming>| {
+            let _ = opened.send(noise::initiate(outgoing, incoming, "phone", &key));
+        });
+        spawn_peer(remote, vec![socket], initiate).unwrap();
+
+        let (mut sender, mut receiver, mac_id) = channel.recv_timeout(Duration::from_secs(20)).unwrap().unwrap();
+        assert_eq!(mac_id, pairing::mac_id().unwrap());
+        sender.send(&Envelope::new(Some(1), Message::Hello(Hello::mac())).encode()).unwrap();
+        loop {
+            let envelope = Envelope::decode(&receiver.receive().unwrap()).unwrap();
+            if envelope.id == Some(1) {
+                assert_eq!(envelope.message.kind(), "welcome");
+                break;
+            }
+        }
+        HUB.publish(Topic::Volume, json!({"volume": 0.625, "muted": false}));
+        loop {
+            let envelope = Envelope::decode(&receiver.receive().unwrap()).unwrap();
+            if envelope.to_json()["body"]["volume"] == json!(0.625) {
+                break;
+            }
+        }
+
+        stop();
+        assert!(receiver.receive().is_err());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
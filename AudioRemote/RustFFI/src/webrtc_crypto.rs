@@ -0,0 +1,273 @@
+//! str0m's crypto on the stack the rest of the crate uses: DTLS from dimpl
+//! with its RustCrypto backend, the DTLS certificate from rcgen on ring, and
+//! SRTP, STUN's HMAC-SHA1 and fingerprints from RustCrypto. str0m's own
+//! RustCrypto provider makes its certificates with aws-lc-rs, which would be
+//! a second native crypto library in the binary, and one that doesn't
+//! cross-compile for the Mac.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes_gcm::aead::generic_array::GenericArray;
+use aes_gcm::aead::{Aead, Payload};
+use aes_gcm::{Aes128Gcm, Aes256Gcm, Nonce};
+use ctr::cipher::{KeyIvInit, StreamCipher};
+use hmac::{Hmac, Mac};
+use rcgen::{CertificateParams, DnType, KeyPair, SerialNumber, PKCS_ECDSA_P256_SHA256};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use str0m::crypto::dtls::{DtlsCert, DtlsImplError, DtlsInstance, DtlsOutput, DtlsProvider, DtlsVersion, ProtocolVersion};
+use str0m::crypto::{AeadAes128GcmCipher, AeadAes256GcmCipher, Aes128CmSha1_80Cipher, CryptoError, CryptoProvider};
+use str0m::crypto::{Sha1HmacProvider, Sha256Provider, SrtpProvider, SupportedAeadAes128Gcm, SupportedAeadAes256Gcm, SupportedAes128CmSha1_80};
+
+use crate::pairing::random_bytes;
+
+pub(crate) fn provider() -> CryptoProvider {
+    CryptoProvider { srtp_provider: &Srtp, sha1_hmac_provider: &Hashes, sha256_provider: &Hashes, dtls_provider: &Dtls }
+}
+
+#[derive(Debug)]
+struct Hashes;
+
+impl Sha1HmacProvider for Hashes {
+    fn sha1_hmac(&self, key: &[u8], payloads: &[&[u8]]) -> [u8; 20] {
+        let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(key).expect("HMAC takes any key length");
+        for payload in payloads {
+            mac.update(payload);
+        }
+        mac.finalize().into_bytes().into()
+    }
+}
+
+impl Sha256Provider for Hashes {
+    fn sha256(&self, data: &[u8]) -> [u8; 32] {
+        Sha256::digest(data).into()
+    }
+}
+
+#[derive(Debug)]
+struct Dtls;
+
+impl DtlsProvider for Dtls {
+    fn generate_certificate(&self) -> Option<DtlsCert> {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).ok()?;
+        let mut params = CertificateParams::new(Vec::<String>::new()).ok()?;
+        params.distinguished_name.push(DnType::CommonName, "Audio Remote");
+        // Firefox refuses a serial it has seen before, from any peer
+        params.serial_number = Some(SerialNumber::from(random_bytes::<16>().ok()?.to_vec()));
+        let certificate = params.self_signed(&key).ok()?;
+        Some(DtlsCert { certificate: certificate.der().to_vec(), private_key: key.serialize_der() })
+    }
+
+    fn new_dtls(&self, cert: &DtlsCert, now: Instant, version: DtlsVersion, mtu: Option<usize>) -> Result<Box<dyn DtlsInstance>, CryptoError> {
+        // ICE has already checked the peer can receive at its address, so no cookie exchange
+        let mut builder = dimpl::Config::builder().use_server_cookie(false);
+        if let Some(mtu) = mtu {
+            builder = builder.mtu(mtu);
+        }
+        let config = Arc::new(builder.build().map_err(|e| CryptoError::Other(format!("can't configure DTLS: {e}")))?);
+        let cert = cert.clone();
+        let dtls = match version {
+            DtlsVersion::Dtls12 => dimpl::Dtls::new_12(config, cert, now),
+            DtlsVersion::Dtls13 => dimpl::Dtls::new_13(config, cert, now),
+            DtlsVersion::Auto => dimpl::Dtls::new_auto(config, cert, now),
+            version => return Err(CryptoError::Other(format!("{version} isn't supported"))),
+        };
+        Ok(Box::new(Session(dtls)))
+    }
+}
+
+struct Session(dimpl::Dtls);
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session").finish_non_exhaustive()
+    }
+}
+
+impl DtlsInstance for Session {
+    fn set_active(&mut self, active: bool) {
+        self.0.set_active(active);
+    }
+
+    fn handle_packet(&mut self, packet: &[u8]) -> Result<(), DtlsImplError> {
+        self.0.handle_packet(packet)
+    }
+
+    fn poll_output<'a>(&mut self, buf: &'a mut [u8]) -> DtlsOutput<'a> {
+        self.0.poll_output(buf)
+    }
+
+    fn handle_timeout(&mut self, now: Instant) -> Result<(), DtlsImplError> {
+        self.0.handle_timeout(now)
+    }
+
+    fn send_application_data(&mut self, data: &[u8]) -> Result<(), DtlsImplError> {
+        self.0.send_application_data(data)
+    }
+
+    fn is_active(&self) -> bool {
+        self.0.is_active()
+    }
+
+    fn protocol_version(&self) -> Option<ProtocolVersion> {
+        self.0.protocol_version()
+    }
+
+    fn is_closing(&self) -> bool {
+        self.0.is_closing()
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+
+    fn close(&mut self) -> Result<(), DtlsImplError> {
+        self.0.close()
+    }
+}
+
+#[derive(Debug)]
+struct Srtp;
+
+impl SrtpProvider for Srtp {
+    fn aes_128_cm_sha1_80(&self) -> &'static dyn SupportedAes128CmSha1_80 {
+        &Srtp
+    }
+
+    fn aead_aes_128_gcm(&self) -> &'static dyn SupportedAeadAes128Gcm {
+        &Srtp
+    }
+
+    fn aead_aes_256_gcm(&self) -> &'static dyn SupportedAeadAes256Gcm {
+        &Srtp
+    }
+
+    fn srtp_aes_128_ecb_round(&self, key: &[u8], input: &[u8], output: &mut [u8]) {
+        ecb_round(&aes::Aes128::new(GenericArray::from_slice(key)), input, output);
+    }
+
+    fn srtp_aes_256_ecb_round(&self, key: &[u8], input: &[u8], output: &mut [u8]) {
+        ecb_round(&aes::Aes256::new(GenericArray::from_slice(key)), input, output);
+    }
+}
+
+/// One block of AES-ECB with PKCS#7 padding, as OpenSSL does it: the block, then a block of padding
+fn ecb_round(cipher: &impl BlockEncrypt, input: &[u8], output: &mut [u8]) {
+    assert!(input.len() == 16 && output.len() >= 32);
+    output[..16].copy_from_slice(input);
+    output[16..32].fill(16);
+    let (first, second) = output[..32].split_at_mut(16);
+    cipher.encrypt_block(GenericArray::from_mut_slice(first));
+    cipher.encrypt_block(GenericArray::from_mut_slice(second));
+}
+
+impl SupportedAes128CmSha1_80 for Srtp {
+    fn create_cipher(&self, key: [u8; 16], _encrypt: bool) -> Box<dyn Aes128CmSha1_80Cipher> {
+        Box::new(AesCounter(key))
+    }
+}
+
+impl SupportedAeadAes128Gcm for Srtp {
+    fn create_cipher(&self, key: [u8; 16], _encrypt: bool) -> Box<dyn AeadAes128GcmCipher> {
+        Box::new(Gcm(Aes128Gcm::new(GenericArray::from_slice(&key))))
+    }
+}
+
+impl SupportedAeadAes256Gcm for Srtp {
+    fn create_cipher(&self, key: [u8; 32], _encrypt: bool) -> Box<dyn AeadAes256GcmCipher> {
+        Box::new(Gcm(Aes256Gcm::new(GenericArray::from_slice(&key))))
+    }
+}
+
+struct AesCounter([u8; 16]);
+
+impl std::fmt::Debug for AesCounter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AesCounter").finish_non_exhaustive()
+    }
+}
+
+impl Aes128CmSha1_80Cipher for AesCounter {
+    fn encrypt(&mut self, iv: &[u8; 16], input: &[u8], output: &mut [u8]) -> Result<(), CryptoError> {
+        let output = &mut output[..input.len()];
+        output.copy_from_slice(input);
+        ctr::Ctr128BE::<aes::Aes128>::new(&self.0.into(), iv.into()).apply_keystream(output);
+        Ok(())
+    }
+
+    fn decrypt(&mut self, iv: &[u8; 16], input: &[u8], output: &mut [u8]) -> Result<(), CryptoError> {
+        self.encrypt(iv, input, output)
+    }
+}
+
+struct Gcm<C>(C);
+
+impl<C> std::fmt::Debug for Gcm<C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Gcm").finish_non_exhaustive()
+    }
+}
+
+impl<C: Aead> Gcm<C> {
+    fn seal(&self, iv: &[u8; 12], aad: &[u8], input: &[u8], output: &mut [u8]) -> Result<(), CryptoError> {
+        let sealed = self.0.encrypt(Nonce::from_slice(iv), Payload { msg: input, aad }).map_err(|_| CryptoError::Other("AES-GCM encryption failed".into()))?;
+        output[..sealed.len()].copy_from_slice(&sealed);
+        Ok(())
+    }
+
+    fn open(&self, iv: &[u8; 12], aads: &[&[u8]], input: &[u8], output: &mut [u8]) -> Result<usize, CryptoError> {
+        let aad = aads.concat();
+        let opened = self.0.decrypt(Nonce::from_slice(iv), Payload { msg: input, aad: &aad }).map_err(|_| CryptoError::Other("AES-GCM authentication failed".into()))?;
+        output[..opened.len()].copy_from_slice(&opened);
+        Ok(opened.len())
+    }
+}
+
+impl AeadAes128GcmCipher for Gcm<Aes128Gcm> {
+    fn encrypt(&mut self, iv: &[u8; 12], aad: &[u8], input: &[u8], output: &mut [u8]) -> Result<(), CryptoError> {
+        self.seal(iv, aad, input, output)
+    }
+
+    fn decrypt(&mut self, iv: &[u8; 12], aads: &[&[u8]], input: &[u8], output: &mut [u8]) -> Result<usize, CryptoError> {
+        self.open(iv, aads, input, output)
+    }
+}
+
+impl AeadAes256GcmCipher for Gcm<Aes256Gcm> {
+    fn encrypt(&mut self, iv: &[u8; 12], aad: &[u8], input: &[u8], output: &mut [u8]) -> Result<(), CryptoError> {
+        self.seal(iv, aad, input, output)
+    }
+
+    fn decrypt(&mut self, iv: &[u8; 12], aads: &[&[u8]], input: &[u8], output: &mut [u8]) -> Result<usize, CryptoError> {
+        self.open(iv, aads, input, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_srtp_primitives() {
+        // FIPS-197 appendix C.1, then the padding block
+        let key: Vec<u8> = (0..16).collect();
+        let input: Vec<u8> = (0..16).map(|i| i * 0x11).collect();
+        let mut output = [0; 32];
+        Srtp.srtp_aes_128_ecb_round(&key, &input, &mut output);
+        assert_eq!(output[..16], [0x69, 0xc4, 0xe0, 0xd8, 0x6a, 0x7b, 0x04, 0x30, 0xd8, 0xcd, 0xb7, 0x80, 0x70, 0xb4, 0xc5, 0x5a]);
+
+        let mut cipher = SupportedAeadAes128Gcm::create_cipher(&Srtp, [7; 16], true);
+        let (iv, aad) = ([1; 12], [2; 12]);
+        let mut sealed = [0; 21];
+        cipher.encrypt(&iv, &aad, b"hello", &mut sealed).unwrap();
+        let mut opened = [0; 21];
+        assert_eq!(cipher.decrypt(&iv, &[&aad[..4], &aad[4..]], &sealed, &mut opened).unwrap(), 5);
+        assert_eq!(&opened[..5], b"hello");
+        sealed[0] ^= 1;
+        assert!(cipher.decrypt(&iv, &[&aad], &sealed, &mut opened).is_err());
+
+        assert!(Dtls.generate_certificate().is_some());
+    }
+}
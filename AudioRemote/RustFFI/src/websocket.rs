@@ -0,0 +1,602 @@
+//! WebSocket push channel at /api/v1/events (RFC 6455). A remote connects,
+//! optionally with `?topics=volume,nowPlaying`, and receives one text message
+//! per state change: `{"topic": "volume", "data": {...}}`, starting with the
+//! current state. It can change its topics at any time by sending
+//! `{"subscribe": ["devices"]}`.
+//!
+//! With `?format=msgpack` the same conversation happens in binary messages
+//! holding protocol envelopes (see `protocol`): `volume`, `devices` and
+//! `nowPlaying` messages out, `subscribe` in. A remote may open with `hello`
+//! to negotiate the session; everything else is answered `unsupported`.
+//!
+//! The connection thread reads client frames; a second thread writes events as
+//! soon as they're published, and pings idle clients to detect dead ones.
+
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use base64::Engine as _;
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::events::{self, Event, Subscription, Topic, HUB};
+use crate::protocol::{self, Envelope, Hello, Message, Session};
+use crate::server::{self, Request, Response, Stream};
+use crate::sessions::{self, Scope};
+use crate::AudioRemoteError;
+
+pub const PATH: &str = "/api/v1/events";
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+/// An idle connection is pinged this often...
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// ...and closed if nothing at all arrives for this long
+const READ_TIMEOUT: Duration = Duration::from_secs(50);
+
+/// How a connection's messages are encoded, chosen with ?format=
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    fn event(self, event: &Event, session: &Session) -> io::Result<(Opcode, Vec<u8>)> {
+        match self {
+            Self::Json => Ok((Opcode::Text, event.to_json().to_string().into_bytes())),
+            Self::MessagePack => {
+                let message = Message::from_event(event).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+                Ok((Opcode::Binary, Envelope::in_session(session, None, message).encode_for(session)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Opcode {
+    Continuation = 0x0,
+    Text = 0x1,
+    Binary = 0x2,
+    Close = 0x8,
+    Ping = 0x9,
+    Pong = 0xa,
+}
+
+impl Opcode {
+    fn from_raw(value: u8) -> Option<Self> {
+        match value {
+            0x0 => Some(Self::Continuation),
+            0x1 => Some(Self::Text),
+            0x2 => Some(Self::Binary),
+            0x8 => Some(Self::Close),
+            0x9 => Some(Self::Ping),
+            0xa => Some(Self::Pong),
+            _ => None,
+        }
+    }
+
+    fn is_control(self) -> bool {
+        self as u8 & 0x8 != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    pub payload: Vec<u8>,
+}
+
+/// Close status codes (RFC 6455 §7.4.1)
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+const CLOSE_TOO_BIG: u16 = 1009;
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Read one client frame, unmasking it. Clients must mask; servers never do.
+pub(crate) fn read_frame(reader: &mut impl Read) -> io::Result<Frame> {
+    read_frame_from(reader, true)
+}
+
+/// Read one server frame, for the client end (see `connection`)
+pub(crate) fn read_server_frame(reader: &mut impl Read) -> io::Result<Frame> {
+    read_frame_from(reader, false)
+}
+
+fn read_frame_from(reader: &mut impl Read, masked: bool) -> io::Result<Frame> {
+    let mut head = [0; 2];
+    reader.read_exact(&mut head)?;
+    if head[0] & 0x70 != 0 {
+        return Err(protocol_error("reserved bits set without an extension"));
+    }
+    let opcode = Opcode::from_raw(head[0] & 0x0f).ok_or_else(|| protocol_error("unknown opcode"))?;
+    let fin = head[0] & 0x80 != 0;
+    match (head[1] & 0x80 != 0, masked) {
+        (false, true) => return Err(protocol_error("client frames must be masked")),
+        (true, false) => return Err(protocol_error("server frames must not be masked")),
+        _ => {}
+    }
+    let length = match head[1] & 0x7f {
+        126 => {
+            let mut length = [0; 2];
+            reader.read_exact(&mut length)?;
+            u64::from(u16::from_be_bytes(length))
+        }
+        127 => {
+            let mut length = [0; 8];
+            reader.read_exact(&mut length)?;
+            u64::from_be_bytes(length)
+        }
+        length => u64::from(length),
+    };
+    if opcode.is_control() && (length > 125 || !fin) {
+        return Err(protocol_error("control frames must be short and unfragmented"));
+    }
+    if length > MAX_MESSAGE_BYTES as u64 {
+        return Err(io::Error::new(io::ErrorKind::OutOfMemory, "message too big"));
+    }
+    let mut mask = [0; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; length as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame { fin, opcode, payload })
+}
+
+pub(crate) fn write_frame(writer: &mut impl Write, opcode: Opcode, payload: &[u8]) -> io::Result<()> {
+    write_frame_with(writer, opcode, payload, None)
+}
+
+/// Write one frame as a client, masked with a fresh random key
+pub(crate) fn write_client_frame(writer: &mut impl Write, opcode: Opcode, payload: &[u8]) -> io::Result<()> {
+    let mut mask = [0; 4];
+    getrandom::getrandom(&mut mask).map_err(|e| io::Error::other(e.to_string()))?;
+    write_frame_with(writer, opcode, payload, Some(mask))
+}
+
+fn write_frame_with(writer: &mut impl Write, opcode: Opcode, payload: &[u8], mask: Option<[u8; 4]>) -> io::Result<()> {
+    let mut head = vec![0x80 | opcode as u8];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        length @ 0..=125 => head.push(mask_bit | length as u8),
+        length @ 126..=0xffff => {
+            head.push(mask_bit | 126);
+            head.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            head.push(mask_bit | 127);
+            head.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            head.extend_from_slice(&mask);
+            head.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+            writer.write_all(&head)?;
+        }
+        None => {
+            writer.write_all(&head)?;
+            writer.write_all(payload)?;
+        }
+    }
+    writer.flush()
+}
+
+fn write_close(writer: &mut impl Write, code: u16, reason: &str) -> io::Result<()> {
+    let mut payload = code.to_be_bytes().to_vec();
+    payload.extend_from_slice(reason.as_bytes());
+    write_frame(writer, Opcode::Close, &payload)
+}
+
+/// Sec-WebSocket-Accept for a client's Sec-WebSocket-Key
+pub(crate) fn accept_key(key: &str) -> String {
+    let digest = Sha1::new().chain_update(key.as_bytes()).chain_update(ACCEPT_GUID.as_bytes()).finalize();
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+pub(crate) fn is_upgrade(request: &Request) -> bool {
+    request.header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+}
+
+/// Check the opening handshake; the error is the response to send instead
+fn handshake(request: &Request) -> Result<(String, BTreeSet<Topic>, Format), Response> {
+    if request.path != PATH {
+        return Err(Response::error(404, format!("no WebSocket endpoint at {}", request.path)));
+    }
+    if request.method != "GET" {
+        return Err(Response::error(405, "WebSocket connections start with GET").with_header("Allow", "GET"));
+    }
+    // WebSockets aren't subject to CORS, so pages elsewhere are turned away here
+    if request.is_cross_site() {
+        return Err(Response::error(403, "connections from other sites aren't allowed"));
+    }
+    let connection_upgrade = request
+        .header("connection")
+        .is_some_and(|c| c.split(',').any(|token| token.trim().eq_ignore_ascii_case("upgrade")));
+    if !connection_upgrade {
+        return Err(Response::error(400, "missing Connection: Upgrade"));
+    }
+    if request.header("sec-websocket-version") != Some("13") {
+        return Err(Response::error(426, "only WebSocket version 13 is supported").with_header("Sec-WebSocket-Version", "13"));
+    }
+    let key = request.header("sec-websocket-key").unwrap_or_default();
+    if base64::engine::general_purpose::STANDARD.decode(key).map_or(true, |key| key.len() != 16) {
+        return Err(Response::error(400, "invalid Sec-WebSocket-Key"));
+    }
+    let topics = requested_topics(request)?;
+    let format = match request.query_param("format").as_deref() {
+        None | Some("json") => Format::Json,
+        Some("msgpack") => Format::MessagePack,
+        Some(other) => return Err(Response::error(400, format!("unknown format \"{other}\"; use json or msgpack"))),
+    };
+    Ok((accept_key(key), topics, format))
+}
+
+/// The topics asked for with ?topics=, or everything the remote's scope covers.
+/// Shared with the server-sent events stream (see `sse`).
+pub(crate) fn requested_topics(request: &Request) -> Result<BTreeSet<Topic>, Response> {
+    let scope = sessions::scope(request);
+    match request.query_param("topics") {
+        Some(list) => {
+            let topics = events::parse_topics(&list).map_err(|e| Response::error(400, e.to_string()))?;
+            permit_topics(scope, &topics).map_err(|e| Response::error(403, e.to_string()))?;
+            Ok(topics)
+        }
+        // Everything the remote may see
+        None => Ok(Topic::ALL.into_iter().filter(|topic| scope.allows_topic(*topic)).collect()),
+    }
+}
+
+fn permit_topics(scope: Scope, topics: &BTreeSet<Topic>) -> Result<(), AudioRemoteError> {
+    match topics.iter().find(|topic| !scope.allows_topic(**topic)) {
+        Some(topic) => Err(AudioRemoteError::Refused(format!(
+            "this remote has {} access; {} needs {}",
+            scope.name(),
+            topic.name(),
+            Scope::required_for_topic(*topic).name()
+        ))),
+        None => Ok(()),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientMessage {
+    subscribe: Vec<String>,
+}
+
+/// Apply a client's binary envelope; returns the answer to send, if any. Shared with the
+/// Noise channel (see `noise`).
+pub(crate) fn handle_envelope(subscription: &Subscription, scope: Scope, session: &Mutex<Session>, bytes: &[u8]) -> Option<Envelope> {
+    let current = *session.lock().unwrap_or_else(|e| e.into_inner());
+    let envelope = match Envelope::decode(bytes) {
+        Ok(envelope) => envelope,
+        Err(e) => return Some(Envelope::in_session(&current, None, Message::Error { status: 400, message: e.to_string() })),
+    };
+    match &envelope.message {
+        Message::Hello(hello) => {
+            let mac = Hello::mac();
+            match mac.negotiate(hello) {
+                Ok(agreed) => {
+                    *session.lock().unwrap_or_else(|e| e.into_inner()) = agreed;
+                    Some(Envelope::in_session(&agreed, envelope.id, Message::Welcome { session: agreed, mac }))
+                }
+                Err(e) => Some(envelope.unsupported(&current, e.to_string())),
+            }
+        }
+        Message::Subscribe(_) if !current.has(protocol::FEATURE_SUBSCRIBE) => {
+            Some(envelope.unsupported(&current, "subscribe wasn't negotiated"))
+        }
+        Message::Subscribe(topics) => {
+            let topics = topics.iter().copied().collect();
+            match permit_topics(scope, &topics) {
+                Ok(()) => {
+                    subscription.set_topics(topics);
+                    None
+                }
+                Err(e) => Some(Envelope::in_session(&current, envelope.id, Message::Error { status: 403, message: e.to_string() })),
+            }
+        }
+        other => Some(envelope.unsupported(&current, format!("{} isn't handled on this connection", other.kind()))),
+    }
+}
+
+/// Apply a client text message; the error is sent back as `{"error": ...}`
+fn handle_message(subscription: &Subscription, scope: Scope, text: &[u8]) -> Result<(), AudioRemoteError> {
+    let message: ClientMessage = serde_json::from_slice(text)
+        .map_err(|e| AudioRemoteError::InvalidArgument(format!("expected {{\"subscribe\": [topics]}}: {e}")))?;
+    let topics = message.subscribe.iter().map(|name| Topic::from_name(name)).collect::<Result<_, _>>()?;
+    permit_topics(scope, &topics)?;
+    subscription.set_topics(topics);
+    Ok(())
+}
+
+fn lock(writer: &Mutex<Stream>) -> std::sync::MutexGuard<'_, Stream> {
+    writer.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Push events until the subscription closes or the client goes away
+fn push(subscription: &Subscription, session: &Mutex<Session>, writer: &Mutex<Stream>, format: Format) -> io::Result<()> {
+    while let Some(events) = subscription.next(PING_INTERVAL) {
+        let session = *session.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stream = lock(writer);
+        if events.is_empty() {
+            write_frame(&mut *stream, Opcode::Ping, b"")?;
+        }
+        if !session.has(protocol::FEATURE_EVENTS) {
+            continue;
+        }
+        for event in events {
+            let (opcode, payload) = format.event(&event, &session)?;
+            write_frame(&mut *stream, opcode, &payload)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read client frames until it closes; returns the close code to answer with
+fn receive(
+    reader: &mut impl BufRead,
+    subscription: &Subscription,
+    scope: Scope,
+    session: &Mutex<Session>,
+    writer: &Mutex<Stream>,
+    format: Format,
+) -> io::Result<u16> {
+    let mut message: Option<Vec<u8>> = None;
+    let mut binary = false;
+    loop {
+        let frame = match read_frame(reader) {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => return Ok(CLOSE_PROTOCOL_ERROR),
+            Err(e) if e.kind() == io::ErrorKind::OutOfMemory => return Ok(CLOSE_TOO_BIG),
+            Err(e) => return Err(e),
+        };
+        let payload = match frame.opcode {
+            Opcode::Ping => {
+                write_frame(&mut *lock(writer), Opcode::Pong, &frame.payload)?;
+                continue;
+            }
+            Opcode::Pong => continue,
+            Opcode::Close => return Ok(CLOSE_NORMAL),
+            Opcode::Text | Opcode::Binary if message.is_none() => {
+                binary = frame.opcode == Opcode::Binary;
+                frame.payload
+            }
+            Opcode::Continuation if message.is_some() => {
+                let mut so_far = message.take().unwrap_or_default();
+                so_far.extend_from_slice(&frame.payload);
+                so_far
+            }
+            _ => return Ok(CLOSE_PROTOCOL_ERROR),
+        };
+        if payload.len() > MAX_MESSAGE_BYTES {
+            return Ok(CLOSE_TOO_BIG);
+        }
+        if !frame.fin {
+            message = Some(payload);
+            continue;
+        }
+        match (format, binary) {
+            (Format::Json, _) => {
+                if let Err(e) = handle_message(subscription, scope, &payload) {
+                    let error = serde_json::json!({"error": {"code": e.code(), "message": e.to_string()}});
+                    write_frame(&mut *lock(writer), Opcode::Text, error.to_string().as_bytes())?;
+                }
+            }
+            (Format::MessagePack, true) => {
+                if let Some(answer) = handle_envelope(subscription, scope, session, &payload) {
+                    let bytes = answer.encode_for(&session.lock().unwrap_or_else(|e| e.into_inner()));
+                    write_frame(&mut *lock(writer), Opcode::Binary, &bytes)?;
+                }
+            }
+            // Text on a MessagePack connection
+            _ => return Ok(CLOSE_UNSUPPORTED_DATA),
+        }
+    }
+}
+
+/// Take over a connection whose request asked for an upgrade
+pub(crate) fn serve(mut stream: Stream, mut reader: BufReader<Stream>, request: &Request) -> io::Result<()> {
+    let (accept, topics, format) = match handshake(request) {
+        Ok(accepted) => accepted,
+        Err(response) => return server::write_response(&mut stream, &response, false),
+    };
+    let head = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\r\n"
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    log::debug!("WebSocket client {} subscribed to {topics:?}", request.peer);
+
+    let _live = sessions::open_stream(request);
+    let subscription = Arc::new(HUB.subscribe(topics));
+    let session = Arc::new(Mutex::new(Session::default()));
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let pusher = {
+        let (subscription, session, writer) = (subscription.clone(), session.clone(), writer.clone());
+        thread::Builder::new().name("audioremote-ws-push".into()).spawn(move || {
+            if push(&subscription, &session, &writer, format).is_err() {
+                // Unblock the reader too
+                let _ = lock(&writer).shutdown(Shutdown::Both);
+            }
+        })?
+    };
+    let result = receive(&mut reader, &subscription, sessions::scope(request), &session, &writer, format);
+    subscription.close();
+    let _ = pusher.join();
+    if let Ok(code) = result {
+        let _ = write_close(&mut *lock(&writer), code, "");
+    }
+    log::debug!("WebSocket client {} disconnected", request.peer);
+    result.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::ServerConfig;
+    use std::net::TcpStream;
+    use std::time::Instant;
+
+    /// A client frame, masked as the RFC requires
+    fn client_frame(opcode: Opcode, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![(fin as u8) << 7 | opcode as u8, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    /// Server frames are unmasked; read one
+    fn server_frame(stream: &mut impl Read) -> (u8, Vec<u8>) {
+        let mut head = [0; 2];
+        stream.read_exact(&mut head).unwrap();
+        let length = match head[1] {
+            126 => {
+                let mut length = [0; 2];
+                stream.read_exact(&mut length).unwrap();
+                u16::from_be_bytes(length) as usize
+            }
+            length => length as usize,
+        };
+        let mut payload = vec![0; length];
+        stream.read_exact(&mut payload).unwrap();
+        (head[0] & 0x0f, payload)
+    }
+
+    #[test]
+    fn test_frames() {
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        let raw = client_frame(Opcode::Text, true, b"hello");
+        let frame = read_frame(&mut raw.as_slice()).unwrap();
+        assert_eq!(frame, Frame { fin: true, opcode: Opcode::Text, payload: b"hello".to_vec() });
+
+        let mut unmasked = raw.clone();
+        unmasked[1] &= 0x7f;
+        assert_eq!(read_frame(&mut unmasked.as_slice()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let fragmented_ping = client_frame(Opcode::Ping, false, b"");
+        assert_eq!(read_frame(&mut fragmented_ping.as_slice()).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut out = Vec::new();
+        write_frame(&mut out, Opcode::Text, &[b'x'; 300]).unwrap();
+        assert_eq!(&out[..4], &[0x81, 126, 0x01, 0x2c]);
+        assert_eq!(out.len(), 304);
+        assert_eq!(read_server_frame(&mut out.as_slice()).unwrap().payload, vec![b'x'; 300]);
+
+        let mut out = Vec::new();
+        write_client_frame(&mut out, Opcode::Binary, b"abc").unwrap();
+        assert_eq!(read_frame(&mut out.as_slice()).unwrap(), Frame { fin: true, opcode: Opcode::Binary, payload: b"abc".to_vec() });
+        assert!(read_server_frame(&mut out.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_msgpack_messages() {
+        static LOCAL: events::Hub = events::Hub::new();
+        let subscription = LOCAL.subscribe(BTreeSet::new());
+        let session = Mutex::new(Session::default());
+        let scope = Scope::FullControl;
+        let subscribe = Envelope::new(Some(1), Message::Subscribe(vec![Topic::Devices])).encode();
+        let answer = handle_envelope(&subscription, Scope::VolumeOnly, &session, &subscribe).unwrap();
+        assert!(matches!(answer.message, Message::Error { status: 403, .. }));
+        assert!(subscription.topics().is_empty());
+        assert_eq!(handle_envelope(&subscription, scope, &session, &subscribe), None);
+        assert_eq!(subscription.topics(), BTreeSet::from([Topic::Devices]));
+        let answer = handle_envelope(&subscription, scope, &session, &Envelope::new(Some(2), Message::GetVolume).encode()).unwrap();
+        assert_eq!(answer.id, Some(2));
+        assert!(matches!(answer.message, Message::Unsupported { ref kind, .. } if kind == "getVolume"));
+
+        // A remote that has events but not subscribe
+        let hello = Hello { min_version: 1, max_version: 9, features: protocol::FEATURE_EVENTS };
+        let answer = handle_envelope(&subscription, scope, &session, &Envelope::new(Some(3), Message::Hello(hello)).encode()).unwrap();
+        let agreed = Session { version: 1, features: protocol::FEATURE_EVENTS };
+        assert_eq!(answer.message, Message::Welcome { session: agreed, mac: Hello::mac() });
+        assert_eq!(*session.lock().unwrap(), agreed);
+        let answer = handle_envelope(&subscription, scope, &session, &subscribe).unwrap();
+        assert!(matches!(answer.message, Message::Unsupported { ref kind, .. } if kind == "subscribe"));
+
+        let event = Event { topic: Topic::Volume, data: serde_json::json!({"volume": 0.5, "muted": true}) };
+        let (opcode, payload) = Format::MessagePack.event(&event, &agreed).unwrap();
+        assert_eq!(opcode, Opcode::Binary);
+        let volume = crate::remote::VolumeState { volume: 0.5, muted: true };
+        assert_eq!(Envelope::decode(&payload).unwrap(), Envelope::new(None, Message::Volume(volume)));
+    }
+
+    #[test]
+    fn test_handshake_refuses_other_sites() {
+        let upgrade = |origin: &str| {
+            let raw = format!(
+                "GET {PATH} HTTP/1.1\r\nHost: mac:8765\r\n{origin}Upgrade: websocket\r\nConnection: Upgrade\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+            );
+            handshake(&crate::server::tests::parse(&raw).unwrap()).map(|_| ()).map_err(|response| response.status)
+        };
+        assert_eq!(upgrade(""), Ok(()));
+        assert_eq!(upgrade("Origin: http://mac:8765\r\n"), Ok(()));
+        assert_eq!(upgrade("Origin: https://evil.example\r\n"), Err(403));
+    }
+
+    #[test]
+    fn test_push_over_tcp() {
+        let _lock = crate::runtime::tests::RUNTIME_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let address = server::start(&ServerConfig { port: 0, bind_address: "127.0.0.1".parse().unwrap(), require_auth: false, ..ServerConfig::default() }).unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let request = format!(
+            "GET {PATH}?topics=volume HTTP/1.1\r\nHost: mac\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        assert_eq!(status, "HTTP/1.1 101 Switching Protocols\r\n");
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if line == "\r\n" {
+                break;
+            }
+        }
+        // Wait for the subscription before publishing, skipping any earlier state
+        while HUB.subscriber_count() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        let published = Instant::now();
+        HUB.publish(Topic::NowPlaying, serde_json::Value::Null);
+        HUB.publish(Topic::Volume, serde_json::json!({"volume": 0.75, "muted": false}));
+        let (opcode, payload) = loop {
+            let (opcode, payload) = server_frame(&mut reader);
+            if payload.windows(4).any(|w| w == b"0.75") {
+                break (opcode, payload);
+            }
+        };
+        assert!(published.elapsed() < Duration::from_millis(500));
+        assert_eq!(opcode, Opcode::Text as u8);
+        assert_eq!(String::from_utf8(payload).unwrap(), r#"{"data":{"muted":false,"volume":0.75},"topic":"volume"}"#);
+
+        stream.write_all(&client_frame(Opcode::Text, true, br#"{"subscribe": ["bass"]}"#)).unwrap();
+        let (_, error) = server_frame(&mut reader);
+        assert!(String::from_utf8(error).unwrap().contains("unknown topic"));
+        stream.write_all(&client_frame(Opcode::Ping, true, b"hi")).unwrap();
+        assert_eq!(server_frame(&mut reader), (Opcode::Pong as u8, b"hi".to_vec()));
+
+        stream.write_all(&client_frame(Opcode::Close, true, &CLOSE_NORMAL.to_be_bytes())).unwrap();
+        let (opcode, _) = server_frame(&mut reader);
+        assert_eq!(opcode, Opcode::Close as u8);
+        server::stop();
+    }
+}
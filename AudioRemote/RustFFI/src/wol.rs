@@ -0,0 +1,178 @@
+//! Wake-on-LAN, so a remote can wake a paired Mac that's asleep before it
+//! sends commands. The magic packet is six 0xff bytes and then the target's
+//! hardware address sixteen times, broadcast over UDP; the network card
+//! watches for it while the Mac sleeps ("Wake for network access").
+
+use std::ffi::c_char;
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::str::FromStr;
+
+use crate::{guard, record, str_arg, AudioRemoteError};
+
+/// The discard port, where wake packets are traditionally sent
+pub const WOL_PORT: u16 = 9;
+const PACKET_SIZE: usize = 6 + 16 * 6;
+
+/// An Ethernet/Wi-Fi hardware address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl FromStr for MacAddress {
+    type Err = AudioRemoteError;
+
+    /// "a4:83:e7:12:34:56", "A4-83-E7-12-34-56", "a483.e712.3456" or "a483e7123456"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |why: &str| AudioRemoteError::InvalidArgument(format!("invalid MAC address \"{s}\": {why}"));
+        let trimmed = s.trim();
+        let separator = trimmed.chars().find(|c| !c.is_ascii_hexdigit());
+        let digits: String = match separator {
+            None => trimmed.into(),
+            Some(separator @ (':' | '-')) => {
+                let groups: Vec<&str> = trimmed.split(separator).collect();
+                if groups.len() != 6 || groups.iter().any(|group| group.len() != 2) {
+                    return Err(invalid("expected six groups of two hex digits"));
+                }
+                groups.concat()
+            }
+            Some('.') => {
+                let groups: Vec<&str> = trimmed.split('.').collect();
+                if groups.len() != 3 || groups.iter().any(|group| group.len() != 4) {
+                    return Err(invalid("expected three groups of four hex digits"));
+                }
+                groups.concat()
+            }
+            Some(other) => return Err(invalid(&format!("unexpected '{other}'"))),
+        };
+        if digits.len() != 12 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid("expected 12 hex digits"));
+        }
+        let mut bytes = [0; 6];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&digits[2 * i..2 * i + 2], 16).map_err(|_| invalid("expected hex digits"))?;
+        }
+        if bytes == [0; 6] {
+            return Err(invalid("the all-zero address belongs to no device"));
+        }
+        if bytes[0] & 1 != 0 {
+            return Err(invalid("that's a multicast address, not a device's"));
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+/// The magic packet that wakes `target`
+pub fn magic_packet(target: MacAddress) -> [u8; PACKET_SIZE] {
+    let mut packet = [0xff; PACKET_SIZE];
+    for chunk in packet[6..].chunks_exact_mut(6) {
+        chunk.copy_from_slice(&target.0);
+    }
+    packet
+}
+
+/// Where to send the packet: "192.168.1.255" or "192.168.1.255:7", the limited broadcast
+/// address if None. IPv4 only; IPv6 has no broadcast.
+pub fn parse_destination(destination: Option<&str>) -> Result<SocketAddr, AudioRemoteError> {
+    let Some(destination) = destination.map(str::trim).filter(|d| !d.is_empty()) else {
+        return Ok(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::BROADCAST, WOL_PORT)));
+    };
+    let address = match destination.parse::<SocketAddrV4>() {
+        Ok(address) => address,
+        Err(_) => SocketAddrV4::new(
+            destination.parse().map_err(|_| {
+                AudioRemoteError::InvalidArgument(format!("invalid broadcast address \"{destination}\": expected IPv4[:port]"))
+            })?,
+            WOL_PORT,
+        ),
+    };
+    Ok(SocketAddr::V4(address))
+}
+
+/// Broadcast a wake packet for `target` to `destination`
+pub fn send(target: MacAddress, destination: SocketAddr) -> Result<(), AudioRemoteError> {
+    let network = |e: std::io::Error| AudioRemoteError::Network(format!("can't send a wake packet to {destination}: {e}"));
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(network)?;
+    socket.set_broadcast(true).map_err(network)?;
+    socket.send_to(&magic_packet(target), destination).map_err(network)?;
+    log::info!("sent a wake packet for {target} to {destination}");
+    Ok(())
+}
+
+/// Wake a sleeping Mac with a Wake-on-LAN magic packet. `mac_address` is its hardware address
+/// ("a4:83:e7:12:34:56", dashes, Cisco dots or bare hex); `broadcast_addr` (nullable) is an IPv4
+/// broadcast address with an optional port, default 255.255.255.255:9. Sending can't tell
+/// whether the Mac woke; wait for it to answer before sending commands.
+/// Returns: 1 if the packet was sent, -999 on error (see last_error_message)
+///
+/// # Safety
+/// `mac_address` must point to a valid NUL-terminated string; `broadcast_addr` must be null or do too.
+#[no_mangle]
+pub unsafe extern "C" fn ar_send_wol(mac_address: *const c_char, broadcast_addr: *const c_char) -> i32 {
+    guard("ar_send_wol", -999, || {
+        let result = str_arg(mac_address, "mac_address").and_then(str::parse).and_then(|target| {
+            let destination = match broadcast_addr.is_null() {
+                true => None,
+                false => Some(str_arg(broadcast_addr, "broadcast_addr")?),
+            };
+            send(target, parse_destination(destination)?)
+        });
+        match record(result) {
+            Some(()) => 1,
+            None => -999,
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_mac_address() {
+        let expected = MacAddress([0xa4, 0x83, 0xe7, 0x12, 0x34, 0x56]);
+        for text in ["a4:83:e7:12:34:56", "A4-83-E7-12-34-56", "a483.e712.3456", " a483e7123456 "] {
+            assert_eq!(text.parse::<MacAddress>().unwrap(), expected, "{text}");
+        }
+        assert_eq!(expected.to_string(), "a4:83:e7:12:34:56");
+
+        for text in ["", "a4:83:e7:12:34", "a4:83:e7:12:34:5g", "a4:83-e7:12:34:56", "a4:83:e7:12:3:456", "a483e712345", "00:00:00:00:00:00"] {
+            assert!(text.parse::<MacAddress>().is_err(), "{text}");
+        }
+        let multicast = "01:00:5e:00:00:fb".parse::<MacAddress>().unwrap_err();
+        assert!(multicast.to_string().contains("multicast"), "{multicast}");
+    }
+
+    #[test]
+    fn test_magic_packet() {
+        let target = MacAddress([1 << 1, 2, 3, 4, 5, 6]);
+        let packet = magic_packet(target);
+        assert_eq!(packet.len(), 102);
+        assert_eq!(packet[..6], [0xff; 6]);
+        assert!(packet[6..].chunks(6).all(|chunk| chunk == target.0));
+    }
+
+    #[test]
+    fn test_destination_and_send() {
+        assert_eq!(parse_destination(None).unwrap(), "255.255.255.255:9".parse().unwrap());
+        assert_eq!(parse_destination(Some("192.168.1.255")).unwrap(), "192.168.1.255:9".parse().unwrap());
+        assert_eq!(parse_destination(Some("10.0.0.255:7")).unwrap(), "10.0.0.255:7".parse().unwrap());
+        assert!(parse_destination(Some("ff02::1")).is_err());
+        assert!(parse_destination(Some("subnet")).is_err());
+
+        let listener = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let target: MacAddress = "a4:83:e7:12:34:56".parse().unwrap();
+        send(target, listener.local_addr().unwrap()).unwrap();
+        let mut received = [0; 256];
+        let (len, _) = listener.recv_from(&mut received).unwrap();
+        assert_eq!(received[..len], magic_packet(target));
+    }
+}